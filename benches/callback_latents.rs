@@ -0,0 +1,56 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmark of the per-step overhead of the `Latents` & `LatentsF16` callbacks at frequency 1, i.e. the copy each
+//! callback receives of a 512x512 image's latents. Run with `cargo bench --bench callback_latents`.
+
+use std::time::{Duration, Instant};
+
+use ndarray::Array4;
+use pyke_diffusers::HalfLatents;
+
+const STEPS: u32 = 50;
+const ITERATIONS: u32 = 20;
+
+/// Times `ITERATIONS` simulated runs of `STEPS` steps, each handing one copy of `latents` to a callback.
+fn bench<T>(name: &str, latents: &Array4<f32>, bytes_per_copy: usize, mut copy: impl FnMut(&Array4<f32>) -> T, mut consume: impl FnMut(T) -> f32) -> Duration {
+	let mut checksum = 0.0;
+	let start = Instant::now();
+	for _ in 0..ITERATIONS {
+		for _ in 0..STEPS {
+			checksum += consume(copy(latents));
+		}
+	}
+	let per_run = start.elapsed() / ITERATIONS;
+	let allocated = bytes_per_copy * STEPS as usize;
+	println!("{name:<24} {per_run:>12?}/run, {:>8.1} MiB allocated/run (checksum {checksum})", allocated as f64 / (1024.0 * 1024.0));
+	per_run
+}
+
+fn main() {
+	for batch_size in [1, 4] {
+		let latents = Array4::from_shape_fn((batch_size, 4, 64, 64), |(b, c, h, w)| ((b * 31 + c * 17 + h * 7 + w) % 97) as f32 / 97.0 - 0.5);
+		println!("batch size {batch_size}, {STEPS} steps at frequency 1:");
+
+		let f32_run = bench("Latents (f32)", &latents, latents.len() * 4, |latents| latents.clone(), |latents| latents[[0, 0, 0, 0]]);
+		let f16_run = bench(
+			"LatentsF16 (f16)",
+			&latents,
+			latents.len() * 2,
+			|latents| HalfLatents::from_latents(latents.view()),
+			|latents| latents.as_slice()[0].to_f32()
+		);
+		println!("f16 / f32 time: {:.2}", f16_run.as_secs_f64() / f32_run.as_secs_f64());
+	}
+}
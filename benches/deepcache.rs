@@ -0,0 +1,68 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmark of txt2img with & without DeepCache. Uses the DeepCache fixture of the test model, whose deep subgraph is
+//! the whole test UNet, unless `DEEPCACHE_MODEL` is set to a model whose config has an `[unet.deepcache]` section; run
+//! with `DEEPCACHE_MODEL=path/to/model cargo bench --bench deepcache`.
+//!
+//! The fixture's shallow subgraph is free, so its speedup is the upper bound for each interval; real split UNets run
+//! their shallow blocks on every step.
+
+use std::{
+	path::PathBuf,
+	time::{Duration, Instant}
+};
+
+use pyke_diffusers::{
+	DeepCacheConfig, EulerDiscreteScheduler, OrtEnvironment, SchedulerOptimizedDefaults, StableDiffusionOptions, StableDiffusionPipeline,
+	StableDiffusionTxt2ImgOptions
+};
+
+#[path = "../tests/common/fixtures.rs"]
+mod fixtures;
+
+const STEPS: usize = 20;
+const ITERATIONS: u32 = 3;
+
+/// Times `ITERATIONS` generations with the given DeepCache config, after one warmup run.
+fn bench(name: &str, pipeline: &StableDiffusionPipeline, size: u32, deepcache: Option<DeepCacheConfig>) -> anyhow::Result<Duration> {
+	let mut options = StableDiffusionTxt2ImgOptions::default().with_size(size, size).with_steps(STEPS).with_seed(42).with_prompt("photo of a red fox");
+	options.deepcache = deepcache;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	options.run(pipeline, &mut scheduler)?;
+
+	let start = Instant::now();
+	for _ in 0..ITERATIONS {
+		options.run(pipeline, &mut scheduler)?;
+	}
+	let per_run = start.elapsed() / ITERATIONS;
+	println!("{name:<32} {per_run:>12?}/image ({STEPS} steps)");
+	Ok(per_run)
+}
+
+fn main() -> anyhow::Result<()> {
+	let (model, size) = match std::env::var("DEEPCACHE_MODEL") {
+		Ok(model) => (PathBuf::from(model), 512),
+		Err(_) => (fixtures::fixture("deepcache")?, 128)
+	};
+	let environment = OrtEnvironment::default().into_arc();
+	let pipeline = StableDiffusionPipeline::new(&environment, model, StableDiffusionOptions::default().with_deepcache(true))?;
+
+	let baseline = bench("full UNet", &pipeline, size, None)?;
+	for interval in [2, 3, 5] {
+		let cached = bench(&format!("DeepCache (interval {interval})"), &pipeline, size, Some(DeepCacheConfig::new(interval)))?;
+		println!("speedup: {:.2}x", baseline.as_secs_f64() / cached.as_secs_f64());
+	}
+	Ok(())
+}
@@ -0,0 +1,54 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Micro-benchmark of `set_timesteps` on cache misses vs. cache hits. Run with `cargo bench --bench set_timesteps`.
+
+use std::time::{Duration, Instant};
+
+use pyke_diffusers::{DiffusionScheduler, EulerDiscreteScheduler, SchedulerOptimizedDefaults};
+
+const ITERATIONS: u32 = 1000;
+
+/// Times `ITERATIONS` calls to `set_timesteps`, creating the scheduler with `new` before each call.
+fn bench(name: &str, mut new: impl FnMut() -> EulerDiscreteScheduler, steps: &[usize]) -> Duration {
+	let mut total = Duration::ZERO;
+	let mut checksum = 0.0;
+	for i in 0..ITERATIONS {
+		let mut scheduler = new();
+		let start = Instant::now();
+		scheduler.set_timesteps(steps[i as usize % steps.len()]);
+		total += start.elapsed();
+		checksum += scheduler.timesteps()[0];
+	}
+	let per_call = total / ITERATIONS;
+	println!("{name:<32} {per_call:>12?}/call (checksum {checksum})");
+	per_call
+}
+
+fn main() -> anyhow::Result<()> {
+	let scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let steps = [20, 25, 30, 50];
+
+	// a fresh scheduler has an empty cache, so every call is a miss
+	let miss = bench("set_timesteps (cache miss)", || scheduler.clone(), &steps);
+
+	let mut warm = scheduler.clone();
+	for n in steps {
+		warm.set_timesteps(n);
+	}
+	let hit = bench("set_timesteps (cache hit)", || warm.clone(), &steps);
+
+	println!("speedup: {:.1}x", miss.as_secs_f64() / hit.as_secs_f64());
+	Ok(())
+}
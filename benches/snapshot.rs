@@ -0,0 +1,58 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmark of the time to load a pipeline from its original models vs. from a warm snapshot. Uses the test model
+//! unless `SNAPSHOT_MODEL` is set; run with `SNAPSHOT_MODEL=path/to/model cargo bench --bench snapshot`.
+//!
+//! Each load runs in the same process, so the OS page cache is warm for both; the difference is the time spent
+//! parsing the config & optimizing the graphs, which a snapshot skips.
+
+use std::{
+	fs,
+	time::{Duration, Instant}
+};
+
+use pyke_diffusers::{OrtEnvironment, StableDiffusionOptions, StableDiffusionPipeline};
+
+const ITERATIONS: u32 = 5;
+
+/// Times `ITERATIONS` loads, after one warmup load.
+fn bench(name: &str, mut load: impl FnMut() -> anyhow::Result<StableDiffusionPipeline>) -> anyhow::Result<Duration> {
+	drop(load()?);
+	let start = Instant::now();
+	for _ in 0..ITERATIONS {
+		drop(load()?);
+	}
+	let per_load = start.elapsed() / ITERATIONS;
+	println!("{name:<24} {per_load:>12?}/load");
+	Ok(per_load)
+}
+
+fn main() -> anyhow::Result<()> {
+	let model = std::env::var("SNAPSHOT_MODEL").unwrap_or_else(|_| "tests/stable-diffusion".to_string());
+	let environment = OrtEnvironment::default().into_arc();
+	let snapshot_dir = std::env::temp_dir().join("pyke-diffusers-snapshot-bench");
+	let _ = fs::remove_dir_all(&snapshot_dir);
+
+	let start = Instant::now();
+	drop(StableDiffusionPipeline::create_snapshot(&environment, &model, StableDiffusionOptions::default(), &snapshot_dir)?);
+	println!("{:<24} {:>12?}", "create snapshot", start.elapsed());
+
+	let source = bench("original models", || StableDiffusionPipeline::new(&environment, &model, StableDiffusionOptions::default()))?;
+	let snapshot = bench("snapshot", || StableDiffusionPipeline::from_snapshot(&environment, &snapshot_dir, StableDiffusionOptions::default()))?;
+	println!("snapshot / original load time: {:.2}", snapshot.as_secs_f64() / source.as_secs_f64());
+
+	fs::remove_dir_all(&snapshot_dir)?;
+	Ok(())
+}
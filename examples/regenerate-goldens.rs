@@ -0,0 +1,38 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Regenerates the golden latents used by `tests/golden`. Only run this when a change is *supposed* to alter outputs.
+//!
+//! ```sh
+//! cargo run --example regenerate-goldens --features all-schedulers
+//! ```
+
+#[path = "../tests/golden/common.rs"]
+mod common;
+
+use pyke_diffusers::{OrtEnvironment, StableDiffusionOptions, StableDiffusionPipeline};
+
+fn main() -> anyhow::Result<()> {
+	let environment = OrtEnvironment::default().into_arc();
+	let pipeline = StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default())?;
+
+	std::fs::create_dir_all(common::golden_dir())?;
+	for (name, latents) in common::generate_all(&pipeline)? {
+		let path = common::golden_dir().join(format!("{name}.npy"));
+		common::write_npy(&path, &latents)?;
+		println!("wrote {}", path.display());
+	}
+
+	Ok(())
+}
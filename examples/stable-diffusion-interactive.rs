@@ -46,7 +46,8 @@ fn main() -> anyhow::Result<()> {
 					})
 				),
 				..Default::default()
-			}
+			},
+			..Default::default()
 		}
 	)?;
 
@@ -34,7 +34,8 @@ fn main() -> anyhow::Result<()> {
 					})
 				),
 				..Default::default()
-			}
+			},
+			..Default::default()
 		}
 	)?;
 
@@ -0,0 +1,71 @@
+# Copyright 2022-2023 pyke.io
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+# 	http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+
+"""Captures the reference values of tests/euler_parity.rs from diffusers' own `EulerDiscreteScheduler`, configured like
+`EulerDiscreteScheduler::stable_diffusion_v1_optimized_default` (scaled linear betas from 0.00085 to 0.012, `linspace`
+timestep spacing), and prints them as the test's Rust constants.
+
+The sample & model outputs are those of the `steps_match_diffusers` test; Euler's default `s_churn` of 0 makes its
+steps deterministic, so no generator is needed.
+
+Usage: python scripts/euler_parity_reference.py (requires `torch` & `diffusers`)
+"""
+import diffusers
+import numpy
+import torch
+from diffusers import EulerDiscreteScheduler
+
+
+def scheduler():
+	return EulerDiscreteScheduler(
+		num_train_timesteps=1000, beta_start=0.00085, beta_end=0.012, beta_schedule='scaled_linear', timestep_spacing='linspace'
+	)
+
+
+def rust_floats(values):
+	# `str` of a numpy float32 is its shortest round-tripping representation, like Rust's `Display` for `f32`
+	floats = (str(v) for v in values.numpy().astype(numpy.float32).flatten())
+	return ', '.join(v if '.' in v or 'e' in v else f'{v}.0' for v in floats)
+
+
+def main():
+	print(f'// captured with diffusers {diffusers.__version__} & torch {torch.__version__}')
+
+	ten = scheduler()
+	ten.set_timesteps(10)
+	print(f'const TIMESTEPS: [f32; 10] = [{rust_floats(ten.timesteps)}];')
+	# diffusers appends a final sigma of 0
+	print(f'const SIGMAS: [f32; 10] = [{rust_floats(ten.sigmas[:-1])}];')
+	assert float(ten.init_noise_sigma) == float(ten.sigmas[0]), 'init_noise_sigma is not the first sigma'
+
+	seven = scheduler()
+	seven.set_timesteps(7)
+	print(f'// 7-step timesteps: [{rust_floats(seven.timesteps)}]')
+	print(f'const SEVEN_STEP_SIGMAS: [f32; 7] = [{rust_floats(seven.sigmas[:-1])}];')
+
+	c, y, x = torch.meshgrid(torch.arange(4), torch.arange(2), torch.arange(2), indexing='ij')
+	sample = ((c * 4 + y * 2 + x).to(torch.float32) / 8.0 - 1.0).unsqueeze(0) * ten.init_noise_sigma
+	outputs = []
+	for k in range(3):
+		timestep = ten.timesteps[k]
+		model_output = ((c * 3 + y * 2 + x + k) % 7).to(torch.float32).unsqueeze(0) / 4.0 - 0.75
+		ten.scale_model_input(sample, timestep)
+		sample = ten.step(model_output, timestep, sample).prev_sample
+		outputs.append(f'\t[{rust_floats(sample)}]')
+	print('const STEP_OUTPUTS: [[f32; 16]; 3] = [\n' + ',\n'.join(outputs) + '\n];')
+
+
+if __name__ == '__main__':
+	main()
@@ -0,0 +1,163 @@
+# Copyright 2022-2023 pyke.io
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+# 	http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+
+"""Pure-Python reimplementation of rand 0.8's `StdRng` (ChaCha12) seeded with `seed_from_u64`, and of rand_distr 0.4's
+`StandardNormal` ziggurat sampler, used to derive the fixed expected values of the RNG draw-order tests in
+src/pipelines/stable_diffusion/impl_txt2img.rs independently of the Rust implementation.
+
+Usage: python scripts/stdrng_reference.py <seed> <count>
+"""
+import math
+import struct
+
+M32 = 0xFFFFFFFF
+M64 = 0xFFFFFFFFFFFFFFFF
+
+
+def rotl(x, n):
+	return ((x << n) | (x >> (32 - n))) & M32
+
+
+def quarter(s, a, b, c, d):
+	s[a] = (s[a] + s[b]) & M32
+	s[d] = rotl(s[d] ^ s[a], 16)
+	s[c] = (s[c] + s[d]) & M32
+	s[b] = rotl(s[b] ^ s[c], 12)
+	s[a] = (s[a] + s[b]) & M32
+	s[d] = rotl(s[d] ^ s[a], 8)
+	s[c] = (s[c] + s[d]) & M32
+	s[b] = rotl(s[b] ^ s[c], 7)
+
+
+def chacha_block(key, counter, stream, rounds):
+	state = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574] + key + [counter & M32, counter >> 32, stream & M32, stream >> 32]
+	s = list(state)
+	for _ in range(rounds // 2):
+		for a, b, c, d in [(0, 4, 8, 12), (1, 5, 9, 13), (2, 6, 10, 14), (3, 7, 11, 15), (0, 5, 10, 15), (1, 6, 11, 12), (2, 7, 8, 13), (3, 4, 9, 14)]:
+			quarter(s, a, b, c, d)
+	return [(x + y) & M32 for x, y in zip(s, state)]
+
+
+def pcg32(state):
+	state = (state * 6364136223846793005 + 11634580027462260723) & M64
+	xorshifted = (((state >> 18) ^ state) >> 27) & M32
+	rot = state >> 59
+	return state, ((xorshifted >> rot) | (xorshifted << ((32 - rot) & 31))) & M32
+
+
+class StdRng:
+	def __init__(self, seed_u64, rounds=12):
+		state = seed_u64 & M64
+		key = []
+		for _ in range(8):
+			state, word = pcg32(state)
+			key.append(word)
+		self.key, self.rounds, self.counter, self.buf = key, rounds, 0, []
+
+	def next_u32(self):
+		if not self.buf:
+			self.buf = chacha_block(self.key, self.counter, 0, self.rounds)
+			self.counter += 1
+		return self.buf.pop(0)
+
+	def next_u64(self):
+		lo = self.next_u32()
+		return (self.next_u32() << 32) | lo
+
+	def gen_f64(self):
+		return (self.next_u64() >> 11) * (1.0 / (1 << 53))
+
+	def open01(self):
+		return f64(((self.next_u64() >> 12) | (1023 << 52))) - (1.0 - 2.220446049250313e-16 / 2.0)
+
+
+def f64(bits):
+	return struct.unpack('<d', struct.pack('<Q', bits))[0]
+
+
+# rand_distr's ziggurat_tables.py, with the values round-tripped through the `%.18f` formatting of the generated table
+TABLE_LEN = 256
+NORM_R = 3.6541528853610088
+NORM_V = 0.00492867323399
+
+
+def norm_f(x):
+	return math.exp(-x * x / 2.0)
+
+
+def norm_f_inv(y):
+	return math.sqrt(-2.0 * math.log(y))
+
+
+def make_tables():
+	x = [0.0] * (TABLE_LEN + 1)
+	x[0] = NORM_V / norm_f(NORM_R)
+	x[1] = NORM_R
+	for i in range(2, TABLE_LEN):
+		x[i] = norm_f_inv(NORM_V / x[i - 1] + norm_f(x[i - 1]))
+	x[TABLE_LEN] = 0
+	f = [norm_f(v) for v in x]
+	return [float('%.18f' % v) for v in x], [float('%.18f' % v) for v in f]
+
+
+ZIG_X, ZIG_F = make_tables()
+ZIG_R = float('3.654152885361008796')
+
+
+def standard_normal(rng):
+	while True:
+		bits = rng.next_u64()
+		i = bits & 0xff
+		u = f64((bits >> 12) | ((1023 + 1) << 52)) - 3.0
+		x = u * ZIG_X[i]
+		if abs(x) < ZIG_X[i + 1]:
+			return x
+		if i == 0:
+			xx, y = 1.0, 0.0
+			while -2.0 * y < xx * xx:
+				x_, y_ = rng.open01(), rng.open01()
+				xx = math.log(x_) / ZIG_R
+				y = math.log(y_)
+			return xx - ZIG_R if u < 0.0 else ZIG_R - xx
+		if ZIG_F[i + 1] + (ZIG_F[i] - ZIG_F[i + 1]) * rng.gen_f64() < math.exp(-x * x / 2.0):
+			return x
+
+
+def f32(x):
+	return struct.unpack('<f', struct.pack('<f', x))[0]
+
+
+def normals_f32(seed, n):
+	rng = StdRng(seed)
+	return [f32(standard_normal(rng)) for _ in range(n)]
+
+
+def shortest_f32(x):
+	"""The shortest decimal representation which parses back to the same float32, as written in Rust literals."""
+	for precision in range(1, 12):
+		s = '%.*g' % (precision, x)
+		if f32(float(s)) == x:
+			return s
+
+
+if __name__ == '__main__':
+	import sys
+
+	# sanity check of the ChaCha core against the ChaCha20 test vector (zero key & nonce, block 0)
+	block = chacha_block([0] * 8, 0, 0, 20)
+	assert block[:4] == [0xade0b876, 0x903df1a0, 0xe56a5d40, 0x28bd8653], [hex(w) for w in block[:4]]
+
+	seed, count = int(sys.argv[1]), int(sys.argv[2])
+	print(', '.join(shortest_f32(v) for v in normals_f32(seed, count)))
@@ -14,11 +14,97 @@
 
 //! CLIP tokenizer implementation.
 
-use std::path::PathBuf;
+use std::{
+	fmt,
+	ops::Range,
+	path::{Path, PathBuf}
+};
 
 use ndarray::Array2;
 use tokenizers::{models::bpe::BPE, EncodeInput, Tokenizer};
 
+use crate::config::TokenizerConfig;
+
+/// The expected surface form of the CLIP beginning-of-string token.
+pub const BOS_TOKEN: &str = "<|startoftext|>";
+/// The expected surface form of the CLIP end-of-string token.
+pub const EOS_TOKEN: &str = "<|endoftext|>";
+/// The ID of [`BOS_TOKEN`] in the standard CLIP vocabulary.
+pub(crate) const DEFAULT_BOS_TOKEN_ID: u32 = 49406;
+/// The ID of [`EOS_TOKEN`] in the standard CLIP vocabulary.
+pub(crate) const DEFAULT_EOS_TOKEN_ID: u32 = 49407;
+
+/// Controls what happens when a tokenizer's configured BOS/EOS token IDs don't exist in its vocabulary or don't
+/// correspond to [`BOS_TOKEN`] & [`EOS_TOKEN`]. This is common with repacked models, and results in subtly wrong text
+/// embeddings.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialTokenValidation {
+	/// Don't check the special tokens.
+	Ignore,
+	/// Log a warning on mismatch. **This is the default.**
+	#[default]
+	Warn,
+	/// Return an error on mismatch.
+	Error
+}
+
+/// Controls how prompts longer than the tokenizer's (or long prompt weighting's) maximum length are handled.
+///
+/// The BOS & EOS tokens are always kept; only the prompt's own tokens are removed.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TruncationStrategy {
+	/// Remove tokens from the end of the prompt. **This is the default.**
+	#[default]
+	TruncateTail,
+	/// Remove tokens from the start of the prompt, keeping the end. This is useful when important tokens (e.g. quality
+	/// tags appended by a template) are at the end of the prompt.
+	TruncateHead,
+	/// Return an error describing the tokens that would have been removed.
+	Error
+}
+
+/// Controls what happens when a prompt consists solely of punctuation, symbols or emoji, e.g. `"???"` or `"🔥🔥🔥"`; see
+/// [`is_low_signal_prompt`]. Such prompts encode without problems, but carry little meaning for the text encoder, so
+/// the image is barely guided by them. Empty prompts (the default negative prompt) are not affected.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LowSignalPrompts {
+	/// Encode such prompts silently.
+	Ignore,
+	/// Log a warning, then encode the prompt as usual. **This is the default.**
+	#[default]
+	Warn,
+	/// Return an error quoting the prompt.
+	Error
+}
+
+/// Returns `true` if `prompt` is not blank but contains no letters or digits, i.e. it consists solely of punctuation,
+/// symbols, emoji & whitespace.
+///
+/// ```
+/// # use pyke_diffusers::clip::is_low_signal_prompt;
+/// assert!(is_low_signal_prompt("!!! ???"));
+/// assert!(is_low_signal_prompt("🦊✨"));
+/// assert!(!is_low_signal_prompt("a 🦊"));
+/// assert!(!is_low_signal_prompt(" "));
+/// ```
+pub fn is_low_signal_prompt(prompt: &str) -> bool {
+	!prompt.trim().is_empty() && !prompt.chars().any(char::is_alphanumeric)
+}
+
+/// Error returned when text prompts are used with a pipeline that has no tokenizer vocabulary, i.e. one configured with
+/// `type = "None"` in its `[tokenizer]` section. Such pipelines only accept prompts as token IDs, see
+/// [`PromptInput::TokenIds`](crate::PromptInput::TokenIds).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenizerUnavailable;
+
+impl fmt::Display for TokenizerUnavailable {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("this pipeline has no tokenizer; prompts must be provided as token IDs")
+	}
+}
+
+impl std::error::Error for TokenizerUnavailable {}
+
 /// A basic [CLIP](https://arxiv.org/abs/2103.00020) tokenizer.
 ///
 /// CLIP is used by many diffusion models, including Stable Diffusion, for prompt tokenization and feature extraction.
@@ -28,7 +114,10 @@ pub struct CLIPStandardTokenizer {
 	pub inner: Tokenizer,
 	model_max_length: usize,
 	bos_token_id: u32,
-	eos_token_id: u32
+	eos_token_id: u32,
+	pad_token_id: u32,
+	truncation_strategy: TruncationStrategy,
+	has_vocab: bool
 }
 
 unsafe impl Send for CLIPStandardTokenizer {}
@@ -43,16 +132,226 @@ impl CLIPStandardTokenizer {
 	}
 
 	/// Loads a CLIP tokenizer from a byte array.
+	///
+	/// Any truncation or padding configured in the tokenizer file is disabled;
+	/// [`CLIPStandardTokenizer::encode_for_text_model`] applies its own. Prompts are padded with the EOS token unless
+	/// another pad token is set with [`CLIPStandardTokenizer::with_pad_token`].
 	pub fn from_bytes<B: AsRef<[u8]>>(bytes: B, model_max_length: usize, bos_token_id: u32, eos_token_id: u32) -> anyhow::Result<Self> {
-		let tokenizer: Tokenizer = serde_json::from_slice(bytes.as_ref())?;
+		let tokenizer = without_postprocessing(serde_json::from_slice(bytes.as_ref())?);
 		Ok(Self {
 			inner: tokenizer,
 			model_max_length,
 			bos_token_id,
-			eos_token_id
+			eos_token_id,
+			pad_token_id: eos_token_id,
+			truncation_strategy: TruncationStrategy::default(),
+			has_vocab: true
 		})
 	}
 
+	/// Loads a CLIP tokenizer from a file, reading the BOS, EOS & pad tokens from a Hugging Face
+	/// `special_tokens_map.json` file. If the special tokens map does not exist or does not contain a token,
+	/// [`BOS_TOKEN`] & [`EOS_TOKEN`] are assumed, and prompts are padded with the EOS token.
+	///
+	/// # Errors
+	/// Returns an error if a special token does not exist in the tokenizer vocabulary.
+	pub fn with_special_tokens_map(path: impl Into<PathBuf>, special_tokens_map: impl AsRef<Path>, model_max_length: usize) -> anyhow::Result<Self> {
+		Self::load_with_special_tokens_map(path.into(), special_tokens_map.as_ref(), model_max_length, [None; 3], SpecialTokenValidation::Error)
+	}
+
+	/// Like [`CLIPStandardTokenizer::with_special_tokens_map`], but tokens given in `overrides` (BOS, EOS & pad) are
+	/// not looked up, and a token missing from the vocabulary is handled according to `validation`. With
+	/// [`SpecialTokenValidation::Warn`] or [`SpecialTokenValidation::Ignore`], BOS & EOS fall back to the standard
+	/// CLIP tokens (or their standard IDs if the vocabulary doesn't have them either), and the pad token falls back
+	/// to EOS.
+	fn load_with_special_tokens_map(
+		path: PathBuf,
+		special_tokens_map: &Path,
+		model_max_length: usize,
+		overrides: [Option<u32>; 3],
+		validation: SpecialTokenValidation
+	) -> anyhow::Result<Self> {
+		let tokenizer = without_postprocessing(serde_json::from_slice(&std::fs::read(path)?)?);
+
+		let map: serde_json::Value = if special_tokens_map.exists() {
+			serde_json::from_slice(&std::fs::read(special_tokens_map)?)?
+		} else {
+			serde_json::Value::Null
+		};
+		// tokens can either be a plain string or an `AddedToken` object with a `content` field
+		let surface_form = |key: &str| -> Option<String> {
+			match &map[key] {
+				serde_json::Value::String(s) => Some(s.clone()),
+				serde_json::Value::Object(o) => o.get("content").and_then(|c| c.as_str()).map(str::to_owned),
+				_ => None
+			}
+		};
+		let lookup = |name: &str, token: String, fallback: u32| -> anyhow::Result<u32> {
+			if let Some(id) = tokenizer.token_to_id(&token) {
+				return Ok(id);
+			}
+			let message = format!("{name} token `{token}` does not exist in the tokenizer vocabulary");
+			match validation {
+				SpecialTokenValidation::Error => anyhow::bail!(message),
+				SpecialTokenValidation::Warn => tracing::warn!("{message}; using token ID {fallback} instead"),
+				SpecialTokenValidation::Ignore => {}
+			}
+			Ok(fallback)
+		};
+		let standard_id = |token: &str, id: u32| tokenizer.token_to_id(token).unwrap_or(id);
+
+		let [bos_token, eos_token, pad_token] = overrides;
+		let bos_token_id = match bos_token {
+			Some(id) => id,
+			None => lookup("BOS", surface_form("bos_token").unwrap_or_else(|| BOS_TOKEN.to_owned()), standard_id(BOS_TOKEN, DEFAULT_BOS_TOKEN_ID))?
+		};
+		let eos_token_id = match eos_token {
+			Some(id) => id,
+			None => lookup("EOS", surface_form("eos_token").unwrap_or_else(|| EOS_TOKEN.to_owned()), standard_id(EOS_TOKEN, DEFAULT_EOS_TOKEN_ID))?
+		};
+		// without a pad token, prompts are padded with EOS like the original CLIP tokenizer
+		let pad_token_id = match (pad_token, surface_form("pad_token")) {
+			(Some(id), _) => id,
+			(None, Some(token)) => lookup("pad", token, eos_token_id)?,
+			(None, None) => eos_token_id
+		};
+
+		Ok(Self {
+			inner: tokenizer,
+			model_max_length,
+			bos_token_id,
+			eos_token_id,
+			pad_token_id,
+			truncation_strategy: TruncationStrategy::default(),
+			has_vocab: true
+		})
+	}
+
+	/// Creates a tokenizer without a vocabulary, for models distributed without tokenizer files. Text cannot be
+	/// encoded with such a tokenizer (see [`TokenizerUnavailable`]), but it still knows the special tokens & maximum
+	/// length needed to encode prompts given as token IDs.
+	pub fn without_vocab(model_max_length: usize, bos_token_id: u32, eos_token_id: u32) -> Self {
+		Self {
+			inner: Tokenizer::new(BPE::default()),
+			model_max_length,
+			bos_token_id,
+			eos_token_id,
+			pad_token_id: eos_token_id,
+			truncation_strategy: TruncationStrategy::default(),
+			has_vocab: false
+		}
+	}
+
+	/// Returns `true` if this tokenizer has a vocabulary and can encode text, i.e. it was not created with
+	/// [`CLIPStandardTokenizer::without_vocab`].
+	pub fn has_vocab(&self) -> bool {
+		self.has_vocab
+	}
+
+	/// Loads the tokenizer described by a model config, resolving omitted special tokens from the model's
+	/// `special_tokens_map.json` and validating them according to `validation`.
+	pub(crate) fn from_config(root: &Path, config: &TokenizerConfig, validation: SpecialTokenValidation) -> anyhow::Result<Self> {
+		let tokenizer = match config {
+			TokenizerConfig::CLIPTokenizer {
+				path,
+				model_max_length,
+				bos_token,
+				eos_token,
+				pad_token
+			} => match (bos_token, eos_token, pad_token) {
+				(Some(bos_token), Some(eos_token), Some(pad_token)) => {
+					Self::new(root.join(path), *model_max_length, *bos_token, *eos_token)?.with_pad_token(*pad_token)
+				}
+				_ => Self::load_with_special_tokens_map(
+					root.join(path),
+					&root.join("special_tokens_map.json"),
+					*model_max_length,
+					[*bos_token, *eos_token, *pad_token],
+					validation
+				)?
+			},
+			TokenizerConfig::None {
+				model_max_length,
+				bos_token,
+				eos_token,
+				pad_token
+			} => {
+				if *model_max_length < 3 {
+					anyhow::bail!("tokenizer `model-max-length` must be at least 3, got {model_max_length}");
+				}
+				return Ok(Self::without_vocab(*model_max_length, *bos_token, *eos_token).with_pad_token(pad_token.unwrap_or(*eos_token)));
+			}
+		};
+		tokenizer.validate_special_tokens(validation)?;
+		Ok(tokenizer)
+	}
+
+	/// Verifies that the configured BOS & EOS token IDs exist in the vocabulary and correspond to [`BOS_TOKEN`] &
+	/// [`EOS_TOKEN`]. Tokenizers without a vocabulary are not checked.
+	///
+	/// # Errors
+	/// Returns an error describing the mismatch if `validation` is [`SpecialTokenValidation::Error`]. With
+	/// [`SpecialTokenValidation::Warn`], mismatches are logged and this always returns `Ok`.
+	pub fn validate_special_tokens(&self, validation: SpecialTokenValidation) -> anyhow::Result<()> {
+		if validation == SpecialTokenValidation::Ignore || !self.has_vocab {
+			return Ok(());
+		}
+
+		let mut problems = Vec::new();
+		for (name, id, expected) in [("BOS", self.bos_token_id, BOS_TOKEN), ("EOS", self.eos_token_id, EOS_TOKEN)] {
+			match self.inner.id_to_token(id) {
+				None => problems.push(format!("{name} token ID {id} does not exist in the tokenizer vocabulary")),
+				Some(token) if token != expected => problems.push(format!("{name} token ID {id} corresponds to `{token}`, expected `{expected}`")),
+				Some(_) => {}
+			}
+		}
+
+		if !problems.is_empty() {
+			let message = format!("tokenizer special tokens are misconfigured: {}", problems.join("; "));
+			match validation {
+				SpecialTokenValidation::Error => anyhow::bail!(message),
+				_ => tracing::warn!("{message}")
+			}
+		}
+		Ok(())
+	}
+
+	/// Sets the [`TruncationStrategy`] used for prompts that are too long.
+	pub fn with_truncation_strategy(mut self, truncation_strategy: TruncationStrategy) -> Self {
+		self.truncation_strategy = truncation_strategy;
+		self
+	}
+
+	/// Returns the [`TruncationStrategy`] used for prompts that are too long.
+	pub fn truncation_strategy(&self) -> TruncationStrategy {
+		self.truncation_strategy
+	}
+
+	/// Applies this tokenizer's [`TruncationStrategy`] to a sequence of `tokens` (without BOS/EOS), returning the range
+	/// of tokens to keep so that at most `max_length` tokens remain. Removed tokens are logged as a warning.
+	///
+	/// # Errors
+	/// Returns an error listing the removed text if the strategy is [`TruncationStrategy::Error`] and truncation is
+	/// required.
+	pub(crate) fn truncation_range(&self, tokens: &[u32], max_length: usize) -> anyhow::Result<Range<usize>> {
+		if tokens.len() <= max_length {
+			return Ok(0..tokens.len());
+		}
+
+		let (kept, removed) = match self.truncation_strategy {
+			TruncationStrategy::TruncateTail | TruncationStrategy::Error => (0..max_length, max_length..tokens.len()),
+			TruncationStrategy::TruncateHead => (tokens.len() - max_length..tokens.len(), 0..tokens.len() - max_length)
+		};
+		let removed_text = self.inner.decode(tokens[removed.clone()].to_vec(), true).map_err(|e| anyhow::anyhow!("{e:?}"))?;
+		match self.truncation_strategy {
+			TruncationStrategy::Error => {
+				anyhow::bail!("prompt is {} tokens long, exceeding the maximum of {max_length} tokens; excess tokens: `{removed_text}`", tokens.len())
+			}
+			strategy => tracing::warn!("prompt exceeds the maximum of {max_length} tokens; {strategy:?} removed {} tokens: `{removed_text}`", removed.len())
+		}
+		Ok(kept)
+	}
+
 	/// Returns the BPE model used by the tokenizer.
 	///
 	/// # Panics
@@ -85,11 +384,29 @@ impl CLIPStandardTokenizer {
 		self.bos_token_id
 	}
 
+	/// Returns the ID of the token prompts are padded with. This is the EOS token unless the model specifies a
+	/// different pad token.
+	pub fn pad(&self) -> u32 {
+		self.pad_token_id
+	}
+
+	/// Sets the ID of the token prompts are padded with.
+	pub fn with_pad_token(mut self, pad_token_id: u32) -> Self {
+		self.pad_token_id = pad_token_id;
+		self
+	}
+
 	/// Encodes the input string(s) into arrays of token IDs.
+	///
+	/// # Errors
+	/// Returns [`TokenizerUnavailable`] if this tokenizer has no vocabulary.
 	pub fn encode<'s, 'e, E>(&self, enc: Vec<E>) -> anyhow::Result<Vec<Vec<u32>>>
 	where
 		E: Into<EncodeInput<'s>> + Send
 	{
+		if !self.has_vocab {
+			return Err(TokenizerUnavailable.into());
+		}
 		Ok(self
 			.inner
 			.encode_batch(enc, true)
@@ -100,19 +417,40 @@ impl CLIPStandardTokenizer {
 	}
 
 	/// Encodes the input prompts into an [`Array2`] to be passed to a CLIPTextModel.
+	///
+	/// Each prompt is wrapped in BOS & EOS tokens and padded with the pad token (see [`CLIPStandardTokenizer::pad`]) to
+	/// [`CLIPStandardTokenizer::len`]. Prompts that are too long are truncated according to this tokenizer's
+	/// [`TruncationStrategy`].
 	pub fn encode_for_text_model<'s, 'e, E>(&self, enc: Vec<E>) -> anyhow::Result<Array2<i32>>
 	where
 		E: Into<EncodeInput<'s>> + Send
 	{
 		let batch_size = enc.len();
-		Ok(Array2::from_shape_vec(
-			(batch_size, self.len()),
-			self.inner
-				.encode_batch(enc, true)
-				.map_err(|e| anyhow::anyhow!("{e:?}"))?
-				.iter()
-				.flat_map(|v| v.get_ids().iter().map(|tok| *tok as _).collect::<Vec<i32>>())
-				.collect()
-		)?)
+		let max_length = self.len();
+		let mut ids = Vec::with_capacity(batch_size * max_length);
+		for tokens in self.encode(enc)? {
+			let mut tokens = tokens.as_slice();
+			if tokens.first() == Some(&self.bos_token_id) {
+				tokens = &tokens[1..];
+			}
+			if tokens.last() == Some(&self.eos_token_id) {
+				tokens = &tokens[..tokens.len() - 1];
+			}
+			let tokens = &tokens[self.truncation_range(tokens, max_length - 2)?];
+
+			ids.push(self.bos_token_id as i32);
+			ids.extend(tokens.iter().map(|tok| *tok as i32));
+			ids.push(self.eos_token_id as i32);
+			ids.extend(std::iter::repeat(self.pad_token_id as i32).take(max_length - 2 - tokens.len()));
+		}
+		Ok(Array2::from_shape_vec((batch_size, max_length), ids)?)
 	}
 }
+
+/// Disables any truncation & padding configured in a `tokenizer.json`, which would otherwise be applied before
+/// [`CLIPStandardTokenizer::encode_for_text_model`]'s own [`TruncationStrategy`] & padding.
+fn without_postprocessing(mut tokenizer: Tokenizer) -> Tokenizer {
+	tokenizer.with_truncation(None);
+	tokenizer.with_padding(None);
+	tokenizer
+}
@@ -30,11 +30,43 @@ pub enum TokenizerConfig {
 	CLIPTokenizer {
 		path: String,
 		model_max_length: usize,
+		/// If omitted, read from `special_tokens_map.json` in the model root.
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		bos_token: Option<u32>,
+		/// If omitted, read from `special_tokens_map.json` in the model root.
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		eos_token: Option<u32>,
+		/// If omitted, read from `special_tokens_map.json` in the model root, or the EOS token if it has none.
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pad_token: Option<u32>
+	},
+	/// No tokenizer is available; prompts must be given as token IDs.
+	#[serde(rename_all = "kebab-case")]
+	None {
+		#[serde(default = "default_model_max_length")]
+		model_max_length: usize,
+		#[serde(default = "default_bos_token")]
 		bos_token: u32,
-		eos_token: u32
+		#[serde(default = "default_eos_token")]
+		eos_token: u32,
+		/// Defaults to the EOS token.
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pad_token: Option<u32>
 	}
 }
 
+fn default_model_max_length() -> usize {
+	77
+}
+
+fn default_bos_token() -> u32 {
+	crate::clip::DEFAULT_BOS_TOKEN_ID
+}
+
+fn default_eos_token() -> u32 {
+	crate::clip::DEFAULT_EOS_TOKEN_ID
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct CLIPFeatureExtractorConfig {
@@ -65,7 +97,39 @@ pub struct CLIPTextModelConfig {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct UNetConfig {
-	pub path: String
+	pub path: String,
+	/// The dimension of the guidance embedding input of guidance-distilled UNets, if the UNet's input has a dynamic
+	/// embedding dimension.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub guidance_embedding_dim: Option<usize>,
+	/// The rank of the UNet's timestep input: `0` for UNets exported with a scalar timestep, or `1` for a 1-element
+	/// array. Detected from the UNet's input shape if unset.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub timestep_rank: Option<usize>,
+	/// A split export of the UNet used for DeepCache, from the `[unet.deepcache]` section; see
+	/// [`DeepCacheConfig`](crate::DeepCacheConfig).
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub deepcache: Option<DeepCacheUNetConfig>,
+	/// The path of a dedicated 9-channel inpainting UNet used by
+	/// [`StableDiffusionPipeline::inpaint_with_mask_channel`](crate::StableDiffusionPipeline::inpaint_with_mask_channel),
+	/// which is only loaded while inpainting.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub inpaint: Option<String>
+}
+
+/// The UNet split into a deep & a shallow subgraph for [DeepCache](https://arxiv.org/abs/2312.00858).
+///
+/// The deep subgraph takes the UNet's `sample`, `timestep` & `encoder_hidden_states` inputs and returns the features of
+/// the skip branch the UNet was split at as its outputs. The shallow subgraph takes the same 3 inputs, followed by one
+/// input of the same name for each output of the deep subgraph, and returns the noise prediction.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeepCacheUNetConfig {
+	pub deep: String,
+	pub shallow: String,
+	/// The index of the skip branch the UNet was split at, if known.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub branch: Option<usize>
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -73,7 +137,13 @@ pub struct UNetConfig {
 pub struct VAEConfig {
 	pub encoder: Option<String>,
 	pub decoder: String,
-	pub scale_factor: f32
+	pub scale_factor: f32,
+	/// Path to a tiny decoder used for previews, like [TAESD](https://github.com/madebyollin/taesd)'s decoder, which
+	/// takes (scaled) UNet latents of shape `(1, 4, height / 8, width / 8)` and produces images of shape
+	/// `(1, 3, height, width)` with values in `[0, 1]`. See
+	/// [`StableDiffusionPipeline::approximate_decode_latents`](crate::StableDiffusionPipeline::approximate_decode_latents).
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub preview_decoder: Option<String>
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -82,6 +152,38 @@ pub struct SafetyCheckerConfig {
 	pub path: String
 }
 
+/// A CLIP image encoder & text projection used to score generated images against their prompts; see
+/// [`StableDiffusionPipeline::clip_score`](crate::StableDiffusionPipeline::clip_score).
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CLIPScorerConfig {
+	/// Path to a `CLIPVisionModelWithProjection` model, taking `pixel_values` and producing `image_embeds` as its first
+	/// output.
+	pub image_encoder: String,
+	/// Path to the `text_projection` weights of the same CLIP model, stored as raw little-endian f32 in PyTorch
+	/// `Linear` layout (`[projection_dim, hidden_size]`).
+	pub text_projection: String,
+	pub projection_dim: usize
+}
+
+/// Paths to int8-quantized versions of a model's components, from the `[quantized]` section of its config. These are
+/// loaded instead of the original models when [`StableDiffusionOptions::quantize`](crate::StableDiffusionOptions::quantize)
+/// is enabled; components without a quantized version are loaded as usual.
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct QuantizedModelsConfig {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub text_encoder: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub unet: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub vae_encoder: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub vae_decoder: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub safety_checker: Option<String>
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct StableDiffusionModelHashes {
@@ -90,7 +192,34 @@ pub struct StableDiffusionModelHashes {
 	pub unet: String,
 	pub vae_encoder: Option<String>,
 	pub vae_decoder: String,
-	pub safety_checker: Option<String>
+	pub safety_checker: Option<String>,
+	pub clip_image_encoder: Option<String>
+}
+
+/// Descriptive information about a model, like its author & license, from the `[metadata]` section of its config.
+///
+/// All fields are optional. Applications distributing or displaying models should show the license where available.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ModelMetadata {
+	/// The human-readable name of the model.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+	/// The author(s) of the model.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub author: Option<String>,
+	/// The model's license, preferably as an SPDX identifier (e.g. `CreativeML-OpenRAIL-M`).
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub license: Option<String>,
+	/// A URL to the model's homepage or model card.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub homepage: Option<String>,
+	/// Freeform tags describing the model, e.g. `anime`.
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub tags: Vec<String>,
+	/// The version of the model.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub version: Option<String>
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -102,9 +231,52 @@ pub struct StableDiffusionConfig {
 	pub vae: VAEConfig,
 	pub unet: UNetConfig,
 	pub safety_checker: Option<SafetyCheckerConfig>,
+	pub clip_scorer: Option<CLIPScorerConfig>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub metadata: Option<ModelMetadata>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub quantized: Option<QuantizedModelsConfig>,
 	pub hashes: StableDiffusionModelHashes
 }
 
+impl StableDiffusionConfig {
+	/// Replaces the path of each component that has an int8-quantized version with the quantized model's path, and
+	/// returns the names of the replaced components.
+	pub(crate) fn use_quantized_models(&mut self) -> anyhow::Result<Vec<&'static str>> {
+		let quantized = match self.quantized.clone() {
+			Some(quantized) => quantized,
+			None => anyhow::bail!(
+				"`quantize` is enabled, but the model config has no `[quantized]` section; quantize the models with ONNX Runtime's `onnxruntime.quantization.quantize_dynamic` & add their paths to the config"
+			)
+		};
+		let mut replaced = Vec::new();
+		if let Some(path) = quantized.text_encoder {
+			self.text_encoder.path = path;
+			replaced.push("text encoder");
+		}
+		if let Some(path) = quantized.unet {
+			self.unet.path = path;
+			replaced.push("UNet");
+		}
+		if let (Some(path), Some(encoder)) = (quantized.vae_encoder, self.vae.encoder.as_mut()) {
+			*encoder = path;
+			replaced.push("VAE encoder");
+		}
+		if let Some(path) = quantized.vae_decoder {
+			self.vae.decoder = path;
+			replaced.push("VAE decoder");
+		}
+		if let (Some(path), Some(safety_checker)) = (quantized.safety_checker, self.safety_checker.as_mut()) {
+			safety_checker.path = path;
+			replaced.push("safety checker");
+		}
+		if replaced.is_empty() {
+			anyhow::bail!("`quantize` is enabled, but the model config's `[quantized]` section lists no quantized models");
+		}
+		Ok(replaced)
+	}
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "pipeline", rename_all = "kebab-case")]
 #[non_exhaustive]
@@ -115,3 +287,87 @@ pub enum DiffusionPipeline {
 		inner: StableDiffusionConfig
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TEST_CONFIG: &str = include_str!("../tests/stable-diffusion/pyke-diffusers.toml");
+
+	fn metadata(config: &DiffusionPipeline) -> Option<&ModelMetadata> {
+		match config {
+			DiffusionPipeline::StableDiffusion { inner, .. } => inner.metadata.as_ref()
+		}
+	}
+
+	#[test]
+	fn missing_metadata() {
+		let config: DiffusionPipeline = toml::from_str(TEST_CONFIG).unwrap();
+		assert_eq!(metadata(&config), None);
+	}
+
+	#[test]
+	fn metadata_roundtrip() {
+		let config = format!("{TEST_CONFIG}\n[metadata]\nname = \"Test Model\"\nlicense = \"CreativeML-OpenRAIL-M\"\ntags = [ \"test\" ]\n");
+		let config: DiffusionPipeline = toml::from_str(&config).unwrap();
+		let expected = ModelMetadata {
+			name: Some("Test Model".to_string()),
+			license: Some("CreativeML-OpenRAIL-M".to_string()),
+			tags: vec!["test".to_string()],
+			..Default::default()
+		};
+		assert_eq!(metadata(&config), Some(&expected));
+
+		let config: DiffusionPipeline = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+		assert_eq!(metadata(&config), Some(&expected));
+	}
+
+	fn stable_diffusion_config(config: &str) -> StableDiffusionConfig {
+		match toml::from_str(config).unwrap() {
+			DiffusionPipeline::StableDiffusion { inner, .. } => inner
+		}
+	}
+
+	#[test]
+	fn quantized_models() -> anyhow::Result<()> {
+		let quantized = "[quantized]\nunet = \"unet.int8.onnx\"\nsafety-checker = \"safety_checker.int8.onnx\"";
+		let mut config = stable_diffusion_config(&format!("{TEST_CONFIG}\n{quantized}\n"));
+		// the model has no safety checker, so only the UNet is replaced
+		assert_eq!(config.use_quantized_models()?, ["UNet"]);
+		assert_eq!(config.unet.path, "unet.int8.onnx");
+		assert_eq!(config.text_encoder.path, "text_encoder.onnx");
+
+		assert!(stable_diffusion_config(TEST_CONFIG).use_quantized_models().is_err());
+		assert!(stable_diffusion_config(&format!("{TEST_CONFIG}\n[quantized]\n")).use_quantized_models().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn preview_decoder() {
+		assert_eq!(stable_diffusion_config(TEST_CONFIG).vae.preview_decoder, None);
+		let config = TEST_CONFIG.replace("scale-factor = 0.18215", "scale-factor = 0.18215\npreview-decoder = \"taesd_decoder.onnx\"");
+		assert_eq!(stable_diffusion_config(&config).vae.preview_decoder.as_deref(), Some("taesd_decoder.onnx"));
+	}
+
+	#[test]
+	fn tokenizer_none() {
+		let tokenizer = |config: &str| -> TokenizerConfig {
+			let clip_tokenizer = "type = \"CLIPTokenizer\"\npath = \"tokenizer.json\"\nmodel-max-length = 77\nbos-token = 0\neos-token = 1";
+			let config = TEST_CONFIG.replace(clip_tokenizer, config);
+			match toml::from_str(&config).unwrap() {
+				DiffusionPipeline::StableDiffusion { inner, .. } => inner.tokenizer
+			}
+		};
+
+		match tokenizer("type = \"None\"") {
+			TokenizerConfig::None { model_max_length, bos_token, eos_token, pad_token } => {
+				assert_eq!((model_max_length, bos_token, eos_token, pad_token), (77, 49406, 49407, None))
+			}
+			config => panic!("unexpected tokenizer config: {config:?}")
+		}
+		match tokenizer("type = \"None\"\nbos-token = 0\neos-token = 1") {
+			TokenizerConfig::None { bos_token, eos_token, .. } => assert_eq!((bos_token, eos_token), (0, 1)),
+			config => panic!("unexpected tokenizer config: {config:?}")
+		}
+	}
+}
@@ -0,0 +1,225 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serialization of [`DiffusionDevice`] & [`DiffusionDeviceControl`].
+//!
+//! The device types wrap `ort`'s execution provider options, which do not implement `serde`'s traits, so they are
+//! (de)serialized through the mirror types in this module instead.
+
+use std::collections::BTreeMap;
+
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{ArenaExtendStrategy, CUDAExecutionProviderCuDNNConvAlgoSearch, CUDAExecutionProviderOptions, DiffusionDevice, DiffusionDeviceControl};
+
+/// The current version of the serialized [`DiffusionDeviceControl`] format.
+pub(crate) const DEVICE_CONFIG_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+enum DeviceRepr {
+	Cpu,
+	Cuda {
+		#[serde(default)]
+		device_id: u32,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		options: Option<CUDAOptionsRepr>
+	},
+	TensorRT,
+	DirectML {
+		#[serde(default)]
+		device_id: u32
+	},
+	ROCm {
+		#[serde(default)]
+		device_id: i32
+	},
+	OneDNN,
+	CoreML
+}
+
+#[derive(Serialize, Deserialize)]
+struct CUDAOptionsRepr {
+	/// Overridden by the device ID of [`DiffusionDevice::CUDA`] when the execution provider is created, but kept so
+	/// options survive a round trip unchanged.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	device_id: Option<u32>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	gpu_mem_limit: Option<usize>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	arena_extend_strategy: Option<ArenaExtendStrategyRepr>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	cudnn_conv_algo_search: Option<CuDNNConvAlgoSearchRepr>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	do_copy_in_default_stream: Option<bool>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ArenaExtendStrategyRepr {
+	NextPowerOfTwo,
+	SameAsRequested
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CuDNNConvAlgoSearchRepr {
+	Exhaustive,
+	Heuristic,
+	Default
+}
+
+impl From<&CUDAExecutionProviderOptions> for CUDAOptionsRepr {
+	fn from(options: &CUDAExecutionProviderOptions) -> Self {
+		Self {
+			device_id: options.device_id,
+			gpu_mem_limit: options.gpu_mem_limit,
+			arena_extend_strategy: options.arena_extend_strategy.as_ref().map(|strategy| match strategy {
+				ArenaExtendStrategy::NextPowerOfTwo => ArenaExtendStrategyRepr::NextPowerOfTwo,
+				ArenaExtendStrategy::SameAsRequested => ArenaExtendStrategyRepr::SameAsRequested
+			}),
+			cudnn_conv_algo_search: options.cudnn_conv_algo_search.as_ref().map(|search| match search {
+				CUDAExecutionProviderCuDNNConvAlgoSearch::Exhaustive => CuDNNConvAlgoSearchRepr::Exhaustive,
+				CUDAExecutionProviderCuDNNConvAlgoSearch::Heuristic => CuDNNConvAlgoSearchRepr::Heuristic,
+				CUDAExecutionProviderCuDNNConvAlgoSearch::Default => CuDNNConvAlgoSearchRepr::Default
+			}),
+			do_copy_in_default_stream: options.do_copy_in_default_stream
+		}
+	}
+}
+
+impl From<CUDAOptionsRepr> for CUDAExecutionProviderOptions {
+	fn from(options: CUDAOptionsRepr) -> Self {
+		CUDAExecutionProviderOptions {
+			device_id: options.device_id,
+			gpu_mem_limit: options.gpu_mem_limit,
+			arena_extend_strategy: options.arena_extend_strategy.map(|strategy| match strategy {
+				ArenaExtendStrategyRepr::NextPowerOfTwo => ArenaExtendStrategy::NextPowerOfTwo,
+				ArenaExtendStrategyRepr::SameAsRequested => ArenaExtendStrategy::SameAsRequested
+			}),
+			cudnn_conv_algo_search: options.cudnn_conv_algo_search.map(|search| match search {
+				CuDNNConvAlgoSearchRepr::Exhaustive => CUDAExecutionProviderCuDNNConvAlgoSearch::Exhaustive,
+				CuDNNConvAlgoSearchRepr::Heuristic => CUDAExecutionProviderCuDNNConvAlgoSearch::Heuristic,
+				CuDNNConvAlgoSearchRepr::Default => CUDAExecutionProviderCuDNNConvAlgoSearch::Default
+			}),
+			do_copy_in_default_stream: options.do_copy_in_default_stream
+		}
+	}
+}
+
+impl Serialize for DiffusionDevice {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		let repr = match self {
+			DiffusionDevice::CPU => DeviceRepr::Cpu,
+			DiffusionDevice::CUDA(device_id, options) => DeviceRepr::Cuda {
+				device_id: *device_id,
+				options: options.as_ref().map(CUDAOptionsRepr::from)
+			},
+			DiffusionDevice::TensorRT => DeviceRepr::TensorRT,
+			DiffusionDevice::DirectML(device_id) => DeviceRepr::DirectML { device_id: *device_id },
+			DiffusionDevice::ROCm(device_id) => DeviceRepr::ROCm { device_id: *device_id },
+			DiffusionDevice::OneDNN => DeviceRepr::OneDNN,
+			DiffusionDevice::CoreML => DeviceRepr::CoreML,
+			DiffusionDevice::Custom(_) => return Err(serde::ser::Error::custom("custom execution providers cannot be serialized"))
+		};
+		repr.serialize(serializer)
+	}
+}
+
+impl<'de> Deserialize<'de> for DiffusionDevice {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		Ok(match DeviceRepr::deserialize(deserializer)? {
+			DeviceRepr::Cpu => DiffusionDevice::CPU,
+			DeviceRepr::Cuda { device_id, options } => DiffusionDevice::CUDA(device_id, options.map(CUDAExecutionProviderOptions::from)),
+			DeviceRepr::TensorRT => DiffusionDevice::TensorRT,
+			DeviceRepr::DirectML { device_id } => DiffusionDevice::DirectML(device_id),
+			DeviceRepr::ROCm { device_id } => DiffusionDevice::ROCm(device_id),
+			DeviceRepr::OneDNN => DiffusionDevice::OneDNN,
+			DeviceRepr::CoreML => DiffusionDevice::CoreML
+		})
+	}
+}
+
+fn cpu() -> DiffusionDevice {
+	DiffusionDevice::CPU
+}
+
+/// The serialized form of [`DiffusionDeviceControl`].
+#[derive(Serialize)]
+pub(crate) struct DeviceControlRepr {
+	version: u32,
+	vae_encoder: DiffusionDevice,
+	vae_decoder: DiffusionDevice,
+	text_encoder: DiffusionDevice,
+	unet: DiffusionDevice,
+	safety_checker: DiffusionDevice
+}
+
+/// The deserialized form of [`DiffusionDeviceControl`]. Models which are not specified are placed on the CPU.
+#[derive(Deserialize)]
+pub(crate) struct DeviceControlFile {
+	#[serde(default = "device_config_version")]
+	version: u32,
+	#[serde(default = "cpu")]
+	vae_encoder: DiffusionDevice,
+	#[serde(default = "cpu")]
+	vae_decoder: DiffusionDevice,
+	#[serde(default = "cpu")]
+	text_encoder: DiffusionDevice,
+	#[serde(default = "cpu")]
+	unet: DiffusionDevice,
+	#[serde(default = "cpu")]
+	safety_checker: DiffusionDevice,
+	/// Fields added by newer versions (or typos); these are ignored with a warning.
+	#[serde(flatten)]
+	unknown: BTreeMap<String, IgnoredAny>
+}
+
+fn device_config_version() -> u32 {
+	DEVICE_CONFIG_VERSION
+}
+
+impl From<DiffusionDeviceControl> for DeviceControlRepr {
+	fn from(devices: DiffusionDeviceControl) -> Self {
+		Self {
+			version: DEVICE_CONFIG_VERSION,
+			vae_encoder: devices.vae_encoder,
+			vae_decoder: devices.vae_decoder,
+			text_encoder: devices.text_encoder,
+			unet: devices.unet,
+			safety_checker: devices.safety_checker
+		}
+	}
+}
+
+impl From<DeviceControlFile> for DiffusionDeviceControl {
+	fn from(file: DeviceControlFile) -> Self {
+		if file.version > DEVICE_CONFIG_VERSION {
+			tracing::warn!(
+				"device configuration has version {}, but this version of pyke Diffusers only supports up to version {DEVICE_CONFIG_VERSION}; newer options will be ignored",
+				file.version
+			);
+		}
+		for field in file.unknown.keys() {
+			tracing::warn!("ignoring unknown device configuration field `{field}`");
+		}
+		Self {
+			vae_encoder: file.vae_encoder,
+			vae_decoder: file.vae_decoder,
+			text_encoder: file.text_encoder,
+			unet: file.unet,
+			safety_checker: file.safety_checker
+		}
+	}
+}
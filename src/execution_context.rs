@@ -0,0 +1,132 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use ort::{Environment, SessionBuilder};
+
+use crate::{CUDAExecutionProviderOptions, DiffusionDevice};
+
+/// Execution settings shared by every session created through it, for applications running several pipelines on the
+/// same device, e.g. a pool of workers or a base model & its refiner.
+///
+/// A context wraps the ONNX Runtime [`Environment`] together with execution provider options that override those of
+/// each pipeline's [`DiffusionDevice`]s. Without a context, every pipeline configures its execution providers from its
+/// own [`StableDiffusionOptions::devices`](crate::StableDiffusionOptions::devices), so two pipelines on the same GPU
+/// can end up with differently configured memory arenas, fragmenting VRAM. All sessions created through a context use
+/// the same allocator settings instead.
+///
+/// ONNX Runtime gives each session its own memory arena, so sessions still don't share the memory itself; a context
+/// only ensures the arenas are configured (limited & extended) the same way.
+///
+/// ```
+/// # fn main() -> anyhow::Result<()> {
+/// # use pyke_diffusers::{
+/// # 	ArenaExtendStrategy, CUDAExecutionProviderOptions, ExecutionContext, OrtEnvironment, StableDiffusionOptions,
+/// # 	StableDiffusionPipeline
+/// # };
+/// let environment = OrtEnvironment::default().into_arc();
+/// let context = ExecutionContext::new(&environment).with_cuda_options(CUDAExecutionProviderOptions {
+/// 	gpu_mem_limit: Some(6 * 1024 * 1024 * 1024),
+/// 	arena_extend_strategy: Some(ArenaExtendStrategy::SameAsRequested),
+/// 	..Default::default()
+/// });
+/// let base = StableDiffusionPipeline::new_with_context(&context, "tests/stable-diffusion", StableDiffusionOptions::default())?;
+/// let refiner = StableDiffusionPipeline::new_with_context(&context, "tests/stable-diffusion", StableDiffusionOptions::default())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ExecutionContext {
+	environment: Arc<Environment>,
+	cuda_options: Option<CUDAExecutionProviderOptions>
+}
+
+impl ExecutionContext {
+	/// Creates a context for the given environment, which doesn't override any execution provider options.
+	pub fn new(environment: &Arc<Environment>) -> Self {
+		Self {
+			environment: Arc::clone(environment),
+			cuda_options: None
+		}
+	}
+
+	/// Applies the given options to every CUDA session created through this context, in place of the options of each
+	/// [`DiffusionDevice::CUDA`] device. Device IDs are kept, so models can still be placed on different GPUs.
+	///
+	/// Note that `gpu_mem_limit` is still a limit **per session**, since each session has its own arena.
+	pub fn with_cuda_options(mut self, options: CUDAExecutionProviderOptions) -> Self {
+		self.cuda_options = Some(options);
+		self
+	}
+
+	/// Returns the ONNX Runtime environment of this context.
+	pub fn environment(&self) -> &Arc<Environment> {
+		&self.environment
+	}
+
+	/// Returns the device a session requested on `device` is actually created with, i.e. with this context's shared
+	/// options applied.
+	pub fn effective_device(&self, device: &DiffusionDevice) -> DiffusionDevice {
+		apply_shared_options(device, self.cuda_options.as_ref())
+	}
+
+	/// Creates a session builder for `device` with this context's environment & shared execution provider options, e.g.
+	/// to load additional models alongside pipelines with the same settings.
+	pub fn session_builder(&self, device: &DiffusionDevice) -> anyhow::Result<SessionBuilder> {
+		Ok(SessionBuilder::new(&self.environment)?.with_execution_providers([self.effective_device(device).into()])?)
+	}
+}
+
+impl From<&Arc<Environment>> for ExecutionContext {
+	fn from(environment: &Arc<Environment>) -> Self {
+		Self::new(environment)
+	}
+}
+
+fn apply_shared_options(device: &DiffusionDevice, cuda_options: Option<&CUDAExecutionProviderOptions>) -> DiffusionDevice {
+	match (device, cuda_options) {
+		(DiffusionDevice::CUDA(device_id, _), Some(options)) => DiffusionDevice::CUDA(*device_id, Some(options.clone())),
+		(device, _) => device.clone()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::apply_shared_options;
+	use crate::{ArenaExtendStrategy, CUDAExecutionProviderOptions, DiffusionDevice};
+
+	#[test]
+	fn shared_cuda_options_keep_device_ids() {
+		let shared = CUDAExecutionProviderOptions {
+			gpu_mem_limit: Some(1 << 30),
+			arena_extend_strategy: Some(ArenaExtendStrategy::SameAsRequested),
+			..Default::default()
+		};
+		let own = CUDAExecutionProviderOptions {
+			gpu_mem_limit: Some(1 << 20),
+			..Default::default()
+		};
+
+		let device = apply_shared_options(&DiffusionDevice::CUDA(1, Some(own.clone())), Some(&shared));
+		assert_eq!(format!("{device:?}"), format!("{:?}", DiffusionDevice::CUDA(1, Some(shared.clone()))));
+		let device = apply_shared_options(&DiffusionDevice::CUDA(0, None), Some(&shared));
+		assert_eq!(format!("{device:?}"), format!("{:?}", DiffusionDevice::CUDA(0, Some(shared))));
+
+		// other devices & contexts without shared options are unaffected
+		assert!(matches!(apply_shared_options(&DiffusionDevice::CPU, None), DiffusionDevice::CPU));
+		let device = apply_shared_options(&DiffusionDevice::CUDA(0, Some(own.clone())), None);
+		assert_eq!(format!("{device:?}"), format!("{:?}", DiffusionDevice::CUDA(0, Some(own))));
+	}
+}
@@ -55,8 +55,14 @@
 #[doc(hidden)]
 pub mod clip;
 pub(crate) mod config;
+mod device_serde;
+pub(crate) mod execution_context;
+#[cfg(test)]
+#[path = "../tests/common/fixtures.rs"]
+mod fixtures;
 pub mod pipelines;
 pub mod schedulers;
+pub(crate) mod session_tracker;
 pub(crate) mod util;
 
 use ort::CPUExecutionProviderOptions;
@@ -67,15 +73,33 @@ use ort::ExecutionProvider;
 use ort::OneDNNExecutionProviderOptions;
 use ort::ROCmExecutionProviderOptions;
 pub use ort::{ArenaExtendStrategy, CUDAExecutionProviderCuDNNConvAlgoSearch, CUDAExecutionProviderOptions};
+use serde::{Deserialize, Serialize};
 
+use self::device_serde::{DeviceControlFile, DeviceControlRepr};
+
+pub use self::clip::{LowSignalPrompts, SpecialTokenValidation, TokenizerUnavailable, TruncationStrategy};
+pub use self::config::ModelMetadata;
+pub use self::execution_context::ExecutionContext;
 pub use self::pipelines::*;
 pub use self::schedulers::*;
-pub use self::util::prompting;
+pub use self::session_tracker::{required_load_memory, InsufficientMemory, ResidentLimitExceeded, SessionInfo, SessionTracker};
+pub use self::util::{
+	compositing,
+	image_hash::{self, hamming_distance, image_hash},
+	latents::{self, average_latents, normalize_latents, visualize_latents, LatentStats},
+	merge::merge_unets,
+	onnx_info::{ComponentCompatibility, ModelCompatibilityReport, OnnxCompatibilityWarning, OnnxModelInfo, OpsetImport, OrtSupport},
+	prompt_templates, prompting
+};
 
 /// A device on which to place a diffusion model on.
 ///
 /// If a device is not specified, or a configured execution provider is not available, the model will be placed on the
 /// CPU.
+///
+/// Devices can be (de)serialized with `serde`, tagged by the name of their execution provider (`cpu`, `cuda`,
+/// `tensorrt`, `directml`, `rocm`, `onednn`, or `coreml`). See [`DiffusionDeviceControl`] for an example.
+/// [`DiffusionDevice::Custom`] devices cannot be serialized.
 #[derive(Debug, Clone)]
 #[non_exhaustive]
 pub enum DiffusionDevice {
@@ -106,6 +130,18 @@ pub enum DiffusionDevice {
 	Custom(ExecutionProvider)
 }
 
+impl DiffusionDevice {
+	/// Resolves this device to the device a session requested on it actually runs on: the device itself if its
+	/// execution provider is available, or [`DiffusionDevice::CPU`] otherwise.
+	pub(crate) fn resolve(&self) -> DiffusionDevice {
+		match self {
+			DiffusionDevice::CPU => DiffusionDevice::CPU,
+			device if ExecutionProvider::from(device.clone()).is_available() => device.clone(),
+			_ => DiffusionDevice::CPU
+		}
+	}
+}
+
 impl From<DiffusionDevice> for ExecutionProvider {
 	fn from(value: DiffusionDevice) -> Self {
 		match value {
@@ -142,7 +178,53 @@ impl From<DiffusionDevice> for ExecutionProvider {
 /// 	..Default::default()
 /// };
 /// ```
-#[derive(Debug, Clone)]
+///
+/// Device configurations can be (de)serialized with `serde`, e.g. as part of a JSON job file. Models which are not
+/// specified are placed on the CPU. Unknown fields are ignored with a warning, so configurations written by newer
+/// versions of pyke Diffusers can still be read; unknown execution providers are an error.
+/// ```
+/// # fn main() -> anyhow::Result<()> {
+/// use pyke_diffusers::{DiffusionDevice, DiffusionDeviceControl};
+///
+/// #[derive(serde::Deserialize)]
+/// struct Job {
+/// 	model: String,
+/// 	prompt: String,
+/// 	steps: usize,
+/// 	devices: DiffusionDeviceControl
+/// }
+///
+/// let job: Job = serde_json::from_str(
+/// 	r#"{
+/// 		"model": "./stable-diffusion-v1-5/",
+/// 		"prompt": "photo of a red fox",
+/// 		"steps": 20,
+/// 		"devices": {
+/// 			"version": 1,
+/// 			"unet": {
+/// 				"provider": "cuda",
+/// 				"device_id": 0,
+/// 				"options": {
+/// 					"gpu_mem_limit": 3500000000,
+/// 					"arena_extend_strategy": "same_as_requested",
+/// 					"cudnn_conv_algo_search": "heuristic"
+/// 				}
+/// 			},
+/// 			"vae_decoder": { "provider": "directml", "device_id": 1 },
+/// 			"text_encoder": { "provider": "cpu" }
+/// 		}
+/// 	}"#
+/// )?;
+/// assert!(matches!(job.devices.unet, DiffusionDevice::CUDA(0, Some(_))));
+/// assert!(matches!(job.devices.safety_checker, DiffusionDevice::CPU));
+///
+/// let err = serde_json::from_str::<DiffusionDevice>(r#"{ "provider": "vulkan" }"#).unwrap_err();
+/// assert!(err.to_string().contains("unknown variant `vulkan`"));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "DeviceControlRepr", from = "DeviceControlFile")]
 pub struct DiffusionDeviceControl {
 	/// The device on which to place the Stable Diffusion variational autoencoder.
 	pub vae_encoder: DiffusionDevice,
@@ -188,6 +270,28 @@ impl DiffusionDeviceControl {
 	}
 }
 
+impl DiffusionDeviceControl {
+	/// Resolves each requested device to the device the model will actually run on: the requested device if its
+	/// execution provider is available, or [`DiffusionDevice::CPU`] otherwise. A warning is logged for each model that
+	/// falls back to the CPU.
+	pub(crate) fn resolve(&self) -> DiffusionDeviceControl {
+		let resolve = |model: &str, device: &DiffusionDevice| -> DiffusionDevice {
+			let resolved = device.resolve();
+			if !matches!(device, DiffusionDevice::CPU) && matches!(resolved, DiffusionDevice::CPU) {
+				tracing::warn!("{model}: requested device {device:?} is not available; falling back to CPU");
+			}
+			resolved
+		};
+		DiffusionDeviceControl {
+			vae_encoder: resolve("VAE encoder", &self.vae_encoder),
+			vae_decoder: resolve("VAE decoder", &self.vae_decoder),
+			text_encoder: resolve("text encoder", &self.text_encoder),
+			unet: resolve("UNet", &self.unet),
+			safety_checker: resolve("safety checker", &self.safety_checker)
+		}
+	}
+}
+
 impl Default for DiffusionDeviceControl {
 	fn default() -> DiffusionDeviceControl {
 		DiffusionDeviceControl::all(DiffusionDevice::CPU)
@@ -14,7 +14,10 @@
 
 //! Diffusion pipelines.
 
-use std::{borrow::Cow, ops::Deref};
+use std::{borrow::Cow, fmt, ops::Deref};
+
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
 
 cfg_if::cfg_if! {
 	if #[cfg(feature = "stable-diffusion")] {
@@ -32,7 +35,8 @@ cfg_if::cfg_if! {
 /// let prompts: Prompt = ["photo of a red fox", "photo of an Arctic fox"].into();
 /// let prompts: Prompt = vec!["photo of a red fox", "photo of an Arctic fox"].into();
 /// ```
-#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Prompt(pub(crate) Vec<String>);
 
 impl Prompt {
@@ -122,3 +126,92 @@ impl From<Vec<String>> for Prompt {
 		Self(value)
 	}
 }
+
+/// Error returned when a batch of prompts and a batch of negative prompts cannot be broadcast together.
+///
+/// A batch of 1 is repeated to match the other batch, so 1 prompt with `N` negative prompts (e.g. for a negative
+/// prompt sweep), `N` prompts with 1 negative prompt, and `N` prompts with `N` negative prompts all give a batch of
+/// `N`; any other combination is an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptBatchMismatch {
+	/// The number of prompts.
+	pub prompts: usize,
+	/// The number of negative prompts.
+	pub negative_prompts: usize
+}
+
+impl fmt::Display for PromptBatchMismatch {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"cannot broadcast {} prompts against {} negative prompts; expected 1 of either, or the same number of both",
+			self.prompts, self.negative_prompts
+		)
+	}
+}
+
+impl std::error::Error for PromptBatchMismatch {}
+
+/// Returns the batch size of `prompts` prompts broadcast against `negative_prompts` negative prompts; see
+/// [`PromptBatchMismatch`].
+pub(crate) fn broadcast_prompt_batch(prompts: usize, negative_prompts: usize) -> Result<usize, PromptBatchMismatch> {
+	match (prompts, negative_prompts) {
+		(n, m) if n == m => Ok(n),
+		(1, n) | (n, 1) => Ok(n),
+		_ => Err(PromptBatchMismatch { prompts, negative_prompts })
+	}
+}
+
+/// Prompt(s) used as input in diffusion pipelines, either as text or as token IDs.
+///
+/// Token IDs are useful for models distributed without tokenizer files (see
+/// [`TokenizerUnavailable`](crate::TokenizerUnavailable)), or when prompts have already been tokenized elsewhere.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PromptInput {
+	/// Text prompt(s), which are tokenized by the pipeline's tokenizer.
+	Text(Prompt),
+	/// Token IDs of shape `(batch_size, length)`. Each row must begin with the BOS token and be padded with EOS tokens
+	/// to the tokenizer's maximum length (usually 77), exactly as produced by
+	/// [`CLIPStandardTokenizer::encode_for_text_model`](crate::clip::CLIPStandardTokenizer::encode_for_text_model).
+	///
+	/// Longer prompts may be given as `n * (length - 2) + 2` tokens, which are encoded in chunks like long prompt
+	/// weighting does. Prompt weighting syntax is not supported for token IDs.
+	TokenIds(Array2<i64>)
+}
+
+impl PromptInput {
+	/// Returns the number of prompts in the batch.
+	pub fn batch_size(&self) -> usize {
+		match self {
+			PromptInput::Text(prompt) => prompt.len(),
+			PromptInput::TokenIds(ids) => ids.nrows()
+		}
+	}
+}
+
+impl From<Prompt> for PromptInput {
+	fn from(value: Prompt) -> Self {
+		Self::Text(value)
+	}
+}
+
+impl From<Array2<i64>> for PromptInput {
+	fn from(value: Array2<i64>) -> Self {
+		Self::TokenIds(value)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{broadcast_prompt_batch, PromptBatchMismatch};
+
+	#[test]
+	fn prompt_batch_broadcasting() {
+		for (prompts, negative_prompts, batch_size) in [(1, 1, 1), (1, 3, 3), (3, 1, 3), (3, 3, 3)] {
+			assert_eq!(broadcast_prompt_batch(prompts, negative_prompts), Ok(batch_size));
+		}
+		for (prompts, negative_prompts) in [(2, 3), (3, 2), (0, 2)] {
+			assert_eq!(broadcast_prompt_batch(prompts, negative_prompts), Err(PromptBatchMismatch { prompts, negative_prompts }));
+		}
+	}
+}
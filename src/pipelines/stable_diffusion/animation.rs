@@ -0,0 +1,285 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use image::DynamicImage;
+use ndarray::{ArrayD, Axis, Slice};
+use serde::{Deserialize, Serialize};
+
+use super::{
+	batch_noise::slerp,
+	guidance_embedding::guidance_embedding_dim,
+	impl_txt2img::{draw_initial_latents, latents_shape}
+};
+use crate::{CompatibilityVersion, DiffusionScheduler, GenerationLimits, ImageRef, Prompt, RngDrawOrder, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions};
+
+/// A keyframe of an [`AnimationSpec`]: the prompt, seed & guidance scale the animation passes through.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnimationKeyframe {
+	/// The keyframe's prompt.
+	pub prompt: String,
+	/// The seed of the keyframe's initial noise.
+	pub seed: u64,
+	/// The keyframe's classifier-free guidance scale. Defaults to `7.5`.
+	pub guidance_scale: f32
+}
+
+impl AnimationKeyframe {
+	/// Creates a keyframe with the default guidance scale of `7.5`.
+	pub fn new(prompt: impl Into<String>, seed: u64) -> Self {
+		Self { prompt: prompt.into(), seed, guidance_scale: 7.5 }
+	}
+
+	/// Sets the keyframe's guidance scale.
+	pub fn with_guidance_scale(mut self, guidance_scale: f32) -> Self {
+		self.guidance_scale = guidance_scale;
+		self
+	}
+}
+
+/// A "flip-book" animation through a sequence of keyframes, rendered with [`StableDiffusionPipeline::animate`].
+///
+/// The animation starts at the first keyframe, and each following keyframe is reached after the given number of
+/// in-between frames. Frame `j` of the `n` in-between frames from keyframe `k` to `k + 1` (counting the next keyframe
+/// itself as frame `n + 1`) is generated at the blend factor `t = j / (n + 1)`:
+/// - its text embeddings are linearly interpolated between the keyframes' embeddings, which are encoded only once per
+///   keyframe;
+/// - its initial noise is [spherically interpolated](crate::BatchNoiseMode::Slerp) between the noise of the keyframes'
+///   seeds, so consecutive frames start from adjacent noise;
+/// - its guidance scale is linearly interpolated;
+/// - its seed, which seeds the scheduler's RNG, is the seed of the nearer keyframe.
+///
+/// Keyframes are rendered exactly like a plain generation with their prompt, seed & guidance scale, and the whole
+/// animation is deterministic.
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// # use pyke_diffusers::{
+/// # 	AnimationKeyframe, AnimationSpec, EulerDiscreteScheduler, OrtEnvironment, SchedulerOptimizedDefaults, StableDiffusionOptions,
+/// # 	StableDiffusionPipeline
+/// # };
+/// # let environment = OrtEnvironment::default().into_arc();
+/// # let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+/// let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+/// let keyframes = vec![AnimationKeyframe::new("a fox in spring", 42), AnimationKeyframe::new("a fox in winter", 1337)];
+/// let spec = AnimationSpec::new(keyframes).with_frames_between(vec![22]);
+/// for frame in pipeline.animate(&mut scheduler, &spec)? {
+/// 	frame.image.save(format!("frame-{:03}.png", frame.info.index))?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnimationSpec {
+	/// The keyframes, in order. At least one keyframe is required.
+	pub keyframes: Vec<AnimationKeyframe>,
+	/// The number of in-between frames from each keyframe to the next; must have one entry less than
+	/// [`keyframes`](Self::keyframes).
+	pub frames_between: Vec<usize>,
+	/// The negative prompt shared by all frames.
+	pub negative_prompt: Option<String>,
+	/// The width of the frames. **Must be divisible by 8.** Defaults to `512`.
+	pub width: u32,
+	/// The height of the frames. **Must be divisible by 8.** Defaults to `512`.
+	pub height: u32,
+	/// The number of denoising steps of each frame. Defaults to `25`.
+	pub steps: usize
+}
+
+impl AnimationSpec {
+	/// Creates an animation through the given keyframes, without in-between frames.
+	pub fn new(keyframes: Vec<AnimationKeyframe>) -> Self {
+		Self {
+			frames_between: vec![0; keyframes.len().saturating_sub(1)],
+			keyframes,
+			negative_prompt: None,
+			width: 512,
+			height: 512,
+			steps: 25
+		}
+	}
+
+	/// Sets the number of in-between frames from each keyframe to the next.
+	pub fn with_frames_between(mut self, frames_between: Vec<usize>) -> Self {
+		self.frames_between = frames_between;
+		self
+	}
+
+	/// Sets the negative prompt shared by all frames.
+	pub fn with_negative_prompt(mut self, negative_prompt: impl Into<String>) -> Self {
+		self.negative_prompt = Some(negative_prompt.into());
+		self
+	}
+
+	/// Sets the size of the frames. **Size will be rounded to a multiple of 8.**
+	pub fn with_size(mut self, width: u32, height: u32) -> Self {
+		self.width = (width / 8).max(1) * 8;
+		self.height = (height / 8).max(1) * 8;
+		self
+	}
+
+	/// Sets the number of denoising steps of each frame.
+	pub fn with_steps(mut self, steps: usize) -> Self {
+		self.steps = steps;
+		self
+	}
+
+	/// Returns the total number of frames of the animation, including the keyframes, or `None` if it overflows
+	/// `usize`.
+	pub fn frame_count(&self) -> Option<usize> {
+		self.frames_between
+			.iter()
+			.try_fold(self.keyframes.len().min(1), |count, &frames| frames.checked_add(1).and_then(|frames| count.checked_add(frames)))
+	}
+
+	/// Returns the keyframe index each frame blends from & its blend factor towards the next keyframe. Frames are
+	/// produced lazily, since their number comes from the (possibly untrusted) spec; only call after validating it.
+	fn frames(&self) -> impl Iterator<Item = (usize, f32)> + '_ {
+		std::iter::once((0, 0.0)).chain(
+			self.frames_between
+				.iter()
+				.enumerate()
+				.flat_map(|(keyframe, &between)| (1..=between + 1).map(move |j| (keyframe, j as f32 / (between + 1) as f32)))
+		)
+	}
+
+	fn validate(&self, limits: &GenerationLimits) -> anyhow::Result<()> {
+		if self.keyframes.is_empty() {
+			anyhow::bail!("an animation needs at least one keyframe");
+		}
+		if self.frames_between.len() + 1 != self.keyframes.len() {
+			anyhow::bail!("`frames_between` has {} entries, expected one less than the {} keyframes", self.frames_between.len(), self.keyframes.len());
+		}
+		let frames = self.frame_count().ok_or_else(|| anyhow::anyhow!("the animation has more than `usize::MAX` frames"))?;
+		limits.check_frames(frames)?;
+		// every frame is generated like a single image, so oversized frames are rejected before encoding any prompt
+		limits.check(self.width, self.height, 1, self.steps)?;
+		Ok(())
+	}
+}
+
+/// The parameters a frame of an animation was generated with, for reproducing it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnimationFrameInfo {
+	/// The index of the frame in the animation.
+	pub index: usize,
+	/// The index of the keyframe the frame blends from. Keyframes other than the first are recorded as the previous
+	/// keyframe with a blend factor of `1.0`.
+	pub keyframe: usize,
+	/// How far the frame is from keyframe [`keyframe`](Self::keyframe) to the next, from `0.0` to `1.0`.
+	pub blend: f32,
+	/// The seed of the frame's initial noise at a blend factor of `0.0`.
+	pub from_seed: u64,
+	/// The seed of the frame's initial noise at a blend factor of `1.0`.
+	pub to_seed: u64,
+	/// The seed passed to the scheduler, i.e. the seed of the nearer keyframe.
+	pub seed: u64,
+	/// The frame's interpolated guidance scale.
+	pub guidance_scale: f32
+}
+
+/// A frame rendered by [`StableDiffusionPipeline::animate`].
+#[derive(Debug, Clone)]
+pub struct AnimationFrame {
+	/// The frame's image.
+	pub image: DynamicImage,
+	/// The parameters the frame was generated with.
+	pub info: AnimationFrameInfo
+}
+
+impl StableDiffusionPipeline {
+	/// Renders a [flip-book animation](AnimationSpec), returning its frames in order.
+	///
+	/// All frames are rendered with the pipeline's loaded sessions. See also
+	/// [`StableDiffusionPipeline::animate_with_callback`] to stream frames as they are rendered.
+	pub fn animate<S: DiffusionScheduler>(&self, scheduler: &mut S, spec: &AnimationSpec) -> anyhow::Result<Vec<AnimationFrame>> {
+		self.animate_with_callback(scheduler, spec, |_| true)
+	}
+
+	/// Renders a [flip-book animation](AnimationSpec) like [`StableDiffusionPipeline::animate`], calling `callback`
+	/// with each frame as soon as it is rendered, e.g. to feed it to a video encoder. If the callback returns `false`,
+	/// rendering stops after that frame, and the frames rendered so far are returned.
+	pub fn animate_with_callback<S, F>(&self, scheduler: &mut S, spec: &AnimationSpec, mut callback: F) -> anyhow::Result<Vec<AnimationFrame>>
+	where
+		S: DiffusionScheduler,
+		F: FnMut(&AnimationFrame) -> bool
+	{
+		spec.validate(&self.options.limits)?;
+		let negative_prompt = spec.negative_prompt.as_deref().map(Prompt::from);
+		// encoded with the negative prompts, so the embeddings serve frames with & without classifier-free guidance
+		let embeddings = spec
+			.keyframes
+			.iter()
+			.map(|keyframe| self.encode_prompt(keyframe.prompt.as_str().into(), true, negative_prompt.as_ref()))
+			.collect::<anyhow::Result<Vec<_>>>()?;
+		if let Some(i) = embeddings.iter().position(|keyframe| keyframe.shape() != embeddings[0].shape()) {
+			anyhow::bail!(
+				"keyframe #{}'s prompt encodes to embeddings of shape {:?}, but the first keyframe's to {:?}; long prompts must span the same number of chunks",
+				i + 1,
+				embeddings[i].shape(),
+				embeddings[0].shape()
+			);
+		}
+		let guidance_distilled = guidance_embedding_dim(self)?.is_some();
+		let shape = latents_shape(1, spec.height, spec.width);
+		let noise = |seed| draw_initial_latents(CompatibilityVersion::default(), RngDrawOrder::default(), seed, shape).0;
+
+		let mut frames = Vec::new();
+		for (index, (keyframe, blend)) in spec.frames().enumerate() {
+			let next = (keyframe + 1).min(spec.keyframes.len() - 1);
+			let (from, to) = (&spec.keyframes[keyframe], &spec.keyframes[next]);
+			let guidance_scale = from.guidance_scale + (to.guidance_scale - from.guidance_scale) * blend;
+			let seed = if blend < 0.5 { from.seed } else { to.seed };
+
+			let mut text_embeddings: ArrayD<f32> = &embeddings[keyframe] * (1.0 - blend) + &embeddings[next] * blend;
+			if guidance_distilled || guidance_scale <= 1.0 {
+				// only the positive half
+				let batch_size = text_embeddings.len_of(Axis(0));
+				text_embeddings = text_embeddings.slice_axis(Axis(0), Slice::from(batch_size / 2..)).to_owned();
+			}
+			scheduler.set_timesteps(spec.steps);
+			let latents = slerp(noise(from.seed).view(), noise(to.seed).view(), blend) * scheduler.init_noise_sigma();
+
+			let mut options = StableDiffusionTxt2ImgOptions::default()
+				.with_size(spec.width, spec.height)
+				.with_steps(spec.steps)
+				.with_seed(seed)
+				.with_guidance_scale(guidance_scale)
+				.with_prompt(if blend < 0.5 { from.prompt.as_str() } else { to.prompt.as_str() })
+				.with_prompt_embeddings(text_embeddings)
+				.with_latents(latents);
+			if let Some(negative_prompt) = spec.negative_prompt.as_deref() {
+				options = options.with_negative_prompt(negative_prompt);
+			}
+			let image = options.run_with_output(self, scheduler)?.images.into_iter().next().map(ImageRef::into_image).transpose()?;
+			let frame = AnimationFrame {
+				image: image.ok_or_else(|| anyhow::anyhow!("frame {index} produced no image"))?,
+				info: AnimationFrameInfo {
+					index,
+					keyframe,
+					blend,
+					from_seed: from.seed,
+					to_seed: to.seed,
+					seed,
+					guidance_scale
+				}
+			};
+			let proceed = callback(&frame);
+			frames.push(frame);
+			if !proceed {
+				break;
+			}
+		}
+		Ok(frames)
+	}
+}
@@ -0,0 +1,259 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ndarray::{s, Array1, Array3, Array4, ArrayView2, ArrayView3, ArrayView4, ArrayViewD, Axis};
+use ndarray_rand::{
+	rand::rngs::StdRng,
+	rand_distr::Bernoulli,
+	RandomExt
+};
+use num_traits::ToPrimitive;
+
+use super::lpw;
+use crate::{DiffusionScheduler, Prompt, PromptWeighting, StableDiffusionPipeline};
+
+/// The name of the UNet output containing cross-attention maps, required for Attend-and-Excite.
+pub(crate) const ATTENTION_MAPS_OUTPUT: &str = "attention_maps";
+
+/// Options for [Attend-and-Excite](https://arxiv.org/abs/2301.13826), which nudges the latents during early steps so
+/// that every subject in the prompt actually appears in the image.
+///
+/// At each of the first [`steps`](AttendAndExciteOptions::steps) steps, the attention of each subject token is
+/// measured, and the latents are updated to strengthen the attention of the most neglected subject. Only the attention
+/// of the prompt's text tokens is considered; the BOS, EOS & padding tokens are masked out, so Attend-and-Excite needs
+/// a text prompt or prompt token IDs, not prompt embeddings.
+///
+/// # Export requirements
+/// Attend-and-Excite requires the UNet to be exported with an additional output named `attention_maps`, of shape
+/// `(batch_size, tokens, height, width)`, containing the cross-attention probabilities between each latent pixel and
+/// each token of `encoder_hidden_states`, averaged over all heads & all cross-attention layers of one resolution (the
+/// original implementation uses the 16x16 layers for 512x512 images). Pipelines without this output fail with an error
+/// when Attend-and-Excite is enabled.
+///
+/// ONNX Runtime cannot compute gradients through the UNet, so unlike the original implementation, the gradient of the
+/// attention loss w.r.t. the latents is estimated by evaluating the loss at two randomly perturbed copies of the
+/// latents ([SPSA](https://en.wikipedia.org/wiki/Simultaneous_perturbation_stochastic_approximation)). Each
+/// Attend-and-Excite step therefore costs 2 extra UNet evaluations on the conditional half of the batch. The
+/// perturbations are drawn from an RNG seeded with the generation seed, so results remain reproducible.
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// # use pyke_diffusers::{AttendAndExciteOptions, StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+/// # let environment = OrtEnvironment::default().into_arc();
+/// # let mut scheduler = EulerDiscreteScheduler::default();
+/// # let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5-attention/", StableDiffusionOptions::default())?;
+/// // token 0 is the BOS token: `a`=1, `cat`=2, `and`=3, `a`=4, `frog`=5
+/// let imgs = StableDiffusionTxt2ImgOptions::default()
+/// 	.with_prompt("a cat and a frog")
+/// 	.with_attend_and_excite(AttendAndExciteOptions::new([2, 5]))
+/// 	.run(&pipeline, &mut scheduler)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttendAndExciteOptions {
+	/// Indices of the subject tokens in the encoded prompt, where index 0 is the BOS token.
+	pub token_indices: Vec<usize>,
+	/// The number of initial steps to apply Attend-and-Excite to. Defaults to 10.
+	pub steps: usize,
+	/// The size of the latent update; decays to half over the course of the Attend-and-Excite steps. Defaults to 20.
+	pub step_size: f32,
+	/// The magnitude of the random perturbations used to estimate the gradient. Defaults to 0.01.
+	pub perturbation: f32
+}
+
+impl AttendAndExciteOptions {
+	/// Creates new Attend-and-Excite options for the given subject token indices, using the default parameters.
+	pub fn new(token_indices: impl IntoIterator<Item = usize>) -> Self {
+		Self {
+			token_indices: token_indices.into_iter().collect(),
+			steps: 10,
+			step_size: 20.0,
+			perturbation: 0.01
+		}
+	}
+
+	/// Sets the number of initial steps to apply Attend-and-Excite to.
+	pub fn with_steps(mut self, steps: usize) -> Self {
+		self.steps = steps;
+		self
+	}
+
+	/// Sets the size of the latent update.
+	pub fn with_step_size(mut self, step_size: f32) -> Self {
+		self.step_size = step_size;
+		self
+	}
+
+	/// Checks the token indices against the `num_tokens` tokens of the text embeddings & the number of text tokens of
+	/// each image's prompt.
+	pub(crate) fn validate(&self, num_tokens: usize, text_lengths: &[usize]) -> anyhow::Result<()> {
+		if self.token_indices.is_empty() {
+			anyhow::bail!("Attend-and-Excite requires at least one subject token index");
+		}
+		if let Some(&index) = self.token_indices.iter().find(|&&index| index == 0 || index >= num_tokens) {
+			anyhow::bail!("Attend-and-Excite subject token index {index} is out of range; expected 1..{num_tokens}");
+		}
+		let max_index = self.token_indices.iter().copied().max().unwrap_or(0);
+		if let Some(&length) = text_lengths.iter().find(|&&length| max_index > length) {
+			anyhow::bail!("Attend-and-Excite subject token index {max_index} is beyond the end of a prompt with {length} tokens");
+		}
+		Ok(())
+	}
+}
+
+/// Computes the Attend-and-Excite loss for each image from cross-attention maps of shape
+/// `(batch_size, tokens, height, width)`: `1 - max attention` of the most neglected subject token.
+///
+/// As in the original implementation, only the `text_lengths[b]` text tokens of each image are kept (excluding the
+/// BOS, EOS & padding tokens), and their attention is re-normalized (via a sharpened softmax) before smoothing each
+/// subject's map with a 3x3 box filter.
+pub(crate) fn excitation_loss(attention_maps: ArrayView4<'_, f32>, token_indices: &[usize], text_lengths: &[usize]) -> Array1<f32> {
+	Array1::from_iter(attention_maps.axis_iter(Axis(0)).zip(text_lengths).map(|(maps, &length)| {
+		let maps = maps.slice(s![1..(length + 1).min(maps.shape()[0]), .., ..]);
+		let normalized = softmax_tokens(maps, 100.0);
+		token_indices
+			.iter()
+			.map(|&index| 1.0 - max_smoothed(normalized.index_axis(Axis(0), index - 1)))
+			.fold(0.0_f32, f32::max)
+	}))
+}
+
+/// Softmax over the token axis of `(tokens, height, width)` maps.
+fn softmax_tokens(maps: ArrayView3<'_, f32>, temperature: f32) -> Array3<f32> {
+	let mut out = maps.mapv(|x| x * temperature);
+	for mut pixel in out.lanes_mut(Axis(0)) {
+		let max = pixel.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+		pixel.mapv_inplace(|x| (x - max).exp());
+		let sum = pixel.sum();
+		pixel /= sum;
+	}
+	out
+}
+
+/// The maximum of a `(height, width)` map after 3x3 box smoothing (with edge clamping).
+fn max_smoothed(map: ArrayView2<'_, f32>) -> f32 {
+	let (height, width) = map.dim();
+	let mut max = f32::NEG_INFINITY;
+	for y in 0..height {
+		for x in 0..width {
+			let (y0, y1, x0, x1) = (y.saturating_sub(1), (y + 2).min(height), x.saturating_sub(1), (x + 2).min(width));
+			let window = map.slice(s![y0..y1, x0..x1]);
+			max = max.max(window.sum() / window.len() as f32);
+		}
+	}
+	max
+}
+
+/// Performs one Attend-and-Excite latent update at step `step`, returning the updated latents.
+///
+/// `encoder_hidden_states` must only contain the conditional text embeddings.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn attend_and_excite_step<S: DiffusionScheduler>(
+	session: &StableDiffusionPipeline,
+	scheduler: &mut S,
+	options: &AttendAndExciteOptions,
+	latents: ArrayView4<'_, f32>,
+	timestep: S::TimestepType,
+	step: usize,
+	encoder_hidden_states: ArrayViewD<'_, f32>,
+	text_lengths: &[usize],
+	rng: &mut StdRng
+) -> anyhow::Result<Array4<f32>> {
+	let timestep_f32 = timestep.to_f32().unwrap();
+	let mut loss_at = |sample: &Array4<f32>| -> anyhow::Result<Array1<f32>> {
+		let latent_model_input = scheduler.scale_model_input(sample.view(), timestep);
+		let (_, attention_maps) = session.predict_noise_with_attention(latent_model_input.view(), timestep_f32, encoder_hidden_states.view())?;
+		let num_tokens = attention_maps.shape()[1];
+		if let Some(&index) = options.token_indices.iter().find(|&&index| index >= num_tokens) {
+			anyhow::bail!("Attend-and-Excite subject token index {index} is out of range for the UNet's attention maps with {num_tokens} tokens");
+		}
+		Ok(excitation_loss(attention_maps.view(), &options.token_indices, text_lengths))
+	};
+
+	let delta = Array4::<bool>::random_using(latents.raw_dim(), Bernoulli::new(0.5).unwrap(), rng).mapv(|b| if b { 1.0_f32 } else { -1.0 });
+	let delta_scaled = options.perturbation * &delta;
+	let loss_pos = loss_at(&(&latents + &delta_scaled))?;
+	let loss_neg = loss_at(&(&latents - &delta_scaled))?;
+
+	let progress = if options.steps > 1 { step as f32 / (options.steps - 1) as f32 } else { 0.0 };
+	let step_size = options.step_size * (1.0 - 0.5 * progress).sqrt();
+
+	let mut latents = latents.to_owned();
+	for (b, mut latent) in latents.axis_iter_mut(Axis(0)).enumerate() {
+		let gradient_scale = (loss_pos[b] - loss_neg[b]) / (2.0 * options.perturbation);
+		latent.scaled_add(-step_size * gradient_scale, &delta.index_axis(Axis(0), b));
+	}
+	Ok(latents)
+}
+
+impl StableDiffusionPipeline {
+	/// Returns the number of text tokens of each (filtered) prompt, excluding BOS, EOS & padding tokens, for
+	/// [`excitation_loss`].
+	pub(crate) fn prompt_text_lengths(&self, prompt: Prompt, weighting: &[PromptWeighting]) -> anyhow::Result<Vec<usize>> {
+		let prompt = self.filter_prompt(prompt)?;
+		// up to 3 chunks, like `encode_prompt_with_weighting`
+		lpw::count_prompt_tokens(&self.text_embeddings, prompt, weighting, 3)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ndarray::{s, Array4};
+
+	use super::{excitation_loss, AttendAndExciteOptions};
+
+	#[test]
+	fn neglected_subject_dominates_loss() {
+		// 4 tokens (BOS, subject 1, subject 2, EOS) over a 4x4 latent
+		let mut maps = Array4::<f32>::zeros((2, 4, 4, 4));
+		// image 0: both subjects attend strongly somewhere
+		maps[[0, 1, 0, 0]] = 1.0;
+		maps[[0, 2, 3, 3]] = 1.0;
+		// image 1: subject 2 is neglected everywhere
+		maps[[1, 1, 0, 0]] = 1.0;
+
+		let loss = excitation_loss(maps.view(), &[1, 2], &[2, 2]);
+		assert!(loss[1] > loss[0], "{loss:?}");
+		assert!(loss[0] >= 0.0 && loss[1] <= 1.0);
+
+		// a single subject token's loss doesn't depend on the other subject
+		let loss = excitation_loss(maps.view(), &[1], &[2, 2]);
+		assert!((loss[0] - loss[1]).abs() < 1e-6, "{loss:?}");
+	}
+
+	#[test]
+	fn eos_and_padding_are_masked() {
+		// 6 tokens (BOS, subject 1, subject 2, EOS, padding, padding) over a 4x4 latent
+		let mut maps = Array4::<f32>::zeros((1, 6, 4, 4));
+		maps[[0, 1, 0, 0]] = 1.0;
+		maps[[0, 2, 3, 3]] = 1.0;
+		let expected = excitation_loss(maps.view(), &[1, 2], &[2]);
+
+		// EOS & padding tokens attending strongly everywhere would otherwise take the subjects' share of the softmax
+		maps.slice_mut(s![0, 3.., .., ..]).fill(1.0);
+		let loss = excitation_loss(maps.view(), &[1, 2], &[2]);
+		assert!((loss[0] - expected[0]).abs() < 1e-6, "{loss:?} != {expected:?}");
+	}
+
+	#[test]
+	fn validate_token_indices() {
+		assert!(AttendAndExciteOptions::new([1, 3]).validate(77, &[3]).is_ok());
+		assert!(AttendAndExciteOptions::new([]).validate(77, &[3]).is_err());
+		assert!(AttendAndExciteOptions::new([0]).validate(77, &[3]).is_err());
+		assert!(AttendAndExciteOptions::new([77]).validate(77, &[75]).is_err());
+		// index 3 would be the EOS token of the second prompt
+		assert!(AttendAndExciteOptions::new([1, 3]).validate(77, &[3, 2]).is_err());
+	}
+}
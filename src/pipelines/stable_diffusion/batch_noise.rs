@@ -0,0 +1,169 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ndarray::{s, Array4, ArrayView4, Zip};
+use serde::{Deserialize, Serialize};
+
+use super::{impl_main::fnv1a, impl_txt2img::draw_initial_latents};
+use crate::{CompatibilityVersion, RngDrawOrder};
+
+/// How the initial noise of the images in a batch relates to each other; see
+/// [`StableDiffusionTxt2ImgOptions::with_batch_noise_mode`](crate::StableDiffusionTxt2ImgOptions::with_batch_noise_mode).
+///
+/// With [`BatchNoiseMode::Slerp`] & [`BatchNoiseMode::SharedWithJitter`], the initial noise only depends on the seeds
+/// given in the mode: the generation's own seed still seeds the scheduler's RNG, but no longer the noise. Each seed's
+/// noise is drawn exactly like the noise of a single image generated with that seed.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchNoiseMode {
+	/// Each image of the batch gets its own independent noise from the generation's seed. **This is the default.**
+	#[default]
+	Independent,
+	/// Spherically interpolates the noise from the noise of `from_seed` (the first image) to the noise of `to_seed`
+	/// (the last image), in equal steps across the batch. Consecutive images start from adjacent noise, so they change
+	/// gradually, e.g. to render the frames of a seed morph.
+	///
+	/// Spherical interpolation (slerp) of the flattened noise keeps interpolated noise at the norm expected of Gaussian
+	/// noise, whereas linear interpolation would shrink it towards the middle of the batch, producing washed out
+	/// images.
+	Slerp { from_seed: u64, to_seed: u64 },
+	/// Starts every image from the noise of `seed`, mixed with independent noise of relative strength `jitter`: image
+	/// `i` starts from `(noise + jitter * noise_i) / sqrt(1 + jitter²)`, which keeps unit variance. Small jitters (e.g.
+	/// `0.1`) give a batch of close variations of one image.
+	SharedWithJitter { seed: u64, jitter: f32 }
+}
+
+impl BatchNoiseMode {
+	pub(crate) fn validate(&self) -> anyhow::Result<()> {
+		if let BatchNoiseMode::SharedWithJitter { jitter, .. } = self {
+			if !jitter.is_finite() || *jitter < 0.0 {
+				anyhow::bail!("batch noise `jitter` must be a finite, non-negative number, got {jitter}");
+			}
+		}
+		Ok(())
+	}
+
+	/// Replaces the (unscaled) initial noise of a batch according to this mode. Latents of
+	/// [`BatchNoiseMode::Independent`] batches are left unchanged.
+	pub(crate) fn apply(&self, compatibility_version: CompatibilityVersion, rng_draw_order: RngDrawOrder, latents: &mut Array4<f32>) {
+		let (batch_size, channels, height, width) = latents.dim();
+		let noise = |seed| draw_initial_latents(compatibility_version, rng_draw_order, seed, (1, channels, height, width)).0;
+		match *self {
+			BatchNoiseMode::Independent => {}
+			BatchNoiseMode::Slerp { from_seed, to_seed } => {
+				let (from, to) = (noise(from_seed), noise(to_seed));
+				for i in 0..batch_size {
+					let t = if batch_size > 1 { i as f32 / (batch_size - 1) as f32 } else { 0.0 };
+					latents.slice_mut(s![i..i + 1, .., .., ..]).assign(&slerp(from.view(), to.view(), t));
+				}
+			}
+			BatchNoiseMode::SharedWithJitter { seed, jitter } => {
+				let shared = noise(seed);
+				let scale = (1.0 + jitter * jitter).sqrt().recip();
+				for i in 0..batch_size {
+					let jitter_seed = fnv1a(seed.to_le_bytes().into_iter().chain((i as u64).to_le_bytes()).chain(*b"jitter"));
+					let mut image = latents.slice_mut(s![i..i + 1, .., .., ..]);
+					Zip::from(&mut image).and(&shared).and(&noise(jitter_seed)).for_each(|x, &shared, &jitter_noise| {
+						*x = (shared + jitter * jitter_noise) * scale;
+					});
+				}
+			}
+		}
+	}
+}
+
+/// Spherically interpolates between `from` (at `t = 0`) and `to` (at `t = 1`), treating both as flattened vectors.
+/// Falls back to linear interpolation for (nearly) parallel vectors, where the angle between them is too small for
+/// slerp to be numerically stable.
+pub(crate) fn slerp(from: ArrayView4<'_, f32>, to: ArrayView4<'_, f32>, t: f32) -> Array4<f32> {
+	if t == 0.0 {
+		return from.to_owned();
+	} else if t == 1.0 {
+		return to.to_owned();
+	}
+
+	let norm = |x: ArrayView4<'_, f32>| x.iter().map(|&x| x as f64 * x as f64).sum::<f64>().sqrt();
+	let dot = from.iter().zip(to.iter()).map(|(&a, &b)| a as f64 * b as f64).sum::<f64>();
+	let cos_theta = (dot / (norm(from) * norm(to))).clamp(-1.0, 1.0);
+	let t = t as f64;
+	let (weight_from, weight_to) = if cos_theta.abs() > 0.9995 {
+		(1.0 - t, t)
+	} else {
+		let theta = cos_theta.acos();
+		(((1.0 - t) * theta).sin() / theta.sin(), (t * theta).sin() / theta.sin())
+	};
+	let mut interpolated = Array4::zeros(from.raw_dim());
+	Zip::from(&mut interpolated).and(&from).and(&to).for_each(|x, &a, &b| {
+		*x = (weight_from * a as f64 + weight_to * b as f64) as f32;
+	});
+	interpolated
+}
+
+#[cfg(test)]
+mod tests {
+	use ndarray::{s, Array4};
+
+	use super::{
+		super::impl_txt2img::{draw_initial_latents, latents_shape},
+		slerp, BatchNoiseMode
+	};
+	use crate::{CompatibilityVersion, RngDrawOrder};
+
+	fn batch(mode: BatchNoiseMode, batch_size: usize) -> Array4<f32> {
+		let (mut latents, _) = draw_initial_latents(CompatibilityVersion::Latest, RngDrawOrder::default(), 1, latents_shape(batch_size, 512, 512));
+		mode.apply(CompatibilityVersion::Latest, RngDrawOrder::default(), &mut latents);
+		latents
+	}
+
+	fn noise(seed: u64) -> Array4<f32> {
+		draw_initial_latents(CompatibilityVersion::Latest, RngDrawOrder::default(), seed, latents_shape(1, 512, 512)).0
+	}
+
+	#[test]
+	fn slerp_endpoints_are_seed_noise() {
+		let latents = batch(BatchNoiseMode::Slerp { from_seed: 42, to_seed: 1337 }, 5);
+		assert_eq!(latents.slice(s![..1, .., .., ..]), noise(42));
+		assert_eq!(latents.slice(s![4.., .., .., ..]), noise(1337));
+		for i in 1..4 {
+			let std = latents.slice(s![i, .., .., ..]).std(0.0);
+			assert!((std - 1.0).abs() < 0.05, "image {i} has standard deviation {std}");
+			// consecutive images are closer to each other than the endpoints are
+			let step = (&latents.slice(s![i, .., .., ..]) - &latents.slice(s![i - 1, .., .., ..])).mapv(|x| x * x).sum();
+			assert!(step < (&noise(42) - &noise(1337)).mapv(|x| x * x).sum() / 2.0);
+		}
+
+		// a single image starts from `from_seed`
+		assert_eq!(batch(BatchNoiseMode::Slerp { from_seed: 42, to_seed: 1337 }, 1), noise(42));
+	}
+
+	#[test]
+	fn slerp_of_parallel_noise() {
+		let a = noise(7);
+		let halfway = slerp(a.view(), (&a * 2.0).view(), 0.5);
+		assert!(halfway.iter().zip(a.iter()).all(|(&x, &a)| (x - 1.5 * a).abs() < 1e-5));
+	}
+
+	#[test]
+	fn shared_noise_with_jitter() {
+		assert_eq!(batch(BatchNoiseMode::SharedWithJitter { seed: 42, jitter: 0.0 }, 3), noise(42).broadcast((3, 4, 64, 64)).unwrap());
+
+		let latents = batch(BatchNoiseMode::SharedWithJitter { seed: 42, jitter: 0.1 }, 3);
+		assert_ne!(latents.slice(s![0, .., .., ..]), latents.slice(s![1, .., .., ..]));
+		for i in 0..3 {
+			let std = latents.slice(s![i, .., .., ..]).std(0.0);
+			assert!((std - 1.0).abs() < 0.05, "image {i} has standard deviation {std}");
+		}
+		assert!(BatchNoiseMode::SharedWithJitter { seed: 42, jitter: -1.0 }.validate().is_err());
+	}
+}
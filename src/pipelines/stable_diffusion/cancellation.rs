@@ -0,0 +1,113 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc
+	},
+	time::Instant
+};
+
+use crate::{StableDiffusionOutput, StableDiffusionTxt2ImgOptions};
+
+/// Why a generation stopped, as recorded in [`StableDiffusionOutput::stop_reason`].
+///
+/// Every way of stopping before the last step is handled the same way: the denoising loop stops before its next step,
+/// the latents it reached are returned in [`StableDiffusionOutput::latents`], and the VAE decoder does not run unless
+/// enabled with [`StableDiffusionTxt2ImgOptions::with_decode_on_early_stop`], so stopping never costs a decode the
+/// caller didn't ask for. Sessions are never dropped or reloaded when a generation stops; the pipeline can be used
+/// again right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+	/// Every step was taken (or generation [exited early](StableDiffusionTxt2ImgOptions::with_early_exit) because the
+	/// latents converged), and the images were decoded.
+	Completed,
+	/// A [`StableDiffusionCallback`](crate::StableDiffusionCallback) returned `false`.
+	CallbackStop,
+	/// The [`CancellationToken`] given with [`StableDiffusionTxt2ImgOptions::with_cancellation_token`] was cancelled.
+	Cancelled,
+	/// The [timeout](StableDiffusionTxt2ImgOptions::with_timeout) elapsed.
+	TimedOut,
+	/// Generation failed with an error. Failed generations return the error instead of an output, so this is never
+	/// recorded in an output; it is only returned by [`StopReason::of`].
+	Error
+}
+
+impl StopReason {
+	/// Returns the stop reason of a generation's result, i.e. [`StopReason::Error`] for errors and the output's
+	/// [`stop_reason`](StableDiffusionOutput::stop_reason) otherwise.
+	pub fn of(result: &anyhow::Result<StableDiffusionOutput>) -> Self {
+		match result {
+			Ok(output) => output.stop_reason,
+			Err(_) => StopReason::Error
+		}
+	}
+
+	/// Whether generation stopped before its last step.
+	pub fn is_early(&self) -> bool {
+		!matches!(self, StopReason::Completed)
+	}
+}
+
+/// A token to cancel a running generation from another thread; see
+/// [`StableDiffusionTxt2ImgOptions::with_cancellation_token`].
+///
+/// Clones of a token share its state, so a clone can be kept to cancel the generation that was given the token.
+/// Cancellation is checked before each denoising step, so a generation stops within one step of being cancelled.
+///
+/// ```
+/// # use pyke_diffusers::{CancellationToken, StableDiffusionTxt2ImgOptions};
+/// let token = CancellationToken::new();
+/// let options = StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox").with_cancellation_token(token.clone());
+/// // on another thread, e.g. when the client disconnects:
+/// token.cancel();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+	/// Creates a new token which has not been cancelled.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Cancels every generation given this token or one of its clones.
+	pub fn cancel(&self) {
+		self.0.store(true, Ordering::Relaxed);
+	}
+
+	/// Returns whether the token has been cancelled.
+	pub fn is_cancelled(&self) -> bool {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+impl StableDiffusionTxt2ImgOptions {
+	/// Returns the deadline of a denoising loop starting now, if a timeout is set.
+	pub(crate) fn deadline(&self) -> Option<Instant> {
+		self.timeout.map(|timeout| Instant::now() + timeout)
+	}
+
+	/// Checks whether the denoising loop must stop before its next step because it was cancelled or timed out.
+	pub(crate) fn interruption(&self, deadline: Option<Instant>) -> Option<StopReason> {
+		if self.cancellation_token.as_ref().map_or(false, CancellationToken::is_cancelled) {
+			Some(StopReason::Cancelled)
+		} else if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+			Some(StopReason::TimedOut)
+		} else {
+			None
+		}
+	}
+}
@@ -0,0 +1,115 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::{Deserialize, Serialize};
+
+use super::{guidance_embedding::guidance_embedding_dim, inpaint::validate_inpaint_unet, reference_attention::check_reference_unet};
+use crate::StableDiffusionPipeline;
+
+/// The features supported by a loaded model, as returned by [`StableDiffusionPipeline::capabilities`].
+///
+/// Each flag is derived from the same checks the corresponding feature performs before running, so a feature whose
+/// flag is `false` fails with an error explaining what the model is missing. This lets frontends hide or disable the
+/// controls of unsupported features up front. Capabilities can be serialized, e.g. to send them to a web frontend:
+///
+/// ```
+/// # fn main() -> anyhow::Result<()> {
+/// # use pyke_diffusers::{StableDiffusionPipeline, StableDiffusionOptions, OrtEnvironment};
+/// # let environment = OrtEnvironment::default().into_arc();
+/// let pipeline = StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default())?;
+/// let json = serde_json::to_string(&pipeline.capabilities())?;
+/// assert!(json.contains("\"txt2img\":true"));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+	/// Text-to-image generation. Always supported.
+	pub txt2img: bool,
+	/// Encoding images into latents, which requires a VAE encoder. Needed by
+	/// [`StableDiffusionPipeline::encode_images`] and everything built on it: img2img-style refinement, creating
+	/// [`InpaintOptions`](crate::InpaintOptions) from images, reference images &
+	/// [super-resolution](StableDiffusionPipeline::super_resolve).
+	pub img2img: bool,
+	/// Inpainting with [`InpaintOptions`](crate::InpaintOptions), either with a dedicated 9-channel inpainting UNet or
+	/// via latent blending with a standard 4-channel UNet. Inpainting from images additionally requires
+	/// [`img2img`](Self::img2img).
+	pub inpaint: bool,
+	/// The number of channels of the UNet's latent input: 4 for standard UNets, 9 for dedicated inpainting UNets, or
+	/// `None` if the dimension is dynamic.
+	pub unet_in_channels: Option<u32>,
+	/// The number of ControlNet residuals the UNet takes after `encoder_hidden_states`, or 0 if it can't be guided by a
+	/// [ControlNet](crate::ControlNetConfig). A ControlNet must produce exactly this many residuals.
+	pub controlnet_residuals: usize,
+	/// Whether the UNet has an `attention_maps` output with its cross-attention maps, as needed by
+	/// [Attend-and-Excite](crate::AttendAndExciteOptions).
+	pub attention_maps: bool,
+	/// [Reference attention](crate::StableDiffusionTxt2ImgOptions::with_reference_image), which requires a UNet exported
+	/// with reference hidden state inputs & outputs, and [`img2img`](Self::img2img) to encode the reference image.
+	pub reference_attention: bool,
+	/// A guidance-distilled UNet, which takes the guidance scale as an embedding instead of running classifier-free
+	/// guidance.
+	pub guidance_embedding: bool,
+	/// [DeepCache](crate::DeepCacheConfig), which requires the UNet split into deep & shallow subgraphs, loaded with
+	/// [`StableDiffusionOptions::with_deepcache`](crate::StableDiffusionOptions::with_deepcache).
+	pub deepcache: bool,
+	/// The `(width, height)` the UNet was exported for, if its latent input has static spatial dimensions. Such models
+	/// can only generate images of this size (or, with [MultiDiffusion](crate::MultiDiffusionOptions), tiles of this
+	/// size); other sizes fail with an error before generating.
+	pub static_size: Option<(u32, u32)>,
+	/// A preview decoder like TAESD for [approximate decoding](StableDiffusionPipeline::approximate_decode_latents);
+	/// without one, previews are linear projections of the latents at 1/8th of the image's resolution.
+	pub preview_decoder: bool,
+	/// A safety checker, so an [`NsfwPolicy`](crate::NsfwPolicy) other than allowing everything can take effect.
+	pub safety_checker: bool,
+	/// [Scoring images](StableDiffusionPipeline::clip_score) against prompts, which requires a CLIP image encoder.
+	pub clip_scoring: bool
+}
+
+impl StableDiffusionPipeline {
+	/// Reports which features the loaded model supports, inspecting its config & the inputs & outputs of its models.
+	///
+	/// ```
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, StableDiffusionOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// let pipeline = StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default())?;
+	/// let capabilities = pipeline.capabilities();
+	/// if !capabilities.img2img {
+	/// 	println!("this model can't encode images; disabling img2img & inpainting");
+	/// }
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn capabilities(&self) -> Capabilities {
+		let reference_unet = check_reference_unet(self).is_ok();
+		let guidance_embedding = matches!(guidance_embedding_dim(self), Ok(Some(_)));
+		Capabilities {
+			txt2img: true,
+			img2img: self.vae_encoder.is_some(),
+			inpaint: validate_inpaint_unet(self.unet_in_channels(), true).is_ok(),
+			unet_in_channels: self.unet_in_channels(),
+			// ControlNet can't be combined with UNets whose extra inputs serve other features
+			controlnet_residuals: if reference_unet || guidance_embedding { 0 } else { self.unet_residual_inputs() },
+			attention_maps: self.attention_maps_output().is_ok(),
+			reference_attention: self.vae_encoder.is_some() && reference_unet,
+			guidance_embedding,
+			deepcache: self.deepcache_unet.is_some(),
+			static_size: self.unet_static_size(),
+			preview_decoder: self.preview_decoder.is_some(),
+			safety_checker: self.safety_checker.is_some(),
+			clip_scoring: self.clip_scorer.is_some()
+		}
+	}
+}
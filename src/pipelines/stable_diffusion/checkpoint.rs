@@ -0,0 +1,276 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+	fs::File,
+	io::{self, BufReader, BufWriter, Read, Write},
+	path::Path
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ndarray::Array4;
+use ndarray_rand::rand::{rngs::StdRng, Error as RandError, RngCore};
+
+use crate::{CompatibilityVersion, RngDrawOrder, SchedulerState};
+
+const CHECKPOINT_MAGIC: &[u8; 4] = b"PDCK";
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// A snapshot of a text-to-image run after a given number of steps, from which the run can be continued along the
+/// exact same denoising trajectory with [`StableDiffusionTxt2ImgOptions::resume_from`](crate::StableDiffusionTxt2ImgOptions::resume_from).
+/// Checkpoints are captured with [`StableDiffusionTxt2ImgOptions::with_checkpoint_at`](crate::StableDiffusionTxt2ImgOptions::with_checkpoint_at).
+///
+/// Unlike img2img, which re-noises an image to some noise level, resuming preserves the exact position in the noise
+/// schedule. To do so, a checkpoint captures:
+/// - the latents after [`step`](DiffusionCheckpoint::step) steps;
+/// - the number of inference steps, from which the scheduler's timesteps are recomputed with
+///   [`set_timesteps`](crate::DiffusionScheduler::set_timesteps). The current step index is
+///   [`step`](DiffusionCheckpoint::step) itself;
+/// - the [`SchedulerState`] accumulated by the scheduler, i.e. the history of model outputs for multistep schedulers
+///   like [`DPMSolverMultistepScheduler`](crate::DPMSolverMultistepScheduler);
+/// - the seed, compatibility version, RNG draw order, and the number of 32-bit words drawn from the scheduler's RNG,
+///   from which the state of the scheduler's RNG is reconstructed for ancestral schedulers.
+///
+/// Checkpoints can be saved to & loaded from a compact binary format with [`DiffusionCheckpoint::save`] &
+/// [`DiffusionCheckpoint::from_file`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffusionCheckpoint {
+	/// The number of steps taken before the checkpoint was captured; resuming continues from this step index.
+	pub step: usize,
+	/// The total number of inference steps of the run.
+	pub steps: usize,
+	/// The seed of the run.
+	pub seed: u64,
+	/// The (resolved) compatibility version of the run.
+	pub compatibility_version: CompatibilityVersion,
+	/// The RNG draw order of the run.
+	pub rng_draw_order: RngDrawOrder,
+	/// The number of 32-bit words drawn from the scheduler's RNG before the checkpoint was captured.
+	pub scheduler_rng_words: u64,
+	/// The latents after [`step`](DiffusionCheckpoint::step) steps.
+	pub latents: Array4<f32>,
+	/// The scheduler's state after [`step`](DiffusionCheckpoint::step) steps.
+	pub scheduler_state: SchedulerState
+}
+
+impl DiffusionCheckpoint {
+	/// Saves this checkpoint to a file.
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+		let mut writer = BufWriter::new(File::create(path)?);
+		self.to_writer(&mut writer)?;
+		writer.flush()
+	}
+
+	/// Writes this checkpoint in pyke Diffusers' binary checkpoint format.
+	pub fn to_writer<W: Write>(&self, mut writer: W) -> io::Result<()> {
+		writer.write_all(CHECKPOINT_MAGIC)?;
+		writer.write_u32::<LittleEndian>(CHECKPOINT_VERSION)?;
+		writer.write_u64::<LittleEndian>(self.step as u64)?;
+		writer.write_u64::<LittleEndian>(self.steps as u64)?;
+		writer.write_u64::<LittleEndian>(self.seed)?;
+		writer.write_u8(match self.compatibility_version.resolve() {
+			CompatibilityVersion::V1_0 | CompatibilityVersion::Latest => 0
+		})?;
+		writer.write_u8(match self.rng_draw_order {
+			RngDrawOrder::Separate => 0,
+			RngDrawOrder::Sequential => 1
+		})?;
+		writer.write_u64::<LittleEndian>(self.scheduler_rng_words)?;
+		write_array(&mut writer, &self.latents)?;
+		writer.write_u64::<LittleEndian>(self.scheduler_state.lower_order_nums as u64)?;
+		writer.write_u64::<LittleEndian>(self.scheduler_state.model_outputs.len() as u64)?;
+		for model_output in &self.scheduler_state.model_outputs {
+			write_array(&mut writer, model_output)?;
+		}
+		Ok(())
+	}
+
+	/// Loads a checkpoint from a file.
+	pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+		Self::from_reader(BufReader::new(File::open(path)?))
+	}
+
+	/// Reads a checkpoint in pyke Diffusers' binary checkpoint format.
+	pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Self> {
+		let mut magic = [0; 4];
+		reader.read_exact(&mut magic)?;
+		if &magic != CHECKPOINT_MAGIC {
+			return Err(invalid_data("not a pyke Diffusers checkpoint"));
+		}
+		let version = reader.read_u32::<LittleEndian>()?;
+		if version != CHECKPOINT_VERSION {
+			return Err(invalid_data(format!("unsupported checkpoint version {version}")));
+		}
+
+		let step = reader.read_u64::<LittleEndian>()? as usize;
+		let steps = reader.read_u64::<LittleEndian>()? as usize;
+		let seed = reader.read_u64::<LittleEndian>()?;
+		let compatibility_version = match reader.read_u8()? {
+			0 => CompatibilityVersion::V1_0,
+			v => return Err(invalid_data(format!("unknown compatibility version {v}")))
+		};
+		let rng_draw_order = match reader.read_u8()? {
+			0 => RngDrawOrder::Separate,
+			1 => RngDrawOrder::Sequential,
+			v => return Err(invalid_data(format!("unknown RNG draw order {v}")))
+		};
+		let scheduler_rng_words = reader.read_u64::<LittleEndian>()?;
+		let latents = read_array(&mut reader)?;
+		let lower_order_nums = reader.read_u64::<LittleEndian>()? as usize;
+		let n_model_outputs = reader.read_u64::<LittleEndian>()?;
+		let model_outputs = (0..n_model_outputs).map(|_| read_array(&mut reader)).collect::<io::Result<Vec<_>>>()?;
+
+		Ok(Self {
+			step,
+			steps,
+			seed,
+			compatibility_version,
+			rng_draw_order,
+			scheduler_rng_words,
+			latents,
+			scheduler_state: SchedulerState { model_outputs, lower_order_nums }
+		})
+	}
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn write_array<W: Write>(writer: &mut W, array: &Array4<f32>) -> io::Result<()> {
+	for &dim in array.shape() {
+		writer.write_u64::<LittleEndian>(dim as u64)?;
+	}
+	for &x in array.iter() {
+		writer.write_f32::<LittleEndian>(x)?;
+	}
+	Ok(())
+}
+
+fn read_array<R: Read>(reader: &mut R) -> io::Result<Array4<f32>> {
+	let mut shape = [0; 4];
+	for dim in shape.iter_mut() {
+		*dim = reader.read_u64::<LittleEndian>()? as usize;
+	}
+	let len = shape.iter().product::<usize>();
+	let mut data = vec![0.0; len];
+	reader.read_f32_into::<LittleEndian>(&mut data)?;
+	Array4::from_shape_vec((shape[0], shape[1], shape[2], shape[3]), data).map_err(invalid_data)
+}
+
+/// Wraps the scheduler's RNG, counting the 32-bit words drawn from it so that its state can be restored by replaying the
+/// same number of words.
+///
+/// This relies on [`StdRng`] being a block RNG, where `next_u64` always consumes exactly 2 words and `fill_bytes`
+/// consumes whole words.
+pub(crate) struct CountingRng {
+	rng: StdRng,
+	words: u64
+}
+
+impl CountingRng {
+	pub(crate) fn new(rng: StdRng) -> Self {
+		Self { rng, words: 0 }
+	}
+
+	/// Advances a freshly created RNG by `words` 32-bit words.
+	pub(crate) fn fast_forwarded(mut rng: StdRng, words: u64) -> Self {
+		for _ in 0..words {
+			rng.next_u32();
+		}
+		Self { rng, words }
+	}
+
+	/// Returns the number of 32-bit words drawn so far.
+	pub(crate) fn words(&self) -> u64 {
+		self.words
+	}
+}
+
+impl RngCore for CountingRng {
+	fn next_u32(&mut self) -> u32 {
+		self.words += 1;
+		self.rng.next_u32()
+	}
+
+	fn next_u64(&mut self) -> u64 {
+		self.words += 2;
+		self.rng.next_u64()
+	}
+
+	fn fill_bytes(&mut self, dest: &mut [u8]) {
+		self.words += (dest.len() as u64 + 3) / 4;
+		self.rng.fill_bytes(dest)
+	}
+
+	fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), RandError> {
+		self.words += (dest.len() as u64 + 3) / 4;
+		self.rng.try_fill_bytes(dest)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ndarray::Array4;
+	use ndarray_rand::{
+		rand::{rngs::StdRng, Rng, RngCore, SeedableRng},
+		rand_distr::StandardNormal,
+		RandomExt
+	};
+
+	use super::{CountingRng, DiffusionCheckpoint};
+	use crate::{CompatibilityVersion, RngDrawOrder, SchedulerState};
+
+	#[test]
+	fn counting_rng_fast_forwards_exactly() {
+		let mut rng = CountingRng::new(StdRng::seed_from_u64(42));
+		// mix of word sizes, including draws straddling block boundaries
+		for i in 0..100 {
+			let _: u32 = rng.gen();
+			let _: u64 = rng.gen();
+			let mut bytes = vec![0; i % 7];
+			rng.fill_bytes(&mut bytes);
+		}
+		let _ = Array4::<f32>::random_using((1, 4, 3, 5), StandardNormal, &mut rng);
+
+		let mut restored = CountingRng::fast_forwarded(StdRng::seed_from_u64(42), rng.words());
+		assert_eq!(restored.words(), rng.words());
+		for _ in 0..100 {
+			assert_eq!(restored.gen::<u64>(), rng.gen::<u64>());
+		}
+	}
+
+	#[test]
+	fn checkpoint_roundtrip() {
+		let checkpoint = DiffusionCheckpoint {
+			step: 3,
+			steps: 10,
+			seed: 42,
+			compatibility_version: CompatibilityVersion::V1_0,
+			rng_draw_order: RngDrawOrder::Sequential,
+			scheduler_rng_words: 1234,
+			latents: Array4::from_shape_fn((1, 4, 2, 3), |(_, c, y, x)| (c * 6 + y * 3 + x) as f32),
+			scheduler_state: SchedulerState {
+				model_outputs: vec![Array4::from_elem((1, 4, 2, 3), 0.5), Array4::from_elem((1, 4, 2, 3), -1.5)],
+				lower_order_nums: 1
+			}
+		};
+		let mut bytes = Vec::new();
+		checkpoint.to_writer(&mut bytes).unwrap();
+		assert_eq!(DiffusionCheckpoint::from_reader(bytes.as_slice()).unwrap(), checkpoint);
+
+		bytes[0] = b'X';
+		assert!(DiffusionCheckpoint::from_reader(bytes.as_slice()).is_err());
+	}
+}
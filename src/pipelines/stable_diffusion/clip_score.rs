@@ -0,0 +1,187 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fs, path::Path};
+
+use image::{imageops::FilterType, DynamicImage};
+use ndarray::{s, Array1, Array2, Array4};
+use ort::{OrtOwnedTensor, Value};
+
+use crate::{
+	config::{CLIPFeatureExtractorConfig, CLIPScorerConfig},
+	session_tracker::{load_session, ModelSource, TrackedSession},
+	DiffusionDevice, ExecutionContext, StableDiffusionPipeline
+};
+
+/// Default CLIP image normalization constants, used if the model has no feature extractor config.
+const CLIP_IMAGE_MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+const CLIP_IMAGE_STD: [f32; 3] = [0.268_629_54, 0.261_302_58, 0.275_777_1];
+const CLIP_IMAGE_SIZE: u32 = 224;
+
+/// The loaded models required to compute CLIP scores.
+pub(crate) struct CLIPScorer {
+	image_encoder: TrackedSession,
+	/// `[projection_dim, hidden_size]`
+	text_projection: Array2<f32>
+}
+
+impl CLIPScorer {
+	pub(crate) fn load(
+		context: &ExecutionContext,
+		root: &Path,
+		config: &CLIPScorerConfig,
+		device: &DiffusionDevice,
+		max_resident_bytes: Option<u64>,
+		replacing: Option<&TrackedSession>
+	) -> anyhow::Result<Self> {
+		let image_encoder = load_session(
+			context,
+			device,
+			"CLIP image encoder",
+			ModelSource::File(&root.join(&config.image_encoder)),
+			max_resident_bytes,
+			replacing
+		)?;
+
+		let bytes = fs::read(root.join(&config.text_projection))?;
+		let weights: Vec<f32> = bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+		if config.projection_dim == 0 || weights.len() % config.projection_dim != 0 {
+			anyhow::bail!("CLIP text projection has {} weights, which is not divisible by `projection-dim` ({})", weights.len(), config.projection_dim);
+		}
+		let hidden_size = weights.len() / config.projection_dim;
+		let text_projection = Array2::from_shape_vec((config.projection_dim, hidden_size), weights)?;
+
+		Ok(Self { image_encoder, text_projection })
+	}
+
+	pub(crate) fn session(&self) -> &TrackedSession {
+		&self.image_encoder
+	}
+}
+
+impl StableDiffusionPipeline {
+	/// Computes the CLIP score of an image, i.e. the cosine similarity between the CLIP embeddings of `image` and
+	/// `prompt`. This can be used to automatically filter out generations which don't match their prompt well.
+	///
+	/// The image is preprocessed according to the model's feature extractor config (resized so its shortest side
+	/// matches `size`, center cropped, and normalized with `image-mean`/`image-std`), or with the standard CLIP
+	/// preprocessing at 224x224 if the model has no feature extractor. The text is encoded with the pipeline's own text
+	/// encoder, and the hidden state at the end-of-text token is projected into the joint embedding space. Both
+	/// embeddings are then L2-normalized, so the score is independent of embedding magnitude.
+	///
+	/// The score is in the range `[-1, 1]`, where higher is better. In practice, scores of well-matching image/prompt
+	/// pairs are usually around `0.25..0.35` for CLIP ViT-L/14, with unrelated pairs scoring below `0.15`. Note that
+	/// some papers report CLIP scores as `100 * max(score, 0)`. Scores are only comparable between images scored with
+	/// the same CLIP model, so prefer relative thresholds (e.g. discarding the lowest-scoring images in a batch).
+	///
+	/// This requires the model to be configured with a `[clip-scorer]` section; otherwise, an error is returned. The
+	/// CLIP image encoder is placed on the same device as the safety checker.
+	///
+	/// Long prompts are truncated to the tokenizer's maximum length, and prompt weighting syntax is not interpreted.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// # let mut scheduler = EulerDiscreteScheduler::default();
+	/// # let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+	/// let prompt = "photo of a red fox";
+	/// let imgs = StableDiffusionTxt2ImgOptions::default()
+	/// 	.with_prompt([prompt; 4])
+	/// 	.run(&pipeline, &mut scheduler)?;
+	/// let mut good_imgs = Vec::new();
+	/// for img in imgs {
+	/// 	if pipeline.clip_score(&img, prompt)? >= 0.2 {
+	/// 		good_imgs.push(img);
+	/// 	}
+	/// }
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn clip_score(&self, image: &DynamicImage, prompt: &str) -> anyhow::Result<f32> {
+		let scorer = self
+			.clip_scorer
+			.as_ref()
+			.ok_or_else(|| anyhow::anyhow!("this model has no CLIP image encoder configured; add a `[clip-scorer]` section to its config to compute CLIP scores"))?;
+
+		let image_embeds = {
+			let pixel_values = preprocess_clip_image(image, self.config.feature_extractor.as_ref());
+			let outputs = scorer.image_encoder.run(ort::inputs![Value::from_array(pixel_values)?]?)?;
+			let image_embeds: OrtOwnedTensor<f32> = outputs[0].extract_tensor()?;
+			image_embeds.view().iter().copied().collect::<Array1<f32>>()
+		};
+
+		let text_embeds = {
+			let tokenizer = &self.text_embeddings.tokenizer;
+			let prompt = self.filter_prompt(prompt.into())?;
+			let input_ids = tokenizer.encode_for_text_model(vec![prompt[0].as_str()])?;
+			let eos_index = input_ids.row(0).iter().position(|&id| id as u32 == tokenizer.eos()).unwrap_or(input_ids.shape()[1] - 1);
+			let input = if self.text_embeddings.is_empty() {
+				Value::from_array(input_ids)
+			} else {
+				Value::from_array(self.text_embeddings.embed(input_ids))
+			}?;
+			let outputs = self.text_encoder.run(ort::inputs![input]?)?;
+			let hidden_states: OrtOwnedTensor<f32> = outputs[0].extract_tensor()?;
+			let pooled = hidden_states.view().slice(s![0, eos_index, ..]).to_owned();
+			if pooled.len() != scorer.text_projection.shape()[1] {
+				anyhow::bail!(
+					"text encoder hidden size ({}) does not match CLIP text projection input size ({})",
+					pooled.len(),
+					scorer.text_projection.shape()[1]
+				);
+			}
+			scorer.text_projection.dot(&pooled)
+		};
+
+		if image_embeds.len() != text_embeds.len() {
+			anyhow::bail!("CLIP image embeddings ({}) and text embeddings ({}) have different dimensions", image_embeds.len(), text_embeds.len());
+		}
+
+		Ok(cosine_similarity(&image_embeds, &text_embeds))
+	}
+}
+
+/// Converts an image into normalized `pixel_values` of shape `(1, 3, height, width)` for a CLIP image encoder.
+pub(crate) fn preprocess_clip_image(image: &DynamicImage, config: Option<&CLIPFeatureExtractorConfig>) -> Array4<f32> {
+	let size = config.map_or(CLIP_IMAGE_SIZE, |c| c.size);
+	let [crop_width, crop_height] = config.map_or([CLIP_IMAGE_SIZE; 2], |c| c.crop);
+	let (mean, std) = match config {
+		Some(c) if c.normalize && c.image_mean.len() == 3 && c.image_std.len() == 3 => {
+			([c.image_mean[0], c.image_mean[1], c.image_mean[2]], [c.image_std[0], c.image_std[1], c.image_std[2]])
+		}
+		Some(c) if !c.normalize => ([0.0; 3], [1.0; 3]),
+		_ => (CLIP_IMAGE_MEAN, CLIP_IMAGE_STD)
+	};
+
+	// resize so the shortest side is `size`, then center crop
+	let (width, height) = (image.width() as f32, image.height() as f32);
+	let scale = size as f32 / width.min(height);
+	let (new_width, new_height) = (((width * scale).round() as u32).max(crop_width), ((height * scale).round() as u32).max(crop_height));
+	let image = image
+		.resize_exact(new_width, new_height, FilterType::CatmullRom)
+		.crop_imm((new_width - crop_width) / 2, (new_height - crop_height) / 2, crop_width, crop_height)
+		.to_rgb32f();
+
+	Array4::from_shape_fn((1, 3, crop_height as usize, crop_width as usize), |(_, c, y, x)| (image.get_pixel(x as u32, y as u32)[c] - mean[c]) / std[c])
+}
+
+fn cosine_similarity(a: &Array1<f32>, b: &Array1<f32>) -> f32 {
+	let norm = a.dot(a).sqrt() * b.dot(b).sqrt();
+	if norm == 0.0 {
+		0.0
+	} else {
+		a.dot(b) / norm
+	}
+}
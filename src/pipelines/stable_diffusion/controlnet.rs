@@ -0,0 +1,258 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt::Debug, sync::Arc};
+
+use image::{DynamicImage, GenericImageView};
+use ndarray::{Array4, ArrayD, ArrayView4, ArrayViewD, CowArray, IxDyn};
+use ort::OrtOwnedTensor;
+
+use super::{impl_main::timestep_input, preprocessing::image_to_array};
+use crate::{session_tracker::TrackedSession, ResizeFilter};
+
+/// A [ControlNet](https://arxiv.org/abs/2302.05543) model, loaded with
+/// [`StableDiffusionPipeline::load_controlnet`](crate::StableDiffusionPipeline::load_controlnet). Clones share the
+/// loaded session, so one ControlNet can be used by many generations without being loaded again.
+///
+/// The model takes the (scaled) latent model input, timestep, text embeddings, & a `(batch_size, 3, height, width)`
+/// conditioning image with values in `[0, 1]` as its inputs, and outputs the residuals of each of the UNet's down
+/// blocks followed by the residual of its mid block. The UNet must be exported to take these residuals, in the same
+/// order, as its inputs after `encoder_hidden_states`.
+#[derive(Clone)]
+pub struct ControlNet {
+	pub(crate) session: Arc<TrackedSession>
+}
+
+impl Debug for ControlNet {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ControlNet").finish_non_exhaustive()
+	}
+}
+
+impl ControlNet {
+	/// Runs the ControlNet, returning its residuals in output order.
+	fn residuals(
+		&self,
+		latent_model_input: ArrayView4<'_, f32>,
+		timestep: f32,
+		encoder_hidden_states: ArrayViewD<'_, f32>,
+		cond: ArrayView4<'_, f32>
+	) -> anyhow::Result<Vec<ArrayD<f32>>> {
+		let latent_model_input: CowArray<f32, IxDyn> = latent_model_input.as_standard_layout().into_dyn();
+		let timestep = timestep_input(timestep, self.session.inputs.get(1).map_or(1, |input| input.dimensions.len()))?;
+		let encoder_hidden_states: CowArray<f32, IxDyn> = encoder_hidden_states.as_standard_layout();
+		let cond: CowArray<f32, IxDyn> = cond.as_standard_layout().into_dyn();
+
+		let outputs = self.session.run(ort::inputs![&latent_model_input, &timestep, &encoder_hidden_states, &cond]?)?;
+		(0..self.session.outputs.len())
+			.map(|i| -> anyhow::Result<ArrayD<f32>> {
+				let residual: OrtOwnedTensor<f32> = outputs[i].extract_tensor()?;
+				Ok(residual.view().to_owned())
+			})
+			.collect()
+	}
+}
+
+/// A ControlNet guiding a generation, with its conditioning image, weight, & the range of steps it is active for; see
+/// [`StableDiffusionTxt2ImgOptions::with_controlnet`](crate::StableDiffusionTxt2ImgOptions::with_controlnet).
+#[derive(Debug, Clone)]
+pub struct ControlNetConfig {
+	/// The ControlNet model.
+	pub controlnet: ControlNet,
+	/// The conditioning image, e.g. a pose skeleton or depth map. **Must be the size of the generated image**, unless a
+	/// [`resize_filter`](Self::resize_filter) is set.
+	pub image: DynamicImage,
+	/// The filter the conditioning image is resized to the size of the generated image with, if set. Without one,
+	/// conditioning images of another size are rejected. `None` by default; see [`ControlNetConfig::with_resize_filter`].
+	pub resize_filter: Option<ResizeFilter>,
+	/// The weight the ControlNet's residuals are multiplied by before they are summed with those of other
+	/// ControlNets. Defaults to `1.0`.
+	pub weight: f32,
+	/// The fraction of the steps after which the ControlNet becomes active, from `0.0` to `1.0`. Defaults to `0.0`.
+	pub start_frac: f32,
+	/// The fraction of the steps after which the ControlNet stops being active, from `0.0` to `1.0`. Defaults to
+	/// `1.0`.
+	pub end_frac: f32
+}
+
+impl ControlNetConfig {
+	/// Creates a ControlNet config which is active for all steps with a weight of `1.0`.
+	pub fn new(controlnet: ControlNet, image: DynamicImage) -> Self {
+		Self {
+			controlnet,
+			image,
+			resize_filter: None,
+			weight: 1.0,
+			start_frac: 0.0,
+			end_frac: 1.0
+		}
+	}
+
+	/// Lets the conditioning image be of another size than the generated image, resizing it with `resize_filter`.
+	/// [`ResizeFilter::Nearest`] keeps the hard edges of edge maps, poses, & segmentation maps intact; smooth inputs
+	/// like depth maps resize better with [`ResizeFilter::Lanczos3`].
+	pub fn with_resize_filter(mut self, resize_filter: ResizeFilter) -> Self {
+		self.resize_filter = Some(resize_filter);
+		self
+	}
+
+	/// Returns the conditioning image as it is fed to the ControlNet when generating `width`x`height` images: resized
+	/// with the [resize filter](Self::with_resize_filter) if needed, as an array of shape `(1, 3, height, width)` with
+	/// values in `[0, 1]`. Returns `None` if the image is of another size and no resize filter is set.
+	pub fn conditioning_image(&self, width: u32, height: u32) -> Option<Array4<f32>> {
+		if self.image.dimensions() == (width, height) {
+			// not resized, so the filter doesn't matter
+			Some(image_to_array(&self.image, ResizeFilter::Nearest, width, height))
+		} else {
+			self.resize_filter.map(|filter| image_to_array(&self.image, filter, width, height))
+		}
+	}
+
+	/// Sets the weight the ControlNet's residuals are multiplied by.
+	pub fn with_weight(mut self, weight: f32) -> Self {
+		self.weight = weight;
+		self
+	}
+
+	/// Makes the ControlNet active only from `start_frac` to `end_frac` of the way through the steps. For example,
+	/// `(0.0, 0.5)` lets a pose ControlNet fix the composition during the first half of the steps, leaving the details
+	/// to the model.
+	pub fn with_step_range(mut self, start_frac: f32, end_frac: f32) -> Self {
+		self.start_frac = start_frac;
+		self.end_frac = end_frac;
+		self
+	}
+
+	/// Whether the ControlNet is active on step `i` of `steps`.
+	fn is_active(&self, i: usize, steps: usize) -> bool {
+		step_in_range(i, steps, self.start_frac, self.end_frac)
+	}
+}
+
+/// Whether step `i` of `steps` lies within `start_frac` to `end_frac` of the way through the steps, i.e. starts at or
+/// after `start_frac` & ends at or before `end_frac`.
+fn step_in_range(i: usize, steps: usize, start_frac: f32, end_frac: f32) -> bool {
+	let (start, end) = (i as f32 / steps as f32, (i + 1) as f32 / steps as f32);
+	start >= start_frac && end <= end_frac
+}
+
+/// The conditioning images of a run's ControlNets, converted to arrays in the order of their configs.
+pub(crate) struct MultiControlNet {
+	conds: Vec<Array4<f32>>,
+	/// The shapes of the residuals, once known, to feed the UNet zeros on steps where no ControlNet is active.
+	residual_shapes: Option<Vec<IxDyn>>
+}
+
+impl MultiControlNet {
+	/// Validates the ControlNet configs of a run generating `width`x`height` images.
+	pub(crate) fn new(configs: &[ControlNetConfig], width: u32, height: u32) -> anyhow::Result<Self> {
+		let mut conds = Vec::with_capacity(configs.len());
+		for (i, config) in configs.iter().enumerate() {
+			if !config.weight.is_finite() {
+				anyhow::bail!("ControlNet #{}'s weight is {}; the weight must be finite", i + 1, config.weight);
+			}
+			if !(0.0 <= config.start_frac && config.start_frac < config.end_frac && config.end_frac <= 1.0) {
+				anyhow::bail!(
+					"ControlNet #{}'s step range is {} to {}; expected 0 <= start_frac < end_frac <= 1",
+					i + 1,
+					config.start_frac,
+					config.end_frac
+				);
+			}
+			let cond = config.conditioning_image(width, height).ok_or_else(|| {
+				anyhow::anyhow!(
+					"ControlNet #{}'s conditioning image is {}x{}, but the generated image is {width}x{height}; resize it or set a resize filter",
+					i + 1,
+					config.image.width(),
+					config.image.height()
+				)
+			})?;
+			conds.push(cond);
+		}
+		Ok(Self { conds, residual_shapes: None })
+	}
+
+	/// Runs each ControlNet active on step `i` of `steps`, returning the sum of their weighted residuals. If no
+	/// ControlNet is active, zeros are returned, since the UNet always takes the residuals as inputs. `configs` must be
+	/// the configs this was created from.
+	pub(crate) fn residuals(
+		&mut self,
+		configs: &[ControlNetConfig],
+		latent_model_input: ArrayView4<'_, f32>,
+		timestep: f32,
+		encoder_hidden_states: ArrayViewD<'_, f32>,
+		i: usize,
+		steps: usize
+	) -> anyhow::Result<Vec<ArrayD<f32>>> {
+		let batch_size = latent_model_input.shape()[0];
+		let mut sum: Option<Vec<ArrayD<f32>>> = None;
+		for (config, cond) in configs.iter().zip(self.conds.iter()).filter(|(config, _)| config.is_active(i, steps)) {
+			let residuals = config.controlnet.residuals(latent_model_input, timestep, encoder_hidden_states.view(), broadcast_batch(cond, batch_size))?;
+			sum = Some(match sum {
+				Some(mut sum) => {
+					if sum.len() != residuals.len() || sum.iter().zip(residuals.iter()).any(|(a, b)| a.shape() != b.shape()) {
+						anyhow::bail!("ControlNets produced residuals of different shapes; all ControlNets must be for the same UNet");
+					}
+					for (sum, residual) in sum.iter_mut().zip(residuals) {
+						sum.scaled_add(config.weight, &residual);
+					}
+					sum
+				}
+				None => residuals.into_iter().map(|residual| residual * config.weight).collect()
+			});
+		}
+
+		match sum {
+			Some(sum) => {
+				self.residual_shapes = Some(sum.iter().map(|residual| residual.raw_dim()).collect());
+				Ok(sum)
+			}
+			None => {
+				let residual_shapes = match self.residual_shapes.take() {
+					Some(residual_shapes) => residual_shapes,
+					None => {
+						// no ControlNet has been active yet, so run one to learn the shapes of the residuals
+						let cond = broadcast_batch(&self.conds[0], batch_size);
+						let residuals = configs[0].controlnet.residuals(latent_model_input, timestep, encoder_hidden_states, cond)?;
+						residuals.iter().map(|residual| residual.raw_dim()).collect()
+					}
+				};
+				let zeros = residual_shapes.iter().map(|shape| ArrayD::zeros(shape.clone())).collect();
+				self.residual_shapes = Some(residual_shapes);
+				Ok(zeros)
+			}
+		}
+	}
+}
+
+/// Broadcasts a conditioning image of shape `(1, 3, height, width)` to the batch size of the latent model input.
+fn broadcast_batch(cond: &Array4<f32>, batch_size: usize) -> ArrayView4<'_, f32> {
+	let (_, channels, height, width) = cond.dim();
+	cond.broadcast((batch_size, channels, height, width)).expect("conditioning image has a batch size of 1")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::step_in_range;
+
+	#[test]
+	fn active_step_ranges() {
+		let active = |start_frac: f32, end_frac: f32| (0..4).filter(|&i| step_in_range(i, 4, start_frac, end_frac)).collect::<Vec<_>>();
+		assert_eq!(active(0.0, 1.0), [0, 1, 2, 3]);
+		assert_eq!(active(0.0, 0.5), [0, 1]);
+		assert_eq!(active(0.5, 1.0), [2, 3]);
+		// a step is only active if it lies entirely within the range
+		assert_eq!(active(0.1, 0.9), [1, 2]);
+	}
+}
@@ -0,0 +1,254 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+
+use ndarray::{Array4, ArrayD, ArrayView4, ArrayViewD, CowArray, IxDyn};
+use ort::{OrtOwnedTensor, Value};
+
+use super::impl_main::{resolve_noise_pred_output, squeeze_noise_pred, timestep_input};
+use crate::{
+	config::StableDiffusionConfig,
+	session_tracker::{load_session, ModelSource, TrackedSession},
+	ExecutionContext, StableDiffusionOptions, StableDiffusionPipeline
+};
+
+/// Options for [DeepCache](https://arxiv.org/abs/2312.00858), which speeds up denoising by reusing the UNet's deep
+/// features across steps; see
+/// [`StableDiffusionTxt2ImgOptions::with_deepcache`](crate::StableDiffusionTxt2ImgOptions::with_deepcache).
+///
+/// The features of the deep blocks of the UNet change slowly between adjacent timesteps. DeepCache runs the deep
+/// blocks only every `interval` steps and caches their output; the steps in between only run the shallow blocks,
+/// with the cached features in place of the deep blocks. This requires a model whose config has an `[unet.deepcache]`
+/// section pointing to the UNet split into a deep & a shallow subgraph:
+///
+/// ```toml
+/// [unet.deepcache]
+/// deep = "unet/deep.onnx"
+/// shallow = "unet/shallow.onnx"
+/// branch = 0
+/// ```
+///
+/// The deep subgraph takes the UNet's usual `sample`, `timestep` & `encoder_hidden_states` inputs and returns the
+/// features to cache; the shallow subgraph takes the same inputs followed by one input for each deep output, matched
+/// by name, and returns the noise prediction. Both subgraphs are loaded on the UNet's device, only if the pipeline is
+/// created with [`StableDiffusionOptions::with_deepcache`].
+///
+/// A step which only runs the shallow subgraph costs a fraction of a full UNet pass, depending on the skip branch the
+/// UNet was split at: the shallower the branch, the faster & less accurate cached steps are. Larger intervals are
+/// faster, but drift further from the uncached result; intervals of 3 to 5 keep images close to the original.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeepCacheConfig {
+	/// Run the deep subgraph every `interval` UNet evaluations. Must be at least 1; `1` runs it on every step, which
+	/// matches the uncached UNet.
+	pub interval: usize,
+	/// The index of the skip branch the model's split UNet is expected to be split at. If set, generation fails unless
+	/// it matches the `branch` declared in the model's `[unet.deepcache]` section, guarding against running a model
+	/// exported for a different configuration.
+	pub branch_config: Option<usize>
+}
+
+impl DeepCacheConfig {
+	/// Creates a DeepCache configuration running the deep subgraph every `interval` steps.
+	pub fn new(interval: usize) -> Self {
+		Self { interval, branch_config: None }
+	}
+
+	/// Requires the model's split UNet to be split at the skip branch `branch`.
+	pub fn with_branch_config(mut self, branch: usize) -> Self {
+		self.branch_config = Some(branch);
+		self
+	}
+
+	/// Checks the config against the pipeline's split UNet.
+	pub(crate) fn validate(&self, session: &StableDiffusionPipeline) -> anyhow::Result<()> {
+		let unet = match session.deepcache_unet.as_ref() {
+			Some(unet) => unet,
+			None if session.config.unet.deepcache.is_some() => anyhow::bail!(
+				"DeepCache is not enabled for this pipeline, so the model's split UNet wasn't loaded; create the pipeline with `StableDiffusionOptions::with_deepcache(true)`"
+			),
+			None => anyhow::bail!(
+				"DeepCache is not supported by this model: its UNet is a single graph; DeepCache requires the UNet split into deep & shallow subgraphs, declared in an `[unet.deepcache]` section of the model config"
+			)
+		};
+		if self.interval == 0 {
+			anyhow::bail!("DeepCache `interval` must be at least 1");
+		}
+		if let Some(expected) = self.branch_config {
+			if unet.branch != Some(expected) {
+				anyhow::bail!(
+					"DeepCache expects a UNet split at branch {expected}, but the model's split UNet declares {}",
+					unet.branch.map_or_else(|| "no branch".to_string(), |branch| format!("branch {branch}"))
+				);
+			}
+		}
+		Ok(())
+	}
+}
+
+/// The deep & shallow subgraphs of a split UNet.
+pub(crate) struct DeepCacheUNet {
+	deep: TrackedSession,
+	shallow: TrackedSession,
+	branch: Option<usize>,
+	/// For each cached feature input of the shallow subgraph, the index of the deep output that feeds it.
+	feature_outputs: Vec<usize>,
+	noise_pred_output: usize
+}
+
+impl DeepCacheUNet {
+	/// Loads the split UNet declared in the model config's `[unet.deepcache]` section if DeepCache is enabled in
+	/// `options`, validating that the shallow subgraph takes every output of the deep subgraph.
+	pub(crate) fn load(
+		context: &ExecutionContext,
+		options: &StableDiffusionOptions,
+		root: &Path,
+		config: &StableDiffusionConfig
+	) -> anyhow::Result<Option<Self>> {
+		let split = match config.unet.deepcache.as_ref() {
+			Some(split) if options.deepcache => split,
+			_ => return Ok(None)
+		};
+		let load = |component, path: &str| {
+			load_session(context, &options.devices.unet, component, ModelSource::File(&root.join(path)), options.max_resident_bytes, None)
+		};
+		let deep = load("DeepCache deep UNet", &split.deep)?;
+		let shallow = load("DeepCache shallow UNet", &split.shallow)?;
+
+		if deep.inputs.len() != 3 {
+			anyhow::bail!("the DeepCache deep UNet takes {} inputs; expected `sample`, `timestep` & `encoder_hidden_states`", deep.inputs.len());
+		}
+		if shallow.inputs.len() != 3 + deep.outputs.len() {
+			anyhow::bail!(
+				"the DeepCache shallow UNet takes {} inputs, but the deep UNet has {} outputs; the shallow UNet must take `sample`, `timestep` & `encoder_hidden_states` followed by each deep output",
+				shallow.inputs.len(),
+				deep.outputs.len()
+			);
+		}
+		let feature_outputs = shallow.inputs[3..]
+			.iter()
+			.map(|input| {
+				deep.outputs
+					.iter()
+					.position(|output| output.name == input.name)
+					.ok_or_else(|| anyhow::anyhow!("the DeepCache shallow UNet's input `{}` is not an output of the deep UNet", input.name))
+			})
+			.collect::<anyhow::Result<Vec<_>>>()?;
+		let noise_pred_output = resolve_noise_pred_output(shallow.outputs.iter().map(|output| (output.name.as_str(), output.dimensions.as_slice())))?;
+
+		Ok(Some(Self {
+			deep,
+			shallow,
+			branch: split.branch,
+			feature_outputs,
+			noise_pred_output
+		}))
+	}
+
+	/// Returns both subgraphs' sessions.
+	pub(crate) fn sessions(&self) -> [&TrackedSession; 2] {
+		[&self.deep, &self.shallow]
+	}
+}
+
+/// Decides which UNet evaluations run the deep subgraph: the first, then every `interval`th.
+#[derive(Debug, Clone)]
+pub(crate) struct DeepCacheSchedule {
+	interval: usize,
+	since_refresh: Option<usize>
+}
+
+impl DeepCacheSchedule {
+	pub(crate) fn new(interval: usize) -> Self {
+		Self { interval, since_refresh: None }
+	}
+
+	/// Advances to the next UNet evaluation, returning `true` if it must run the deep subgraph.
+	pub(crate) fn advance(&mut self) -> bool {
+		let since_refresh = match self.since_refresh {
+			Some(since_refresh) if since_refresh + 1 < self.interval => since_refresh + 1,
+			_ => 0
+		};
+		self.since_refresh = Some(since_refresh);
+		since_refresh == 0
+	}
+}
+
+/// Runs a split UNet with DeepCache over the steps of one generation.
+pub(crate) struct DeepCache<'s> {
+	unet: &'s DeepCacheUNet,
+	timestep_rank: usize,
+	schedule: DeepCacheSchedule,
+	features: Vec<ArrayD<f32>>
+}
+
+impl<'s> DeepCache<'s> {
+	/// Prepares DeepCache for a generation; `config` must have been [validated](DeepCacheConfig::validate).
+	pub(crate) fn new(session: &'s StableDiffusionPipeline, config: &DeepCacheConfig) -> Self {
+		let unet = session.deepcache_unet.as_ref().expect("DeepCache config was not validated");
+		let timestep_rank = session.config.unet.timestep_rank.or_else(|| unet.shallow.inputs.get(1).map(|input| input.dimensions.len())).unwrap_or(1);
+		Self {
+			unet,
+			timestep_rank,
+			schedule: DeepCacheSchedule::new(config.interval),
+			features: Vec::new()
+		}
+	}
+
+	/// Predicts the noise like [`StableDiffusionPipeline::predict_noise`], running the deep subgraph only when the
+	/// schedule calls for it and reusing its cached features otherwise.
+	pub(crate) fn predict_noise(
+		&mut self,
+		latent_model_input: ArrayView4<'_, f32>,
+		timestep: f32,
+		encoder_hidden_states: ArrayViewD<'_, f32>
+	) -> anyhow::Result<Array4<f32>> {
+		let latent_model_input: CowArray<f32, IxDyn> = latent_model_input.as_standard_layout().into_dyn();
+		let timestep = timestep_input(timestep, self.timestep_rank)?;
+		let encoder_hidden_states: CowArray<f32, IxDyn> = encoder_hidden_states.as_standard_layout();
+
+		if self.schedule.advance() || self.features.is_empty() {
+			let outputs = self.unet.deep.run(ort::inputs![&latent_model_input, &timestep, &encoder_hidden_states]?)?;
+			self.features = (0..self.unet.deep.outputs.len())
+				.map(|i| -> anyhow::Result<ArrayD<f32>> {
+					let feature: OrtOwnedTensor<f32> = outputs[i].extract_tensor()?;
+					Ok(feature.view().to_owned())
+				})
+				.collect::<anyhow::Result<Vec<_>>>()?;
+		}
+
+		let mut inputs = ort::inputs![&latent_model_input, &timestep, &encoder_hidden_states]?;
+		for &output in &self.unet.feature_outputs {
+			inputs.push(Value::from_array(self.features[output].clone())?);
+		}
+		let outputs = self.unet.shallow.run(inputs)?;
+		let noise_pred: OrtOwnedTensor<f32> = outputs[self.unet.noise_pred_output].extract_tensor()?;
+		squeeze_noise_pred(noise_pred.view().to_owned())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::DeepCacheSchedule;
+
+	#[test]
+	fn refreshes_every_interval() {
+		let mut schedule = DeepCacheSchedule::new(3);
+		let refreshes = (0..8).map(|_| schedule.advance()).collect::<Vec<_>>();
+		assert_eq!(refreshes, [true, false, false, true, false, false, true, false]);
+
+		let mut schedule = DeepCacheSchedule::new(1);
+		assert!((0..4).all(|_| schedule.advance()));
+	}
+}
@@ -0,0 +1,136 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ndarray::{s, Array4};
+use ndarray_rand::{
+	rand::{rngs::StdRng, Rng, SeedableRng},
+	rand_distr::StandardNormal,
+	RandomExt
+};
+
+use super::impl_main::fnv1a;
+use crate::ImageRegion;
+
+/// Re-samples rectangular regions of the initial noise of each image in a batch from a secondary seed, to get a more
+/// diverse batch from one prompt without changing the seed entirely; see
+/// [`StableDiffusionTxt2ImgOptions::with_diversity`](crate::StableDiffusionTxt2ImgOptions::with_diversity).
+///
+/// The image at index `i` of the batch has `i * regions` regions of its noise re-sampled, so the first image keeps
+/// the seed's noise unchanged & later images drift progressively further from it. Each region covers roughly
+/// `area_frac` of the latents, with the aspect ratio of the image; regions may overlap. The regions & their noise only
+/// depend on `secondary_seed`, the image's index, & the image size, so the same seeds always perturb an image the same
+/// way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiversityConfig {
+	/// The number of regions added per image: the image at index `i` has `i * regions` perturbed regions.
+	pub regions: usize,
+	/// The fraction of the latents' area covered by each region, in `(0, 1]`.
+	pub area_frac: f32,
+	/// The seed the regions' positions & noise are drawn from.
+	pub secondary_seed: u64
+}
+
+impl DiversityConfig {
+	/// Creates a new diversity config; see [`DiversityConfig`].
+	pub fn new(regions: usize, area_frac: f32, secondary_seed: u64) -> Self {
+		Self { regions, area_frac, secondary_seed }
+	}
+
+	pub(crate) fn validate(&self) -> anyhow::Result<()> {
+		if !(self.area_frac > 0.0 && self.area_frac <= 1.0) {
+			anyhow::bail!("diversity `area_frac` is {}; it must be in (0, 1]", self.area_frac);
+		}
+		Ok(())
+	}
+
+	/// Re-samples regions of each image of the (unscaled, standard normal) initial noise `latents` in place, returning
+	/// the perturbed regions of each image in pixels.
+	pub(crate) fn perturb(&self, latents: &mut Array4<f32>) -> Vec<Vec<ImageRegion>> {
+		let (batch_size, channels, height, width) = latents.dim();
+		let scale = self.area_frac.sqrt();
+		let region_height = ((height as f32 * scale).round() as usize).clamp(1, height);
+		let region_width = ((width as f32 * scale).round() as usize).clamp(1, width);
+
+		(0..batch_size)
+			.map(|index| {
+				let mut rng = StdRng::seed_from_u64(fnv1a(self.secondary_seed.to_le_bytes().into_iter().chain((index as u64).to_le_bytes())));
+				(0..index * self.regions)
+					.map(|_| {
+						let y = rng.gen_range(0..=height - region_height);
+						let x = rng.gen_range(0..=width - region_width);
+						let noise = Array4::<f32>::random_using((1, channels, region_height, region_width), StandardNormal, &mut rng);
+						latents.slice_mut(s![index..index + 1, .., y..y + region_height, x..x + region_width]).assign(&noise);
+						ImageRegion::new(x as u32 * 8, y as u32 * 8, region_width as u32 * 8, region_height as u32 * 8)
+					})
+					.collect()
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ndarray::s;
+
+	use super::{
+		super::impl_txt2img::{draw_initial_latents, latents_shape},
+		DiversityConfig
+	};
+	use crate::{CompatibilityVersion, RngDrawOrder};
+
+	#[test]
+	fn first_image_keeps_base_noise() {
+		let (base, _) = draw_initial_latents(CompatibilityVersion::Latest, RngDrawOrder::Separate, 42, latents_shape(3, 64, 96));
+		let mut latents = base.clone();
+		let regions = DiversityConfig::new(2, 0.25, 7).perturb(&mut latents);
+
+		assert_eq!(latents.slice(s![0, .., .., ..]), base.slice(s![0, .., .., ..]));
+		assert_eq!(regions.iter().map(Vec::len).collect::<Vec<_>>(), [0, 2, 4]);
+		assert_ne!(latents.slice(s![1, .., .., ..]), base.slice(s![1, .., .., ..]));
+
+		// only the reported regions change
+		for (index, regions) in regions.iter().enumerate() {
+			for y in 0..8 {
+				for x in 0..12 {
+					let (px, py) = (x as u32 * 8, y as u32 * 8);
+					let inside = regions.iter().any(|r| (r.x..r.x + r.width).contains(&px) && (r.y..r.y + r.height).contains(&py));
+					if !inside {
+						assert_eq!(latents.slice(s![index, .., y, x]), base.slice(s![index, .., y, x]));
+					}
+				}
+			}
+			// 0.25 of the area at the image's aspect ratio
+			assert!(regions.iter().all(|r| (r.width, r.height) == (48, 32)));
+		}
+	}
+
+	#[test]
+	fn perturbation_is_deterministic() {
+		let (base, _) = draw_initial_latents(CompatibilityVersion::Latest, RngDrawOrder::Separate, 42, latents_shape(2, 64, 64));
+		let config = DiversityConfig::new(1, 0.1, 7);
+		let (mut a, mut b, mut c) = (base.clone(), base.clone(), base);
+		assert_eq!(config.perturb(&mut a), config.perturb(&mut b));
+		assert_eq!(a, b);
+		DiversityConfig { secondary_seed: 8, ..config }.perturb(&mut c);
+		assert_ne!(a, c);
+	}
+
+	#[test]
+	fn invalid_area() {
+		assert!(DiversityConfig::new(1, 0.0, 0).validate().is_err());
+		assert!(DiversityConfig::new(1, 1.5, 0).validate().is_err());
+		assert!(DiversityConfig::new(1, f32::NAN, 0).validate().is_err());
+		assert!(DiversityConfig::new(1, 1.0, 0).validate().is_ok());
+	}
+}
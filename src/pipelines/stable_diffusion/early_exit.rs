@@ -0,0 +1,107 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ndarray::ArrayView4;
+
+use super::step_stats::{l2_distance, l2_norm};
+
+/// The number of consecutive steps the latents must change by less than the threshold before exiting early.
+const CONVERGED_STEPS: usize = 2;
+
+/// Exits the denoising loop early once the latents have converged; see
+/// [`StableDiffusionTxt2ImgOptions::with_early_exit`](crate::StableDiffusionTxt2ImgOptions::with_early_exit).
+///
+/// After each step, the relative change of the latents, `||latents - previous_latents|| / ||previous_latents||`, is
+/// compared against `latent_delta_threshold`. Once it is below the threshold on two consecutive steps after the first
+/// `min_steps`, the remaining steps are skipped and the latents are decoded. The check is deterministic, so runs with
+/// the same seed exit on the same step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EarlyExit {
+	/// The number of steps always taken before convergence is checked.
+	pub min_steps: usize,
+	/// The relative change of the latents below which a step counts as converged. A threshold of `0.0` never exits
+	/// early.
+	pub latent_delta_threshold: f32
+}
+
+impl EarlyExit {
+	/// Creates early exit options with the given minimum number of steps & relative latent change threshold.
+	pub fn new(min_steps: usize, latent_delta_threshold: f32) -> Self {
+		Self { min_steps, latent_delta_threshold }
+	}
+
+	pub(crate) fn validate(&self) -> anyhow::Result<()> {
+		if !(self.latent_delta_threshold.is_finite() && self.latent_delta_threshold >= 0.0) {
+			anyhow::bail!("early exit `latent_delta_threshold` is {}; expected a finite value >= 0", self.latent_delta_threshold);
+		}
+		Ok(())
+	}
+}
+
+/// Tracks the convergence of the latents over the steps of a run.
+pub(crate) struct ConvergenceTracker {
+	early_exit: EarlyExit,
+	converged_steps: usize
+}
+
+impl ConvergenceTracker {
+	pub(crate) fn new(early_exit: EarlyExit) -> Self {
+		Self { early_exit, converged_steps: 0 }
+	}
+
+	/// Records step `i`, which changed the latents from `previous` to `latents`, returning `true` if the run should
+	/// exit after this step.
+	pub(crate) fn step(&mut self, i: usize, previous: ArrayView4<'_, f32>, latents: ArrayView4<'_, f32>) -> bool {
+		if i < self.early_exit.min_steps {
+			return false;
+		}
+		let delta = l2_distance(latents, previous) / l2_norm(previous);
+		if delta < self.early_exit.latent_delta_threshold {
+			self.converged_steps += 1;
+		} else {
+			self.converged_steps = 0;
+		}
+		self.converged_steps >= CONVERGED_STEPS
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ndarray::Array4;
+
+	use super::{ConvergenceTracker, EarlyExit};
+
+	/// Runs a tracker over latents which are scaled by each of `scales` in turn, returning the number of steps taken.
+	fn steps_taken(early_exit: EarlyExit, scales: &[f32]) -> usize {
+		let mut tracker = ConvergenceTracker::new(early_exit);
+		let mut latents = Array4::<f32>::ones((1, 4, 2, 2));
+		for (i, scale) in scales.iter().enumerate() {
+			let next = &latents * *scale;
+			if tracker.step(i, latents.view(), next.view()) {
+				return i + 1;
+			}
+			latents = next;
+		}
+		scales.len()
+	}
+
+	#[test]
+	fn exits_after_consecutive_converged_steps() {
+		let scales = [0.5, 0.5, 0.99, 0.5, 0.99, 0.99, 0.99, 0.99];
+		assert_eq!(steps_taken(EarlyExit::new(0, 0.05), &scales), 6);
+		// steps before `min_steps` don't count towards convergence
+		assert_eq!(steps_taken(EarlyExit::new(5, 0.05), &scales), 7);
+		assert_eq!(steps_taken(EarlyExit::new(0, 0.0), &[1.0; 8]), 8);
+	}
+}
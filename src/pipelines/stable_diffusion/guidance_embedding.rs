@@ -0,0 +1,96 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ndarray::{Array1, Array2, Axis};
+
+use crate::StableDiffusionPipeline;
+
+/// Names of the UNet input taking the guidance scale embedding in guidance-distilled models.
+const GUIDANCE_EMBEDDING_INPUTS: [&str; 2] = ["guidance", "w_embedding"];
+
+/// Returns the dimension of the guidance embedding if the pipeline's UNet is guidance-distilled, i.e. has a 4th input
+/// named `guidance` or `w_embedding` of shape `(batch_size, embedding_dim)`.
+///
+/// The embedding dimension is read from the model config's `unet.guidance-embedding-dim`, falling back to the
+/// input's static dimension.
+pub(crate) fn guidance_embedding_dim(session: &StableDiffusionPipeline) -> anyhow::Result<Option<usize>> {
+	let position = session.unet.inputs.iter().position(|input| GUIDANCE_EMBEDDING_INPUTS.contains(&input.name.as_str()));
+	let input = match position {
+		Some(3) => &session.unet.inputs[3],
+		Some(i) => anyhow::bail!("the UNet's guidance embedding input `{}` must be its 4th input, but is input #{}", session.unet.inputs[i].name, i + 1),
+		None => return Ok(None)
+	};
+	match session.config.unet.guidance_embedding_dim.or_else(|| input.dimensions.get(1).copied().flatten().map(|dim| dim as usize)) {
+		Some(dim) => Ok(Some(dim)),
+		None => anyhow::bail!(
+			"the UNet's guidance embedding input `{}` has a dynamic embedding dimension; set `guidance-embedding-dim` in the `[unet]` section of the model config",
+			input.name
+		)
+	}
+}
+
+/// Computes the sinusoidal embedding of a guidance scale, as used by guidance-distilled models such as Latent
+/// Consistency Models.
+///
+/// Like in diffusers, the embedded value is `(guidance_scale - 1) * 1000`; the first half of the embedding holds the
+/// sines and the second half the cosines of that value at geometrically spaced frequencies from `1` to `1 / 10000`.
+/// Odd dimensions are zero-padded.
+pub(crate) fn guidance_scale_embedding(guidance_scale: f32, embedding_dim: usize) -> Array1<f32> {
+	let w = f64::from(guidance_scale - 1.0) * 1000.0;
+	let half_dim = embedding_dim / 2;
+	let exponent = 10000_f64.ln() / (half_dim.max(2) - 1) as f64;
+	let mut embedding = Array1::zeros(embedding_dim);
+	for i in 0..half_dim {
+		let angle = w * (-exponent * i as f64).exp();
+		embedding[i] = angle.sin() as f32;
+		embedding[half_dim + i] = angle.cos() as f32;
+	}
+	embedding
+}
+
+/// Computes the guidance embedding input of shape `(batch_size, embedding_dim)` for a batch.
+pub(crate) fn guidance_embedding_input(guidance_scale: f32, embedding_dim: usize, batch_size: usize) -> Array2<f32> {
+	let embedding = guidance_scale_embedding(guidance_scale, embedding_dim).insert_axis(Axis(0));
+	embedding.broadcast((batch_size, embedding_dim)).unwrap().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{guidance_embedding_input, guidance_scale_embedding};
+
+	#[test]
+	fn sinusoidal_embedding() {
+		// guidance scale 1 embeds 0: sin(0) = 0, cos(0) = 1
+		let embedding = guidance_scale_embedding(1.0, 8);
+		assert_eq!(embedding.to_vec(), vec![0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0]);
+
+		// the first frequency is 1, the last 1 / 10000
+		let embedding = guidance_scale_embedding(1.001, 4);
+		assert!((embedding[0] - 1.0_f32.sin()).abs() < 1e-3);
+		assert!((embedding[1] - 1e-4_f32.sin()).abs() < 1e-6);
+		assert!((embedding[2] - 1.0_f32.cos()).abs() < 1e-3);
+
+		// odd dimensions are zero-padded
+		let embedding = guidance_scale_embedding(8.0, 5);
+		assert_eq!(embedding.len(), 5);
+		assert_eq!(embedding[4], 0.0);
+	}
+
+	#[test]
+	fn batched_input() {
+		let input = guidance_embedding_input(8.0, 256, 3);
+		assert_eq!(input.dim(), (3, 256));
+		assert_eq!(input.row(0), input.row(2));
+	}
+}
@@ -1,8 +1,12 @@
-use image::imageops::FilterType;
 use image::{DynamicImage, Rgb32FImage};
 use ndarray::{Array4, Ix};
+use ndarray_rand::rand::{self, Rng};
 
-use crate::{pipelines::stable_diffusion::StableDiffusionTxt2ImgOptions, Prompt, StableDiffusionCallback};
+use super::impl_txt2img::latents_shape;
+use crate::{
+	pipelines::stable_diffusion::StableDiffusionTxt2ImgOptions, schedulers::validate_custom_sigmas, DiffusionScheduler, HalfLatents, ImageRef, Prompt,
+	ResizeFilter, StableDiffusionCallback, StableDiffusionOutput, StableDiffusionPipeline,
+};
 
 /// The image preprocessing method to on images that mismatch size.
 #[derive(Debug)]
@@ -13,12 +17,83 @@ pub enum ImagePreprocessing {
 	CropFill,
 }
 
+/// How to fit an init image whose dimensions aren't a multiple of 8 when generating at the image's own size; see
+/// [`StableDiffusionImg2ImgOptions::with_resize_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeMode {
+	/// Resize the image to the nearest multiple of 8 in each dimension. This slightly distorts the aspect ratio.
+	Stretch,
+	/// Pad the bottom & right edges of the image up to the next multiple of 8 with the given [`PadMode`]. The original
+	/// image occupies the top-left corner of the output.
+	Pad(PadMode),
+	/// Center crop the image down to the previous multiple of 8. At most 7 pixels are removed from each dimension,
+	/// split evenly between both sides.
+	Crop,
+}
+
+/// How to fill the border added by [`ResizeMode::Pad`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PadMode {
+	/// Repeat the pixels at the edge of the image. **This is the default.**
+	#[default]
+	Edge,
+	/// Mirror the image at its edge, excluding the edge pixels themselves (`abc|ba`).
+	Reflect,
+}
+
+impl ResizeMode {
+	/// Returns the size (which is always a multiple of 8) an image of the given size will be fitted to.
+	pub fn target_size(&self, width: u32, height: u32) -> (u32, u32) {
+		let fit = |x: u32| match self {
+			ResizeMode::Stretch => ((x + 4) / 8).max(1) * 8,
+			ResizeMode::Pad(_) => ((x + 7) / 8).max(1) * 8,
+			ResizeMode::Crop => (x / 8).max(1) * 8,
+		};
+		(fit(width), fit(height))
+	}
+
+	fn apply(&self, image: &DynamicImage, filter: ResizeFilter) -> Rgb32FImage {
+		let (width, height) = (image.width(), image.height());
+		let (target_width, target_height) = self.target_size(width, height);
+		match self {
+			ResizeMode::Stretch => filter.resize(image, target_width, target_height).to_rgb32f(),
+			ResizeMode::Pad(mode) => {
+				let image = image.to_rgb32f();
+				let map = |x: u32, len: u32| match mode {
+					_ if x < len => x,
+					PadMode::Edge => len - 1,
+					PadMode::Reflect => (2 * (len - 1)).saturating_sub(x).min(len - 1),
+				};
+				Rgb32FImage::from_fn(target_width, target_height, |x, y| *image.get_pixel(map(x, width), map(y, height)))
+			}
+			ResizeMode::Crop if target_width > width || target_height > height => {
+				// smaller than 8 pixels; nothing to crop
+				filter.resize(image, target_width, target_height).to_rgb32f()
+			}
+			ResizeMode::Crop => image
+				.crop_imm((width - target_width) / 2, (height - target_height) / 2, target_width, target_height)
+				.to_rgb32f(),
+		}
+	}
+}
+
 /// Options for the Stable Diffusion image-to-image pipeline.
 #[derive(Debug)]
 pub struct StableDiffusionImg2ImgOptions {
 	pub reference_image: Array4<f32>,
 	pub noise_strength: f32,
 	pub preprocessing: ImagePreprocessing,
+	/// The filter reference images are resized with. Defaults to [`ResizeFilter::Lanczos3`]. See
+	/// [`StableDiffusionImg2ImgOptions::with_resize_filter`].
+	pub resize_filter: ResizeFilter,
+	/// If set, reference images are generated at their own size, fitted to a multiple of 8 with this mode, instead of
+	/// being resized to the configured size. See [`StableDiffusionImg2ImgOptions::with_resize_mode`].
+	pub resize_mode: Option<ResizeMode>,
+	/// The size of the (first) reference image before it was fitted with `resize_mode`.
+	pub original_size: Option<(u32, u32)>,
+	/// The reference images as given to [`with_image`](Self::with_image) or [`with_images`](Self::with_images), one per
+	/// image of the batch, which are preprocessed again when the resize filter or mode changes.
+	pub source_images: Vec<DynamicImage>,
 	pub text_config: StableDiffusionTxt2ImgOptions,
 }
 
@@ -28,6 +103,10 @@ impl Default for StableDiffusionImg2ImgOptions {
 			reference_image: Array4::default((1, 1, 1, 1)),
 			noise_strength: 0.6,
 			preprocessing: ImagePreprocessing::CropFill,
+			resize_filter: ResizeFilter::Lanczos3,
+			resize_mode: None,
+			original_size: None,
+			source_images: Vec::new(),
 			text_config: StableDiffusionTxt2ImgOptions::default(),
 		}
 	}
@@ -68,6 +147,7 @@ impl StableDiffusionImg2ImgOptions {
 	#[inline(always)]
 	fn drop_reference_image(&mut self) {
 		self.reference_image = Array4::default((1, 1, 1, 1));
+		self.source_images.clear();
 	}
 
 	/// The number of steps to take to generate the image. Typically, more steps yields higher quality images up to a
@@ -173,27 +253,82 @@ impl StableDiffusionImg2ImgOptions {
 		self
 	}
 
+	/// Generate at the reference image's own size instead of the size set by
+	/// [`with_size`](StableDiffusionImg2ImgOptions::with_size), using `resize_mode` to fit images whose dimensions aren't
+	/// a multiple of 8. Unlike resizing to an arbitrary size, this preserves the aspect ratio (except with
+	/// [`ResizeMode::Stretch`]). Reference images which were already set are fitted again.
+	///
+	/// The size of the generated images is given by [`ResizeMode::target_size`]. Generated images map back to the
+	/// original image as follows:
+	/// - [`ResizeMode::Stretch`]: the whole output corresponds to the whole original image, slightly rescaled.
+	/// - [`ResizeMode::Pad`]: the top-left `original_width x original_height` pixels correspond to the original image;
+	///   the rest is padding.
+	/// - [`ResizeMode::Crop`]: the output corresponds to the original image with `(original - output) / 2` pixels
+	///   (rounded down) removed from the left/top, and the remainder from the right/bottom.
+	///
+	/// [`StableDiffusionImg2ImgOptions::restore_original_size`] performs this mapping for `Stretch` & `Pad`.
+	///
+	/// If multiple images with different sizes are given, the size is determined by the first image, and the other
+	/// images are fitted to it with the configured [`ImagePreprocessing`].
+	pub fn with_resize_mode(mut self, resize_mode: ResizeMode) -> Self {
+		self.resize_mode = Some(resize_mode);
+		self.preprocess_source_images();
+		self
+	}
+
+	/// Sets the filter reference images are resized with, both to fit them to the generation size and to restore
+	/// [`ResizeMode::Stretch`] images to their original size. Defaults to [`ResizeFilter::Lanczos3`], which suits
+	/// photos. Reference images which were already set are resized again with the new filter.
+	pub fn with_resize_filter(mut self, resize_filter: ResizeFilter) -> Self {
+		self.resize_filter = resize_filter;
+		self.preprocess_source_images();
+		self
+	}
+
+	/// Maps an image generated with a [`ResizeMode`] back to the size of the original reference image: images
+	/// generated with [`ResizeMode::Pad`] are cropped to remove the padding, and images generated with
+	/// [`ResizeMode::Stretch`] are resized back to the original size. Images generated with [`ResizeMode::Crop`] (or
+	/// without a resize mode) are returned unchanged, since the cropped pixels can't be restored.
+	pub fn restore_original_size(&self, image: &DynamicImage) -> DynamicImage {
+		match (self.resize_mode, self.original_size) {
+			(Some(ResizeMode::Pad(_)), Some((width, height))) => image.crop_imm(0, 0, width, height),
+			(Some(ResizeMode::Stretch), Some((width, height))) => self.resize_filter.resize(image, width, height),
+			_ => image.clone(),
+		}
+	}
+
+	/// Sets the generation size from the (first) reference image if a resize mode is set.
+	fn size_from_image(&mut self) {
+		if let (Some(resize_mode), Some(image)) = (self.resize_mode, self.source_images.first()) {
+			let (width, height) = resize_mode.target_size(image.width(), image.height());
+			self.text_config.width = width;
+			self.text_config.height = height;
+			self.original_size = Some((image.width(), image.height()));
+		}
+	}
+
 	/// Set a reference image to for generating
 	pub fn with_image(mut self, image: &DynamicImage, batch: usize) -> Self {
-		// whc -> nchw
-		let image = self.img_norm(image);
-		let shape = [batch, 3, self.text_config.height as usize, self.text_config.width as usize];
-		self.reference_image = Array4::from_shape_fn(shape, |(_, c, h, w)| {
-			let pixel = image.get_pixel(w as u32, h as u32);
-			match c {
-				0 => pixel.0[0],
-				1 => pixel.0[1],
-				2 => pixel.0[2],
-				_ => unreachable!(),
-			}
-		});
+		self.source_images = vec![image.clone(); batch];
+		self.preprocess_source_images();
 		self
 	}
 
 	/// Set reference images to for generating, batch size must be equal to `images.len()`
 	pub fn with_images(mut self, images: &[DynamicImage]) -> Self {
+		self.source_images = images.to_vec();
+		self.preprocess_source_images();
+		self
+	}
+
+	/// Converts the source images to the reference image array with the current resize filter & mode.
+	fn preprocess_source_images(&mut self) {
+		if self.source_images.is_empty() {
+			return;
+		}
 		// nwhc -> nchw
-		let images = images.iter().map(|image| self.img_norm(image)).collect::<Vec<_>>();
+		self.size_from_image();
+		let images = self.source_images.iter().map(|image| self.img_norm(image)).collect::<Vec<_>>();
 		let shape = [images.len(), 3, self.text_config.height as usize, self.text_config.width as usize];
 		self.reference_image = Array4::from_shape_fn(shape, |(n, c, h, w)| {
 			let pixel = images[n].get_pixel(w as u32, h as u32);
@@ -204,13 +339,18 @@ impl StableDiffusionImg2ImgOptions {
 				_ => unreachable!(),
 			}
 		});
-		self
 	}
 
 	fn img_norm(&self, image: &DynamicImage) -> Rgb32FImage {
+		if let Some(resize_mode) = self.resize_mode {
+			let fitted = resize_mode.apply(image, self.resize_filter);
+			if fitted.width() == self.text_config.width && fitted.height() == self.text_config.height {
+				return fitted;
+			}
+		}
 		let img = match self.preprocessing {
-			ImagePreprocessing::Resize => image.resize_exact(self.text_config.width, self.text_config.height, FilterType::Lanczos3),
-			ImagePreprocessing::CropFill => image.resize_to_fill(self.text_config.width, self.text_config.height, FilterType::Lanczos3),
+			ImagePreprocessing::Resize => self.resize_filter.resize(image, self.text_config.width, self.text_config.height),
+			ImagePreprocessing::CropFill => image.resize_to_fill(self.text_config.width, self.text_config.height, self.resize_filter.filter_type()),
 		};
 		// normalize to [0, 1]
 		img.to_rgb32f()
@@ -234,6 +374,15 @@ impl StableDiffusionImg2ImgOptions {
 		self
 	}
 
+	#[doc = include_str!("_doc/callback-latents-f16.md")]
+	pub fn callback_latents_f16<F>(mut self, frequency: usize, callback: F) -> Self
+	where
+		F: Fn(usize, f32, HalfLatents) -> bool + 'static,
+	{
+		self.text_config.callback = Some(StableDiffusionCallback::LatentsF16 { frequency, cb: Box::new(callback) });
+		self
+	}
+
 	#[doc = include_str!("_doc/callback-decode-image.md")]
 	pub fn callback_decoded<F>(mut self, frequency: usize, callback: F) -> Self
 	where
@@ -251,4 +400,70 @@ impl StableDiffusionImg2ImgOptions {
 		self.text_config.callback = Some(StableDiffusionCallback::ApproximateDecoded { frequency, cb: Box::new(callback) });
 		self
 	}
+
+	/// Generates images from the reference image(s) & returns them. See
+	/// [`StableDiffusionImg2ImgOptions::run_with_output`].
+	pub fn run<S: DiffusionScheduler>(&self, session: &StableDiffusionPipeline, scheduler: &mut S) -> anyhow::Result<Vec<DynamicImage>> {
+		self.run_with_output(session, scheduler)?.images.into_iter().map(ImageRef::into_image).collect()
+	}
+
+	/// Generates images from the reference image(s), returning the full [`StableDiffusionOutput`].
+	///
+	/// The reference images are encoded through the VAE encoder, and noised to the timestep `floor(steps * strength)`
+	/// steps before the end of the schedule (see [`with_noise_strength`](Self::with_noise_strength)); only the
+	/// remaining tail of the scheduler's timesteps is denoised. Callbacks are called for each of these steps, with step
+	/// numbers counted from the start of the full schedule. A strength of `0` takes no steps, and returns the reference
+	/// images as reconstructed by the VAE.
+	///
+	/// The noise is drawn exactly like the initial noise of a text-to-image generation with the same seed, so the
+	/// scheduler's RNG also continues from the same state. A single reference image is used for every image of the
+	/// batch; otherwise, there must be one reference image per generated image.
+	pub fn run_with_output<S: DiffusionScheduler>(&self, session: &StableDiffusionPipeline, scheduler: &mut S) -> anyhow::Result<StableDiffusionOutput> {
+		let options = &self.text_config;
+		let (width, height) = (options.width as usize, options.height as usize);
+		if self.reference_image.shape()[2..] != [height, width] {
+			anyhow::bail!("no reference image was set for the size of {width}x{height}; call `with_image` or `with_images` after setting the size");
+		}
+		if options.latents.is_some() {
+			anyhow::bail!("image-to-image generation cannot be combined with initial latents");
+		}
+		if !options.averaged_seeds.is_empty() {
+			anyhow::bail!("image-to-image generation cannot be combined with seed averaging");
+		}
+		let prompt_batch_size = options.positive_prompt.len().max(options.negative_prompt.as_ref().map_or(0, |negative_prompt| negative_prompt.len()));
+		let batch_size = prompt_batch_size * options.num_images_per_prompt;
+		let reference_batch_size = self.reference_image.shape()[0];
+		if reference_batch_size != 1 && reference_batch_size != batch_size {
+			anyhow::bail!("{reference_batch_size} reference images were given for a batch of {batch_size} images; expected 1 or one per image");
+		}
+		// the size may have been taken from the reference images, so it is checked before they are encoded
+		session.options.limits.check(options.width, options.height, batch_size, options.steps)?;
+
+		let steps = match options.custom_sigmas.as_deref() {
+			Some(sigmas) => {
+				let steps = validate_custom_sigmas(sigmas)?.len();
+				scheduler.set_sigmas(sigmas)?;
+				steps
+			}
+			None => options.supported_steps(&mut *scheduler)?,
+		};
+		let start_step = (img2img_start_step(self.noise_strength, steps) * S::order()).min(scheduler.timesteps().len());
+		let crop = options.checked_output_crop()?;
+
+		let seed = options.seed.unwrap_or_else(|| rand::thread_rng().gen::<u64>());
+		let mut latents = session.encode_images(self.reference_image.view())?;
+		let shape = latents_shape(batch_size, options.height, options.width);
+		if latents.dim() != shape {
+			latents = latents.broadcast(shape).ok_or_else(|| anyhow::anyhow!("reference latents cannot be broadcast to {shape:?}"))?.to_owned();
+		}
+		let denoised = options.denoise_from_latents(session, scheduler, latents.view(), start_step, steps, seed)?;
+		options.decode_denoised(session, denoised, crop)
+	}
+}
+
+/// Returns the step an image-to-image generation of `steps` steps starts at: the reference image is noised to
+/// `init_timestep = floor(steps * noise_strength)` steps before the end of the schedule.
+pub(crate) fn img2img_start_step(noise_strength: f32, steps: usize) -> usize {
+	let init_timestep = ((steps as f32 * noise_strength.clamp(0.0, 1.0)).floor() as usize).min(steps);
+	steps - init_timestep
 }
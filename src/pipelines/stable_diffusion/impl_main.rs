@@ -13,22 +13,36 @@
 // limitations under the License.
 
 use std::{
-	fs,
+	collections::HashMap,
+	fmt, fs,
 	path::{Path, PathBuf},
 	sync::Arc,
 };
 
 use image::{DynamicImage, Rgb32FImage};
-use ndarray::{concatenate, Array2, Array4, ArrayD, ArrayView4, Axis};
-use ndarray_einsum_beta::einsum;
-use ort::{Environment, OrtOwnedTensor, OrtResult, Session, SessionBuilder};
+use ndarray::{arr0, concatenate, s, Array1, Array2, Array3, Array4, ArrayD, ArrayView2, ArrayView3, ArrayView4, ArrayViewD, Axis, CowArray, Ix4, IxDyn};
+use ort::{Environment, OrtOwnedTensor, Value};
 
+use super::{
+	attend_and_excite::ATTENTION_MAPS_OUTPUT,
+	clip_score::CLIPScorer,
+	deepcache::DeepCacheUNet,
+	lpw,
+	prompt_cache::{cache_key, PromptCache},
+	reference_attention::REFERENCE_HIDDEN_STATES_OUTPUT,
+	snapshot::{SessionLoader, SnapshotManifest},
+	timing::TimingModel,
+	to_files::write_image,
+};
 use crate::{
-	clip::CLIPStandardTokenizer,
-	config::{DiffusionFramework, DiffusionPipeline, StableDiffusionConfig, TokenizerConfig},
-	pipelines::StableDiffusionOptions,
+	clip::{is_low_signal_prompt, CLIPStandardTokenizer, TokenizerUnavailable},
+	config::{DiffusionFramework, DiffusionPipeline, ModelMetadata, StableDiffusionConfig},
+	merge_unets,
+	pipelines::{broadcast_prompt_batch, LatentsDecoder, StableDiffusionOptions, VAEOutputMismatch},
+	session_tracker::{load_session, ModelSource, TrackedSession},
 	text_embeddings::TextEmbeddings,
-	Prompt,
+	ClampReport, ComponentCompatibility, ControlNet, DiffusionDevice, DiffusionDeviceControl, ExecutionContext, ImageFileFormat, ImageRegion,
+	LowSignalPrompts, ModelCompatibilityReport, Prompt, PromptInput, PromptWeighting, SessionInfo,
 };
 
 /// A [Stable Diffusion](https://github.com/CompVis/stable-diffusion) pipeline.
@@ -52,21 +66,48 @@ use crate::{
 /// # }
 /// ```
 pub struct StableDiffusionPipeline {
-	environment: Arc<Environment>,
-	options: StableDiffusionOptions,
-	config: StableDiffusionConfig,
-	vae_encoder: Option<Session>,
-	vae_decoder: Session,
-	text_encoder: Session,
+	context: ExecutionContext,
+	pub(crate) options: StableDiffusionOptions,
+	active_devices: DiffusionDeviceControl,
+	pub(crate) config: StableDiffusionConfig,
+	pub(crate) vae_encoder: Option<TrackedSession>,
+	vae_decoder: TrackedSession,
+	pub(crate) preview_decoder: Option<TrackedSession>,
+	pub(crate) text_encoder: TrackedSession,
 	/// The [text embeddings](TextEmbeddings) used by the text encoder. This can be used to add textual inversion
 	/// weights.
 	pub text_embeddings: TextEmbeddings,
-	pub(crate) unet: Session,
-	safety_checker: Option<Session>,
+	pub(crate) unet: TrackedSession,
+	pub(crate) deepcache_unet: Option<DeepCacheUNet>,
+	pub(crate) inpaint_unet_path: Option<PathBuf>,
+	pub(crate) safety_checker: Option<TrackedSession>,
 	#[allow(dead_code)]
 	feature_extractor: Option<()>,
+	pub(crate) clip_scorer: Option<CLIPScorer>,
+	pub(crate) timing_model: Option<TimingModel>,
+	pub(crate) prompt_cache: Option<PromptCache>,
+}
+
+/// Error returned by [`StableDiffusionPipeline::from_roots`] when one or more models fail to load, holding the root &
+/// error of each failed model.
+#[derive(Debug)]
+pub struct PipelineLoadErrors {
+	/// The root of each model which failed to load, with its error, in the order of the given roots.
+	pub failures: Vec<(PathBuf, anyhow::Error)>,
+}
+
+impl fmt::Display for PipelineLoadErrors {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} model(s) failed to load:", self.failures.len())?;
+		for (root, error) in &self.failures {
+			write!(f, "\n- `{}`: {error:#}", root.display())?;
+		}
+		Ok(())
+	}
 }
 
+impl std::error::Error for PipelineLoadErrors {}
+
 impl StableDiffusionPipeline {
 	/// A recommended 'safety concept' for original Stable Diffusion models. This prompt is designed to be used as a
 	/// negative prompt to prevent the model from generating potentially harmful content.
@@ -107,76 +148,229 @@ impl StableDiffusionPipeline {
 	/// # }
 	/// ```
 	pub fn new(environment: &Arc<Environment>, root: impl Into<PathBuf>, options: StableDiffusionOptions) -> anyhow::Result<Self> {
+		Self::new_with_context(&ExecutionContext::new(environment), root, options)
+	}
+
+	/// Creates a new Stable Diffusion pipeline like [`StableDiffusionPipeline::new`], creating its sessions through
+	/// `context` so that they share its execution provider options with the sessions of other pipelines created through
+	/// it; see [`ExecutionContext`].
+	pub fn new_with_context(context: &ExecutionContext, root: impl Into<PathBuf>, options: StableDiffusionOptions) -> anyhow::Result<Self> {
 		let root: PathBuf = root.into();
-		let config: DiffusionPipeline = toml::from_str(&fs::read_to_string(root.join("pyke-diffusers.toml"))?)?;
-		let config: StableDiffusionConfig = match config {
-			DiffusionPipeline::StableDiffusion { framework, inner } => {
-				match framework {
-					DiffusionFramework::Orte { .. } => (),
-					_ => panic!("bad framework"),
-				}
-				inner
+		let config = read_config(&root)?;
+		Self::load(context, &root, config, options, &mut SessionLoader::Source)
+	}
+
+	/// Creates a pipeline for each model root in `roots`, in order, all sharing `environment` & `options`.
+	///
+	/// Sharing one [`Environment`] between any number of pipelines is supported and recommended: the environment
+	/// holds ONNX Runtime's global state (logging & thread pools), so creating one per pipeline only adds overhead.
+	/// Each pipeline still loads its own sessions, so models are not deduplicated between pipelines; use
+	/// [`SessionTracker`](crate::SessionTracker) or [`StableDiffusionOptions::max_resident_bytes`] to keep the total
+	/// memory usage in check.
+	///
+	/// Every model is attempted even if an earlier one fails to load. If any fail, no pipelines are returned, and the
+	/// error is a [`PipelineLoadErrors`] listing the error of each failed root.
+	///
+	/// ```
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use std::path::PathBuf;
+	/// # use pyke_diffusers::{StableDiffusionPipeline, StableDiffusionOptions, OrtEnvironment};
+	/// let environment = OrtEnvironment::default().into_arc();
+	/// let roots = [PathBuf::from("tests/stable-diffusion"), PathBuf::from("tests/stable-diffusion")];
+	/// let pipelines = StableDiffusionPipeline::from_roots(&environment, &roots, StableDiffusionOptions::default())?;
+	/// assert_eq!(pipelines.len(), 2);
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn from_roots(environment: &Arc<Environment>, roots: &[PathBuf], options: StableDiffusionOptions) -> anyhow::Result<Vec<Self>> {
+		Self::from_roots_with_context(&ExecutionContext::new(environment), roots, options)
+	}
+
+	/// Creates a pipeline for each model root in `roots` like [`StableDiffusionPipeline::from_roots`], creating all
+	/// sessions through `context`; see [`ExecutionContext`].
+	pub fn from_roots_with_context(context: &ExecutionContext, roots: &[PathBuf], options: StableDiffusionOptions) -> anyhow::Result<Vec<Self>> {
+		let mut pipelines = Vec::with_capacity(roots.len());
+		let mut failures = Vec::new();
+		for root in roots {
+			match Self::new_with_context(context, root.clone(), options.clone()) {
+				Ok(pipeline) => pipelines.push(pipeline),
+				Err(error) => failures.push((root.clone(), error)),
 			}
-			#[allow(unreachable_patterns)]
-			_ => anyhow::bail!("not a stable diffusion pipeline"),
-		};
+		}
+		if !failures.is_empty() {
+			return Err(PipelineLoadErrors { failures }.into());
+		}
+		Ok(pipelines)
+	}
 
-		let tokenizer = match &config.tokenizer {
-			TokenizerConfig::CLIPTokenizer {
-				path,
-				model_max_length,
-				bos_token,
-				eos_token,
-			} => CLIPStandardTokenizer::new(root.join(path.clone()), *model_max_length, *bos_token, *eos_token)?,
-			#[allow(unreachable_patterns)]
-			_ => anyhow::bail!("not a clip tokenizer"),
-		};
+	/// Loads a pipeline like [`StableDiffusionPipeline::new`], and writes a warm snapshot of it to `snapshot_dir`,
+	/// which [`StableDiffusionPipeline::from_snapshot`] can load much faster. `snapshot_dir` is created if it doesn't
+	/// exist; an existing snapshot in it is replaced.
+	///
+	/// The snapshot consists of the ONNX Runtime-optimized graphs of the text encoder, VAE, UNet, & safety checker, and
+	/// a manifest recording the optimized models' digests, the original models' sizes & modification times, the
+	/// tokenizer's digest, the devices each model was optimized for, and the validated model config. The CLIP scorer
+	/// (if any) is not snapshotted. Snapshots are not supported with
+	/// [merged UNets](StableDiffusionOptions::with_merged_unet).
+	///
+	/// Optimized graphs may contain device-specific operators, so a snapshot should only be used on the machine (or an
+	/// identical one) it was created on.
+	pub fn create_snapshot(
+		environment: &Arc<Environment>,
+		root: impl Into<PathBuf>,
+		options: StableDiffusionOptions,
+		snapshot_dir: impl AsRef<Path>,
+	) -> anyhow::Result<Self> {
+		let root = fs::canonicalize(root.into())?;
+		let config = read_config(&root)?;
+		Self::write_snapshot(&ExecutionContext::new(environment), root, config, options, snapshot_dir.as_ref())
+	}
+
+	/// Loads a pipeline from a warm snapshot written by [`StableDiffusionPipeline::create_snapshot`].
+	///
+	/// Loading a snapshot skips parsing the model config & graph optimization, which is most of the time spent loading
+	/// a large model on a cold start; the sessions are created directly from the pre-optimized graphs. How much time is
+	/// saved depends on the model & execution provider, and is largest for the UNet; measure it for your model with
+	/// `SNAPSHOT_MODEL=path/to/model cargo bench --bench snapshot`. Validating the snapshot reads each optimized model
+	/// once to check its digest, which also warms the OS page cache for ONNX Runtime.
+	///
+	/// If the snapshot is stale or corrupt (the tokenizer or an original model changed, an optimized model's digest
+	/// doesn't match, or `options` places a model on a different device), the pipeline is loaded from the original
+	/// models recorded in the snapshot instead, and the snapshot is refreshed. Fails if `snapshot_dir` contains no
+	/// readable snapshot manifest.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, StableDiffusionOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// // once, e.g. when building the worker image
+	/// StableDiffusionPipeline::create_snapshot(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default(), "./snapshot/")?;
+	/// // on every cold start
+	/// let pipeline = StableDiffusionPipeline::from_snapshot(&environment, "./snapshot/", StableDiffusionOptions::default())?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn from_snapshot(environment: &Arc<Environment>, snapshot_dir: impl AsRef<Path>, options: StableDiffusionOptions) -> anyhow::Result<Self> {
+		let context = &ExecutionContext::new(environment);
+		let snapshot_dir = snapshot_dir.as_ref();
+		let manifest = SnapshotManifest::read(snapshot_dir)?;
+		if options.unet_merge.is_some() {
+			anyhow::bail!("snapshots do not support merged UNets");
+		}
+
+		let mut devices = vec![("text encoder", &options.devices.text_encoder), ("VAE decoder", &options.devices.vae_decoder), ("UNet", &options.devices.unet)];
+		if manifest.config.vae.encoder.is_some() {
+			devices.push(("VAE encoder", &options.devices.vae_encoder));
+		}
+		if manifest.config.safety_checker.is_some() {
+			devices.push(("safety checker", &options.devices.safety_checker));
+		}
+		let restored = manifest.validate(snapshot_dir, &devices).and_then(|_| {
+			let (root, config) = (manifest.root.clone(), manifest.config.clone());
+			let mut loader = SessionLoader::RestoreSnapshot { dir: snapshot_dir.to_path_buf(), manifest: manifest.clone() };
+			Self::load(context, &root, config, options.clone(), &mut loader)
+		});
+		match restored {
+			Ok(pipeline) => Ok(pipeline),
+			Err(e) => {
+				tracing::warn!("refreshing stale snapshot at `{}`: {e}", snapshot_dir.display());
+				let config = read_config(&manifest.root)?;
+				Self::write_snapshot(context, manifest.root, config, options, snapshot_dir)
+			}
+		}
+	}
+
+	fn write_snapshot(
+		context: &ExecutionContext,
+		root: PathBuf,
+		config: StableDiffusionConfig,
+		options: StableDiffusionOptions,
+		snapshot_dir: &Path,
+	) -> anyhow::Result<Self> {
+		if options.unet_merge.is_some() {
+			anyhow::bail!("snapshots do not support merged UNets");
+		}
+		fs::create_dir_all(snapshot_dir)?;
+		let mut loader = SessionLoader::CreateSnapshot { dir: snapshot_dir.to_path_buf(), components: Vec::new() };
+		let pipeline = Self::load(context, &root, config.clone(), options, &mut loader)?;
+		if let SessionLoader::CreateSnapshot { components, .. } = loader {
+			SnapshotManifest::new(root, config, components)?.write(snapshot_dir)?;
+		}
+		Ok(pipeline)
+	}
+
+	fn load(
+		context: &ExecutionContext,
+		root: &Path,
+		mut config: StableDiffusionConfig,
+		options: StableDiffusionOptions,
+		loader: &mut SessionLoader,
+	) -> anyhow::Result<Self> {
+		options.color_transfer.validate()?;
+		if options.quantize {
+			use_quantized_models(&mut config, &options)?;
+		}
+		let tokenizer = CLIPStandardTokenizer::from_config(root, &config.tokenizer, options.special_token_validation)?
+			.with_truncation_strategy(options.truncation_strategy);
 		let text_embeddings = TextEmbeddings::from_file(root.join(&config.text_encoder.text_embeddings.as_ref().unwrap().path), tokenizer)?;
 
-		let text_encoder = SessionBuilder::new(environment)?
-			.with_execution_providers([options.devices.text_encoder.clone().into()])?
-			.with_model_from_file(root.join(config.text_encoder.path.clone()))?;
+		let max_resident_bytes = options.max_resident_bytes;
+		let text_encoder = loader.load(context, &options.devices.text_encoder, "text encoder", &root.join(&config.text_encoder.path), max_resident_bytes)?;
 
 		let vae_encoder = config
 			.vae
 			.encoder
 			.as_ref()
-			.map(|path| -> OrtResult<Session> {
-				SessionBuilder::new(environment)?
-					.with_execution_providers([options.devices.vae_encoder.clone().into()])?
-					.with_model_from_file(root.join(path))
-			})
+			.map(|path| loader.load(context, &options.devices.vae_encoder, "VAE encoder", &root.join(path), max_resident_bytes))
 			.transpose()?;
 
-		let vae_decoder = SessionBuilder::new(environment)?
-			.with_execution_providers([options.devices.vae_decoder.clone().into()])?
-			.with_model_from_file(root.join(config.vae.decoder.clone()))?;
+		check_free_memory(&options, "VAE decoder", &root.join(&config.vae.decoder))?;
+		let vae_decoder = loader.load(context, &options.devices.vae_decoder, "VAE decoder", &root.join(&config.vae.decoder), max_resident_bytes)?;
+		let preview_decoder = load_preview_decoder(context, &options, root, &config)?;
 
-		let unet = SessionBuilder::new(environment)?
-			.with_execution_providers([options.devices.unet.clone().into()])?
-			.with_model_from_file(root.join(config.unet.path.clone()))?;
+		check_unet_free_memory(&options, &root.join(&config.unet.path))?;
+		let unet = match options.unet_merge {
+			Some(_) => load_unet(context, &options, root.join(config.unet.path.clone()), None)?,
+			None => loader.load(context, &options.devices.unet, "UNet", &root.join(&config.unet.path), max_resident_bytes)?,
+		};
+		let deepcache_unet = DeepCacheUNet::load(context, &options, root, &config)?;
+		let inpaint_unet_path = config.unet.inpaint.as_ref().map(|path| root.join(path));
 
 		let safety_checker = config
 			.safety_checker
 			.as_ref()
-			.map(|safety_checker| -> OrtResult<Session> {
-				SessionBuilder::new(environment)?
-					.with_execution_providers([options.devices.safety_checker.clone().into()])?
-					.with_model_from_file(root.join(safety_checker.path.clone()))
+			.map(|safety_checker| {
+				loader.load(context, &options.devices.safety_checker, "safety checker", &root.join(&safety_checker.path), max_resident_bytes)
 			})
 			.transpose()?;
 
+		let clip_scorer = config
+			.clip_scorer
+			.as_ref()
+			.map(|clip_scorer| CLIPScorer::load(context, root, clip_scorer, &options.devices.safety_checker, max_resident_bytes, None))
+			.transpose()?;
+
+		let active_devices = options.devices.resolve();
+		let prompt_cache = options.prompt_cache.clone().map(|cache| PromptCache::new(cache, root, &config, &options));
+
 		Ok(Self {
-			environment: Arc::clone(environment),
+			context: context.clone(),
+			active_devices,
 			options,
 			config,
 			vae_encoder,
 			vae_decoder,
+			preview_decoder,
 			text_encoder,
 			text_embeddings,
 			unet,
+			deepcache_unet,
+			inpaint_unet_path,
 			safety_checker,
 			feature_extractor: None,
+			clip_scorer,
+			timing_model: None,
+			prompt_cache,
 		})
 	}
 
@@ -197,58 +391,189 @@ impl StableDiffusionPipeline {
 	/// ```
 	pub fn replace(mut self, new_root: impl Into<PathBuf>, options: Option<StableDiffusionOptions>) -> anyhow::Result<Self> {
 		let new_root: PathBuf = new_root.into();
-		let new_config: DiffusionPipeline = toml::from_str(&fs::read_to_string(new_root.join("pyke-diffusers.toml"))?)?;
-		let new_config: StableDiffusionConfig = match new_config {
-			DiffusionPipeline::StableDiffusion { framework, inner } => {
-				match framework {
-					DiffusionFramework::Orte { .. } => (),
-					_ => panic!("bad framework"),
-				}
-				inner
-			}
-			#[allow(unreachable_patterns)]
-			_ => anyhow::bail!("not a stable diffusion pipeline!"),
-		};
+		let mut new_config = read_config(&new_root)?;
 
 		let options = options.unwrap_or_else(|| self.options.clone());
+		if options.quantize {
+			use_quantized_models(&mut new_config, &options)?;
+		}
+		// the hashes are of the original models, so toggling quantization reloads every model
+		let requantize = self.options.quantize != options.quantize;
 
-		if self.config.hashes.unet != new_config.hashes.unet {
+		if requantize || self.config.hashes.unet != new_config.hashes.unet || self.options.unet_merge != options.unet_merge {
 			let path = new_root.join(new_config.unet.path.clone());
-			self.replace_unet(path)?
+			check_unet_free_memory(&options, &path)?;
+			self.unet = load_unet(&self.context, &options, path, Some(&self.unet))?;
 		}
-		if self.config.hashes.text_encoder != new_config.hashes.text_encoder {
+		if requantize || self.config.hashes.text_encoder != new_config.hashes.text_encoder {
 			let path = new_root.join(new_config.text_encoder.path.clone());
 			self.replace_text_encoder(path)?
 		}
-		if self.config.hashes.vae_decoder != new_config.hashes.vae_decoder || self.config.hashes.vae_encoder != new_config.hashes.vae_encoder {
+		if requantize
+			|| self.config.hashes.vae_decoder != new_config.hashes.vae_decoder
+			|| self.config.hashes.vae_encoder != new_config.hashes.vae_encoder
+		{
 			let decoder = new_root.join(new_config.vae.decoder.clone());
 			let encoder = new_config.vae.encoder.as_ref().map(|s| new_root.join(s));
 			self.replace_vae(decoder, encoder)?
 		}
-		if self.config.hashes.safety_checker != new_config.hashes.safety_checker {
+		if requantize || self.config.hashes.safety_checker != new_config.hashes.safety_checker {
 			let path = new_config.safety_checker.as_ref().map(|s| new_root.join(&s.path));
 			self.replace_safety_checker(path)?
 		}
+		// the preview decoder has no hash, but is small enough to always reload
+		self.preview_decoder = None;
+		self.preview_decoder = load_preview_decoder(&self.context, &options, &new_root, &new_config)?;
+		// the split UNet has no hash either, and must always match the UNet
+		self.deepcache_unet = None;
+		self.deepcache_unet = DeepCacheUNet::load(&self.context, &options, &new_root, &new_config)?;
+		self.inpaint_unet_path = new_config.unet.inpaint.as_ref().map(|path| new_root.join(path));
+		if self.config.hashes.clip_image_encoder != new_config.hashes.clip_image_encoder {
+			self.clip_scorer = new_config
+				.clip_scorer
+				.as_ref()
+				.map(|clip_scorer| {
+					CLIPScorer::load(
+						&self.context,
+						&new_root,
+						clip_scorer,
+						&options.devices.safety_checker,
+						options.max_resident_bytes,
+						self.clip_scorer.as_ref().map(CLIPScorer::session),
+					)
+				})
+				.transpose()?;
+		}
 
-		let tokenizer = match &new_config.tokenizer {
-			TokenizerConfig::CLIPTokenizer {
-				path,
-				model_max_length,
-				bos_token,
-				eos_token,
-			} => CLIPStandardTokenizer::new(new_root.join(path.clone()), *model_max_length, *bos_token, *eos_token)?,
-			#[allow(unreachable_patterns)]
-			_ => anyhow::bail!("not a clip tokenizer"),
-		};
+		let tokenizer = CLIPStandardTokenizer::from_config(&new_root, &new_config.tokenizer, options.special_token_validation)?
+			.with_truncation_strategy(options.truncation_strategy);
 		self.text_embeddings = TextEmbeddings::from_file(new_root.join(&new_config.text_encoder.text_embeddings.as_ref().unwrap().path), tokenizer)?;
 
+		self.active_devices = options.devices.resolve();
 		self.options.clone_from(&options);
+		self.timing_model = None;
+		self.prompt_cache = options.prompt_cache.clone().map(|cache| PromptCache::new(cache, &new_root, &new_config, &options));
 		self.config = new_config;
 
 		Ok(self)
 	}
 
-	/// Replace unet model at runtime, ensuring that the model is using the same config as before.
+	/// Checks the compatibility of each model file of the Stable Diffusion model at `root` with the linked ONNX Runtime
+	/// version, without loading any models.
+	///
+	/// Each model's IR version & opset imports are read from its header and compared against the versions supported by
+	/// ONNX Runtime; see [`ModelCompatibilityReport`]. This is much faster than loading the pipeline, and the report
+	/// (printed with `{}`) points out models converted with incompatible exporter versions. The same report is included
+	/// in the error returned by [`StableDiffusionPipeline::new`] if ONNX Runtime fails to load a model.
+	///
+	/// ```
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::StableDiffusionPipeline;
+	/// let report = StableDiffusionPipeline::preflight("tests/stable-diffusion")?;
+	/// for (component, warning) in report.warnings() {
+	/// 	eprintln!("{component}: {warning}");
+	/// }
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn preflight(root: impl AsRef<Path>) -> anyhow::Result<ModelCompatibilityReport> {
+		let root = root.as_ref();
+		let config = read_config(root)?;
+
+		let mut models = vec![("text encoder", &config.text_encoder.path), ("UNet", &config.unet.path)];
+		models.extend(config.vae.encoder.as_ref().map(|path| ("VAE encoder", path)));
+		models.push(("VAE decoder", &config.vae.decoder));
+		models.extend(config.vae.preview_decoder.as_ref().filter(|path| root.join(path).exists()).map(|path| ("preview decoder", path)));
+		if let Some(split) = config.unet.deepcache.as_ref() {
+			models.extend([("DeepCache deep UNet", &split.deep), ("DeepCache shallow UNet", &split.shallow)]);
+		}
+		models.extend(config.unet.inpaint.as_ref().map(|path| ("inpainting UNet", path)));
+		models.extend(config.safety_checker.as_ref().map(|safety_checker| ("safety checker", &safety_checker.path)));
+		models.extend(config.clip_scorer.as_ref().map(|clip_scorer| ("CLIP image encoder", &clip_scorer.image_encoder)));
+
+		Ok(ModelCompatibilityReport {
+			components: models
+				.into_iter()
+				.map(|(component, path)| ComponentCompatibility::inspect_file(component, root.join(path)))
+				.collect(),
+		})
+	}
+
+	/// Returns the model's [metadata](ModelMetadata), like its name, author, and license, if its config has a
+	/// `[metadata]` section.
+	///
+	/// ```
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, StableDiffusionOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// let pipeline = StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default())?;
+	/// if let Some(license) = pipeline.metadata().and_then(|m| m.license.as_ref()) {
+	/// 	println!("model license: {license}");
+	/// }
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn metadata(&self) -> Option<&ModelMetadata> {
+		self.config.metadata.as_ref()
+	}
+
+	/// Returns the devices each model was actually placed on.
+	///
+	/// If the execution provider of a requested device is not available (for example, requesting CUDA with a build of
+	/// ONNX Runtime without CUDA support), the model silently falls back to the CPU, which makes generation much
+	/// slower. This returns the resolved devices, so such fallbacks can be detected & reported; a warning is also
+	/// logged via `tracing` when a fallback occurs.
+	///
+	/// Note that this only checks whether the execution provider is available in the loaded ONNX Runtime build; an
+	/// available provider can still fail to initialize (e.g. due to missing CUDA libraries), which ONNX Runtime
+	/// reports in its own logs.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{DiffusionDevice, DiffusionDeviceControl, StableDiffusionPipeline, StableDiffusionOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// let pipeline = StableDiffusionPipeline::new(
+	/// 	&environment,
+	/// 	"./stable-diffusion-v1-5/",
+	/// 	StableDiffusionOptions {
+	/// 		devices: DiffusionDeviceControl::all(DiffusionDevice::CUDA(0, None)),
+	/// 		..Default::default()
+	/// 	}
+	/// )?;
+	/// if matches!(pipeline.active_execution_provider().unet, DiffusionDevice::CPU) {
+	/// 	eprintln!("CUDA is not available; the UNet is running on the CPU");
+	/// }
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn active_execution_provider(&self) -> &DiffusionDeviceControl {
+		&self.active_devices
+	}
+
+	/// Returns the [`ExecutionContext`] this pipeline's sessions are created through. Pipelines created without a
+	/// context have their own, which doesn't override any execution provider options.
+	pub fn execution_context(&self) -> &ExecutionContext {
+		&self.context
+	}
+
+	/// Returns information about each of this pipeline's loaded sessions, including the execution provider each was
+	/// created with; see [`SessionInfo`].
+	pub fn sessions(&self) -> Vec<SessionInfo> {
+		[Some(&self.text_encoder), self.vae_encoder.as_ref(), Some(&self.vae_decoder), self.preview_decoder.as_ref(), Some(&self.unet)]
+			.into_iter()
+			.chain(self.deepcache_unet.iter().flat_map(|unet| unet.sessions().map(Some)))
+			.chain([self.safety_checker.as_ref(), self.clip_scorer.as_ref().map(CLIPScorer::session)])
+			.flatten()
+			.map(|session| session.info().clone())
+			.collect()
+	}
+
+	/// Replace unet model at runtime, ensuring that the model is using the same config as before. This unloads the
+	/// model's [DeepCache](crate::DeepCacheConfig) split UNet, if any, since it was exported from the previous UNet.
+	///
+	/// Returns an `anyhow::Result`, since besides ONNX Runtime errors, loading fails with
+	/// [`ResidentLimitExceeded`](crate::ResidentLimitExceeded) when the new model would exceed
+	/// [`StableDiffusionOptions::max_resident_bytes`]; the previous model is kept loaded.
 	///
 	/// # Arguments
 	///
@@ -268,22 +593,48 @@ impl StableDiffusionPipeline {
 	/// # Ok(())
 	/// # }
 	/// ```
-	pub fn replace_unet<P: AsRef<Path>>(&mut self, path: P) -> OrtResult<()> {
-		self.unet = SessionBuilder::new(&self.environment)?
-			.with_execution_providers([self.options.devices.unet.clone().into()])?
-			.with_model_from_file(path)?;
+	pub fn replace_unet<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+		check_free_memory(&self.options, "UNet", path.as_ref())?;
+		self.unet = load_session(
+			&self.context,
+			&self.options.devices.unet,
+			"UNet",
+			ModelSource::File(path.as_ref()),
+			self.options.max_resident_bytes,
+			Some(&self.unet),
+		)?;
+		// the DeepCache split was exported from the previous UNet
+		self.deepcache_unet = None;
+		self.timing_model = None;
 		Ok(())
 	}
 	/// Replace text encode model at runtime, ensuring that the model is using the same config as before.
-	pub fn replace_text_encoder<P: AsRef<Path>>(&mut self, path: P) -> OrtResult<()> {
-		self.text_encoder = SessionBuilder::new(&self.environment)?
-			.with_execution_providers([self.options.devices.text_encoder.clone().into()])?
-			.with_model_from_file(path)?;
+	///
+	/// Returns an `anyhow::Result`, since besides ONNX Runtime errors, loading fails with
+	/// [`ResidentLimitExceeded`](crate::ResidentLimitExceeded) when the new model would exceed
+	/// [`StableDiffusionOptions::max_resident_bytes`]; the previous model is kept loaded.
+	pub fn replace_text_encoder<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+		self.text_encoder = load_session(
+			&self.context,
+			&self.options.devices.text_encoder,
+			"text encoder",
+			ModelSource::File(path.as_ref()),
+			self.options.max_resident_bytes,
+			Some(&self.text_encoder),
+		)?;
+		self.timing_model = None;
+		if let Some(cache) = self.prompt_cache.as_mut() {
+			cache.replace_text_encoder(path.as_ref());
+		}
 		Ok(())
 	}
 
 	/// Replace vae model at runtime, ensuring that the model is using the same config as before.
 	///
+	/// Returns an `anyhow::Result`, since besides ONNX Runtime errors, loading fails with
+	/// [`ResidentLimitExceeded`](crate::ResidentLimitExceeded) when the new model would exceed
+	/// [`StableDiffusionOptions::max_resident_bytes`]; the previous model is kept loaded.
+	///
 	/// # Arguments
 	///
 	/// * `decoder`: Path to the new vae decoder model, this is required
@@ -303,112 +654,1145 @@ impl StableDiffusionPipeline {
 	/// # Ok(())
 	/// # }
 	/// ```
-	pub fn replace_vae<D, E>(&mut self, decoder: D, encoder: Option<E>) -> OrtResult<()>
+	pub fn replace_vae<D, E>(&mut self, decoder: D, encoder: Option<E>) -> anyhow::Result<()>
 	where
 		E: AsRef<Path>,
 		D: AsRef<Path>,
 	{
-		self.vae_decoder = SessionBuilder::new(&self.environment)?
-			.with_execution_providers([self.options.devices.vae_decoder.clone().into()])?
-			.with_model_from_file(decoder.as_ref())?;
+		check_free_memory(&self.options, "VAE decoder", decoder.as_ref())?;
+		self.vae_decoder = load_session(
+			&self.context,
+			&self.options.devices.vae_decoder,
+			"VAE decoder",
+			ModelSource::File(decoder.as_ref()),
+			self.options.max_resident_bytes,
+			Some(&self.vae_decoder),
+		)?;
 		// unable to use ? in map, so use match here
 		self.vae_encoder = match encoder {
-			Some(s) => Some(
-				SessionBuilder::new(&self.environment)?
-					.with_execution_providers([self.options.devices.vae_encoder.clone().into()])?
-					.with_model_from_file(s)?,
-			),
+			Some(s) => Some(load_session(
+				&self.context,
+				&self.options.devices.vae_encoder,
+				"VAE encoder",
+				ModelSource::File(s.as_ref()),
+				self.options.max_resident_bytes,
+				self.vae_encoder.as_ref(),
+			)?),
 			None => None,
 		};
+		self.timing_model = None;
 		Ok(())
 	}
 	/// Replace safety checker at runtime, ensuring that the model is using the same config as before.
-	pub fn replace_safety_checker<P: AsRef<Path>>(&mut self, path: Option<P>) -> OrtResult<()> {
+	///
+	/// Returns an `anyhow::Result`, since besides ONNX Runtime errors, loading fails with
+	/// [`ResidentLimitExceeded`](crate::ResidentLimitExceeded) when the new model would exceed
+	/// [`StableDiffusionOptions::max_resident_bytes`]; the previous model is kept loaded.
+	pub fn replace_safety_checker<P: AsRef<Path>>(&mut self, path: Option<P>) -> anyhow::Result<()> {
 		self.safety_checker = match path {
-			Some(s) => Some(
-				SessionBuilder::new(&self.environment)?
-					.with_execution_providers([self.options.devices.safety_checker.clone().into()])?
-					.with_model_from_file(s)?,
-			),
+			Some(s) => Some(load_session(
+				&self.context,
+				&self.options.devices.safety_checker,
+				"safety checker",
+				ModelSource::File(s.as_ref()),
+				self.options.max_resident_bytes,
+				self.safety_checker.as_ref(),
+			)?),
 			None => None,
 		};
 		Ok(())
 	}
 
+	/// Loads a [`ControlNet`] from the ONNX model at `path` onto the UNet's device, to guide generations with
+	/// [`StableDiffusionTxt2ImgOptions::with_controlnet`](crate::StableDiffusionTxt2ImgOptions::with_controlnet).
+	///
+	/// ControlNets are loaded separately from the pipeline, so they can be shared by generations & dropped when no
+	/// longer needed; they count towards [`StableDiffusionOptions::max_resident_bytes`] while loaded.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, ControlNetConfig, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// # let mut scheduler = EulerDiscreteScheduler::default();
+	/// let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5-controlnet/", StableDiffusionOptions::default())?;
+	/// let (pose, depth) = (pipeline.load_controlnet("./controlnet-openpose.onnx")?, pipeline.load_controlnet("./controlnet-depth.onnx")?);
+	/// let imgs = StableDiffusionTxt2ImgOptions::default()
+	/// 	.with_prompt("photo of a dancer")
+	/// 	.with_controlnet(ControlNetConfig::new(pose, image::open("pose.png")?).with_step_range(0.0, 0.6))
+	/// 	.with_controlnet(ControlNetConfig::new(depth, image::open("depth.png")?).with_weight(0.5))
+	/// 	.run(&pipeline, &mut scheduler)?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn load_controlnet<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<ControlNet> {
+		let session = load_session(
+			&self.context,
+			&self.options.devices.unet,
+			"ControlNet",
+			ModelSource::File(path.as_ref()),
+			self.options.max_resident_bytes,
+			None,
+		)?;
+		Ok(ControlNet { session: Arc::new(session) })
+	}
+
 	/// Encodes the given prompt(s) into an array of text embeddings to be used as input to the UNet.
+	///
+	/// The embeddings have shape `(batch_size, tokens, hidden_size)`, the layout of the UNet's `encoder_hidden_states`
+	/// input. With classifier-free guidance, the negative prompts' embeddings come first along the batch axis (axis 0),
+	/// followed by the prompts', so the batch size is doubled. Only the text encoder's per-token hidden states are used;
+	/// pooled outputs are ignored.
+	///
+	/// The prompts & negative prompts are broadcast against each other: a single prompt or negative prompt is repeated
+	/// to match the other's batch size, so the batch size of the embeddings may be larger than the number of prompts.
+	///
+	/// # Errors
+	/// Returns [`TokenizerUnavailable`] if the pipeline has no tokenizer vocabulary; use
+	/// [`StableDiffusionPipeline::encode_prompt_input`] with token IDs instead.
+	///
+	/// Returns [`PromptBatchMismatch`](crate::PromptBatchMismatch) if the prompts & negative prompts cannot be
+	/// broadcast together.
+	///
+	/// Returns an error if the text encoder's hidden states don't match the shape of the UNet's `encoder_hidden_states`
+	/// input, e.g. because a custom text encoder has a different hidden size than the UNet was trained with.
 	pub fn encode_prompt(&self, prompt: Prompt, do_classifier_free_guidance: bool, negative_prompt: Option<&Prompt>) -> anyhow::Result<ArrayD<f32>> {
-		let batch_size = prompt.len();
-		let negative_prompt = if let Some(negative_prompt) = negative_prompt {
-			if batch_size > 1 && negative_prompt.len() == 1 {
-				Some(Prompt::from(vec![negative_prompt[0].clone(); batch_size]))
-			} else {
-				assert_eq!(batch_size, negative_prompt.len());
-				Some(negative_prompt.to_owned())
+		self.encode_prompt_with_weighting(prompt, do_classifier_free_guidance, negative_prompt, &[])
+	}
+
+	/// Encodes the given prompt(s) like [`StableDiffusionPipeline::encode_prompt`], choosing how each prompt's text is
+	/// encoded: `weighting` holds either one [`PromptWeighting`] used for every prompt, or one per prompt. An empty
+	/// slice encodes every prompt as [`PromptWeighting::Weighted`]. Each negative prompt is encoded with the weighting
+	/// of its prompt.
+	///
+	/// All prompts of a batch, and their negative prompts, are padded with EOS tokens to the length of the longest
+	/// prompt, since the UNet takes a single `encoder_hidden_states` tensor. A plain prompt batched with a weighted
+	/// prompt longer than the tokenizer's maximum length is therefore padded to several chunks of embeddings, and the
+	/// UNet's cross-attention also attends to the additional padding tokens, which slightly changes its image compared
+	/// to generating it in a batch of its own.
+	pub fn encode_prompt_with_weighting(
+		&self,
+		prompt: Prompt,
+		do_classifier_free_guidance: bool,
+		negative_prompt: Option<&Prompt>,
+		weighting: &[PromptWeighting],
+	) -> anyhow::Result<ArrayD<f32>> {
+		if !self.text_embeddings.tokenizer.has_vocab() {
+			return Err(TokenizerUnavailable.into());
+		}
+		let prompt = self.filter_prompt(prompt)?;
+		let negative_prompt = negative_prompt.map(|negative_prompt| self.filter_prompt(negative_prompt.to_owned())).transpose()?;
+		let negative_prompt = negative_prompt.as_ref();
+		for text in prompt.iter().chain(negative_prompt.into_iter().flat_map(|prompt| prompt.iter())) {
+			if is_low_signal_prompt(text) {
+				match self.options.low_signal_prompts {
+					LowSignalPrompts::Ignore => {}
+					LowSignalPrompts::Warn => tracing::warn!("prompt `{text}` contains no letters or digits and will barely guide the image"),
+					LowSignalPrompts::Error => anyhow::bail!("prompt `{text}` contains no letters or digits"),
+				}
 			}
+		}
+
+		let mut weighting = match weighting.len() {
+			0 => Vec::new(),
+			1 => vec![weighting[0]; prompt.len()],
+			n if n == prompt.len() => weighting.to_vec(),
+			n => anyhow::bail!("{n} prompt weightings were given for {} prompts; expected 1 or one per prompt", prompt.len()),
+		};
+		let batch_size = match negative_prompt {
+			Some(negative_prompt) => broadcast_prompt_batch(prompt.len(), negative_prompt.len())?,
+			None => prompt.len(),
+		};
+		let prompt = if prompt.len() == batch_size {
+			prompt
 		} else {
-			None
+			weighting = weighting.first().map(|&weighting| vec![weighting; batch_size]).unwrap_or_default();
+			prompt.batched(batch_size)
+		};
+		let negative_prompt = negative_prompt.map(|negative_prompt| {
+			if negative_prompt.len() == batch_size { negative_prompt.to_owned() } else { negative_prompt.to_owned().batched(batch_size) }
+		});
+
+		// without classifier-free guidance, the negative prompt is tokenized but not encoded, since it still pads the prompt
+		let uncond_prompt = match negative_prompt {
+			Some(negative_prompt) => Some(negative_prompt),
+			None if do_classifier_free_guidance => Some(Prompt::default_batched(batch_size)),
+			None => None,
 		};
+		let (text_tokens, uncond_tokens) = lpw::tokenize_weighted_prompts(&self.text_embeddings, prompt.clone(), uncond_prompt.clone(), &weighting, 3, true)?;
+		let uncond = if do_classifier_free_guidance { uncond_prompt.zip(uncond_tokens) } else { None };
+
+		// both halves are cached separately, so e.g. changing only the negative prompt doesn't re-encode the prompt
+		let key = |prompt: &Prompt, unconditional: bool| cache_key(prompt, unconditional, &weighting, text_tokens.length(), self.text_embeddings.len());
+		let cached = |key: &str| self.prompt_cache.as_ref().and_then(|cache| cache.get(key));
+		let text_key = self.prompt_cache.as_ref().map(|_| key(&prompt, false));
+		let uncond_key = self.prompt_cache.as_ref().and(uncond.as_ref()).map(|(uncond_prompt, _)| key(uncond_prompt, true));
+		let mut text_embeddings = text_key.as_deref().and_then(cached);
+		let mut uncond_embeddings = uncond_key.as_deref().and_then(cached);
 
-		let text_embeddings = {
-			let embeddings = crate::pipelines::lpw::get_weighted_text_embeddings(
+		let encode_text = text_embeddings.is_none();
+		let encode_uncond = uncond.is_some() && uncond_embeddings.is_none();
+		if encode_text || encode_uncond {
+			let (encoded_text, encoded_uncond) = lpw::encode_weighted_tokens(
 				&self.text_embeddings,
 				&self.text_encoder,
-				prompt,
-				if do_classifier_free_guidance {
-					negative_prompt.or_else(|| Some(Prompt::default_batched(batch_size)))
-				} else {
-					negative_prompt
-				},
-				3,
+				Some(&text_tokens).filter(|_| encode_text),
+				uncond.as_ref().map(|(_, tokens)| tokens).filter(|_| encode_uncond),
 				true,
+				self.options.weight_normalization,
 			)?;
-			let mut text_embeddings = embeddings.0;
-			if do_classifier_free_guidance {
-				if let Some(uncond_embeddings) = embeddings.1 {
-					text_embeddings = concatenate![Axis(0), uncond_embeddings, text_embeddings];
+			let store = |encoded: Array3<f32>, key: Option<&str>| -> anyhow::Result<ArrayD<f32>> {
+				let encoded = encoded.into_dyn();
+				self.check_text_embeddings(&encoded)?;
+				if let (Some(cache), Some(key)) = (self.prompt_cache.as_ref(), key) {
+					cache.insert(key, &encoded);
 				}
+				Ok(encoded)
+			};
+			if let Some(encoded_text) = encoded_text {
+				text_embeddings = Some(store(encoded_text, text_key.as_deref())?);
 			}
-			text_embeddings.into_dyn()
+			if let Some(encoded_uncond) = encoded_uncond {
+				uncond_embeddings = Some(store(encoded_uncond, uncond_key.as_deref())?);
+			}
+		}
+
+		let text_embeddings = text_embeddings.ok_or_else(|| anyhow::anyhow!("prompt embeddings were neither cached nor encoded"))?;
+		let text_embeddings = match uncond_embeddings {
+			Some(uncond_embeddings) => concatenate(Axis(0), &[uncond_embeddings.view(), text_embeddings.view()])?,
+			None => text_embeddings,
 		};
+		self.check_text_embeddings(&text_embeddings)?;
+		Ok(text_embeddings)
+	}
 
+	/// Encodes the given prompt(s), given as either text or token IDs, into an array of text embeddings to be used as
+	/// input to the UNet. See [`StableDiffusionPipeline::encode_prompt`] & [`StableDiffusionPipeline::encode_token_ids`].
+	///
+	/// The prompt & negative prompt must both be the same kind of input.
+	pub fn encode_prompt_input(
+		&self,
+		prompt: &PromptInput,
+		do_classifier_free_guidance: bool,
+		negative_prompt: Option<&PromptInput>,
+	) -> anyhow::Result<ArrayD<f32>> {
+		match (prompt, negative_prompt) {
+			(PromptInput::Text(prompt), None) => self.encode_prompt(prompt.clone(), do_classifier_free_guidance, None),
+			(PromptInput::Text(prompt), Some(PromptInput::Text(negative_prompt))) => {
+				self.encode_prompt(prompt.clone(), do_classifier_free_guidance, Some(negative_prompt))
+			}
+			(PromptInput::TokenIds(ids), None) => self.encode_token_ids(ids.view(), do_classifier_free_guidance, None),
+			(PromptInput::TokenIds(ids), Some(PromptInput::TokenIds(negative_ids))) => {
+				self.encode_token_ids(ids.view(), do_classifier_free_guidance, Some(negative_ids.view()))
+			}
+			_ => anyhow::bail!("prompt and negative prompt must both be text or both be token IDs"),
+		}
+	}
+
+	/// Encodes prompt(s) given as token IDs into an array of text embeddings to be used as input to the UNet. This works
+	/// even if the pipeline has no tokenizer vocabulary.
+	///
+	/// `token_ids` has shape `(batch_size, length)`, where `length` is the tokenizer's maximum length (usually 77), or
+	/// `n * (length - 2) + 2` for long prompts; see [`PromptInput::TokenIds`]. If `negative_token_ids` is `None` and
+	/// classifier-free guidance is enabled, an empty negative prompt is used. The prompts & negative prompts are broadcast
+	/// against each other like in [`StableDiffusionPipeline::encode_prompt`].
+	pub fn encode_token_ids(
+		&self,
+		token_ids: ArrayView2<'_, i64>,
+		do_classifier_free_guidance: bool,
+		negative_token_ids: Option<ArrayView2<'_, i64>>,
+	) -> anyhow::Result<ArrayD<f32>> {
+		let tokenizer = &self.text_embeddings.tokenizer;
+		let chunk_length = tokenizer.len();
+		let (batch_size, length) = token_ids.dim();
+		if batch_size == 0 {
+			anyhow::bail!("token IDs must contain at least one prompt");
+		}
+		if length < chunk_length || (length - 2) % (chunk_length - 2) != 0 {
+			anyhow::bail!(
+				"token IDs have length {length}; expected {chunk_length} (or a multiple of {} plus 2 for long prompts), including BOS & EOS tokens",
+				chunk_length - 2
+			);
+		}
+
+		let to_text_input = |token_ids: ArrayView2<'_, i64>| -> anyhow::Result<Array2<i32>> {
+			let mut text_input = Array2::zeros(token_ids.raw_dim());
+			for (input, &id) in text_input.iter_mut().zip(token_ids.iter()) {
+				*input = match i32::try_from(id) {
+					Ok(id) if id >= 0 && (self.text_embeddings.is_empty() || self.text_embeddings.tokens.contains_key(&(id as u32))) => id,
+					_ => anyhow::bail!("token ID {id} does not exist in the text encoder's vocabulary"),
+				};
+			}
+			Ok(text_input)
+		};
+		let token_ids = match negative_token_ids {
+			Some(negative_token_ids) => {
+				let batch_size = broadcast_prompt_batch(batch_size, negative_token_ids.nrows())?;
+				token_ids.broadcast((batch_size, length)).unwrap()
+			}
+			None => token_ids,
+		};
+		let batch_size = token_ids.nrows();
+		let text_input = to_text_input(token_ids)?;
+		if !do_classifier_free_guidance {
+			let text_embeddings = lpw::get_unweighted_text_embeddings(&self.text_embeddings, &self.text_encoder, text_input, chunk_length, true)?.into_dyn();
+			self.check_text_embeddings(&text_embeddings)?;
+			return Ok(text_embeddings);
+		}
+
+		let negative_token_ids = match negative_token_ids {
+			Some(negative_token_ids) => negative_token_ids.to_owned(),
+			None => {
+				// the empty prompt, as encoded by `encode_for_text_model`
+				let mut negative_token_ids = Array2::from_elem((1, length), tokenizer.pad() as i64);
+				negative_token_ids[[0, 0]] = tokenizer.bos() as i64;
+				negative_token_ids[[0, 1]] = tokenizer.eos() as i64;
+				negative_token_ids
+			}
+		};
+		let negative_token_ids = match negative_token_ids.dim() {
+			(n, l) if n == batch_size && l == length => negative_token_ids,
+			(1, l) if l == length => negative_token_ids.broadcast((batch_size, length)).unwrap().to_owned(),
+			(_, l) => anyhow::bail!("negative token IDs have length {l}; expected {length} to match the prompt's token IDs"),
+		};
+		let uncond_input = to_text_input(negative_token_ids.view())?;
+		let (text_embeddings, uncond_embeddings) =
+			lpw::get_unweighted_text_embeddings_with_uncond(&self.text_embeddings, &self.text_encoder, text_input, uncond_input, chunk_length, true)?;
+		let text_embeddings = concatenate![Axis(0), uncond_embeddings, text_embeddings].into_dyn();
+		self.check_text_embeddings(&text_embeddings)?;
 		Ok(text_embeddings)
 	}
 
-	fn to_image(&self, width: u32, height: u32, arr: &Array4<f32>) -> anyhow::Result<DynamicImage> {
-		Ok(DynamicImage::ImageRgb32F(
-			Rgb32FImage::from_raw(width, height, arr.map(|f| f.clamp(0.0, 1.0)).into_iter().collect::<Vec<_>>())
+	/// Checks text embeddings against the shape of the UNet's `encoder_hidden_states` input, so that embeddings from a
+	/// mismatched text encoder fail with a descriptive error instead of deep inside the UNet. The batch axis is not
+	/// checked, since the embeddings are repeated for each image before they are passed to the UNet.
+	pub(crate) fn check_text_embeddings(&self, text_embeddings: &ArrayD<f32>) -> anyhow::Result<()> {
+		let input = match self.unet.inputs.iter().find(|input| input.name == "encoder_hidden_states").or_else(|| self.unet.inputs.get(2)) {
+			Some(input) => input,
+			None => return Ok(()),
+		};
+		let shape = text_embeddings.shape();
+		let mismatched = shape.len() != input.dimensions.len()
+			|| shape.iter().zip(input.dimensions.iter()).skip(1).any(|(&actual, expected)| expected.map_or(false, |expected| expected as usize != actual));
+		if mismatched {
+			let expected = input
+				.dimensions
+				.iter()
+				.enumerate()
+				.map(|(i, dim)| match dim {
+					_ if i == 0 => "batch_size".to_string(),
+					Some(dim) => dim.to_string(),
+					None => "?".to_string(),
+				})
+				.collect::<Vec<_>>()
+				.join(", ");
+			anyhow::bail!(
+				"text embeddings have shape {shape:?}, but the UNet's `{}` input expects ({expected}); embeddings must be laid out as (batch_size, tokens, hidden_size), so the text encoder may not match the UNet",
+				input.name,
+			);
+		}
+		Ok(())
+	}
+
+	/// Runs the UNet on the given (already scaled) latent model input, returning the noise prediction.
+	pub(crate) fn predict_noise(
+		&self,
+		latent_model_input: ArrayView4<'_, f32>,
+		timestep: f32,
+		encoder_hidden_states: ArrayViewD<'_, f32>,
+	) -> anyhow::Result<Array4<f32>> {
+		self.predict_noise_with_unet(&self.unet, latent_model_input, timestep, encoder_hidden_states)
+	}
+
+	/// Runs `unet` instead of the pipeline's own UNet like [`StableDiffusionPipeline::predict_noise`], for UNets loaded
+	/// on demand such as the inpainting UNet of [`StableDiffusionPipeline::inpaint_with_mask_channel`].
+	pub(crate) fn predict_noise_with_unet(
+		&self,
+		unet: &TrackedSession,
+		latent_model_input: ArrayView4<'_, f32>,
+		timestep: f32,
+		encoder_hidden_states: ArrayViewD<'_, f32>,
+	) -> anyhow::Result<Array4<f32>> {
+		let timestep_rank = self.config.unet.timestep_rank.or_else(|| unet.inputs.get(1).map(|input| input.dimensions.len())).unwrap_or(1);
+		let latent_model_input: CowArray<f32, IxDyn> = latent_model_input.as_standard_layout().into_dyn();
+		let timestep = timestep_input(timestep, timestep_rank)?;
+		let encoder_hidden_states: CowArray<f32, IxDyn> = encoder_hidden_states.as_standard_layout();
+
+		let noise_pred = unet.run(ort::inputs![&latent_model_input, &timestep, &encoder_hidden_states]?)?;
+		let noise_pred_output = resolve_noise_pred_output(unet.outputs.iter().map(|output| (output.name.as_str(), output.dimensions.as_slice())))?;
+		let noise_pred: OrtOwnedTensor<f32> = noise_pred[noise_pred_output].extract_tensor()?;
+		squeeze_noise_pred(noise_pred.view().to_owned())
+	}
+
+	/// Runs the UNet like [`StableDiffusionPipeline::predict_noise`], additionally feeding the guidance embedding of
+	/// shape `(batch_size, embedding_dim)` as the 4th input of guidance-distilled UNets if `guidance_embedding` is given.
+	pub(crate) fn predict_noise_with_guidance_embedding(
+		&self,
+		latent_model_input: ArrayView4<'_, f32>,
+		timestep: f32,
+		encoder_hidden_states: ArrayViewD<'_, f32>,
+		guidance_embedding: Option<ArrayView2<'_, f32>>,
+	) -> anyhow::Result<Array4<f32>> {
+		let guidance_embedding = match guidance_embedding {
+			Some(guidance_embedding) => guidance_embedding,
+			None => return self.predict_noise(latent_model_input, timestep, encoder_hidden_states),
+		};
+
+		let latent_model_input: CowArray<f32, IxDyn> = latent_model_input.as_standard_layout().into_dyn();
+		let timestep = timestep_input(timestep, self.unet_timestep_rank())?;
+		let encoder_hidden_states: CowArray<f32, IxDyn> = encoder_hidden_states.as_standard_layout();
+		let guidance_embedding: CowArray<f32, IxDyn> = guidance_embedding.as_standard_layout().into_dyn();
+
+		let noise_pred = self.unet.run(ort::inputs![&latent_model_input, &timestep, &encoder_hidden_states, &guidance_embedding]?)?;
+		let noise_pred: OrtOwnedTensor<f32> = noise_pred[self.noise_pred_output()?].extract_tensor()?;
+		squeeze_noise_pred(noise_pred.view().to_owned())
+	}
+
+	/// Runs a UNet exported for ControlNet like [`StableDiffusionPipeline::predict_noise`], feeding the ControlNet
+	/// residuals as its inputs after `encoder_hidden_states`.
+	pub(crate) fn predict_noise_with_controlnet(
+		&self,
+		latent_model_input: ArrayView4<'_, f32>,
+		timestep: f32,
+		encoder_hidden_states: ArrayViewD<'_, f32>,
+		residuals: Vec<ArrayD<f32>>,
+	) -> anyhow::Result<Array4<f32>> {
+		if self.unet_residual_inputs() != residuals.len() {
+			anyhow::bail!(
+				"the ControlNet produced {} residuals, but the UNet takes {} inputs; ControlNet requires a UNet exported to take each residual after `encoder_hidden_states`",
+				residuals.len(),
+				self.unet.inputs.len(),
+			);
+		}
+
+		let latent_model_input: CowArray<f32, IxDyn> = latent_model_input.as_standard_layout().into_dyn();
+		let timestep = timestep_input(timestep, self.unet_timestep_rank())?;
+		let encoder_hidden_states: CowArray<f32, IxDyn> = encoder_hidden_states.as_standard_layout();
+
+		let mut inputs = ort::inputs![&latent_model_input, &timestep, &encoder_hidden_states]?;
+		for residual in residuals {
+			inputs.push(Value::from_array(residual)?);
+		}
+		let noise_pred = self.unet.run(inputs)?;
+		let noise_pred: OrtOwnedTensor<f32> = noise_pred[self.noise_pred_output()?].extract_tensor()?;
+		squeeze_noise_pred(noise_pred.view().to_owned())
+	}
+
+	/// Runs the UNet like [`StableDiffusionPipeline::predict_noise`], additionally returning the cross-attention maps
+	/// from the UNet's `attention_maps` output (see [`AttendAndExciteOptions`](crate::AttendAndExciteOptions)).
+	pub(crate) fn predict_noise_with_attention(
+		&self,
+		latent_model_input: ArrayView4<'_, f32>,
+		timestep: f32,
+		encoder_hidden_states: ArrayViewD<'_, f32>,
+	) -> anyhow::Result<(Array4<f32>, Array4<f32>)> {
+		let attention_output = self.attention_maps_output()?;
+
+		let latent_model_input: CowArray<f32, IxDyn> = latent_model_input.as_standard_layout().into_dyn();
+		let timestep = timestep_input(timestep, self.unet_timestep_rank())?;
+		let encoder_hidden_states: CowArray<f32, IxDyn> = encoder_hidden_states.as_standard_layout();
+
+		let outputs = self.unet.run(ort::inputs![&latent_model_input, &timestep, &encoder_hidden_states]?)?;
+		let noise_pred: OrtOwnedTensor<f32> = outputs[self.noise_pred_output()?].extract_tensor()?;
+		let attention_maps: OrtOwnedTensor<f32> = outputs[attention_output].extract_tensor()?;
+		Ok((squeeze_noise_pred(noise_pred.view().to_owned())?, attention_maps.view().to_owned().into_dimensionality()?))
+	}
+
+	/// Runs a UNet exported for reference attention, feeding `reference_hidden_states` & `reference_weight` as its 4th
+	/// & 5th inputs. Returns the noise prediction & the UNet's own `out_reference_hidden_states` output.
+	pub(crate) fn predict_noise_with_reference(
+		&self,
+		latent_model_input: ArrayView4<'_, f32>,
+		timestep: f32,
+		encoder_hidden_states: ArrayViewD<'_, f32>,
+		reference_hidden_states: ArrayViewD<'_, f32>,
+		reference_weight: f32,
+	) -> anyhow::Result<(Array4<f32>, ArrayD<f32>)> {
+		let reference_output = self
+			.unet
+			.outputs
+			.iter()
+			.position(|output| output.name == REFERENCE_HIDDEN_STATES_OUTPUT)
+			.ok_or_else(|| anyhow::anyhow!("the UNet has no `{REFERENCE_HIDDEN_STATES_OUTPUT}` output"))?;
+
+		let latent_model_input: CowArray<f32, IxDyn> = latent_model_input.as_standard_layout().into_dyn();
+		let timestep = timestep_input(timestep, self.unet_timestep_rank())?;
+		let encoder_hidden_states: CowArray<f32, IxDyn> = encoder_hidden_states.as_standard_layout();
+		let reference_hidden_states: CowArray<f32, IxDyn> = reference_hidden_states.as_standard_layout();
+		let reference_weight: CowArray<f32, IxDyn> = CowArray::from(Array1::from_iter([reference_weight]).into_dyn());
+
+		let outputs = self.unet.run(ort::inputs![&latent_model_input, &timestep, &encoder_hidden_states, &reference_hidden_states, &reference_weight]?)?;
+		let noise_pred: OrtOwnedTensor<f32> = outputs[self.noise_pred_output()?].extract_tensor()?;
+		let reference_hidden_states: OrtOwnedTensor<f32> = outputs[reference_output].extract_tensor()?;
+		Ok((squeeze_noise_pred(noise_pred.view().to_owned())?, reference_hidden_states.view().to_owned()))
+	}
+
+	/// Converts an `NHWC` array into an image, also returning how many of its pixels were clamped.
+	fn to_image(&self, width: u32, height: u32, arr: &Array4<f32>) -> anyhow::Result<(DynamicImage, ClampReport)> {
+		let clamp_report = ClampReport::from_pixels(arr.lanes(Axis(3)));
+		let image = DynamicImage::ImageRgb32F(
+			Rgb32FImage::from_raw(width, height, arr.map(|f| self.options.color_transfer.apply(f.clamp(0.0, 1.0))).into_iter().collect::<Vec<_>>())
 				.ok_or_else(|| anyhow::anyhow!("failed to construct image"))?,
-		))
+		);
+		Ok((image, clamp_report))
+	}
+
+	/// Returns the rank of the UNet's timestep input, read from the model config's `unet.timestep-rank`, falling back
+	/// to the rank of the UNet's 2nd input.
+	pub(crate) fn unet_timestep_rank(&self) -> usize {
+		self.config.unet.timestep_rank.or_else(|| self.unet.inputs.get(1).map(|input| input.dimensions.len())).unwrap_or(1)
+	}
+
+	/// Returns the index of the UNet output holding the noise prediction; see [`resolve_noise_pred_output`].
+	pub(crate) fn noise_pred_output(&self) -> anyhow::Result<usize> {
+		resolve_noise_pred_output(self.unet.outputs.iter().map(|output| (output.name.as_str(), output.dimensions.as_slice())))
+	}
+
+	/// Returns the number of inputs the UNet takes after `encoder_hidden_states`, which hold the ControlNet residuals of
+	/// UNets exported for ControlNet.
+	pub(crate) fn unet_residual_inputs(&self) -> usize {
+		self.unet.inputs.len().saturating_sub(3)
+	}
+
+	/// Returns the number of channels of the UNet's latent input, or `None` if the dimension is dynamic.
+	pub(crate) fn unet_in_channels(&self) -> Option<u32> {
+		latent_in_channels(&self.unet)
+	}
+
+	/// Returns the index of the UNet's `attention_maps` output, which Attend-and-Excite requires.
+	pub(crate) fn attention_maps_output(&self) -> anyhow::Result<usize> {
+		self.unet.outputs.iter().position(|output| output.name == ATTENTION_MAPS_OUTPUT).ok_or_else(|| {
+			anyhow::anyhow!("the UNet has no `{ATTENTION_MAPS_OUTPUT}` output; Attend-and-Excite requires a UNet exported with cross-attention maps")
+		})
+	}
+
+	/// Returns the `(width, height)` in pixels the UNet was exported for, if its latent input has static spatial
+	/// dimensions.
+	pub(crate) fn unet_static_size(&self) -> Option<(u32, u32)> {
+		self.unet.inputs.first().and_then(|input| match input.dimensions.as_slice() {
+			[_, _, Some(height), Some(width)] => Some((width * 8, height * 8)),
+			_ => None
+		})
+	}
+
+	/// Fails if the UNet was exported for a static size other than `width`x`height`, the size in pixels of the latents
+	/// it would be run on.
+	pub(crate) fn check_unet_size(&self, width: u32, height: u32) -> anyhow::Result<()> {
+		match self.unet_static_size() {
+			Some((static_width, static_height)) if (static_width, static_height) != (width, height) => {
+				anyhow::bail!("the UNet was exported for a static size of {static_width}x{static_height}, but would be run on {width}x{height} latents")
+			}
+			_ => Ok(())
+		}
+	}
+
+	/// Encodes images of shape `(batch_size, 3, height, width)` with values in `[0, 1]` into (scaled) UNet latents via
+	/// the variational autoencoder. Fails if the pipeline has no VAE encoder.
+	pub fn encode_images(&self, images: ArrayView4<'_, f32>) -> anyhow::Result<Array4<f32>> {
+		let vae_encoder = self.vae_encoder.as_ref().ok_or_else(|| anyhow::anyhow!("this pipeline has no VAE encoder"))?;
+		let images = images.mapv(|f| f * 2.0 - 1.0);
+		let mut latents = Vec::with_capacity(images.shape()[0]);
+		for image in images.axis_iter(Axis(0)) {
+			let latent = vae_encoder.run(ort::inputs![image.insert_axis(Axis(0))]?)?;
+			let latent: OrtOwnedTensor<f32> = latent[0].extract_tensor()?;
+			let latent: Array4<f32> = latent.view().to_owned().into_dimensionality()?;
+			latents.push(latent * 0.18215);
+		}
+		let latents = latents.iter().map(|latent| latent.view()).collect::<Vec<_>>();
+		Ok(concatenate(Axis(0), &latents)?)
 	}
 
-	/// Decodes UNet latents via a cheap approximation into an array of [`image::DynamicImage`]s.
+	/// Decodes UNet latents via a cheap approximation into an array of [`image::DynamicImage`]s; this is used for
+	/// previews.
+	///
+	/// If the model config's `[vae]` section has a `preview-decoder`, like [TAESD](https://github.com/madebyollin/taesd),
+	/// latents are decoded by it, giving previews close to the VAE's output at a fraction of its cost. Otherwise, or if
+	/// the preview decoder's file does not exist, each latent pixel is linearly projected onto RGB, which gives blurry
+	/// previews at 1/8th of the image's resolution. The preview decoder is only a few MB, so it is not counted towards
+	/// [`StableDiffusionOptions::max_resident_bytes`].
 	pub fn approximate_decode_latents(&self, latents: ArrayView4<'_, f32>) -> anyhow::Result<Vec<DynamicImage>> {
-		let coefs = Array2::from_shape_vec((4, 3), vec![0.298, 0.207, 0.208, 0.187, 0.286, 0.173, -0.158, 0.189, 0.264, -0.184, -0.271, -0.473])?;
-		let approx = einsum("blxy,lr->bxyr", &[&latents, &coefs]).expect("einsum error");
+		if let Some(preview_decoder) = self.preview_decoder.as_ref() {
+			return latents
+				.axis_iter(Axis(0))
+				.map(|latent_chunk| {
+					let image = preview_decoder.run(ort::inputs![latent_chunk.insert_axis(Axis(0))]?)?;
+					let image: OrtOwnedTensor<f32> = image[0].extract_tensor()?;
+					let image: Array4<f32> = image.view().to_owned().into_dimensionality()?;
+					if image.shape()[1] != 3 {
+						anyhow::bail!("preview decoder produced an image with {} channels; expected 3", image.shape()[1]);
+					}
+					let image = image.permuted_axes([0, 2, 3, 1]);
+					Ok(self.to_image(image.shape()[2] as _, image.shape()[1] as _, &image)?.0)
+				})
+				.collect();
+		}
+
+		let approx = approximate_latents_to_rgb(latents)?;
 		let mut images = Vec::new();
 		for approx_chunk in approx.axis_iter(Axis(0)) {
 			let approx_chunk = approx_chunk.insert_axis(Axis(0)).into_dimensionality()?.to_owned();
-			let image = self.to_image(approx_chunk.shape()[2] as _, approx_chunk.shape()[1] as _, &approx_chunk)?;
+			let (image, _) = self.to_image(approx_chunk.shape()[2] as _, approx_chunk.shape()[1] as _, &approx_chunk)?;
 			images.push(image);
 		}
 		Ok(images)
 	}
 
 	/// Decodes UNet latents via the variational autoencoder into an array of [`image::DynamicImage`]s.
+	///
+	/// If [`StableDiffusionOptions::dedupe_decode`] is enabled, latents which are exactly identical to a previous
+	/// latent in the batch are only decoded once. Otherwise, with the `parallel-decode` feature, latents can be decoded
+	/// in parallel; see [`StableDiffusionOptions::with_parallel_decode`].
 	pub fn decode_latents(&self, latents: ArrayView4<'_, f32>) -> anyhow::Result<Vec<DynamicImage>> {
-		let latents = 1.0 / 0.18215 * &latents;
+		Ok(self.decode_latents_with_clamp_reports(latents)?.0)
+	}
 
-		let mut images = Vec::new();
-		for latent_chunk in latents.axis_iter(Axis(0)) {
-			let image = self.vae_decoder.run(ort::inputs![latent_chunk.insert_axis(Axis(0))]?)?;
-			let image: OrtOwnedTensor<f32> = image[0].extract_tensor()?;
-			let f_image: Array4<f32> = image.view().to_owned().into_dimensionality()?;
-			let f_image = f_image.permuted_axes([0, 2, 3, 1]) / 2.0 + 0.5;
+	/// Decodes UNet latents like [`StableDiffusionPipeline::decode_latents`], also returning a [`ClampReport`] for
+	/// each image.
+	pub(crate) fn decode_latents_with_clamp_reports(&self, latents: ArrayView4<'_, f32>) -> anyhow::Result<(Vec<DynamicImage>, Vec<ClampReport>)> {
+		let latents = 1.0 / 0.18215 * &self.upcast_latents_for_decode(latents);
 
-			let image = self.to_image(f_image.shape()[2] as _, f_image.shape()[1] as _, &f_image)?;
-			images.push(image);
+		let decoded: Vec<(DynamicImage, ClampReport)> = if self.options.dedupe_decode {
+			decode_deduplicated(latents.view(), |latent_chunk| self.decode_latent_chunk(latent_chunk))?
+		} else {
+			self.decode_latent_chunks(latents.view())?
+		};
+		Ok(decoded.into_iter().unzip())
+	}
+
+	/// Decodes each (already scaled) latent of a batch, in parallel if enabled with
+	/// [`StableDiffusionOptions::with_parallel_decode`].
+	fn decode_latent_chunks(&self, latents: ArrayView4<'_, f32>) -> anyhow::Result<Vec<(DynamicImage, ClampReport)>> {
+		#[cfg(feature = "parallel-decode")]
+		if let Some(threads) = self.options.parallel_decode_threads {
+			use ndarray::parallel::prelude::*;
+
+			let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build()?;
+			// rayon's `collect` preserves the order of the batch
+			return pool.install(|| latents.axis_iter(Axis(0)).into_par_iter().map(|latent_chunk| self.decode_latent_chunk(latent_chunk)).collect());
 		}
+		latents.axis_iter(Axis(0)).map(|latent_chunk| self.decode_latent_chunk(latent_chunk)).collect()
+	}
 
-		Ok(images)
+	/// Decodes UNet latents via the variational autoencoder like [`StableDiffusionPipeline::decode_latents`], but
+	/// writes each image to `dir` as an 8-bit PNG as soon as it is decoded, so that at most one decoded image is held in
+	/// memory at once. Images are named `{prefix}-{index}.png`; existing files are overwritten. Returns the paths of the
+	/// written images.
+	///
+	/// Latents are always decoded one by one, ignoring [`StableDiffusionOptions::dedupe_decode`].
+	pub fn decode_latents_to_disk(&self, latents: ArrayView4<'_, f32>, dir: impl AsRef<Path>, prefix: &str) -> anyhow::Result<Vec<PathBuf>> {
+		Ok(self.decode_latents_to_disk_with_clamp_reports(latents, dir.as_ref(), prefix)?.0)
+	}
+
+	/// Decodes UNet latents to disk like [`StableDiffusionPipeline::decode_latents_to_disk`], also returning a
+	/// [`ClampReport`] for each image.
+	pub(crate) fn decode_latents_to_disk_with_clamp_reports(
+		&self,
+		latents: ArrayView4<'_, f32>,
+		dir: &Path,
+		prefix: &str,
+	) -> anyhow::Result<(Vec<PathBuf>, Vec<ClampReport>)> {
+		fs::create_dir_all(dir)?;
+		let latents = 1.0 / 0.18215 * &self.upcast_latents_for_decode(latents);
+		let mut paths = Vec::with_capacity(latents.shape()[0]);
+		let mut clamp_reports = Vec::with_capacity(latents.shape()[0]);
+		for (index, latent_chunk) in latents.axis_iter(Axis(0)).enumerate() {
+			let path = dir.join(format!("{prefix}-{index}.png"));
+			let (image, clamp_report) = self.decode_latent_chunk(latent_chunk)?;
+			write_image(&image, &path, ImageFileFormat::Png)?;
+			paths.push(path);
+			clamp_reports.push(clamp_report);
+		}
+		Ok((paths, clamp_reports))
+	}
+
+	/// Decodes only the given region of UNet latents via the variational autoencoder into an array of
+	/// [`image::DynamicImage`]s, each the size of the region. The region's position & size must be divisible by 8, and
+	/// the region must lie within the image.
+	pub fn decode_latents_region(&self, latents: ArrayView4<'_, f32>, region: &ImageRegion) -> anyhow::Result<Vec<DynamicImage>> {
+		self.decode_latents(crop_latents(latents, region)?)
+	}
+
+	/// Applies [`StableDiffusionOptions::latent_upcast_before_decode`], if enabled, to (unscaled) latents about to be
+	/// decoded.
+	fn upcast_latents_for_decode<'l>(&self, latents: ArrayView4<'l, f32>) -> CowArray<'l, f32, Ix4> {
+		if !self.options.latent_upcast_before_decode {
+			return latents.into();
+		}
+		let (latents, report) = upcast_latents(latents);
+		if report.non_finite > 0 {
+			tracing::warn!("{} latent values are NaN or infinite; they were replaced with 0 before decoding", report.non_finite);
+		}
+		if report.max_magnitude > EXPECTED_LATENT_MAGNITUDE {
+			tracing::warn!(
+				"latents reach a magnitude of {}, above the expected {EXPECTED_LATENT_MAGNITUDE}; values were clamped to ±{MAX_DECODE_LATENT_MAGNITUDE} before decoding, but the image may be distorted",
+				report.max_magnitude
+			);
+		}
+		latents.into()
+	}
+
+	/// Decodes a single (already scaled) latent of shape `(4, height, width)`.
+	fn decode_latent_chunk(&self, latent_chunk: ArrayView3<'_, f32>) -> anyhow::Result<(DynamicImage, ClampReport)> {
+		let (expected_height, expected_width) = (latent_chunk.shape()[1] * 8, latent_chunk.shape()[2] * 8);
+		let image = self.vae_decoder.run(ort::inputs![latent_chunk.insert_axis(Axis(0))]?)?;
+		let image: OrtOwnedTensor<f32> = image[0].extract_tensor()?;
+		let f_image: Array4<f32> = image.view().to_owned().into_dimensionality()?;
+		let f_image = f_image.permuted_axes([0, 2, 3, 1]) / 2.0 + 0.5;
+		if f_image.shape()[3] != 3 {
+			anyhow::bail!("VAE decoder produced an image with {} channels; expected 3", f_image.shape()[3]);
+		}
+
+		let f_image = fit_decoded_image(f_image, expected_height, expected_width, self.options.vae_output_mismatch)?;
+
+		self.to_image(f_image.shape()[2] as _, f_image.shape()[1] as _, &f_image)
+	}
+}
+
+impl LatentsDecoder for StableDiffusionPipeline {
+	fn decode_latents(&self, latents: ArrayView4<'_, f32>) -> anyhow::Result<Vec<DynamicImage>> {
+		StableDiffusionPipeline::decode_latents(self, latents)
+	}
+
+	fn approximate_decode_latents(&self, latents: ArrayView4<'_, f32>) -> anyhow::Result<Vec<DynamicImage>> {
+		StableDiffusionPipeline::approximate_decode_latents(self, latents)
+	}
+
+	fn decode_latents_region(&self, latents: ArrayView4<'_, f32>, region: &ImageRegion) -> anyhow::Result<Vec<DynamicImage>> {
+		StableDiffusionPipeline::decode_latents_region(self, latents, region)
+	}
+}
+
+/// Loads the preview decoder from the model config's `vae.preview-decoder`, if set and present. It is placed on the VAE
+/// decoder's device, and is exempt from `max_resident_bytes`.
+fn load_preview_decoder(
+	context: &ExecutionContext,
+	options: &StableDiffusionOptions,
+	root: &Path,
+	config: &StableDiffusionConfig,
+) -> anyhow::Result<Option<TrackedSession>> {
+	let path = match config.vae.preview_decoder.as_ref() {
+		Some(path) => root.join(path),
+		None => return Ok(None),
+	};
+	if !path.exists() {
+		tracing::warn!("preview decoder `{}` does not exist; previews will be approximated linearly", path.display());
+		return Ok(None);
+	}
+	Ok(Some(load_session(context, &options.devices.vae_decoder, "preview decoder", ModelSource::File(&path), None, None)?))
+}
+
+/// Reads the `pyke-diffusers.toml` config of the Stable Diffusion model at `root`.
+fn read_config(root: &Path) -> anyhow::Result<StableDiffusionConfig> {
+	let config: DiffusionPipeline = toml::from_str(&fs::read_to_string(root.join("pyke-diffusers.toml"))?)?;
+	match config {
+		DiffusionPipeline::StableDiffusion { framework, inner } => {
+			match framework {
+				DiffusionFramework::Orte { .. } => (),
+				_ => panic!("bad framework"),
+			}
+			Ok(inner)
+		}
+		#[allow(unreachable_patterns)]
+		_ => anyhow::bail!("not a stable diffusion pipeline"),
+	}
+}
+
+/// Crops latents to the given image-space region.
+pub(crate) fn crop_latents<'a>(latents: ArrayView4<'a, f32>, region: &ImageRegion) -> anyhow::Result<ArrayView4<'a, f32>> {
+	let (y, x, height, width) = region.to_latent(latents.shape()[2], latents.shape()[3])?;
+	Ok(latents.slice_move(s![.., .., y..y + height, x..x + width]))
+}
+
+/// Checks that there is enough free memory to load the model at `path`, if enabled with
+/// [`StableDiffusionOptions::with_free_memory_check`].
+pub(crate) fn check_free_memory(options: &StableDiffusionOptions, component: &'static str, path: &Path) -> anyhow::Result<()> {
+	check_free_memory_for(options, component, &[path])
+}
+
+/// Checks that there is enough free memory to load the UNet at `path`, counting the other UNet of a
+/// [merge](StableDiffusionOptions::with_merged_unet), since both are read into memory to be merged.
+fn check_unet_free_memory(options: &StableDiffusionOptions, path: &Path) -> anyhow::Result<()> {
+	match options.unet_merge.as_ref() {
+		Some(merge) => check_free_memory_for(options, "UNet", &[path, merge.other.as_path()]),
+		None => check_free_memory(options, "UNet", path),
+	}
+}
+
+fn check_free_memory_for(options: &StableDiffusionOptions, component: &'static str, paths: &[&Path]) -> anyhow::Result<()> {
+	#[cfg(feature = "sysinfo")]
+	if let Some(headroom_bytes) = options.free_memory_headroom {
+		crate::session_tracker::check_free_memory(component, paths, headroom_bytes)?;
+	}
+	#[cfg(not(feature = "sysinfo"))]
+	let _ = (options, component, paths);
+	Ok(())
+}
+
+/// Returns the number of channels of the latent (1st) input of a UNet, or `None` if the dimension is dynamic.
+pub(crate) fn latent_in_channels(unet: &TrackedSession) -> Option<u32> {
+	unet.inputs.first().and_then(|input| input.dimensions.get(1).copied().flatten())
+}
+
+/// Loads the UNet at `path`, merging it with another UNet first if configured in `options`.
+fn load_unet(
+	context: &ExecutionContext,
+	options: &StableDiffusionOptions,
+	path: PathBuf,
+	replacing: Option<&TrackedSession>,
+) -> anyhow::Result<TrackedSession> {
+	match options.unet_merge.as_ref() {
+		Some(merge) => {
+			let merged = merge_unets(path, &merge.other, merge.alpha)?;
+			load_session(context, &options.devices.unet, "UNet", ModelSource::Memory(&merged), options.max_resident_bytes, replacing)
+		}
+		None => load_session(context, &options.devices.unet, "UNet", ModelSource::File(&path), options.max_resident_bytes, replacing),
+	}
+}
+
+/// Switches the config to the int8-quantized models listed in its `[quantized]` section, warning about quantized models
+/// placed on devices other than the CPU.
+fn use_quantized_models(config: &mut StableDiffusionConfig, options: &StableDiffusionOptions) -> anyhow::Result<()> {
+	if options.unet_merge.is_some() {
+		anyhow::bail!("quantized models cannot be combined with a merged UNet");
+	}
+	for component in config.use_quantized_models()? {
+		let device = match component {
+			"text encoder" => &options.devices.text_encoder,
+			"UNet" => &options.devices.unet,
+			"VAE encoder" => &options.devices.vae_encoder,
+			"VAE decoder" => &options.devices.vae_decoder,
+			_ => &options.devices.safety_checker,
+		};
+		if !matches!(device, DiffusionDevice::CPU) {
+			tracing::warn!("the int8-quantized {component} is placed on {device:?}; quantized models are optimized for the CPU and may run slowly elsewhere");
+		}
+	}
+	Ok(())
+}
+
+/// Decodes each latent in a batch with `decode`, reusing the decoded image for latents that are exactly identical to
+/// an earlier latent in the batch. Returns one image per latent.
+fn decode_deduplicated<T, F>(latents: ArrayView4<'_, f32>, mut decode: F) -> anyhow::Result<Vec<T>>
+where
+	T: Clone,
+	F: FnMut(ArrayView3<'_, f32>) -> anyhow::Result<T>,
+{
+	let mut decoded: HashMap<u64, Vec<usize>> = HashMap::new();
+	let mut images: Vec<T> = Vec::with_capacity(latents.shape()[0]);
+	for (i, latent_chunk) in latents.axis_iter(Axis(0)).enumerate() {
+		let hash = fnv1a(latent_chunk.iter().flat_map(|x| x.to_bits().to_le_bytes()));
+		let candidates = decoded.entry(hash).or_default();
+		// compare the latents themselves in case of a hash collision
+		let duplicate_of = candidates
+			.iter()
+			.copied()
+			.find(|&j| latents.index_axis(Axis(0), j).iter().zip(latent_chunk.iter()).all(|(a, b)| a.to_bits() == b.to_bits()));
+		let image = match duplicate_of {
+			Some(j) => images[j].clone(),
+			None => {
+				candidates.push(i);
+				decode(latent_chunk)?
+			}
+		};
+		images.push(image);
+	}
+	Ok(images)
+}
+
+/// 64-bit FNV-1a hash.
+pub(crate) fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+	bytes.into_iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Builds the timestep input of a UNet (or ControlNet) whose timestep input has the given rank: a scalar for rank 0,
+/// or a 1-element array for rank 1.
+pub(crate) fn timestep_input(timestep: f32, rank: usize) -> anyhow::Result<CowArray<'static, f32, IxDyn>> {
+	match rank {
+		0 => Ok(CowArray::from(arr0(timestep).into_dyn())),
+		1 => Ok(CowArray::from(Array1::from_iter([timestep]).into_dyn())),
+		rank => anyhow::bail!("the timestep input has rank {rank}; expected a scalar (rank 0) or a 1-element array (rank 1)"),
+	}
+}
+
+/// Names under which UNet exports emit the noise prediction, in order of preference.
+const NOISE_PRED_OUTPUTS: [&str; 2] = ["out_sample", "sample"];
+
+/// Finds the UNet output holding the noise prediction among `(name, dimensions)` pairs: the first output named in
+/// [`NOISE_PRED_OUTPUTS`], falling back to the first output of rank 4 once leading dimensions of size 1 are removed.
+/// Community exports don't always emit it as the first output, e.g. when attention maps are exported alongside it.
+pub(crate) fn resolve_noise_pred_output<'a>(outputs: impl Iterator<Item = (&'a str, &'a [Option<u32>])> + Clone) -> anyhow::Result<usize> {
+	if let Some(index) = NOISE_PRED_OUTPUTS.iter().find_map(|name| outputs.clone().position(|(output, _)| output == *name)) {
+		return Ok(index);
+	}
+	if let Some(index) = outputs.clone().position(|(_, dimensions)| squeezed_rank(dimensions) == 4) {
+		return Ok(index);
+	}
+
+	let available = outputs
+		.map(|(name, dimensions)| {
+			let dimensions = dimensions.iter().map(|d| d.map_or_else(|| "?".to_string(), |d| d.to_string())).collect::<Vec<_>>();
+			format!("`{name}` ({})", dimensions.join(", "))
+		})
+		.collect::<Vec<_>>();
+	anyhow::bail!(
+		"couldn't find the UNet's noise prediction output; expected an output named {} or of rank 4, but the UNet has outputs {}",
+		NOISE_PRED_OUTPUTS.map(|name| format!("`{name}`")).join(" or "),
+		available.join(", ")
+	)
+}
+
+/// The rank of an output of the given dimensions after dropping leading dimensions statically known to be 1, keeping at
+/// least 4 dimensions.
+fn squeezed_rank(dimensions: &[Option<u32>]) -> usize {
+	let leading_ones = dimensions.iter().take_while(|d| **d == Some(1)).count();
+	dimensions.len() - leading_ones.min(dimensions.len().saturating_sub(4))
+}
+
+/// Converts a noise prediction to shape `(batch_size, 4, height, width)`, removing extra leading dimensions of size 1
+/// that some exports wrap it in.
+pub(crate) fn squeeze_noise_pred(mut noise_pred: ArrayD<f32>) -> anyhow::Result<Array4<f32>> {
+	while noise_pred.ndim() > 4 && noise_pred.shape()[0] == 1 {
+		noise_pred = noise_pred.index_axis_move(Axis(0), 0);
+	}
+	if noise_pred.ndim() != 4 {
+		anyhow::bail!("the UNet's noise prediction has shape {:?}; expected (batch_size, channels, height, width)", noise_pred.shape());
+	}
+	Ok(noise_pred.into_dimensionality()?)
+}
+
+/// Coefficients projecting the 4 latent channels onto RGB, for [`StableDiffusionPipeline::approximate_decode_latents`].
+const LATENT_RGB_COEFFICIENTS: [[f32; 3]; 4] = [[0.298, 0.207, 0.208], [0.187, 0.286, 0.173], [-0.158, 0.189, 0.264], [-0.184, -0.271, -0.473]];
+
+/// Projects latents of shape `(batch_size, 4, height, width)` onto an `NHWC` RGB image of shape
+/// `(batch_size, height, width, 3)` by multiplying each latent pixel with [`LATENT_RGB_COEFFICIENTS`].
+fn approximate_latents_to_rgb(latents: ArrayView4<'_, f32>) -> anyhow::Result<Array4<f32>> {
+	let (batch_size, channels, height, width) = latents.dim();
+	if channels != LATENT_RGB_COEFFICIENTS.len() {
+		anyhow::bail!("latents have {channels} channels, expected {}", LATENT_RGB_COEFFICIENTS.len());
+	}
+	let coefs = Array2::from_shape_fn((4, 3), |(l, r)| LATENT_RGB_COEFFICIENTS[l][r]);
+	// (B, 4, H, W) -> (B * H * W, 4), so the projection is a single matmul
+	let pixels = latents.permuted_axes([0, 2, 3, 1]).as_standard_layout().into_shape((batch_size * height * width, channels))?.dot(&coefs);
+	Ok(pixels.into_shape((batch_size, height, width, 3))?)
+}
+
+/// The largest magnitude expected of (VAE-scaled) latents after denoising; see
+/// [`StableDiffusionOptions::latent_upcast_before_decode`].
+pub(crate) const EXPECTED_LATENT_MAGNITUDE: f32 = 5.0;
+/// The magnitude latents are clamped to before decoding with [`StableDiffusionOptions::latent_upcast_before_decode`].
+pub(crate) const MAX_DECODE_LATENT_MAGNITUDE: f32 = 10.0;
+
+/// What [`upcast_latents`] found in a batch of latents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct UpcastReport {
+	/// The number of NaN or infinite values, which were replaced with 0.
+	pub(crate) non_finite: usize,
+	/// The largest magnitude of the finite values, before clamping.
+	pub(crate) max_magnitude: f32
+}
+
+/// Copies latents into a contiguous float32 array for decoding, replacing non-finite values with 0 and clamping the
+/// rest to ±[`MAX_DECODE_LATENT_MAGNITUDE`].
+pub(crate) fn upcast_latents(latents: ArrayView4<'_, f32>) -> (Array4<f32>, UpcastReport) {
+	let mut report = UpcastReport { non_finite: 0, max_magnitude: 0.0 };
+	let latents = latents.as_standard_layout().mapv(|x| {
+		if !x.is_finite() {
+			report.non_finite += 1;
+			return 0.0;
+		}
+		report.max_magnitude = report.max_magnitude.max(x.abs());
+		x.clamp(-MAX_DECODE_LATENT_MAGNITUDE, MAX_DECODE_LATENT_MAGNITUDE)
+	});
+	(latents, report)
+}
+
+/// Checks that a decoded `NHWC` image is `expected_height`x`expected_width`, handling mismatches according to `mismatch`.
+fn fit_decoded_image(image: Array4<f32>, expected_height: usize, expected_width: usize, mismatch: VAEOutputMismatch) -> anyhow::Result<Array4<f32>> {
+	let (height, width) = (image.shape()[1], image.shape()[2]);
+	if height == expected_height && width == expected_width {
+		return Ok(image);
+	}
+	if height == 0 || width == 0 {
+		anyhow::bail!("VAE decoder produced an empty {width}x{height} image; expected {expected_width}x{expected_height}");
+	}
+	match mismatch {
+		VAEOutputMismatch::Error => anyhow::bail!(
+			"VAE decoder produced a {width}x{height} image, but a {expected_width}x{expected_height} image was expected; set `vae_output_mismatch` to `VAEOutputMismatch::CropOrPad` to crop/pad the output"
+		),
+		VAEOutputMismatch::CropOrPad => Ok(fit_image_to(&image, expected_height, expected_width)),
+	}
+}
+
+/// Crops or edge-pads an `NHWC` image to the given height & width, anchored at the top-left corner. The image must not
+/// be empty.
+fn fit_image_to(image: &Array4<f32>, height: usize, width: usize) -> Array4<f32> {
+	let (batch, src_height, src_width, channels) = image.dim();
+	Array4::from_shape_fn((batch, height, width, channels), |(n, y, x, c)| image[[n, y.min(src_height - 1), x.min(src_width - 1), c]])
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::Cell;
+
+	use image::{DynamicImage, RgbImage};
+	use ndarray::{s, stack, Array3, Array4, ArrayD, Axis};
+
+	use super::{
+		approximate_latents_to_rgb, crop_latents, decode_deduplicated, fit_decoded_image, resolve_noise_pred_output, squeeze_noise_pred, timestep_input,
+		upcast_latents, UpcastReport, LATENT_RGB_COEFFICIENTS, MAX_DECODE_LATENT_MAGNITUDE
+	};
+	use crate::{ImageRegion, VAEOutputMismatch};
+
+	#[test]
+	fn timestep_input_ranks() {
+		let scalar = timestep_input(999.0, 0).unwrap();
+		assert_eq!(scalar.shape(), &[] as &[usize]);
+		assert_eq!(scalar.iter().copied().collect::<Vec<_>>(), vec![999.0]);
+
+		let array = timestep_input(999.0, 1).unwrap();
+		assert_eq!(array.shape(), &[1]);
+		assert_eq!(array[[0]], 999.0);
+
+		assert!(timestep_input(999.0, 2).is_err());
+	}
+
+	#[test]
+	fn noise_pred_output_resolution() {
+		fn resolve(outputs: &[(&'static str, Vec<Option<u32>>)]) -> anyhow::Result<usize> {
+			resolve_noise_pred_output(outputs.iter().map(|(name, dimensions)| (*name, dimensions.as_slice())))
+		}
+
+		let sample = vec![None, Some(4), None, None];
+		// the usual export
+		assert_eq!(resolve(&[("out_sample", sample.clone())]).unwrap(), 0);
+		// attention maps exported before the noise prediction
+		assert_eq!(resolve(&[("attention_maps", vec![None, None, Some(77)]), ("out_sample", sample.clone())]).unwrap(), 1);
+		// names take priority over shapes
+		assert_eq!(resolve(&[("out_reference_hidden_states", sample.clone()), ("sample", sample.clone())]).unwrap(), 1);
+		// unnamed, wrapped in an extra leading dimension
+		assert_eq!(resolve(&[("attention_maps", vec![None, None, Some(77)]), ("output_1", vec![Some(1), None, Some(4), None, None])]).unwrap(), 1);
+		// a dynamic leading dimension can't be squeezed
+		let err = resolve(&[("attention_maps", vec![None, None, Some(77)]), ("output_1", vec![None, None, Some(4), None, None])]).unwrap_err();
+		let message = err.to_string();
+		assert!(message.contains("`attention_maps` (?, ?, 77)"), "{message}");
+		assert!(message.contains("`output_1` (?, ?, 4, ?, ?)"), "{message}");
+	}
+
+	#[test]
+	fn squeeze_wrapped_noise_pred() {
+		let noise_pred = Array4::<f32>::from_shape_fn((2, 4, 3, 3), |(b, c, y, x)| (b * 36 + c * 9 + y * 3 + x) as f32);
+		assert_eq!(squeeze_noise_pred(noise_pred.clone().into_dyn()).unwrap(), noise_pred);
+		assert_eq!(squeeze_noise_pred(noise_pred.clone().insert_axis(Axis(0)).insert_axis(Axis(0)).into_dyn()).unwrap(), noise_pred);
+		assert!(squeeze_noise_pred(ArrayD::zeros(vec![2, 1, 4, 3, 3])).is_err());
+		assert!(squeeze_noise_pred(ArrayD::zeros(vec![2, 4, 3])).is_err());
+	}
+
+	#[test]
+	fn upcast_sanitizes_latents() {
+		// a transposed, non-contiguous view
+		let latents = Array4::<f32>::from_shape_fn((1, 3, 3, 4), |(_, y, x, c)| (c * 9 + y * 3 + x) as f32 / 10.0);
+		let mut latents = latents.permuted_axes([0, 3, 1, 2]);
+		latents[[0, 0, 0, 0]] = f32::NAN;
+		latents[[0, 1, 0, 0]] = f32::NEG_INFINITY;
+		latents[[0, 2, 0, 0]] = 25.0;
+		latents[[0, 3, 0, 0]] = -12.0;
+
+		let (upcast, report) = upcast_latents(latents.view());
+		assert!(upcast.is_standard_layout());
+		assert_eq!(report, UpcastReport { non_finite: 2, max_magnitude: 25.0 });
+		assert_eq!(upcast.slice(s![0, .., 0, 0]).to_vec(), [0.0, 0.0, MAX_DECODE_LATENT_MAGNITUDE, -MAX_DECODE_LATENT_MAGNITUDE]);
+		assert_eq!(upcast.slice(s![.., .., 1.., ..]), latents.slice(s![.., .., 1.., ..]));
+	}
+
+	#[test]
+	fn approximate_latents_match_einsum() {
+		let latents = Array4::<f32>::from_shape_fn((2, 4, 3, 5), |(b, l, x, y)| (b as f32 - 0.5) * (l as f32 + 1.0) * (x as f32 - y as f32 * 0.3));
+		let approx = approximate_latents_to_rgb(latents.view()).unwrap();
+
+		// the previous `einsum("blxy,lr->bxyr", &[&latents, &coefs])`
+		let expected = Array4::from_shape_fn((2, 3, 5, 3), |(b, x, y, r)| (0..4).map(|l| latents[[b, l, x, y]] * LATENT_RGB_COEFFICIENTS[l][r]).sum::<f32>());
+		assert_eq!(approx.shape(), expected.shape());
+		for (a, e) in approx.iter().zip(expected.iter()) {
+			assert!((a - e).abs() < 1e-5, "{a} != {e}");
+		}
+
+		assert!(approximate_latents_to_rgb(Array4::zeros((1, 3, 2, 2)).view()).is_err());
+	}
+
+	#[test]
+	fn dedupe_identical_latents() {
+		let a = Array3::<f32>::from_elem((4, 8, 8), 0.5);
+		let b = Array3::<f32>::from_elem((4, 8, 8), -0.25);
+		let latents = stack![Axis(0), a, b, a];
+
+		let decodes = Cell::new(0);
+		let images = decode_deduplicated(latents.view(), |latent_chunk| {
+			decodes.set(decodes.get() + 1);
+			let value = (latent_chunk[[0, 0, 0]] * 100.0 + 100.0) as u8;
+			Ok(DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 64, [value; 3].into())))
+		})
+		.unwrap();
+
+		assert_eq!(decodes.get(), 2);
+		assert_eq!(images.len(), 3);
+		assert_eq!(images[0], images[2]);
+		assert_ne!(images[0], images[1]);
+	}
+
+	#[test]
+	fn crop_latents_regions() {
+		// 64x48 image
+		let latents = Array4::<f32>::from_shape_fn((2, 4, 6, 8), |(_, _, y, x)| (y * 8 + x) as f32);
+
+		let crop = crop_latents(latents.view(), &ImageRegion::new(16, 8, 24, 16)).unwrap();
+		assert_eq!(crop.shape(), &[2, 4, 2, 3]);
+		assert_eq!(crop, latents.slice(s![.., .., 1..3, 2..5]));
+
+		// adjacent to the bottom-right edge
+		let crop = crop_latents(latents.view(), &ImageRegion::new(40, 32, 24, 16)).unwrap();
+		assert_eq!(crop.shape(), &[2, 4, 2, 3]);
+		assert_eq!(crop[[0, 0, 1, 2]], latents[[0, 0, 5, 7]]);
+
+		// the whole image
+		assert_eq!(crop_latents(latents.view(), &ImageRegion::new(0, 0, 64, 48)).unwrap(), latents.view());
+
+		// centered
+		assert_eq!(ImageRegion::centered(32, 32, 64, 48), ImageRegion::new(16, 8, 32, 32));
+		assert_eq!(crop_latents(latents.view(), &ImageRegion::centered(32, 32, 64, 48)).unwrap().shape(), &[2, 4, 4, 4]);
+	}
+
+	#[test]
+	fn crop_latents_invalid_regions() {
+		let latents = Array4::<f32>::zeros((1, 4, 6, 8));
+		// past the right edge
+		assert!(crop_latents(latents.view(), &ImageRegion::new(48, 0, 24, 16)).is_err());
+		// past the bottom edge
+		assert!(crop_latents(latents.view(), &ImageRegion::new(0, 40, 8, 16)).is_err());
+		// not aligned to 8 pixels
+		assert!(crop_latents(latents.view(), &ImageRegion::new(4, 0, 16, 16)).is_err());
+		assert!(crop_latents(latents.view(), &ImageRegion::new(0, 0, 12, 16)).is_err());
+		// empty
+		assert!(crop_latents(latents.view(), &ImageRegion::new(0, 0, 0, 16)).is_err());
+	}
+
+	#[test]
+	fn fit_mismatched_decoder_output() {
+		// 1x3x4 image where each pixel's channels hold its row & column
+		let image = Array4::from_shape_fn((1, 3, 4, 3), |(_, y, x, c)| if c == 0 { y as f32 } else { x as f32 });
+		assert_eq!(fit_decoded_image(image.clone(), 3, 4, VAEOutputMismatch::Error).unwrap(), image);
+		assert!(fit_decoded_image(image.clone(), 2, 4, VAEOutputMismatch::Error).is_err());
+
+		// cropped from the bottom/right...
+		let cropped = fit_decoded_image(image.clone(), 2, 2, VAEOutputMismatch::CropOrPad).unwrap();
+		assert_eq!(cropped, image.slice(s![.., ..2, ..2, ..]));
+		// ...and padded by repeating the last row & column
+		let padded = fit_decoded_image(image.clone(), 4, 6, VAEOutputMismatch::CropOrPad).unwrap();
+		assert_eq!(padded.shape(), &[1, 4, 6, 3]);
+		assert_eq!(padded.slice(s![.., ..3, ..4, ..]), image);
+		assert_eq!((padded[[0, 3, 5, 0]], padded[[0, 3, 5, 1]]), (2.0, 3.0));
+		assert_eq!((padded[[0, 3, 1, 0]], padded[[0, 1, 5, 1]]), (2.0, 3.0));
+
+		// empty outputs can't be padded
+		let empty = Array4::<f32>::zeros((1, 0, 4, 3));
+		assert!(fit_decoded_image(empty, 3, 4, VAEOutputMismatch::CropOrPad).is_err());
 	}
 }
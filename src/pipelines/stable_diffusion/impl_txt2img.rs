@@ -1,14 +1,208 @@
+use std::{
+	fmt,
+	path::PathBuf,
+	time::{Duration, Instant},
+};
+
 use image::DynamicImage;
-use ndarray::{concatenate, s, Array1, Array4, Axis, CowArray, IxDyn};
+use ndarray::{concatenate, s, Array1, Array2, Array3, Array4, ArrayD, ArrayView3, ArrayView4, Axis, ScalarOperand, Slice};
 use ndarray_rand::{
 	rand::{self, rngs::StdRng, Rng, SeedableRng},
 	rand_distr::StandardNormal,
 	RandomExt,
 };
-use num_traits::ToPrimitive;
-use ort::OrtOwnedTensor;
+use num_traits::{Float, FromPrimitive, ToPrimitive};
+
+use super::{
+	attend_and_excite::attend_and_excite_step,
+	checkpoint::CountingRng,
+	controlnet::MultiControlNet,
+	deepcache::DeepCache,
+	early_exit::ConvergenceTracker,
+	guidance_embedding::{guidance_embedding_dim, guidance_embedding_input},
+	impl_main::{fnv1a, latent_in_channels},
+	inpaint::{check_inpaint_unet, inpaint_unet_input, reimpose_known_region},
+	reference_attention::ReferenceAttention,
+	restart::{renoise, restart_plan, PlannedStep},
+	step_stats::l2_distance,
+};
+use crate::{
+	average_latents, normalize_latents,
+	schedulers::validate_custom_sigmas,
+	AttendAndExciteOptions, BatchNoiseMode, CancellationToken, ControlNetConfig, DeepCacheConfig, DiffusionCheckpoint, DiffusionScheduler, DiversityConfig,
+	EarlyExit, GenerationStage, HalfLatents, ImageFileFormat, ImageRef, ImageRegion, InpaintOptions, LatentStats, MetadataMode, MultiDiffusionOptions, Prompt,
+	PromptInput, PromptWeighting, ResizeFilter, RestartInterval, SchedulerState, StableDiffusionCallback, StableDiffusionOutput, StableDiffusionPipeline,
+	StableDiffusionPreview, StepRecord, StepStats, StopReason, StylePrompt, TextToImagePipeline, DEFAULT_STD_JUMP_THRESHOLD,
+};
+
+/// Offset added to the seed for the RNG used by Attend-and-Excite.
+const ATTEND_AND_EXCITE_SEED_OFFSET: u64 = 0x0ae0;
+/// Offset added to the seed for the RNG used to re-noise latents for Restart sampling.
+const RESTART_SEED_OFFSET: u64 = 0x7e57;
+/// Offset added to the seed for the RNG used to draw the padding noise of [`DimensionPolicy::PadAndCrop`].
+const PADDING_SEED_OFFSET: u64 = 0x9add;
+
+/// Pins the order & seeding of random number generation to the behavior of a specific release of pyke Diffusers, so
+/// that a saved seed will continue to generate the same image after upgrading.
+///
+/// Supported versions:
+/// - [`CompatibilityVersion::V1_0`]: the behavior of pyke Diffusers v1.0. Initial latents are drawn from an RNG seeded
+///   with `seed`, and the scheduler is given a separate RNG seeded with `seed + 31337` (wrapping around at `u64::MAX`).
+///
+/// [`CompatibilityVersion::Latest`] always refers to the behavior of the current release; this is the default. New
+/// variants are only added when a release changes RNG usage. No release has changed it yet, so `V1_0` is currently the
+/// only behavior and `Latest` resolves to it.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CompatibilityVersion {
+	/// The behavior of pyke Diffusers v1.0.
+	V1_0,
+	/// The behavior of the current release. **This is the default.**
+	#[default]
+	Latest,
+}
+
+impl CompatibilityVersion {
+	/// Resolves [`CompatibilityVersion::Latest`] to the concrete version it currently refers to.
+	pub fn resolve(self) -> Self {
+		match self {
+			CompatibilityVersion::Latest => CompatibilityVersion::V1_0,
+			v => v,
+		}
+	}
+
+	/// Creates the RNG used to generate the initial latents.
+	pub(crate) fn latents_rng(self, seed: u64) -> StdRng {
+		match self.resolve() {
+			CompatibilityVersion::V1_0 | CompatibilityVersion::Latest => StdRng::seed_from_u64(seed),
+		}
+	}
+
+	/// Creates the RNG passed to the scheduler's `step` function.
+	pub(crate) fn scheduler_rng(self, seed: u64) -> StdRng {
+		match self.resolve() {
+			CompatibilityVersion::V1_0 | CompatibilityVersion::Latest => StdRng::seed_from_u64(seed.wrapping_add(31337)),
+		}
+	}
+}
+
+/// The order in which random numbers are drawn during text-to-image generation. Seeds are only portable between tools
+/// that draw random numbers in the same order (and with the same RNG algorithm).
+///
+/// The exact sequence of draws is:
+/// 1. The initial latents, as `batch_size * 4 * (height / 8) * (width / 8)` standard normal samples in row-major
+///    (`NCHW`) order, i.e. all of the first image's latents before the second's.
+/// 2. For ancestral/stochastic schedulers (e.g. [`EulerAncestralDiscreteScheduler`](crate::EulerAncestralDiscreteScheduler)),
+///    the noise for each step, in step order, with the same shape & layout as the latents. The RNG used for these draws
+///    depends on the draw order.
+///
+/// If [Attend-and-Excite](AttendAndExciteOptions) is enabled, its perturbations are drawn from a separate RNG seeded
+/// with `seed`, so enabling it doesn't change the draws listed above. No other random numbers are drawn. The
+/// unconditional half of the classifier-free guidance batch reuses the same latents, so it doesn't draw any extra
+/// noise.
+///
+/// Note that pyke Diffusers uses Rust's [`StdRng`] rather than PyTorch's generator, so even with
+/// [`RngDrawOrder::Sequential`], the same seed won't produce the same numbers as diffusers-python; the draw order only
+/// ensures that porting a different RNG yields matching results.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RngDrawOrder {
+	/// The initial latents are drawn from an RNG seeded with `seed`, and the scheduler is given a separate RNG (seeded
+	/// according to the [`CompatibilityVersion`]). Changing the scheduler's noise usage therefore doesn't affect the
+	/// initial latents. **This is the default.**
+	#[default]
+	Separate,
+	/// A single RNG seeded with `seed` is used for everything: the initial latents are drawn first, then the
+	/// scheduler's per-step noise continues from the same RNG. This matches the ordering of diffusers-python's
+	/// pipelines when passing a single `generator`.
+	Sequential,
+}
+
+/// Draws the initial (unscaled) latents and creates the RNG to be passed to the scheduler, according to the RNG draw
+/// order.
+pub(crate) fn draw_initial_latents(
+	compatibility_version: CompatibilityVersion,
+	rng_draw_order: RngDrawOrder,
+	seed: u64,
+	latents_shape: (usize, usize, usize, usize),
+) -> (Array4<f32>, StdRng) {
+	let mut rng = compatibility_version.latents_rng(seed);
+	let latents = Array4::<f32>::random_using(latents_shape, StandardNormal, &mut rng);
+	let scheduler_rng = match rng_draw_order {
+		RngDrawOrder::Separate => compatibility_version.scheduler_rng(seed),
+		RngDrawOrder::Sequential => rng,
+	};
+	(latents, scheduler_rng)
+}
+
+/// How [`StableDiffusionTxt2ImgOptions::with_size`] handles sizes which are not multiples of 8, which Stable Diffusion
+/// cannot generate directly. See [`StableDiffusionTxt2ImgOptions::with_dimension_policy`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DimensionPolicy {
+	/// Each dimension is rounded down to a multiple of 8 (and at least 8), so images may be up to 7 pixels smaller than
+	/// requested. **This is the default.**
+	#[default]
+	RoundDown,
+	/// Images are delivered at exactly the requested size. Each dimension which isn't a multiple of 8 is generated with
+	/// one extra latent (8 pixels) of noise on both sides of the noise a [`DimensionPolicy::RoundDown`] generation would
+	/// use, and the decoded images are cropped to the requested size starting at that noise, so they line up with the
+	/// [`DimensionPolicy::RoundDown`] image and extend it by up to 7 pixels on the right/bottom.
+	PadAndCrop,
+}
+
+impl DimensionPolicy {
+	/// Returns the number of padding latents on each side of a requested dimension.
+	fn padding(self, size: u32) -> u32 {
+		match self {
+			DimensionPolicy::PadAndCrop if size > 8 && size % 8 != 0 => 1,
+			_ => 0,
+		}
+	}
+
+	/// Returns the size a requested dimension is generated at.
+	pub(crate) fn generated_size(self, size: u32) -> u32 {
+		(size / 8).max(1) * 8 + self.padding(size) * 16
+	}
+
+	/// Returns the offset of the delivered crop of a requested dimension within the generated size, which is where the
+	/// noise of the rounded-down size starts.
+	fn crop_offset(self, size: u32) -> u32 {
+		self.padding(size) * 8
+	}
+}
 
-use crate::{DiffusionScheduler, Prompt, StableDiffusionCallback, StableDiffusionPipeline};
+/// What happens when more [steps](StableDiffusionTxt2ImgOptions::steps) are requested than the scheduler supports: more
+/// than its [`max_inference_steps`](DiffusionScheduler::max_inference_steps), or more than the timesteps it actually
+/// computes for them (`timesteps().len() / order()`), e.g. because it dropped duplicate timesteps. See
+/// [`StableDiffusionTxt2ImgOptions::with_step_limit_policy`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepLimitPolicy {
+	/// The step count is clamped to the scheduler's maximum and a warning is logged. **This is the default.**
+	#[default]
+	Clamp,
+	/// Generation fails with an error.
+	Error,
+}
+
+/// Returns the shape of the initial latents of a batch of `width`x`height` images.
+pub(crate) fn latents_shape(batch_size: usize, height: u32, width: u32) -> (usize, usize, usize, usize) {
+	(batch_size, 4, (height / 8) as usize, (width / 8) as usize)
+}
+
+impl StableDiffusionPipeline {
+	/// Prepares the initial latents of a batch of `batch_size` `width`x`height` images generated with `seed`, exactly as
+	/// text-to-image generation with the default options draws them, so they can be inspected or modified before
+	/// generating from them with [`StableDiffusionTxt2ImgOptions::with_latents`]. Latents have shape
+	/// `(batch_size, 4, height / 8, width / 8)`; `height` & `width` should be divisible by 8.
+	///
+	/// The returned latents are **already scaled** by the scheduler's
+	/// [`init_noise_sigma`](DiffusionScheduler::init_noise_sigma). Generating from unmodified latents produces the same
+	/// images as generating with the same seed directly. Use [`StableDiffusionTxt2ImgOptions::prepare_latents`] to
+	/// prepare latents for options other than the defaults, e.g. another [`CompatibilityVersion`].
+	pub fn prepare_latents<S: DiffusionScheduler>(&self, batch_size: usize, height: u32, width: u32, seed: u64, scheduler: &S) -> Array4<f32> {
+		let options = StableDiffusionTxt2ImgOptions { height, width, seed: Some(seed), ..Default::default() };
+		options.prepare_latents(batch_size, scheduler).expect("the default options always prepare latents")
+	}
+}
 
 /// Options for the Stable Diffusion text-to-image pipeline.
 #[derive(Debug)]
@@ -19,21 +213,69 @@ pub struct StableDiffusionTxt2ImgOptions {
 	/// The width of the image. **Must be divisible by 8.**
 	/// Note that higher resolution images require more VRAM.
 	pub width: u32,
+	/// How sizes which aren't multiples of 8 are handled. Defaults to [`DimensionPolicy::RoundDown`]. See
+	/// [`StableDiffusionTxt2ImgOptions::with_dimension_policy`].
+	pub dimension_policy: DimensionPolicy,
+	/// The exact `(width, height)` given to [`StableDiffusionTxt2ImgOptions::with_size`], before applying the
+	/// [`dimension_policy`](Self::dimension_policy). Defaults to `(512, 512)`.
+	pub requested_size: (u32, u32),
 	/// The 'guidance scale' for classifier-free guidance. A lower guidance scale gives the model more freedom, but the
 	/// output may not match the prompt. A higher guidance scale mean the model will match the prompt(s) more strictly,
 	/// but may introduce artifacts; `7.5` is a good balance.
+	///
+	/// Guidance-distilled UNets (with a `guidance` or `w_embedding` input) instead receive the guidance scale as a
+	/// sinusoidal embedding and always run without classifier-free guidance; negative prompts are ignored.
 	pub guidance_scale: f32,
 	/// Set to `Some(multiplier)` to enable classifier-free guidance rescaling according to section 3.4 of https://arxiv.org/pdf/2305.08891.pdf.
 	/// `multiplier` should be a value between 0.5-0.75 for best results.
 	pub rescale_cfg: Option<f32>,
 	/// The number of steps to take to generate the image. More steps typically yields higher quality images.
 	pub steps: usize,
+	/// What happens when more [`steps`](Self::steps) are requested than the scheduler supports. Defaults to
+	/// [`StepLimitPolicy::Clamp`].
+	pub step_limit_policy: StepLimitPolicy,
+	/// A custom noise schedule, replacing the one computed by the scheduler for [`steps`](Self::steps); see
+	/// [`StableDiffusionTxt2ImgOptions::with_custom_sigmas`].
+	pub custom_sigmas: Option<Vec<f32>>,
+	/// Exits the denoising loop early once the latents have converged; see
+	/// [`StableDiffusionTxt2ImgOptions::with_early_exit`].
+	pub early_exit: Option<EarlyExit>,
+	/// The number of images to generate for each prompt. Defaults to `1`. See
+	/// [`StableDiffusionTxt2ImgOptions::with_num_images_per_prompt`].
+	pub num_images_per_prompt: usize,
 	/// An optional seed to use when first generating noise. The same seed with the same scheduler, prompt, & guidance
 	/// scale will produce the same image. If `None`, a random seed will be generated.
 	///
 	/// Seeds are not interchangable between schedulers, and **a seed from Hugging Face diffusers or AUTOMATIC1111's
 	/// web UI will *not* generate the same image** in pyke Diffusers.
 	pub seed: Option<u64>,
+	/// Additional seeds whose final latents are averaged with those of `seed` before decoding; see
+	/// [`StableDiffusionTxt2ImgOptions::with_averaged_seeds`].
+	pub averaged_seeds: Vec<u64>,
+	/// Re-samples regions of each image's initial noise from a secondary seed for a more diverse batch; see
+	/// [`StableDiffusionTxt2ImgOptions::with_diversity`].
+	pub diversity: Option<DiversityConfig>,
+	/// How the initial noise of the images in a batch relates to each other. Defaults to independent noise per image.
+	/// See [`StableDiffusionTxt2ImgOptions::with_batch_noise_mode`].
+	pub batch_noise_mode: BatchNoiseMode,
+	/// Per-element factors by which each image's conditional embeddings are moved toward the unconditional ones; see
+	/// [`StableDiffusionTxt2ImgOptions::with_conditioning_dropout`].
+	pub conditioning_dropout: Option<Vec<f32>>,
+	/// The maximum number of times generation is retried with a new seed if the latents contain NaN or infinite values
+	/// after denoising. Defaults to `0` (no retries). See [`StableDiffusionTxt2ImgOptions::with_retry_on_nan`].
+	pub retry_on_nan: usize,
+	/// Reuses the deep features of a split UNet across steps to speed up denoising; see
+	/// [`StableDiffusionTxt2ImgOptions::with_deepcache`].
+	pub deepcache: Option<DeepCacheConfig>,
+	/// If set, only these elements of the batch are generated; see [`StableDiffusionTxt2ImgOptions::with_batch_subset`].
+	pub batch_subset: Option<Vec<usize>>,
+	/// A token to cancel generation from another thread; see [`StableDiffusionTxt2ImgOptions::with_cancellation_token`].
+	pub cancellation_token: Option<CancellationToken>,
+	/// The maximum duration of the denoising loop; see [`StableDiffusionTxt2ImgOptions::with_timeout`].
+	pub timeout: Option<Duration>,
+	/// Whether the latents of a generation which stopped early are still decoded. Disabled by default. See
+	/// [`StableDiffusionTxt2ImgOptions::with_decode_on_early_stop`].
+	pub decode_on_early_stop: bool,
 	/// ETA noise seed delta (ENSD). The scheduler will be given an RNG seeded with `seed + ensd`.
 	pub ensd: u64,
 	/// Prompt(s) describing what the model should generate in classifier-free guidance.
@@ -42,9 +284,98 @@ pub struct StableDiffusionTxt2ImgOptions {
 	/// to produce safe outputs, e.g. `negative_prompt: Some("gore, violence, blood".into())`. Must have the same
 	/// number of prompts as the 'positive' prompt input.
 	pub negative_prompt: Option<Prompt>,
+	/// How the text of each prompt is encoded; empty (every prompt is weighted) by default. See
+	/// [`StableDiffusionTxt2ImgOptions::with_prompt_weighting`].
+	pub prompt_weighting: Vec<PromptWeighting>,
+	/// Prompt(s) given as token IDs, used instead of [`positive_prompt`](Self::positive_prompt) if set. See
+	/// [`StableDiffusionTxt2ImgOptions::with_prompt_input`].
+	pub prompt_token_ids: Option<Array2<i64>>,
+	/// Negative prompt(s) given as token IDs; may only be used together with
+	/// [`prompt_token_ids`](Self::prompt_token_ids).
+	pub negative_prompt_token_ids: Option<Array2<i64>>,
+	/// Pre-computed text embeddings, used instead of encoding the prompts if set. See
+	/// [`StableDiffusionTxt2ImgOptions::with_prompt_embeddings`].
+	pub prompt_embeddings: Option<ArrayD<f32>>,
+	/// An optional style prompt blended with the prompts' embeddings; see
+	/// [`StableDiffusionTxt2ImgOptions::with_style_prompt`].
+	pub style_prompt: Option<StylePrompt>,
 	/// An optional callback to call every `n` steps in the generation process. Can be used to log or display progress,
 	/// see [`StableDiffusionCallback`] for more details.
 	pub callback: Option<StableDiffusionCallback>,
+	/// An optional preview callback, called once with approximately decoded images; see
+	/// [`StableDiffusionTxt2ImgOptions::with_preview`].
+	pub preview: Option<StableDiffusionPreview>,
+	/// Pins random number generation to the behavior of a specific release, see [`CompatibilityVersion`].
+	pub compatibility_version: CompatibilityVersion,
+	/// An optional latent-resolution mask of shape `(1 or batch_size, height / 8, width / 8)` marking regions of the
+	/// latents to freeze. After each scheduler step, latents where the mask is `1.0` are reset to their initial value;
+	/// latents where the mask is `0.0` are denoised as usual. Values in between blend linearly.
+	pub freeze_mask: Option<Array3<f32>>,
+	/// Inpaints the masked region of an image instead of generating a whole new image. See [`InpaintOptions`].
+	pub inpaint: Option<InpaintOptions>,
+	/// Enables [MultiDiffusion](https://arxiv.org/abs/2302.08113) tiled generation, optionally with per-region
+	/// prompts. See [`MultiDiffusionOptions`].
+	pub multidiffusion: Option<MultiDiffusionOptions>,
+	/// Whether to record per-step [`StepStats`] into the [`StableDiffusionOutput`] returned by
+	/// [`StableDiffusionTxt2ImgOptions::run_with_output`]. Collecting stats costs a few reductions over the latents per
+	/// step, so it is enabled by default.
+	pub collect_step_stats: bool,
+	/// Whether to record the scheduler's trajectory, a [`StepRecord`] per step, into the [`StableDiffusionOutput`].
+	/// Defaults to `false`. See [`StableDiffusionTxt2ImgOptions::with_trajectory`].
+	pub collect_trajectory: bool,
+	/// Whether to record a [`ClampReport`](crate::ClampReport) for each image into the [`StableDiffusionOutput`].
+	/// Defaults to `false`. See [`StableDiffusionTxt2ImgOptions::with_clamp_reports`].
+	pub collect_clamp_reports: bool,
+	/// The order in which random numbers are drawn; see [`RngDrawOrder`].
+	pub rng_draw_order: RngDrawOrder,
+	/// If `true`, the initial latents are not multiplied by the scheduler's
+	/// [`init_noise_sigma`](DiffusionScheduler::init_noise_sigma). Defaults to `false`. See
+	/// [`StableDiffusionTxt2ImgOptions::with_skip_init_noise_scaling`].
+	pub skip_init_noise_scaling: bool,
+	/// Initial latents to generate from instead of drawing them from the seed; see
+	/// [`StableDiffusionTxt2ImgOptions::with_latents`].
+	pub latents: Option<Array4<f32>>,
+	/// Statistics to normalize the initial [`latents`](Self::latents) to before generating; see
+	/// [`StableDiffusionTxt2ImgOptions::with_latents_normalized_to`].
+	pub latents_target_stats: Option<LatentStats>,
+	/// Enables [Attend-and-Excite](https://arxiv.org/abs/2301.13826) for the given subject tokens. See
+	/// [`AttendAndExciteOptions`].
+	pub attend_and_excite: Option<AttendAndExciteOptions>,
+	/// Captures a [`DiffusionCheckpoint`] after this many steps; see
+	/// [`StableDiffusionTxt2ImgOptions::with_checkpoint_at`].
+	pub checkpoint_at: Option<usize>,
+	/// If set, the run stops after this fraction of the steps, for handing the latents off to a refiner; see
+	/// [`StableDiffusionTxt2ImgOptions::with_denoising_end`].
+	pub denoising_end: Option<f32>,
+	/// Intervals of the noise schedule to re-noise & denoise again with Restart sampling. Empty (no restarts) by
+	/// default. See [`StableDiffusionTxt2ImgOptions::with_restart_schedule`].
+	pub restart_schedule: Vec<RestartInterval>,
+	/// If `true`, the classifier-free guidance combination is computed in `f64`. Defaults to `false`. See
+	/// [`StableDiffusionTxt2ImgOptions::with_f64_guidance`].
+	pub f64_guidance: bool,
+	/// The file format images are written in by [`StableDiffusionPipeline::txt2img_to_files`]. Defaults to PNG.
+	pub file_format: ImageFileFormat,
+	/// Where [`StableDiffusionPipeline::txt2img_to_files`] records the parameters of each image. Defaults to
+	/// [`MetadataMode::None`]. See [`StableDiffusionTxt2ImgOptions::with_metadata_mode`].
+	pub metadata_mode: MetadataMode,
+	/// If set, decoded images are written to this directory one at a time instead of being held in memory; see
+	/// [`StableDiffusionTxt2ImgOptions::with_decode_to_disk`].
+	pub decode_to_disk: Option<PathBuf>,
+	/// A reference image whose self-attention hidden states are injected into the generation; see
+	/// [`StableDiffusionTxt2ImgOptions::with_reference_image`].
+	pub reference_image: Option<DynamicImage>,
+	/// The weight of the [`reference_image`](Self::reference_image), from `0.0` (no effect) to `1.0`. Defaults to
+	/// `1.0`.
+	pub reference_weight: f32,
+	/// The filter the reference image, & images upscaled by [`StableDiffusionPipeline::super_resolve`], are resized
+	/// with. Defaults to [`ResizeFilter::Lanczos3`]. See [`StableDiffusionTxt2ImgOptions::with_resize_filter`].
+	pub resize_filter: ResizeFilter,
+	/// The filter [`StableDiffusionPipeline::inpaint`] resizes mask images to the latent resolution with. Defaults to
+	/// [`ResizeFilter::Nearest`]. See [`StableDiffusionTxt2ImgOptions::with_mask_resize_filter`].
+	pub mask_resize_filter: ResizeFilter,
+	/// The ControlNets guiding the generation, whose weighted residuals are summed on each step. Empty by default. See
+	/// [`StableDiffusionTxt2ImgOptions::with_controlnet`].
+	pub controlnets: Vec<ControlNetConfig>,
 }
 
 impl Default for StableDiffusionTxt2ImgOptions {
@@ -52,41 +383,113 @@ impl Default for StableDiffusionTxt2ImgOptions {
 		Self {
 			height: 512,
 			width: 512,
+			dimension_policy: DimensionPolicy::default(),
+			requested_size: (512, 512),
 			guidance_scale: 7.5,
 			rescale_cfg: None,
 			steps: 25,
+			step_limit_policy: StepLimitPolicy::default(),
+			custom_sigmas: None,
+			early_exit: None,
+			num_images_per_prompt: 1,
 			seed: None,
+			averaged_seeds: Vec::new(),
+			diversity: None,
+			batch_noise_mode: BatchNoiseMode::Independent,
+			conditioning_dropout: None,
+			retry_on_nan: 0,
+			deepcache: None,
+			batch_subset: None,
+			cancellation_token: None,
+			timeout: None,
+			decode_on_early_stop: false,
 			ensd: 0,
 			positive_prompt: Prompt::default(),
 			negative_prompt: None,
+			prompt_weighting: Vec::new(),
+			prompt_token_ids: None,
+			negative_prompt_token_ids: None,
+			prompt_embeddings: None,
+			style_prompt: None,
 			callback: None,
+			preview: None,
+			compatibility_version: CompatibilityVersion::default(),
+			freeze_mask: None,
+			inpaint: None,
+			multidiffusion: None,
+			collect_step_stats: true,
+			collect_trajectory: false,
+			collect_clamp_reports: false,
+			rng_draw_order: RngDrawOrder::default(),
+			skip_init_noise_scaling: false,
+			latents: None,
+			latents_target_stats: None,
+			attend_and_excite: None,
+			checkpoint_at: None,
+			denoising_end: None,
+			restart_schedule: Vec::new(),
+			f64_guidance: false,
+			file_format: ImageFileFormat::default(),
+			metadata_mode: MetadataMode::None,
+			decode_to_disk: None,
+			reference_image: None,
+			reference_weight: 1.0,
+			resize_filter: ResizeFilter::Lanczos3,
+			mask_resize_filter: ResizeFilter::Nearest,
+			controlnets: Vec::new(),
 		}
 	}
 }
 
 impl StableDiffusionTxt2ImgOptions {
-	/// Set the size of the image. **Size will be rounded to a multiple of 8**. Note that higher resolution images
-	/// require more (V)RAM to generate.
+	/// Set the size of the image. **Size will be rounded down to a multiple of 8** unless the
+	/// [dimension policy](StableDiffusionTxt2ImgOptions::with_dimension_policy) is [`DimensionPolicy::PadAndCrop`].
+	/// Note that higher resolution images require more (V)RAM to generate.
 	pub fn with_size(self, width: u32, height: u32) -> Self {
 		self.with_width(width).with_height(height)
 	}
 
-	/// Set the width of the image. **Width will be rounded to a multiple of 8**. Note that higher resolution images
-	/// require more (V)RAM to generate.
+	/// Set the width of the image. **Width will be rounded down to a multiple of 8** unless the
+	/// [dimension policy](StableDiffusionTxt2ImgOptions::with_dimension_policy) is [`DimensionPolicy::PadAndCrop`].
+	/// Note that higher resolution images require more (V)RAM to generate.
 	#[inline]
 	pub fn with_width(mut self, width: u32) -> Self {
-		self.width = (width / 8).max(1) * 8;
+		self.requested_size.0 = width;
+		self.width = self.dimension_policy.generated_size(width);
 		self
 	}
 
-	/// Set the height of the image. **Height will be rounded to a multiple of 8**. Note that higher resolution images
-	/// require more (V)RAM to generate.
+	/// Set the height of the image. **Height will be rounded down to a multiple of 8** unless the
+	/// [dimension policy](StableDiffusionTxt2ImgOptions::with_dimension_policy) is [`DimensionPolicy::PadAndCrop`].
+	/// Note that higher resolution images require more (V)RAM to generate.
 	#[inline]
 	pub fn with_height(mut self, height: u32) -> Self {
-		self.height = (height / 8).max(1) * 8;
+		self.requested_size.1 = height;
+		self.height = self.dimension_policy.generated_size(height);
 		self
 	}
 
+	/// Sets how sizes which aren't multiples of 8 are handled, re-applying the size last given to
+	/// [`StableDiffusionTxt2ImgOptions::with_size`] (or `512x512`). Defaults to [`DimensionPolicy::RoundDown`].
+	///
+	/// With [`DimensionPolicy::PadAndCrop`], [`width`](Self::width) & [`height`](Self::height) hold the padded size
+	/// which is actually generated, and everything but the final images sees that size: callbacks & previews receive
+	/// uncropped images, and per-pixel inputs such as inpainting masks must be given at the generated size. Only images
+	/// decoded in memory can be cropped, so this policy can't be combined with
+	/// [`StableDiffusionTxt2ImgOptions::with_decode_to_disk`] for sizes which need padding. If `width` or `height` are
+	/// changed directly afterwards, the policy no longer applies and images are delivered at the generated size.
+	///
+	/// ```
+	/// # use pyke_diffusers::{DimensionPolicy, StableDiffusionTxt2ImgOptions};
+	/// let options = StableDiffusionTxt2ImgOptions::default().with_dimension_policy(DimensionPolicy::PadAndCrop).with_size(500, 333);
+	/// assert_eq!((options.width, options.height), (512, 344));
+	/// ```
+	pub fn with_dimension_policy(mut self, policy: DimensionPolicy) -> Self {
+		self.dimension_policy = policy;
+		let (width, height) = self.requested_size;
+		self.with_size(width, height)
+	}
+
 	/// The number of steps to take to generate the image. Typically, more steps yields higher quality images up to a
 	/// certain point, depending on the scheduler. For instance, the quality of images generated using
 	/// [`DPMSolverMultistepScheduler`](crate::schedulers::DPMSolverMultistepScheduler) does not meaningfully improve
@@ -96,6 +499,46 @@ impl StableDiffusionTxt2ImgOptions {
 		self
 	}
 
+	/// Sets what happens when more [`steps`](Self::steps) are requested than the scheduler supports, e.g. more steps
+	/// than a [`DDPMScheduler`](crate::schedulers::DDPMScheduler) has train timesteps, or than a scheduler computes
+	/// timesteps for after dropping duplicates (see [`StepLimitPolicy`]). By default, the step count is
+	/// clamped to the scheduler's maximum with a warning; all step-dependent settings, such as the first step callbacks
+	/// are invoked on, and [`StableDiffusionOutput::steps_taken`] then follow the clamped count.
+	pub fn with_step_limit_policy(mut self, policy: StepLimitPolicy) -> Self {
+		self.step_limit_policy = policy;
+		self
+	}
+
+	/// Denoises with exactly the given sigmas, e.g. a noise schedule exported from another tool, instead of the schedule
+	/// the scheduler computes for [`steps`](Self::steps). One step is taken per sigma; `sigmas` must be strictly
+	/// decreasing and may end with `0.0`. The initial noise is scaled by the first sigma.
+	///
+	/// Only sigma-based schedulers support custom sigmas; see [`DiffusionScheduler::set_sigmas`].
+	pub fn with_custom_sigmas(mut self, sigmas: impl Into<Vec<f32>>) -> Self {
+		self.custom_sigmas = Some(sigmas.into());
+		self
+	}
+
+	/// Skips the remaining steps once the latents stop changing meaningfully, which on easy prompts can save a
+	/// significant amount of time. The number of steps actually taken is returned in
+	/// [`StableDiffusionOutput::steps_taken`]. See [`EarlyExit`].
+	///
+	/// Early exit cannot be combined with restart sampling or `denoising_end`.
+	pub fn with_early_exit(mut self, early_exit: EarlyExit) -> Self {
+		self.early_exit = Some(early_exit);
+		self
+	}
+
+	/// Set the number of images to generate for each prompt. Defaults to `1`.
+	///
+	/// Images are returned grouped by prompt: with prompts `[a, b]` and 3 images per prompt, the images are generated
+	/// from `[a, a, a, b, b, b]`. Each image is generated from different initial noise. The prompts are only encoded
+	/// once; their embeddings are repeated to match the expanded batch.
+	pub fn with_num_images_per_prompt(mut self, num_images_per_prompt: usize) -> Self {
+		self.num_images_per_prompt = num_images_per_prompt;
+		self
+	}
+
 	/// Set the prompt(s) describing what the model should generate in classifier-free guidance.
 	///
 	/// Prompts support long prompt weighting (LPW). LPW enables prompts beyond the typical 77 token limit of the CLIP
@@ -154,6 +597,99 @@ impl StableDiffusionTxt2ImgOptions {
 		self
 	}
 
+	/// Chooses how the text of each prompt is encoded, given either a single [`PromptWeighting`] for every prompt, or
+	/// one per prompt in batch order. This allows batching prompts using LPW syntax with prompts whose brackets should
+	/// be kept literally. Each negative prompt is encoded with its prompt's weighting. Only applies to text prompts.
+	///
+	/// See [`StableDiffusionPipeline::encode_prompt_with_weighting`] for how prompts of different lengths are padded.
+	pub fn with_prompt_weighting<I>(mut self, prompt_weighting: I) -> Self
+	where
+		I: IntoIterator<Item = PromptWeighting>,
+	{
+		self.prompt_weighting = prompt_weighting.into_iter().collect();
+		self
+	}
+
+	/// Set the prompt(s) describing what the model should generate, as either text or token IDs (see [`PromptInput`]).
+	///
+	/// Token IDs allow generating with pipelines that have no tokenizer (configured with `type = "None"` in the
+	/// model's `[tokenizer]` section), which fail with [`TokenizerUnavailable`](crate::TokenizerUnavailable) when given a
+	/// text prompt.
+	///
+	/// ```
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionTxt2ImgOptions, PromptInput};
+	/// # use ndarray::Array2;
+	/// // BOS, `a`, `photo`, EOS padding
+	/// let mut ids = Array2::from_elem((1, 77), 49407_i64);
+	/// ids.row_mut(0).slice_mut(ndarray::s![..3]).assign(&ndarray::arr1(&[49406, 320, 1125]));
+	/// let options = StableDiffusionTxt2ImgOptions::default().with_prompt_input(PromptInput::TokenIds(ids));
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn with_prompt_input<P>(mut self, prompt: P) -> Self
+	where
+		P: Into<PromptInput>,
+	{
+		match prompt.into() {
+			PromptInput::Text(prompt) => {
+				self.positive_prompt = prompt;
+				self.prompt_token_ids = None;
+			}
+			PromptInput::TokenIds(ids) => self.prompt_token_ids = Some(ids),
+		}
+		self
+	}
+
+	/// Set the negative prompt(s) as either text or token IDs (see [`PromptInput`]). This must be the same kind of
+	/// input as the prompt set with [`StableDiffusionTxt2ImgOptions::with_prompt_input`]. A negative prompt given as
+	/// token IDs must have the same length as the prompt's token IDs.
+	pub fn with_negative_prompt_input<P>(mut self, negative_prompt: P) -> Self
+	where
+		P: Into<PromptInput>,
+	{
+		match negative_prompt.into() {
+			PromptInput::Text(negative_prompt) => {
+				self.negative_prompt = Some(negative_prompt);
+				self.negative_prompt_token_ids = None;
+			}
+			PromptInput::TokenIds(ids) => {
+				self.negative_prompt = None;
+				self.negative_prompt_token_ids = Some(ids);
+			}
+		}
+		self
+	}
+
+	/// Generates from pre-computed text embeddings instead of the prompts, e.g. embeddings of several prompts blended
+	/// together. The embeddings must have the layout returned by [`StableDiffusionPipeline::encode_prompt`]: with
+	/// classifier-free guidance (a guidance scale above 1 with a UNet which isn't guidance-distilled), the negative
+	/// prompts' embeddings followed by the prompts' embeddings, and only the prompts' embeddings otherwise. Text &
+	/// token ID prompts are ignored, but are still recorded in [metadata](Self::with_metadata_mode).
+	pub fn with_prompt_embeddings(mut self, embeddings: ArrayD<f32>) -> Self {
+		self.prompt_embeddings = Some(embeddings);
+		self
+	}
+
+	/// Encodes a separate prompt describing the style of the image & blends its embeddings with the prompts', so that
+	/// the subject can be described in [`positive_prompt`](Self::positive_prompt) and the style changed independently.
+	/// See [`StylePrompt`] for how the embeddings are blended & how the blend interacts with classifier-free guidance.
+	///
+	/// ```
+	/// # use pyke_diffusers::{StableDiffusionTxt2ImgOptions, StylePrompt};
+	/// let options = StableDiffusionTxt2ImgOptions::default()
+	/// 	.with_prompt("photo of a red fox in a forest")
+	/// 	.with_style_prompt(StylePrompt::new("watercolor painting, soft pastel colors", 0.4));
+	/// ```
+	///
+	/// Style prompts can only be used with text prompts, not with [token IDs](Self::with_prompt_input) or
+	/// [pre-computed embeddings](Self::with_prompt_embeddings); use [`StableDiffusionPipeline::encode_styled_prompt`] to
+	/// blend embeddings ahead of time instead.
+	pub fn with_style_prompt(mut self, style_prompt: StylePrompt) -> Self {
+		self.style_prompt = Some(style_prompt);
+		self
+	}
+
 	/// Set a seed to use when first generating noise. The same seed with the same prompt and parameters will produce
 	/// the same image. If `None`, a random seed will be generated.
 	///
@@ -164,165 +700,2033 @@ impl StableDiffusionTxt2ImgOptions {
 		self
 	}
 
-	/// Use a random seed, so that each run generates a different image.
-	pub fn with_random_seed(mut self) -> Self {
-		self.seed = None;
+	/// Also denoises the batch with each of the given seeds, and averages the final latents of all runs (including
+	/// the one with [`seed`](Self::seed)) with [`average_latents`](crate::average_latents) before decoding them once.
+	/// Each additional seed costs a full denoising run; callbacks & previews are called for every run, while the
+	/// returned step statistics & [`steps_taken`](StableDiffusionOutput::steps_taken) are those of the first run.
+	///
+	/// Averaging gives smoother, less noisy results, at the cost of fine detail & variance: features the runs don't
+	/// agree on are washed out. This is not the same as averaging the decoded images, as the VAE decoder is not
+	/// linear; averaged latents decode to one coherent (if softer) image rather than an overlay of several.
+	///
+	/// Seed averaging cannot be combined with checkpoints or `denoising_end`.
+	pub fn with_averaged_seeds<I: IntoIterator<Item = u64>>(mut self, seeds: I) -> Self {
+		self.averaged_seeds = seeds.into_iter().collect();
 		self
 	}
 
-	/// The 'guidance scale' for classifier-free guidance. A lower guidance scale gives the model more freedom, but the
-	/// output may not match the prompt. A higher guidance scale mean the model will match the prompt(s) more strictly,
-	/// but may introduce artifacts; `7.5` is a good balance.
-	pub fn with_guidance_scale(mut self, guidance_scale: f32) -> Self {
-		self.guidance_scale = guidance_scale;
+	/// Perturbs the initial noise of each image in the batch by re-sampling rectangular regions of it from
+	/// `config.secondary_seed`, so that one seed gives a more diverse batch. The first image keeps the seed's noise, and
+	/// each further image has `config.regions` more regions perturbed than the previous one; see [`DiversityConfig`].
+	///
+	/// The perturbed regions of each image are returned in
+	/// [`StableDiffusionOutput::perturbed_regions`]. Generating again with the same seed, secondary seed, & batch
+	/// reproduces each image exactly. Diversity only applies to drawn noise, so it cannot be combined with
+	/// [`StableDiffusionTxt2ImgOptions::with_latents`].
+	///
+	/// ```
+	/// # use pyke_diffusers::{DiversityConfig, StableDiffusionTxt2ImgOptions};
+	/// let options = StableDiffusionTxt2ImgOptions::default()
+	/// 	.with_prompt("photo of a red fox")
+	/// 	.with_seed(42)
+	/// 	.with_num_images_per_prompt(4)
+	/// 	.with_diversity(DiversityConfig::new(2, 0.1, 1337));
+	/// ```
+	pub fn with_diversity(mut self, config: DiversityConfig) -> Self {
+		self.diversity = Some(config);
 		self
 	}
 
-	/// ETA noise seed delta (ENSD). The scheduler will be given an RNG seeded with `seed + ensd`.
-	pub fn with_eta_noise_seed_delta(mut self, ensd: u64) -> Self {
-		self.ensd = ensd;
+	/// If the latents contain NaN or infinite values after denoising (which some seeds occasionally cause on some
+	/// hardware), retries generation up to `max_retries` times before failing with [`NonFiniteLatents`]. By default,
+	/// generation fails on the first such run.
+	///
+	/// **Retrying changes the effective seed**: each retry uses a new seed derived deterministically from the failed
+	/// one, so the retried images differ from what the requested seed would generate. The seed the returned images were
+	/// actually generated with is given by [`StableDiffusionOutput::seed`], and is the one written to image metadata.
+	/// Runs resumed from a checkpoint are never retried, since their seed is fixed by the checkpoint; image-to-image
+	/// generation & super-resolution are retried with noise drawn from the new seed.
+	pub fn with_retry_on_nan(mut self, max_retries: usize) -> Self {
+		self.retry_on_nan = max_retries;
 		self
 	}
 
-	#[doc = include_str!("_doc/callback-progress.md")]
-	pub fn callback_progress<F>(mut self, frequency: usize, callback: F) -> Self
-	where
-		F: Fn(usize, f32) -> bool + 'static,
-	{
-		self.callback = Some(StableDiffusionCallback::Progress { frequency, cb: Box::new(callback) });
-		self
-	}
-	#[doc = include_str!("_doc/callback-latents.md")]
-	pub fn callback_latents<F>(mut self, frequency: usize, callback: F) -> Self
-	where
-		F: Fn(usize, f32, Array4<f32>) -> bool + 'static,
-	{
-		self.callback = Some(StableDiffusionCallback::Latents { frequency, cb: Box::new(callback) });
+	/// Sets how the initial noise of the images in a batch relates to each other; see [`BatchNoiseMode`]. For example,
+	/// [`BatchNoiseMode::Slerp`] smoothly morphs the noise from one seed to another across the batch, so consecutive
+	/// images can be used as the frames of an animation.
+	///
+	/// ```
+	/// # use pyke_diffusers::{BatchNoiseMode, StableDiffusionTxt2ImgOptions};
+	/// let options = StableDiffusionTxt2ImgOptions::default()
+	/// 	.with_prompt("photo of a red fox")
+	/// 	.with_num_images_per_prompt(16)
+	/// 	.with_batch_noise_mode(BatchNoiseMode::Slerp { from_seed: 42, to_seed: 1337 });
+	/// ```
+	pub fn with_batch_noise_mode(mut self, mode: BatchNoiseMode) -> Self {
+		self.batch_noise_mode = mode;
 		self
 	}
-	#[doc = include_str!("_doc/callback-decode-image.md")]
-	pub fn callback_decoded<F>(mut self, frequency: usize, callback: F) -> Self
-	where
-		F: Fn(usize, f32, Vec<DynamicImage>) -> bool + 'static,
-	{
-		self.callback = Some(StableDiffusionCallback::Decoded { frequency, cb: Box::new(callback) });
+
+	/// Weakens the conditioning of individual batch elements, so a single batch spans from images which closely follow
+	/// the prompt to more diverse ones. `factors` holds one factor per element of the full batch of
+	/// `prompts * num_images_per_prompt` images: before denoising, each element's conditional embeddings are linearly
+	/// interpolated toward its unconditional embeddings by its factor. `0.0` leaves the element unchanged, while `1.0`
+	/// generates it fully unconditionally, i.e. as if the negative prompt was also the prompt. The embeddings of
+	/// [MultiDiffusion](MultiDiffusionOptions) regional prompts are interpolated by the same factors.
+	///
+	/// This requires classifier-free guidance; generation fails with an error if the number of factors does not match
+	/// the batch size, or if a factor is outside `0.0..=1.0`.
+	///
+	/// ```
+	/// # use pyke_diffusers::StableDiffusionTxt2ImgOptions;
+	/// let options = StableDiffusionTxt2ImgOptions::default()
+	/// 	.with_prompt("photo of a red fox")
+	/// 	.with_num_images_per_prompt(4)
+	/// 	.with_conditioning_dropout(vec![0.0, 0.2, 0.4, 0.6]);
+	/// ```
+	pub fn with_conditioning_dropout(mut self, factors: Vec<f32>) -> Self {
+		self.conditioning_dropout = Some(factors);
 		self
 	}
-	#[doc = include_str!("_doc/callback-approximate-image.md")]
-	pub fn callback_approximate<F>(mut self, frequency: usize, callback: F) -> Self
-	where
-		F: Fn(usize, f32, Vec<DynamicImage>) -> bool + 'static,
-	{
-		self.callback = Some(StableDiffusionCallback::ApproximateDecoded { frequency, cb: Box::new(callback) });
-		self
+
+	/// Checks that the batch noise mode can be applied, i.e. that it is valid & no initial latents or averaged seeds
+	/// are given.
+	pub(crate) fn validate_batch_noise_mode(&self) -> anyhow::Result<()> {
+		if self.batch_noise_mode != BatchNoiseMode::Independent && (self.latents.is_some() || !self.averaged_seeds.is_empty()) {
+			anyhow::bail!("batch noise modes cannot be combined with initial latents or seed averaging");
+		}
+		self.batch_noise_mode.validate()
 	}
-}
 
-impl StableDiffusionTxt2ImgOptions {
-	/// Generates images from given text prompt(s). Returns a vector of [`image::DynamicImage`]s, using float32 buffers.
-	/// In most cases, you'll want to convert the images into RGB8 via `img.into_rgb8().`
+	/// Speeds up denoising with [DeepCache](https://arxiv.org/abs/2312.00858): the deep blocks of the UNet only run every
+	/// `interval` steps, and the steps in between reuse their cached features. This requires a model whose config
+	/// declares the UNet split into deep & shallow subgraphs, loaded by creating the pipeline with
+	/// [`StableDiffusionOptions::with_deepcache`](crate::StableDiffusionOptions::with_deepcache); otherwise, generation
+	/// fails with an error. See [`DeepCacheConfig`].
 	///
-	/// `scheduler` must be a Stable Diffusion-compatible scheduler.
+	/// DeepCache replaces the UNet for every step, so it cannot be combined with MultiDiffusion, ControlNet, reference
+	/// attention, or guidance-distilled UNets.
 	///
-	/// See [`StableDiffusionTxt2ImgOptions`] for additional configuration.
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{DeepCacheConfig, StableDiffusionTxt2ImgOptions};
+	/// let options = StableDiffusionTxt2ImgOptions::default()
+	/// 	.with_prompt("photo of a red fox")
+	/// 	.with_deepcache(DeepCacheConfig::new(3));
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn with_deepcache(mut self, config: DeepCacheConfig) -> Self {
+		self.deepcache = Some(config);
+		self
+	}
+
+	/// Only generates the batch elements at `indices`, e.g. to continue a batch aggregated from several requests after
+	/// some of them were cancelled. Indices refer to the full batch of `prompts * num_images_per_prompt` images, where
+	/// the images of each prompt are consecutive: the `j`th image of the `i`th prompt has index
+	/// `i * num_images_per_prompt + j`. Indices may be given in any order, but must not repeat.
 	///
-	/// # Examples
+	/// The initial noise is drawn for the full batch, so each generated element starts from the same noise as it would
+	/// in the full batch. With deterministic schedulers, it is therefore generated exactly like in the full batch;
+	/// ancestral schedulers draw their per-step noise for the generated elements only, so their images differ.
 	///
-	/// Simple text-to-image:
+	/// ## Return alignment
+	/// The returned [`StableDiffusionOutput::images`] (and other per-image outputs) only hold the generated elements,
+	/// in ascending index order. [`StableDiffusionOutput::generated`] marks which elements of the full batch were
+	/// generated, and [`StableDiffusionOutput::aligned_images`] returns the images aligned to the full batch, with
+	/// `None` in place of skipped elements:
 	///
 	/// ```no_run
 	/// # fn main() -> anyhow::Result<()> {
 	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
 	/// # let environment = OrtEnvironment::default().into_arc();
 	/// # let mut scheduler = EulerDiscreteScheduler::default();
-	/// let pipeline =
-	/// 	StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
-	///
-	/// let mut imgs = StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox").run(&pipeline, &mut scheduler)?;
-	/// imgs.remove(0).into_rgb8().save("result.png")?;
+	/// # let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+	/// let output = StableDiffusionTxt2ImgOptions::default()
+	/// 	.with_prompt(["photo of a red fox", "photo of an Arctic fox", "photo of a fennec fox"])
+	/// 	.with_batch_subset([0, 2])
+	/// 	.run_with_output(&pipeline, &mut scheduler)?;
+	/// assert_eq!(output.images.len(), 2);
+	/// let images = output.aligned_images();
+	/// assert!(images[0].is_some() && images[1].is_none() && images[2].is_some());
 	/// # Ok(())
 	/// # }
 	/// ```
-	pub fn run<S: DiffusionScheduler>(&self, session: &StableDiffusionPipeline, scheduler: &mut S) -> anyhow::Result<Vec<DynamicImage>> {
-		let steps = self.steps;
-		let seed = self.seed.unwrap_or_else(|| rand::thread_rng().gen::<u64>());
-		let mut rng = StdRng::seed_from_u64(seed);
+	///
+	/// Options which hold per-image inputs for the full batch (initial latents, inpainting, freeze masks & regional
+	/// prompts) or checkpoints of the full batch cannot be combined with a batch subset.
+	pub fn with_batch_subset<I: IntoIterator<Item = usize>>(mut self, indices: I) -> Self {
+		self.batch_subset = Some(indices.into_iter().collect());
+		self
+	}
 
-		if self.height % 8 != 0 || self.width % 8 != 0 {
-			anyhow::bail!("`width` ({}) and `height` ({}) must be divisible by 8 for Stable Diffusion", self.width, self.height);
+	/// Stops generation before the next step once `token` is cancelled, recording [`StopReason::Cancelled`]. See
+	/// [`CancellationToken`] & [`StopReason`] for what is returned when generation stops early.
+	pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+		self.cancellation_token = Some(token);
+		self
+	}
+
+	/// Stops generation before the next step once the denoising loop has run for `timeout`, recording
+	/// [`StopReason::TimedOut`]. See [`StopReason`] for what is returned when generation stops early.
+	///
+	/// The timeout is checked between steps, so a generation can run over it by up to one step (plus the time spent
+	/// encoding prompts & decoding images, which is not counted). Each denoising loop has its own timeout, so with
+	/// [seed averaging](StableDiffusionTxt2ImgOptions::with_averaged_seeds) every averaged seed may take up to `timeout`.
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// Decodes the latents of a generation which stopped early (because a callback returned `false`, its
+	/// [token](StableDiffusionTxt2ImgOptions::with_cancellation_token) was cancelled, or it
+	/// [timed out](StableDiffusionTxt2ImgOptions::with_timeout)) instead of only returning them, e.g. to show the
+	/// partially denoised images. By default, early stops skip the VAE decoder; the partial latents are always returned
+	/// in [`StableDiffusionOutput::latents`].
+	pub fn with_decode_on_early_stop(mut self, decode_on_early_stop: bool) -> Self {
+		self.decode_on_early_stop = decode_on_early_stop;
+		self
+	}
+
+	/// Checks the batch subset against a full batch of `batch_size` elements, returning which elements are generated,
+	/// or an empty mask if the full batch is generated.
+	pub(crate) fn batch_subset_mask(&self, batch_size: usize, resume: bool) -> anyhow::Result<Vec<bool>> {
+		let subset = match self.batch_subset.as_deref() {
+			Some(subset) => subset,
+			None => return Ok(Vec::new()),
+		};
+		if self.latents.is_some() || self.inpaint.is_some() || self.freeze_mask.is_some() || self.multidiffusion.is_some() {
+			anyhow::bail!("a batch subset cannot be combined with initial latents, inpainting, freeze masks, or MultiDiffusion");
 		}
+		if resume || self.checkpoint_at.is_some() || self.denoising_end.is_some() {
+			anyhow::bail!("a batch subset cannot be combined with checkpoints, `denoising_end`, or resuming");
+		}
+		if subset.is_empty() {
+			anyhow::bail!("the batch subset is empty; at least one batch element must be generated");
+		}
+		let mut generated = vec![false; batch_size];
+		for &index in subset {
+			match generated.get_mut(index) {
+				Some(true) => anyhow::bail!("batch element {index} is given more than once in the batch subset"),
+				Some(generated) => *generated = true,
+				None => anyhow::bail!("the batch subset includes element {index}, but the batch only has {batch_size} elements"),
+			}
+		}
+		Ok(generated)
+	}
 
-		let prompt = self.positive_prompt.clone();
-		let batch_size = prompt.len();
+	/// Use a random seed, so that each run generates a different image.
+	pub fn with_random_seed(mut self) -> Self {
+		self.seed = None;
+		self
+	}
 
-		let do_classifier_free_guidance = self.guidance_scale > 1.0;
-		let text_embeddings = session.encode_prompt(prompt, do_classifier_free_guidance, self.negative_prompt.as_ref())?;
+	/// The 'guidance scale' for classifier-free guidance. A lower guidance scale gives the model more freedom, but the
+	/// output may not match the prompt. A higher guidance scale mean the model will match the prompt(s) more strictly,
+	/// but may introduce artifacts; `7.5` is a good balance.
+	///
+	/// Guidance-distilled UNets (with a `guidance` or `w_embedding` input) instead receive the guidance scale as a
+	/// sinusoidal embedding and always run without classifier-free guidance; negative prompts are ignored.
+	pub fn with_guidance_scale(mut self, guidance_scale: f32) -> Self {
+		self.guidance_scale = guidance_scale;
+		self
+	}
 
-		let latents_shape = (batch_size, 4_usize, (self.height / 8) as usize, (self.width / 8) as usize);
-		let mut latents = Array4::<f32>::random_using(latents_shape, StandardNormal, &mut rng);
+	/// ETA noise seed delta (ENSD). The scheduler will be given an RNG seeded with `seed + ensd`.
+	pub fn with_eta_noise_seed_delta(mut self, ensd: u64) -> Self {
+		self.ensd = ensd;
+		self
+	}
 
-		scheduler.set_timesteps(steps);
-		latents *= scheduler.init_noise_sigma();
+	/// Pins random number generation to the behavior of a specific release, so that saved seeds keep generating the
+	/// same images after upgrading. See [`CompatibilityVersion`] for the supported versions.
+	pub fn with_compatibility_version(mut self, version: CompatibilityVersion) -> Self {
+		self.compatibility_version = version;
+		self
+	}
 
-		let mut scheduler_rng = StdRng::seed_from_u64(seed + 31337);
+	/// Set a latent-resolution mask of regions to freeze; see [`StableDiffusionTxt2ImgOptions::freeze_mask`]. The mask
+	/// must have the shape `(1 or batch_size, height / 8, width / 8)`, where `1.0` = freeze and `0.0` = denoise.
+	pub fn with_freeze_mask(mut self, mask: Array3<f32>) -> Self {
+		self.freeze_mask = Some(mask);
+		self
+	}
 
-		let timesteps = scheduler.timesteps().to_owned();
-		let num_warmup_steps = timesteps.len() - self.steps * S::order();
+	/// Inpaints the masked region of an image instead of generating a whole new image; see [`InpaintOptions`]. The
+	/// image size must match the size of the image latents.
+	pub fn with_inpaint(mut self, inpaint: InpaintOptions) -> Self {
+		self.inpaint = Some(inpaint);
+		self
+	}
 
-		for (i, t) in timesteps.indexed_iter() {
-			let latent_model_input = if do_classifier_free_guidance {
-				concatenate![Axis(0), latents, latents]
-			} else {
-				latents.clone()
-			};
-			let latent_model_input = scheduler.scale_model_input(latent_model_input.view(), *t);
-			let latent_model_input: CowArray<f32, IxDyn> = CowArray::from(latent_model_input.into_dyn());
-			let timestep: CowArray<f32, IxDyn> = CowArray::from(Array1::from_iter([t.to_f32().unwrap()]).into_dyn());
-			let encoder_hidden_states: CowArray<f32, IxDyn> = CowArray::from(text_embeddings.clone().into_dyn());
-
-			let noise_pred = session.unet.run(ort::inputs![&latent_model_input, &timestep, &encoder_hidden_states]?)?;
-			let noise_pred: OrtOwnedTensor<f32> = noise_pred[0].extract_tensor()?;
-			let noise_pred: Array4<f32> = noise_pred.view().to_owned().into_dimensionality()?;
-
-			let mut noise_pred: Array4<f32> = noise_pred.clone();
-			if do_classifier_free_guidance {
-				assert!(noise_pred.shape()[0] % 2 == 0);
-				let split_len = (noise_pred.shape()[0] / 2) as isize;
-				let noise_pred_uncond = noise_pred.slice(s![..split_len, .., .., ..]);
-				let noise_pred_text = noise_pred.slice(s![split_len.., .., .., ..]);
-				if let Some(multiplier) = self.rescale_cfg {
-					let x_cfg = &noise_pred_uncond + self.guidance_scale * (&noise_pred_text - &noise_pred_uncond);
-					let (ro_pos, ro_cfg) = (noise_pred_text.std(0.), x_cfg.std(0.));
-					let x_rescaled = &x_cfg * (ro_pos / ro_cfg);
-					noise_pred = multiplier * &x_rescaled + (1.0 - multiplier) * &x_cfg;
-				} else {
-					noise_pred = &noise_pred_uncond + self.guidance_scale * (&noise_pred_text - &noise_pred_uncond);
-				}
-			}
+	/// Enables [MultiDiffusion](https://arxiv.org/abs/2302.08113) tiled generation for images larger than the model's
+	/// native resolution, optionally with per-region prompts. See [`MultiDiffusionOptions`].
+	pub fn with_multidiffusion(mut self, multidiffusion: MultiDiffusionOptions) -> Self {
+		self.multidiffusion = Some(multidiffusion);
+		self
+	}
 
-			let scheduler_output = scheduler.step(noise_pred.view(), *t, latents.view(), &mut scheduler_rng);
-			latents = scheduler_output.prev_sample;
-
-			if let Some(callback) = self.callback.as_ref() {
-				if i == timesteps.len() - 1 || ((i + 1) > num_warmup_steps && (i + 1) % S::order() == 0) {
-					let keep_going = match callback {
-						StableDiffusionCallback::Progress { frequency, cb } if i % frequency == 0 => cb(i, t.to_f32().unwrap()),
-						StableDiffusionCallback::Latents { frequency, cb } if i % frequency == 0 => cb(i, t.to_f32().unwrap(), latents.clone()),
-						StableDiffusionCallback::Decoded { frequency, cb } if i != 0 && i % frequency == 0 => {
-							cb(i, t.to_f32().unwrap(), session.decode_latents(latents.view())?)
-						}
-						StableDiffusionCallback::ApproximateDecoded { frequency, cb } if i != 0 && i % frequency == 0 => {
-							cb(i, t.to_f32().unwrap(), session.approximate_decode_latents(latents.view())?)
-						}
-						_ => true,
-					};
-					if !keep_going {
-						break;
-					}
-				}
+	/// Sets the order in which random numbers are drawn; see [`RngDrawOrder`]. Use [`RngDrawOrder::Sequential`] to
+	/// match the ordering of diffusers-python.
+	pub fn with_rng_draw_order(mut self, rng_draw_order: RngDrawOrder) -> Self {
+		self.rng_draw_order = rng_draw_order;
+		self
+	}
+
+	/// Skips multiplying the initial latents by the scheduler's
+	/// [`init_noise_sigma`](DiffusionScheduler::init_noise_sigma).
+	///
+	/// Schedulers expect the initial latents to be pure noise scaled to the scheduler's starting noise level; for
+	/// sigma-based schedulers like Euler, `init_noise_sigma` is much larger than 1 (~14.6 for Stable Diffusion), while
+	/// it is 1 for DDIM/DDPM. Only enable this if the scaling is inappropriate for your scheduler, e.g. a custom
+	/// [`DiffusionScheduler`] whose `init_noise_sigma` doesn't describe the noise level its first step expects, or if
+	/// the latents are already scaled by other means, e.g. if a freeze mask or callback expects unscaled latents.
+	/// Skipping the scaling with a standard sigma-based scheduler will produce washed out, noisy images.
+	///
+	/// This is disabled by default.
+	pub fn with_skip_init_noise_scaling(mut self, skip_init_noise_scaling: bool) -> Self {
+		self.skip_init_noise_scaling = skip_init_noise_scaling;
+		self
+	}
+
+	/// Generates from the given initial latents, e.g. prepared with [`StableDiffusionTxt2ImgOptions::prepare_latents`]
+	/// and then modified, instead of drawing them from the seed. The latents must have shape
+	/// `(batch_size, 4, height / 8, width / 8)`, where `batch_size` is the number of prompts times
+	/// [`num_images_per_prompt`](Self::num_images_per_prompt), and are used as-is: they must already be scaled by the
+	/// scheduler's [`init_noise_sigma`](DiffusionScheduler::init_noise_sigma), or be unscaled with
+	/// [`skip_init_noise_scaling`](Self::skip_init_noise_scaling). Inpainting & [latent
+	/// normalization](Self::with_latents_normalized_to) rely on this to recover the unscaled noise.
+	///
+	/// The seed is still used for everything else, e.g. the noise of ancestral schedulers.
+	pub fn with_latents(mut self, latents: Array4<f32>) -> Self {
+		self.latents = Some(latents);
+		self
+	}
+
+	/// Normalizes the initial latents given with [`StableDiffusionTxt2ImgOptions::with_latents`] to the given
+	/// per-channel statistics before generating, e.g. statistics measured with [`LatentStats::from_latents`] from this
+	/// model's encoded images when generating from latents inverted with another model. The statistics describe
+	/// VAE-scaled latents and are multiplied by the scheduler's
+	/// [`init_noise_sigma`](DiffusionScheduler::init_noise_sigma), unless
+	/// [`skip_init_noise_scaling`](Self::skip_init_noise_scaling) is set. See
+	/// [`normalize_latents`](crate::normalize_latents) for the limits of this technique.
+	///
+	/// Setting this without initial latents is an error.
+	pub fn with_latents_normalized_to(mut self, stats: LatentStats) -> Self {
+		self.latents_target_stats = Some(stats);
+		self
+	}
+
+	/// Enables [Attend-and-Excite](https://arxiv.org/abs/2301.13826), which updates the latents during the first few
+	/// steps so that every given subject token appears in the image. This requires a UNet exported with cross-attention
+	/// maps; see [`AttendAndExciteOptions`].
+	pub fn with_attend_and_excite(mut self, attend_and_excite: AttendAndExciteOptions) -> Self {
+		self.attend_and_excite = Some(attend_and_excite);
+		self
+	}
+
+	/// Captures a [`DiffusionCheckpoint`] after `step` steps (between 1 and the number of steps), which is returned in
+	/// [`StableDiffusionOutput::checkpoint`] by [`StableDiffusionTxt2ImgOptions::run_with_output`]. The run can later
+	/// be continued from the checkpoint with [`StableDiffusionTxt2ImgOptions::resume_from`].
+	pub fn with_checkpoint_at(mut self, step: usize) -> Self {
+		self.checkpoint_at = Some(step);
+		self
+	}
+
+	/// Stops the run after `denoising_end` (between 0 & 1) of the steps, i.e. after `round(denoising_end * steps)`
+	/// steps, for handing the partially denoised latents off to a refiner. Instead of images, the
+	/// [`StableDiffusionOutput`] returned by [`StableDiffusionTxt2ImgOptions::run_with_output`] then holds a
+	/// [`checkpoint`](StableDiffusionOutput::checkpoint) of the latents at the handoff, which can be finished with
+	/// [`StableDiffusionPipeline::refine_checkpoint`]. See [`StableDiffusionPipeline::txt2img_with_refiner`].
+	///
+	/// This cannot be combined with [`StableDiffusionTxt2ImgOptions::with_checkpoint_at`].
+	pub fn with_denoising_end(mut self, denoising_end: f32) -> Self {
+		self.denoising_end = Some(denoising_end);
+		self
+	}
+
+	/// Enables [Restart sampling](https://arxiv.org/abs/2306.14878), which improves quality by repeatedly adding noise
+	/// back to the latents & denoising an interval of the schedule again.
+	///
+	/// The schedule is a list of `(t_min, t_max, num_restarts)` intervals, in training timesteps (e.g. `0..1000` for
+	/// Stable Diffusion, where higher timesteps are noisier). Once denoising reaches `t_min`, fresh noise is added to
+	/// the latents to bring them back to the noise level of `t_max` (`x + sqrt(σ_max² - σ_min²)·ε`, as in the paper),
+	/// and the steps between `t_max` & `t_min` are taken again; this is repeated `num_restarts` times before denoising
+	/// continues. Restart noise is drawn from its own RNG seeded from the seed, so it doesn't change the noise drawn by
+	/// the scheduler.
+	///
+	/// Intervals are snapped to the scheduler's timesteps, so the number of steps determines where restarts happen &
+	/// how much they cost: an interval covering `k` of the `steps` timesteps adds `k * num_restarts` UNet evaluations
+	/// to the run. An interval covering none of the timesteps (e.g. a narrow interval with few steps) is an error.
+	/// Restarted steps are reported to callbacks & [`StepStats`] with the step number they re-run.
+	///
+	/// Restarts cannot be combined with checkpoints ([`StableDiffusionTxt2ImgOptions::with_checkpoint_at`] &
+	/// [`StableDiffusionTxt2ImgOptions::with_denoising_end`]). No restarts are taken by default.
+	///
+	/// ```no_run
+	/// # use pyke_diffusers::StableDiffusionTxt2ImgOptions;
+	/// // once denoising reaches timestep 100, jump back up to timestep 300 & denoise twice more
+	/// let options = StableDiffusionTxt2ImgOptions::default().with_steps(30).with_restart_schedule([(100.0, 300.0, 2)]);
+	/// ```
+	pub fn with_restart_schedule<I, R>(mut self, schedule: I) -> Self
+	where
+		I: IntoIterator<Item = R>,
+		R: Into<RestartInterval>,
+	{
+		self.restart_schedule = schedule.into_iter().map(Into::into).collect();
+		self
+	}
+
+	/// Computes the classifier-free guidance combination `uncond + guidance_scale * (text - uncond)` (and
+	/// [CFG rescaling](StableDiffusionTxt2ImgOptions::rescale_cfg), if enabled) in `f64`, converting the result back to
+	/// `f32` afterwards.
+	///
+	/// The difference between the text & unconditional noise predictions is small relative to the predictions
+	/// themselves, so multiplying it by a large guidance scale amplifies rounding error. This mainly matters for guidance
+	/// scales above ~20; at typical guidance scales, the results are practically identical to `f32`.
+	///
+	/// This is disabled by default, since it costs 2 extra conversions of the noise predictions per step.
+	pub fn with_f64_guidance(mut self, f64_guidance: bool) -> Self {
+		self.f64_guidance = f64_guidance;
+		self
+	}
+
+	/// Sets the file format images are written in by [`StableDiffusionPipeline::txt2img_to_files`]. See
+	/// [`ImageFileFormat`].
+	pub fn with_file_format(mut self, file_format: ImageFileFormat) -> Self {
+		self.file_format = file_format;
+		self
+	}
+
+	/// Makes [`StableDiffusionPipeline::txt2img_to_files`] record the parameters needed to reproduce each image as a
+	/// [`ReproRecord`](crate::ReproRecord), embedded in the image and/or in a JSON sidecar file next to it. Records are
+	/// read back with [`ReproRecord::load_with_image`](crate::ReproRecord::load_with_image). See [`MetadataMode`].
+	pub fn with_metadata_mode(mut self, metadata_mode: MetadataMode) -> Self {
+		self.metadata_mode = metadata_mode;
+		self
+	}
+
+	/// Enables a low-memory decode mode, where each image is written to `dir` as a PNG file as soon as it is decoded,
+	/// so that at most one decoded image is held in memory at once. This is useful on machines with very little RAM,
+	/// where even a few decoded float32 images at high resolutions exceed the memory budget.
+	///
+	/// [`StableDiffusionTxt2ImgOptions::run_with_output`] then returns [`ImageRef::OnDisk`] handles instead of
+	/// in-memory images; [`StableDiffusionTxt2ImgOptions::run`] loads the images back into memory, so use
+	/// `run_with_output` to benefit from this mode. Images are written with 8 bits per channel & named
+	/// `image-{seed}-{index}.png`; existing files are overwritten. `dir` is created if it doesn't exist. Latents are
+	/// decoded one by one, so [`dedupe_decode`](crate::StableDiffusionOptions::dedupe_decode) has no effect.
+	pub fn with_decode_to_disk(mut self, dir: impl Into<PathBuf>) -> Self {
+		self.decode_to_disk = Some(dir.into());
+		self
+	}
+
+	/// Enables reference-only cross-frame attention: the hidden states entering each of the UNet's self-attention
+	/// layers when denoising `reference_image` are injected into the self-attention layers of the generation, so that
+	/// generated images share the reference's appearance (e.g. a character's identity or a scene's style). Generating
+	/// each frame of an animation with the same reference image keeps frames consistent.
+	///
+	/// The reference is resized to the generated image's size and encoded with the VAE encoder. At each step, it is
+	/// noised to the current timestep with noise drawn from an RNG seeded with the generation seed (so frames generated
+	/// with the same seed see identical reference hidden states) and evaluated by the UNet to capture its hidden states,
+	/// which are then fed into the UNet evaluation of the latents. This doubles the cost of each step. Reference
+	/// attention cannot be combined with MultiDiffusion.
+	///
+	/// # Export requirements
+	/// Reference attention requires a UNet exported with 2 additional inputs & 1 additional output:
+	/// - a 4th input named `reference_hidden_states` of shape `(batch_size, n)`: the flattened & concatenated hidden
+	///   states entering each self-attention layer of the reference image. Each self-attention layer appends the
+	///   reference's hidden states of that layer to its keys & values. If the input is empty (`n = 0`), the UNet must
+	///   behave exactly like a standard UNet;
+	/// - a 5th input named `reference_weight` of shape `(1,)`, where each self-attention layer's output becomes
+	///   `weight * attn(x, [x, reference]) + (1 - weight) * attn(x, x)`;
+	/// - an output named `out_reference_hidden_states` of shape `(batch_size, n)`, containing the flattened &
+	///   concatenated hidden states entering each self-attention layer of this evaluation, in the same layout as the
+	///   input.
+	///
+	/// Pipelines without these inputs & outputs, or without a VAE encoder, fail with an error.
+	pub fn with_reference_image(mut self, reference_image: DynamicImage) -> Self {
+		self.reference_image = Some(reference_image);
+		self
+	}
+
+	/// Sets the weight of the [reference image](StableDiffusionTxt2ImgOptions::with_reference_image), from `0.0` (no
+	/// effect) to `1.0` (full reference attention). Defaults to `1.0`.
+	pub fn with_reference_weight(mut self, reference_weight: f32) -> Self {
+		self.reference_weight = reference_weight;
+		self
+	}
+
+	/// Sets the filter the [reference image](StableDiffusionTxt2ImgOptions::with_reference_image) is resized to the
+	/// generated image's size with, which is also the filter [`StableDiffusionPipeline::super_resolve`] upscales its
+	/// image with. Defaults to [`ResizeFilter::Lanczos3`], which suits photos.
+	pub fn with_resize_filter(mut self, resize_filter: ResizeFilter) -> Self {
+		self.resize_filter = resize_filter;
+		self
+	}
+
+	/// Sets the filter mask images are resized to the latent resolution with by [`StableDiffusionPipeline::inpaint`] &
+	/// [`StableDiffusionPipeline::inpaint_with_mask_channel`]; see
+	/// [`prepare_inpaint_mask_with_filter`](crate::prepare_inpaint_mask_with_filter). Defaults to
+	/// [`ResizeFilter::Nearest`], which never smears the mask's edges into the region to keep; smooth filters like
+	/// [`ResizeFilter::Triangle`] feather them.
+	pub fn with_mask_resize_filter(mut self, mask_resize_filter: ResizeFilter) -> Self {
+		self.mask_resize_filter = mask_resize_filter;
+		self
+	}
+
+	/// Adds a ControlNet guiding the generation. Multiple ControlNets, e.g. for pose & depth, can be added to guide the
+	/// generation together: on each step, every ControlNet active on that step is run, and the sum of their residuals,
+	/// each multiplied by its [weight](ControlNetConfig::weight), is fed to the UNet. ControlNets are active for the
+	/// steps within their [step range](ControlNetConfig::with_step_range); on steps where none is active, the UNet is
+	/// fed zero residuals.
+	///
+	/// See [`ControlNet`](crate::ControlNet) for the inputs & outputs the ControlNet & UNet models must have. ControlNet
+	/// can't be combined with MultiDiffusion, reference attention, Attend-and-Excite, or guidance-distilled UNets.
+	pub fn with_controlnet(mut self, controlnet: ControlNetConfig) -> Self {
+		self.controlnets.push(controlnet);
+		self
+	}
+
+	/// Enables or disables recording per-step [`StepStats`]. Enabled by default.
+	pub fn with_step_stats(mut self, collect_step_stats: bool) -> Self {
+		self.collect_step_stats = collect_step_stats;
+		self
+	}
+
+	/// Enables or disables recording the scheduler's trajectory into [`StableDiffusionOutput::trajectory`]: the
+	/// timestep, sigma, latent norm & noise prediction norm of every step taken, as a [`StepRecord`]. Unlike
+	/// [`StepStats`], which summarize the latents for debugging, the trajectory follows the scheduler's noise schedule
+	/// so that runs with different schedulers can be compared. Disabled by default.
+	pub fn with_trajectory(mut self, collect_trajectory: bool) -> Self {
+		self.collect_trajectory = collect_trajectory;
+		self
+	}
+
+	/// Enables or disables recording a [`ClampReport`](crate::ClampReport) for each image, counting the pixels the
+	/// decoder's output had to be clamped to 0 or 1 for, to flag crushed or blown-out generations without re-scanning
+	/// the images. The counts are taken before any [`ColorTransfer`](crate::ColorTransfer) is applied. Disabled by
+	/// default.
+	pub fn with_clamp_reports(mut self, collect_clamp_reports: bool) -> Self {
+		self.collect_clamp_reports = collect_clamp_reports;
+		self
+	}
+
+	/// Calls `preview` once with [approximately decoded](StableDiffusionPipeline::approximate_decode_latents) images
+	/// after `step` steps, then continues generating to completion; the final images are returned by
+	/// [`StableDiffusionTxt2ImgOptions::run`] as usual. This gives UIs a fast preview early in the generation without the
+	/// cost of decoding with the VAE, and works alongside any [`StableDiffusionCallback`]. If the generation has fewer
+	/// than `step` steps (or is cancelled by a callback before then), `preview` is never called.
+	///
+	/// # Threading
+	/// `preview` is called synchronously on the thread running the pipeline, and generation resumes once it returns, so
+	/// it should only hand the images off (e.g. over a channel) rather than doing slow work itself. To keep the UI
+	/// responsive while generation continues, run the pipeline on a worker thread:
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use std::{sync::{mpsc, Arc}, thread};
+	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// let pipeline = Arc::new(StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?);
+	/// let (preview_tx, preview_rx) = mpsc::channel();
+	/// let worker = thread::spawn(move || {
+	/// 	let mut scheduler = EulerDiscreteScheduler::default();
+	/// 	StableDiffusionTxt2ImgOptions::default()
+	/// 		.with_prompt("photo of a red fox")
+	/// 		.with_preview(5, move |images| {
+	/// 			let _ = preview_tx.send(images);
+	/// 		})
+	/// 		.run(&pipeline, &mut scheduler)
+	/// });
+	/// if let Ok(preview) = preview_rx.recv() {
+	/// 	// show the preview...
+	/// }
+	/// let images = worker.join().unwrap()?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn with_preview<F>(mut self, step: usize, preview: F) -> Self
+	where
+		F: Fn(Vec<DynamicImage>) + 'static,
+	{
+		self.preview = Some(StableDiffusionPreview { step, cb: Box::new(preview) });
+		self
+	}
+
+	#[doc = include_str!("_doc/callback-progress.md")]
+	pub fn callback_progress<F>(mut self, frequency: usize, callback: F) -> Self
+	where
+		F: Fn(usize, f32) -> bool + 'static,
+	{
+		self.callback = Some(StableDiffusionCallback::Progress { frequency, cb: Box::new(callback) });
+		self
+	}
+	#[doc = include_str!("_doc/callback-latents.md")]
+	pub fn callback_latents<F>(mut self, frequency: usize, callback: F) -> Self
+	where
+		F: Fn(usize, f32, Array4<f32>) -> bool + 'static,
+	{
+		self.callback = Some(StableDiffusionCallback::Latents { frequency, cb: Box::new(callback) });
+		self
+	}
+	#[doc = include_str!("_doc/callback-latents-f16.md")]
+	pub fn callback_latents_f16<F>(mut self, frequency: usize, callback: F) -> Self
+	where
+		F: Fn(usize, f32, HalfLatents) -> bool + 'static,
+	{
+		self.callback = Some(StableDiffusionCallback::LatentsF16 { frequency, cb: Box::new(callback) });
+		self
+	}
+	#[doc = include_str!("_doc/callback-decode-image.md")]
+	pub fn callback_decoded<F>(mut self, frequency: usize, callback: F) -> Self
+	where
+		F: Fn(usize, f32, Vec<DynamicImage>) -> bool + 'static,
+	{
+		self.callback = Some(StableDiffusionCallback::Decoded { frequency, cb: Box::new(callback) });
+		self
+	}
+	#[doc = include_str!("_doc/callback-region-decoded.md")]
+	pub fn callback_region_decoded<F>(mut self, frequency: usize, region: ImageRegion, callback: F) -> Self
+	where
+		F: Fn(usize, f32, Vec<DynamicImage>) -> bool + 'static,
+	{
+		self.callback = Some(StableDiffusionCallback::RegionDecoded { region, frequency, cb: Box::new(callback) });
+		self
+	}
+	/// Sets a progress callback which is also given the [`GenerationStage`] each step belongs to; see
+	/// [`StableDiffusionCallback::Staged`].
+	pub fn callback_staged<F>(mut self, frequency: usize, callback: F) -> Self
+	where
+		F: Fn(GenerationStage, usize, f32) -> bool + 'static,
+	{
+		self.callback = Some(StableDiffusionCallback::Staged { frequency, cb: Box::new(callback) });
+		self
+	}
+	#[doc = include_str!("_doc/callback-approximate-image.md")]
+	pub fn callback_approximate<F>(mut self, frequency: usize, callback: F) -> Self
+	where
+		F: Fn(usize, f32, Vec<DynamicImage>) -> bool + 'static,
+	{
+		self.callback = Some(StableDiffusionCallback::ApproximateDecoded { frequency, cb: Box::new(callback) });
+		self
+	}
+}
+
+impl StableDiffusionTxt2ImgOptions {
+	/// Generates images from given text prompt(s). Returns a vector of [`image::DynamicImage`]s, using float32 buffers.
+	/// In most cases, you'll want to convert the images into RGB8 via `img.into_rgb8().`
+	///
+	/// `scheduler` must be a Stable Diffusion-compatible scheduler.
+	///
+	/// See [`StableDiffusionTxt2ImgOptions`] for additional configuration.
+	///
+	/// # Examples
+	///
+	/// Simple text-to-image:
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// # let mut scheduler = EulerDiscreteScheduler::default();
+	/// let pipeline =
+	/// 	StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+	///
+	/// let mut imgs = StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox").run(&pipeline, &mut scheduler)?;
+	/// imgs.remove(0).into_rgb8().save("result.png")?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn run<S: DiffusionScheduler>(&self, session: &StableDiffusionPipeline, scheduler: &mut S) -> anyhow::Result<Vec<DynamicImage>> {
+		self.run_with_output(session, scheduler)?.images.into_iter().map(ImageRef::into_image).collect()
+	}
+
+	/// Generates images from given text prompt(s) like [`StableDiffusionTxt2ImgOptions::run`], but returns a
+	/// [`StableDiffusionOutput`] which additionally contains diagnostic information like per-step [`StepStats`].
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, StepStats, DEFAULT_STD_JUMP_THRESHOLD, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// # let mut scheduler = EulerDiscreteScheduler::default();
+	/// # let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+	/// let output = StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox").run_with_output(&pipeline, &mut scheduler)?;
+	/// for step in StepStats::detect_anomalies(&output.step_stats, DEFAULT_STD_JUMP_THRESHOLD) {
+	/// 	println!("latents diverged at step {step}");
+	/// }
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn run_with_output<S: DiffusionScheduler>(&self, session: &StableDiffusionPipeline, scheduler: &mut S) -> anyhow::Result<StableDiffusionOutput> {
+		self.run_from(session, scheduler, None, GenerationStage::Full)
+	}
+
+	/// Continues a run from a [`DiffusionCheckpoint`] captured with
+	/// [`StableDiffusionTxt2ImgOptions::with_checkpoint_at`], following the exact same denoising trajectory: resuming
+	/// a checkpoint with the options it was captured with produces the same images as the original run.
+	///
+	/// The number of steps, seed, compatibility version, and RNG draw order are taken from the checkpoint, and the
+	/// options' own values are ignored. All other options apply to the remaining steps, so e.g. the prompt or guidance
+	/// scale can be changed to refine an image from step K onwards. The image size & batch size must match the
+	/// checkpoint's latents, and `scheduler` must be the same kind of scheduler (with the same configuration) as the
+	/// original run.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerAncestralDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, DiffusionCheckpoint, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// # let mut scheduler = EulerAncestralDiscreteScheduler::default();
+	/// # let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+	/// let output = StableDiffusionTxt2ImgOptions::default()
+	/// 	.with_prompt("photo of a red fox")
+	/// 	.with_steps(30)
+	/// 	.with_checkpoint_at(10)
+	/// 	.run_with_output(&pipeline, &mut scheduler)?;
+	/// output.checkpoint.unwrap().save("fox.ckpt")?;
+	///
+	/// // later: continue from step 10 with a different prompt
+	/// let checkpoint = DiffusionCheckpoint::from_file("fox.ckpt")?;
+	/// let output = StableDiffusionTxt2ImgOptions::default()
+	/// 	.with_prompt("photo of a red fox in the snow")
+	/// 	.resume_from(&pipeline, &mut scheduler, &checkpoint)?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn resume_from<S: DiffusionScheduler>(
+		&self,
+		session: &StableDiffusionPipeline,
+		scheduler: &mut S,
+		checkpoint: &DiffusionCheckpoint,
+	) -> anyhow::Result<StableDiffusionOutput> {
+		self.run_from(session, scheduler, Some(checkpoint), GenerationStage::Full)
+	}
+
+	/// Returns the given initial latents, normalized to
+	/// [`latents_target_stats`](StableDiffusionTxt2ImgOptions::latents_target_stats) if set.
+	pub(crate) fn initial_latents(&self, init_noise_sigma: f32) -> anyhow::Result<Option<Array4<f32>>> {
+		match (self.latents.as_ref(), self.latents_target_stats.as_ref()) {
+			(Some(latents), Some(stats)) => Ok(Some(normalize_latents(latents.view(), &stats.scaled(self.init_noise_scale(init_noise_sigma))))),
+			(Some(latents), None) => Ok(Some(latents.clone())),
+			(None, Some(_)) => anyhow::bail!("`latents_target_stats` is set, but no initial latents were given"),
+			(None, None) => Ok(None),
+		}
+	}
+
+	/// Returns the factor the initial latents are scaled by: the scheduler's `init_noise_sigma`, or 1 with
+	/// [`skip_init_noise_scaling`](Self::skip_init_noise_scaling).
+	pub(crate) fn init_noise_scale(&self, init_noise_sigma: f32) -> f32 {
+		if self.skip_init_noise_scaling { 1.0 } else { init_noise_sigma }
+	}
+
+	/// Prepares the initial latents of a batch of `batch_size` images generated with these options, exactly as
+	/// [`StableDiffusionTxt2ImgOptions::run`] draws them: honoring the size &
+	/// [`dimension_policy`](Self::dimension_policy), the [`seed`](Self::seed) (which must be set), the
+	/// [`compatibility_version`](Self::compatibility_version), the [`batch_noise_mode`](Self::batch_noise_mode) and
+	/// [`skip_init_noise_scaling`](Self::skip_init_noise_scaling). `batch_size` is the number of prompts times
+	/// [`num_images_per_prompt`](Self::num_images_per_prompt).
+	///
+	/// The latents can be modified & passed back with [`StableDiffusionTxt2ImgOptions::with_latents`]; unmodified, they
+	/// produce the same images as generating without them. Batch noise modes & [diversity](Self::with_diversity) cannot
+	/// be combined with initial latents, so the batch noise mode is already applied to the returned latents (generate
+	/// from them without it), and diversity is not applied. The scheduler's [`init_noise_sigma`](DiffusionScheduler::init_noise_sigma) is read as it is, so for
+	/// schedulers whose initial sigma depends on the step count, call
+	/// [`set_timesteps`](DiffusionScheduler::set_timesteps) first.
+	pub fn prepare_latents<S: DiffusionScheduler>(&self, batch_size: usize, scheduler: &S) -> anyhow::Result<Array4<f32>> {
+		let seed = self.seed.ok_or_else(|| anyhow::anyhow!("preparing latents requires a seed; see `with_seed`"))?;
+		let latents_shape = latents_shape(batch_size, self.height, self.width);
+		let (mut latents, _) = self.draw_padded_latents(self.compatibility_version, self.rng_draw_order, seed, latents_shape);
+		self.validate_batch_noise_mode()?;
+		self.batch_noise_mode.apply(self.compatibility_version, self.rng_draw_order, &mut latents);
+		Ok(latents * self.init_noise_scale(scheduler.init_noise_sigma()))
+	}
+
+	/// Returns the number of [`steps`](StableDiffusionTxt2ImgOptions::steps) to take with `scheduler` & sets its
+	/// timesteps for them, applying the [`StepLimitPolicy`] if more steps are requested than it supports.
+	pub(crate) fn supported_steps<S: DiffusionScheduler>(&self, scheduler: &mut S) -> anyhow::Result<usize> {
+		let mut steps = match scheduler.max_inference_steps() {
+			Some(max_steps) if self.steps > max_steps => self.limit_steps(max_steps)?,
+			_ => self.steps,
+		};
+		// fewer steps may compute even fewer timesteps, so this repeats until the timesteps cover the steps; the step
+		// count strictly decreases, so it ends at 0 at the latest
+		loop {
+			scheduler.set_timesteps(steps);
+			let computed = scheduler.timesteps().len() / S::order();
+			if computed >= steps {
+				return Ok(steps);
+			}
+			steps = self.limit_steps(computed)?;
+		}
+	}
+
+	/// Applies the [`StepLimitPolicy`] to the requested [`steps`](StableDiffusionTxt2ImgOptions::steps) if the
+	/// scheduler supports at most `max_steps`.
+	fn limit_steps(&self, max_steps: usize) -> anyhow::Result<usize> {
+		match self.step_limit_policy {
+			StepLimitPolicy::Clamp => {
+				tracing::warn!("{} steps were requested, but the scheduler supports at most {max_steps}; taking {max_steps} steps", self.steps);
+				Ok(max_steps)
+			}
+			StepLimitPolicy::Error => anyhow::bail!("{} steps were requested, but the scheduler supports at most {max_steps}", self.steps),
+		}
+	}
+
+	/// Returns whether the [`DimensionPolicy`] still applies, i.e. `width` & `height` are still the generated size of
+	/// the requested size.
+	fn dimension_policy_applies(&self) -> bool {
+		let (width, height) = self.requested_size;
+		self.width == self.dimension_policy.generated_size(width) && self.height == self.dimension_policy.generated_size(height)
+	}
+
+	/// Returns the `(width, height)` of the images delivered to the caller, which differs from the generated size only
+	/// with [`DimensionPolicy::PadAndCrop`].
+	pub(crate) fn output_size(&self) -> (u32, u32) {
+		if self.dimension_policy_applies() { self.requested_size } else { (self.width, self.height) }
+	}
+
+	/// Returns the region of the generated images which is delivered, if they must be cropped.
+	fn output_crop(&self) -> Option<ImageRegion> {
+		let (width, height) = self.output_size();
+		if (width, height) == (self.width, self.height) {
+			return None;
+		}
+		let (requested_width, requested_height) = self.requested_size;
+		Some(ImageRegion {
+			x: self.dimension_policy.crop_offset(requested_width),
+			y: self.dimension_policy.crop_offset(requested_height),
+			width,
+			height,
+		})
+	}
+
+	/// Returns the number of text tokens of each image's prompt, excluding BOS, EOS & padding tokens, which
+	/// Attend-and-Excite masks out of the attention maps. `prompt_batch_size` is the batch size before
+	/// `num_images_per_prompt` is applied.
+	fn prompt_text_lengths(&self, session: &StableDiffusionPipeline, prompt_batch_size: usize) -> anyhow::Result<Vec<usize>> {
+		let lengths = match (self.prompt_embeddings.as_ref(), self.prompt_token_ids.as_ref()) {
+			(Some(_), _) => anyhow::bail!("Attend-and-Excite must locate the prompt's EOS & padding tokens, so it needs a text prompt or `prompt_token_ids`"),
+			(None, Some(token_ids)) => {
+				let eos = i64::from(session.text_embeddings.tokenizer.eos());
+				token_ids
+					.outer_iter()
+					.map(|ids| ids.iter().skip(1).position(|&id| id == eos).unwrap_or(ids.len().saturating_sub(1)))
+					.collect()
+			}
+			(None, None) => session.prompt_text_lengths(self.positive_prompt.clone(), &self.prompt_weighting)?,
+		};
+		// prompts may have been broadcast against a larger batch of negative prompts
+		let lengths = if lengths.len() == 1 { vec![lengths[0]; prompt_batch_size] } else { lengths };
+		Ok(lengths.into_iter().flat_map(|length| std::iter::repeat(length).take(self.num_images_per_prompt)).collect())
+	}
+
+	/// Draws the initial latents like [`draw_initial_latents`]. With [`DimensionPolicy::PadAndCrop`], the noise of the
+	/// rounded-down size is drawn exactly as a [`DimensionPolicy::RoundDown`] generation would, and surrounded by
+	/// padding noise drawn from a separate RNG, so the delivered crop starts from the same noise.
+	fn draw_padded_latents(
+		&self,
+		compatibility_version: CompatibilityVersion,
+		rng_draw_order: RngDrawOrder,
+		seed: u64,
+		latents_shape: (usize, usize, usize, usize),
+	) -> (Array4<f32>, StdRng) {
+		let (requested_width, requested_height) = self.requested_size;
+		let (pad_x, pad_y) = if self.dimension_policy_applies() {
+			(self.dimension_policy.padding(requested_width) as usize, self.dimension_policy.padding(requested_height) as usize)
+		} else {
+			(0, 0)
+		};
+		if pad_x == 0 && pad_y == 0 {
+			return draw_initial_latents(compatibility_version, rng_draw_order, seed, latents_shape);
+		}
+
+		let (batch_size, channels, height, width) = latents_shape;
+		let inner_shape = (batch_size, channels, height - 2 * pad_y, width - 2 * pad_x);
+		let (inner, scheduler_rng) = draw_initial_latents(compatibility_version, rng_draw_order, seed, inner_shape);
+		let mut latents = Array4::<f32>::random_using(latents_shape, StandardNormal, &mut StdRng::seed_from_u64(seed.wrapping_add(PADDING_SEED_OFFSET)));
+		latents.slice_mut(s![.., .., pad_y..height - pad_y, pad_x..width - pad_x]).assign(&inner);
+		(latents, scheduler_rng)
+	}
+
+	/// Runs the pipeline, optionally resuming from a checkpoint. `stage` is the stage reported to
+	/// [`StableDiffusionCallback::Staged`]; full runs with a `denoising_end` are reported as [`GenerationStage::Base`].
+	pub(crate) fn run_from<S: DiffusionScheduler>(
+		&self,
+		session: &StableDiffusionPipeline,
+		scheduler: &mut S,
+		resume: Option<&DiffusionCheckpoint>,
+		stage: GenerationStage,
+	) -> anyhow::Result<StableDiffusionOutput> {
+		if !self.averaged_seeds.is_empty() && (resume.is_some() || self.checkpoint_at.is_some() || self.denoising_end.is_some()) {
+			anyhow::bail!("seed averaging cannot be combined with checkpoints or `denoising_end`");
+		}
+		let crop = self.checked_output_crop()?;
+
+		let mut denoised = self.denoise_with_nan_retries(session, scheduler, resume, stage)?;
+		if !self.averaged_seeds.is_empty() && !denoised.stop_reason.is_early() {
+			let mut latents = vec![denoised.latents];
+			let mut stopped = None;
+			for &seed in &self.averaged_seeds {
+				let averaged = self.denoise(session, scheduler, None, stage, Some(seed), None)?;
+				if averaged.stop_reason.is_early() {
+					stopped = Some(averaged);
+					break;
+				}
+				latents.push(averaged.latents);
+			}
+			match stopped {
+				// averaging partial latents with finished ones is meaningless, so the stopped run's latents are returned
+				Some(stopped) => {
+					denoised.latents = stopped.latents;
+					denoised.stop_reason = stopped.stop_reason;
+				}
+				None => denoised.latents = average_latents(&latents),
+			}
+		}
+		self.decode_denoised(session, denoised, crop)
+	}
+
+	/// Returns the region of the generated images which is delivered like [`output_crop`](Self::output_crop), failing
+	/// if they must be cropped but are decoded to disk.
+	pub(crate) fn checked_output_crop(&self) -> anyhow::Result<Option<ImageRegion>> {
+		let crop = self.output_crop();
+		if crop.is_some() && self.decode_to_disk.is_some() {
+			anyhow::bail!("`DimensionPolicy::PadAndCrop` cannot be combined with `decode_to_disk`");
+		}
+		Ok(crop)
+	}
+
+	/// Decodes denoised latents into the images of a [`StableDiffusionOutput`], cropped to `crop`; see
+	/// [`StableDiffusionTxt2ImgOptions::run_from`].
+	pub(crate) fn decode_denoised(
+		&self,
+		session: &StableDiffusionPipeline,
+		denoised: Denoised,
+		crop: Option<ImageRegion>,
+	) -> anyhow::Result<StableDiffusionOutput> {
+		let Denoised { latents, seed, step_stats, trajectory, checkpoint, steps_taken, perturbed_regions, generated, stop_reason, handoff } = denoised;
+		// handed off latents are decoded by the refiner instead, and the latents of early stops only if asked to
+		if handoff || (stop_reason.is_early() && !self.decode_on_early_stop) {
+			return Ok(StableDiffusionOutput {
+				images: Vec::new(),
+				step_stats,
+				trajectory,
+				checkpoint,
+				clamp_reports: Vec::new(),
+				steps_taken,
+				nsfw_flags: Vec::new(),
+				perturbed_regions,
+				generated,
+				latents: stop_reason.is_early().then_some(latents),
+				stop_reason,
+				seed,
+			});
+		}
+
+		let (mut images, mut clamp_reports) = match self.decode_to_disk.as_ref() {
+			Some(dir) => {
+				let (paths, clamp_reports) = session.decode_latents_to_disk_with_clamp_reports(latents.view(), dir, &format!("image-{seed}"))?;
+				(paths.into_iter().map(ImageRef::OnDisk).collect(), clamp_reports)
+			}
+			None => {
+				let (images, clamp_reports) = session.decode_latents_with_clamp_reports(latents.view())?;
+				(images.into_iter().map(ImageRef::InMemory).collect(), clamp_reports)
+			}
+		};
+		if !self.collect_clamp_reports {
+			clamp_reports.clear();
+		}
+		if let Some(crop) = crop {
+			for image in images.iter_mut() {
+				if let ImageRef::InMemory(image) = image {
+					*image = image.crop_imm(crop.x, crop.y, crop.width, crop.height);
+				}
+			}
+		}
+		let nsfw_flags = session.apply_nsfw_policy(&mut images)?;
+		Ok(StableDiffusionOutput {
+			images,
+			step_stats,
+			trajectory,
+			checkpoint,
+			clamp_reports,
+			steps_taken,
+			nsfw_flags,
+			perturbed_regions,
+			generated,
+			latents: stop_reason.is_early().then_some(latents),
+			stop_reason,
+			seed,
+		})
+	}
+
+	/// Runs [`StableDiffusionTxt2ImgOptions::denoise`], retrying with a new seed up to
+	/// [`retry_on_nan`](StableDiffusionTxt2ImgOptions::retry_on_nan) times if it fails with [`NonFiniteLatents`].
+	pub(crate) fn denoise_with_nan_retries<S: DiffusionScheduler>(
+		&self,
+		session: &StableDiffusionPipeline,
+		scheduler: &mut S,
+		resume: Option<&DiffusionCheckpoint>,
+		stage: GenerationStage,
+	) -> anyhow::Result<Denoised> {
+		// resumed runs continue the checkpoint's trajectory, so they can't switch to another seed
+		let max_retries = if resume.is_some() { 0 } else { self.retry_on_nan };
+		self.with_nan_retries(max_retries, |seed| self.denoise(session, scheduler, resume, stage, seed, None))
+	}
+
+	/// Denoises `init_latents` (e.g. encoded images) from `start_step` of `steps`, after noising them to the timestep
+	/// of `start_step` with noise drawn exactly like the initial noise of a text-to-image generation with `seed`, as
+	/// image-to-image generation & super-resolution do. The scheduler's timesteps must already be set for `steps`.
+	///
+	/// This runs like a run resumed from a checkpoint at `start_step`, but unlike resumed runs, a run producing NaNs is
+	/// retried with new noise from a new seed, up to [`retry_on_nan`](StableDiffusionTxt2ImgOptions::retry_on_nan)
+	/// times.
+	pub(crate) fn denoise_from_latents<S: DiffusionScheduler>(
+		&self,
+		session: &StableDiffusionPipeline,
+		scheduler: &mut S,
+		init_latents: ArrayView4<'_, f32>,
+		start_step: usize,
+		steps: usize,
+		seed: u64,
+	) -> anyhow::Result<Denoised> {
+		let compatibility_version = self.compatibility_version.resolve();
+		self.with_nan_retries(self.retry_on_nan, |retry_seed| {
+			let seed = retry_seed.unwrap_or(seed);
+			let latents = match scheduler.timesteps().get(start_step) {
+				Some(&timestep) => {
+					let (noise, _) = draw_initial_latents(compatibility_version, self.rng_draw_order, seed, init_latents.dim());
+					scheduler.add_noise(init_latents, noise.view(), timestep)
+				}
+				// no steps are left to take, so the latents are returned as they are
+				None => init_latents.to_owned(),
+			};
+			let checkpoint = DiffusionCheckpoint {
+				step: start_step,
+				steps,
+				seed,
+				compatibility_version,
+				rng_draw_order: self.rng_draw_order,
+				scheduler_rng_words: 0,
+				latents,
+				scheduler_state: SchedulerState::default(),
+			};
+			self.denoise(session, scheduler, Some(&checkpoint), GenerationStage::Full, None, None)
+		})
+	}
+
+	/// Runs `attempt` with no seed override, retrying it with a new seed up to `max_retries` times if it fails with
+	/// [`NonFiniteLatents`]. The retry seed is derived from the seed that failed.
+	fn with_nan_retries(&self, max_retries: usize, mut attempt: impl FnMut(Option<u64>) -> anyhow::Result<Denoised>) -> anyhow::Result<Denoised> {
+		let mut seed = None;
+		for retry in 1.. {
+			let error = match attempt(seed) {
+				Err(error) if retry <= max_retries => error,
+				result => return result,
+			};
+			let failed_seed = match error.downcast_ref::<NonFiniteLatents>() {
+				Some(failure) => failure.seed,
+				None => return Err(error),
+			};
+			let retry_seed = nan_retry_seed(failed_seed);
+			tracing::warn!("seed {failed_seed} produced NaN latents; retrying with seed {retry_seed} ({retry}/{max_retries})");
+			seed = Some(retry_seed);
+		}
+		unreachable!()
+	}
+
+	/// Runs the denoising loop of [`StableDiffusionTxt2ImgOptions::run_from`], with `seed` in place of the options'
+	/// seed if given. If `stop_at` is given, the loop stops after that many steps (counted from the start of the
+	/// schedule) and captures a checkpoint there.
+	pub(crate) fn denoise<S: DiffusionScheduler>(
+		&self,
+		session: &StableDiffusionPipeline,
+		scheduler: &mut S,
+		resume: Option<&DiffusionCheckpoint>,
+		stage: GenerationStage,
+		seed: Option<u64>,
+		stop_at: Option<usize>,
+	) -> anyhow::Result<Denoised> {
+		let mut state = self.start_denoising(session, scheduler, resume, stage, seed, stop_at)?;
+		while !state.finished {
+			self.denoise_step(session, scheduler, &mut state)?;
+		}
+		self.check_finite_latents(&state)?;
+		Ok(state.into_denoised())
+	}
+
+	/// Prepares the denoising loop of [`StableDiffusionTxt2ImgOptions::denoise`]: encodes the prompts, draws the initial
+	/// latents & sets up the scheduler and every model wrapping the UNet, so that the loop can be driven with
+	/// [`StableDiffusionTxt2ImgOptions::denoise_step`].
+	pub(crate) fn start_denoising<'s, S: DiffusionScheduler>(
+		&self,
+		session: &'s StableDiffusionPipeline,
+		scheduler: &mut S,
+		resume: Option<&DiffusionCheckpoint>,
+		stage: GenerationStage,
+		seed: Option<u64>,
+		stop_at: Option<usize>,
+	) -> anyhow::Result<DenoiseLoop<'s, S>> {
+		let (steps, seed, compatibility_version, rng_draw_order) = match resume {
+			Some(checkpoint) => (checkpoint.steps, checkpoint.seed, checkpoint.compatibility_version, checkpoint.rng_draw_order),
+			None => (
+				match self.custom_sigmas.as_deref() {
+					Some(sigmas) => validate_custom_sigmas(sigmas)?.len(),
+					None => self.supported_steps(&mut *scheduler)?,
+				},
+				seed.or(self.seed).unwrap_or_else(|| rand::thread_rng().gen::<u64>()),
+				self.compatibility_version,
+				self.rng_draw_order,
+			),
+		};
+
+		if self.height % 8 != 0 || self.width % 8 != 0 {
+			anyhow::bail!("`width` ({}) and `height` ({}) must be divisible by 8 for Stable Diffusion", self.width, self.height);
+		}
+		// text prompts are checked before they are encoded; the full batch is checked once its size is known
+		let limits = &session.options.limits;
+		let text_batch_size = match (self.prompt_embeddings.as_ref(), self.prompt_token_ids.as_ref()) {
+			(None, None) => self.positive_prompt.len().max(self.negative_prompt.as_ref().map_or(0, |negative_prompt| negative_prompt.len())),
+			_ => 1,
+		};
+		limits.check(self.width, self.height, text_batch_size.max(1) * self.num_images_per_prompt.max(1), steps)?;
+
+		// guidance-distilled models take the guidance scale as an embedding instead of running a doubled CFG batch
+		let guidance_embedding_dim = guidance_embedding_dim(session)?;
+		if guidance_embedding_dim.is_some() {
+			if self.negative_prompt.is_some() || self.negative_prompt_token_ids.is_some() {
+				tracing::warn!("the UNet is guidance-distilled and runs without classifier-free guidance; the negative prompt will be ignored");
+			}
+			if self.reference_image.is_some() || self.attend_and_excite.is_some() {
+				anyhow::bail!("reference attention & Attend-and-Excite are not supported with guidance-distilled UNets");
+			}
+		}
+		let do_classifier_free_guidance = guidance_embedding_dim.is_none() && self.guidance_scale > 1.0;
+		if self.style_prompt.is_some() && (self.prompt_embeddings.is_some() || self.prompt_token_ids.is_some()) {
+			anyhow::bail!("a `style_prompt` can only be used with text prompts, not with `prompt_embeddings` or `prompt_token_ids`");
+		}
+		let text_embeddings = match (self.prompt_embeddings.as_ref(), self.prompt_token_ids.as_ref()) {
+			(Some(embeddings), _) => {
+				session.check_text_embeddings(embeddings)?;
+				if do_classifier_free_guidance && embeddings.shape()[0] % 2 != 0 {
+					anyhow::bail!(
+						"prompt embeddings for classifier-free guidance must hold negative & positive embeddings, but have a batch size of {}",
+						embeddings.shape()[0]
+					);
+				}
+				embeddings.clone()
+			}
+			(None, Some(token_ids)) => {
+				if self.negative_prompt.is_some() {
+					anyhow::bail!("a text `negative_prompt` cannot be used with `prompt_token_ids`; use `negative_prompt_token_ids` instead");
+				}
+				let negative_token_ids = self.negative_prompt_token_ids.as_ref().map(|ids| ids.view());
+				session.encode_token_ids(token_ids.view(), do_classifier_free_guidance, negative_token_ids)?
+			}
+			(None, None) => {
+				if self.negative_prompt_token_ids.is_some() {
+					anyhow::bail!("`negative_prompt_token_ids` can only be used with `prompt_token_ids`");
+				}
+				let prompt = self.positive_prompt.clone();
+				let negative_prompt = self.negative_prompt.as_ref();
+				match self.style_prompt.as_ref() {
+					Some(style) => {
+						let embeddings = session.encode_styled_prompt(prompt, style, do_classifier_free_guidance, negative_prompt, &self.prompt_weighting)?;
+						session.check_text_embeddings(&embeddings)?;
+						embeddings
+					}
+					None => session.encode_prompt_with_weighting(prompt, do_classifier_free_guidance, negative_prompt, &self.prompt_weighting)?,
+				}
+			}
+		};
+		// prompts may have been broadcast against a larger batch of negative prompts
+		let batch_size = if do_classifier_free_guidance { text_embeddings.shape()[0] / 2 } else { text_embeddings.shape()[0] };
+
+		if self.num_images_per_prompt == 0 {
+			anyhow::bail!("`num_images_per_prompt` must be at least 1");
+		}
+		let prompt_batch_size = batch_size;
+		let batch_size = prompt_batch_size * self.num_images_per_prompt;
+		limits.check(self.width, self.height, batch_size, steps)?;
+		// MultiDiffusion runs the UNet on tiles, which are shrunk to canvases smaller than a tile
+		match self.multidiffusion.as_ref() {
+			Some(multidiffusion) => session.check_unet_size(multidiffusion.tile_size.min(self.width), multidiffusion.tile_size.min(self.height))?,
+			None => session.check_unet_size(self.width, self.height)?,
+		}
+		let mut text_embeddings = repeat_text_embeddings(text_embeddings, self.num_images_per_prompt);
+		if let Some(factors) = self.conditioning_dropout.as_deref() {
+			apply_conditioning_dropout(&mut text_embeddings, factors, do_classifier_free_guidance)?;
+		}
+
+		let latents_shape = latents_shape(batch_size, self.height, self.width);
+		// drawn even when initial latents are given, so the scheduler's RNG continues from the same state
+		let (mut drawn_latents, scheduler_rng) = self.draw_padded_latents(compatibility_version, rng_draw_order, seed, latents_shape);
+		self.validate_batch_noise_mode()?;
+		self.batch_noise_mode.apply(compatibility_version, rng_draw_order, &mut drawn_latents);
+		let perturbed_regions = match self.diversity.as_ref() {
+			Some(_) if self.latents.is_some() => anyhow::bail!("diversity cannot be combined with initial latents"),
+			Some(diversity) => {
+				diversity.validate()?;
+				diversity.perturb(&mut drawn_latents)
+			}
+			None => Vec::new(),
+		};
+
+		// the full batch's noise is drawn first, so each element of a subset starts from its own noise
+		let generated = self.batch_subset_mask(batch_size, resume.is_some())?;
+		let (batch_size, latents_shape, text_embeddings, drawn_latents, perturbed_regions) = if generated.is_empty() {
+			(batch_size, latents_shape, text_embeddings, drawn_latents, perturbed_regions)
+		} else {
+			let indices = generated.iter().enumerate().filter(|(_, &generated)| generated).map(|(i, _)| i).collect::<Vec<_>>();
+			let text_indices = if do_classifier_free_guidance {
+				indices.iter().copied().chain(indices.iter().map(|i| i + batch_size)).collect()
+			} else {
+				indices.clone()
+			};
+			let perturbed_regions = if perturbed_regions.is_empty() {
+				perturbed_regions
+			} else {
+				indices.iter().map(|&i| perturbed_regions[i].clone()).collect()
+			};
+			(
+				indices.len(),
+				(indices.len(), latents_shape.1, latents_shape.2, latents_shape.3),
+				text_embeddings.select(Axis(0), &text_indices),
+				drawn_latents.select(Axis(0), &indices),
+				perturbed_regions,
+			)
+		};
+		let guidance_embedding = guidance_embedding_dim.map(|dim| guidance_embedding_input(self.guidance_scale, dim, batch_size));
+
+		match self.custom_sigmas.as_deref() {
+			Some(sigmas) => scheduler.set_sigmas(sigmas)?,
+			None => scheduler.set_timesteps(steps),
+		}
+		if let Some(latents) = self.latents.as_ref() {
+			if latents.dim() != latents_shape {
+				anyhow::bail!("initial latents have shape {:?}, expected {latents_shape:?}", latents.shape());
+			}
+		}
+		let initial_latents = self.initial_latents(scheduler.init_noise_sigma())?;
+
+		let mut dedicated_inpaint = false;
+		let inpaint_noise = if let Some(inpaint) = self.inpaint.as_ref() {
+			inpaint.validate(batch_size, latents_shape.2, latents_shape.3)?;
+			let in_channels = inpaint.unet.as_deref().map_or_else(|| session.unet_in_channels(), latent_in_channels);
+			dedicated_inpaint = check_inpaint_unet(in_channels, inpaint.masked_image_latents.is_some())?;
+			let unet_wrapped = !self.controlnets.is_empty()
+				|| self.reference_image.is_some()
+				|| self.attend_and_excite.is_some()
+				|| self.deepcache.is_some()
+				|| self.multidiffusion.is_some();
+			if dedicated_inpaint && unet_wrapped {
+				anyhow::bail!(
+					"a dedicated inpainting UNet cannot be combined with ControlNet, reference attention, Attend-and-Excite, DeepCache or MultiDiffusion"
+				);
+			}
+			if inpaint.unet.is_some() && guidance_embedding.is_some() {
+				anyhow::bail!("the inpainting UNet cannot be used with a guidance-distilled model, since it takes no guidance embedding");
+			}
+			// dedicated inpainting UNets regenerate the masked region on their own, so the known region is only re-imposed
+			// if asked to
+			(!dedicated_inpaint || inpaint.blend_known_region).then(|| match initial_latents.as_ref() {
+				Some(latents) => latents / self.init_noise_scale(scheduler.init_noise_sigma()),
+				None => drawn_latents.clone(),
+			})
+		} else {
+			None
+		};
+		let mut latents = match initial_latents {
+			Some(latents) => latents,
+			None => drawn_latents * self.init_noise_scale(scheduler.init_noise_sigma()),
+		};
+
+		let frozen_latents = if let Some(mask) = self.freeze_mask.as_ref() {
+			let (mask_batch, mask_height, mask_width) = mask.dim();
+			if (mask_batch != 1 && mask_batch != batch_size) || mask_height != latents_shape.2 || mask_width != latents_shape.3 {
+				anyhow::bail!(
+					"`freeze_mask` has shape {:?}, expected (1 or {batch_size}, {}, {})",
+					mask.shape(),
+					latents_shape.2,
+					latents_shape.3
+				);
+			}
+			Some(latents.clone())
+		} else {
+			None
+		};
+
+		let (tile_regions, region_embeddings) = if let Some(multidiffusion) = self.multidiffusion.as_ref() {
+			multidiffusion.validate()?;
+			let tile_regions = multidiffusion
+				.tiles(latents_shape.2, latents_shape.3)
+				.into_iter()
+				.map(|tile| multidiffusion.region_for_tile(tile))
+				.collect::<Vec<_>>();
+			let mut region_embeddings = Vec::with_capacity(multidiffusion.regions.len());
+			for (_, region_prompt) in multidiffusion.regions.iter() {
+				let region_prompt = match region_prompt.len() {
+					1 => region_prompt.clone().batched(prompt_batch_size),
+					n if n == prompt_batch_size => region_prompt.clone(),
+					n => anyhow::bail!("MultiDiffusion regional prompt has {n} prompts; expected 1 or {prompt_batch_size}"),
+				};
+				let embeddings = session.encode_prompt(region_prompt, do_classifier_free_guidance, self.negative_prompt.as_ref())?;
+				let mut embeddings = repeat_text_embeddings(embeddings, self.num_images_per_prompt);
+				if let Some(factors) = self.conditioning_dropout.as_deref() {
+					apply_conditioning_dropout(&mut embeddings, factors, do_classifier_free_guidance)?;
+				}
+				region_embeddings.push(embeddings);
+			}
+			(tile_regions, region_embeddings)
+		} else {
+			(Vec::new(), Vec::new())
+		};
+
+		let timesteps = scheduler.timesteps().to_owned();
+		if timesteps.is_empty() {
+			anyhow::bail!("the scheduler computed no timesteps for {steps} steps");
+		}
+		if let Some(inpaint) = self.inpaint.as_ref() {
+			inpaint.strength.validate(timesteps.len())?;
+		}
+		if let Some(checkpoint_at) = self.checkpoint_at {
+			if checkpoint_at == 0 || checkpoint_at > timesteps.len() {
+				anyhow::bail!("`checkpoint_at` is {checkpoint_at}, expected 1..={}", timesteps.len());
+			}
+		}
+		let end_step = match self.denoising_end {
+			Some(denoising_end) => {
+				if self.checkpoint_at.is_some() {
+					anyhow::bail!("`checkpoint_at` cannot be combined with `denoising_end`, which captures its own checkpoint");
+				}
+				Some(denoising_end_step(denoising_end, timesteps.len())?)
+			}
+			None => None,
+		};
+		let checkpoint_at = end_step.or(stop_at).or(self.checkpoint_at);
+		let stage = match stage {
+			GenerationStage::Full if end_step.is_some() => GenerationStage::Base,
+			stage => stage,
+		};
+
+		let (start_step, scheduler_rng) = if let Some(checkpoint) = resume {
+			if checkpoint.latents.dim() != latents_shape {
+				anyhow::bail!(
+					"checkpoint latents have shape {:?}, but these options generate latents of shape {latents_shape:?}; the image & batch size must match",
+					checkpoint.latents.shape()
+				);
+			}
+			if checkpoint.step > timesteps.len() {
+				anyhow::bail!("checkpoint was captured after step {}, but the scheduler only has {} timesteps", checkpoint.step, timesteps.len());
+			}
+			if let Some(attend_and_excite) = self.attend_and_excite.as_ref() {
+				if checkpoint.step < attend_and_excite.steps {
+					anyhow::bail!("cannot resume from step {} during the first {} Attend-and-Excite steps", checkpoint.step, attend_and_excite.steps);
+				}
+			}
+			latents = checkpoint.latents.clone();
+			scheduler.restore_state(checkpoint.scheduler_state.clone());
+			(checkpoint.step, CountingRng::fast_forwarded(scheduler_rng, checkpoint.scheduler_rng_words))
+		} else {
+			(0, CountingRng::new(scheduler_rng))
+		};
+
+		let (attend_and_excite_rng, attend_and_excite_lengths) = if let Some(attend_and_excite) = self.attend_and_excite.as_ref() {
+			let text_lengths = self.prompt_text_lengths(session, prompt_batch_size)?;
+			attend_and_excite.validate(text_embeddings.shape()[1], &text_lengths)?;
+			(Some(StdRng::seed_from_u64(seed.wrapping_add(ATTEND_AND_EXCITE_SEED_OFFSET))), text_lengths)
+		} else {
+			(None, Vec::new())
+		};
+
+		let reference_attention = match self.reference_image.as_ref() {
+			Some(reference_image) => {
+				if self.multidiffusion.is_some() {
+					anyhow::bail!("a reference image cannot be combined with MultiDiffusion");
+				}
+				Some(ReferenceAttention::new(session, reference_image, self.resize_filter, self.reference_weight, self.width, self.height, seed)?)
+			}
+			None => None,
+		};
+
+		let controlnet = if self.controlnets.is_empty() {
+			None
+		} else {
+			if self.multidiffusion.is_some() || reference_attention.is_some() || self.attend_and_excite.is_some() || guidance_embedding_dim.is_some() {
+				anyhow::bail!("ControlNet cannot be combined with MultiDiffusion, reference attention, Attend-and-Excite, or guidance-distilled UNets");
+			}
+			Some(MultiControlNet::new(&self.controlnets, self.width, self.height)?)
+		};
+
+		let deepcache = match self.deepcache.as_ref() {
+			Some(config) => {
+				config.validate(session)?;
+				if self.multidiffusion.is_some() || controlnet.is_some() || reference_attention.is_some() || guidance_embedding_dim.is_some() {
+					anyhow::bail!("DeepCache cannot be combined with MultiDiffusion, ControlNet, reference attention, or guidance-distilled UNets");
+				}
+				Some(DeepCache::new(session, config))
+			}
+			None => None,
+		};
+
+		if !self.restart_schedule.is_empty() && (resume.is_some() || checkpoint_at.is_some()) {
+			anyhow::bail!("restart sampling cannot be combined with checkpoints, `denoising_end`, or resuming");
+		}
+		let timestep_values = timesteps.iter().map(|t| t.to_f32().unwrap()).collect::<Vec<_>>();
+		// restarts re-run steps, so they count towards the step limit; each restart re-runs at least one step, which bounds
+		// the plan before it is allocated
+		let num_restarts = self.restart_schedule.iter().fold(0_usize, |n, interval| n.saturating_add(interval.num_restarts));
+		limits.check(self.width, self.height, batch_size, steps.saturating_add(num_restarts))?;
+		let plan = restart_plan(&timestep_values, &self.restart_schedule, start_step)?;
+		limits.check(self.width, self.height, batch_size, plan.len())?;
+		let restart_rng = StdRng::seed_from_u64(seed.wrapping_add(RESTART_SEED_OFFSET));
+
+		let convergence = match self.early_exit {
+			Some(early_exit) => {
+				early_exit.validate()?;
+				if !self.restart_schedule.is_empty() || end_step.is_some() {
+					anyhow::bail!("early exit cannot be combined with restart sampling or `denoising_end`");
+				}
+				Some(ConvergenceTracker::new(early_exit))
+			}
+			None => None,
+		};
+
+		Ok(DenoiseLoop {
+			steps,
+			seed,
+			compatibility_version,
+			rng_draw_order,
+			stage,
+			do_classifier_free_guidance,
+			batch_size,
+			text_embeddings,
+			guidance_embedding,
+			dedicated_inpaint,
+			inpaint_noise,
+			frozen_latents,
+			tile_regions,
+			region_embeddings,
+			num_warmup_steps: scheduler.num_warmup_steps(steps),
+			timesteps,
+			end_step,
+			checkpoint_at,
+			stop_at,
+			scheduler_rng,
+			attend_and_excite_rng,
+			attend_and_excite_lengths,
+			reference_attention,
+			controlnet,
+			deepcache,
+			restart_rng,
+			convergence,
+			deadline: self.deadline(),
+			step_stats: Vec::with_capacity(if self.collect_step_stats { plan.len() } else { 0 }),
+			trajectory: Vec::with_capacity(if self.collect_trajectory { plan.len() } else { 0 }),
+			finished: plan.is_empty(),
+			plan: plan.into_iter(),
+			latents,
+			last_step: None,
+			checkpoint: None,
+			steps_taken: 0,
+			perturbed_regions,
+			generated,
+			stop_reason: StopReason::Completed,
+		})
+	}
+
+	/// Takes the next planned step of a denoising loop started with [`StableDiffusionTxt2ImgOptions::start_denoising`],
+	/// returning the index of the step taken, or `None` if the loop was interrupted before it. Marks the loop as
+	/// finished once no steps are left or it has to stop early.
+	pub(crate) fn denoise_step<S: DiffusionScheduler>(
+		&self,
+		session: &StableDiffusionPipeline,
+		scheduler: &mut S,
+		state: &mut DenoiseLoop<'_, S>,
+	) -> anyhow::Result<Option<usize>> {
+		let planned = match state.plan.next() {
+			Some(planned) => planned,
+			None => {
+				state.finished = true;
+				return Ok(None);
+			}
+		};
+		if let Some(interruption) = self.interruption(state.deadline) {
+			state.stop_reason = interruption;
+			state.finished = true;
+			return Ok(None);
+		}
+		let (i, t) = (planned.step, state.timesteps[planned.step]);
+		if let Some(from) = planned.renoise_from {
+			let noise = Array4::<f32>::random_using(state.latents.raw_dim(), StandardNormal, &mut state.restart_rng);
+			state.latents = renoise(scheduler, state.latents.view(), noise.view(), state.timesteps.get(from).copied(), t);
+			// multistep history refers to the trajectory before the restart
+			scheduler.restore_state(SchedulerState::default());
+		}
+
+		if let (Some(attend_and_excite), Some(rng)) = (self.attend_and_excite.as_ref(), state.attend_and_excite_rng.as_mut()) {
+			if i < attend_and_excite.steps {
+				let cond_embeddings = if state.do_classifier_free_guidance {
+					state.text_embeddings.slice_axis(Axis(0), Slice::from(state.batch_size..))
+				} else {
+					state.text_embeddings.view()
+				};
+				state.latents = attend_and_excite_step(
+					session,
+					scheduler,
+					attend_and_excite,
+					state.latents.view(),
+					t,
+					i,
+					cond_embeddings,
+					&state.attend_and_excite_lengths,
+					rng,
+				)?;
+			}
+		}
+
+		let latent_model_input = if state.do_classifier_free_guidance {
+			concatenate![Axis(0), state.latents, state.latents]
+		} else {
+			state.latents.clone()
+		};
+		let latent_model_input = scheduler.scale_model_input(latent_model_input.view(), t);
+		let latent_model_input = match self.inpaint.as_ref() {
+			Some(InpaintOptions { mask, masked_image_latents: Some(masked_image_latents), .. }) if state.dedicated_inpaint => {
+				inpaint_unet_input(latent_model_input.view(), mask.view(), masked_image_latents.view())
+			}
+			_ => latent_model_input,
+		};
+
+		let text_embeddings = &state.text_embeddings;
+		let guidance_embedding = state.guidance_embedding.as_ref().map(|embedding| embedding.view());
+		let mut noise_pred: Array4<f32> = if let Some(multidiffusion) = self.multidiffusion.as_ref() {
+			// average the noise predictions of all tiles covering each latent pixel
+			let (_, _, latent_height, latent_width) = state.latents.dim();
+			let mut noise_pred_sum = Array4::<f32>::zeros(latent_model_input.raw_dim());
+			let mut tile_count = Array4::<f32>::zeros(latent_model_input.raw_dim());
+			for (tile, region) in multidiffusion.tiles(latent_height, latent_width).into_iter().zip(state.tile_regions.iter()) {
+				let (y, x, height, width) = tile;
+				let encoder_hidden_states = region.map_or(text_embeddings, |r| &state.region_embeddings[r]);
+				let tile_noise_pred = session.predict_noise_with_guidance_embedding(
+					latent_model_input.slice(s![.., .., y..y + height, x..x + width]),
+					t.to_f32().unwrap(),
+					encoder_hidden_states.view(),
+					guidance_embedding,
+				)?;
+				let mut tile_sum = noise_pred_sum.slice_mut(s![.., .., y..y + height, x..x + width]);
+				tile_sum += &tile_noise_pred;
+				let mut tile_hits = tile_count.slice_mut(s![.., .., y..y + height, x..x + width]);
+				tile_hits += 1.0;
+			}
+			noise_pred_sum / tile_count
+		} else if let Some(controlnet) = state.controlnet.as_mut() {
+			let residuals =
+				controlnet.residuals(&self.controlnets, latent_model_input.view(), t.to_f32().unwrap(), text_embeddings.view(), i, state.timesteps.len())?;
+			session.predict_noise_with_controlnet(latent_model_input.view(), t.to_f32().unwrap(), text_embeddings.view(), residuals)?
+		} else if let Some(reference_attention) = state.reference_attention.as_ref() {
+			reference_attention.predict_noise(session, scheduler, latent_model_input.view(), t, text_embeddings.view())?
+		} else if let Some(deepcache) = state.deepcache.as_mut() {
+			deepcache.predict_noise(latent_model_input.view(), t.to_f32().unwrap(), text_embeddings.view())?
+		} else if let Some(unet) = self.inpaint.as_ref().and_then(|inpaint| inpaint.unet.as_deref()) {
+			session.predict_noise_with_unet(unet, latent_model_input.view(), t.to_f32().unwrap(), text_embeddings.view())?
+		} else {
+			session.predict_noise_with_guidance_embedding(latent_model_input.view(), t.to_f32().unwrap(), text_embeddings.view(), guidance_embedding)?
+		};
+		let mut guidance_norm = None;
+		if state.do_classifier_free_guidance {
+			assert!(noise_pred.shape()[0] % 2 == 0);
+			let split_len = (noise_pred.shape()[0] / 2) as isize;
+			let noise_pred_uncond = noise_pred.slice(s![..split_len, .., .., ..]);
+			let noise_pred_text = noise_pred.slice(s![split_len.., .., .., ..]);
+			if self.collect_step_stats {
+				guidance_norm = Some(self.guidance_scale * l2_distance(noise_pred_text, noise_pred_uncond));
+			}
+			noise_pred = if self.f64_guidance {
+				let (noise_pred_uncond, noise_pred_text) = (noise_pred_uncond.mapv(f64::from), noise_pred_text.mapv(f64::from));
+				let guidance_scale = f64::from(self.guidance_scale);
+				combine_guidance(noise_pred_uncond.view(), noise_pred_text.view(), guidance_scale, self.rescale_cfg.map(f64::from)).mapv(|x| x as f32)
+			} else {
+				combine_guidance(noise_pred_uncond, noise_pred_text, self.guidance_scale, self.rescale_cfg)
+			};
+		}
+
+		let previous_latents = state.convergence.as_ref().map(|_| state.latents.clone());
+		let sigma = if self.collect_trajectory { scheduler.sigma(i) } else { None };
+		let scheduler_output = scheduler.step(noise_pred.view(), t, state.latents.view(), &mut state.scheduler_rng);
+		state.latents = scheduler_output.prev_sample;
+		if let (Some(mask), Some(frozen_latents)) = (self.freeze_mask.as_ref(), state.frozen_latents.as_ref()) {
+			state.latents = blend_latents(state.latents.view(), frozen_latents.view(), mask.view());
+		}
+		if let (Some(inpaint), Some(noise)) = (self.inpaint.as_ref(), state.inpaint_noise.as_ref()) {
+			let next_timestep = state.timesteps.get(i + 1).copied();
+			let strength = inpaint.strength.strength(i, state.timesteps.len());
+			state.latents = reimpose_known_region(
+				scheduler,
+				state.latents.view(),
+				inpaint.init_latents.view(),
+				noise.view(),
+				inpaint.mask.view(),
+				next_timestep,
+				strength,
+			);
+		}
+		state.steps_taken += 1;
+		state.last_step = Some(i);
+		if self.collect_step_stats {
+			state.step_stats.push(StepStats::new(i, t.to_f32().unwrap(), state.latents.view(), noise_pred.view(), guidance_norm));
+		}
+		if self.collect_trajectory {
+			state.trajectory.push(StepRecord::new(i, t.to_f32().unwrap(), sigma, state.latents.view(), noise_pred.view()));
+		}
+		let converged = match (state.convergence.as_mut(), previous_latents.as_ref()) {
+			(Some(convergence), Some(previous_latents)) => convergence.step(i, previous_latents.view(), state.latents.view()),
+			_ => false,
+		};
+		if state.checkpoint_at == Some(i + 1) {
+			state.checkpoint = state.capture_checkpoint(scheduler);
+		}
+
+		if let Some(preview) = self.preview.as_ref() {
+			if i + 1 == preview.step && !planned.restarted {
+				(preview.cb)(session.approximate_decode_latents(state.latents.view())?);
+			}
+		}
+
+		if let Some(callback) = self.callback.as_ref() {
+			if i == state.timesteps.len() - 1 || ((i + 1) > state.num_warmup_steps && (i + 1) % S::order() == 0) {
+				if !callback.invoke(session, state.stage, i, t.to_f32().unwrap(), &state.latents)? {
+					state.stop_reason = StopReason::CallbackStop;
+					state.finished = true;
+				}
+			}
+		}
+
+		if state.end_step == Some(i + 1) || state.stop_at == Some(i + 1) || converged || state.plan.as_slice().is_empty() {
+			state.finished = true;
+		}
+		Ok(Some(i))
+	}
+
+	/// Returns the error of a denoising loop whose latents contain NaN or infinite values.
+	pub(crate) fn check_finite_latents<S: DiffusionScheduler>(&self, state: &DenoiseLoop<'_, S>) -> anyhow::Result<()> {
+		if state.latents.iter().any(|x| !x.is_finite()) {
+			return Err(NonFiniteLatents {
+				seed: state.seed,
+				first_anomalous_step: StepStats::detect_anomalies(&state.step_stats, DEFAULT_STD_JUMP_THRESHOLD).first().copied(),
+				step_stats_collected: self.collect_step_stats,
+			}
+			.into());
+		}
+		Ok(())
+	}
+}
+
+/// Error returned when the latents contain NaN or infinite values after denoising, and no
+/// [retries](StableDiffusionTxt2ImgOptions::with_retry_on_nan) are left.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonFiniteLatents {
+	/// The seed of the failed run.
+	pub seed: u64,
+	/// The first step whose statistics were anomalous, if step statistics were collected and any were; see
+	/// [`StepStats::detect_anomalies`].
+	pub first_anomalous_step: Option<usize>,
+	/// Whether step statistics were collected for the failed run.
+	pub step_stats_collected: bool,
+}
+
+impl fmt::Display for NonFiniteLatents {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "latents contain NaN or infinite values after denoising with seed {}", self.seed)?;
+		match self.first_anomalous_step {
+			Some(step) => write!(f, "; latents first became anomalous at step {step}"),
+			None if !self.step_stats_collected => write!(f, "; enable `collect_step_stats` to find the step where latents diverged"),
+			None => Ok(()),
+		}
+	}
+}
+
+impl std::error::Error for NonFiniteLatents {}
+
+/// The seed a run is retried with after `seed` produced NaN latents.
+fn nan_retry_seed(seed: u64) -> u64 {
+	fnv1a(seed.to_le_bytes().into_iter().chain(*b"nan-retry"))
+}
+
+/// The result of [`StableDiffusionTxt2ImgOptions::denoise`].
+pub(crate) struct Denoised {
+	pub(crate) latents: Array4<f32>,
+	pub(crate) seed: u64,
+	pub(crate) step_stats: Vec<StepStats>,
+	pub(crate) trajectory: Vec<StepRecord>,
+	pub(crate) checkpoint: Option<DiffusionCheckpoint>,
+	pub(crate) steps_taken: usize,
+	pub(crate) perturbed_regions: Vec<Vec<ImageRegion>>,
+	/// Which elements of the full batch were generated; empty if all were.
+	pub(crate) generated: Vec<bool>,
+	pub(crate) stop_reason: StopReason,
+	/// Whether the run stopped at `denoising_end` to hand off its latents to a refiner.
+	pub(crate) handoff: bool,
+}
+
+/// The state of a denoising loop between its steps; see [`StableDiffusionTxt2ImgOptions::start_denoising`].
+pub(crate) struct DenoiseLoop<'s, S: DiffusionScheduler> {
+	steps: usize,
+	seed: u64,
+	compatibility_version: CompatibilityVersion,
+	rng_draw_order: RngDrawOrder,
+	stage: GenerationStage,
+	do_classifier_free_guidance: bool,
+	batch_size: usize,
+	text_embeddings: ArrayD<f32>,
+	guidance_embedding: Option<Array2<f32>>,
+	dedicated_inpaint: bool,
+	inpaint_noise: Option<Array4<f32>>,
+	frozen_latents: Option<Array4<f32>>,
+	/// The index of the MultiDiffusion region each tile is prompted with, if any.
+	tile_regions: Vec<Option<usize>>,
+	region_embeddings: Vec<ArrayD<f32>>,
+	num_warmup_steps: usize,
+	timesteps: Array1<S::TimestepType>,
+	end_step: Option<usize>,
+	checkpoint_at: Option<usize>,
+	stop_at: Option<usize>,
+	scheduler_rng: CountingRng,
+	attend_and_excite_rng: Option<StdRng>,
+	attend_and_excite_lengths: Vec<usize>,
+	reference_attention: Option<ReferenceAttention>,
+	controlnet: Option<MultiControlNet>,
+	deepcache: Option<DeepCache<'s>>,
+	restart_rng: StdRng,
+	convergence: Option<ConvergenceTracker>,
+	deadline: Option<Instant>,
+	step_stats: Vec<StepStats>,
+	trajectory: Vec<StepRecord>,
+	plan: std::vec::IntoIter<PlannedStep>,
+	/// Whether no more steps will be taken.
+	pub(crate) finished: bool,
+	pub(crate) latents: Array4<f32>,
+	/// The index of the last step taken, if any.
+	last_step: Option<usize>,
+	checkpoint: Option<DiffusionCheckpoint>,
+	steps_taken: usize,
+	perturbed_regions: Vec<Vec<ImageRegion>>,
+	generated: Vec<bool>,
+	pub(crate) stop_reason: StopReason,
+}
+
+impl<S: DiffusionScheduler> DenoiseLoop<'_, S> {
+	/// Returns the timestep of step `i`.
+	pub(crate) fn timestep(&self, i: usize) -> f32 {
+		self.timesteps.get(i).and_then(ToPrimitive::to_f32).unwrap_or_default()
+	}
+
+	/// Captures a checkpoint after the last step taken, or `None` before the first step.
+	pub(crate) fn capture_checkpoint(&self, scheduler: &S) -> Option<DiffusionCheckpoint> {
+		self.last_step.map(|i| DiffusionCheckpoint {
+			step: i + 1,
+			steps: self.steps,
+			seed: self.seed,
+			compatibility_version: self.compatibility_version.resolve(),
+			rng_draw_order: self.rng_draw_order,
+			scheduler_rng_words: self.scheduler_rng.words(),
+			latents: self.latents.clone(),
+			scheduler_state: scheduler.state(),
+		})
+	}
+
+	fn into_denoised(self) -> Denoised {
+		Denoised {
+			latents: self.latents,
+			seed: self.seed,
+			step_stats: self.step_stats,
+			trajectory: self.trajectory,
+			checkpoint: self.checkpoint,
+			steps_taken: self.steps_taken,
+			perturbed_regions: self.perturbed_regions,
+			generated: self.generated,
+			stop_reason: self.stop_reason,
+			handoff: self.end_step.is_some(),
+		}
+	}
+}
+
+impl TextToImagePipeline for StableDiffusionPipeline {
+	fn txt2img<S: DiffusionScheduler>(&self, scheduler: &mut S, options: &StableDiffusionTxt2ImgOptions) -> anyhow::Result<StableDiffusionOutput> {
+		options.run_with_output(self, scheduler)
+	}
+}
+
+/// Combines the unconditional & text noise predictions for classifier-free guidance, optionally applying CFG rescaling
+/// with the given multiplier (see [`StableDiffusionTxt2ImgOptions::rescale_cfg`]).
+fn combine_guidance<T: Float + FromPrimitive + ScalarOperand>(
+	noise_pred_uncond: ArrayView4<'_, T>,
+	noise_pred_text: ArrayView4<'_, T>,
+	guidance_scale: T,
+	rescale_cfg: Option<T>,
+) -> Array4<T> {
+	let x_cfg = &noise_pred_uncond + &((&noise_pred_text - &noise_pred_uncond) * guidance_scale);
+	match rescale_cfg {
+		Some(multiplier) => {
+			let (ro_pos, ro_cfg) = (noise_pred_text.std(T::zero()), x_cfg.std(T::zero()));
+			let x_rescaled = &x_cfg * (ro_pos / ro_cfg);
+			x_rescaled * multiplier + x_cfg * (T::one() - multiplier)
+		}
+		None => x_cfg,
+	}
+}
+
+/// Returns the number of steps after which a run with the given `denoising_end` stops.
+pub(crate) fn denoising_end_step(denoising_end: f32, steps: usize) -> anyhow::Result<usize> {
+	let step = handoff_step(denoising_end, steps);
+	if !(denoising_end > 0.0 && denoising_end <= 1.0) || step == 0 {
+		anyhow::bail!("`denoising_end` is {denoising_end}, which is not within (0, 1] or ends before the first of {steps} steps");
+	}
+	Ok(step)
+}
+
+/// Returns the step index at which a refiner with the given `denoising_start` starts.
+pub(crate) fn denoising_start_step(denoising_start: f32, steps: usize) -> anyhow::Result<usize> {
+	let step = handoff_step(denoising_start, steps);
+	if !(0.0..1.0).contains(&denoising_start) || step >= steps {
+		anyhow::bail!("`denoising_start` is {denoising_start}, which is not within [0, 1) or starts after the last of {steps} steps");
+	}
+	Ok(step)
+}
+
+/// The step at which a base+refiner run hands off for a given fraction of the steps. The base & refiner stages use
+/// the same rounding, so a `denoising_end` & `denoising_start` of the same value meet at the same step.
+fn handoff_step(fraction: f32, steps: usize) -> usize {
+	(fraction * steps as f32).round() as usize
+}
+
+/// Interpolates the conditional half of `[uncond; cond]` text embeddings toward the unconditional half, by one factor
+/// per batch element.
+pub(crate) fn apply_conditioning_dropout(text_embeddings: &mut ArrayD<f32>, factors: &[f32], do_classifier_free_guidance: bool) -> anyhow::Result<()> {
+	if !do_classifier_free_guidance {
+		anyhow::bail!("conditioning dropout requires classifier-free guidance (a guidance scale above 1 without a guidance-distilled UNet)");
+	}
+	let batch_size = text_embeddings.shape()[0] / 2;
+	if factors.len() != batch_size {
+		anyhow::bail!("conditioning dropout has {} factors, but the batch has {batch_size} elements", factors.len());
+	}
+	if let Some(factor) = factors.iter().find(|factor| !(0.0..=1.0).contains(*factor)) {
+		anyhow::bail!("conditioning dropout factors must be within 0.0..=1.0, but got {factor}");
+	}
+	for (i, &factor) in factors.iter().enumerate() {
+		if factor == 0.0 {
+			continue;
+		}
+		let uncond = text_embeddings.index_axis(Axis(0), i).to_owned();
+		let mut cond = text_embeddings.index_axis_mut(Axis(0), batch_size + i);
+		cond.zip_mut_with(&uncond, |cond, &uncond| *cond = (1.0 - factor) * *cond + factor * uncond);
+	}
+	Ok(())
+}
+
+/// Repeats each prompt's text embeddings `num_images_per_prompt` times along the batch axis. Each row is repeated in
+/// place, so with classifier-free guidance the embeddings stay ordered as `[uncond; cond]`, with the repetition applied
+/// within each block, and the `i`th unconditional & conditional embeddings belong to the same image.
+pub(crate) fn repeat_text_embeddings(text_embeddings: ArrayD<f32>, num_images_per_prompt: usize) -> ArrayD<f32> {
+	if num_images_per_prompt == 1 {
+		return text_embeddings;
+	}
+	let indices = (0..text_embeddings.shape()[0])
+		.flat_map(|i| std::iter::repeat(i).take(num_images_per_prompt))
+		.collect::<Vec<_>>();
+	text_embeddings.select(Axis(0), &indices)
+}
+
+/// Blends `original` into `latents` according to a latent-resolution `mask` of shape `(1 or batch_size, height,
+/// width)`, where `1.0` keeps `original` and `0.0` keeps `latents`. The mask is broadcast over the channel axis.
+pub(crate) fn blend_latents(latents: ArrayView4<'_, f32>, original: ArrayView4<'_, f32>, mask: ArrayView3<'_, f32>) -> Array4<f32> {
+	let mask = mask.insert_axis(Axis(1));
+	&mask * &original + (1.0 - &mask) * &latents
+}
+
+#[cfg(test)]
+mod tests {
+	use ndarray::{s, Array3, Array4};
+	use ndarray_rand::{
+		rand::{rngs::StdRng, Rng, SeedableRng},
+		rand_distr::StandardNormal,
+	};
+
+	use super::{
+		blend_latents, combine_guidance, denoising_end_step, denoising_start_step, draw_initial_latents, nan_retry_seed, repeat_text_embeddings,
+		CompatibilityVersion, NonFiniteLatents, RngDrawOrder, StableDiffusionTxt2ImgOptions,
+	};
+
+	const SEED: u64 = 42;
+	const SHAPE: (usize, usize, usize, usize) = (2, 4, 2, 3);
+
+	#[test]
+	fn nan_retries() {
+		// retry seeds are fixed across releases (FNV-1a of the seed's LE bytes & `nan-retry`) & don't cycle back quickly
+		let seeds = std::iter::successors(Some(SEED), |&seed| Some(nan_retry_seed(seed))).take(8).collect::<Vec<_>>();
+		assert_eq!(seeds[1..3], [5239326184676249663, 7070962117678338012]);
+		assert!(seeds.iter().enumerate().all(|(i, seed)| !seeds[..i].contains(seed)));
+
+		let error = anyhow::Error::from(NonFiniteLatents { seed: SEED, first_anomalous_step: Some(3), step_stats_collected: true });
+		assert_eq!(error.downcast_ref::<NonFiniteLatents>().map(|failure| failure.seed), Some(SEED));
+		assert_eq!(error.to_string(), "latents contain NaN or infinite values after denoising with seed 42; latents first became anomalous at step 3");
+		let error = NonFiniteLatents { seed: SEED, first_anomalous_step: None, step_stats_collected: false };
+		assert!(error.to_string().ends_with("enable `collect_step_stats` to find the step where latents diverged"));
+	}
+
+	#[test]
+	fn nan_retries_use_derived_seeds() {
+		let options = StableDiffusionTxt2ImgOptions::default();
+		let mut attempts = Vec::new();
+		let error = options
+			.with_nan_retries(2, |seed| {
+				attempts.push(seed);
+				Err(NonFiniteLatents { seed: seed.unwrap_or(SEED), first_anomalous_step: None, step_stats_collected: false }.into())
+			})
+			.err()
+			.unwrap();
+		let (first, second) = (nan_retry_seed(SEED), nan_retry_seed(nan_retry_seed(SEED)));
+		assert_eq!(attempts, [None, Some(first), Some(second)]);
+		assert_eq!(error.downcast_ref::<NonFiniteLatents>().map(|failure| failure.seed), Some(second));
+
+		// other errors are never retried
+		attempts.clear();
+		assert!(options
+			.with_nan_retries(2, |seed| {
+				attempts.push(seed);
+				Err(anyhow::anyhow!("not a NaN"))
+			})
+			.is_err());
+		assert_eq!(attempts, [None]);
+	}
+
+	/// The first `n` standard normal samples of an RNG seeded with `seed`.
+	fn reference(seed: u64, n: usize) -> Vec<f32> {
+		let mut rng = StdRng::seed_from_u64(seed);
+		(0..n).map(|_| rng.sample(StandardNormal)).collect()
+	}
+
+	fn next_samples(rng: &mut StdRng, n: usize) -> Vec<f32> {
+		(0..n).map(|_| rng.sample(StandardNormal)).collect()
+	}
+
+	/// Standard normal samples 0-5, 42-47 & 48-51 of `StdRng::seed_from_u64(42)`, and samples 0-3 of
+	/// `StdRng::seed_from_u64(42 + 31337)`, as computed by an independent reimplementation of `StdRng` & `StandardNormal`
+	/// in `scripts/stdrng_reference.py`.
+	const FIRST_LATENTS: [f32; 6] = [0.069427915, 0.13293812, 0.26257637, -0.22530088, -0.66422486, -0.2153902];
+	const LAST_LATENTS: [f32; 6] = [-0.13212654, -1.7721263, -1.4762224, -0.4118597, -0.025097072, 0.80970347];
+	const SEED_CONTINUED: [f32; 4] = [1.6236116, 0.11845162, 0.6323347, 0.7486509];
+	const SCHEDULER_SEED: [f32; 4] = [0.9503483, -1.3956223, 0.35331768, 2.2412484];
+
+	/// Checks the first image's first latent channel & the last image's last latent channel against the fixed samples.
+	fn assert_seed_latents(latents: &Array4<f32>) {
+		assert_eq!(latents.slice(s![0, 0, .., ..]).iter().copied().collect::<Vec<_>>(), FIRST_LATENTS);
+		assert_eq!(latents.slice(s![1, 3, .., ..]).iter().copied().collect::<Vec<_>>(), LAST_LATENTS);
+	}
+
+	#[test]
+	fn separate_draw_order() {
+		let (latents, mut scheduler_rng) = draw_initial_latents(CompatibilityVersion::V1_0, RngDrawOrder::Separate, SEED, SHAPE);
+		assert_seed_latents(&latents);
+		// scheduler noise comes from a second RNG seeded with `seed + 31337`
+		assert_eq!(next_samples(&mut scheduler_rng, 4), SCHEDULER_SEED);
+	}
+
+	#[test]
+	fn v1_0_rngs_are_frozen() {
+		// V1_0 must keep drawing these samples in every future release, whatever `Latest` resolves to
+		assert_eq!(next_samples(&mut CompatibilityVersion::V1_0.latents_rng(SEED), 6), FIRST_LATENTS);
+		assert_eq!(next_samples(&mut CompatibilityVersion::V1_0.scheduler_rng(SEED), 4), SCHEDULER_SEED);
+	}
+
+	#[test]
+	fn scheduler_seed_wraps() {
+		let mut scheduler_rng = CompatibilityVersion::V1_0.scheduler_rng(u64::MAX);
+		assert_eq!(next_samples(&mut scheduler_rng, 4), reference(31336, 4));
+	}
+
+	#[test]
+	fn sequential_draw_order() {
+		let (latents, mut scheduler_rng) = draw_initial_latents(CompatibilityVersion::V1_0, RngDrawOrder::Sequential, SEED, SHAPE);
+		assert_seed_latents(&latents);
+		// scheduler noise continues the same stream after the latents
+		assert_eq!(next_samples(&mut scheduler_rng, 4), SEED_CONTINUED);
+	}
+
+	#[test]
+	fn latest_draws_like_v1_0() {
+		for order in [RngDrawOrder::Separate, RngDrawOrder::Sequential] {
+			let (latents, mut scheduler_rng) = draw_initial_latents(CompatibilityVersion::Latest, order, SEED, SHAPE);
+			assert_seed_latents(&latents);
+			let expected = if order == RngDrawOrder::Separate { SCHEDULER_SEED } else { SEED_CONTINUED };
+			assert_eq!(next_samples(&mut scheduler_rng, 4), expected);
+		}
+	}
+
+	#[test]
+	fn blended_latents_follow_mask() {
+		let latents = Array4::from_elem(SHAPE, 2.0_f32);
+		let original = Array4::from_elem(SHAPE, -2.0_f32);
+
+		// a single mask is broadcast over the batch & channels
+		let mask = Array3::from_shape_vec((1, 2, 3), vec![1.0, 0.0, 0.5, 0.0, 0.25, 1.0]).unwrap();
+		let blended = blend_latents(latents.view(), original.view(), mask.view());
+		assert_eq!(blended.dim(), SHAPE);
+		for ((_, _, y, x), &value) in blended.indexed_iter() {
+			assert_eq!(value, [[-2.0, 2.0, 0.0], [2.0, 1.0, -2.0]][y][x]);
+		}
+
+		// a freeze mask per image only freezes that image's latents
+		let mask = Array3::from_shape_fn((2, 2, 3), |(b, _, _)| if b == 0 { 1.0 } else { 0.0 });
+		let blended = blend_latents(latents.view(), original.view(), mask.view());
+		assert!(blended.outer_iter().next().unwrap().iter().all(|&v| v == -2.0));
+		assert!(blended.outer_iter().nth(1).unwrap().iter().all(|&v| v == 2.0));
+	}
+
+	#[test]
+	fn repeated_embeddings_keep_cfg_halves_aligned() {
+		// 2 prompts with CFG: rows are [uncond 0, uncond 1, cond 0, cond 1], tagged 0-3
+		let embeddings = Array3::from_shape_fn((4, 77, 8), |(b, _, _)| b as f32).into_dyn();
+		let repeated = repeat_text_embeddings(embeddings, 3);
+		assert_eq!(repeated.shape(), &[12, 77, 8]);
+		let tags = repeated.outer_iter().map(|row| row[[0, 0]]).collect::<Vec<_>>();
+		assert_eq!(tags, vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 3.0, 3.0, 3.0]);
+		// image `i`'s unconditional embedding at `i` and conditional embedding at `6 + i` belong to the same prompt
+		for i in 0..6 {
+			assert_eq!(tags[6 + i] - tags[i], 2.0);
+		}
+	}
+
+	#[test]
+	fn handoff_steps_meet() {
+		for (fraction, steps, step) in [(0.8, 25, 20), (0.5, 30, 15), (0.7, 4, 3)] {
+			assert_eq!(denoising_end_step(fraction, steps).unwrap(), step);
+			assert_eq!(denoising_start_step(fraction, steps).unwrap(), step);
+		}
+		assert_eq!(denoising_end_step(1.0, 25).unwrap(), 25);
+		assert_eq!(denoising_start_step(0.0, 25).unwrap(), 0);
+
+		assert!(denoising_end_step(0.0, 25).is_err());
+		assert!(denoising_end_step(0.01, 25).is_err());
+		assert!(denoising_start_step(1.0, 25).is_err());
+		assert!(denoising_start_step(0.99, 25).is_err());
+	}
+
+	#[test]
+	fn f64_guidance_matches_f32() {
+		let mut rng = StdRng::seed_from_u64(SEED);
+		let uncond = Array4::from_shape_simple_fn(SHAPE, || rng.sample::<f32, _>(StandardNormal));
+		let text = &uncond + Array4::from_shape_simple_fn(SHAPE, || 0.01 * rng.sample::<f32, _>(StandardNormal));
+
+		for (guidance_scale, rescale_cfg) in [(7.5, None), (7.5, Some(0.7)), (30.0, None)] {
+			let f32_result = combine_guidance(uncond.view(), text.view(), guidance_scale, rescale_cfg);
+			let (uncond_f64, text_f64) = (uncond.mapv(f64::from), text.mapv(f64::from));
+			let f64_result = combine_guidance(uncond_f64.view(), text_f64.view(), f64::from(guidance_scale), rescale_cfg.map(f64::from));
+			for (a, b) in f32_result.iter().zip(f64_result.iter()) {
+				assert!((f64::from(*a) - b).abs() < 1e-4, "{a} != {b} at guidance scale {guidance_scale}");
 			}
 		}
 
-		session.decode_latents(latents.view())
+		// plain CFG is unchanged from `uncond + scale * (text - uncond)`
+		assert_eq!(combine_guidance(uncond.view(), text.view(), 7.5, None), &uncond + 7.5 * (&text - &uncond));
 	}
 }
@@ -0,0 +1,577 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use image::{DynamicImage, GrayImage, Luma};
+use ndarray::{concatenate, Array3, Array4, ArrayView3, ArrayView4, Axis};
+
+use super::{
+	impl_main::{check_free_memory, latent_in_channels},
+	impl_txt2img::blend_latents
+};
+use crate::{
+	compositing::composite_inpaint_result,
+	session_tracker::{load_session, ModelSource, TrackedSession},
+	DiffusionScheduler, ImageRef, ResizeFilter, StableDiffusionOutput, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions
+};
+
+/// Number of UNet input channels of dedicated inpainting models: 4 latent channels, 1 mask channel, and 4 channels of
+/// masked image latents.
+const INPAINT_UNET_IN_CHANNELS: u32 = 9;
+
+/// Options for inpainting, i.e. regenerating the masked region of an image while keeping the rest intact.
+///
+/// With a dedicated 9-channel inpainting UNet, the UNet is given the mask & the latents of the masked image
+/// ([`masked_image_latents`](Self::masked_image_latents)) alongside the latents, and regenerates the masked region
+/// itself.
+///
+/// Standard 4-channel UNets inpaint with the legacy latent blending technique instead: after each scheduler step, the
+/// unmasked region of the latents is replaced by the image latents noised to the next timestep
+/// (`add_noise(init_latents, noise, t)`), using the initial noise of the generation. The masked region is denoised as
+/// usual and gradually harmonizes with its surroundings. Because the UNet never sees the mask, seams at the mask
+/// border are more visible than with dedicated inpainting models;
+/// [`compositing::composite_inpaint_result`](crate::compositing::composite_inpaint_result) can hide small seams.
+///
+/// [`StableDiffusionPipeline::inpaint`] prepares these options from an image & a mask, runs the generation, and
+/// composites the result onto the image.
+///
+/// How strongly the known region is re-imposed can vary over the denoising steps with a [`StrengthSchedule`]; see
+/// [`InpaintOptions::with_strength_schedule`].
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// # use pyke_diffusers::{InpaintOptions, StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+/// # let environment = OrtEnvironment::default().into_arc();
+/// # let mut scheduler = EulerDiscreteScheduler::default();
+/// let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+/// let image = image::open("photo.png")?;
+/// let mask = image::open("mask.png")?;
+/// let inpaint = InpaintOptions::from_images(&pipeline, &image, &mask)?;
+/// let imgs = StableDiffusionTxt2ImgOptions::default()
+/// 	.with_size(image.width(), image.height())
+/// 	.with_prompt("a red fox sitting on a bench")
+/// 	.with_inpaint(inpaint)
+/// 	.run(&pipeline, &mut scheduler)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct InpaintOptions {
+	/// The (scaled) latents of the image to inpaint, of shape `(1 or batch_size, 4, height / 8, width / 8)`, as
+	/// returned by [`StableDiffusionPipeline::encode_images`].
+	pub init_latents: Array4<f32>,
+	/// A latent-resolution mask of shape `(1 or batch_size, height / 8, width / 8)`, where `1.0` marks the region to
+	/// regenerate and `0.0` the region to keep. See [`prepare_inpaint_mask`].
+	pub mask: Array3<f32>,
+	/// How strongly the known region is re-imposed after each step. Defaults to `StrengthSchedule::Constant(1.0)`. See
+	/// [`InpaintOptions::with_strength_schedule`].
+	pub strength: StrengthSchedule,
+	/// The (scaled) latents of the image with its masked region blanked out, of the same shape as
+	/// [`init_latents`](Self::init_latents), for dedicated 9-channel inpainting UNets. See
+	/// [`InpaintOptions::with_masked_image_latents`].
+	pub masked_image_latents: Option<Array4<f32>>,
+	/// Whether the known region is re-imposed after each step even with a dedicated inpainting UNet. See
+	/// [`InpaintOptions::with_known_region_blending`].
+	pub blend_known_region: bool,
+	/// A dedicated inpainting UNet used instead of the pipeline's UNet, loaded by
+	/// [`StableDiffusionPipeline::inpaint_with_mask_channel`].
+	pub(crate) unet: Option<Arc<TrackedSession>>
+}
+
+impl InpaintOptions {
+	/// Creates inpainting options from image latents & a latent-resolution mask.
+	pub fn new(init_latents: Array4<f32>, mask: Array3<f32>) -> Self {
+		Self {
+			init_latents,
+			mask,
+			strength: StrengthSchedule::default(),
+			masked_image_latents: None,
+			blend_known_region: false,
+			unet: None
+		}
+	}
+
+	/// Sets whether the known region is re-imposed after each step with dedicated 9-channel inpainting UNets too, so
+	/// that the unmasked latents stay exactly those of the image. Dedicated UNets regenerate only the masked region on
+	/// their own, so this is disabled by default; standard UNets always re-impose the known region.
+	pub fn with_known_region_blending(mut self, blend_known_region: bool) -> Self {
+		self.blend_known_region = blend_known_region;
+		self
+	}
+
+	/// Sets the latents of the masked image, which dedicated 9-channel inpainting UNets take as input. These are the
+	/// latents of the image with its masked region set to gray (`0.5`, i.e. `0.0` in the VAE's input range), as
+	/// computed by [`InpaintOptions::from_images`] when the pipeline's UNet has 9 input channels. Inpainting with a
+	/// 9-channel UNet fails without them; standard UNets ignore them.
+	pub fn with_masked_image_latents(mut self, masked_image_latents: Array4<f32>) -> Self {
+		self.masked_image_latents = Some(masked_image_latents);
+		self
+	}
+
+	/// Sets how strongly the known region is re-imposed after each step. After step `i`, the kept region of the
+	/// latents becomes `strength * known + (1 - strength) * latents`, where `known` is the noised init latents and
+	/// `strength` is the schedule's strength at step `i`, scaled by the mask's keep weight (`1 - mask`).
+	///
+	/// The default constant strength of `1.0` replaces the known region entirely, which is classic inpainting. Lower
+	/// strengths let the known region drift from the image. With an all-zero mask (keeping the whole image), a
+	/// decaying schedule gives img2img-style refinement where the output closely follows the image's composition
+	/// early on, while the last steps are free to refine details:
+	///
+	/// ```
+	/// # use ndarray::{Array3, Array4};
+	/// # use pyke_diffusers::{InpaintOptions, StrengthSchedule};
+	/// # let init_latents = Array4::zeros((1, 4, 64, 64));
+	/// let refine = InpaintOptions::new(init_latents, Array3::zeros((1, 64, 64))).with_strength_schedule(StrengthSchedule::Linear { start: 1.0, end: 0.0 });
+	/// ```
+	///
+	/// Note that a strength below `1.0` after the final step means the known region isn't exactly the image.
+	pub fn with_strength_schedule(mut self, strength: StrengthSchedule) -> Self {
+		self.strength = strength;
+		self
+	}
+
+	/// Creates inpainting options from an image & a mask image, where white marks the region to regenerate. The image
+	/// is encoded with the pipeline's VAE encoder at its own size, which must be divisible by 8, and the mask is
+	/// downsampled to the latent resolution with [`prepare_inpaint_mask`].
+	///
+	/// The mask must be exactly the size of the image; masks of another size are rejected rather than stretched, which
+	/// would silently shift the mask's edges. If the pipeline's UNet is a dedicated 9-channel inpainting UNet, the
+	/// masked image is also encoded; see [`InpaintOptions::with_masked_image_latents`].
+	pub fn from_images(session: &StableDiffusionPipeline, image: &DynamicImage, mask: &DynamicImage) -> anyhow::Result<Self> {
+		Self::from_images_with_filter(session, image, mask, ResizeFilter::Nearest)
+	}
+
+	/// Creates inpainting options like [`InpaintOptions::from_images`], downsampling the mask with
+	/// [`prepare_inpaint_mask_with_filter`] & the given filter.
+	pub fn from_images_with_filter(session: &StableDiffusionPipeline, image: &DynamicImage, mask: &DynamicImage, filter: ResizeFilter) -> anyhow::Result<Self> {
+		Self::from_images_for(session, image, mask, filter, session.unet_in_channels())
+	}
+
+	/// Creates inpainting options like [`InpaintOptions::from_images_with_filter`] for a UNet with `in_channels` input
+	/// channels.
+	fn from_images_for(
+		session: &StableDiffusionPipeline,
+		image: &DynamicImage,
+		mask: &DynamicImage,
+		filter: ResizeFilter,
+		in_channels: Option<u32>
+	) -> anyhow::Result<Self> {
+		let (width, height) = (image.width(), image.height());
+		if width % 8 != 0 || height % 8 != 0 {
+			anyhow::bail!("image to inpaint is {width}x{height}; width & height must be divisible by 8");
+		}
+		if mask.width() != width || mask.height() != height {
+			anyhow::bail!("inpainting mask is {}x{}, but the image is {width}x{height}; the mask must be the size of the image", mask.width(), mask.height());
+		}
+		let image = image.to_rgb32f();
+		let pixels = Array4::from_shape_fn((1, 3, height as usize, width as usize), |(_, c, y, x)| image.get_pixel(x as u32, y as u32).0[c]);
+		let options = Self::new(session.encode_images(pixels.view())?, prepare_inpaint_mask_with_filter(mask, width, height, filter));
+		if in_channels != Some(INPAINT_UNET_IN_CHANNELS) {
+			return Ok(options);
+		}
+
+		let mask = mask.to_luma32f();
+		let mut masked_pixels = pixels;
+		for ((_, _, y, x), pixel) in masked_pixels.indexed_iter_mut() {
+			if mask.get_pixel(x as u32, y as u32).0[0] >= 0.5 {
+				*pixel = 0.5;
+			}
+		}
+		let masked_image_latents = session.encode_images(masked_pixels.view())?;
+		Ok(options.with_masked_image_latents(masked_image_latents))
+	}
+
+	pub(crate) fn validate(&self, batch_size: usize, latent_height: usize, latent_width: usize) -> anyhow::Result<()> {
+		let (latents_batch, _, height, width) = self.init_latents.dim();
+		if (latents_batch != 1 && latents_batch != batch_size) || height != latent_height || width != latent_width {
+			anyhow::bail!(
+				"inpainting `init_latents` have shape {:?}, expected (1 or {batch_size}, 4, {latent_height}, {latent_width})",
+				self.init_latents.shape()
+			);
+		}
+		let (mask_batch, height, width) = self.mask.dim();
+		if (mask_batch != 1 && mask_batch != batch_size) || height != latent_height || width != latent_width {
+			anyhow::bail!("inpainting `mask` has shape {:?}, expected (1 or {batch_size}, {latent_height}, {latent_width})", self.mask.shape());
+		}
+		if let Some(masked_image_latents) = self.masked_image_latents.as_ref() {
+			if masked_image_latents.dim() != self.init_latents.dim() {
+				anyhow::bail!(
+					"inpainting `masked_image_latents` have shape {:?}, expected the shape of `init_latents` ({:?})",
+					masked_image_latents.shape(),
+					self.init_latents.shape()
+				);
+			}
+		}
+		Ok(())
+	}
+}
+
+impl StableDiffusionPipeline {
+	/// Inpaints an image: regenerates the region of `image` marked white in `mask` with the given options (prompt,
+	/// steps, seed, ...), and keeps the rest of the image.
+	///
+	/// `image` & `mask` must be the same size, which must be divisible by 8 & is used as the options' size; see
+	/// [`InpaintOptions::from_images`]. The mask is downsampled with the options'
+	/// [mask resize filter](StableDiffusionTxt2ImgOptions::with_mask_resize_filter), nearest-neighbor by default. If the
+	/// pipeline's UNet is a dedicated 9-channel inpainting UNet, it is given the mask & masked image; otherwise,
+	/// inpainting falls back to legacy latent blending, see [`InpaintOptions`].
+	///
+	/// Even where the latents are exactly those of the image, the VAE doesn't reconstruct the image exactly, so the
+	/// decoded images are composited onto `image` with
+	/// [`compositing::composite_inpaint_result`](crate::compositing::composite_inpaint_result): pixels outside the mask
+	/// (black in `mask`) are exactly those of `image`, and gray mask pixels blend the two. For the same reason,
+	/// inpainting cannot be combined with [`StableDiffusionTxt2ImgOptions::with_decode_to_disk`].
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// # let mut scheduler = EulerDiscreteScheduler::default();
+	/// let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5-inpainting/", StableDiffusionOptions::default())?;
+	/// let options = StableDiffusionTxt2ImgOptions::default().with_prompt("a red fox sitting on a bench");
+	/// let output = pipeline.inpaint(&mut scheduler, &image::open("photo.png")?, &image::open("mask.png")?, options)?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn inpaint<S: DiffusionScheduler>(
+		&self,
+		scheduler: &mut S,
+		image: &DynamicImage,
+		mask: &DynamicImage,
+		options: StableDiffusionTxt2ImgOptions
+	) -> anyhow::Result<StableDiffusionOutput> {
+		let filter = options.mask_resize_filter;
+		self.run_inpaint(scheduler, image, mask, options, || InpaintOptions::from_images_with_filter(self, image, mask, filter))
+	}
+
+	/// Inpaints an image like [`StableDiffusionPipeline::inpaint`] with the dedicated 9-channel inpainting UNet from the
+	/// `inpaint` key of the model config's `[unet]` section, so a standard model can inpaint with its inpainting
+	/// variant. Fails if the config has no inpainting UNet.
+	///
+	/// The inpainting UNet is loaded on the UNet's device for the duration of the call and dropped afterwards, so it
+	/// only occupies memory while inpainting. In exchange, every call reads it from disk & creates its session again,
+	/// which takes about as long as loading the pipeline's UNet. To inpaint many images, load a pipeline whose UNet is
+	/// the inpainting UNet instead, and use [`inpaint`](Self::inpaint), which inpaints with the mask channel too. Pair
+	/// this with
+	/// [`StableDiffusionOptions::with_free_memory_check`](crate::StableDiffusionOptions::with_free_memory_check) on
+	/// machines with little memory to fail early instead of swapping.
+	///
+	/// The mask is binarized at `0.5` before being resized to the latent resolution with the options'
+	/// [mask resize filter](StableDiffusionTxt2ImgOptions::with_mask_resize_filter) (nearest-neighbor by default), and
+	/// the known region is re-imposed after each step (see [`InpaintOptions::with_known_region_blending`]), so pixels
+	/// outside the mask are exactly those of `image`.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// # let mut scheduler = EulerDiscreteScheduler::default();
+	/// // pyke-diffusers.toml has `inpaint = "unet-inpainting.onnx"` in its `[unet]` section
+	/// let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+	/// let options = StableDiffusionTxt2ImgOptions::default().with_prompt("a red fox sitting on a bench");
+	/// let output = pipeline.inpaint_with_mask_channel(&mut scheduler, &image::open("photo.png")?, &image::open("mask.png")?, options)?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn inpaint_with_mask_channel<S: DiffusionScheduler>(
+		&self,
+		scheduler: &mut S,
+		image: &DynamicImage,
+		mask: &DynamicImage,
+		options: StableDiffusionTxt2ImgOptions
+	) -> anyhow::Result<StableDiffusionOutput> {
+		let path = self.inpaint_unet_path.as_ref().ok_or_else(|| {
+			anyhow::anyhow!("the model config has no inpainting UNet; set `inpaint` in its `[unet]` section to the path of a 9-channel inpainting UNet")
+		})?;
+		let mask = binarize_mask(mask);
+		let filter = options.mask_resize_filter;
+		self.run_inpaint(scheduler, image, &mask, options, || {
+			check_free_memory(&self.options, "inpainting UNet", path)?;
+			let unet = load_session(
+				self.execution_context(),
+				&self.options.devices.unet,
+				"inpainting UNet",
+				ModelSource::File(path),
+				self.options.max_resident_bytes,
+				None
+			)?;
+			match latent_in_channels(&unet) {
+				Some(INPAINT_UNET_IN_CHANNELS) => {}
+				Some(channels) => {
+					anyhow::bail!("the inpainting UNet at `{}` has {channels} input channels, expected {INPAINT_UNET_IN_CHANNELS}", path.display())
+				}
+				None => anyhow::bail!("the inpainting UNet at `{}` has a dynamic number of input channels", path.display())
+			}
+
+			let mut inpaint = InpaintOptions::from_images_for(self, image, &mask, filter, Some(INPAINT_UNET_IN_CHANNELS))?.with_known_region_blending(true);
+			inpaint.unet = Some(Arc::new(unet));
+			Ok(inpaint)
+		})
+	}
+
+	/// Generates images at the size of `image` with the inpainting options created by `inpaint`, and composites the
+	/// decoded images onto `image` according to `mask`. `inpaint` is only called once the options were checked.
+	fn run_inpaint<S: DiffusionScheduler>(
+		&self,
+		scheduler: &mut S,
+		image: &DynamicImage,
+		mask: &DynamicImage,
+		options: StableDiffusionTxt2ImgOptions,
+		inpaint: impl FnOnce() -> anyhow::Result<InpaintOptions>
+	) -> anyhow::Result<StableDiffusionOutput> {
+		if options.decode_to_disk.is_some() {
+			anyhow::bail!("inpainting cannot be combined with `decode_to_disk`, since decoded images are composited onto the image");
+		}
+		let mut output = options.with_size(image.width(), image.height()).with_inpaint(inpaint()?).run_with_output(self, scheduler)?;
+		for generated in output.images.iter_mut() {
+			if let ImageRef::InMemory(generated) = generated {
+				*generated = composite_inpaint_result(image, generated, mask, 0.0)?;
+			}
+		}
+		Ok(output)
+	}
+}
+
+/// Binarizes a mask image at `0.5`: pixels at least half white become white (regenerate), the rest black (keep).
+fn binarize_mask(mask: &DynamicImage) -> DynamicImage {
+	let mask = mask.to_luma32f();
+	DynamicImage::ImageLuma8(GrayImage::from_fn(mask.width(), mask.height(), |x, y| Luma([if mask.get_pixel(x, y).0[0] >= 0.5 { 255 } else { 0 }])))
+}
+
+/// How strongly init latents are re-imposed on each denoising step; see [`InpaintOptions::with_strength_schedule`].
+///
+/// Strengths range from `0.0` (the latents are left alone) to `1.0` (known latents are replaced entirely). Steps are
+/// counted over the scheduler's timesteps, including any skipped by resuming from a checkpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StrengthSchedule {
+	/// The same strength on every step.
+	Constant(f32),
+	/// A strength interpolated linearly from `start` after the first step to `end` after the last step.
+	Linear { start: f32, end: f32 },
+	/// One strength per step; must have exactly one entry per timestep of the scheduler.
+	PerStep(Vec<f32>)
+}
+
+impl Default for StrengthSchedule {
+	fn default() -> Self {
+		StrengthSchedule::Constant(1.0)
+	}
+}
+
+impl StrengthSchedule {
+	/// Returns the strength after step `step` of `steps`.
+	pub fn strength(&self, step: usize, steps: usize) -> f32 {
+		match self {
+			StrengthSchedule::Constant(strength) => *strength,
+			StrengthSchedule::Linear { start, end } if steps > 1 => start + (end - start) * step as f32 / (steps - 1) as f32,
+			StrengthSchedule::Linear { start, .. } => *start,
+			StrengthSchedule::PerStep(strengths) => strengths.get(step).or(strengths.last()).copied().unwrap_or(1.0)
+		}
+	}
+
+	pub(crate) fn validate(&self, steps: usize) -> anyhow::Result<()> {
+		let strengths = match self {
+			StrengthSchedule::Constant(strength) => vec![*strength],
+			StrengthSchedule::Linear { start, end } => vec![*start, *end],
+			StrengthSchedule::PerStep(strengths) => {
+				if strengths.len() != steps {
+					anyhow::bail!("strength schedule has {} entries, expected one per step ({steps})", strengths.len());
+				}
+				strengths.clone()
+			}
+		};
+		if let Some(strength) = strengths.iter().find(|strength| !(0.0..=1.0).contains(*strength)) {
+			anyhow::bail!("strengths must be within [0, 1], got {strength}");
+		}
+		Ok(())
+	}
+}
+
+/// Converts a mask image to a latent-resolution inpainting mask of shape `(1, height / 8, width / 8)` for an image of
+/// the given size. The mask is converted to grayscale and resized to the latent resolution with nearest-neighbor
+/// sampling, so white (`1.0`) marks the region to regenerate, black (`0.0`) the region to keep, and gray values at
+/// soft edges blend between the two. Nearest-neighbor sampling never smears the mask's edges into the region to keep.
+pub fn prepare_inpaint_mask(mask: &DynamicImage, width: u32, height: u32) -> Array3<f32> {
+	prepare_inpaint_mask_with_filter(mask, width, height, ResizeFilter::Nearest)
+}
+
+/// Converts a mask image to a latent-resolution inpainting mask like [`prepare_inpaint_mask`], resizing it with
+/// `filter`. Smooth filters like [`ResizeFilter::Triangle`] feather the mask's edges into soft blends.
+pub fn prepare_inpaint_mask_with_filter(mask: &DynamicImage, width: u32, height: u32, filter: ResizeFilter) -> Array3<f32> {
+	let (latent_width, latent_height) = ((width / 8).max(1), (height / 8).max(1));
+	let mask = filter.resize(mask, latent_width, latent_height).to_luma32f();
+	Array3::from_shape_fn((1, latent_height as usize, latent_width as usize), |(_, y, x)| mask.get_pixel(x as u32, y as u32).0[0].clamp(0.0, 1.0))
+}
+
+/// Checks that inpainting is possible with a UNet with the given number of input channels, and returns whether it is
+/// a dedicated 9-channel inpainting UNet, which needs masked image latents. Standard 4-channel UNets (or UNets whose
+/// channel dimension is dynamic) inpaint via legacy latent blending.
+pub(crate) fn validate_inpaint_unet(in_channels: Option<u32>, has_masked_image_latents: bool) -> anyhow::Result<bool> {
+	match in_channels {
+		Some(INPAINT_UNET_IN_CHANNELS) if has_masked_image_latents => Ok(true),
+		Some(INPAINT_UNET_IN_CHANNELS) => anyhow::bail!(
+			"dedicated inpainting UNets ({INPAINT_UNET_IN_CHANNELS} input channels) need masked image latents; see `InpaintOptions::with_masked_image_latents`"
+		),
+		Some(4) | None => Ok(false),
+		Some(channels) => anyhow::bail!("cannot inpaint with a UNet with {channels} input channels")
+	}
+}
+
+/// Checks that inpainting is possible like [`validate_inpaint_unet`]. Legacy latent blending is logged as a warning
+/// since dedicated inpainting models produce better results.
+pub(crate) fn check_inpaint_unet(in_channels: Option<u32>, has_masked_image_latents: bool) -> anyhow::Result<bool> {
+	let dedicated = validate_inpaint_unet(in_channels, has_masked_image_latents)?;
+	if !dedicated {
+		tracing::warn!("inpainting with a standard UNet via latent blending; results may show seams, a dedicated inpainting model gives better results");
+	}
+	Ok(dedicated)
+}
+
+/// Builds the input of a dedicated inpainting UNet: the (scaled) latent model input, followed by the mask & the
+/// masked image latents along the channel axis. The mask & masked image latents are repeated to the batch size of the
+/// latent model input, which is doubled with classifier-free guidance.
+pub(crate) fn inpaint_unet_input(latent_model_input: ArrayView4<'_, f32>, mask: ArrayView3<'_, f32>, masked_image_latents: ArrayView4<'_, f32>) -> Array4<f32> {
+	let batch_size = latent_model_input.shape()[0];
+	let mask = repeat_to_batch(mask.insert_axis(Axis(1)), batch_size);
+	let masked_image_latents = repeat_to_batch(masked_image_latents, batch_size);
+	concatenate![Axis(1), latent_model_input, mask, masked_image_latents]
+}
+
+fn repeat_to_batch(array: ArrayView4<'_, f32>, batch_size: usize) -> Array4<f32> {
+	let (batch, channels, height, width) = array.dim();
+	if batch == 1 {
+		array.broadcast((batch_size, channels, height, width)).expect("inpainting inputs were validated").to_owned()
+	} else {
+		concatenate(Axis(0), &vec![array; batch_size / batch]).expect("inpainting inputs were validated")
+	}
+}
+
+/// Re-imposes the known (unmasked) region after a scheduler step with the given strength. `timestep` is the timestep
+/// the latents will be denoised at next, or `None` after the final step, in which case the image latents are imposed
+/// without noise.
+pub(crate) fn reimpose_known_region<S: DiffusionScheduler>(
+	scheduler: &mut S,
+	latents: ArrayView4<'_, f32>,
+	init_latents: ArrayView4<'_, f32>,
+	noise: ArrayView4<'_, f32>,
+	mask: ArrayView3<'_, f32>,
+	timestep: Option<S::TimestepType>,
+	strength: f32
+) -> Array4<f32> {
+	let init_latents = init_latents.broadcast(latents.raw_dim()).expect("init latents were validated");
+	let known = match timestep {
+		Some(timestep) => scheduler.add_noise(init_latents, noise, timestep),
+		None => init_latents.to_owned()
+	};
+	let keep_mask = (1.0 - &mask) * strength;
+	blend_latents(latents, known.view(), keep_mask.view())
+}
+
+#[cfg(test)]
+mod tests {
+	use image::{DynamicImage, GrayImage, Luma};
+	use ndarray::{Array3, Array4};
+
+	use super::{binarize_mask, check_inpaint_unet, inpaint_unet_input, prepare_inpaint_mask, StrengthSchedule};
+
+	#[test]
+	fn mask_is_downsampled_to_latent_resolution() {
+		// right half white
+		let mask = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 32, |x, _| Luma([if x >= 32 { 255 } else { 0 }])));
+		let mask = prepare_inpaint_mask(&mask, 64, 32);
+		assert_eq!(mask.dim(), (1, 4, 8));
+		assert_eq!(mask[[0, 2, 0]], 0.0);
+		assert_eq!(mask[[0, 2, 7]], 1.0);
+	}
+
+	#[test]
+	fn binarized_mask_is_hard_edged() {
+		let mask = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 8, |x, _| Luma([(x * 4) as u8])));
+		let mask = prepare_inpaint_mask(&binarize_mask(&mask), 64, 8);
+		assert!(mask.iter().all(|&m| m == 0.0 || m == 1.0));
+		assert_eq!(mask[[0, 0, 3]], 0.0);
+		assert_eq!(mask[[0, 0, 4]], 1.0);
+	}
+
+	#[test]
+	fn unet_channels() {
+		assert!(!check_inpaint_unet(Some(4), false).unwrap());
+		assert!(!check_inpaint_unet(None, false).unwrap());
+		// standard UNets ignore masked image latents
+		assert!(!check_inpaint_unet(Some(4), true).unwrap());
+		assert!(check_inpaint_unet(Some(9), true).unwrap());
+		assert!(check_inpaint_unet(Some(9), false).is_err());
+		assert!(check_inpaint_unet(Some(8), true).is_err());
+	}
+
+	#[test]
+	fn dedicated_unet_input() {
+		let latent_model_input = Array4::from_elem((4, 4, 2, 3), 1.0);
+		let mask = Array3::from_shape_fn((2, 2, 3), |(b, _, x)| if x == b { 1.0 } else { 0.0 });
+		let masked_image_latents = Array4::from_elem((1, 4, 2, 3), -1.0);
+		let input = inpaint_unet_input(latent_model_input.view(), mask.view(), masked_image_latents.view());
+		assert_eq!(input.dim(), (4, 9, 2, 3));
+		assert_eq!(input[[3, 3, 1, 2]], 1.0);
+		assert_eq!(input[[3, 8, 1, 2]], -1.0);
+		// the mask is repeated for the unconditional & conditional halves of the batch
+		for b in 0..4 {
+			assert_eq!(input[[b, 4, 0, b % 2]], 1.0);
+			assert_eq!(input[[b, 4, 0, 2]], 0.0);
+		}
+	}
+
+	#[test]
+	fn strength_schedules() {
+		let linear = StrengthSchedule::Linear { start: 1.0, end: 0.0 };
+		assert_eq!((0..5).map(|step| linear.strength(step, 5)).collect::<Vec<_>>(), [1.0, 0.75, 0.5, 0.25, 0.0]);
+		assert_eq!(linear.strength(0, 1), 1.0);
+		assert_eq!(StrengthSchedule::default().strength(3, 5), 1.0);
+		assert_eq!(StrengthSchedule::PerStep(vec![0.2, 0.4]).strength(1, 2), 0.4);
+
+		assert!(linear.validate(5).is_ok());
+		assert!(StrengthSchedule::PerStep(vec![0.2, 0.4]).validate(3).is_err());
+		assert!(StrengthSchedule::Constant(1.5).validate(3).is_err());
+		assert!(StrengthSchedule::Linear { start: f32::NAN, end: 0.0 }.validate(3).is_err());
+	}
+
+	#[test]
+	#[cfg(feature = "scheduler-euler")]
+	fn known_region_tracks_noised_init_latents() {
+		use super::reimpose_known_region;
+		use crate::{DiffusionScheduler, EulerDiscreteScheduler};
+
+		let mut scheduler = EulerDiscreteScheduler::default();
+		scheduler.set_timesteps(10);
+		let timestep = scheduler.timesteps()[3];
+
+		let latents = Array4::from_elem((2, 4, 2, 3), 5.0);
+		let init_latents = Array4::from_shape_fn((1, 4, 2, 3), |(_, c, y, x)| (c * 6 + y * 3 + x) as f32 / 24.0);
+		let noise = Array4::from_shape_fn((2, 4, 2, 3), |(b, c, y, x)| ((b + c + y + x) % 3) as f32 - 1.0);
+		// regenerate the right column only
+		let mask = Array3::from_shape_fn((1, 2, 3), |(_, _, x)| if x == 2 { 1.0 } else { 0.0 });
+
+		let expected = scheduler.add_noise(init_latents.broadcast((2, 4, 2, 3)).unwrap(), noise.view(), timestep);
+		let out = reimpose_known_region(&mut scheduler, latents.view(), init_latents.view(), noise.view(), mask.view(), Some(timestep), 1.0);
+		for ((b, c, y, x), &value) in out.indexed_iter() {
+			let expected = if x == 2 { 5.0 } else { expected[[b, c, y, x]] };
+			assert!((value - expected).abs() < 1e-6, "{value} != {expected} at {:?}", (b, c, y, x));
+		}
+
+		// after the final step, the known region is exactly the init latents
+		let out = reimpose_known_region(&mut scheduler, latents.view(), init_latents.view(), noise.view(), mask.view(), None, 1.0);
+		assert_eq!(out[[1, 3, 1, 0]], init_latents[[0, 3, 1, 0]]);
+		assert_eq!(out[[1, 3, 1, 2]], 5.0);
+	}
+}
@@ -0,0 +1,197 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use super::impl_txt2img::latents_shape;
+
+/// Limits on the resources a single generation may use, e.g. to serve untrusted requests; see
+/// [`StableDiffusionOptions::with_limits`](crate::StableDiffusionOptions::with_limits).
+///
+/// Limits are checked against the size a generation actually runs at, before any model runs or latents are allocated,
+/// so they also bound sizes derived by other features: the padded size of
+/// [`DimensionPolicy::PadAndCrop`](crate::DimensionPolicy::PadAndCrop), the full canvas of
+/// [MultiDiffusion](crate::MultiDiffusionOptions), the upscaled size of
+/// [`StableDiffusionPipeline::super_resolve`](crate::StableDiffusionPipeline::super_resolve), the size taken from
+/// reference images in image-to-image generation, and the steps re-run by
+/// [Restart sampling](crate::StableDiffusionTxt2ImgOptions::with_restart_schedule). Each frame of an
+/// [animation](crate::StableDiffusionPipeline::animate) is checked like a generation, and the number of frames is
+/// bounded as well. A generation exceeding a limit fails with a [`LimitExceeded`] error.
+///
+/// Limits are unset by default; see [`GenerationLimits::untrusted_input`] for a conservative profile.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenerationLimits {
+	/// The maximum width of generated images, in pixels.
+	pub max_width: Option<u32>,
+	/// The maximum height of generated images, in pixels.
+	pub max_height: Option<u32>,
+	/// The maximum number of images generated at once, i.e. prompts times images per prompt.
+	pub max_batch_size: Option<usize>,
+	/// The maximum number of denoising steps.
+	pub max_steps: Option<usize>,
+	/// The maximum number of elements of the latents of a whole batch, `batch_size * 4 * (height / 8) * (width / 8)`,
+	/// which bounds the memory & compute of each step independently of how it is split into size & batch size.
+	pub max_latent_elements: Option<usize>,
+	/// The maximum number of frames of an [animation](crate::StableDiffusionPipeline::animate), including its
+	/// keyframes.
+	pub max_frames: Option<usize>
+}
+
+impl GenerationLimits {
+	/// Creates limits which allow any generation.
+	pub fn unlimited() -> Self {
+		Self::default()
+	}
+
+	/// A conservative profile for generations requested by untrusted users: images of up to 1024x1024 pixels, batches
+	/// of up to 4 images, up to 150 steps, latents of up to 65536 elements, i.e. one 1024x1024 image or four
+	/// 512x512 images, and animations of up to 240 frames.
+	pub fn untrusted_input() -> Self {
+		Self {
+			max_width: Some(1024),
+			max_height: Some(1024),
+			max_batch_size: Some(4),
+			max_steps: Some(150),
+			max_latent_elements: Some(65536),
+			max_frames: Some(240)
+		}
+	}
+
+	/// Sets the maximum width & height of generated images, in pixels.
+	pub fn with_max_size(mut self, max_width: u32, max_height: u32) -> Self {
+		self.max_width = Some(max_width);
+		self.max_height = Some(max_height);
+		self
+	}
+
+	/// Sets the maximum number of images generated at once.
+	pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+		self.max_batch_size = Some(max_batch_size);
+		self
+	}
+
+	/// Sets the maximum number of denoising steps.
+	pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+		self.max_steps = Some(max_steps);
+		self
+	}
+
+	/// Sets the maximum number of elements of a batch's latents.
+	pub fn with_max_latent_elements(mut self, max_latent_elements: usize) -> Self {
+		self.max_latent_elements = Some(max_latent_elements);
+		self
+	}
+
+	/// Sets the maximum number of frames of an animation.
+	pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+		self.max_frames = Some(max_frames);
+		self
+	}
+
+	/// Checks an animation of `frames` frames against the limits.
+	pub(crate) fn check_frames(&self, frames: usize) -> Result<(), LimitExceeded> {
+		match self.max_frames {
+			Some(max) if frames > max => Err(LimitExceeded { limit: GenerationLimit::Frames, requested: frames, max }),
+			_ => Ok(())
+		}
+	}
+
+	/// Checks a generation of `batch_size` images of `width`x`height` pixels taking `steps` steps against the limits.
+	pub(crate) fn check(&self, width: u32, height: u32, batch_size: usize, steps: usize) -> Result<(), LimitExceeded> {
+		let (batch_size, channels, latent_height, latent_width) = latents_shape(batch_size, height, width);
+		let latent_elements = batch_size.saturating_mul(channels).saturating_mul(latent_height).saturating_mul(latent_width);
+		let checks = [
+			(GenerationLimit::Width, width as usize, self.max_width.map(|max| max as usize)),
+			(GenerationLimit::Height, height as usize, self.max_height.map(|max| max as usize)),
+			(GenerationLimit::BatchSize, batch_size, self.max_batch_size),
+			(GenerationLimit::Steps, steps, self.max_steps),
+			(GenerationLimit::LatentElements, latent_elements, self.max_latent_elements)
+		];
+		match checks.into_iter().find(|&(_, requested, max)| max.map_or(false, |max| requested > max)) {
+			Some((limit, requested, Some(max))) => Err(LimitExceeded { limit, requested, max }),
+			_ => Ok(())
+		}
+	}
+}
+
+/// A limit of [`GenerationLimits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenerationLimit {
+	/// [`GenerationLimits::max_width`].
+	Width,
+	/// [`GenerationLimits::max_height`].
+	Height,
+	/// [`GenerationLimits::max_batch_size`].
+	BatchSize,
+	/// [`GenerationLimits::max_steps`].
+	Steps,
+	/// [`GenerationLimits::max_latent_elements`].
+	LatentElements,
+	/// [`GenerationLimits::max_frames`].
+	Frames
+}
+
+impl fmt::Display for GenerationLimit {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			GenerationLimit::Width => "width",
+			GenerationLimit::Height => "height",
+			GenerationLimit::BatchSize => "batch size",
+			GenerationLimit::Steps => "number of steps",
+			GenerationLimit::LatentElements => "number of latent elements",
+			GenerationLimit::Frames => "number of frames"
+		})
+	}
+}
+
+/// Error returned when a generation exceeds one of the pipeline's [`GenerationLimits`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitExceeded {
+	/// The limit that was exceeded.
+	pub limit: GenerationLimit,
+	/// The value the generation would have used.
+	pub requested: usize,
+	/// The configured limit.
+	pub max: usize
+}
+
+impl fmt::Display for LimitExceeded {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "the requested {} of {} exceeds the limit of {}", self.limit, self.requested, self.max)
+	}
+}
+
+impl std::error::Error for LimitExceeded {}
+
+#[cfg(test)]
+mod tests {
+	use super::{GenerationLimit, GenerationLimits, LimitExceeded};
+
+	#[test]
+	fn limits() {
+		let limits = GenerationLimits::untrusted_input();
+		assert_eq!(limits.check(1024, 1024, 1, 150), Ok(()));
+		assert_eq!(limits.check(512, 512, 4, 20), Ok(()));
+		assert_eq!(limits.check(1032, 512, 1, 20), Err(LimitExceeded { limit: GenerationLimit::Width, requested: 1032, max: 1024 }));
+		assert_eq!(limits.check(512, 512, 5, 20).unwrap_err().limit, GenerationLimit::BatchSize);
+		assert_eq!(limits.check(512, 512, 1, 151).unwrap_err().limit, GenerationLimit::Steps);
+		// within every other limit, but too large as a whole
+		assert_eq!(limits.check(768, 768, 4, 20), Err(LimitExceeded { limit: GenerationLimit::LatentElements, requested: 147456, max: 65536 }));
+		assert_eq!(GenerationLimits::unlimited().check(u32::MAX, u32::MAX, usize::MAX, usize::MAX), Ok(()));
+
+		assert_eq!(limits.check_frames(240), Ok(()));
+		assert_eq!(limits.check_frames(241), Err(LimitExceeded { limit: GenerationLimit::Frames, requested: 241, max: 240 }));
+		assert_eq!(GenerationLimits::unlimited().check_frames(usize::MAX), Ok(()));
+	}
+}
@@ -12,9 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::num::ParseFloatError;
+//! Parsing of prompt weighting syntax, e.g. `(word)`, `[word]` & `(word:1.2)`; see [`PromptWeighting::Weighted`].
 
-use ndarray::{s, Array2, Array3, Axis, NewAxis};
+use std::fmt::{self, Write as _};
+
+use ndarray::{concatenate, s, Array2, Array3, Axis, NewAxis};
 use once_cell::sync::Lazy;
 use ort::{OrtResult, Session, Value};
 use regex::Regex;
@@ -42,17 +44,63 @@ static RE_ATTENTION: Lazy<Regex> = Lazy::new(|| {
 	.unwrap()
 });
 
+/// The largest weight a token can be given. Larger weights, and products of nested weights exceeding it, are clamped to
+/// it, so that no prompt can produce infinite or NaN embeddings.
+pub const MAX_WEIGHT: f32 = 100.0;
+
+const ROUND_BRACKET_MULTIPLIER: f32 = 1.1;
+const SQUARE_BRACKET_MULTIPLIER: f32 = 1.0 / 1.1;
+
 type LpwTokens = Vec<Vec<u32>>;
 type LpwWeights = Vec<Vec<f32>>;
 
-fn parse_prompt_attention(text: impl AsRef<str>) -> Result<Vec<(String, f32)>, ParseFloatError> {
+/// How text embeddings are renormalized after applying prompt weights.
+///
+/// Multiplying embeddings by emphasis weights changes their overall magnitude, which the UNet is sensitive to, so most
+/// implementations rescale the weighted embeddings so that their mean matches the mean of the unweighted embeddings.
+/// The implementations differ in the scope of the mean, which noticeably changes the result of heavily weighted
+/// prompts.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeightNormalization {
+	/// Don't renormalize; embeddings are multiplied by their weights as-is.
+	None,
+	/// Match the mean of each 75-token chunk of each prompt separately. This matches AUTOMATIC1111's web UI.
+	PerChunk,
+	/// Match the mean of each prompt as a whole. This matches the Python `lpw_stable_diffusion` diffusers community
+	/// pipeline. **This is the default.**
+	#[default]
+	WholePrompt
+}
+
+/// How the text of a prompt is encoded; see
+/// [`StableDiffusionTxt2ImgOptions::with_prompt_weighting`](crate::StableDiffusionTxt2ImgOptions::with_prompt_weighting).
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptWeighting {
+	/// Parse weighting syntax like `(word)`, `[word]` & `(word:1.2)`, and encode prompts longer than the tokenizer's
+	/// maximum length in chunks. This matches the Python `lpw_stable_diffusion` diffusers community pipeline. **This is
+	/// the default.**
+	#[default]
+	Weighted,
+	/// Encode the text literally, so brackets & colons are kept as-is, and truncate it to the tokenizer's maximum
+	/// length. This matches the standard diffusers pipeline.
+	Plain
+}
+
+/// Multiplies the weights of `res[start..]` by `multiplier`, clamping them to [`MAX_WEIGHT`].
+fn multiply_range(res: &mut [(String, f32)], start: usize, multiplier: f32) {
+	for (_, weight) in &mut res[start..] {
+		*weight = (*weight * multiplier).min(MAX_WEIGHT);
+	}
+}
+
+/// Parses weighting syntax into `(text, weight)` pairs. Malformed weights like `(word:.)` and closing brackets which
+/// close nothing are kept as literal text, and brackets which are never closed apply to the rest of the prompt; see
+/// [`sanitize`]. Weights are clamped to `0.0..=`[`MAX_WEIGHT`].
+fn parse_prompt_attention(text: impl AsRef<str>) -> Vec<(String, f32)> {
 	let mut res: Vec<(String, f32)> = Vec::new();
 	let mut round_brackets = Vec::new();
 	let mut square_brackets = Vec::new();
 
-	const ROUND_BRACKET_MULTIPLIER: f32 = 1.1;
-	const SQUARE_BRACKET_MULTIPLIER: f32 = 1.0 / 1.1;
-
 	for m in RE_ATTENTION.captures_iter(text.as_ref()) {
 		let text = m.get(0).map_or("", |m| m.as_str());
 		let weight = m.get(1).map(|m| m.as_str());
@@ -65,19 +113,12 @@ fn parse_prompt_attention(text: impl AsRef<str>) -> Result<Vec<(String, f32)>, P
 			round_brackets.push(rlen);
 		} else if text == "[" {
 			square_brackets.push(rlen);
-		} else if weight.is_some() && !round_brackets.is_empty() {
-			let multiplier = weight.unwrap().parse::<f32>()?.max(0.0);
-			for i in res.iter_mut().take(rlen).skip(round_brackets.pop().unwrap()) {
-				i.1 *= multiplier;
-			}
+		} else if let (Some(Ok(multiplier)), false) = (weight.map(str::parse::<f32>), round_brackets.is_empty()) {
+			multiply_range(&mut res, round_brackets.pop().unwrap(), multiplier.clamp(0.0, MAX_WEIGHT));
 		} else if text == ")" && !round_brackets.is_empty() {
-			for i in res.iter_mut().take(rlen).skip(round_brackets.pop().unwrap()) {
-				i.1 *= ROUND_BRACKET_MULTIPLIER;
-			}
-		} else if text == ")" && !square_brackets.is_empty() {
-			for i in res.iter_mut().take(rlen).skip(square_brackets.pop().unwrap()) {
-				i.1 *= SQUARE_BRACKET_MULTIPLIER;
-			}
+			multiply_range(&mut res, round_brackets.pop().unwrap(), ROUND_BRACKET_MULTIPLIER);
+		} else if text == "]" && !square_brackets.is_empty() {
+			multiply_range(&mut res, square_brackets.pop().unwrap(), SQUARE_BRACKET_MULTIPLIER);
 		} else {
 			res.push((text.to_owned(), 1.0));
 		}
@@ -85,15 +126,11 @@ fn parse_prompt_attention(text: impl AsRef<str>) -> Result<Vec<(String, f32)>, P
 
 	// process remaining
 	let rlen = res.len();
-	for pos in round_brackets.iter() {
-		for i in res.iter_mut().take(rlen).skip(*pos) {
-			i.1 *= ROUND_BRACKET_MULTIPLIER;
-		}
+	for &pos in round_brackets.iter() {
+		multiply_range(&mut res, pos, ROUND_BRACKET_MULTIPLIER);
 	}
-	for pos in square_brackets.iter() {
-		for i in res.iter_mut().take(rlen).skip(*pos) {
-			i.1 *= SQUARE_BRACKET_MULTIPLIER;
-		}
+	for &pos in square_brackets.iter() {
+		multiply_range(&mut res, pos, SQUARE_BRACKET_MULTIPLIER);
 	}
 
 	if rlen == 0 {
@@ -111,29 +148,172 @@ fn parse_prompt_attention(text: impl AsRef<str>) -> Result<Vec<(String, f32)>, P
 		}
 	}
 
-	Ok(res)
+	res
+}
+
+/// A problem with a prompt's weighting syntax found by [`sanitize`]. Positions are byte offsets into the prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PromptSyntaxWarning {
+	/// A `(` or `[` is never closed, so it applies to the rest of the prompt.
+	UnclosedBracket {
+		/// The position of the bracket.
+		position: usize
+	},
+	/// A `)` or `]` doesn't close any bracket, so it is literal text.
+	UnmatchedBracket {
+		/// The position of the bracket.
+		position: usize
+	},
+	/// The weight of a `(text:weight)` group is not a number, so it is literal text, and the group's `(` is never
+	/// closed.
+	InvalidWeight {
+		/// The position of the weight's `:`.
+		position: usize,
+		/// The weight as written.
+		weight: String
+	},
+	/// The weight of a `(text:weight)` group is outside of `0.0..=`[`MAX_WEIGHT`], so it is clamped.
+	ClampedWeight {
+		/// The position of the weight's `:`.
+		position: usize,
+		/// The weight as written.
+		weight: f32,
+		/// The weight which is used instead.
+		clamped: f32
+	}
+}
+
+impl fmt::Display for PromptSyntaxWarning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			PromptSyntaxWarning::UnclosedBracket { position } => write!(f, "bracket at {position} is never closed and applies to the rest of the prompt"),
+			PromptSyntaxWarning::UnmatchedBracket { position } => write!(f, "bracket at {position} closes nothing and is treated as text"),
+			PromptSyntaxWarning::InvalidWeight { position, weight } => write!(f, "weight `{weight}` at {position} is not a number and is treated as text"),
+			PromptSyntaxWarning::ClampedWeight { position, weight, clamped } => {
+				write!(f, "weight {weight} at {position} is out of range and is clamped to {clamped}")
+			}
+		}
+	}
+}
+
+/// Checks a prompt's weighting syntax, returning an equivalent prompt without ambiguous syntax & a warning for each
+/// problem found, e.g. to pre-validate user input. Prompts are always encoded as if they were sanitized first, so
+/// sanitizing never changes how a prompt is weighted; it only makes the fallbacks explicit:
+///
+/// - brackets which are never closed are closed at the end of the prompt,
+/// - closing brackets which close nothing, and the `)` of weights which aren't numbers, are escaped (`\)`),
+/// - weights outside of `0.0..=`[`MAX_WEIGHT`] are replaced with the clamped weight, and
+/// - backslashes which don't escape anything (and are therefore ignored) are removed.
+///
+/// Sanitizing a sanitized prompt returns it unchanged, without warnings.
+///
+/// ```
+/// # use pyke_diffusers::lpw::{sanitize, PromptSyntaxWarning};
+/// let (clean, warnings) = sanitize("a (red:1.5) fox) in [the snow");
+/// assert_eq!(clean, "a (red:1.5) fox\\) in [the snow]");
+/// assert_eq!(warnings, [PromptSyntaxWarning::UnmatchedBracket { position: 15 }, PromptSyntaxWarning::UnclosedBracket { position: 20 }]);
+/// ```
+pub fn sanitize(prompt: &str) -> (String, Vec<PromptSyntaxWarning>) {
+	let mut clean = String::with_capacity(prompt.len());
+	let mut warnings = Vec::new();
+	let mut round_brackets = Vec::new();
+	let mut square_brackets = Vec::new();
+
+	// mirrors `parse_prompt_attention`
+	for m in RE_ATTENTION.captures_iter(prompt) {
+		let (text, position) = m.get(0).map_or(("", 0), |m| (m.as_str(), m.start()));
+		let weight = m.get(1).map(|m| m.as_str());
+
+		if text == "\\" {
+			// escapes nothing
+		} else if text.starts_with('\\') {
+			clean.push_str(text);
+		} else if text == "(" {
+			round_brackets.push(position);
+			clean.push_str(text);
+		} else if text == "[" {
+			square_brackets.push(position);
+			clean.push_str(text);
+		} else if let Some(weight) = weight {
+			let literal = &text[..text.len() - 1];
+			match weight.parse::<f32>() {
+				_ if round_brackets.is_empty() => {
+					warnings.push(PromptSyntaxWarning::UnmatchedBracket { position: position + literal.len() });
+					let _ = write!(clean, "{literal}\\)");
+				}
+				Ok(multiplier) => {
+					round_brackets.pop();
+					let clamped = multiplier.clamp(0.0, MAX_WEIGHT);
+					if clamped == multiplier {
+						clean.push_str(text);
+					} else {
+						warnings.push(PromptSyntaxWarning::ClampedWeight { position, weight: multiplier, clamped });
+						let _ = write!(clean, ":{clamped})");
+					}
+				}
+				Err(_) => {
+					warnings.push(PromptSyntaxWarning::InvalidWeight { position, weight: weight.to_owned() });
+					let _ = write!(clean, "{literal}\\)");
+				}
+			}
+		} else if (text == ")" && round_brackets.pop().is_none()) || (text == "]" && square_brackets.pop().is_none()) {
+			warnings.push(PromptSyntaxWarning::UnmatchedBracket { position });
+			let _ = write!(clean, "\\{text}");
+		} else {
+			clean.push_str(text);
+		}
+	}
+
+	// closing round brackets first applies the remaining weights in the same order as parsing does
+	for (brackets, closing) in [(round_brackets, ')'), (square_brackets, ']')] {
+		for &position in brackets.iter().rev() {
+			warnings.push(PromptSyntaxWarning::UnclosedBracket { position });
+			clean.push(closing);
+		}
+	}
+	warnings.sort_by_key(|warning| match warning {
+		PromptSyntaxWarning::UnclosedBracket { position }
+		| PromptSyntaxWarning::UnmatchedBracket { position }
+		| PromptSyntaxWarning::InvalidWeight { position, .. }
+		| PromptSyntaxWarning::ClampedWeight { position, .. } => *position
+	});
+	(clean, warnings)
 }
 
-fn get_prompts_with_weights(embeddings: &TextEmbeddings, prompts: Prompt, max_length: usize) -> anyhow::Result<(LpwTokens, LpwWeights)> {
+/// Tokenizes each prompt without BOS & EOS tokens, returning the tokens & their weights. `weighting` holds the
+/// weighting of each prompt; prompts beyond its length are [weighted](PromptWeighting::Weighted).
+fn get_prompts_with_weights(
+	embeddings: &TextEmbeddings,
+	prompts: Prompt,
+	weighting: &[PromptWeighting],
+	max_length: usize
+) -> anyhow::Result<(LpwTokens, LpwWeights)> {
 	let mut tokens = vec![];
 	let mut weights = vec![];
-	for prompt in prompts.iter() {
-		let texts_and_weights = parse_prompt_attention(prompt)?;
+	for (i, prompt) in prompts.iter().enumerate() {
+		let (texts_and_weights, max_length) = match weighting.get(i).copied().unwrap_or_default() {
+			PromptWeighting::Weighted => (parse_prompt_attention(prompt), max_length),
+			PromptWeighting::Plain => (vec![(prompt.clone(), 1.0)], max_length.min(embeddings.tokenizer.len() - 2))
+		};
 		let mut text_token = vec![];
 		let mut text_weight = vec![];
 		for (word, weight) in texts_and_weights {
 			let token = &embeddings.tokenizer.encode(vec![word])?[0];
-			let token = &token[1..token.len() - 1];
+			let mut token = token.as_slice();
+			if token.first() == Some(&embeddings.tokenizer.bos()) {
+				token = &token[1..];
+			}
+			if token.last() == Some(&embeddings.tokenizer.eos()) {
+				token = &token[..token.len() - 1];
+			}
 			text_token.extend_from_slice(token);
 			text_weight.extend_from_slice(&[weight].repeat(token.len()));
-			if text_token.len() > max_length {
-				break;
-			}
 		}
 
-		if text_token.len() > max_length {
-			text_token = text_token[..max_length].to_vec();
-			text_weight = text_weight[..max_length].to_vec();
+		let kept = embeddings.tokenizer.truncation_range(&text_token, max_length)?;
+		if kept.len() < text_token.len() {
+			text_token = text_token[kept.clone()].to_vec();
+			text_weight = text_weight[kept].to_vec();
 		}
 
 		tokens.push(text_token);
@@ -171,7 +351,9 @@ fn pad_tokens_and_weights(
 			} else {
 				for j in 0..max_embeddings_multiples {
 					w.push(1.0);
-					w.extend_from_slice(&weights[i][j * (chunk_length - 2)..weights[i].len().min((j + 1) * (chunk_length - 2))]);
+					// prompts shorter than the longest prompt run out of weights before the last chunk
+					let start = weights[i].len().min(j * (chunk_length - 2));
+					w.extend_from_slice(&weights[i][start..weights[i].len().min((j + 1) * (chunk_length - 2))]);
 					w.push(1.0);
 				}
 				w.extend_from_slice(&[1.0].repeat(weights_length - w.len()));
@@ -182,7 +364,7 @@ fn pad_tokens_and_weights(
 	(tokens, weights)
 }
 
-pub fn get_unweighted_text_embeddings(
+pub(crate) fn get_unweighted_text_embeddings(
 	#[cfg_attr(test, allow(unused))] embeddings: &TextEmbeddings,
 	text_encoder: &Session,
 	text_input: Array2<i32>,
@@ -246,19 +428,93 @@ pub fn get_unweighted_text_embeddings(
 	}
 }
 
-pub fn get_weighted_text_embeddings(
+/// Returns `true` if the text encoder can be run with a batch of `batch_size` prompts, i.e. its batch dimension is
+/// dynamic or exactly `batch_size`.
+fn text_encoder_accepts_batch_size(text_encoder: &Session, batch_size: usize) -> bool {
+	match text_encoder.inputs.first().and_then(|input| input.dimensions.first().copied()) {
+		Some(Some(static_batch_size)) => static_batch_size as usize == batch_size,
+		_ => true
+	}
+}
+
+/// Encodes the conditional & unconditional token IDs like [`get_unweighted_text_embeddings`], returning
+/// `(text_embeddings, uncond_embeddings)`.
+///
+/// Both batches are encoded in a single text encoder run to avoid paying the fixed per-run overhead twice. If the
+/// inputs have different lengths, or the text encoder was exported with a static batch dimension that can't fit both
+/// batches, they are encoded separately instead.
+pub(crate) fn get_unweighted_text_embeddings_with_uncond(
 	embeddings: &TextEmbeddings,
 	text_encoder: &Session,
+	text_input: Array2<i32>,
+	uncond_input: Array2<i32>,
+	chunk_length: usize,
+	no_boseos_middle: bool
+) -> OrtResult<(Array3<f32>, Array3<f32>)> {
+	let (uncond_batch_size, uncond_length) = uncond_input.dim();
+	let (batch_size, length) = text_input.dim();
+	if uncond_length != length || !text_encoder_accepts_batch_size(text_encoder, uncond_batch_size + batch_size) {
+		let text_embeddings = get_unweighted_text_embeddings(embeddings, text_encoder, text_input, chunk_length, no_boseos_middle)?;
+		let uncond_embeddings = get_unweighted_text_embeddings(embeddings, text_encoder, uncond_input, chunk_length, no_boseos_middle)?;
+		return Ok((text_embeddings, uncond_embeddings));
+	}
+
+	// batched in the same `[uncond, cond]` order used for classifier-free guidance
+	let combined_input = concatenate![Axis(0), uncond_input, text_input];
+	let combined_embeddings = get_unweighted_text_embeddings(embeddings, text_encoder, combined_input, chunk_length, no_boseos_middle)?;
+	let (uncond_embeddings, text_embeddings) = combined_embeddings.view().split_at(Axis(0), uncond_batch_size);
+	Ok((text_embeddings.to_owned(), uncond_embeddings.to_owned()))
+}
+
+/// Prompts tokenized & padded by [`tokenize_weighted_prompts`], with the weight of each token.
+pub(crate) struct WeightedTokens {
+	tokens: LpwTokens,
+	weights: LpwWeights
+}
+
+impl WeightedTokens {
+	/// Returns the padded length of the prompts in tokens, including BOS & EOS tokens.
+	pub(crate) fn length(&self) -> usize {
+		self.tokens[0].len()
+	}
+
+	fn input(&self) -> anyhow::Result<Array2<i32>> {
+		Ok(Array2::from_shape_vec((self.tokens.len(), self.length()), self.tokens.concat())?.map(|f| *f as i32))
+	}
+
+	fn weights(&self) -> anyhow::Result<Array2<f32>> {
+		Ok(Array2::from_shape_vec((self.weights.len(), self.weights[0].len()), self.weights.concat())?)
+	}
+}
+
+/// Returns the number of tokens of each prompt as tokenized by [`tokenize_weighted_prompts`], without BOS, EOS &
+/// padding tokens.
+pub(crate) fn count_prompt_tokens(
+	embeddings: &TextEmbeddings,
+	prompt: Prompt,
+	weighting: &[PromptWeighting],
+	max_embeddings_multiples: usize
+) -> anyhow::Result<Vec<usize>> {
+	let max_length = (embeddings.tokenizer.len() - 2) * max_embeddings_multiples;
+	let (tokens, _) = get_prompts_with_weights(embeddings, prompt, weighting, max_length)?;
+	Ok(tokens.iter().map(Vec::len).collect())
+}
+
+/// Tokenizes the prompts & negative prompts, padding both to the length of the longest of them, so that their
+/// embeddings can be concatenated for classifier-free guidance.
+pub(crate) fn tokenize_weighted_prompts(
+	embeddings: &TextEmbeddings,
 	prompt: Prompt,
 	neg_prompt: Option<Prompt>,
+	weighting: &[PromptWeighting],
 	max_embeddings_multiples: usize,
 	no_boseos_middle: bool
-) -> anyhow::Result<(Array3<f32>, Option<Array3<f32>>)> {
+) -> anyhow::Result<(WeightedTokens, Option<WeightedTokens>)> {
 	let max_length = (embeddings.tokenizer.len() - 2) * max_embeddings_multiples + 2;
 
-	let (prompt_tokens, prompt_weights) = get_prompts_with_weights(embeddings, prompt, max_length - 2)?;
+	let (prompt_tokens, prompt_weights) = get_prompts_with_weights(embeddings, prompt, weighting, max_length - 2)?;
 	let uncond_ptt = if let Some(neg_prompt) = neg_prompt {
-		Some(get_prompts_with_weights(embeddings, neg_prompt, max_length - 2)?)
+		Some(get_prompts_with_weights(embeddings, neg_prompt, weighting, max_length - 2)?)
 	} else {
 		None
 	};
@@ -274,48 +530,309 @@ pub fn get_weighted_text_embeddings(
 
 	let bos_id = embeddings.tokenizer.bos();
 	let eos_id = embeddings.tokenizer.eos();
-	let (prompt_tokens, prompt_weights) =
-		pad_tokens_and_weights(prompt_tokens, prompt_weights, max_length, bos_id, eos_id, no_boseos_middle, embeddings.tokenizer.len());
-	let uncond_padded = if let Some((uncond_tokens, uncond_weights)) = uncond_ptt {
-		Some(pad_tokens_and_weights(uncond_tokens, uncond_weights, max_length, bos_id, eos_id, no_boseos_middle, embeddings.tokenizer.len()))
-	} else {
-		None
+	let (tokens, weights) = pad_tokens_and_weights(prompt_tokens, prompt_weights, max_length, bos_id, eos_id, no_boseos_middle, embeddings.tokenizer.len());
+	let uncond_padded = uncond_ptt.map(|(uncond_tokens, uncond_weights)| {
+		let (tokens, weights) = pad_tokens_and_weights(uncond_tokens, uncond_weights, max_length, bos_id, eos_id, no_boseos_middle, embeddings.tokenizer.len());
+		WeightedTokens { tokens, weights }
+	});
+	Ok((WeightedTokens { tokens, weights }, uncond_padded))
+}
+
+/// Encodes prompts tokenized by [`tokenize_weighted_prompts`] & applies their weights, returning
+/// `(text_embeddings, uncond_embeddings)` for whichever of the two were given. If both are given, they are encoded in a
+/// single text encoder run where possible, like [`get_unweighted_text_embeddings_with_uncond`].
+pub(crate) fn encode_weighted_tokens(
+	embeddings: &TextEmbeddings,
+	text_encoder: &Session,
+	prompt: Option<&WeightedTokens>,
+	uncond: Option<&WeightedTokens>,
+	no_boseos_middle: bool,
+	weight_normalization: WeightNormalization
+) -> anyhow::Result<(Option<Array3<f32>>, Option<Array3<f32>>)> {
+	let chunk_length = embeddings.tokenizer.len();
+	let (text_embeddings, uncond_embeddings) = match (prompt, uncond) {
+		(Some(prompt), Some(uncond)) => {
+			let (text_embeddings, uncond_embeddings) =
+				get_unweighted_text_embeddings_with_uncond(embeddings, text_encoder, prompt.input()?, uncond.input()?, chunk_length, no_boseos_middle)?;
+			(Some(text_embeddings), Some(uncond_embeddings))
+		}
+		(prompt, uncond) => {
+			let encode = |tokens: Option<&WeightedTokens>| -> anyhow::Result<Option<Array3<f32>>> {
+				match tokens {
+					Some(tokens) => Ok(Some(get_unweighted_text_embeddings(embeddings, text_encoder, tokens.input()?, chunk_length, no_boseos_middle)?)),
+					None => Ok(None)
+				}
+			};
+			(encode(prompt)?, encode(uncond)?)
+		}
 	};
 
-	let text_embeddings = get_unweighted_text_embeddings(
-		embeddings,
-		text_encoder,
-		Array2::from_shape_vec((prompt_tokens.len(), prompt_tokens[0].len()), prompt_tokens.concat())?.map(|f| *f as i32),
-		embeddings.tokenizer.len(),
-		no_boseos_middle
-	)?;
-
-	let previous_mean = text_embeddings.mean_axis(Axis(2)).unwrap().mean_axis(Axis(1)).unwrap();
-	let text_embeddings = text_embeddings
-		* Array2::from_shape_vec((prompt_weights.len(), prompt_weights[0].len()), prompt_weights.concat())?
-			.slice(s![.., .., NewAxis])
-			.to_owned();
-	let current_mean = text_embeddings.mean_axis(Axis(2)).unwrap().mean_axis(Axis(1)).unwrap();
-	let text_embeddings = text_embeddings * (previous_mean / current_mean).insert_axis(Axis(1)).insert_axis(Axis(2));
-
-	let uncond_embeddings = if let Some((uncond_tokens, uncond_weights)) = uncond_padded {
-		let uncond_embeddings = get_unweighted_text_embeddings(
-			embeddings,
-			text_encoder,
-			Array2::from_shape_vec((uncond_tokens.len(), uncond_tokens[0].len()), uncond_tokens.concat())?.map(|f| *f as i32),
-			embeddings.tokenizer.len(),
-			no_boseos_middle
-		)?;
-		let previous_mean = uncond_embeddings.mean_axis(Axis(2)).unwrap().mean_axis(Axis(1)).unwrap();
-		let uncond_embeddings = uncond_embeddings
-			* Array2::from_shape_vec((uncond_weights.len(), uncond_weights[0].len()), uncond_weights.concat())?
-				.slice(s![.., .., NewAxis])
-				.to_owned();
-		let current_mean = uncond_embeddings.mean_axis(Axis(2)).unwrap().mean_axis(Axis(1)).unwrap();
-		Some(uncond_embeddings * (previous_mean / current_mean).insert_axis(Axis(1)).insert_axis(Axis(2)))
-	} else {
-		None
+	let weighted = |text_embeddings: Option<Array3<f32>>, tokens: Option<&WeightedTokens>| -> anyhow::Result<Option<Array3<f32>>> {
+		match (text_embeddings, tokens) {
+			(Some(text_embeddings), Some(tokens)) => Ok(Some(apply_prompt_weights(text_embeddings, tokens.weights()?, weight_normalization, chunk_length))),
+			_ => Ok(None)
+		}
+	};
+	Ok((weighted(text_embeddings, prompt)?, weighted(uncond_embeddings, uncond)?))
+}
+
+/// Multiplies `text_embeddings` (`[batch, tokens, dim]`) by per-token `weights` (`[batch, tokens]`), then renormalizes
+/// the result according to `normalization`. `chunk_length` is the tokenizer's maximum length, including BOS & EOS.
+fn apply_prompt_weights(text_embeddings: Array3<f32>, weights: Array2<f32>, normalization: WeightNormalization, chunk_length: usize) -> Array3<f32> {
+	let mut weighted = &text_embeddings * &weights.slice(s![.., .., NewAxis]);
+	match normalization {
+		WeightNormalization::None => {}
+		WeightNormalization::WholePrompt => {
+			let previous_mean = text_embeddings.mean_axis(Axis(2)).unwrap().mean_axis(Axis(1)).unwrap();
+			let current_mean = weighted.mean_axis(Axis(2)).unwrap().mean_axis(Axis(1)).unwrap();
+			weighted *= &(previous_mean / current_mean).insert_axis(Axis(1)).insert_axis(Axis(2));
+		}
+		WeightNormalization::PerChunk => {
+			// chunks of `chunk_length - 2` prompt tokens; the BOS & EOS tokens belong to the first & last chunk
+			let num_tokens = text_embeddings.shape()[1];
+			let chunk_tokens = chunk_length - 2;
+			let num_chunks = ((num_tokens.saturating_sub(2) + chunk_tokens - 1) / chunk_tokens).max(1);
+			for i in 0..num_chunks {
+				let start = if i == 0 { 0 } else { 1 + i * chunk_tokens };
+				let end = if i == num_chunks - 1 { num_tokens } else { 1 + (i + 1) * chunk_tokens };
+				for (mut weighted, original) in weighted.outer_iter_mut().zip(text_embeddings.outer_iter()) {
+					let previous_mean = original.slice(s![start..end, ..]).mean().unwrap();
+					let mut weighted_chunk = weighted.slice_mut(s![start..end, ..]);
+					let current_mean = weighted_chunk.mean().unwrap();
+					weighted_chunk *= previous_mean / current_mean;
+				}
+			}
+		}
+	}
+	weighted
+}
+
+#[cfg(test)]
+mod tests {
+	use ndarray::{s, Array2, Array3};
+
+	use proptest::prelude::*;
+
+	use super::{
+		apply_prompt_weights, get_unweighted_text_embeddings, get_unweighted_text_embeddings_with_uncond, pad_tokens_and_weights, parse_prompt_attention,
+		sanitize, PromptSyntaxWarning, PromptWeighting, WeightNormalization, MAX_WEIGHT
 	};
+	use crate::{OrtEnvironment, PromptBatchMismatch, StableDiffusionOptions, StableDiffusionPipeline};
+
+	/// A single prompt of 2 chunks (chunk length 4): `[BOS, a, b, c, d, EOS]` with 1-dimensional embeddings.
+	fn embeddings() -> (Array3<f32>, Array2<f32>) {
+		let embeddings = Array3::from_shape_vec((1, 6, 1), vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+		let weights = Array2::from_shape_vec((1, 6), vec![1.0, 2.0, 1.0, 1.0, 0.5, 1.0]).unwrap();
+		(embeddings, weights)
+	}
+
+	fn assert_close(actual: Array3<f32>, expected: &[f32]) {
+		for (a, e) in actual.iter().zip(expected) {
+			assert!((a - e).abs() < 1e-5, "expected {expected:?}, got {actual:?}");
+		}
+	}
+
+	#[test]
+	fn pad_prompts_of_different_lengths() {
+		// chunks of 2 tokens (chunk length 4); the second prompt runs out of weights before its last chunk
+		let tokens = vec![vec![10, 11, 12, 13], vec![20]];
+		let weights = vec![vec![2.0, 3.0, 4.0, 5.0], vec![0.5]];
+		let (tokens, weights) = pad_tokens_and_weights(tokens, weights, 6, 0, 1, false, 4);
+		assert_eq!(tokens, vec![vec![0, 10, 11, 12, 13, 1], vec![0, 20, 1, 1, 1, 1]]);
+		assert_eq!(weights, vec![vec![1.0, 2.0, 3.0, 1.0, 1.0, 4.0, 5.0, 1.0], vec![1.0, 0.5, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0]]);
+	}
+
+	#[test]
+	fn no_normalization() {
+		let (embeddings, weights) = embeddings();
+		assert_close(apply_prompt_weights(embeddings, weights, WeightNormalization::None, 4), &[1.0, 4.0, 3.0, 4.0, 2.5, 6.0]);
+	}
+
+	#[test]
+	fn whole_prompt_normalization() {
+		let (embeddings, weights) = embeddings();
+		// weighted sum 20.5, original sum 21; scaled by 21 / 20.5
+		let scale = 21.0 / 20.5;
+		assert_close(
+			apply_prompt_weights(embeddings, weights, WeightNormalization::WholePrompt, 4),
+			&[1.0 * scale, 4.0 * scale, 3.0 * scale, 4.0 * scale, 2.5 * scale, 6.0 * scale]
+		);
+	}
+
+	#[test]
+	fn per_chunk_normalization() {
+		let (embeddings, weights) = embeddings();
+		// chunk 1 = [BOS, a, b]: weighted sum 8, original sum 6; chunk 2 = [c, d, EOS]: weighted sum 12.5, original sum 15
+		let (scale_1, scale_2) = (6.0 / 8.0, 15.0 / 12.5);
+		assert_close(
+			apply_prompt_weights(embeddings, weights, WeightNormalization::PerChunk, 4),
+			&[1.0 * scale_1, 4.0 * scale_1, 3.0 * scale_1, 4.0 * scale_2, 2.5 * scale_2, 6.0 * scale_2]
+		);
+	}
+
+	#[test]
+	fn malformed_weights_are_literal() {
+		assert_eq!(parse_prompt_attention("(a:.)"), [("a:.)".to_owned(), 1.1)]);
+		assert_eq!(parse_prompt_attention("(())"), [(String::new(), 1.0)]);
+	}
+
+	#[test]
+	fn brackets_close_their_own_kind() {
+		assert_eq!(parse_prompt_attention("a [b] c"), [("a ".to_owned(), 1.0), ("b".to_owned(), 1.0 / 1.1), (" c".to_owned(), 1.0)]);
+		// a `)` doesn't close a `[`, so it is literal & the `[` applies to the rest of the prompt
+		assert_eq!(parse_prompt_attention("a [b) c"), [("a ".to_owned(), 1.0), ("b) c".to_owned(), 1.0 / 1.1)]);
+		assert_eq!(parse_prompt_attention("a] (b"), [("a] ".to_owned(), 1.0), ("b".to_owned(), 1.1)]);
+	}
+
+	#[test]
+	fn weights_are_clamped() {
+		assert_eq!(parse_prompt_attention("(a:1000) (b:-1)"), [("a".to_owned(), MAX_WEIGHT), (" ".to_owned(), 1.0), ("b".to_owned(), 0.0)]);
+		let nested = format!("{}a:0{}", "(".repeat(1000), ")".repeat(1000));
+		assert_eq!(parse_prompt_attention(nested), [("a".to_owned(), 0.0)]);
+	}
+
+	#[test]
+	fn sanitize_makes_fallbacks_explicit() {
+		assert_eq!(sanitize("a (b:1.2) [c]"), ("a (b:1.2) [c]".to_owned(), vec![]));
+		assert_eq!(
+			sanitize("(a:.) b] \\ (c:500)"),
+			(
+				"(a:.\\) b\\]  (c:100))".to_owned(),
+				vec![
+					PromptSyntaxWarning::UnclosedBracket { position: 0 },
+					PromptSyntaxWarning::InvalidWeight { position: 2, weight: ".".to_owned() },
+					PromptSyntaxWarning::UnmatchedBracket { position: 7 },
+					PromptSyntaxWarning::ClampedWeight { position: 13, weight: 500.0, clamped: 100.0 },
+				]
+			)
+		);
+		assert_eq!(sanitize("[[a").0, "[[a]]");
+	}
+
+	/// Drops empty texts & merges texts of equal weights, which doesn't change how a prompt is tokenized.
+	fn effective_weights(weights: Vec<(String, f32)>) -> Vec<(String, f32)> {
+		let mut merged: Vec<(String, f32)> = Vec::new();
+		for (text, weight) in weights.into_iter().filter(|(text, _)| !text.is_empty()) {
+			match merged.last_mut() {
+				Some(last) if last.1 == weight => last.0 += &text,
+				_ => merged.push((text, weight))
+			}
+		}
+		merged
+	}
+
+	/// Prompts made mostly of weighting syntax, which arbitrary strings rarely contain.
+	fn syntax_heavy() -> impl Strategy<Value = String> {
+		"[()\\[\\]:\\\\.0-9a -]{0,48}"
+	}
+
+	proptest! {
+		#[test]
+		fn parser_never_panics(prompt in "\\PC*") {
+			let weights = parse_prompt_attention(&prompt);
+			prop_assert!(!weights.is_empty());
+			prop_assert!(weights.iter().all(|(_, weight)| (0.0..=MAX_WEIGHT).contains(weight)));
+		}
+
+		#[test]
+		fn syntax_weights_are_sane(prompt in syntax_heavy()) {
+			prop_assert!(parse_prompt_attention(&prompt).iter().all(|(_, weight)| (0.0..=MAX_WEIGHT).contains(weight)));
+		}
+
+		#[test]
+		fn plain_text_roundtrips(text in "[a-z][a-z ]{0,30}", weight in 0.0_f32..10.0) {
+			prop_assert_eq!(parse_prompt_attention(&text), vec![(text.clone(), 1.0)]);
+			let weight = format!("{weight:.2}");
+			prop_assert_eq!(parse_prompt_attention(format!("({text}:{weight})")), vec![(text.clone(), weight.parse::<f32>().unwrap())]);
+			prop_assert_eq!(sanitize(&text), (text, vec![]));
+		}
+
+		#[test]
+		fn sanitize_preserves_weights(prompt in prop_oneof![syntax_heavy(), "\\PC{0,48}"]) {
+			let (clean, _) = sanitize(&prompt);
+			prop_assert_eq!(effective_weights(parse_prompt_attention(&clean)), effective_weights(parse_prompt_attention(&prompt)));
+			prop_assert_eq!(sanitize(&clean), (clean.clone(), vec![]));
+		}
+	}
+
+	fn max_diff(a: &Array3<f32>, b: &Array3<f32>) -> f32 {
+		assert_eq!(a.shape(), b.shape());
+		a.iter().zip(b.iter()).map(|(a, b)| (a - b).abs()).fold(0.0_f32, f32::max)
+	}
+
+	#[test]
+	fn batched_uncond_matches_separate_runs() -> anyhow::Result<()> {
+		let environment = OrtEnvironment::default().into_arc();
+		let pipeline = StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default())?;
+		let (embeddings, text_encoder) = (&pipeline.text_embeddings, &*pipeline.text_encoder);
+		let chunk_length = embeddings.tokenizer.len();
+
+		let text_input = embeddings.tokenizer.encode_for_text_model(vec!["a b", "c"])?;
+		let uncond_input = embeddings.tokenizer.encode_for_text_model(vec!["d", ""])?;
+		let (text_embeddings, uncond_embeddings) =
+			get_unweighted_text_embeddings_with_uncond(embeddings, text_encoder, text_input.clone(), uncond_input.clone(), chunk_length, true)?;
+
+		let expected_text = get_unweighted_text_embeddings(embeddings, text_encoder, text_input, chunk_length, true)?;
+		let expected_uncond = get_unweighted_text_embeddings(embeddings, text_encoder, uncond_input, chunk_length, true)?;
+		assert!(max_diff(&text_embeddings, &expected_text) < 1e-5);
+		assert!(max_diff(&uncond_embeddings, &expected_uncond) < 1e-5);
+
+		// the classifier-free guidance batch is still ordered `[uncond, cond]`
+		let cfg_embeddings: Array3<f32> = pipeline.encode_prompt("a b".into(), true, Some(&"d".into()))?.into_dimensionality()?;
+		let cond_embeddings: Array3<f32> = pipeline.encode_prompt("a b".into(), false, None)?.into_dimensionality()?;
+		let negative_embeddings: Array3<f32> = pipeline.encode_prompt("d".into(), false, None)?.into_dimensionality()?;
+		assert!(max_diff(&cfg_embeddings.slice(s![..1, .., ..]).to_owned(), &negative_embeddings) < 1e-5);
+		assert!(max_diff(&cfg_embeddings.slice(s![1.., .., ..]).to_owned(), &cond_embeddings) < 1e-5);
+		Ok(())
+	}
+
+	#[test]
+	fn mixed_prompt_weighting() -> anyhow::Result<()> {
+		let environment = OrtEnvironment::default().into_arc();
+		let pipeline = StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default())?;
+		let encode = |prompt: &[&str], weighting: &[PromptWeighting]| -> anyhow::Result<Array3<f32>> {
+			Ok(pipeline.encode_prompt_with_weighting(prompt.into(), false, None, weighting)?.into_dimensionality()?)
+		};
+
+		let weighted = encode(&["(a:1.5) b"], &[])?;
+		let plain = encode(&["(c)"], &[PromptWeighting::Plain])?;
+		// plain prompts keep their brackets
+		assert!(max_diff(&plain, &encode(&["(c)"], &[PromptWeighting::Weighted])?) > 1e-5);
+		assert!(max_diff(&encode(&["a b"], &[PromptWeighting::Plain])?, &encode(&["a b"], &[])?) < 1e-5);
+
+		let mixed = encode(&["(a:1.5) b", "(c)"], &[PromptWeighting::Weighted, PromptWeighting::Plain])?;
+		assert!(max_diff(&mixed.slice(s![..1, .., ..]).to_owned(), &weighted) < 1e-5);
+		assert!(max_diff(&mixed.slice(s![1.., .., ..]).to_owned(), &plain) < 1e-5);
+
+		assert!(encode(&["a", "b", "c"], &[PromptWeighting::Plain, PromptWeighting::Weighted]).is_err());
+		Ok(())
+	}
 
-	Ok((text_embeddings, uncond_embeddings))
+	#[test]
+	fn negative_prompt_broadcasting() -> anyhow::Result<()> {
+		let environment = OrtEnvironment::default().into_arc();
+		let pipeline = StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default())?;
+		let encode = |prompt: &[&str], negative_prompt: &[&str]| -> anyhow::Result<Array3<f32>> {
+			Ok(pipeline.encode_prompt(prompt.into(), true, Some(&negative_prompt.into()))?.into_dimensionality()?)
+		};
+
+		// (prompts, negative prompts) -> batch size
+		for (prompts, negative_prompts, batch_size) in [(1, 1, 1), (1, 3, 3), (3, 1, 3), (3, 3, 3)] {
+			let prompt = ["a", "(b:1.2)", "c"][..prompts].to_vec();
+			let negative_prompt = ["d", "e", "[f]"][..negative_prompts].to_vec();
+			let embeddings = encode(&prompt, &negative_prompt)?;
+			assert_eq!(embeddings.shape()[0], batch_size * 2);
+
+			// each side is encoded as if its single prompt were repeated
+			let repeat = |prompts: Vec<&'static str>| if prompts.len() == 1 { vec![prompts[0]; batch_size] } else { prompts };
+			let expected = encode(&repeat(prompt), &repeat(negative_prompt))?;
+			assert!(max_diff(&embeddings, &expected) < 1e-5);
+		}
+
+		for (prompts, negative_prompts) in [(2, 3), (3, 2)] {
+			let err = encode(&["a", "b", "c"][..prompts], &["d", "e", "f"][..negative_prompts]).unwrap_err();
+			assert_eq!(err.downcast_ref::<PromptBatchMismatch>(), Some(&PromptBatchMismatch { prompts, negative_prompts }));
+		}
+		Ok(())
+	}
 }
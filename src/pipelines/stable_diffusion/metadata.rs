@@ -0,0 +1,471 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+	fs,
+	path::{Path, PathBuf}
+};
+
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use super::impl_main::fnv1a;
+use crate::{BatchNoiseMode, DimensionPolicy, ImageFileFormat, ImageRef, Prompt, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions, StylePrompt};
+
+/// The keyword of the PNG text chunk holding an embedded [`ReproRecord`].
+const PNG_TEXT_KEYWORD: &str = "pyke-diffusers";
+
+/// Where the [`ReproRecord`] of a saved image is written; see
+/// [`StableDiffusionTxt2ImgOptions::with_metadata_mode`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataMode {
+	/// Embed the record in the image file as a PNG `iTXt` chunk. Only supported for PNG images.
+	Embedded,
+	/// Write the record to a JSON sidecar file next to the image, named after the image's full filename with `.json`
+	/// appended, e.g. `fox.png.json`. Works with any image format.
+	Sidecar,
+	/// Both embed the record & write a sidecar. Only supported for PNG images.
+	Both,
+	/// Don't record metadata. **This is the default.**
+	#[default]
+	None
+}
+
+impl MetadataMode {
+	fn embeds(&self) -> bool {
+		matches!(self, MetadataMode::Embedded | MetadataMode::Both)
+	}
+
+	fn writes_sidecar(&self) -> bool {
+		matches!(self, MetadataMode::Sidecar | MetadataMode::Both)
+	}
+}
+
+/// The parameters needed to reproduce a generated image, saved alongside it with a [`MetadataMode`].
+///
+/// All images of a batch are generated from the same seed, so an image from a batch of more than one image is only
+/// reproduced exactly by generating the full batch again, with the same prompts in the same order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReproRecord {
+	/// The name & version of the library that generated the image, e.g. `pyke-diffusers 1.0.0`.
+	pub generator: String,
+	/// The image's prompt.
+	pub prompt: String,
+	/// The image's negative prompt, if any.
+	pub negative_prompt: Option<String>,
+	/// The seed of the batch the image was generated in.
+	pub seed: u64,
+	/// The ETA noise seed delta; see [`StableDiffusionTxt2ImgOptions::ensd`].
+	pub ensd: u64,
+	/// The number of denoising steps.
+	pub steps: usize,
+	/// The classifier-free guidance scale.
+	pub guidance_scale: f32,
+	/// The width of the image.
+	pub width: u32,
+	/// The height of the image.
+	pub height: u32,
+	/// The `(width, height)` the image was generated at before cropping it to its size, if it was generated with
+	/// [`DimensionPolicy::PadAndCrop`].
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub internal_size: Option<(u32, u32)>,
+	/// How the initial noise of the batch's images relates to each other; see [`BatchNoiseMode`].
+	#[serde(default, skip_serializing_if = "is_independent")]
+	pub batch_noise_mode: BatchNoiseMode,
+	/// The image's style prompt, if any, holding the single style prompt of the image.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub style_prompt: Option<StylePrompt>,
+	/// The image's conditioning dropout factor, if the batch was generated with
+	/// [`StableDiffusionTxt2ImgOptions::with_conditioning_dropout`].
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub conditioning_dropout: Option<f32>,
+	/// The index of the image in its batch.
+	pub index: usize,
+	/// The number of images in the batch.
+	pub batch_size: usize
+}
+
+fn is_independent(mode: &BatchNoiseMode) -> bool {
+	*mode == BatchNoiseMode::Independent
+}
+
+/// The text prompts of a batch as they were encoded, i.e. after the
+/// [prompt filter](crate::StableDiffusionOptions::with_prompt_filter), so text it scrubbed isn't recorded.
+pub(crate) struct EncodedPrompts {
+	pub(crate) prompt: Prompt,
+	pub(crate) negative_prompt: Option<Prompt>,
+	pub(crate) style_prompt: Option<Prompt>
+}
+
+impl EncodedPrompts {
+	/// Runs the text prompts of `options` through the pipeline's prompt filter.
+	pub(crate) fn filter(session: &StableDiffusionPipeline, options: &StableDiffusionTxt2ImgOptions) -> anyhow::Result<Self> {
+		Ok(Self {
+			prompt: session.filter_prompt(options.positive_prompt.clone())?,
+			negative_prompt: options.negative_prompt.clone().map(|negative_prompt| session.filter_prompt(negative_prompt)).transpose()?,
+			style_prompt: options.style_prompt.as_ref().map(|style| session.filter_prompt(style.prompt.clone())).transpose()?
+		})
+	}
+}
+
+impl ReproRecord {
+	/// Records the parameters of image `index` of a batch generated with `options` & the given seed, from the batch's
+	/// `prompts` as they were encoded.
+	pub(crate) fn new(options: &StableDiffusionTxt2ImgOptions, prompts: &EncodedPrompts, seed: u64, index: usize) -> Self {
+		let prompt_index = index / options.num_images_per_prompt;
+		// a single prompt or negative prompt is broadcast to the whole batch
+		let broadcast = |prompt: &Prompt| match prompt.len() {
+			1 => prompt.first().cloned(),
+			_ => prompt.get(prompt_index).cloned()
+		};
+		let prompt_batch_size = prompts.prompt.len().max(prompts.negative_prompt.as_ref().map_or(0, |negative_prompt| negative_prompt.len()));
+		let style_prompt = options.style_prompt.as_ref().zip(prompts.style_prompt.as_ref()).map(|(style, prompt)| StylePrompt {
+			prompt: Prompt(broadcast(prompt).into_iter().collect()),
+			..style.clone()
+		});
+		let (width, height) = options.output_size();
+		Self {
+			generator: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+			prompt: broadcast(&prompts.prompt).unwrap_or_default(),
+			negative_prompt: prompts.negative_prompt.as_ref().and_then(broadcast),
+			seed,
+			ensd: options.ensd,
+			steps: options.steps,
+			guidance_scale: options.guidance_scale,
+			width,
+			height,
+			internal_size: ((width, height) != (options.width, options.height)).then_some((options.width, options.height)),
+			batch_noise_mode: options.batch_noise_mode,
+			style_prompt,
+			conditioning_dropout: options.conditioning_dropout.as_ref().and_then(|factors| factors.get(index).copied()),
+			index,
+			batch_size: prompt_batch_size * options.num_images_per_prompt
+		}
+	}
+
+	/// Fails if images generated with `options` can't be reproduced from a record, i.e. if they are generated from
+	/// precomputed prompt embeddings, which aren't recorded.
+	pub(crate) fn ensure_recordable(options: &StableDiffusionTxt2ImgOptions) -> anyhow::Result<()> {
+		if options.prompt_embeddings.is_some() {
+			anyhow::bail!("images generated from prompt embeddings can't be reproduced from their metadata; disable the metadata mode");
+		}
+		Ok(())
+	}
+
+	/// Creates options which generate the recorded image with its prompt, seed, & parameters. For images from batches
+	/// of more than one image, see the note on [`ReproRecord`].
+	pub fn to_options(&self) -> StableDiffusionTxt2ImgOptions {
+		let mut options = StableDiffusionTxt2ImgOptions::default()
+			.with_prompt(self.prompt.as_str())
+			.with_seed(self.seed)
+			.with_eta_noise_seed_delta(self.ensd)
+			.with_steps(self.steps)
+			.with_guidance_scale(self.guidance_scale);
+		match self.internal_size {
+			Some(_) => options = options.with_dimension_policy(DimensionPolicy::PadAndCrop).with_size(self.width, self.height),
+			None => {
+				options.width = self.width;
+				options.height = self.height;
+			}
+		}
+		if let Some(negative_prompt) = self.negative_prompt.as_deref() {
+			options = options.with_negative_prompt(negative_prompt);
+		}
+		if let Some(style_prompt) = self.style_prompt.clone() {
+			options = options.with_style_prompt(style_prompt);
+		}
+		if let Some(factor) = self.conditioning_dropout {
+			options = options.with_conditioning_dropout(vec![factor]);
+		}
+		options.with_batch_noise_mode(self.batch_noise_mode)
+	}
+
+	/// Loads the image at `path` together with its record, reuniting an image with the sidecar or embedded record
+	/// written by a [`MetadataMode`].
+	///
+	/// The sidecar is preferred over an embedded record. A sidecar records a hash of the image file it was written with,
+	/// so a stale sidecar left next to an image that has since been replaced is detected: its record is ignored in
+	/// favor of the embedded record, or an error is returned if the image has none. Returns `None` as the record if
+	/// the image has neither.
+	pub fn load_with_image(path: impl AsRef<Path>) -> anyhow::Result<(DynamicImage, Option<ReproRecord>)> {
+		let path = path.as_ref();
+		let bytes = fs::read(path)?;
+		let image = image::load_from_memory(&bytes)?;
+		let embedded = match read_png_text(&bytes, PNG_TEXT_KEYWORD) {
+			Some(text) => Some(serde_json::from_str(&text)?),
+			None => None
+		};
+
+		let sidecar_path = sidecar_path(path);
+		if !sidecar_path.exists() {
+			return Ok((image, embedded));
+		}
+		let sidecar: Sidecar = serde_json::from_str(&fs::read_to_string(&sidecar_path)?)?;
+		if sidecar.image_hash == image_hash(&bytes) {
+			return Ok((image, Some(sidecar.record)));
+		}
+		match embedded {
+			Some(record) => Ok((image, Some(record))),
+			None => anyhow::bail!(
+				"the sidecar `{}` was written for a different image than `{}`; the image may have been replaced",
+				sidecar_path.display(),
+				path.display()
+			)
+		}
+	}
+}
+
+/// The contents of a sidecar file.
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+	/// The FNV-1a hash of the image file the sidecar was written with, as 16 hex digits.
+	image_hash: String,
+	#[serde(flatten)]
+	record: ReproRecord
+}
+
+/// Returns the path of the sidecar of the image at `path`: the image's path with `.json` appended.
+pub fn sidecar_path(path: impl AsRef<Path>) -> PathBuf {
+	let mut sidecar = path.as_ref().as_os_str().to_owned();
+	sidecar.push(".json");
+	PathBuf::from(sidecar)
+}
+
+fn image_hash(bytes: &[u8]) -> String {
+	format!("{:016x}", fnv1a(bytes.iter().copied()))
+}
+
+impl ImageRef {
+	/// Writes the image to `path` like [`ImageRef::save`], also recording `record` as described by `mode`.
+	///
+	/// Sidecars are written atomically, by writing to a temporary file which is then renamed, so a crash never leaves a
+	/// half-written sidecar behind. An existing sidecar, e.g. of an earlier image saved to the same path, is replaced.
+	/// Existing sidecars are left as-is when no sidecar is written; [`ReproRecord::load_with_image`] detects that they
+	/// belong to a different image.
+	pub fn save_with_metadata(&self, path: impl AsRef<Path>, format: ImageFileFormat, mode: MetadataMode, record: &ReproRecord) -> anyhow::Result<()> {
+		let path = path.as_ref();
+		if mode == MetadataMode::None {
+			return self.save(path, format);
+		}
+		if mode.embeds() && format != ImageFileFormat::Png {
+			anyhow::bail!("metadata can only be embedded in PNG images; use `MetadataMode::Sidecar` for {} images", format.extension());
+		}
+
+		let mut bytes = Vec::new();
+		match (self, format) {
+			(ImageRef::OnDisk(src), ImageFileFormat::Png) => bytes = fs::read(src)?,
+			(ImageRef::OnDisk(src), _) => format.write(&image::open(src)?, &mut bytes)?,
+			(ImageRef::InMemory(image), _) => format.write(image, &mut bytes)?
+		}
+		if mode.embeds() {
+			insert_png_text(&mut bytes, PNG_TEXT_KEYWORD, &serde_json::to_string(record)?)?;
+		}
+		fs::write(path, &bytes)?;
+
+		if mode.writes_sidecar() {
+			let sidecar_path = sidecar_path(path);
+			let sidecar = Sidecar { image_hash: image_hash(&bytes), record: record.clone() };
+			let mut tmp = sidecar_path.as_os_str().to_owned();
+			tmp.push(".tmp");
+			fs::write(&tmp, serde_json::to_string_pretty(&sidecar)?)?;
+			fs::rename(tmp, sidecar_path)?;
+		}
+		Ok(())
+	}
+}
+
+/// Inserts an `iTXt` chunk with the given keyword & uncompressed UTF-8 text after the `IHDR` chunk of a PNG file.
+fn insert_png_text(png: &mut Vec<u8>, keyword: &str, text: &str) -> anyhow::Result<()> {
+	// 8 byte signature, then the IHDR chunk: 4 byte length, 4 byte type, 13 bytes of data, & a 4 byte CRC
+	if png.len() < 33 || &png[12..16] != b"IHDR" {
+		anyhow::bail!("not a PNG image");
+	}
+	let mut chunk = b"iTXt".to_vec();
+	chunk.extend(keyword.as_bytes());
+	// keyword terminator, no compression, then empty language tag & translated keyword
+	chunk.extend([0, 0, 0, 0, 0]);
+	chunk.extend(text.as_bytes());
+
+	let mut encoded = ((chunk.len() - 4) as u32).to_be_bytes().to_vec();
+	encoded.extend(&chunk);
+	encoded.extend(crc32(&chunk).to_be_bytes());
+	png.splice(33..33, encoded);
+	Ok(())
+}
+
+/// Reads the text of the first uncompressed `iTXt` chunk with the given keyword from a PNG file.
+fn read_png_text(png: &[u8], keyword: &str) -> Option<String> {
+	let mut offset = 8;
+	while offset + 12 <= png.len() {
+		let len = u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap()) as usize;
+		let data = png.get(offset + 8..offset + 8 + len)?;
+		if &png[offset + 4..offset + 8] == b"iTXt" {
+			let mut fields = data.splitn(2, |&b| b == 0);
+			if fields.next() == Some(keyword.as_bytes()) {
+				let rest = fields.next()?;
+				// uncompressed, followed by the language tag & translated keyword
+				if rest.first() == Some(&0) {
+					let text = rest.get(2..)?.splitn(3, |&b| b == 0).nth(2)?;
+					return String::from_utf8(text.to_vec()).ok();
+				}
+			}
+		}
+		offset += len + 12;
+	}
+	None
+}
+
+/// The CRC-32 of PNG chunks (ISO 3309).
+fn crc32(bytes: &[u8]) -> u32 {
+	!bytes.iter().fold(!0_u32, |crc, &byte| {
+		(0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 })
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use image::{DynamicImage, RgbImage};
+
+	use super::{crc32, read_png_text, sidecar_path, EncodedPrompts, MetadataMode, ReproRecord};
+	use crate::{BatchNoiseMode, DimensionPolicy, ImageFileFormat, ImageRef, StableDiffusionTxt2ImgOptions, StyleBlend, StylePrompt};
+
+	impl EncodedPrompts {
+		fn unfiltered(options: &StableDiffusionTxt2ImgOptions) -> Self {
+			Self {
+				prompt: options.positive_prompt.clone(),
+				negative_prompt: options.negative_prompt.clone(),
+				style_prompt: options.style_prompt.as_ref().map(|style| style.prompt.clone())
+			}
+		}
+	}
+
+	fn record(prompt: &str, seed: u64) -> ReproRecord {
+		let options = StableDiffusionTxt2ImgOptions::default().with_prompt(prompt).with_negative_prompt("blurry").with_steps(20);
+		ReproRecord::new(&options, &EncodedPrompts::unfiltered(&options), seed, 0)
+	}
+
+	fn image(value: u8) -> ImageRef {
+		ImageRef::InMemory(DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, [value; 3].into())))
+	}
+
+	#[test]
+	fn png_chunk_crc() {
+		assert_eq!(crc32(b"IEND"), 0xae42_6082);
+	}
+
+	#[test]
+	fn records_reproduce_options() {
+		let record = record("a red fox", 42);
+		assert_eq!(record.negative_prompt.as_deref(), Some("blurry"));
+		let options = record.to_options();
+		assert_eq!((options.seed, options.steps, options.positive_prompt[0].as_str()), (Some(42), 20, "a red fox"));
+	}
+
+	#[test]
+	fn records_batch_options() -> anyhow::Result<()> {
+		let options = StableDiffusionTxt2ImgOptions::default()
+			.with_prompt(["a red fox", "a grey wolf"])
+			.with_style_prompt(StylePrompt::new(["watercolor", "oil painting"], 0.4).with_blend(StyleBlend::Concatenate))
+			.with_batch_noise_mode(BatchNoiseMode::Slerp { from_seed: 1, to_seed: 2 })
+			.with_conditioning_dropout(vec![0.0, 0.5]);
+		let record = ReproRecord::new(&options, &EncodedPrompts::unfiltered(&options), 42, 1);
+		assert_eq!(record.style_prompt, Some(StylePrompt::new("oil painting", 0.4).with_blend(StyleBlend::Concatenate)));
+		assert_eq!((record.batch_noise_mode, record.conditioning_dropout), (BatchNoiseMode::Slerp { from_seed: 1, to_seed: 2 }, Some(0.5)));
+
+		let roundtrip: ReproRecord = serde_json::from_str(&serde_json::to_string(&record)?)?;
+		assert_eq!(roundtrip, record);
+		let options = record.to_options();
+		assert_eq!(options.style_prompt, record.style_prompt);
+		assert_eq!((options.batch_noise_mode, options.conditioning_dropout), (record.batch_noise_mode, Some(vec![0.5])));
+
+		// records of plain generations don't mention these options, so they still read as before
+		let json = serde_json::to_string(&self::record("a red fox", 42))?;
+		assert!(!json.contains("batch_noise_mode") && !json.contains("style_prompt") && !json.contains("conditioning_dropout"), "{json}");
+		Ok(())
+	}
+
+	#[test]
+	fn records_pad_and_crop_sizes() {
+		let options = StableDiffusionTxt2ImgOptions::default().with_dimension_policy(DimensionPolicy::PadAndCrop).with_size(500, 333);
+		let record = ReproRecord::new(&options, &EncodedPrompts::unfiltered(&options), 42, 0);
+		assert_eq!((record.width, record.height, record.internal_size), (500, 333, Some((512, 344))));
+		let options = record.to_options();
+		assert_eq!((options.width, options.height, options.output_size()), (512, 344, (500, 333)));
+
+		let options = StableDiffusionTxt2ImgOptions::default().with_dimension_policy(DimensionPolicy::PadAndCrop);
+		let record = ReproRecord::new(&options, &EncodedPrompts::unfiltered(&options), 42, 0);
+		assert_eq!((record.width, record.height, record.internal_size), (512, 512, None));
+	}
+
+	#[test]
+	fn embedded_and_sidecar_roundtrip() -> anyhow::Result<()> {
+		let dir = std::env::temp_dir().join("pyke-diffusers-metadata-roundtrip");
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir)?;
+
+		let path = dir.join("fox.png");
+		let fox = record("a red fox — 日本の狐", 42);
+		image(128).save_with_metadata(&path, ImageFileFormat::Png, MetadataMode::Embedded, &fox)?;
+		assert!(read_png_text(&fs::read(&path)?, "pyke-diffusers").is_some());
+		assert!(!sidecar_path(&path).exists());
+		let (image, loaded) = ReproRecord::load_with_image(&path)?;
+		assert_eq!((image.width(), loaded), (8, Some(fox.clone())));
+
+		let path = dir.join("fox-sidecar.png");
+		image(0).save(&path, ImageFileFormat::Png)?;
+		assert_eq!(ReproRecord::load_with_image(&path)?.1, None);
+		image(128).save_with_metadata(&path, ImageFileFormat::Png, MetadataMode::Sidecar, &fox)?;
+		assert!(sidecar_path(&path).exists());
+		assert_eq!(ReproRecord::load_with_image(&path)?.1, Some(fox));
+
+		fs::remove_dir_all(&dir)?;
+		Ok(())
+	}
+
+	#[test]
+	fn differing_sidecars() -> anyhow::Result<()> {
+		let dir = std::env::temp_dir().join("pyke-diffusers-metadata-collisions");
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir)?;
+		let path = dir.join("image.png");
+		let (fox, wolf) = (record("a red fox", 1), record("a grey wolf", 2));
+
+		// saving over an image replaces its differing sidecar
+		image(10).save_with_metadata(&path, ImageFileFormat::Png, MetadataMode::Sidecar, &fox)?;
+		image(20).save_with_metadata(&path, ImageFileFormat::Png, MetadataMode::Sidecar, &wolf)?;
+		assert_eq!(ReproRecord::load_with_image(&path)?.1, Some(wolf.clone()));
+		assert!(!dir.join("image.png.json.tmp").exists());
+
+		// a stale sidecar is detected once its image is replaced...
+		image(0).save(&path, ImageFileFormat::Png)?;
+		assert!(ReproRecord::load_with_image(&path).is_err());
+		// ...and the embedded record is used instead, if there is one
+		image(30).save_with_metadata(&path, ImageFileFormat::Png, MetadataMode::Both, &fox)?;
+		image(40).save_with_metadata(dir.join("other.png"), ImageFileFormat::Png, MetadataMode::Sidecar, &wolf)?;
+		fs::rename(dir.join("other.png.json"), sidecar_path(&path))?;
+		assert_eq!(ReproRecord::load_with_image(&path)?.1, Some(fox.clone()));
+
+		// saving without metadata leaves the differing sidecar, which is then detected as stale
+		image(50).save_with_metadata(&path, ImageFileFormat::Png, MetadataMode::None, &fox)?;
+		assert!(sidecar_path(&path).exists());
+		assert!(ReproRecord::load_with_image(&path).is_err());
+
+		assert!(image(60).save_with_metadata(&path, ImageFileFormat::Jpeg { quality: 90 }, MetadataMode::Embedded, &fox).is_err());
+
+		fs::remove_dir_all(&dir)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,339 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{thread, time::Duration};
+
+use image::{DynamicImage, GenericImageView, Rgb, Rgb32FImage};
+use ndarray::{concatenate, Array4, ArrayView4, Axis};
+use ndarray_rand::{
+	rand::{self, rngs::StdRng, Rng, SeedableRng},
+	rand_distr::StandardNormal,
+	RandomExt
+};
+use num_traits::ToPrimitive;
+
+use super::{
+	early_exit::ConvergenceTracker,
+	impl_main::{crop_latents, fnv1a},
+	LatentsDecoder
+};
+use crate::{
+	ClampReport, DiffusionScheduler, GenerationStage, ImageRef, ImageRegion, StableDiffusionOutput, StableDiffusionTxt2ImgOptions, StepRecord, StepStats,
+	StopReason, TextToImagePipeline
+};
+
+/// Identifies images generated by a [`MockPipeline`].
+const MOCK_MAGIC: &[u8; 4] = b"PYKM";
+
+/// A deterministic stand-in for [`StableDiffusionPipeline`](crate::StableDiffusionPipeline) which loads no models, for
+/// testing code built on the [`TextToImagePipeline`] trait, e.g. progress reporting, cancellation, or queueing.
+///
+/// The mock runs the real scheduler loop: the initial latents are drawn from an RNG seeded with the seed and a hash of
+/// each prompt, the UNet is replaced by a cheap function of the latents, and callbacks & previews are called on the
+/// same steps, with the same stages, as with a real pipeline. Callbacks returning `false`, cancellation tokens, &
+/// timeouts stop generation like with a real pipeline (see [`StopReason`]). Decoding maps the first three latent
+/// channels to colors.
+///
+/// The top-left pixels of each output image encode the parameters it was generated with; see [`MockImageInfo`].
+///
+/// Model-specific options, like inpainting, MultiDiffusion, ControlNets, reference images, Attend-and-Excite, restart
+/// sampling, checkpoints, seed averaging, negative prompts, & decoding to disk, are ignored, and images are generated
+/// at the padded size of [`DimensionPolicy::PadAndCrop`](crate::DimensionPolicy::PadAndCrop) without being cropped.
+/// Early exit, custom sigmas, diversity, batch noise modes, batch subsets, & initial latents are supported. Mock
+/// latents never contain NaNs, so NaN retries are never taken.
+///
+/// ```
+/// # fn main() -> anyhow::Result<()> {
+/// # use pyke_diffusers::{EulerDiscreteScheduler, MockImageInfo, MockPipeline, StableDiffusionTxt2ImgOptions, TextToImagePipeline};
+/// let pipeline = MockPipeline::new();
+/// let mut scheduler = EulerDiscreteScheduler::default();
+/// let options = StableDiffusionTxt2ImgOptions::default().with_size(64, 64).with_steps(4).with_seed(42).with_prompt("a red fox");
+/// let output = pipeline.txt2img(&mut scheduler, &options)?;
+/// let info = MockImageInfo::decode(&output.images[0].clone().into_image()?).unwrap();
+/// assert_eq!((info.seed, info.steps_taken), (42, 4));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default, Debug, Clone)]
+pub struct MockPipeline {
+	step_delay: Duration
+}
+
+impl MockPipeline {
+	/// Creates a mock pipeline which takes no time per step.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Sleeps for `step_delay` on each step, to simulate the time taken by the UNet.
+	pub fn with_step_delay(mut self, step_delay: Duration) -> Self {
+		self.step_delay = step_delay;
+		self
+	}
+}
+
+/// The parameters a [`MockPipeline`] image was generated with, as encoded in the image's top-left pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MockImageInfo {
+	/// The seed of the generation.
+	pub seed: u64,
+	/// A hash of the image's prompt text, or of its token IDs with
+	/// [`StableDiffusionTxt2ImgOptions::prompt_token_ids`].
+	pub prompt_hash: u64,
+	/// The number of steps requested.
+	pub steps: u32,
+	/// The number of steps taken before generation finished or was cancelled.
+	pub steps_taken: u32,
+	/// The index of the image in the full batch, even if only a subset of the batch was generated.
+	pub index: u32,
+	/// The guidance scale of the generation.
+	pub guidance_scale: f32
+}
+
+impl MockImageInfo {
+	/// The number of pixels holding the encoded parameters.
+	const PIXELS: u32 = 12;
+
+	fn to_bytes(self) -> Vec<u8> {
+		let mut bytes = MOCK_MAGIC.to_vec();
+		bytes.extend(self.seed.to_le_bytes());
+		bytes.extend(self.prompt_hash.to_le_bytes());
+		bytes.extend(self.steps.to_le_bytes());
+		bytes.extend(self.steps_taken.to_le_bytes());
+		bytes.extend(self.index.to_le_bytes());
+		bytes.extend(self.guidance_scale.to_le_bytes());
+		bytes
+	}
+
+	/// Reads the parameters encoded in an image generated by a [`MockPipeline`], or returns `None` if the image wasn't
+	/// generated by one. The image can have any color type, as long as it holds at least 8 bits per channel.
+	pub fn decode(image: &DynamicImage) -> Option<Self> {
+		let (width, height) = image.dimensions();
+		if width * height < Self::PIXELS {
+			return None;
+		}
+		let image = image.to_rgb8();
+		let bytes = (0..Self::PIXELS).flat_map(|i| image.get_pixel(i % width, i / width).0).collect::<Vec<_>>();
+		if &bytes[..4] != MOCK_MAGIC {
+			return None;
+		}
+		let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+		let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+		Some(Self {
+			seed: u64_at(4),
+			prompt_hash: u64_at(12),
+			steps: u32_at(20),
+			steps_taken: u32_at(24),
+			index: u32_at(28),
+			guidance_scale: f32::from_bits(u32_at(32))
+		})
+	}
+
+	fn encode_into(self, image: &mut Rgb32FImage) {
+		let width = image.width();
+		for (i, rgb) in (0..Self::PIXELS).zip(self.to_bytes().chunks(3)) {
+			let pixel = Rgb([rgb[0] as f32 / 255.0, rgb[1] as f32 / 255.0, rgb[2] as f32 / 255.0]);
+			image.put_pixel(i % width, i / width, pixel);
+		}
+	}
+}
+
+impl TextToImagePipeline for MockPipeline {
+	fn txt2img<S: DiffusionScheduler>(&self, scheduler: &mut S, options: &StableDiffusionTxt2ImgOptions) -> anyhow::Result<StableDiffusionOutput> {
+		if options.height % 8 != 0 || options.width % 8 != 0 {
+			anyhow::bail!("`width` ({}) and `height` ({}) must be divisible by 8 for Stable Diffusion", options.width, options.height);
+		}
+		if options.num_images_per_prompt == 0 {
+			anyhow::bail!("`num_images_per_prompt` must be at least 1");
+		}
+		let seed = options.seed.unwrap_or_else(|| rand::thread_rng().gen::<u64>());
+
+		let prompt_hashes = match options.prompt_token_ids.as_ref() {
+			Some(token_ids) => token_ids.outer_iter().map(|ids| fnv1a(ids.iter().flat_map(|id| id.to_le_bytes()))).collect::<Vec<_>>(),
+			None => options.positive_prompt.iter().map(|prompt| fnv1a(prompt.bytes())).collect()
+		};
+		let prompt_hashes = prompt_hashes
+			.into_iter()
+			.flat_map(|hash| std::iter::repeat(hash).take(options.num_images_per_prompt))
+			.collect::<Vec<_>>();
+
+		let (height, width) = ((options.height / 8) as usize, (options.width / 8) as usize);
+		let latents = prompt_hashes
+			.iter()
+			.enumerate()
+			.map(|(index, &hash)| {
+				let mut rng = StdRng::seed_from_u64(fnv1a(seed.to_le_bytes().into_iter().chain(hash.to_le_bytes()).chain((index as u64).to_le_bytes())));
+				Array4::<f32>::random_using((1, 4, height, width), StandardNormal, &mut rng)
+			})
+			.collect::<Vec<_>>();
+		let mut latents = concatenate(Axis(0), &latents.iter().map(|latents| latents.view()).collect::<Vec<_>>())?;
+		options.validate_batch_noise_mode()?;
+		options.batch_noise_mode.apply(options.compatibility_version, options.rng_draw_order, &mut latents);
+		let mut perturbed_regions = match options.diversity.as_ref() {
+			Some(_) if options.latents.is_some() => anyhow::bail!("diversity cannot be combined with initial latents"),
+			Some(diversity) => {
+				diversity.validate()?;
+				diversity.perturb(&mut latents)
+			}
+			None => Vec::new()
+		};
+		// like with a real pipeline, a batch subset is selected from the full batch's noise
+		let generated = options.batch_subset_mask(prompt_hashes.len(), false)?;
+		let mut indices = (0..prompt_hashes.len()).collect::<Vec<_>>();
+		if !generated.is_empty() {
+			indices.retain(|&i| generated[i]);
+			latents = latents.select(Axis(0), &indices);
+			if !perturbed_regions.is_empty() {
+				perturbed_regions = indices.iter().map(|&i| perturbed_regions[i].clone()).collect();
+			}
+		}
+
+		let steps = match options.custom_sigmas.as_deref() {
+			Some(sigmas) => {
+				scheduler.set_sigmas(sigmas)?;
+				scheduler.timesteps().len()
+			}
+			None => options.supported_steps(&mut *scheduler)?,
+		};
+		if let Some(initial_latents) = options.initial_latents(scheduler.init_noise_sigma())? {
+			if initial_latents.dim() != latents.dim() {
+				anyhow::bail!("initial latents have shape {:?}, expected {:?}", initial_latents.shape(), latents.shape());
+			}
+			latents = initial_latents;
+		} else if !options.skip_init_noise_scaling {
+			latents *= scheduler.init_noise_sigma();
+		}
+		let mut scheduler_rng = options.compatibility_version.scheduler_rng(seed);
+
+		let timesteps = scheduler.timesteps().to_owned();
+		let num_warmup_steps = scheduler.num_warmup_steps(steps);
+		let mut step_stats = Vec::new();
+		let mut trajectory = Vec::new();
+		let mut steps_taken = 0;
+		let mut convergence = match options.early_exit {
+			Some(early_exit) => {
+				early_exit.validate()?;
+				Some(ConvergenceTracker::new(early_exit))
+			}
+			None => None
+		};
+		let deadline = options.deadline();
+		let mut stop_reason = StopReason::Completed;
+		for (i, t) in timesteps.iter().enumerate() {
+			if let Some(interruption) = options.interruption(deadline) {
+				stop_reason = interruption;
+				break;
+			}
+			if !self.step_delay.is_zero() {
+				thread::sleep(self.step_delay);
+			}
+
+			// predicting the (scaled) latents as noise denoises towards zero, which keeps latents finite with any scheduler
+			let noise_pred = scheduler.scale_model_input(latents.view(), *t);
+			let sigma = if options.collect_trajectory { scheduler.sigma(i) } else { None };
+			let previous_latents = latents;
+			latents = scheduler.step(noise_pred.view(), *t, previous_latents.view(), &mut scheduler_rng).prev_sample;
+			steps_taken += 1;
+			let converged = convergence.as_mut().map_or(false, |convergence| convergence.step(i, previous_latents.view(), latents.view()));
+			if options.collect_step_stats {
+				step_stats.push(StepStats::new(i, t.to_f32().unwrap(), latents.view(), noise_pred.view(), None));
+			}
+			if options.collect_trajectory {
+				trajectory.push(StepRecord::new(i, t.to_f32().unwrap(), sigma, latents.view(), noise_pred.view()));
+			}
+
+			if let Some(preview) = options.preview.as_ref() {
+				if i + 1 == preview.step {
+					(preview.cb)(self.approximate_decode_latents(latents.view())?);
+				}
+			}
+
+			if let Some(callback) = options.callback.as_ref() {
+				if i == timesteps.len() - 1 || ((i + 1) > num_warmup_steps && (i + 1) % S::order() == 0) {
+					if !callback.invoke(self, GenerationStage::Full, i, t.to_f32().unwrap(), &latents)? {
+						stop_reason = StopReason::CallbackStop;
+						break;
+					}
+				}
+			}
+
+			if converged {
+				break;
+			}
+		}
+
+		let early_stop = stop_reason.is_early();
+		let decoded = if early_stop && !options.decode_on_early_stop { Vec::new() } else { latents_to_images(latents.view(), 8) };
+		let images = decoded
+			.into_iter()
+			.zip(indices)
+			.map(|(mut image, index)| {
+				MockImageInfo {
+					seed,
+					prompt_hash: prompt_hashes[index],
+					steps: steps as u32,
+					steps_taken,
+					index: index as u32,
+					guidance_scale: options.guidance_scale
+				}
+				.encode_into(&mut image);
+				ImageRef::InMemory(DynamicImage::ImageRgb32F(image))
+			})
+			.collect::<Vec<_>>();
+		// colors are mapped with `tanh`, so no pixel is ever clamped
+		let clamp_reports = if options.collect_clamp_reports { vec![ClampReport::default(); images.len()] } else { Vec::new() };
+		Ok(StableDiffusionOutput {
+			images,
+			step_stats,
+			trajectory,
+			checkpoint: None,
+			clamp_reports,
+			steps_taken: steps_taken as usize,
+			nsfw_flags: Vec::new(),
+			perturbed_regions,
+			generated,
+			stop_reason,
+			latents: early_stop.then_some(latents),
+			seed
+		})
+	}
+}
+
+impl LatentsDecoder for MockPipeline {
+	fn decode_latents(&self, latents: ArrayView4<'_, f32>) -> anyhow::Result<Vec<DynamicImage>> {
+		Ok(latents_to_images(latents, 8).into_iter().map(DynamicImage::ImageRgb32F).collect())
+	}
+
+	fn approximate_decode_latents(&self, latents: ArrayView4<'_, f32>) -> anyhow::Result<Vec<DynamicImage>> {
+		Ok(latents_to_images(latents, 1).into_iter().map(DynamicImage::ImageRgb32F).collect())
+	}
+
+	fn decode_latents_region(&self, latents: ArrayView4<'_, f32>, region: &ImageRegion) -> anyhow::Result<Vec<DynamicImage>> {
+		self.decode_latents(crop_latents(latents, region)?)
+	}
+}
+
+/// Maps the first three latent channels to colors, upscaling each latent pixel to `scale`x`scale` pixels.
+fn latents_to_images(latents: ArrayView4<'_, f32>, scale: u32) -> Vec<Rgb32FImage> {
+	let (_, _, height, width) = latents.dim();
+	latents
+		.outer_iter()
+		.map(|latents| {
+			Rgb32FImage::from_fn(width as u32 * scale, height as u32 * scale, |x, y| {
+				let (x, y) = ((x / scale) as usize, (y / scale) as usize);
+				Rgb([0, 1, 2].map(|c| 0.5 + 0.5 * latents[[c, y, x]].tanh()))
+			})
+		})
+		.collect()
+}
@@ -12,29 +12,571 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::fmt::Debug;
+use std::{fmt::Debug, path::PathBuf, sync::Arc};
 
+use half::f16;
 use image::DynamicImage;
-use ndarray::Array4;
+use ndarray::{Array4, ArrayView1, ArrayView4};
 
+mod animation;
+mod attend_and_excite;
+mod batch_noise;
+mod cancellation;
+mod capabilities;
+mod checkpoint;
+mod clip_score;
+mod controlnet;
+mod deepcache;
+mod diversity;
+mod early_exit;
+mod guidance_embedding;
 mod impl_img2img;
 mod impl_main;
 // mod impl_memory_optimized;
 mod impl_txt2img;
+mod inpaint;
+mod limits;
+mod metadata;
+#[cfg(feature = "mock")]
+mod mock;
+mod multidiffusion;
+mod preprocessing;
+mod prompt_cache;
+mod prompt_filter;
+mod reference_attention;
+mod refiner;
+mod restart;
+mod safety;
+mod snapshot;
+mod step_stats;
+mod steps;
+mod style_prompt;
+mod super_resolution;
+mod timing;
+mod to_files;
 
-pub(crate) mod lpw;
+pub mod lpw;
 pub(crate) mod text_embeddings;
 
-pub use self::impl_img2img::{ImagePreprocessing, StableDiffusionImg2ImgOptions};
-pub use self::impl_main::StableDiffusionPipeline;
-pub use self::impl_txt2img::StableDiffusionTxt2ImgOptions;
-use crate::DiffusionDeviceControl;
+pub use self::animation::{AnimationFrame, AnimationFrameInfo, AnimationKeyframe, AnimationSpec};
+pub use self::attend_and_excite::AttendAndExciteOptions;
+pub use self::batch_noise::BatchNoiseMode;
+pub use self::cancellation::{CancellationToken, StopReason};
+pub use self::capabilities::Capabilities;
+pub use self::checkpoint::DiffusionCheckpoint;
+pub use self::controlnet::{ControlNet, ControlNetConfig};
+pub use self::deepcache::DeepCacheConfig;
+pub use self::diversity::DiversityConfig;
+pub use self::early_exit::EarlyExit;
+pub use self::impl_img2img::{ImagePreprocessing, PadMode, ResizeMode, StableDiffusionImg2ImgOptions};
+pub use self::impl_main::{PipelineLoadErrors, StableDiffusionPipeline};
+pub use self::impl_txt2img::{CompatibilityVersion, DimensionPolicy, NonFiniteLatents, RngDrawOrder, StableDiffusionTxt2ImgOptions, StepLimitPolicy};
+pub use self::inpaint::{prepare_inpaint_mask, prepare_inpaint_mask_with_filter, InpaintOptions, StrengthSchedule};
+pub use self::limits::{GenerationLimit, GenerationLimits, LimitExceeded};
+pub use self::lpw::{PromptWeighting, WeightNormalization};
+pub use self::metadata::{sidecar_path, MetadataMode, ReproRecord};
+#[cfg(feature = "mock")]
+pub use self::mock::{MockImageInfo, MockPipeline};
+pub use self::multidiffusion::MultiDiffusionOptions;
+pub use self::preprocessing::ResizeFilter;
+pub use self::prompt_cache::{PromptCacheConfig, PromptCacheStats};
+pub use self::prompt_filter::{PromptFilter, PromptRejected, RegexPromptFilter};
+pub use self::restart::RestartInterval;
+pub use self::safety::{NsfwPolicy, UnsafeContentDetected};
+pub use self::step_stats::{StepRecord, StepStats, DEFAULT_STD_JUMP_THRESHOLD};
+pub use self::steps::{DiffusionStepOutput, Txt2ImgSteps};
+pub use self::style_prompt::{StyleBlend, StylePrompt};
+pub use self::timing::TimingModel;
+pub use self::to_files::{ImageFileFormat, ImageRef};
+use crate::{DiffusionDeviceControl, DiffusionScheduler, LowSignalPrompts, SpecialTokenValidation, TruncationStrategy};
 
 /// Options for the Stable Diffusion pipeline. This includes options like device control and long prompt weighting.
 #[derive(Default, Debug, Clone)]
 pub struct StableDiffusionOptions {
 	/// A [`DiffusionDeviceControl`] object, mapping what device to place each model on.
-	pub devices: DiffusionDeviceControl
+	pub devices: DiffusionDeviceControl,
+	/// How to handle VAE decoder outputs whose spatial dimensions don't match the expected image size (8x the latent
+	/// size). See [`VAEOutputMismatch`].
+	pub vae_output_mismatch: VAEOutputMismatch,
+	/// The transfer function applied to decoded images before they are converted to [`DynamicImage`]s. See
+	/// [`ColorTransfer`].
+	pub color_transfer: ColorTransfer,
+	/// What to do when the tokenizer's configured BOS/EOS token IDs don't match its vocabulary. See
+	/// [`SpecialTokenValidation`].
+	pub special_token_validation: SpecialTokenValidation,
+	/// How to handle prompts that exceed the maximum prompt length (which is 3x the tokenizer's maximum length with
+	/// long prompt weighting). See [`TruncationStrategy`].
+	pub truncation_strategy: TruncationStrategy,
+	/// What to do with prompts consisting solely of punctuation, symbols or emoji. See [`LowSignalPrompts`].
+	pub low_signal_prompts: LowSignalPrompts,
+	/// If enabled, latents in a batch which are exactly identical to a previous latent are only decoded once by the
+	/// VAE, with the decoded image cloned for each duplicate. Detecting duplicates requires hashing each latent, so
+	/// this is disabled by default.
+	pub dedupe_decode: bool,
+	/// If enabled, latents are sanitized before they are decoded by the VAE; see
+	/// [`StableDiffusionOptions::with_latent_upcast_before_decode`]. Disabled by default.
+	pub latent_upcast_before_decode: bool,
+	/// If set, the model's UNet is merged with another UNet on load; see [`StableDiffusionOptions::with_merged_unet`].
+	pub unet_merge: Option<UNetMerge>,
+	/// How text embeddings are renormalized after applying long prompt weighting emphasis. See
+	/// [`WeightNormalization`].
+	pub weight_normalization: WeightNormalization,
+	/// An optional limit on the approximate total size, in bytes, of all ONNX Runtime sessions resident at once
+	/// (including sessions of other pipelines), as reported by the [`SessionTracker`](crate::SessionTracker). Loading a
+	/// model which would exceed the limit fails with a [`ResidentLimitExceeded`](crate::ResidentLimitExceeded) error
+	/// naming the resident sessions. When replacing a model, the model being replaced is not counted.
+	///
+	/// Sizes are approximated by the size of the model files (including their external data files), so the actual
+	/// memory usage will be higher.
+	pub max_resident_bytes: Option<u64>,
+	/// If enabled, int8-quantized versions of the models are loaded instead of the original models; see
+	/// [`StableDiffusionOptions::with_quantize`].
+	pub quantize: bool,
+	/// If enabled, the split UNet from the model config's `[unet.deepcache]` section is loaded, so generations can use
+	/// DeepCache; see [`StableDiffusionOptions::with_deepcache`].
+	pub deepcache: bool,
+	/// What to do with images in which the safety checker detects unsafe content. Defaults to [`NsfwPolicy::Blank`];
+	/// before the policy existed, flagged images were returned unchanged. See [`StableDiffusionOptions::with_nsfw_policy`].
+	pub nsfw_policy: NsfwPolicy,
+	/// An optional policy filter applied to every text prompt & negative prompt before it is tokenized. See
+	/// [`StableDiffusionOptions::with_prompt_filter`].
+	pub prompt_filter: Option<Arc<dyn PromptFilter>>,
+	/// If set, text embeddings of prompts are cached in memory and optionally on disk. See
+	/// [`StableDiffusionOptions::with_prompt_cache`].
+	pub prompt_cache: Option<PromptCacheConfig>,
+	/// Limits on the size, batch size & steps of every generation. Unlimited by default; see
+	/// [`StableDiffusionOptions::with_limits`].
+	pub limits: GenerationLimits,
+	/// If set, batches are decoded by the VAE in parallel across batch elements on a dedicated `rayon` thread pool
+	/// with this many threads (`0` uses one thread per CPU core). Requires the `parallel-decode` feature. See
+	/// [`StableDiffusionOptions::with_parallel_decode`].
+	#[cfg(feature = "parallel-decode")]
+	pub parallel_decode_threads: Option<usize>,
+	/// If set, loading the UNet or VAE decoder first checks that enough RAM is available to load it while keeping this
+	/// many bytes free. Requires the `sysinfo` feature. See [`StableDiffusionOptions::with_free_memory_check`].
+	#[cfg(feature = "sysinfo")]
+	pub free_memory_headroom: Option<u64>
+}
+
+impl StableDiffusionOptions {
+	/// Merges the model's UNet with the UNet at `other` on load, via weighted averaging of their weights:
+	/// `(1 - alpha) * unet + alpha * other`. Both UNets must have identical graph structure. See
+	/// [`merge_unets`](crate::merge_unets) for details on which weights are merged.
+	///
+	/// Merging happens in memory, so loading requires enough RAM to hold both UNets at once.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, StableDiffusionOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// let pipeline = StableDiffusionPipeline::new(
+	/// 	&environment,
+	/// 	"./stable-diffusion-v1-5/",
+	/// 	StableDiffusionOptions::default().with_merged_unet("./anything-v3/unet.onnx", 0.3)
+	/// )?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn with_merged_unet(mut self, other: impl Into<PathBuf>, alpha: f32) -> Self {
+		self.unet_merge = Some(UNetMerge { other: other.into(), alpha });
+		self
+	}
+
+	/// Decodes batches with the VAE in parallel across batch elements, using a dedicated `rayon` thread pool with
+	/// `threads` threads (`0` uses one thread per CPU core). This mainly speeds up decoding large batches on CPU, where
+	/// each image is otherwise decoded sequentially.
+	///
+	/// All workers share the pipeline's VAE decoder session, since ONNX Runtime sessions can be run concurrently, so
+	/// the model's weights are only loaded once. However, each concurrent decode allocates its own intermediate
+	/// activations, so peak memory usage grows with the number of threads: decoding `n` images at once needs roughly
+	/// `n` times the memory of a single decode on top of the weights. ONNX Runtime's own intra-op thread pool also
+	/// competes for the same cores, so fewer threads than cores may be faster.
+	///
+	/// Decoded images are returned in the same order as with sequential decoding, and are identical to sequentially
+	/// decoded images. [`dedupe_decode`](StableDiffusionOptions::dedupe_decode) takes precedence; when it is enabled,
+	/// latents are decoded sequentially.
+	#[cfg(feature = "parallel-decode")]
+	pub fn with_parallel_decode(mut self, threads: usize) -> Self {
+		self.parallel_decode_threads = Some(threads);
+		self
+	}
+
+	/// Checks that enough RAM is available before loading the UNet or VAE decoder, so loading fails with an
+	/// [`InsufficientMemory`](crate::InsufficientMemory) error instead of pushing the system into swap or getting the
+	/// process killed by the OOM killer midway through. This applies to the initial load as well as
+	/// [`StableDiffusionPipeline::replace`], [`StableDiffusionPipeline::replace_unet`] &
+	/// [`StableDiffusionPipeline::replace_vae`].
+	///
+	/// A model needs the free memory estimated by [`required_load_memory`](crate::required_load_memory), plus
+	/// `headroom_bytes` which should be left for the rest of the system (and for activations during inference). Model
+	/// sizes include the external data files weights may be stored in, and a [merged UNet](Self::with_merged_unet)
+	/// counts both UNets, which are read into memory to be merged. Free memory is the memory the OS reports as
+	/// available, which includes reclaimable caches. The check can't account for
+	/// memory other processes allocate while the model is loading, so it is a safeguard rather than a guarantee.
+	///
+	/// Requires the `sysinfo` feature. Disabled by default.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, StableDiffusionOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// // keep at least 512 MiB free for the rest of the system
+	/// let options = StableDiffusionOptions::default().with_free_memory_check(512 * 1024 * 1024);
+	/// let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", options)?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	#[cfg(feature = "sysinfo")]
+	pub fn with_free_memory_check(mut self, headroom_bytes: u64) -> Self {
+		self.free_memory_headroom = Some(headroom_bytes);
+		self
+	}
+
+	/// Loads int8-quantized versions of the models instead of the original (float32 or float16) models, for extreme
+	/// memory savings on low-resource machines. Quantized weights take a quarter of the memory of float32 weights, and
+	/// integer matrix multiplication is often faster on CPUs.
+	///
+	/// The quantized models are listed in the `[quantized]` section of the model's config, with paths relative to the
+	/// model root; components without a quantized version are loaded as usual. Models are quantized ahead of time with
+	/// ONNX Runtime's dynamic quantization tooling, e.g. `onnxruntime.quantization.quantize_dynamic(unet, unet_int8,
+	/// weight_type=QuantType.QUInt8)`:
+	/// ```toml
+	/// [quantized]
+	/// text-encoder = "text_encoder.int8.onnx"
+	/// unet = "unet.int8.onnx"
+	/// ```
+	///
+	/// Quantization trades quality for memory: images generated with a quantized UNet are noticeably less detailed and
+	/// don't match images generated with the original model for the same seed. Quantizing the text encoder & UNet
+	/// gives most of the savings; the VAE is small and quantizing it introduces visible color banding, so it is best
+	/// left unquantized.
+	///
+	/// This is focused on CPU inference. The dynamically quantized operators are only optimized for ONNX Runtime's CPU
+	/// execution provider; GPU execution providers may run them slowly or fall back to the CPU, so a warning is logged
+	/// when a quantized model is placed on another device. Quantization cannot be combined with
+	/// [merged UNets](StableDiffusionOptions::with_merged_unet). Disabled by default.
+	pub fn with_quantize(mut self, quantize: bool) -> Self {
+		self.quantize = quantize;
+		self
+	}
+
+	/// Loads the deep & shallow subgraphs of the split UNet declared in the model config's `[unet.deepcache]` section
+	/// alongside the UNet, so generations can use [DeepCache](crate::DeepCacheConfig). The subgraphs are about as large
+	/// as the UNet itself & count against [`max_resident_bytes`](StableDiffusionOptions::max_resident_bytes), so they
+	/// are only loaded when enabled. Disabled by default; has no effect for models without a split UNet.
+	pub fn with_deepcache(mut self, deepcache: bool) -> Self {
+		self.deepcache = deepcache;
+		self
+	}
+
+	/// Sets what happens when the safety checker detects unsafe content in a generated image: blank the image (the
+	/// default), return it unchanged, or fail with an [`UnsafeContentDetected`] error. See [`NsfwPolicy`].
+	///
+	/// The safety checker only runs for models whose config has a `[safety-checker]` section. Whenever it runs, the
+	/// per-image results are returned in [`StableDiffusionOutput::nsfw_flags`] regardless of the policy, so e.g. a
+	/// research tool can use [`NsfwPolicy::Flag`] to keep the images and inspect the flags, while a public app keeps
+	/// the default of blanking them.
+	pub fn with_nsfw_policy(mut self, nsfw_policy: NsfwPolicy) -> Self {
+		self.nsfw_policy = nsfw_policy;
+		self
+	}
+
+	/// Sets how VAE decoder outputs whose width or height doesn't match 8x the latent size are handled: fail with an
+	/// error (the default), or crop/pad them to the expected size. See [`VAEOutputMismatch`].
+	pub fn with_vae_output_mismatch(mut self, vae_output_mismatch: VAEOutputMismatch) -> Self {
+		self.vae_output_mismatch = vae_output_mismatch;
+		self
+	}
+
+	/// Sanitizes latents right before they are decoded by the VAE, as a guard against the "black image" failure of VAE
+	/// decoders: latents are copied into a contiguous float32 array, NaN & infinite values are replaced with 0, and the
+	/// remaining values are clamped to ±10. A warning is logged when latents contain non-finite values or exceed the
+	/// expected magnitude.
+	///
+	/// After the scheduler's final step, the UNet's latents are scaled by the VAE's scaling factor (`0.18215`) to have
+	/// roughly zero mean & unit variance, so nearly all values lie within ±4 and values beyond ±5 indicate latents
+	/// which diverged, e.g. due to a too high guidance scale or float16 overflow in the UNet. Such latents decode to
+	/// distorted or black images; clamping them limits the damage, but doesn't recover the intended image.
+	///
+	/// This applies to every VAE decode, including [`StableDiffusionPipeline::decode_latents`]. Previews are not
+	/// affected. Disabled by default.
+	pub fn with_latent_upcast_before_decode(mut self, enable: bool) -> Self {
+		self.latent_upcast_before_decode = enable;
+		self
+	}
+
+	/// Runs every text prompt & negative prompt through `filter` right before it is tokenized, e.g. to enforce a
+	/// profanity or PII policy. Because the filter runs inside the pipeline, it also sees prompts built programmatically,
+	/// such as expanded [prompt templates](crate::prompt_templates), MultiDiffusion regional prompts & prompts given to
+	/// [`StableDiffusionPipeline::encode_prompt`]. Rejected prompts fail with a [`PromptRejected`] error carrying the
+	/// filter's reason. See [`PromptFilter`] and the example [`RegexPromptFilter`].
+	pub fn with_prompt_filter(mut self, filter: impl PromptFilter + 'static) -> Self {
+		self.prompt_filter = Some(Arc::new(filter));
+		self
+	}
+
+	/// Caches the text embeddings of prompts, so that generating with the same prompts again skips the text encoder.
+	/// Up to [`max_resident`](PromptCacheConfig::max_resident) entries are kept in memory; with
+	/// [`PromptCacheConfig::with_dir`], entries are also persisted to disk and reused across pipelines & restarts.
+	/// Entries are invalidated automatically when the text encoder, tokenizer or prompt encoding options change. See
+	/// [`PromptCacheConfig`] for details.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, StableDiffusionOptions, PromptCacheConfig, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// let pipeline = StableDiffusionPipeline::new(
+	/// 	&environment,
+	/// 	"./stable-diffusion-v1-5/",
+	/// 	StableDiffusionOptions::default().with_prompt_cache(PromptCacheConfig::new(64).with_dir("./prompt-cache/"))
+	/// )?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	///
+	/// Only text prompts are cached; prompts given as token IDs are always encoded. Disabled by default.
+	pub fn with_prompt_cache(mut self, config: PromptCacheConfig) -> Self {
+		self.prompt_cache = Some(config);
+		self
+	}
+
+	/// Bounds the resources every generation of the pipeline may use. Generations exceeding a limit fail with a
+	/// [`LimitExceeded`] error before any model runs; see [`GenerationLimits`] for which derived sizes are checked.
+	pub fn with_limits(mut self, limits: GenerationLimits) -> Self {
+		self.limits = limits;
+		self
+	}
+
+	/// Creates options for a pipeline serving untrusted requests, e.g. from a public API, with the conservative
+	/// [`GenerationLimits::untrusted_input`] limits. The other options are the defaults.
+	///
+	/// ```
+	/// # use pyke_diffusers::{GenerationLimits, StableDiffusionOptions};
+	/// let options = StableDiffusionOptions::untrusted_input_defaults();
+	/// assert_eq!(options.limits, GenerationLimits::untrusted_input());
+	/// // limits can still be adjusted, e.g. to also bound the time each request takes
+	/// let options = options.with_limits(GenerationLimits::untrusted_input().with_max_steps(50));
+	/// ```
+	pub fn untrusted_input_defaults() -> Self {
+		Self::default().with_limits(GenerationLimits::untrusted_input())
+	}
+}
+
+/// Describes a UNet to merge into a pipeline's UNet on load.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UNetMerge {
+	/// Path to the UNet to merge with.
+	pub other: PathBuf,
+	/// The weight of `other` in the merged UNet, between 0 and 1.
+	pub alpha: f32
+}
+
+/// A rectangular region of the output image, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageRegion {
+	/// X coordinate of the left edge of the region.
+	pub x: u32,
+	/// Y coordinate of the top edge of the region.
+	pub y: u32,
+	/// Width of the region.
+	pub width: u32,
+	/// Height of the region.
+	pub height: u32
+}
+
+impl ImageRegion {
+	/// Creates a new region from its top-left corner & size, in pixels.
+	pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+		Self { x, y, width, height }
+	}
+
+	/// Creates a region of the given size centered in an image of size `image_width` x `image_height`. The region's
+	/// position is rounded down to a multiple of 8, so it can be used with
+	/// [`StableDiffusionCallback::RegionDecoded`] if its size is also a multiple of 8.
+	pub fn centered(width: u32, height: u32, image_width: u32, image_height: u32) -> Self {
+		let (width, height) = (width.min(image_width), height.min(image_height));
+		Self {
+			x: (image_width - width) / 2 / 8 * 8,
+			y: (image_height - height) / 2 / 8 * 8,
+			width,
+			height
+		}
+	}
+
+	/// Converts this region into latent-space `(y, x, height, width)` for latents of the given latent height & width,
+	/// checking that the region is aligned to 8 pixels and lies within the image.
+	pub(crate) fn to_latent(&self, latent_height: usize, latent_width: usize) -> anyhow::Result<(usize, usize, usize, usize)> {
+		if self.x % 8 != 0 || self.y % 8 != 0 || self.width % 8 != 0 || self.height % 8 != 0 || self.width == 0 || self.height == 0 {
+			anyhow::bail!("region {self:?} must have a non-zero size, and its position & size must be divisible by 8");
+		}
+		let (y, x, height, width) = ((self.y / 8) as usize, (self.x / 8) as usize, (self.height / 8) as usize, (self.width / 8) as usize);
+		if y + height > latent_height || x + width > latent_width {
+			anyhow::bail!("region {self:?} extends outside of the {}x{} image", latent_width * 8, latent_height * 8);
+		}
+		Ok((y, x, height, width))
+	}
+
+	/// Squared distance from a point (in pixels) to the nearest point of this region; 0 if the point is inside.
+	pub(crate) fn distance_sq(&self, x: f32, y: f32) -> f32 {
+		let dx = (self.x as f32 - x).max(x - (self.x + self.width) as f32).max(0.0);
+		let dy = (self.y as f32 - y).max(y - (self.y + self.height) as f32).max(0.0);
+		dx * dx + dy * dy
+	}
+}
+
+/// The full output of a Stable Diffusion pipeline run, including the generated images & diagnostic information.
+#[derive(Debug, Clone)]
+pub struct StableDiffusionOutput {
+	/// The generated images. Images are held in memory using float32 buffers, unless
+	/// [`StableDiffusionTxt2ImgOptions::with_decode_to_disk`] was used, in which case they are PNG files on disk.
+	pub images: Vec<ImageRef>,
+	/// Per-step statistics of the denoising process, if enabled; see [`StepStats`].
+	pub step_stats: Vec<StepStats>,
+	/// The scheduler's trajectory, one record per step taken, if enabled with
+	/// [`StableDiffusionTxt2ImgOptions::with_trajectory`]; otherwise empty. See [`StepRecord`].
+	pub trajectory: Vec<StepRecord>,
+	/// The checkpoint captured at the step requested with
+	/// [`StableDiffusionTxt2ImgOptions::with_checkpoint_at`], if any.
+	pub checkpoint: Option<DiffusionCheckpoint>,
+	/// How many pixels of each image were clamped when converting the decoder's output, if enabled with
+	/// [`StableDiffusionTxt2ImgOptions::with_clamp_reports`]; otherwise empty. In the same order as
+	/// [`images`](Self::images).
+	pub clamp_reports: Vec<ClampReport>,
+	/// The number of denoising steps actually taken, which is less than the number of steps requested if generation
+	/// stopped early (see [`stop_reason`](Self::stop_reason)) or exited early (see
+	/// [`StableDiffusionTxt2ImgOptions::with_early_exit`]).
+	pub steps_taken: usize,
+	/// Why generation stopped. If it stopped early, the partially denoised latents are returned in
+	/// [`latents`](Self::latents), and [`images`](Self::images) is empty unless
+	/// [`StableDiffusionTxt2ImgOptions::with_decode_on_early_stop`] was enabled. See [`StopReason`].
+	pub stop_reason: StopReason,
+	/// The latents reached when generation stopped early, i.e. after the last of [`steps_taken`](Self::steps_taken)
+	/// steps, as passed to latents callbacks. `None` if generation completed.
+	pub latents: Option<Array4<f32>>,
+	/// Whether the safety checker detected unsafe content in each image, in the same order as
+	/// [`images`](Self::images); empty if the model has no safety checker. With [`NsfwPolicy::Blank`], flagged images
+	/// have already been replaced by black images. See [`StableDiffusionOptions::with_nsfw_policy`].
+	pub nsfw_flags: Vec<bool>,
+	/// The regions of each image's initial noise that were re-sampled with
+	/// [`StableDiffusionTxt2ImgOptions::with_diversity`], in pixels & in the same order as [`images`](Self::images);
+	/// empty if diversity was not enabled. The first image is never perturbed.
+	pub perturbed_regions: Vec<Vec<ImageRegion>>,
+	/// Which elements of the full batch were generated, if only a subset was generated with
+	/// [`StableDiffusionTxt2ImgOptions::with_batch_subset`]: each image is the next element marked `true`, so
+	/// [`images`](Self::images) hold the generated elements in ascending index order. Empty if the full batch was
+	/// generated. See [`StableDiffusionOutput::aligned_images`].
+	pub generated: Vec<bool>,
+	/// The seed the images were generated with. This is the options' seed (or the random seed drawn if none was
+	/// given), unless generation was retried with a new seed after producing NaNs; see
+	/// [`StableDiffusionTxt2ImgOptions::with_retry_on_nan`].
+	pub seed: u64
+}
+
+impl StableDiffusionOutput {
+	/// Returns the images aligned to the indices of the full batch, with `None` in place of the elements skipped with
+	/// [`StableDiffusionTxt2ImgOptions::with_batch_subset`]. If the full batch was generated, every image is returned.
+	pub fn aligned_images(&self) -> Vec<Option<&ImageRef>> {
+		if self.generated.is_empty() {
+			return self.images.iter().map(Some).collect();
+		}
+		let mut images = self.images.iter();
+		self.generated.iter().map(|&generated| if generated { images.next() } else { None }).collect()
+	}
+}
+
+/// The number of pixels of a decoded image whose values were outside `[0, 1]` and had to be clamped, to detect
+/// blown-out or crushed generations; see [`StableDiffusionTxt2ImgOptions::with_clamp_reports`].
+///
+/// Each pixel is counted at most once, by the channel furthest outside `[0, 1]`: a pixel with one channel below 0 and
+/// another above 1 counts as clamped low if its lowest channel is further below 0 than its highest is above 1, and
+/// as clamped high otherwise, including when both are equally far out. Values of exactly 0 or 1, and NaNs, are not
+/// counted as clamped.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClampReport {
+	/// The number of pixels clamped to 0.
+	pub clamped_low: usize,
+	/// The number of pixels clamped to 1.
+	pub clamped_high: usize
+}
+
+impl ClampReport {
+	/// Counts the clamped pixels of an image given as the channel values of each pixel.
+	pub(crate) fn from_pixels<'a>(pixels: impl IntoIterator<Item = ArrayView1<'a, f32>>) -> Self {
+		let mut report = ClampReport::default();
+		for pixel in pixels {
+			let (below, above) = pixel.iter().fold((0.0_f32, 0.0_f32), |(below, above), &x| (below.max(-x), above.max(x - 1.0)));
+			if above > 0.0 && above >= below {
+				report.clamped_high += 1;
+			} else if below > 0.0 {
+				report.clamped_low += 1;
+			}
+		}
+		report
+	}
+}
+
+/// A pipeline which generates images from text, implemented by [`StableDiffusionPipeline`] &, with the `mock`
+/// feature, by `MockPipeline`, so that code driving generation can be tested without loading any models.
+pub trait TextToImagePipeline {
+	/// Generates images from the text prompt(s) of `options`, like [`StableDiffusionTxt2ImgOptions::run_with_output`].
+	fn txt2img<S: DiffusionScheduler>(&self, scheduler: &mut S, options: &StableDiffusionTxt2ImgOptions) -> anyhow::Result<StableDiffusionOutput>;
+}
+
+/// Describes how to handle a VAE decoder output whose width or height doesn't match the expected image size.
+///
+/// Some nonstandard VAE exports pad their outputs, producing an image slightly larger (or smaller) than 8x the latent
+/// resolution.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VAEOutputMismatch {
+	/// Return an error describing the expected & actual dimensions. **This is the default.**
+	#[default]
+	Error,
+	/// Crop the bottom/right of the decoded image if it is too large, or repeat its edge pixels if it is too small.
+	CropOrPad
+}
+
+/// The transfer function applied to each channel of a decoded image, after clamping to `[0, 1]` and before conversion
+/// to a [`DynamicImage`].
+///
+/// The VAE's output is treated as-is by default. Displays & color-managed workflows expecting a particular encoding
+/// can use this to sRGB-encode the output or apply a custom gamma, which affects perceived brightness & contrast.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum ColorTransfer {
+	/// Leave decoded values unchanged. **This is the default.**
+	#[default]
+	None,
+	/// Encode linear values with the sRGB transfer function (IEC 61966-2-1):
+	/// `12.92 * x` for `x <= 0.0031308`, otherwise `1.055 * x^(1 / 2.4) - 0.055`.
+	Srgb,
+	/// Encode with a pure power function of the given gamma: `x^(1 / gamma)`. A gamma above 1 brightens midtones, a
+	/// gamma below 1 darkens them. The gamma must be positive & finite; pipelines fail to load otherwise.
+	Gamma(f32)
+}
+
+impl ColorTransfer {
+	/// Applies this transfer function to a single value in `[0, 1]`.
+	pub fn apply(&self, x: f32) -> f32 {
+		match *self {
+			ColorTransfer::None => x,
+			ColorTransfer::Srgb => {
+				if x <= 0.0031308 {
+					12.92 * x
+				} else {
+					1.055 * x.powf(1.0 / 2.4) - 0.055
+				}
+			}
+			ColorTransfer::Gamma(gamma) => x.powf(1.0 / gamma)
+		}
+	}
+
+	pub(crate) fn validate(&self) -> anyhow::Result<()> {
+		if let ColorTransfer::Gamma(gamma) = *self {
+			if !(gamma.is_finite() && gamma > 0.0) {
+				anyhow::bail!("color transfer gamma must be positive & finite, but is {gamma}");
+			}
+		}
+		Ok(())
+	}
 }
 
 /// Describes a function to be called on each step of the pipeline.
@@ -58,6 +600,18 @@ pub enum StableDiffusionCallback {
 		/// - **`latents`** (`Array4<f32>`): Scheduler latent outputs for this step.
 		cb: Box<dyn Fn(usize, f32, Array4<f32>) -> bool>
 	},
+	/// A callback to receive this step's latents as a compact float16 copy. This halves the size of the per-step
+	/// allocation compared to [`StableDiffusionCallback::Latents`], which is useful when the latents are only used
+	/// for e.g. generating previews.
+	LatentsF16 {
+		/// Describes how frequently to call this callback (3 = every 3 steps).
+		frequency: usize,
+		/// Function Parameters:
+		/// - **`step`** (usize): The current step number.
+		/// - **`timestep`** (f32): This step's timestep.
+		/// - **`latents`** ([`HalfLatents`]): Scheduler latent outputs for this step, converted to float16.
+		cb: Box<dyn Fn(usize, f32, HalfLatents) -> bool>
+	},
 	/// A callback to receive this step's fully decoded latents, to be used for e.g. showing image progress visually.
 	/// This is very expensive, as it will execute the VAE decoder on each call. See
 	/// [`StableDiffusionCallback::ApproximateDecoded`] for an approximated version.
@@ -82,6 +636,132 @@ pub enum StableDiffusionCallback {
 		/// - **`timestep`** (f32): This step's timestep.
 		/// - **`image`** (`Vec<DynamicImage>`): Vector of approximated decoded images for this step.
 		cb: Box<dyn Fn(usize, f32, Vec<DynamicImage>) -> bool>
+	},
+	#[doc = include_str!("_doc/callback-region-decoded.md")]
+	RegionDecoded {
+		/// The region of the image to decode, in pixels. Its position & size must be divisible by 8.
+		region: ImageRegion,
+		/// Describes how frequently to call this callback (3 = every 3 steps).
+		frequency: usize,
+		/// Function Parameters:
+		/// - **`step`** (usize): The current step number.
+		/// - **`timestep`** (f32): This step's timestep.
+		/// - **`image`** (`Vec<DynamicImage>`): Vector of decoded images of the region for this step.
+		cb: Box<dyn Fn(usize, f32, Vec<DynamicImage>) -> bool>
+	},
+	/// A progress callback which is also given the [`GenerationStage`] the step belongs to, to tell the base & refiner
+	/// stages of a [base+refiner generation](StableDiffusionPipeline::txt2img_with_refiner) apart.
+	Staged {
+		/// Describes how frequently to call this callback (3 = every 3 steps).
+		frequency: usize,
+		/// Function Parameters:
+		/// - **`stage`** ([`GenerationStage`]): The stage of the generation.
+		/// - **`step`** (usize): The current step number, counted across all stages.
+		/// - **`timestep`** (f32): This step's timestep.
+		cb: Box<dyn Fn(GenerationStage, usize, f32) -> bool>
+	}
+}
+
+impl StableDiffusionCallback {
+	/// Calls the callback for step `i` if its frequency is due, decoding the latents with `decoder` for callbacks that
+	/// receive images. Returns whether generation should continue.
+	pub(crate) fn invoke<D: LatentsDecoder>(
+		&self,
+		decoder: &D,
+		stage: GenerationStage,
+		i: usize,
+		timestep: f32,
+		latents: &Array4<f32>
+	) -> anyhow::Result<bool> {
+		Ok(match self {
+			StableDiffusionCallback::Progress { frequency, cb } if i % frequency == 0 => cb(i, timestep),
+			StableDiffusionCallback::Latents { frequency, cb } if i % frequency == 0 => cb(i, timestep, latents.clone()),
+			StableDiffusionCallback::LatentsF16 { frequency, cb } if i % frequency == 0 => cb(i, timestep, HalfLatents::from_latents(latents.view())),
+			StableDiffusionCallback::Decoded { frequency, cb } if i != 0 && i % frequency == 0 => cb(i, timestep, decoder.decode_latents(latents.view())?),
+			StableDiffusionCallback::ApproximateDecoded { frequency, cb } if i != 0 && i % frequency == 0 => {
+				cb(i, timestep, decoder.approximate_decode_latents(latents.view())?)
+			}
+			StableDiffusionCallback::RegionDecoded { region, frequency, cb } if i != 0 && i % frequency == 0 => {
+				cb(i, timestep, decoder.decode_latents_region(latents.view(), region)?)
+			}
+			StableDiffusionCallback::Staged { frequency, cb } if i % frequency == 0 => cb(stage, i, timestep),
+			_ => true
+		})
+	}
+}
+
+/// Decodes latents into images for [`StableDiffusionCallback`]s.
+pub(crate) trait LatentsDecoder {
+	fn decode_latents(&self, latents: ArrayView4<'_, f32>) -> anyhow::Result<Vec<DynamicImage>>;
+
+	fn approximate_decode_latents(&self, latents: ArrayView4<'_, f32>) -> anyhow::Result<Vec<DynamicImage>>;
+
+	fn decode_latents_region(&self, latents: ArrayView4<'_, f32>, region: &ImageRegion) -> anyhow::Result<Vec<DynamicImage>>;
+}
+
+/// The stage of a generation, as passed to [`StableDiffusionCallback::Staged`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenerationStage {
+	/// A single-stage generation.
+	Full,
+	/// The base stage of a base+refiner generation, which stops at
+	/// [`denoising_end`](StableDiffusionTxt2ImgOptions::denoising_end).
+	Base,
+	/// The refiner stage, which continues from the base stage's latents; see [`StableDiffusionPipeline::refine`].
+	Refiner
+}
+
+/// A callback receiving an approximate preview of the images once during generation; see
+/// [`StableDiffusionTxt2ImgOptions::with_preview`].
+pub struct StableDiffusionPreview {
+	/// The number of steps after which the preview is generated.
+	pub step: usize,
+	pub(crate) cb: Box<dyn Fn(Vec<DynamicImage>)>
+}
+
+impl Debug for StableDiffusionPreview {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("StableDiffusionPreview").field("step", &self.step).finish_non_exhaustive()
+	}
+}
+
+/// A float16 copy of a step's latents, as passed to [`StableDiffusionCallback::LatentsF16`].
+///
+/// Use [`HalfLatents::to_f32`] to convert back into an `Array4<f32>` if full precision is needed.
+#[derive(Debug, Clone)]
+pub struct HalfLatents {
+	shape: [usize; 4],
+	data: Vec<f16>
+}
+
+impl HalfLatents {
+	/// Converts float32 latents into a float16 copy.
+	pub fn from_latents(latents: ArrayView4<'_, f32>) -> Self {
+		let (b, c, h, w) = latents.dim();
+		Self {
+			shape: [b, c, h, w],
+			data: latents.iter().map(|f| f16::from_f32(*f)).collect()
+		}
+	}
+
+	/// Returns the shape of the latents, in `NCHW` order.
+	pub fn shape(&self) -> [usize; 4] {
+		self.shape
+	}
+
+	/// Returns the float16 latent values in row-major `NCHW` order.
+	pub fn as_slice(&self) -> &[f16] {
+		&self.data
+	}
+
+	/// Consumes these latents, returning the float16 values in row-major `NCHW` order.
+	pub fn into_vec(self) -> Vec<f16> {
+		self.data
+	}
+
+	/// Converts these latents back into a float32 array.
+	pub fn to_f32(&self) -> Array4<f32> {
+		Array4::from_shape_vec(self.shape, self.data.iter().map(|f| f.to_f32()).collect()).expect("HalfLatents shape does not match data length")
 	}
 }
 
@@ -90,3 +770,48 @@ impl Debug for StableDiffusionCallback {
 		f.write_str("<StableDiffusionCallback>")
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use ndarray::{arr2, Axis};
+
+	use super::{ClampReport, ColorTransfer};
+
+	#[test]
+	fn color_transfer_functions() {
+		for x in [0.0, 0.001, 0.25, 0.5, 1.0] {
+			assert_eq!(ColorTransfer::None.apply(x), x);
+			assert!((ColorTransfer::Gamma(1.0).apply(x) - x).abs() < 1e-6);
+		}
+		assert!((ColorTransfer::Gamma(2.0).apply(0.25) - 0.5).abs() < 1e-6);
+		// both pieces of the sRGB curve, which meet (nearly) continuously at the threshold
+		assert!((ColorTransfer::Srgb.apply(0.001) - 0.01292).abs() < 1e-6);
+		assert!((ColorTransfer::Srgb.apply(0.5) - 0.735_357).abs() < 1e-5);
+		assert!((ColorTransfer::Srgb.apply(1.0) - 1.0).abs() < 1e-6);
+		assert!((ColorTransfer::Srgb.apply(0.0031308) - ColorTransfer::Srgb.apply(0.003131)).abs() < 1e-5);
+	}
+
+	#[test]
+	fn gamma_must_be_positive_and_finite() {
+		assert!(ColorTransfer::Gamma(2.2).validate().is_ok());
+		assert!(ColorTransfer::Srgb.validate().is_ok());
+		for gamma in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+			assert!(ColorTransfer::Gamma(gamma).validate().is_err(), "{gamma}");
+		}
+	}
+
+	#[test]
+	fn clamp_report_counts_pixels_once() {
+		let pixels = arr2(&[
+			[0.0, 0.5, 1.0],
+			[-0.1, 0.5, 0.5],
+			[1.2, 0.5, -0.1],
+			[-0.3, 1.2, 0.5],
+			[-0.5, 1.5, 0.5],
+			[f32::NAN, 0.5, 0.5]
+		]);
+		let report = ClampReport::from_pixels(pixels.lanes(Axis(1)));
+		// the tie in the 5th pixel counts as clamped high
+		assert_eq!(report, ClampReport { clamped_low: 2, clamped_high: 2 });
+	}
+}
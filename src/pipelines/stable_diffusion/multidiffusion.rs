@@ -0,0 +1,157 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{ImageRegion, Prompt};
+
+/// Options for [MultiDiffusion](https://arxiv.org/abs/2302.08113) tiled generation, which enables generating images
+/// larger than the model's native resolution (e.g. panoramas) by denoising overlapping tiles and averaging them.
+///
+/// Each tile is denoised with its own UNet pass. The noise predictions of all tiles covering a latent pixel are
+/// averaged with equal weight, which blends tiles smoothly in their overlapping areas.
+///
+/// ## Regional prompts
+/// Optionally, different tiles can use different prompts: each tile uses the prompt of the region containing its
+/// center, or the nearest region if its center lies outside all regions. Because overlaps are averaged, regions with
+/// different prompts transition smoothly over the width of the tile overlap. If no regions are given, every tile uses
+/// the main prompt.
+///
+/// ## Performance
+/// Each step runs the UNet once per tile, so the cost of a step grows with the number of tiles:
+/// `ceil((width - tile_size) / stride + 1) * ceil((height - tile_size) / stride + 1)`, where
+/// `stride = tile_size - tile_overlap`. Each distinct regional prompt is encoded once before generation.
+#[derive(Debug, Clone)]
+pub struct MultiDiffusionOptions {
+	/// Size of each (square) tile, in pixels. **Must be divisible by 8.** Defaults to 512.
+	pub tile_size: u32,
+	/// Overlap between adjacent tiles, in pixels. **Must be divisible by 8** and smaller than `tile_size`. Defaults to
+	/// 128.
+	pub tile_overlap: u32,
+	/// Regional prompts; see [the struct docs](MultiDiffusionOptions#regional-prompts). Each prompt must have either 1
+	/// prompt or as many prompts as the main positive prompt.
+	pub regions: Vec<(ImageRegion, Prompt)>
+}
+
+impl Default for MultiDiffusionOptions {
+	fn default() -> Self {
+		Self {
+			tile_size: 512,
+			tile_overlap: 128,
+			regions: Vec::new()
+		}
+	}
+}
+
+impl MultiDiffusionOptions {
+	/// Adds a regional prompt.
+	pub fn with_region(mut self, region: ImageRegion, prompt: impl Into<Prompt>) -> Self {
+		self.regions.push((region, prompt.into()));
+		self
+	}
+
+	pub(crate) fn validate(&self) -> anyhow::Result<()> {
+		if self.tile_size == 0 || self.tile_size % 8 != 0 || self.tile_overlap % 8 != 0 {
+			anyhow::bail!("MultiDiffusion `tile_size` ({}) and `tile_overlap` ({}) must be divisible by 8", self.tile_size, self.tile_overlap);
+		}
+		if self.tile_overlap >= self.tile_size {
+			anyhow::bail!("MultiDiffusion `tile_overlap` ({}) must be smaller than `tile_size` ({})", self.tile_overlap, self.tile_size);
+		}
+		Ok(())
+	}
+
+	/// Computes the tiles covering a latent of the given size, as `(y, x, height, width)` in latent pixels.
+	pub(crate) fn tiles(&self, latent_height: usize, latent_width: usize) -> Vec<(usize, usize, usize, usize)> {
+		let tile = (self.tile_size / 8) as usize;
+		let stride = ((self.tile_size - self.tile_overlap) / 8) as usize;
+		let (tile_height, tile_width) = (tile.min(latent_height), tile.min(latent_width));
+		let mut tiles = Vec::new();
+		for y in tile_starts(latent_height, tile_height, stride) {
+			for x in tile_starts(latent_width, tile_width, stride) {
+				tiles.push((y, x, tile_height, tile_width));
+			}
+		}
+		tiles
+	}
+
+	/// Returns the index of the region whose prompt should be used for the given tile, or `None` if there are no
+	/// regions.
+	pub(crate) fn region_for_tile(&self, tile: (usize, usize, usize, usize)) -> Option<usize> {
+		let (y, x, height, width) = tile;
+		let (center_x, center_y) = ((x as f32 + width as f32 / 2.0) * 8.0, (y as f32 + height as f32 / 2.0) * 8.0);
+		self.regions
+			.iter()
+			.enumerate()
+			.map(|(i, (region, _))| (i, region.distance_sq(center_x, center_y)))
+			.min_by(|a, b| a.1.total_cmp(&b.1))
+			.map(|(i, _)| i)
+	}
+}
+
+/// Start offsets of tiles of size `tile` with the given `stride` covering `len`; the last tile is aligned to the end.
+fn tile_starts(len: usize, tile: usize, stride: usize) -> Vec<usize> {
+	let mut starts: Vec<usize> = (0..=(len - tile)).step_by(stride.max(1)).collect();
+	if *starts.last().unwrap() != len - tile {
+		starts.push(len - tile);
+	}
+	starts
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{tile_starts, MultiDiffusionOptions};
+	use crate::ImageRegion;
+
+	#[test]
+	fn tile_starts_cover_len() {
+		assert_eq!(tile_starts(10, 4, 3), vec![0, 3, 6]);
+		// the last tile is aligned to the end instead of overhanging it
+		assert_eq!(tile_starts(11, 4, 3), vec![0, 3, 6, 7]);
+		assert_eq!(tile_starts(8, 8, 6), vec![0]);
+		// a stride of 0 (overlap == tile size) doesn't loop forever
+		assert_eq!(tile_starts(4, 2, 0), vec![0, 1, 2]);
+	}
+
+	#[test]
+	fn tiles() {
+		let options = MultiDiffusionOptions::default();
+		// 768x512: 64x64 latent tiles with a stride of 48 latent pixels
+		assert_eq!(options.tiles(64, 96), vec![(0, 0, 64, 64), (0, 32, 64, 64)]);
+		// 1024x640: the last row & column are aligned to the bottom/right edges
+		assert_eq!(
+			options.tiles(80, 128),
+			vec![(0, 0, 64, 64), (0, 48, 64, 64), (0, 64, 64, 64), (16, 0, 64, 64), (16, 48, 64, 64), (16, 64, 64, 64)]
+		);
+		// tiles are shrunk to latents smaller than a tile
+		assert_eq!(options.tiles(8, 100), vec![(0, 0, 8, 64), (0, 36, 8, 64)]);
+	}
+
+	#[test]
+	fn region_for_tile() {
+		assert_eq!(MultiDiffusionOptions::default().region_for_tile((0, 0, 64, 64)), None);
+
+		let options = MultiDiffusionOptions::default()
+			.with_region(ImageRegion::new(0, 0, 256, 512), "left")
+			.with_region(ImageRegion::new(256, 0, 256, 512), "right");
+		// tiles use the region containing their center (at (16, 16) & (496, 16) px)
+		assert_eq!(options.region_for_tile((0, 0, 4, 4)), Some(0));
+		assert_eq!(options.region_for_tile((0, 60, 4, 4)), Some(1));
+
+		let options = MultiDiffusionOptions::default()
+			.with_region(ImageRegion::new(0, 0, 64, 64), "top left")
+			.with_region(ImageRegion::new(448, 448, 64, 64), "bottom right");
+		// centered at (256, 96) px, outside both regions but closer to the first
+		assert_eq!(options.region_for_tile((8, 28, 8, 8)), Some(0));
+		// centered at (256, 416) px
+		assert_eq!(options.region_for_tile((48, 28, 8, 8)), Some(1));
+	}
+}
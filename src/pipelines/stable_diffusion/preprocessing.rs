@@ -0,0 +1,69 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use image::{imageops::FilterType, DynamicImage};
+use ndarray::Array4;
+
+/// The filter used to resize a conditioning image, e.g. an init image, a ControlNet conditioning image, or an
+/// inpainting mask, to the size it is used at.
+///
+/// Smooth filters like [`ResizeFilter::Lanczos3`] anti-alias photos, but blur hard edges into intermediate values,
+/// which destroys the signal of binary inputs like edge maps & masks. Each input defaults to the filter suiting it:
+/// - init images ([`with_resize_filter`](crate::StableDiffusionImg2ImgOptions::with_resize_filter)), and reference
+///   images & images upscaled by [`super_resolve`](crate::StableDiffusionPipeline::super_resolve)
+///   ([`with_resize_filter`](crate::StableDiffusionTxt2ImgOptions::with_resize_filter)): [`ResizeFilter::Lanczos3`];
+/// - inpainting masks ([`with_mask_resize_filter`](crate::StableDiffusionTxt2ImgOptions::with_mask_resize_filter),
+///   [`prepare_inpaint_mask_with_filter`](crate::prepare_inpaint_mask_with_filter)): [`ResizeFilter::Nearest`].
+///
+/// ControlNet conditioning images aren't resized unless a filter is set with
+/// [`ControlNetConfig::with_resize_filter`](crate::ControlNetConfig::with_resize_filter); use [`ResizeFilter::Nearest`]
+/// for edge maps, poses, or segmentation maps, and a smooth filter for photo-like inputs such as depth maps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeFilter {
+	/// Nearest-neighbor sampling, which only ever produces values of the input image.
+	Nearest,
+	/// Linear (bilinear) filtering.
+	Triangle,
+	/// Cubic filtering.
+	CatmullRom,
+	/// Lanczos filtering with a window of 3, which keeps photos sharpest.
+	Lanczos3
+}
+
+impl ResizeFilter {
+	/// Resizes `image` to exactly `width`x`height` with this filter.
+	pub fn resize(&self, image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+		image.resize_exact(width, height, self.filter_type())
+	}
+
+	pub(crate) fn filter_type(&self) -> FilterType {
+		match self {
+			ResizeFilter::Nearest => FilterType::Nearest,
+			ResizeFilter::Triangle => FilterType::Triangle,
+			ResizeFilter::CatmullRom => FilterType::CatmullRom,
+			ResizeFilter::Lanczos3 => FilterType::Lanczos3
+		}
+	}
+}
+
+/// Resizes `image` to `width`x`height` with `filter` (unless it already is that size) and converts it to an RGB array
+/// of shape `(1, 3, height, width)` with values in `[0, 1]`.
+pub(crate) fn image_to_array(image: &DynamicImage, filter: ResizeFilter, width: u32, height: u32) -> Array4<f32> {
+	let image = if image.width() == width && image.height() == height {
+		image.to_rgb32f()
+	} else {
+		filter.resize(image, width, height).to_rgb32f()
+	};
+	Array4::from_shape_fn((1, 3, height as usize, width as usize), |(_, c, y, x)| image.get_pixel(x as u32, y as u32).0[c])
+}
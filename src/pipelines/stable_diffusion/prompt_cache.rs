@@ -0,0 +1,448 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+	fmt::Write as _,
+	fs,
+	io::{self, Cursor, Read},
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Mutex
+	},
+	time::UNIX_EPOCH
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use half::f16;
+use ndarray::{ArrayD, IxDyn};
+
+use super::impl_main::fnv1a;
+use crate::{
+	config::{StableDiffusionConfig, TokenizerConfig},
+	Prompt, PromptWeighting, StableDiffusionOptions, StableDiffusionPipeline
+};
+
+/// Magic bytes at the start of every persisted cache entry.
+const ENTRY_MAGIC: &[u8; 8] = b"PYKEPEMB";
+/// The version of the persisted entry format. Entries of other versions are treated as stale. Version 2 invalidates
+/// entries of models with a separate pad token, which were previously padded with EOS.
+const ENTRY_VERSION: u32 = 2;
+/// The file extension of persisted cache entries.
+const ENTRY_EXTENSION: &str = "embeddings";
+
+/// Options for caching text embeddings of prompts; see
+/// [`StableDiffusionOptions::with_prompt_cache`](crate::StableDiffusionOptions::with_prompt_cache).
+///
+/// Entries hold the embeddings of the prompts given to
+/// [`StableDiffusionPipeline::encode_prompt_with_weighting`](crate::StableDiffusionPipeline::encode_prompt_with_weighting)
+/// (which all text prompts of a generation go through). The conditional embeddings of the prompts & the unconditional
+/// embeddings of the negative prompts are cached as separate entries, each keyed by its own texts, their weightings &
+/// the length both are padded to, so changing only the negative prompt re-encodes only the negative prompt (and vice
+/// versa). The embeddings are only assembled into the classifier-free guidance batch afterwards. Prompts are keyed
+/// after the [prompt filter](crate::StableDiffusionOptions::with_prompt_filter) has run.
+///
+/// Since both halves are padded to the length of the longer one, changing a prompt across a 75-token boundary also
+/// changes the key of the other half. [`StableDiffusionPipeline::prompt_cache_stats`] counts hits & misses.
+///
+/// ## Disk persistence
+/// If `dir` is set, every newly encoded entry is also written to a file in `dir`, and entries not resident in memory
+/// are looked up there before encoding, so embeddings survive restarts. Each file holds a versioned binary entry:
+/// the hash of its prompts, a fingerprint of the options affecting encoding, a fingerprint of the model's text
+/// encoder, tokenizer & text embeddings, and the embeddings compressed to float16. Embeddings loaded from disk are
+/// therefore rounded to float16 precision, which changes generated images very slightly compared to freshly encoded
+/// embeddings.
+///
+/// Entries are invalidated automatically: an entry whose fingerprints don't match the pipeline (e.g. because the text
+/// encoder's hash in the model config, the tokenizer file, or the [`WeightNormalization`](crate::WeightNormalization)
+/// changed) is never used. Entries of different fingerprints are stored in separate files, so one directory can be
+/// shared by pipelines of different models. Unreadable, truncated or corrupt files are skipped with a warning &
+/// overwritten once the prompt is encoded again, and failing to write an entry only logs a warning; the cache never
+/// fails an encode. The directory is never cleaned up, so it grows with the number of distinct prompts & models
+/// encoded; it can be deleted at any time.
+///
+/// Text embeddings added at runtime (e.g. for textual inversion) are only tracked by their number: replacing
+/// the embedding of an existing token does not invalidate the cache, so call
+/// [`StableDiffusionPipeline::clear_prompt_cache`](crate::StableDiffusionPipeline::clear_prompt_cache) & delete the
+/// cache directory after doing so.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptCacheConfig {
+	/// The maximum number of entries kept in memory. When the cache is full, the least recently used entry is evicted.
+	/// `0` disables the in-memory cache, so that entries are only persisted to `dir`.
+	pub max_resident: usize,
+	/// If set, entries are also persisted to this directory; see [the struct docs](PromptCacheConfig#disk-persistence).
+	pub dir: Option<PathBuf>
+}
+
+impl PromptCacheConfig {
+	/// Creates a cache keeping up to `max_resident` entries in memory, without disk persistence.
+	pub fn new(max_resident: usize) -> Self {
+		Self { max_resident, dir: None }
+	}
+
+	/// Persists entries to `dir`, which is created if it does not exist.
+	pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+		self.dir = Some(dir.into());
+		self
+	}
+}
+
+struct CacheEntry {
+	key: String,
+	embeddings: ArrayD<f32>
+}
+
+/// The prompt embedding cache of a pipeline.
+pub(crate) struct PromptCache {
+	config: PromptCacheConfig,
+	/// Fingerprint of the text encoder, tokenizer & text embeddings file the embeddings are encoded with.
+	model_fingerprint: u64,
+	/// Fingerprint of the pipeline options which affect how prompts are encoded.
+	options_fingerprint: u64,
+	/// Resident entries, least recently used first.
+	entries: Mutex<Vec<CacheEntry>>,
+	hits: AtomicUsize,
+	misses: AtomicUsize
+}
+
+/// Hit & miss counts of a pipeline's prompt cache; see [`StableDiffusionPipeline::prompt_cache_stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PromptCacheStats {
+	/// The number of lookups served from memory or disk.
+	pub hits: usize,
+	/// The number of lookups which had to be encoded.
+	pub misses: usize
+}
+
+impl PromptCache {
+	/// Creates an empty cache for the model at `root`.
+	pub(crate) fn new(config: PromptCacheConfig, root: &Path, model_config: &StableDiffusionConfig, options: &StableDiffusionOptions) -> Self {
+		let hashes = &model_config.hashes;
+		let text_embeddings = hashes.text_embeddings.as_deref().unwrap_or_default();
+		let tokenizer = tokenizer_digest(root, &model_config.tokenizer);
+		let model_fingerprint = fnv1a(
+			hashes.text_encoder.bytes().chain([0]).chain(text_embeddings.bytes()).chain([0]).chain(tokenizer.to_le_bytes())
+		);
+		let options_fingerprint = fnv1a(format!("{:?};{:?}", options.weight_normalization, options.truncation_strategy).into_bytes());
+		Self {
+			config,
+			model_fingerprint,
+			options_fingerprint,
+			entries: Mutex::new(Vec::new()),
+			hits: AtomicUsize::new(0),
+			misses: AtomicUsize::new(0)
+		}
+	}
+
+	/// Invalidates the cache after the text encoder was replaced with the model at `path`, identified by its path,
+	/// size & modification time.
+	pub(crate) fn replace_text_encoder(&mut self, path: &Path) {
+		let metadata = fs::metadata(path).ok();
+		let size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+		let modified = metadata
+			.and_then(|metadata| metadata.modified().ok())
+			.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+			.map_or(0, |modified| modified.as_nanos());
+		let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+		self.model_fingerprint = fnv1a(
+			self.model_fingerprint
+				.to_le_bytes()
+				.into_iter()
+				.chain(path.to_string_lossy().bytes())
+				.chain(size.to_le_bytes())
+				.chain(modified.to_le_bytes())
+		);
+		self.clear();
+	}
+
+	/// Returns the cached embeddings for `key`, looking them up on disk if they are not resident.
+	pub(crate) fn get(&self, key: &str) -> Option<ArrayD<f32>> {
+		let embeddings = self.lookup(key);
+		let counter = if embeddings.is_some() { &self.hits } else { &self.misses };
+		counter.fetch_add(1, Ordering::Relaxed);
+		embeddings
+	}
+
+	fn lookup(&self, key: &str) -> Option<ArrayD<f32>> {
+		{
+			let mut entries = self.entries.lock().unwrap();
+			if let Some(i) = entries.iter().position(|entry| entry.key == key) {
+				let entry = entries.remove(i);
+				let embeddings = entry.embeddings.clone();
+				entries.push(entry);
+				return Some(embeddings);
+			}
+		}
+		let embeddings = self.load(key)?;
+		self.make_resident(key, embeddings.clone());
+		Some(embeddings)
+	}
+
+	pub(crate) fn stats(&self) -> PromptCacheStats {
+		PromptCacheStats {
+			hits: self.hits.load(Ordering::Relaxed),
+			misses: self.misses.load(Ordering::Relaxed)
+		}
+	}
+
+	/// Caches newly encoded embeddings for `key`, persisting them to disk if enabled.
+	pub(crate) fn insert(&self, key: &str, embeddings: &ArrayD<f32>) {
+		self.make_resident(key, embeddings.clone());
+		if let Some(path) = self.entry_path(key) {
+			if let Err(e) = self.store(&path, key, embeddings) {
+				tracing::warn!("failed to persist prompt embeddings to `{}`: {e}", path.display());
+			}
+		}
+	}
+
+	/// Evicts all resident entries. Persisted entries are kept.
+	pub(crate) fn clear(&self) {
+		self.entries.lock().unwrap().clear();
+	}
+
+	fn make_resident(&self, key: &str, embeddings: ArrayD<f32>) {
+		if self.config.max_resident == 0 {
+			return;
+		}
+		let mut entries = self.entries.lock().unwrap();
+		entries.retain(|entry| entry.key != key);
+		if entries.len() >= self.config.max_resident {
+			entries.remove(0);
+		}
+		entries.push(CacheEntry { key: key.to_owned(), embeddings });
+	}
+
+	fn entry_path(&self, key: &str) -> Option<PathBuf> {
+		let dir = self.config.dir.as_ref()?;
+		// entries of other models & options get their own files, so pipelines can share a directory
+		let name = fnv1a(self.model_fingerprint.to_le_bytes().into_iter().chain(self.options_fingerprint.to_le_bytes()).chain(key.bytes()));
+		Some(dir.join(format!("{name:016x}.{ENTRY_EXTENSION}")))
+	}
+
+	fn load(&self, key: &str) -> Option<ArrayD<f32>> {
+		let path = self.entry_path(key)?;
+		let bytes = match fs::read(&path) {
+			Ok(bytes) => bytes,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+			Err(e) => {
+				tracing::warn!("skipping unreadable prompt cache entry `{}`: {e}", path.display());
+				return None;
+			}
+		};
+		match read_entry(&bytes, self.model_fingerprint, self.options_fingerprint, key) {
+			Ok(embeddings) => embeddings,
+			Err(e) => {
+				tracing::warn!("skipping corrupt prompt cache entry `{}`: {e}", path.display());
+				None
+			}
+		}
+	}
+
+	fn store(&self, path: &Path, key: &str, embeddings: &ArrayD<f32>) -> io::Result<()> {
+		if let Some(dir) = path.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		// written to a temporary file first, so readers never see a partially written entry
+		let temp_path = path.with_extension(format!("{ENTRY_EXTENSION}.tmp{}", std::process::id()));
+		fs::write(&temp_path, write_entry(self.model_fingerprint, self.options_fingerprint, key, embeddings))?;
+		fs::rename(&temp_path, path)
+	}
+}
+
+/// Builds the cache key of the conditional embeddings of a (broadcast) prompt batch, or the unconditional embeddings of
+/// a negative prompt batch, padded to `tokens` tokens.
+pub(crate) fn cache_key(prompt: &Prompt, unconditional: bool, weighting: &[PromptWeighting], tokens: usize, text_embeddings: usize) -> String {
+	let half = if unconditional { "uncond" } else { "cond" };
+	// texts are length-prefixed, so no prompt can be confused with another
+	let mut key = format!("{half};tokens={tokens};embeddings={text_embeddings};weighting={weighting:?}");
+	for text in prompt.iter() {
+		let _ = write!(key, ";prompt={}:{text}", text.len());
+	}
+	key
+}
+
+/// Returns a digest of the tokenizer described by a model config, covering its files & special tokens. Files which
+/// can't be read are left out, since loading the tokenizer fails anyway.
+fn tokenizer_digest(root: &Path, config: &TokenizerConfig) -> u64 {
+	let mut bytes = format!("{config:?}").into_bytes();
+	if let TokenizerConfig::CLIPTokenizer { path, .. } = config {
+		for file in [root.join(path), root.join("special_tokens_map.json")] {
+			bytes.extend(fs::read(file).unwrap_or_default());
+			bytes.push(0);
+		}
+	}
+	fnv1a(bytes)
+}
+
+/// Serializes a cache entry: the magic bytes & format version, the fingerprints, the prompt hash, the key, the
+/// embeddings' shape & float16 values, and a checksum of everything before it, all little-endian.
+fn write_entry(model_fingerprint: u64, options_fingerprint: u64, key: &str, embeddings: &ArrayD<f32>) -> Vec<u8> {
+	let mut bytes = Vec::with_capacity(48 + key.len() + embeddings.ndim() * 8 + embeddings.len() * 2);
+	bytes.extend_from_slice(ENTRY_MAGIC);
+	bytes.extend_from_slice(&ENTRY_VERSION.to_le_bytes());
+	bytes.extend_from_slice(&model_fingerprint.to_le_bytes());
+	bytes.extend_from_slice(&options_fingerprint.to_le_bytes());
+	bytes.extend_from_slice(&fnv1a(key.bytes()).to_le_bytes());
+	bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+	bytes.extend_from_slice(key.as_bytes());
+	bytes.extend_from_slice(&(embeddings.ndim() as u32).to_le_bytes());
+	for &dim in embeddings.shape() {
+		bytes.extend_from_slice(&(dim as u64).to_le_bytes());
+	}
+	for &value in embeddings.iter() {
+		bytes.extend_from_slice(&f16::from_f32(value).to_le_bytes());
+	}
+	let checksum = fnv1a(bytes.iter().copied());
+	bytes.extend_from_slice(&checksum.to_le_bytes());
+	bytes
+}
+
+/// Deserializes a cache entry written by [`write_entry`]. Returns `Ok(None)` for valid entries which don't apply, i.e.
+/// entries of another format version, with other fingerprints, or for another key (a hash collision), and an error
+/// for corrupt or truncated entries.
+fn read_entry(bytes: &[u8], model_fingerprint: u64, options_fingerprint: u64, key: &str) -> io::Result<Option<ArrayD<f32>>> {
+	let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+	if bytes.len() < ENTRY_MAGIC.len() + 8 || !bytes.starts_with(ENTRY_MAGIC) {
+		return Err(invalid("not a prompt cache entry"));
+	}
+	let (body, checksum) = bytes.split_at(bytes.len() - 8);
+	if fnv1a(body.iter().copied()).to_le_bytes() != checksum {
+		return Err(invalid("checksum mismatch; the entry is truncated or corrupt"));
+	}
+
+	let mut reader = Cursor::new(&body[ENTRY_MAGIC.len()..]);
+	if reader.read_u32::<LittleEndian>()? != ENTRY_VERSION {
+		return Ok(None);
+	}
+	if reader.read_u64::<LittleEndian>()? != model_fingerprint || reader.read_u64::<LittleEndian>()? != options_fingerprint {
+		return Ok(None);
+	}
+	let _prompt_hash = reader.read_u64::<LittleEndian>()?;
+	let key_len = reader.read_u32::<LittleEndian>()? as usize;
+	if key_len > body.len() {
+		return Err(invalid("key length exceeds the entry"));
+	}
+	let mut stored_key = vec![0; key_len];
+	reader.read_exact(&mut stored_key)?;
+	if stored_key != key.as_bytes() {
+		return Ok(None);
+	}
+
+	let ndim = reader.read_u32::<LittleEndian>()? as usize;
+	if ndim > 8 {
+		return Err(invalid("embeddings have too many dimensions"));
+	}
+	let shape = (0..ndim).map(|_| -> io::Result<usize> { Ok(reader.read_u64::<LittleEndian>()? as usize) }).collect::<io::Result<Vec<_>>>()?;
+	let len = shape.iter().try_fold(1_usize, |len, &dim| len.checked_mul(dim)).ok_or_else(|| invalid("embeddings shape overflows"))?;
+	let remaining = body.len() - ENTRY_MAGIC.len() - reader.position() as usize;
+	if len.checked_mul(2) != Some(remaining) {
+		return Err(invalid("embeddings size does not match their shape"));
+	}
+	let values = (0..len).map(|_| -> io::Result<f32> { Ok(f16::from_bits(reader.read_u16::<LittleEndian>()?).to_f32()) }).collect::<io::Result<Vec<_>>>()?;
+	ArrayD::from_shape_vec(IxDyn(&shape), values).map(Some).map_err(|_| invalid("embeddings size does not match their shape"))
+}
+
+impl StableDiffusionPipeline {
+	/// Evicts all embeddings resident in the pipeline's prompt cache, if enabled with
+	/// [`StableDiffusionOptions::with_prompt_cache`]. Entries persisted to disk are kept.
+	pub fn clear_prompt_cache(&self) {
+		if let Some(cache) = self.prompt_cache.as_ref() {
+			cache.clear();
+		}
+	}
+
+	/// Returns how many lookups the pipeline's prompt cache served & missed since the pipeline was created (or its
+	/// prompt cache was last replaced), if enabled. Each encode looks up its conditional & unconditional embeddings
+	/// separately, so an encode with classifier-free guidance counts two lookups.
+	pub fn prompt_cache_stats(&self) -> Option<PromptCacheStats> {
+		self.prompt_cache.as_ref().map(PromptCache::stats)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ndarray::{ArrayD, IxDyn};
+
+	use super::{cache_key, read_entry, write_entry, PromptCache, PromptCacheConfig, PromptCacheStats};
+	use crate::{Prompt, PromptWeighting};
+
+	fn embeddings(offset: f32) -> ArrayD<f32> {
+		ArrayD::from_shape_fn(IxDyn(&[2, 3, 4]), |index| offset + (index[0] * 12 + index[1] * 4 + index[2]) as f32 / 8.0)
+	}
+
+	fn cache(max_resident: usize) -> PromptCache {
+		PromptCache {
+			config: PromptCacheConfig::new(max_resident),
+			model_fingerprint: 1,
+			options_fingerprint: 2,
+			entries: Default::default(),
+			hits: Default::default(),
+			misses: Default::default()
+		}
+	}
+
+	#[test]
+	fn entry_roundtrip() -> std::io::Result<()> {
+		let bytes = write_entry(1, 2, "key", &embeddings(0.0));
+		assert_eq!(read_entry(&bytes, 1, 2, "key")?, Some(embeddings(0.0)));
+		// stale fingerprints & hash collisions are misses, not errors
+		assert_eq!(read_entry(&bytes, 3, 2, "key")?, None);
+		assert_eq!(read_entry(&bytes, 1, 3, "key")?, None);
+		assert_eq!(read_entry(&bytes, 1, 2, "other key")?, None);
+		Ok(())
+	}
+
+	#[test]
+	fn corrupt_entries_are_errors() {
+		let bytes = write_entry(1, 2, "key", &embeddings(0.0));
+		for len in 0..bytes.len() {
+			assert!(read_entry(&bytes[..len], 1, 2, "key").is_err(), "entry truncated to {len} bytes was read");
+		}
+		for i in [0, 12, 40, bytes.len() - 1] {
+			let mut corrupt = bytes.clone();
+			corrupt[i] ^= 0x10;
+			assert!(read_entry(&corrupt, 1, 2, "key").is_err(), "entry with byte {i} flipped was read");
+		}
+		assert!(read_entry(b"", 1, 2, "key").is_err());
+	}
+
+	#[test]
+	fn evicts_least_recently_used() {
+		let cache = cache(2);
+		cache.insert("a", &embeddings(0.0));
+		cache.insert("b", &embeddings(1.0));
+		assert_eq!(cache.get("a"), Some(embeddings(0.0)));
+		cache.insert("c", &embeddings(2.0));
+		assert_eq!(cache.get("b"), None);
+		assert_eq!(cache.get("a"), Some(embeddings(0.0)));
+		assert_eq!(cache.get("c"), Some(embeddings(2.0)));
+
+		cache.clear();
+		assert_eq!(cache.get("a"), None);
+		assert_eq!(cache.stats(), PromptCacheStats { hits: 3, misses: 2 });
+		let cache = self::cache(0);
+		cache.insert("a", &embeddings(0.0));
+		assert_eq!(cache.get("a"), None);
+	}
+
+	#[test]
+	fn keys_distinguish_batches() {
+		let key = |prompt: &[&str], unconditional, tokens| cache_key(&Prompt::from(prompt), unconditional, &[PromptWeighting::Weighted], tokens, 0);
+		assert_eq!(key(&["a"], false, 77), key(&["a"], false, 77));
+		assert_ne!(key(&["a"], false, 77), key(&["a"], true, 77));
+		assert_ne!(key(&["a"], false, 77), key(&["a"], false, 152));
+		assert_ne!(key(&["a;prompt=1:b"], false, 77), key(&["a", "b"], false, 77));
+		assert_ne!(key(&["a"], false, 77), cache_key(&Prompt::from("a"), false, &[PromptWeighting::Plain], 77, 0));
+	}
+}
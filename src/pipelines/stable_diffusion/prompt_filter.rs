@@ -0,0 +1,262 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, sync::Arc};
+
+use regex::Regex;
+
+use crate::{Prompt, StableDiffusionPipeline};
+
+/// A policy filter applied to every text prompt & negative prompt right before it is tokenized; see
+/// [`StableDiffusionOptions::with_prompt_filter`](crate::StableDiffusionOptions::with_prompt_filter).
+///
+/// Filters see prompts exactly as they are passed to the pipeline's encoding methods, i.e. after any template or
+/// wildcard expansion done by the caller, and before long prompt weighting syntax like `(word:1.2)` is parsed. A filter
+/// may either reject a prompt, failing the encode with a [`PromptRejected`] error, or return a scrubbed copy of it,
+/// which is then encoded in place of the original.
+///
+/// Prompts given as token IDs (see [`PromptInput::TokenIds`](crate::PromptInput::TokenIds)) have no text to filter and
+/// are not passed to the filter.
+pub trait PromptFilter: fmt::Debug + Send + Sync {
+	/// Filters a (possibly batched) prompt, returning the prompt to encode. The returned prompt must have the same
+	/// number of prompts as `prompt`.
+	fn filter(&self, prompt: &Prompt) -> Result<Prompt, PromptRejected>;
+}
+
+/// Error returned when a [`PromptFilter`] rejects a prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptRejected {
+	/// The reason given by the filter.
+	pub reason: String
+}
+
+impl PromptRejected {
+	/// Creates a rejection with the given reason.
+	pub fn new(reason: impl Into<String>) -> Self {
+		Self { reason: reason.into() }
+	}
+}
+
+impl fmt::Display for PromptRejected {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "prompt rejected by the prompt filter: {}", self.reason)
+	}
+}
+
+impl std::error::Error for PromptRejected {}
+
+impl<F: PromptFilter + ?Sized> PromptFilter for Arc<F> {
+	fn filter(&self, prompt: &Prompt) -> Result<Prompt, PromptRejected> {
+		(**self).filter(prompt)
+	}
+}
+
+/// A simple [`PromptFilter`] matching prompts against a list of regular expressions. By default, prompts matching any
+/// pattern are rejected; with [`RegexPromptFilter::with_replacement`], matches are replaced instead.
+///
+/// This is meant as an example; real-world profanity or PII filtering needs far more than a list of patterns.
+///
+/// ```
+/// # fn main() -> anyhow::Result<()> {
+/// # use pyke_diffusers::{PromptFilter, RegexPromptFilter};
+/// let filter = RegexPromptFilter::new([r"(?i)\bdarn\b"])?;
+/// assert!(filter.filter(&"a darn cat".into()).is_err());
+///
+/// let scrubber = RegexPromptFilter::new([r"\b\d{3}-\d{3}-\d{4}\b"])?.with_replacement("");
+/// assert_eq!(scrubber.filter(&"a sign saying 555-123-4567".into())?[0], "a sign saying ");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RegexPromptFilter {
+	patterns: Vec<Regex>,
+	replacement: Option<String>
+}
+
+impl RegexPromptFilter {
+	/// Creates a filter rejecting prompts which match any of the given patterns. Fails if a pattern is not a valid
+	/// regular expression.
+	pub fn new<S: AsRef<str>>(patterns: impl IntoIterator<Item = S>) -> Result<Self, regex::Error> {
+		let patterns = patterns.into_iter().map(|pattern| Regex::new(pattern.as_ref())).collect::<Result<Vec<_>, _>>()?;
+		Ok(Self { patterns, replacement: None })
+	}
+
+	/// Scrubs matches from prompts by replacing them with `replacement` instead of rejecting the prompt.
+	/// `replacement` is inserted literally; `$` group references are not expanded.
+	pub fn with_replacement(mut self, replacement: impl Into<String>) -> Self {
+		self.replacement = Some(replacement.into());
+		self
+	}
+}
+
+impl PromptFilter for RegexPromptFilter {
+	fn filter(&self, prompt: &Prompt) -> Result<Prompt, PromptRejected> {
+		let mut filtered = Vec::with_capacity(prompt.len());
+		for (i, text) in prompt.iter().enumerate() {
+			let mut text = text.clone();
+			for pattern in &self.patterns {
+				match self.replacement.as_deref() {
+					Some(replacement) => text = pattern.replace_all(&text, regex::NoExpand(replacement)).into_owned(),
+					None if pattern.is_match(&text) => {
+						return Err(PromptRejected::new(format!("prompt {i} matches the blocked pattern `{}`", pattern.as_str())));
+					}
+					None => {}
+				}
+			}
+			filtered.push(text);
+		}
+		Ok(Prompt(filtered))
+	}
+}
+
+impl StableDiffusionPipeline {
+	/// Applies the pipeline's [`PromptFilter`], if any, to a prompt about to be tokenized.
+	pub(crate) fn filter_prompt(&self, prompt: Prompt) -> anyhow::Result<Prompt> {
+		let filter = match self.options.prompt_filter.as_ref() {
+			Some(filter) => filter,
+			None => return Ok(prompt)
+		};
+		let filtered = filter.filter(&prompt)?;
+		if filtered.len() != prompt.len() {
+			anyhow::bail!("the prompt filter returned {} prompts for {} prompts; filters must not change the batch size", filtered.len(), prompt.len());
+		}
+		Ok(filtered)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use image::{DynamicImage, RgbImage};
+
+	use super::{PromptFilter, PromptRejected, RegexPromptFilter};
+	use crate::{fixtures::fixture, OrtEnvironment, Prompt, PromptInput, PromptWeighting, StableDiffusionOptions, StableDiffusionPipeline};
+
+	/// Records every prompt it sees, rejecting prompts containing `blocked`.
+	#[derive(Debug, Default)]
+	struct RecordingFilter {
+		seen: Mutex<Vec<String>>
+	}
+
+	impl PromptFilter for RecordingFilter {
+		fn filter(&self, prompt: &Prompt) -> Result<Prompt, PromptRejected> {
+			self.seen.lock().unwrap().extend(prompt.iter().cloned());
+			if prompt.iter().any(|text| text.contains("blocked")) {
+				return Err(PromptRejected::new("contains `blocked`"));
+			}
+			Ok(prompt.clone())
+		}
+	}
+
+	#[test]
+	fn regex_filter() -> anyhow::Result<()> {
+		let filter = RegexPromptFilter::new(["(?i)secret", r"\d{4}"])?;
+		assert_eq!(filter.filter(&["a cat", "a dog"].into())?, Prompt::from(["a cat", "a dog"]));
+		let err = filter.filter(&["a cat", "a SECRET dog"].into()).unwrap_err();
+		assert!(err.reason.contains("prompt 1"), "{err}");
+
+		let scrubber = filter.with_replacement("$1");
+		assert_eq!(scrubber.filter(&"secret code 1234".into())?, Prompt::from("$1 code $1"));
+		assert!(RegexPromptFilter::new(["("]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn every_encode_path_is_filtered() -> anyhow::Result<()> {
+		let environment = OrtEnvironment::default().into_arc();
+		let filter = Arc::new(RecordingFilter::default());
+		let pipeline =
+			StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default().with_prompt_filter(filter.clone()))?;
+		let take_seen = || std::mem::take(&mut *filter.seen.lock().unwrap());
+
+		pipeline.encode_prompt("(a:1.2) b".into(), true, Some(&"c".into()))?;
+		assert_eq!(take_seen(), ["(a:1.2) b", "c"]);
+		pipeline.encode_prompt_with_weighting("d".into(), false, None, &[PromptWeighting::Plain])?;
+		assert_eq!(take_seen(), ["d"]);
+		pipeline.encode_prompt_input(&PromptInput::Text("e".into()), true, Some(&PromptInput::Text("f".into())))?;
+		assert_eq!(take_seen(), ["e", "f"]);
+
+		let clip_scorer =
+			StableDiffusionPipeline::new(&environment, fixture("clip-scorer")?, StableDiffusionOptions::default().with_prompt_filter(filter.clone()))?;
+		let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 64, [255, 0, 0].into()));
+		clip_scorer.clip_score(&image, "g")?;
+		assert_eq!(take_seen(), ["g"]);
+
+		for err in [
+			pipeline.encode_prompt("a blocked prompt".into(), false, None).unwrap_err(),
+			pipeline.encode_prompt("a".into(), true, Some(&"blocked".into())).unwrap_err(),
+			clip_scorer.clip_score(&image, "blocked").unwrap_err()
+		] {
+			assert_eq!(err.downcast_ref::<PromptRejected>(), Some(&PromptRejected::new("contains `blocked`")));
+		}
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "scheduler-euler")]
+	fn generation_paths_are_filtered() -> anyhow::Result<()> {
+		use crate::{EulerDiscreteScheduler, ImageRegion, MultiDiffusionOptions, StableDiffusionTxt2ImgOptions};
+
+		let environment = OrtEnvironment::default().into_arc();
+		let filter = Arc::new(RecordingFilter::default());
+		let pipeline =
+			StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default().with_prompt_filter(filter.clone()))?;
+		let take_seen = || std::mem::take(&mut *filter.seen.lock().unwrap());
+
+		let mut scheduler = EulerDiscreteScheduler::default();
+		let options = || StableDiffusionTxt2ImgOptions::default().with_size(64, 64).with_steps(1).with_seed(42);
+		options().with_prompt("a").with_negative_prompt("b").run(&pipeline, &mut scheduler)?;
+		assert_eq!(take_seen(), ["a", "b"]);
+
+		let multidiffusion = MultiDiffusionOptions { tile_size: 32, tile_overlap: 16, ..Default::default() }.with_region(ImageRegion::new(0, 0, 32, 64), "d");
+		options().with_prompt("c").with_multidiffusion(multidiffusion).run(&pipeline, &mut scheduler)?;
+		let seen = take_seen();
+		assert!(seen.contains(&"c".to_string()) && seen.contains(&"d".to_string()), "{seen:?}");
+
+		let err = options().with_prompt("blocked").run(&pipeline, &mut scheduler).unwrap_err();
+		assert_eq!(err.downcast_ref::<PromptRejected>(), Some(&PromptRejected::new("contains `blocked`")));
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "scheduler-euler")]
+	fn records_scrubbed_prompts() -> anyhow::Result<()> {
+		use crate::{sidecar_path, EulerDiscreteScheduler, MetadataMode, ReproRecord, StableDiffusionTxt2ImgOptions};
+
+		let environment = OrtEnvironment::default().into_arc();
+		let scrubber = RegexPromptFilter::new([r"\d{3}-\d{4}"])?.with_replacement("[redacted]");
+		let pipeline = StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default().with_prompt_filter(scrubber))?;
+		let out_dir = std::env::temp_dir().join("pyke-diffusers-scrubbed-records");
+		let _ = std::fs::remove_dir_all(&out_dir);
+
+		let mut scheduler = EulerDiscreteScheduler::default();
+		let options = StableDiffusionTxt2ImgOptions::default()
+			.with_size(64, 64)
+			.with_steps(1)
+			.with_seed(42)
+			.with_negative_prompt("call 555-0000")
+			.with_metadata_mode(MetadataMode::Sidecar);
+		let paths = pipeline.txt2img_to_files("a sign saying 555-1234", &mut scheduler, options, &out_dir, "{index}-{prompt_slug}")?;
+		assert_eq!(paths, [out_dir.join("0-a-sign-saying-redacted.png")]);
+		let sidecar = std::fs::read_to_string(sidecar_path(&paths[0]))?;
+		assert!(!sidecar.contains("555"), "{sidecar}");
+		let (_, record) = ReproRecord::load_with_image(&paths[0])?;
+		let record = record.unwrap();
+		assert_eq!((record.prompt.as_str(), record.negative_prompt.as_deref()), ("a sign saying [redacted]", Some("call [redacted]")));
+
+		std::fs::remove_dir_all(&out_dir)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,110 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use image::DynamicImage;
+use ndarray::{Array2, Array4, ArrayView4, ArrayViewD};
+use ndarray_rand::{
+	rand::{rngs::StdRng, SeedableRng},
+	rand_distr::StandardNormal,
+	RandomExt
+};
+use num_traits::ToPrimitive;
+
+use super::preprocessing::image_to_array;
+use crate::{DiffusionScheduler, ResizeFilter, StableDiffusionPipeline};
+
+/// The name of the UNet input containing the reference's hidden states entering each self-attention layer.
+pub(crate) const REFERENCE_HIDDEN_STATES: &str = "reference_hidden_states";
+/// The name of the UNet output containing the hidden states entering each self-attention layer. ONNX graphs can't
+/// output a value under the name of an input, hence the `out_` prefix (like the noise prediction's `out_sample`).
+pub(crate) const REFERENCE_HIDDEN_STATES_OUTPUT: &str = "out_reference_hidden_states";
+/// The name of the UNet input containing the weight of the reference.
+pub(crate) const REFERENCE_WEIGHT_INPUT: &str = "reference_weight";
+
+/// Offset added to the seed for the RNG used to noise the reference image.
+const REFERENCE_SEED_OFFSET: u64 = 0x4ef0;
+
+/// The reference latents of a run with a reference image; see
+/// [`StableDiffusionTxt2ImgOptions::with_reference_image`](crate::StableDiffusionTxt2ImgOptions::with_reference_image).
+pub(crate) struct ReferenceAttention {
+	/// The (scaled) latents of the reference image, of shape `(1, 4, height / 8, width / 8)`.
+	latents: Array4<f32>,
+	/// The noise used to noise the reference latents to each timestep, shared by all steps.
+	noise: Array4<f32>,
+	weight: f32
+}
+
+impl ReferenceAttention {
+	/// Encodes the reference image, resized to the generated image's size with `filter`. The reference is noised with
+	/// noise drawn from an RNG seeded with `seed`, so that generating multiple frames with the same seed & reference
+	/// image injects identical reference hidden states into each frame.
+	pub(crate) fn new(
+		session: &StableDiffusionPipeline,
+		image: &DynamicImage,
+		filter: ResizeFilter,
+		weight: f32,
+		width: u32,
+		height: u32,
+		seed: u64
+	) -> anyhow::Result<Self> {
+		check_reference_unet(session)?;
+
+		let latents = session.encode_images(image_to_array(image, filter, width, height).view())?;
+		let noise = Array4::random_using(latents.raw_dim(), StandardNormal, &mut StdRng::seed_from_u64(seed.wrapping_add(REFERENCE_SEED_OFFSET)));
+		Ok(Self { latents, noise, weight })
+	}
+
+	/// Predicts noise for `latent_model_input` with the reference's self-attention hidden states injected.
+	///
+	/// The reference latents are noised to `timestep` and evaluated by the UNet first to capture their hidden states,
+	/// which are then fed into the evaluation of `latent_model_input`. This doubles the cost of each step.
+	pub(crate) fn predict_noise<S: DiffusionScheduler>(
+		&self,
+		session: &StableDiffusionPipeline,
+		scheduler: &mut S,
+		latent_model_input: ArrayView4<'_, f32>,
+		timestep: S::TimestepType,
+		encoder_hidden_states: ArrayViewD<'_, f32>
+	) -> anyhow::Result<Array4<f32>> {
+		let batch_size = latent_model_input.shape()[0];
+		let reference_latents = self.latents.broadcast(latent_model_input.raw_dim()).expect("reference latents match the latent size");
+		let noise = self.noise.broadcast(latent_model_input.raw_dim()).expect("reference noise matches the latent size");
+		let reference_input = scheduler.add_noise(reference_latents, noise, timestep);
+		let reference_input = scheduler.scale_model_input(reference_input.view(), timestep);
+
+		let t = timestep.to_f32().unwrap();
+		let no_reference = Array2::<f32>::zeros((batch_size, 0)).into_dyn();
+		// capture pass: an empty reference makes the UNet behave as a standard UNet
+		let (_, reference_hidden_states) =
+			session.predict_noise_with_reference(reference_input.view(), t, encoder_hidden_states.view(), no_reference.view(), 0.0)?;
+		let (noise_pred, _) =
+			session.predict_noise_with_reference(latent_model_input, t, encoder_hidden_states, reference_hidden_states.view(), self.weight)?;
+		Ok(noise_pred)
+	}
+}
+
+/// Checks that the pipeline's UNet was exported with reference attention inputs & outputs.
+pub(crate) fn check_reference_unet(session: &StableDiffusionPipeline) -> anyhow::Result<()> {
+	let input_names = session.unet.inputs.iter().map(|input| input.name.as_str()).collect::<Vec<_>>();
+	let has_output = session.unet.outputs.iter().any(|output| output.name == REFERENCE_HIDDEN_STATES_OUTPUT);
+	if input_names.get(3) != Some(&REFERENCE_HIDDEN_STATES) || input_names.get(4) != Some(&REFERENCE_WEIGHT_INPUT) || !has_output {
+		anyhow::bail!(
+			"reference attention requires a UNet exported with `{}` & `{}` inputs and a `{}` output; the UNet has inputs {input_names:?}",
+			REFERENCE_HIDDEN_STATES,
+			REFERENCE_WEIGHT_INPUT,
+			REFERENCE_HIDDEN_STATES_OUTPUT
+		);
+	}
+	Ok(())
+}
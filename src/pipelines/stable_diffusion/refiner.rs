@@ -0,0 +1,147 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ndarray::Array4;
+use ndarray_rand::rand::{self, Rng};
+
+use super::impl_txt2img::denoising_start_step;
+use crate::{
+	schedulers::validate_custom_sigmas, DiffusionCheckpoint, DiffusionScheduler, GenerationStage, SchedulerState, StableDiffusionOutput,
+	StableDiffusionPipeline, StableDiffusionTxt2ImgOptions
+};
+
+impl StableDiffusionPipeline {
+	/// Finishes partially denoised `latents` with this pipeline as a refiner, starting `denoising_start` (between 0 &
+	/// 1) of the way through the steps taken, i.e. at step `round(denoising_start * steps)`. Like in base runs, these are
+	/// `options.steps`, limited to the timesteps the scheduler supports.
+	///
+	/// The refiner sets the scheduler's timesteps for the full number of steps and skips the steps before the handoff,
+	/// so the sigma schedule is the same as that of a base run with `denoising_end` set to `denoising_start` & the same
+	/// number of steps: the refiner continues at exactly the noise level the base stopped at. The latents must have the
+	/// shape `(batch_size, 4, height / 8, width / 8)` of the options' image & batch size.
+	///
+	/// Callbacks set with [`StableDiffusionTxt2ImgOptions::callback_staged`] are called with
+	/// [`GenerationStage::Refiner`], and step numbers counted from the start of the full schedule.
+	pub fn refine<S: DiffusionScheduler>(
+		&self,
+		scheduler: &mut S,
+		latents: Array4<f32>,
+		denoising_start: f32,
+		options: StableDiffusionTxt2ImgOptions
+	) -> anyhow::Result<StableDiffusionOutput> {
+		check_refiner_options(&options)?;
+		// the scheduler may compute fewer timesteps than steps were requested, so the handoff is placed in the steps taken
+		let steps = match options.custom_sigmas.as_deref() {
+			Some(sigmas) => validate_custom_sigmas(sigmas)?.len(),
+			None => options.supported_steps(&mut *scheduler)?
+		};
+		let checkpoint = DiffusionCheckpoint {
+			step: denoising_start_step(denoising_start, steps)?,
+			steps,
+			seed: options.seed.unwrap_or_else(|| rand::thread_rng().gen::<u64>()),
+			compatibility_version: options.compatibility_version.resolve(),
+			rng_draw_order: options.rng_draw_order,
+			scheduler_rng_words: 0,
+			latents,
+			scheduler_state: SchedulerState::default()
+		};
+		options.run_from(self, scheduler, Some(&checkpoint), GenerationStage::Refiner)
+	}
+
+	/// Finishes the [`checkpoint`](StableDiffusionOutput::checkpoint) captured by a base run with
+	/// [`StableDiffusionTxt2ImgOptions::with_denoising_end`], using this pipeline as a refiner.
+	///
+	/// The number of steps, seed, & RNG state are taken from the checkpoint, so the refiner continues the base's
+	/// schedule at the step it stopped at; the options' `steps` & `seed` are ignored. The scheduler's accumulated state
+	/// is not carried over, since the refiner's model predicts differently from the base's. The options' image & batch
+	/// size must match the base run.
+	///
+	/// Only the checkpoint is needed to refine, so this is also the memory-friendly way to run a base+refiner
+	/// generation: drop the base pipeline after capturing the checkpoint, then load the refiner. Unlike
+	/// [`StableDiffusionPipeline::txt2img_with_refiner`], only one pipeline's models are then loaded at a time.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// # let mut scheduler = EulerDiscreteScheduler::default();
+	/// let options = || StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox").with_steps(40);
+	/// let base = StableDiffusionPipeline::new(&environment, "./sdxl-base/", StableDiffusionOptions::default())?;
+	/// let checkpoint = options().with_denoising_end(0.8).run_with_output(&base, &mut scheduler)?.checkpoint.unwrap();
+	/// drop(base);
+	///
+	/// let refiner = StableDiffusionPipeline::new(&environment, "./sdxl-refiner/", StableDiffusionOptions::default())?;
+	/// let images = refiner.refine_checkpoint(&mut scheduler, &checkpoint, options())?.images;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn refine_checkpoint<S: DiffusionScheduler>(
+		&self,
+		scheduler: &mut S,
+		checkpoint: &DiffusionCheckpoint,
+		options: StableDiffusionTxt2ImgOptions
+	) -> anyhow::Result<StableDiffusionOutput> {
+		check_refiner_options(&options)?;
+		let checkpoint = DiffusionCheckpoint {
+			scheduler_state: SchedulerState::default(),
+			..checkpoint.clone()
+		};
+		options.run_from(self, scheduler, Some(&checkpoint), GenerationStage::Refiner)
+	}
+
+	/// Generates images with this pipeline as the base & `refiner` finishing the last steps, e.g. with Stable Diffusion
+	/// XL's base & refiner models.
+	///
+	/// The base stage runs `base_options`, which must set [`StableDiffusionTxt2ImgOptions::with_denoising_end`], and
+	/// the refiner then finishes its latents with `refiner_options` as in [`StableDiffusionPipeline::refine_checkpoint`].
+	/// Both stages' callbacks are called; use [`StableDiffusionTxt2ImgOptions::callback_staged`] to tell their steps
+	/// apart. The returned output holds the refiner's images & the step statistics & trajectories of both stages. If the
+	/// base stage stops early (see [`StopReason`](crate::StopReason)), the refiner doesn't run and the base stage's
+	/// output is returned, holding its partial latents.
+	///
+	/// This keeps both pipelines loaded; see [`StableDiffusionPipeline::refine_checkpoint`] to unload the base before
+	/// loading the refiner.
+	pub fn txt2img_with_refiner<S: DiffusionScheduler, R: DiffusionScheduler>(
+		&self,
+		scheduler: &mut S,
+		base_options: StableDiffusionTxt2ImgOptions,
+		refiner: &StableDiffusionPipeline,
+		refiner_scheduler: &mut R,
+		refiner_options: StableDiffusionTxt2ImgOptions
+	) -> anyhow::Result<StableDiffusionOutput> {
+		if base_options.denoising_end.is_none() {
+			anyhow::bail!("the base stage's options must set `denoising_end` to hand off to the refiner");
+		}
+		let base = base_options.run_from(self, scheduler, None, GenerationStage::Base)?;
+		if base.stop_reason.is_early() {
+			// the refiner never runs, so the base stage's partial latents are returned as is
+			return Ok(base);
+		}
+		let checkpoint = match base.checkpoint {
+			Some(checkpoint) => checkpoint,
+			None => anyhow::bail!("the base stage did not capture its handoff checkpoint")
+		};
+		let mut output = refiner.refine_checkpoint(refiner_scheduler, &checkpoint, refiner_options)?;
+		output.step_stats.splice(0..0, base.step_stats);
+		output.trajectory.splice(0..0, base.trajectory);
+		Ok(output)
+	}
+}
+
+fn check_refiner_options(options: &StableDiffusionTxt2ImgOptions) -> anyhow::Result<()> {
+	if options.denoising_end.is_some() || options.checkpoint_at.is_some() {
+		anyhow::bail!("the refiner's options cannot set `denoising_end` or `checkpoint_at`");
+	}
+	Ok(())
+}
@@ -0,0 +1,212 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp::Ordering;
+
+use ndarray::{Array4, ArrayView4};
+
+use crate::DiffusionScheduler;
+
+/// An interval of the noise schedule to re-denoise with [Restart sampling](https://arxiv.org/abs/2306.14878); see
+/// [`StableDiffusionTxt2ImgOptions::with_restart_schedule`](crate::StableDiffusionTxt2ImgOptions::with_restart_schedule).
+///
+/// Timesteps are given in training timesteps, e.g. `0..1000` for Stable Diffusion, where higher timesteps are noisier.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestartInterval {
+	/// Once denoising reaches this timestep, the latents are re-noised up to [`t_max`](Self::t_max).
+	pub t_min: f32,
+	/// The timestep the latents are re-noised to.
+	pub t_max: f32,
+	/// The number of times the interval is re-noised & denoised again.
+	pub num_restarts: usize
+}
+
+impl From<(f32, f32, usize)> for RestartInterval {
+	fn from((t_min, t_max, num_restarts): (f32, f32, usize)) -> Self {
+		Self { t_min, t_max, num_restarts }
+	}
+}
+
+/// A step of the denoising loop, as planned by [`restart_plan`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PlannedStep {
+	/// The index of the step's timestep.
+	pub step: usize,
+	/// If the latents are re-noised to this step's timestep before the step is taken, the index of the timestep whose
+	/// noise level the latents are at, i.e. where the restarted interval ends. This is the number of steps if the
+	/// interval ends after the last step.
+	pub renoise_from: Option<usize>,
+	/// `false` for the steps of the normal pass through the schedule, `true` for steps re-run by a restart.
+	pub restarted: bool
+}
+
+/// Plans the order in which the steps of a schedule with the given `timesteps` are taken, starting from `start_step`.
+///
+/// Each interval is snapped to the schedule: its restarts begin once denoising reaches the first timestep at or below
+/// `t_min` (or after the last step, if all timesteps are above `t_min`), and re-noise the latents to the first
+/// timestep at or below `t_max`. Restarts are only taken during the normal pass through the schedule, so intervals
+/// don't trigger each other.
+pub(crate) fn restart_plan(timesteps: &[f32], schedule: &[RestartInterval], start_step: usize) -> anyhow::Result<Vec<PlannedStep>> {
+	let num_steps = timesteps.len();
+	let mut restarts = Vec::with_capacity(schedule.len());
+	for interval in schedule {
+		if interval.t_min.partial_cmp(&interval.t_max) != Some(Ordering::Less) {
+			anyhow::bail!("restart interval has `t_min` {} & `t_max` {}; `t_min` must be below `t_max`", interval.t_min, interval.t_max);
+		}
+		let end = timesteps.iter().position(|&t| t <= interval.t_min).unwrap_or(num_steps);
+		let start = timesteps.iter().position(|&t| t <= interval.t_max).unwrap_or(num_steps);
+		if start >= end {
+			anyhow::bail!(
+				"restart interval from timestep {} to {} contains none of the {num_steps} steps' timesteps; widen it or use more steps",
+				interval.t_max,
+				interval.t_min
+			);
+		}
+		restarts.push((start, end, interval.num_restarts));
+	}
+	// stable, so intervals ending at the same step restart in schedule order
+	restarts.sort_by_key(|&(_, end, _)| end);
+
+	let mut plan = Vec::with_capacity(num_steps + restarts.iter().map(|(start, end, n)| (end - start) * n).sum::<usize>());
+	let mut restarts = restarts.into_iter().filter(|&(_, end, _)| end > start_step).peekable();
+	for step in start_step..=num_steps {
+		while let Some((start, _, num_restarts)) = restarts.next_if(|&(_, end, _)| end == step) {
+			for _ in 0..num_restarts {
+				plan.extend((start..step).map(|s| PlannedStep { step: s, renoise_from: (s == start).then_some(step), restarted: true }));
+			}
+		}
+		if step < num_steps {
+			plan.push(PlannedStep { step, renoise_from: None, restarted: false });
+		}
+	}
+	Ok(plan)
+}
+
+/// Re-noises `latents` from the noise level of timestep `from` (`None` for fully denoised latents, after the last
+/// step) up to that of timestep `to` with Restart sampling's forward process. In the variance-exploding
+/// parametrization `x = x0 + σ·ε`, this adds fresh noise to the current latents: `x' = x + sqrt(σ_max² - σ_min²)·ε`.
+///
+/// Schedulers parametrize latents differently (e.g. the DDIM scheduler's latents are `sqrt(ᾱ)·x0 + sqrt(1 - ᾱ)·ε`),
+/// so each timestep's scale `a` & noise level `b` of latents `a·x0 + b·ε` are probed with the scheduler's
+/// [`add_noise`](DiffusionScheduler::add_noise), giving `σ = b / a`: the latents are converted to the
+/// variance-exploding parametrization at `from`, noised, and converted back at `to`.
+pub(crate) fn renoise<S: DiffusionScheduler>(
+	scheduler: &mut S,
+	latents: ArrayView4<'_, f32>,
+	noise: ArrayView4<'_, f32>,
+	from: Option<S::TimestepType>,
+	to: S::TimestepType
+) -> Array4<f32> {
+	let (scale_min, noise_min) = from.map_or((1.0, 0.0), |from| noise_coefficients(scheduler, from));
+	let (scale_max, noise_max) = noise_coefficients(scheduler, to);
+	let (sigma_min, sigma_max) = (noise_min / scale_min, noise_max / scale_max);
+	let noise_scale = (sigma_max * sigma_max - sigma_min * sigma_min).max(0.0).sqrt();
+	(&latents / scale_min + &noise * noise_scale) * scale_max
+}
+
+/// Returns the scale `a` & noise level `b` of the scheduler's latents `a·x0 + b·ε` at `timestep`.
+fn noise_coefficients<S: DiffusionScheduler>(scheduler: &mut S, timestep: S::TimestepType) -> (f32, f32) {
+	let (ones, zeros) = (Array4::<f32>::ones((1, 1, 1, 1)), Array4::<f32>::zeros((1, 1, 1, 1)));
+	let scale = scheduler.add_noise(ones.view(), zeros.view(), timestep)[[0, 0, 0, 0]];
+	let noise = scheduler.add_noise(zeros.view(), ones.view(), timestep)[[0, 0, 0, 0]];
+	(scale, noise)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{restart_plan, PlannedStep, RestartInterval};
+
+	fn steps(plan: &[PlannedStep]) -> Vec<(usize, bool)> {
+		plan.iter().map(|step| (step.step, step.renoise_from.is_some())).collect()
+	}
+
+	#[test]
+	fn no_restarts() -> anyhow::Result<()> {
+		let timesteps = [999.0, 749.0, 499.0, 249.0];
+		let plan = restart_plan(&timesteps, &[], 1)?;
+		assert_eq!(steps(&plan), [(1, false), (2, false), (3, false)]);
+		assert!(plan.iter().all(|step| !step.restarted));
+		Ok(())
+	}
+
+	#[test]
+	fn restarts_snap_to_timesteps() -> anyhow::Result<()> {
+		let timesteps = [999.0, 799.0, 599.0, 399.0, 199.0];
+		// re-noise from 399 (reached after step 2) back to 799, twice
+		let plan = restart_plan(&timesteps, &[RestartInterval::from((450.0, 800.0, 2))], 0)?;
+		assert_eq!(
+			steps(&plan),
+			[(0, false), (1, false), (2, false), (1, true), (2, false), (1, true), (2, false), (3, false), (4, false)]
+		);
+		assert_eq!(plan.iter().filter(|step| step.restarted).count(), 4);
+		// the latents are re-noised from the noise level of timestep 399
+		assert!(plan.iter().filter_map(|step| step.renoise_from).all(|from| from == 3));
+
+		// an interval below the last timestep restarts after the last step
+		let plan = restart_plan(&timesteps, &[RestartInterval::from((0.0, 250.0, 1))], 0)?;
+		assert_eq!(steps(&plan)[4..], [(4, false), (4, true)]);
+		assert_eq!(plan[5].renoise_from, Some(5));
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "scheduler-euler")]
+	fn renoise_adds_noise_to_current_latents() {
+		use ndarray::Array4;
+
+		use super::renoise;
+		use crate::{DiffusionScheduler, EulerDiscreteScheduler, SchedulerOptimizedDefaults};
+
+		let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default().unwrap();
+		scheduler.set_timesteps(4);
+		let (from, to) = (scheduler.timesteps()[2], scheduler.timesteps()[1]);
+		let (sigma_min, sigma_max) = (scheduler.sigma(2).unwrap(), scheduler.sigma(1).unwrap());
+		let (latents, noise) = (Array4::from_elem((1, 4, 2, 2), 0.5), Array4::from_elem((1, 4, 2, 2), -1.0));
+
+		let renoised = renoise(&mut scheduler, latents.view(), noise.view(), Some(from), to);
+		let expected = 0.5 - (sigma_max * sigma_max - sigma_min * sigma_min).sqrt();
+		assert!(renoised.iter().all(|x| (x - expected).abs() < 1e-4), "{renoised:?} != {expected}");
+		// fully denoised latents are noised to the full noise level of `to`
+		let renoised = renoise(&mut scheduler, latents.view(), noise.view(), None, to);
+		assert!(renoised.iter().all(|x| (x - (0.5 - sigma_max)).abs() < 1e-4));
+	}
+
+	#[test]
+	#[cfg(feature = "scheduler-ddim")]
+	fn renoise_converts_variance_preserving_latents() {
+		use ndarray::Array4;
+
+		use super::renoise;
+		use crate::{DDIMScheduler, DiffusionScheduler, SchedulerOptimizedDefaults};
+
+		let mut scheduler = DDIMScheduler::stable_diffusion_v1_optimized_default().unwrap();
+		scheduler.set_timesteps(4);
+		let (from, to) = (scheduler.timesteps()[2], scheduler.timesteps()[1]);
+		let (sigma_min, sigma_max) = (scheduler.sigma(2).unwrap(), scheduler.sigma(1).unwrap());
+		let (latents, noise) = (Array4::from_elem((1, 4, 2, 2), 0.5), Array4::from_elem((1, 4, 2, 2), -1.0));
+
+		// the latents are `sqrt(ᾱ)·(x0 + σ·ε)`, with `sqrt(ᾱ) = 1 / sqrt(1 + σ²)`
+		let renoised = renoise(&mut scheduler, latents.view(), noise.view(), Some(from), to);
+		let variance_exploding = 0.5 * (1.0 + sigma_min * sigma_min).sqrt() - (sigma_max * sigma_max - sigma_min * sigma_min).sqrt();
+		let expected = variance_exploding / (1.0 + sigma_max * sigma_max).sqrt();
+		assert!(renoised.iter().all(|x| (x - expected).abs() < 1e-4), "{renoised:?} != {expected}");
+	}
+
+	#[test]
+	fn rejects_empty_intervals() {
+		let timesteps = [999.0, 666.0, 333.0];
+		assert!(restart_plan(&timesteps, &[RestartInterval::from((400.0, 600.0, 1))], 0).is_err());
+		assert!(restart_plan(&timesteps, &[RestartInterval::from((800.0, 400.0, 1))], 0).is_err());
+	}
+}
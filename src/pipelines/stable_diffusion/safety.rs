@@ -0,0 +1,184 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, fs};
+
+use image::{DynamicImage, Rgb32FImage, RgbImage};
+use ndarray::Array4;
+use ort::{OrtOwnedTensor, Value};
+
+use super::{clip_score::preprocess_clip_image, to_files::write_image};
+use crate::{ImageFileFormat, ImageRef, StableDiffusionPipeline};
+
+/// What to do with images in which the safety checker detects unsafe content; see
+/// [`StableDiffusionOptions::with_nsfw_policy`](crate::StableDiffusionOptions::with_nsfw_policy).
+///
+/// Regardless of the policy, whether each image was flagged is returned in
+/// [`StableDiffusionOutput::nsfw_flags`](crate::StableDiffusionOutput::nsfw_flags). Policies only apply to pipelines
+/// whose model config has a `[safety-checker]` section.
+///
+/// Before this policy existed, the safety checker was loaded but never run, so models with a `[safety-checker]` section
+/// returned flagged images unchanged. They are now blanked by default; use [`NsfwPolicy::Flag`] for the old behavior.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NsfwPolicy {
+	/// Replace flagged images with black images of the same size. **This is the default.**
+	#[default]
+	Blank,
+	/// Return flagged images unchanged, leaving it to the caller to act on the flags.
+	Flag,
+	/// Fail generation with an [`UnsafeContentDetected`] error naming the flagged images. Flagged images that were
+	/// already [written to disk](crate::StableDiffusionTxt2ImgOptions::with_decode_to_disk) are deleted first.
+	Error
+}
+
+/// Error returned when the safety checker detects unsafe content with [`NsfwPolicy::Error`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsafeContentDetected {
+	/// The indices of the flagged images within the batch.
+	pub indices: Vec<usize>
+}
+
+impl fmt::Display for UnsafeContentDetected {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "the safety checker detected unsafe content in images {:?} of the batch", self.indices)
+	}
+}
+
+impl std::error::Error for UnsafeContentDetected {}
+
+impl StableDiffusionPipeline {
+	/// Runs the safety checker on an image, returning `true` if it detected unsafe content. Fails if the model config
+	/// has no `[safety-checker]` section.
+	///
+	/// The safety checker is the ONNX export of diffusers' `StableDiffusionSafetyChecker`, taking the CLIP-preprocessed
+	/// image as `clip_input` and the image itself as `images` (`(1, height, width, 3)`, with values in `[0, 1]`), and
+	/// producing `has_nsfw_concepts` as its 2nd output.
+	pub fn check_safety(&self, image: &DynamicImage) -> anyhow::Result<bool> {
+		let safety_checker = self.safety_checker.as_ref().ok_or_else(|| anyhow::anyhow!("this model has no safety checker"))?;
+		let clip_input = preprocess_clip_image(image, self.config.feature_extractor.as_ref());
+		let pixels = image.to_rgb32f();
+		let images = Array4::from_shape_vec((1, pixels.height() as usize, pixels.width() as usize, 3), pixels.into_raw())?;
+
+		let outputs = safety_checker.run(ort::inputs![Value::from_array(clip_input)?, Value::from_array(images)?]?)?;
+		let has_nsfw_concepts: OrtOwnedTensor<bool> = outputs[1].extract_tensor()?;
+		let flagged = has_nsfw_concepts.view().iter().any(|&flagged| flagged);
+		Ok(flagged)
+	}
+
+	/// Checks each image with the safety checker and applies the pipeline's [`NsfwPolicy`], returning whether each
+	/// image was flagged. Returns no flags if the pipeline has no safety checker.
+	pub(crate) fn apply_nsfw_policy(&self, images: &mut [ImageRef]) -> anyhow::Result<Vec<bool>> {
+		if self.safety_checker.is_none() {
+			return Ok(Vec::new());
+		}
+
+		let flags = images
+			.iter()
+			.map(|image| match image {
+				ImageRef::InMemory(image) => self.check_safety(image),
+				ImageRef::OnDisk(path) => self.check_safety(&image::open(path)?)
+			})
+			.collect::<anyhow::Result<Vec<_>>>()?;
+		enforce_nsfw_policy(self.options.nsfw_policy, images, &flags)?;
+		Ok(flags)
+	}
+}
+
+/// Applies `policy` to the images flagged in `flags`. Under [`NsfwPolicy::Error`], flagged images that were written to
+/// disk are deleted before the error is returned, so no unsafe image is left behind.
+fn enforce_nsfw_policy(policy: NsfwPolicy, images: &mut [ImageRef], flags: &[bool]) -> anyhow::Result<()> {
+	match policy {
+		NsfwPolicy::Blank => {
+			for (image, _) in images.iter_mut().zip(flags).filter(|(_, &flagged)| flagged) {
+				let (width, height) = image.dimensions()?;
+				match image {
+					ImageRef::InMemory(image) => *image = DynamicImage::ImageRgb32F(Rgb32FImage::new(width, height)),
+					ImageRef::OnDisk(path) => write_image(&DynamicImage::ImageRgb8(RgbImage::new(width, height)), path, ImageFileFormat::Png)?
+				}
+			}
+		}
+		NsfwPolicy::Flag => {}
+		NsfwPolicy::Error => {
+			let indices = flags.iter().enumerate().filter(|(_, &flagged)| flagged).map(|(i, _)| i).collect::<Vec<_>>();
+			if !indices.is_empty() {
+				for path in indices.iter().filter_map(|&i| images[i].path()) {
+					fs::remove_file(path)?;
+				}
+				return Err(UnsafeContentDetected { indices }.into());
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use image::{DynamicImage, GenericImageView, Rgb32FImage};
+
+	use super::{enforce_nsfw_policy, write_image, NsfwPolicy, UnsafeContentDetected};
+	use crate::{ImageFileFormat, ImageRef};
+
+	fn white() -> DynamicImage {
+		DynamicImage::ImageRgb32F(Rgb32FImage::from_pixel(4, 4, image::Rgb([1.0, 1.0, 1.0])))
+	}
+
+	fn on_disk(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join("pyke-diffusers-nsfw-policy");
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join(name);
+		write_image(&white(), &path, ImageFileFormat::Png).unwrap();
+		path
+	}
+
+	fn is_black(image: &DynamicImage) -> bool {
+		image.pixels().all(|(_, _, p)| p.0[..3] == [0, 0, 0])
+	}
+
+	#[test]
+	fn blank_replaces_only_flagged_images() {
+		let path = on_disk("blank.png");
+		let mut images = vec![ImageRef::InMemory(white()), ImageRef::InMemory(white()), ImageRef::OnDisk(path.clone())];
+		enforce_nsfw_policy(NsfwPolicy::Blank, &mut images, &[true, false, true]).unwrap();
+
+		let images = images.into_iter().map(|image| image.into_image().unwrap()).collect::<Vec<_>>();
+		assert!(is_black(&images[0]));
+		assert!(!is_black(&images[1]));
+		assert!(is_black(&images[2]));
+		assert_eq!(images[2].dimensions(), (4, 4));
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn flag_keeps_images() {
+		let mut images = vec![ImageRef::InMemory(white())];
+		enforce_nsfw_policy(NsfwPolicy::Flag, &mut images, &[true]).unwrap();
+		assert!(!is_black(&images.remove(0).into_image().unwrap()));
+	}
+
+	#[test]
+	fn error_deletes_flagged_files() {
+		let (flagged, kept) = (on_disk("error-flagged.png"), on_disk("error-kept.png"));
+		let mut images = vec![ImageRef::OnDisk(kept.clone()), ImageRef::OnDisk(flagged.clone())];
+		let err = enforce_nsfw_policy(NsfwPolicy::Error, &mut images, &[false, true]).unwrap_err();
+		assert_eq!(err.downcast_ref::<UnsafeContentDetected>().unwrap().indices, vec![1]);
+		assert!(!flagged.exists());
+		assert!(kept.exists());
+		std::fs::remove_file(kept).unwrap();
+
+		// nothing flagged is not an error
+		enforce_nsfw_policy(NsfwPolicy::Error, &mut [ImageRef::InMemory(white())], &[false]).unwrap();
+	}
+}
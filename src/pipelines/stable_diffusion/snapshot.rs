@@ -0,0 +1,273 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+	fs::{self, File},
+	io::{self, Read},
+	path::{Path, PathBuf},
+	time::UNIX_EPOCH
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	config::{StableDiffusionConfig, TokenizerConfig},
+	session_tracker::{load_session_with_optimization, GraphOptimization, ModelSource, TrackedSession},
+	DiffusionDevice, ExecutionContext
+};
+
+/// The name of the manifest file in a snapshot directory.
+pub(crate) const SNAPSHOT_MANIFEST: &str = "snapshot.json";
+/// The current version of the snapshot manifest format.
+const SNAPSHOT_VERSION: u32 = 1;
+/// The size of the chunks files are read in by [`file_digest`]; a multiple of 8.
+const DIGEST_CHUNK_LEN: usize = 1 << 20;
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// A snapshot manifest, recording everything needed to load a pipeline without parsing its config or re-optimizing
+/// its models.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SnapshotManifest {
+	pub version: u32,
+	/// The root of the model the snapshot was created from.
+	pub root: PathBuf,
+	/// The validated model config.
+	pub config: StableDiffusionConfig,
+	/// Digest of the tokenizer file, or `0` if the model has no tokenizer.
+	pub tokenizer_digest: u64,
+	pub components: Vec<SnapshotComponent>
+}
+
+/// An optimized model recorded in a [`SnapshotManifest`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SnapshotComponent {
+	pub component: String,
+	/// The device the model was optimized for; optimized graphs may contain device-specific nodes.
+	pub device: serde_json::Value,
+	/// The original model, identified by its size & modification time so it doesn't need to be read.
+	pub source: PathBuf,
+	pub source_len: u64,
+	pub source_modified: u64,
+	/// The optimized model's filename, relative to the snapshot directory.
+	pub optimized: String,
+	pub optimized_digest: u64
+}
+
+/// Loads sessions for a pipeline, either from the original models or from a snapshot.
+pub(crate) enum SessionLoader {
+	/// Load & optimize the original models.
+	Source,
+	/// Load & optimize the original models, writing the optimized models to the snapshot directory and recording them.
+	CreateSnapshot { dir: PathBuf, components: Vec<SnapshotComponent> },
+	/// Load the already optimized models of a validated snapshot, skipping graph optimization.
+	RestoreSnapshot { dir: PathBuf, manifest: SnapshotManifest }
+}
+
+impl SessionLoader {
+	pub(crate) fn load(
+		&mut self,
+		context: &ExecutionContext,
+		device: &DiffusionDevice,
+		component: &'static str,
+		path: &Path,
+		max_resident_bytes: Option<u64>
+	) -> anyhow::Result<TrackedSession> {
+		match self {
+			SessionLoader::Source => {
+				load_session_with_optimization(context, device, component, ModelSource::File(path), max_resident_bytes, None, GraphOptimization::OnLoad)
+			}
+			SessionLoader::CreateSnapshot { dir, components } => {
+				let optimized = format!("{}.onnx", component.replace(' ', "-").to_lowercase());
+				let optimized_path = dir.join(&optimized);
+				let optimization = GraphOptimization::OnLoadAndWrite(&optimized_path);
+				let session = load_session_with_optimization(context, device, component, ModelSource::File(path), max_resident_bytes, None, optimization)?;
+				let (source_len, source_modified) = fingerprint(path)?;
+				components.push(SnapshotComponent {
+					component: component.to_string(),
+					device: serde_json::to_value(device)?,
+					source: path.to_path_buf(),
+					source_len,
+					source_modified,
+					optimized_digest: file_digest(&optimized_path)?,
+					optimized
+				});
+				Ok(session)
+			}
+			SessionLoader::RestoreSnapshot { dir, manifest } => {
+				let recorded = manifest
+					.components
+					.iter()
+					.find(|c| c.component == component)
+					.ok_or_else(|| anyhow::anyhow!("snapshot has no optimized {component}"))?;
+				if recorded.source != path {
+					anyhow::bail!("snapshot's {component} was created from `{}`, not `{}`", recorded.source.display(), path.display());
+				}
+				let optimized_path = dir.join(&recorded.optimized);
+				let source = ModelSource::File(&optimized_path);
+				load_session_with_optimization(context, device, component, source, max_resident_bytes, None, GraphOptimization::Preoptimized)
+			}
+		}
+	}
+}
+
+impl SnapshotManifest {
+	pub(crate) fn read(dir: &Path) -> anyhow::Result<Self> {
+		let manifest: SnapshotManifest = serde_json::from_str(&fs::read_to_string(dir.join(SNAPSHOT_MANIFEST))?)?;
+		if manifest.version != SNAPSHOT_VERSION {
+			anyhow::bail!("snapshot has version {}, expected {SNAPSHOT_VERSION}", manifest.version);
+		}
+		Ok(manifest)
+	}
+
+	pub(crate) fn new(root: PathBuf, config: StableDiffusionConfig, components: Vec<SnapshotComponent>) -> anyhow::Result<Self> {
+		let tokenizer_digest = tokenizer_digest(&root, &config)?;
+		Ok(Self {
+			version: SNAPSHOT_VERSION,
+			root,
+			config,
+			tokenizer_digest,
+			components
+		})
+	}
+
+	pub(crate) fn write(&self, dir: &Path) -> anyhow::Result<()> {
+		// write to a temporary file first so a crash never leaves a truncated manifest behind
+		let tmp = dir.join(format!("{SNAPSHOT_MANIFEST}.tmp"));
+		fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+		fs::rename(tmp, dir.join(SNAPSHOT_MANIFEST))?;
+		Ok(())
+	}
+
+	/// Checks that the snapshot is still valid for the given devices: the tokenizer & original models are unchanged,
+	/// and the optimized models are intact & were optimized for the same devices.
+	pub(crate) fn validate(&self, dir: &Path, devices: &[(&'static str, &DiffusionDevice)]) -> anyhow::Result<()> {
+		if tokenizer_digest(&self.root, &self.config)? != self.tokenizer_digest {
+			anyhow::bail!("the tokenizer has changed");
+		}
+		for (component, device) in devices {
+			let recorded = self
+				.components
+				.iter()
+				.find(|c| c.component == *component)
+				.ok_or_else(|| anyhow::anyhow!("snapshot has no optimized {component}"))?;
+			if recorded.device != serde_json::to_value(device)? {
+				anyhow::bail!("the {component} was optimized for a different device");
+			}
+			if fingerprint(&recorded.source)? != (recorded.source_len, recorded.source_modified) {
+				anyhow::bail!("the {component} at `{}` has changed", recorded.source.display());
+			}
+			if file_digest(&dir.join(&recorded.optimized))? != recorded.optimized_digest {
+				anyhow::bail!("the optimized {component} is corrupt");
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Returns the size & modification time (in seconds since the Unix epoch) of a file.
+fn fingerprint(path: &Path) -> anyhow::Result<(u64, u64)> {
+	let metadata = fs::metadata(path)?;
+	let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+	Ok((metadata.len(), modified))
+}
+
+/// Computes a digest of a file's contents: FNV-1a over the file's little-endian 64-bit words (& its trailing bytes),
+/// reading the file in [`DIGEST_CHUNK_LEN`] chunks. Hashing words instead of single bytes keeps validating a snapshot
+/// of multi-gigabyte models fast.
+fn file_digest(path: &Path) -> anyhow::Result<u64> {
+	let mut file = File::open(path)?;
+	let mut buf = vec![0; DIGEST_CHUNK_LEN];
+	let mut hash = FNV_OFFSET_BASIS;
+	loop {
+		let n = read_chunk(&mut file, &mut buf)?;
+		let mut words = buf[..n].chunks_exact(8);
+		for word in &mut words {
+			hash = (hash ^ u64::from_le_bytes(word.try_into().unwrap())).wrapping_mul(FNV_PRIME);
+		}
+		for &byte in words.remainder() {
+			hash = (hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+		}
+		if n < buf.len() {
+			return Ok(hash);
+		}
+	}
+}
+
+/// Fills `buf` from `reader`, returning fewer bytes than `buf.len()` only at the end of the file, so that every chunk
+/// but the last is word-aligned.
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+	let mut filled = 0;
+	while filled < buf.len() {
+		match reader.read(&mut buf[filled..]) {
+			Ok(0) => break,
+			Ok(n) => filled += n,
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+			Err(e) => return Err(e)
+		}
+	}
+	Ok(filled)
+}
+
+fn tokenizer_digest(root: &Path, config: &StableDiffusionConfig) -> anyhow::Result<u64> {
+	match &config.tokenizer {
+		TokenizerConfig::CLIPTokenizer { path, .. } => file_digest(&root.join(path)),
+		_ => Ok(0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{fs, io::Read};
+
+	use super::{file_digest, read_chunk, DIGEST_CHUNK_LEN, FNV_OFFSET_BASIS, FNV_PRIME};
+
+	#[test]
+	fn digest_detects_changes() -> anyhow::Result<()> {
+		let path = std::env::temp_dir().join("pyke-diffusers-snapshot-digest");
+		fs::write(&path, b"optimized model")?;
+		let digest = file_digest(&path)?;
+		assert_eq!(digest, file_digest(&path)?);
+		fs::write(&path, b"optimized modal")?;
+		assert_ne!(digest, file_digest(&path)?);
+		fs::remove_file(&path)?;
+		Ok(())
+	}
+
+	#[test]
+	fn digest_is_independent_of_chunking() -> anyhow::Result<()> {
+		// a file spanning 2 chunks, with trailing bytes that don't fill a word
+		let contents = (0..DIGEST_CHUNK_LEN + 13).map(|i| (i * 31 % 251) as u8).collect::<Vec<_>>();
+		let path = std::env::temp_dir().join("pyke-diffusers-snapshot-digest-chunks");
+		fs::write(&path, &contents)?;
+		let mut words = contents.chunks_exact(8);
+		let mut expected = FNV_OFFSET_BASIS;
+		for word in &mut words {
+			expected = (expected ^ u64::from_le_bytes(word.try_into().unwrap())).wrapping_mul(FNV_PRIME);
+		}
+		for &byte in words.remainder() {
+			expected = (expected ^ byte as u64).wrapping_mul(FNV_PRIME);
+		}
+		assert_eq!(file_digest(&path)?, expected);
+		fs::remove_file(&path)?;
+
+		// short reads are combined into full chunks
+		let mut reader = (&contents[..5]).chain(&contents[5..20]);
+		let mut buf = [0; 16];
+		assert_eq!(read_chunk(&mut reader, &mut buf)?, 16);
+		assert_eq!(buf, contents[..16]);
+		assert_eq!(read_chunk(&mut reader, &mut buf)?, 4);
+		Ok(())
+	}
+}
@@ -0,0 +1,135 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ndarray::{ArrayView4, Zip};
+use serde::{Deserialize, Serialize};
+
+/// The default ratio between the latent standard deviation of consecutive steps above which
+/// [`StepStats::detect_anomalies`] flags a step.
+pub const DEFAULT_STD_JUMP_THRESHOLD: f32 = 1.5;
+
+/// Scalar statistics recorded after a single denoising step, useful for debugging generations that diverge (e.g.
+/// over-contrasted or "fried" outputs).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepStats {
+	/// The step number.
+	pub step: usize,
+	/// This step's timestep.
+	pub timestep: f32,
+	/// Mean of the latents after this step.
+	pub latent_mean: f32,
+	/// Standard deviation of the latents after this step.
+	pub latent_std: f32,
+	/// Minimum value of the latents after this step.
+	pub latent_min: f32,
+	/// Maximum value of the latents after this step.
+	pub latent_max: f32,
+	/// L2 norm of the (guided) noise prediction used for this step.
+	pub noise_pred_norm: f32,
+	/// L2 norm of the classifier-free guidance term, `guidance_scale * (noise_pred_text - noise_pred_uncond)`, or
+	/// `None` if classifier-free guidance is disabled.
+	pub guidance_norm: Option<f32>
+}
+
+impl StepStats {
+	pub(crate) fn new(step: usize, timestep: f32, latents: ArrayView4<'_, f32>, noise_pred: ArrayView4<'_, f32>, guidance_norm: Option<f32>) -> Self {
+		let (latent_min, latent_max) = latents.fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &x| (min.min(x), max.max(x)));
+		Self {
+			step,
+			timestep,
+			latent_mean: latents.mean().unwrap_or(0.0),
+			latent_std: latents.std(0.0),
+			latent_min,
+			latent_max,
+			noise_pred_norm: l2_norm(noise_pred),
+			guidance_norm
+		}
+	}
+
+	/// Returns `true` if any of this step's statistics are `NaN` or infinite.
+	pub fn is_non_finite(&self) -> bool {
+		[self.latent_mean, self.latent_std, self.latent_min, self.latent_max, self.noise_pred_norm]
+			.into_iter()
+			.chain(self.guidance_norm)
+			.any(|x| !x.is_finite())
+	}
+
+	/// Returns the step numbers of steps that look anomalous: steps whose statistics are non-finite, or whose latent
+	/// standard deviation grew by more than `threshold`x compared to the previous step (see
+	/// [`DEFAULT_STD_JUMP_THRESHOLD`]).
+	///
+	/// ```
+	/// # use pyke_diffusers::StepStats;
+	/// # fn stats(step: usize, latent_std: f32) -> StepStats {
+	/// # 	StepStats { step, timestep: 0.0, latent_mean: 0.0, latent_std, latent_min: 0.0, latent_max: 0.0, noise_pred_norm: 0.0, guidance_norm: None }
+	/// # }
+	/// let stats = [stats(0, 14.6), stats(1, 10.2), stats(2, 31.0), stats(3, f32::NAN)];
+	/// assert_eq!(StepStats::detect_anomalies(&stats, 1.5), vec![2, 3]);
+	/// ```
+	pub fn detect_anomalies(stats: &[StepStats], threshold: f32) -> Vec<usize> {
+		let mut anomalies = Vec::new();
+		for (i, s) in stats.iter().enumerate() {
+			let jumped = i > 0 && s.latent_std > stats[i - 1].latent_std * threshold;
+			if s.is_non_finite() || jumped {
+				anomalies.push(s.step);
+			}
+		}
+		anomalies
+	}
+}
+
+/// The scheduler's trajectory at a single denoising step, recorded with
+/// [`StableDiffusionTxt2ImgOptions::with_trajectory`](crate::StableDiffusionTxt2ImgOptions::with_trajectory) for
+/// plotting convergence & comparing schedulers.
+///
+/// Records are serializable, e.g. to export a trajectory as JSON. Each record takes 32 bytes, so the trajectory of a
+/// run costs a few KB at most. Recording it costs two reductions per step in addition to [`StepStats`]: one over the
+/// latents & one over the noise prediction.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct StepRecord {
+	/// The step number, i.e. the index of this step's timestep in the scheduler's timesteps.
+	pub step: usize,
+	/// This step's timestep.
+	pub timestep: f32,
+	/// The noise level of the latents denoised by this step, as returned by
+	/// [`DiffusionScheduler::sigma`](crate::DiffusionScheduler::sigma); `None` if the scheduler doesn't expose its
+	/// noise levels.
+	pub sigma: Option<f32>,
+	/// L2 norm of the latents after this step.
+	pub latent_norm: f32,
+	/// L2 norm of the (guided) noise prediction used for this step.
+	pub noise_pred_norm: f32
+}
+
+impl StepRecord {
+	pub(crate) fn new(step: usize, timestep: f32, sigma: Option<f32>, latents: ArrayView4<'_, f32>, noise_pred: ArrayView4<'_, f32>) -> Self {
+		Self {
+			step,
+			timestep,
+			sigma,
+			latent_norm: l2_norm(latents),
+			noise_pred_norm: l2_norm(noise_pred)
+		}
+	}
+}
+
+/// Computes the L2 norm of an array without allocating.
+pub(crate) fn l2_norm(x: ArrayView4<'_, f32>) -> f32 {
+	x.fold(0.0, |acc, &x| acc + x * x).sqrt()
+}
+
+/// Computes the L2 norm of `a - b` without allocating.
+pub(crate) fn l2_distance(a: ArrayView4<'_, f32>, b: ArrayView4<'_, f32>) -> f32 {
+	Zip::from(a).and(b).fold(0.0, |acc, &a, &b| acc + (a - b) * (a - b)).sqrt()
+}
@@ -0,0 +1,164 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use image::DynamicImage;
+use ndarray::Array4;
+
+use super::impl_txt2img::DenoiseLoop;
+use crate::{DiffusionCheckpoint, DiffusionScheduler, GenerationStage, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions, StopReason};
+
+/// The state of a generation after one denoising step, yielded by [`Txt2ImgSteps`].
+#[derive(Debug, Clone)]
+pub struct DiffusionStepOutput {
+	/// The index of the step, counted from `0`.
+	pub step: usize,
+	/// The timestep the step denoised at.
+	pub timestep: f32,
+	/// The latents after the step, of shape `(batch_size, 4, height / 8, width / 8)`.
+	pub latents: Array4<f32>,
+	/// An approximate decode of the latents, if enabled with [`Txt2ImgSteps::with_previews`].
+	pub preview: Option<Vec<DynamicImage>>
+}
+
+/// An iterator over the denoising steps of a text-to-image generation, created with
+/// [`StableDiffusionPipeline::txt2img_iter`].
+///
+/// Each call to [`next`](Iterator::next) takes one step and yields its [`DiffusionStepOutput`]; after the last step
+/// (or once a step fails, a callback stops generation, or generation is
+/// [cancelled](StableDiffusionTxt2ImgOptions::with_cancellation_token)), the iterator returns `None`. The iterator only
+/// denoises: decode the latents of the last step with [`StableDiffusionPipeline::decode_latents`].
+///
+/// The first call to `next` encodes the prompts & prepares the generation; the iterator then keeps the state of the
+/// denoising loop between steps, so the latents follow the exact trajectory of
+/// [`StableDiffusionTxt2ImgOptions::run_with_output`] with the same options, and dropping the iterator between steps
+/// simply stops the generation.
+pub struct Txt2ImgSteps<'p, S: DiffusionScheduler> {
+	session: &'p StableDiffusionPipeline,
+	scheduler: &'p mut S,
+	options: StableDiffusionTxt2ImgOptions,
+	previews: bool,
+	state: Option<DenoiseLoop<'p, S>>,
+	stop_reason: Option<StopReason>
+}
+
+impl<S: DiffusionScheduler> Txt2ImgSteps<'_, S> {
+	/// Sets whether each step's latents are approximately decoded into [`DiffusionStepOutput::preview`], like
+	/// [`StableDiffusionPipeline::approximate_decode_latents`]. Disabled by default.
+	pub fn with_previews(mut self, previews: bool) -> Self {
+		self.previews = previews;
+		self
+	}
+
+	/// Returns why the generation stopped, or `None` while steps are left.
+	pub fn stop_reason(&self) -> Option<StopReason> {
+		self.stop_reason
+	}
+
+	/// Captures a checkpoint of the last step taken, from which the generation can be finished with
+	/// [`StableDiffusionTxt2ImgOptions::resume_from`], or returns `None` before the first step.
+	pub fn checkpoint(&self) -> Option<DiffusionCheckpoint> {
+		self.state.as_ref().and_then(|state| state.capture_checkpoint(&*self.scheduler))
+	}
+
+	fn step(&mut self) -> anyhow::Result<Option<DiffusionStepOutput>> {
+		if self.state.is_none() {
+			self.state = Some(self.options.start_denoising(self.session, self.scheduler, None, GenerationStage::Full, None, None)?);
+		}
+		let state = self.state.as_mut().expect("denoising loop was started");
+		let step = self.options.denoise_step(self.session, self.scheduler, state)?;
+		if state.finished {
+			self.stop_reason = Some(state.stop_reason);
+		}
+		let step = match step {
+			Some(step) => step,
+			// interrupted before the step was taken
+			None => return Ok(None)
+		};
+		self.options.check_finite_latents(state)?;
+
+		let preview = if self.previews {
+			Some(self.session.approximate_decode_latents(state.latents.view())?)
+		} else {
+			None
+		};
+		Ok(Some(DiffusionStepOutput {
+			step,
+			timestep: state.timestep(step),
+			latents: state.latents.clone(),
+			preview
+		}))
+	}
+}
+
+impl<S: DiffusionScheduler> Iterator for Txt2ImgSteps<'_, S> {
+	type Item = anyhow::Result<DiffusionStepOutput>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.stop_reason.is_some() {
+			return None;
+		}
+		let output = self.step();
+		if output.is_err() {
+			self.stop_reason = Some(StopReason::Error);
+		}
+		output.transpose()
+	}
+}
+
+impl StableDiffusionPipeline {
+	/// Returns an iterator taking one denoising step of a text-to-image generation at a time; see [`Txt2ImgSteps`].
+	/// This is an alternative to [callbacks](StableDiffusionTxt2ImgOptions::callback_progress) for driving e.g. a UI
+	/// event loop, where errors can be propagated with `?` and no state has to be moved into closures.
+	///
+	/// Stepping cannot be combined with options which span the whole loop: seed averaging, early exit, restart
+	/// sampling, Attend-and-Excite, `denoising_end`, or a batch subset.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// # let mut scheduler = EulerDiscreteScheduler::default();
+	/// let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+	/// let options = StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox");
+	/// let mut latents = None;
+	/// for step in pipeline.txt2img_iter(&mut scheduler, options)?.with_previews(true) {
+	/// 	let step = step?;
+	/// 	// show `step.preview`...
+	/// 	latents = Some(step.latents);
+	/// }
+	/// let images = pipeline.decode_latents(latents.unwrap().view())?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn txt2img_iter<'p, S: DiffusionScheduler>(
+		&'p self,
+		scheduler: &'p mut S,
+		options: StableDiffusionTxt2ImgOptions
+	) -> anyhow::Result<Txt2ImgSteps<'p, S>> {
+		if !options.averaged_seeds.is_empty() || options.early_exit.is_some() || !options.restart_schedule.is_empty() {
+			anyhow::bail!("stepping through a generation cannot be combined with seed averaging, early exit, or restart sampling");
+		}
+		if options.attend_and_excite.is_some() || options.denoising_end.is_some() || options.batch_subset.is_some() {
+			anyhow::bail!("stepping through a generation cannot be combined with Attend-and-Excite, `denoising_end`, or a batch subset");
+		}
+		Ok(Txt2ImgSteps {
+			session: self,
+			scheduler,
+			options,
+			previews: false,
+			state: None,
+			stop_reason: None
+		})
+	}
+}
@@ -0,0 +1,165 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ndarray::{concatenate, ArrayD, Axis, Slice};
+use serde::{Deserialize, Serialize};
+
+use crate::{Prompt, PromptWeighting, StableDiffusionPipeline};
+
+/// How the embeddings of a [`StylePrompt`] are combined with the embeddings of the content prompt.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StyleBlend {
+	/// Interpolates each token's embedding: `(1 - weight) * content + weight * style`. The UNet sees a single prompt's
+	/// worth of tokens which is part content & part style; a weight of `0.0` is only the content prompt, `1.0` only the
+	/// style prompt. Both prompts must be padded to the same number of tokens, i.e. be in the same 75-token chunk count
+	/// as each other and the negative prompt. **This is the default.**
+	#[default]
+	WeightedSum,
+	/// Appends the style prompt's tokens after the content prompt's tokens, scaling the style tokens' embeddings by
+	/// `weight`. The UNet's cross-attention attends to both prompts side by side, which keeps the content prompt intact
+	/// & works with prompts of any length, but requires a UNet with a dynamic token axis (like long weighted prompts).
+	Concatenate,
+}
+
+/// A prompt describing the style of an image, encoded separately from the prompt describing its content & blended
+/// with it in embedding space; see
+/// [`StableDiffusionTxt2ImgOptions::with_style_prompt`](crate::StableDiffusionTxt2ImgOptions::with_style_prompt).
+///
+/// ## Classifier-free guidance
+/// The negative prompt is encoded once for each of the two prompts, padded to its length. Only the positive half of
+/// the guidance batch is blended: with [`StyleBlend::WeightedSum`], the unconditional embeddings are the content
+/// encode's (which are the same as the style encode's, since both halves are padded alike), and with
+/// [`StyleBlend::Concatenate`], the two unconditional embeddings are concatenated like the prompts, so both halves of
+/// the guidance batch have the same number of tokens. Guidance therefore pushes away from the negative prompt towards
+/// the blend of content & style.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StylePrompt {
+	/// The style prompt(s): either one prompt for the whole batch, or one per content prompt.
+	pub prompt: Prompt,
+	/// How strongly the style prompt is blended in; see [`StyleBlend`].
+	pub weight: f32,
+	/// How the embeddings are combined. Defaults to [`StyleBlend::WeightedSum`].
+	pub blend: StyleBlend
+}
+
+impl StylePrompt {
+	/// Creates a style prompt blended in with the given weight as a [`StyleBlend::WeightedSum`].
+	pub fn new(prompt: impl Into<Prompt>, weight: f32) -> Self {
+		Self {
+			prompt: prompt.into(),
+			weight,
+			blend: StyleBlend::default()
+		}
+	}
+
+	/// Sets how the embeddings are combined.
+	pub fn with_blend(mut self, blend: StyleBlend) -> Self {
+		self.blend = blend;
+		self
+	}
+}
+
+impl StableDiffusionPipeline {
+	/// Encodes a content prompt & a [`StylePrompt`] like [`StableDiffusionPipeline::encode_prompt_with_weighting`] and
+	/// blends their embeddings, returning embeddings with the same layout as `encode_prompt` (so that they can be
+	/// given to [`StableDiffusionTxt2ImgOptions::with_prompt_embeddings`](crate::StableDiffusionTxt2ImgOptions::with_prompt_embeddings)).
+	/// The style prompt is encoded with the first of the given weightings.
+	///
+	/// # Errors
+	/// Returns an error if the style prompt's batch size is neither 1 nor the content prompt's batch size, if its weight
+	/// isn't finite, or if the embeddings of both prompts have different numbers of tokens with
+	/// [`StyleBlend::WeightedSum`].
+	pub fn encode_styled_prompt(
+		&self,
+		content: Prompt,
+		style: &StylePrompt,
+		do_classifier_free_guidance: bool,
+		negative_prompt: Option<&Prompt>,
+		weighting: &[PromptWeighting]
+	) -> anyhow::Result<ArrayD<f32>> {
+		if !style.weight.is_finite() {
+			anyhow::bail!("style prompt weight is {}; expected a finite value", style.weight);
+		}
+		let content = self.encode_prompt_with_weighting(content, do_classifier_free_guidance, negative_prompt, weighting)?;
+		let halves = if do_classifier_free_guidance { 2 } else { 1 };
+		let batch_size = content.shape()[0] / halves;
+		let style_prompt = match style.prompt.len() {
+			1 => style.prompt.clone().batched(batch_size),
+			n if n == batch_size => style.prompt.clone(),
+			n => anyhow::bail!("{n} style prompts were given for a batch of {batch_size} prompts; expected 1 or one per prompt")
+		};
+		let style_embeddings =
+			self.encode_prompt_with_weighting(style_prompt, do_classifier_free_guidance, negative_prompt, &weighting[..weighting.len().min(1)])?;
+		blend_style(content, style_embeddings, style, halves)
+	}
+}
+
+/// Blends content & style embeddings laid out as `halves` halves of `[uncond, cond]`.
+fn blend_style(content: ArrayD<f32>, style_embeddings: ArrayD<f32>, style: &StylePrompt, halves: usize) -> anyhow::Result<ArrayD<f32>> {
+	let batch_size = content.shape()[0] / halves;
+	let cond = Slice::from(batch_size * (halves - 1)..);
+	match style.blend {
+		StyleBlend::WeightedSum => {
+			if content.shape() != style_embeddings.shape() {
+				anyhow::bail!(
+					"content & style embeddings have shapes {:?} & {:?}; use `StyleBlend::Concatenate` for prompts padded to different lengths",
+					content.shape(),
+					style_embeddings.shape()
+				);
+			}
+			let mut blended = content;
+			let mut blended_cond = blended.slice_axis_mut(Axis(0), cond);
+			blended_cond *= 1.0 - style.weight;
+			blended_cond.scaled_add(style.weight, &style_embeddings.slice_axis(Axis(0), cond));
+			Ok(blended)
+		}
+		StyleBlend::Concatenate => {
+			let mut style_embeddings = style_embeddings;
+			style_embeddings.slice_axis_mut(Axis(0), cond).mapv_inplace(|x| x * style.weight);
+			Ok(concatenate(Axis(1), &[content.view(), style_embeddings.view()])?)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ndarray::{ArrayD, Axis, IxDyn};
+
+	use super::{blend_style, StyleBlend, StylePrompt};
+
+	fn embeddings(batch_size: usize, tokens: usize, value: impl Fn(usize) -> f32) -> ArrayD<f32> {
+		ArrayD::from_shape_fn(IxDyn(&[batch_size, tokens, 2]), |index| value(index[0]))
+	}
+
+	#[test]
+	fn weighted_sum_blends_the_positive_half() {
+		let style = StylePrompt::new("style", 0.25);
+		let blended = blend_style(embeddings(2, 3, |i| [-1.0, 1.0][i]), embeddings(2, 3, |i| [-1.0, 5.0][i]), &style, 2).unwrap();
+		assert_eq!(blended, embeddings(2, 3, |i| [-1.0, 2.0][i]));
+		// without classifier-free guidance, the whole batch is positive
+		let blended = blend_style(embeddings(1, 3, |_| 1.0), embeddings(1, 3, |_| 5.0), &style, 1).unwrap();
+		assert_eq!(blended, embeddings(1, 3, |_| 2.0));
+		assert!(blend_style(embeddings(2, 3, |_| 1.0), embeddings(2, 6, |_| 1.0), &style, 2).is_err());
+	}
+
+	#[test]
+	fn concatenation_appends_scaled_style_tokens() {
+		let style = StylePrompt::new("style", 0.5).with_blend(StyleBlend::Concatenate);
+		let blended = blend_style(embeddings(2, 3, |i| [-1.0, 1.0][i]), embeddings(2, 1, |i| [-1.0, 4.0][i]), &style, 2).unwrap();
+		assert_eq!(blended.shape(), [2, 4, 2]);
+		assert_eq!(blended.index_axis(Axis(0), 0).iter().copied().collect::<Vec<_>>(), [-1.0; 8]);
+		assert_eq!(blended.index_axis(Axis(0), 1).iter().copied().collect::<Vec<_>>(), [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 2.0, 2.0]);
+	}
+}
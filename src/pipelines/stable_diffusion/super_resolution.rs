@@ -0,0 +1,256 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use image::{DynamicImage, Rgb, Rgb32FImage};
+use ndarray::{s, Array2, Array4, ArrayView4, Axis};
+use ndarray_rand::rand::{self, Rng};
+
+use super::{
+	impl_txt2img::{denoising_start_step, latents_shape},
+	preprocessing::image_to_array
+};
+use crate::{
+	schedulers::validate_custom_sigmas, DiffusionScheduler, ImageRef, ImageRegion, MultiDiffusionOptions, StableDiffusionOutput, StableDiffusionPipeline,
+	StableDiffusionTxt2ImgOptions
+};
+
+impl StableDiffusionPipeline {
+	/// Upscales `image` by `scale` and adds detail to it with a tiled img2img pass, denoising the upscaled image tile by
+	/// tile with [MultiDiffusion](MultiDiffusionOptions) after noising it to `strength`. The prompt, steps, seed &
+	/// guidance scale are taken from `options`.
+	///
+	/// The image is first resized by `scale` (rounded to a multiple of 8) with the options'
+	/// [resize filter](StableDiffusionTxt2ImgOptions::with_resize_filter) (Lanczos by default), then encoded with
+	/// [`StableDiffusionPipeline::encode_images_tiled`]. Its latents are noised to the timestep `strength` of the way
+	/// from the end of the schedule, like in img2img, and the remaining steps are denoised with MultiDiffusion, so that
+	/// each UNet pass only sees one tile. Finally, the latents are decoded with
+	/// [`StableDiffusionPipeline::decode_latents_tiled`]. All three stages use the tile size & overlap of the options'
+	/// [`MultiDiffusionOptions`], or [the defaults](MultiDiffusionOptions::default) (512px tiles overlapping by 128px)
+	/// if none are set; regional prompts are supported as usual.
+	///
+	/// ## Tile size, overlap & strength
+	/// Tiles should be the model's native resolution (512px for Stable Diffusion v1.x, 768px for v2.x): smaller tiles
+	/// see too little of the image to add coherent detail, and larger tiles exceed the resolution the model was trained
+	/// at. Each tile is denoised with the full prompt, so the prompt should describe the image as a whole without
+	/// naming objects that only appear in some part of it, or they will be repeated in every tile.
+	///
+	/// Overlapping tiles are blended, which hides the seams between them; larger overlaps blend more smoothly but add
+	/// tiles. The number of tiles, and thus the cost of each step & of the VAE passes, is that of MultiDiffusion:
+	/// `ceil((width - tile_size) / stride + 1) * ceil((height - tile_size) / stride + 1)` with
+	/// `stride = tile_size - tile_overlap`.
+	///
+	/// `strength` (in `(0, 1]`) controls how much is changed: only `round(strength * steps)` of the steps are taken.
+	/// Low strengths like `0.2`-`0.4` keep the composition & colors of the upscaled image and only sharpen & add
+	/// texture. Higher strengths add more detail, but also let each tile drift towards its own interpretation of the
+	/// prompt; above ~0.5, tiles no longer agree in their overlaps and the image falls apart into a grid of separate
+	/// pictures. Strengths low enough to take no step at all fail with an error.
+	///
+	/// ## Memory
+	/// The VAE & UNet only ever process one tile at a time, so their peak memory is that of a single
+	/// `tile_size`x`tile_size` generation regardless of the output size. On top of that, the full-size upscaled image,
+	/// latents & blending buffers are kept in memory as float32 arrays: about `28 * width * height` bytes for a single
+	/// image, plus `12 * width * height` bytes for each additional image of the batch, e.g. ~117 MB for one 2048x2048
+	/// image.
+	///
+	/// The output has `prompts * num_images_per_prompt` images, each starting from the same upscaled image with its own
+	/// noise. `denoising_end`, checkpoints, seed averaging & decoding to disk are not supported, and no
+	/// [`ClampReport`](crate::ClampReport)s are collected.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// # let mut scheduler = EulerDiscreteScheduler::default();
+	/// let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+	/// let image = image::open("landscape.png")?;
+	/// let options = StableDiffusionTxt2ImgOptions::default()
+	/// 	.with_prompt("a detailed photo of mountains, sharp focus, 8k")
+	/// 	.with_steps(30);
+	/// let output = pipeline.super_resolve(&mut scheduler, &image, 2.0, 0.3, options)?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn super_resolve<S: DiffusionScheduler>(
+		&self,
+		scheduler: &mut S,
+		image: &DynamicImage,
+		scale: f32,
+		strength: f32,
+		mut options: StableDiffusionTxt2ImgOptions
+	) -> anyhow::Result<StableDiffusionOutput> {
+		if !(scale.is_finite() && scale >= 1.0) {
+			anyhow::bail!("super-resolution `scale` must be at least 1, got {scale}");
+		}
+		if !(strength > 0.0 && strength <= 1.0) {
+			anyhow::bail!("super-resolution `strength` must be within (0, 1], got {strength}");
+		}
+		if options.denoising_end.is_some() || options.checkpoint_at.is_some() || !options.averaged_seeds.is_empty() || options.decode_to_disk.is_some() {
+			anyhow::bail!("super-resolution cannot be combined with `denoising_end`, `checkpoint_at`, seed averaging, or decoding to disk");
+		}
+		let tiling = options.multidiffusion.get_or_insert_with(MultiDiffusionOptions::default).clone();
+		tiling.validate()?;
+
+		let fit = |size: u32| ((size as f32 * scale / 8.0).round() as u32).max(1) * 8;
+		options.width = fit(image.width());
+		options.height = fit(image.height());
+		// the upscaled image is checked against the limits before it is allocated
+		self.options.limits.check(options.width, options.height, options.positive_prompt.len() * options.num_images_per_prompt, options.steps)?;
+		let pixels = image_to_array(image, options.resize_filter, options.width, options.height);
+		let init_latents = self.encode_images_tiled(pixels.view(), tiling.tile_size, tiling.tile_overlap)?;
+		drop(pixels);
+
+		let steps = match options.custom_sigmas.as_deref() {
+			Some(sigmas) => {
+				scheduler.set_sigmas(sigmas)?;
+				validate_custom_sigmas(sigmas)?.len()
+			}
+			None => options.supported_steps(&mut *scheduler)?,
+		};
+		let step = denoising_start_step(1.0 - strength, steps)
+			.map_err(|_| anyhow::anyhow!("super-resolution `strength` {strength} is too low to take any of the {steps} steps"))?;
+		if step >= scheduler.timesteps().len() {
+			anyhow::bail!("super-resolution would start at step {step}, but the scheduler only computed {} timesteps", scheduler.timesteps().len());
+		}
+		let batch_size = options.positive_prompt.len() * options.num_images_per_prompt;
+		let seed = options.seed.unwrap_or_else(|| rand::thread_rng().gen::<u64>());
+		let shape = latents_shape(batch_size, options.height, options.width);
+		let init_latents = init_latents.broadcast(shape).ok_or_else(|| anyhow::anyhow!("cannot broadcast image latents to {shape:?}"))?;
+
+		let denoised = options.denoise_from_latents(self, scheduler, init_latents, step, steps, seed)?;
+		let early_stop = denoised.stop_reason.is_early();
+		let mut images = if early_stop && !options.decode_on_early_stop {
+			Vec::new()
+		} else {
+			let images = self.decode_latents_tiled(denoised.latents.view(), tiling.tile_size, tiling.tile_overlap)?;
+			images.into_iter().map(ImageRef::InMemory).collect::<Vec<_>>()
+		};
+		let nsfw_flags = self.apply_nsfw_policy(&mut images)?;
+		Ok(StableDiffusionOutput {
+			images,
+			step_stats: denoised.step_stats,
+			trajectory: denoised.trajectory,
+			checkpoint: None,
+			clamp_reports: Vec::new(),
+			steps_taken: denoised.steps_taken,
+			nsfw_flags,
+			perturbed_regions: denoised.perturbed_regions,
+			generated: denoised.generated,
+			stop_reason: denoised.stop_reason,
+			latents: early_stop.then_some(denoised.latents),
+			seed: denoised.seed
+		})
+	}
+
+	/// Encodes images like [`StableDiffusionPipeline::encode_images`], but tile by tile: the images are split into
+	/// overlapping `tile_size`x`tile_size` tiles (in pixels, like [`MultiDiffusionOptions`]), each tile is encoded on
+	/// its own, and the latents of overlapping tiles are cross-faded over the overlap. This bounds the VAE encoder's
+	/// memory use to that of a single tile, at the cost of slight differences to encoding the whole image at once.
+	///
+	/// The images' width & height must be divisible by 8; `tile_size` & `tile_overlap` must be divisible by 8, and
+	/// `tile_overlap` smaller than `tile_size`.
+	pub fn encode_images_tiled(&self, images: ArrayView4<'_, f32>, tile_size: u32, tile_overlap: u32) -> anyhow::Result<Array4<f32>> {
+		let tiling = MultiDiffusionOptions { tile_size, tile_overlap, regions: Vec::new() };
+		tiling.validate()?;
+		let (batch_size, _, height, width) = images.dim();
+		if height % 8 != 0 || width % 8 != 0 {
+			anyhow::bail!("images to encode are {width}x{height}; width & height must be divisible by 8");
+		}
+		let (latent_height, latent_width) = (height / 8, width / 8);
+
+		let mut latents = Array4::<f32>::zeros((batch_size, 4, latent_height, latent_width));
+		let mut weight_sum = Array2::<f32>::zeros((latent_height, latent_width));
+		for tile in tiling.tiles(latent_height, latent_width) {
+			let (y, x, tile_height, tile_width) = tile;
+			let tile_latents = self.encode_images(images.slice(s![.., .., y * 8..(y + tile_height) * 8, x * 8..(x + tile_width) * 8]))?;
+			let weights = tile_weights(tile, (latent_height, latent_width), (tile_overlap / 8) as usize);
+			let mut latents_tile = latents.slice_mut(s![.., .., y..y + tile_height, x..x + tile_width]);
+			latents_tile += &(&tile_latents * &weights);
+			let mut weight_tile = weight_sum.slice_mut(s![y..y + tile_height, x..x + tile_width]);
+			weight_tile += &weights;
+		}
+		Ok(latents / &weight_sum)
+	}
+
+	/// Decodes UNet latents like [`StableDiffusionPipeline::decode_latents`], but tile by tile: the latents are split
+	/// into overlapping `tile_size`x`tile_size` tiles (in pixels, like [`MultiDiffusionOptions`]), each tile is decoded
+	/// on its own, and overlapping tiles are cross-faded over the overlap. This bounds the VAE decoder's memory use to
+	/// that of a single tile, which makes decoding images far larger than the model's native resolution possible; with
+	/// too small an overlap, seams between tiles may become visible.
+	///
+	/// `tile_size` & `tile_overlap` must be divisible by 8, and `tile_overlap` smaller than `tile_size`.
+	pub fn decode_latents_tiled(&self, latents: ArrayView4<'_, f32>, tile_size: u32, tile_overlap: u32) -> anyhow::Result<Vec<DynamicImage>> {
+		let tiling = MultiDiffusionOptions { tile_size, tile_overlap, regions: Vec::new() };
+		tiling.validate()?;
+		let (batch_size, _, latent_height, latent_width) = latents.dim();
+		let (height, width) = (latent_height * 8, latent_width * 8);
+
+		let mut pixels = Array4::<f32>::zeros((batch_size, height, width, 3));
+		let mut weight_sum = Array2::<f32>::zeros((height, width));
+		for (y, x, tile_height, tile_width) in tiling.tiles(latent_height, latent_width) {
+			let (y, x, tile_height, tile_width) = (y * 8, x * 8, tile_height * 8, tile_width * 8);
+			let region = ImageRegion::new(x as u32, y as u32, tile_width as u32, tile_height as u32);
+			let weights = tile_weights((y, x, tile_height, tile_width), (height, width), tile_overlap as usize);
+			for (image_pixels, image) in pixels.outer_iter_mut().zip(self.decode_latents_region(latents, &region)?) {
+				let image = image.to_rgb32f();
+				let mut pixels_tile = image_pixels.slice_move(s![y..y + tile_height, x..x + tile_width, ..]);
+				for ((py, px, c), value) in pixels_tile.indexed_iter_mut() {
+					*value += weights[[py, px]] * image.get_pixel(px as u32, py as u32).0[c];
+				}
+			}
+			let mut weight_tile = weight_sum.slice_mut(s![y..y + tile_height, x..x + tile_width]);
+			weight_tile += &weights;
+		}
+		Ok(pixels
+			.axis_iter(Axis(0))
+			.map(|pixels| {
+				DynamicImage::ImageRgb32F(Rgb32FImage::from_fn(width as u32, height as u32, |x, y| {
+					let (x, y) = (x as usize, y as usize);
+					Rgb([0, 1, 2].map(|c| pixels[[y, x, c]] / weight_sum[[y, x]]))
+				}))
+			})
+			.collect())
+	}
+}
+
+/// Returns the blending weights of a tile at `(y, x, height, width)` of an image of size `(image_height,
+/// image_width)`: `1` inside the tile, ramping down linearly over `overlap` pixels towards each edge the tile shares
+/// with its neighbours, so overlapping tiles cross-fade. Edges at the border of the image are not ramped. All weights
+/// are positive, so every pixel covered by a tile has a non-zero total weight.
+fn tile_weights(tile: (usize, usize, usize, usize), (image_height, image_width): (usize, usize), overlap: usize) -> Array2<f32> {
+	let (y, x, height, width) = tile;
+	let ramp = |i: usize, start: usize, len: usize, image_len: usize| {
+		let rising = if start == 0 { 1.0 } else { (i + 1) as f32 / (overlap + 1) as f32 };
+		let falling = if start + len == image_len { 1.0 } else { (len - i) as f32 / (overlap + 1) as f32 };
+		rising.min(falling).min(1.0)
+	};
+	Array2::from_shape_fn((height, width), |(py, px)| ramp(py, y, height, image_height) * ramp(px, x, width, image_width))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::tile_weights;
+
+	#[test]
+	fn tile_weights_cross_fade() {
+		// two 6px tiles overlapping by 2px in a 10px wide image
+		let left = tile_weights((0, 0, 1, 6), (1, 10), 2);
+		let right = tile_weights((0, 4, 1, 6), (1, 10), 2);
+		let third = |x: f32| (x * 3.0).round() / 3.0;
+		assert_eq!(left.row(0).mapv(third).to_vec(), [1.0, 1.0, 1.0, 1.0, 2.0 / 3.0, 1.0 / 3.0].map(third));
+		assert_eq!(right.row(0).mapv(third).to_vec(), [1.0 / 3.0, 2.0 / 3.0, 1.0, 1.0, 1.0, 1.0].map(third));
+
+		// a tile covering the whole image isn't ramped
+		assert!(tile_weights((0, 0, 4, 4), (4, 4), 2).iter().all(|&w| w == 1.0));
+	}
+}
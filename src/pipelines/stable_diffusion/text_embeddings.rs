@@ -31,7 +31,7 @@ impl TextEmbeddings {
 		let n_tokens = reader.read_u32::<LittleEndian>()?;
 		let text_hidden_size = reader.read_u32::<LittleEndian>()?;
 
-		if n_tokens != 0 {
+		if n_tokens != 0 && tokenizer.has_vocab() {
 			assert_eq!(n_tokens as usize, tokenizer.inner.get_vocab_size(true));
 		}
 
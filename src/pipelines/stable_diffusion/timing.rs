@@ -0,0 +1,155 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+use ndarray::Array4;
+
+use crate::{Prompt, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions};
+
+/// The image size used to calibrate a [`TimingModel`].
+const CALIBRATION_SIZE: (u32, u32) = (512, 512);
+
+/// Timings of a single run of each model, measured by [`StableDiffusionPipeline::calibrate`] and used to estimate
+/// generation time with [`StableDiffusionPipeline::estimate_duration`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingModel {
+	/// The width & height of the image the UNet & VAE decoder were timed at.
+	pub size: (u32, u32),
+	/// Time taken by a single UNet step with classifier-free guidance (i.e. a batch of 2) for one image.
+	pub unet_step: Duration,
+	/// Time taken to encode a single prompt & its negative prompt.
+	pub text_encode: Duration,
+	/// Time taken to decode a single image with the VAE decoder.
+	pub vae_decode: Duration
+}
+
+impl TimingModel {
+	/// Estimates how long generating an image with the given options would take.
+	///
+	/// UNet & VAE timings are scaled linearly by the number of pixels & batch size, and UNet timings are halved when
+	/// classifier-free guidance is disabled (`guidance_scale <= 1.0`).
+	pub fn estimate(&self, options: &StableDiffusionTxt2ImgOptions) -> Duration {
+		let batch_size = options.prompt_token_ids.as_ref().map_or(options.positive_prompt.len(), |ids| ids.nrows()).max(1) as f64;
+		let pixel_ratio = (options.width as f64 * options.height as f64) / (self.size.0 as f64 * self.size.1 as f64);
+		let cfg_ratio = if options.guidance_scale > 1.0 { 1.0 } else { 0.5 };
+
+		let unet = self.unet_step.as_secs_f64() * options.steps as f64 * pixel_ratio * cfg_ratio * batch_size;
+		let text_encode = self.text_encode.as_secs_f64() * batch_size;
+		let vae_decode = self.vae_decode.as_secs_f64() * pixel_ratio * batch_size;
+		Duration::from_secs_f64(unet + text_encode + vae_decode)
+	}
+}
+
+impl StableDiffusionPipeline {
+	/// Calibrates the pipeline's [`TimingModel`] by timing a single run of the text encoder, a single UNet step, and
+	/// a single VAE decode at 512x512, for use with [`StableDiffusionPipeline::estimate_duration`].
+	///
+	/// Each model is run once untimed beforehand, since the first run of a session is typically much slower due to
+	/// memory allocation & kernel selection. Calibration thus takes roughly as long as generating a 512x512 image with
+	/// 2 steps.
+	///
+	/// The timing model is reset when models are replaced, e.g. via [`StableDiffusionPipeline::replace`] or
+	/// [`StableDiffusionPipeline::replace_unet`], since the new models may have different performance characteristics.
+	///
+	/// ```
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// let mut pipeline = StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default())?;
+	/// pipeline.calibrate()?;
+	///
+	/// let options = StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox").with_steps(20);
+	/// println!("ETA: {:?}", pipeline.estimate_duration(&options).unwrap());
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn calibrate(&mut self) -> anyhow::Result<&TimingModel> {
+		let (width, height) = CALIBRATION_SIZE;
+
+		let encode = || self.encode_prompt(Prompt::default(), true, None);
+		encode()?;
+		let start = Instant::now();
+		let text_embeddings = encode()?;
+		let text_encode = start.elapsed();
+
+		let latents = Array4::<f32>::zeros((2, 4, (height / 8) as usize, (width / 8) as usize));
+		self.predict_noise(latents.view(), 999.0, text_embeddings.view())?;
+		let start = Instant::now();
+		self.predict_noise(latents.view(), 999.0, text_embeddings.view())?;
+		let unet_step = start.elapsed();
+
+		let latents = Array4::<f32>::zeros((1, 4, (height / 8) as usize, (width / 8) as usize));
+		self.decode_latents(latents.view())?;
+		let start = Instant::now();
+		self.decode_latents(latents.view())?;
+		let vae_decode = start.elapsed();
+
+		Ok(self.timing_model.insert(TimingModel {
+			size: CALIBRATION_SIZE,
+			unet_step,
+			text_encode,
+			vae_decode
+		}))
+	}
+
+	/// Returns the pipeline's [`TimingModel`], or `None` if [`StableDiffusionPipeline::calibrate`] has not been called.
+	pub fn timing_model(&self) -> Option<&TimingModel> {
+		self.timing_model.as_ref()
+	}
+
+	/// Estimates how long generating images with the given options would take, e.g. for ordering jobs in a queue or
+	/// displaying an ETA. Returns `None` if the pipeline has not been [calibrated](StableDiffusionPipeline::calibrate).
+	///
+	/// This is only a heuristic: the estimate is the calibrated UNet step time multiplied by the number of steps, plus
+	/// the time taken to encode the prompt & decode the image, with UNet & VAE timings scaled linearly by the number of
+	/// pixels. Actual performance depends heavily on hardware & resolution (attention scales worse than linearly with
+	/// image size, and some execution providers are faster at certain sizes), so estimates become less accurate the
+	/// further the resolution is from 512x512. Schedulers which evaluate the UNet more than once per step,
+	/// MultiDiffusion tiling, and callbacks are not accounted for.
+	pub fn estimate_duration(&self, options: &StableDiffusionTxt2ImgOptions) -> Option<Duration> {
+		self.timing_model.as_ref().map(|timing_model| timing_model.estimate(options))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::TimingModel;
+	use crate::StableDiffusionTxt2ImgOptions;
+
+	fn estimate_ms(timing_model: &TimingModel, options: StableDiffusionTxt2ImgOptions) -> u128 {
+		timing_model.estimate(&options).as_micros().saturating_add(500) / 1000
+	}
+
+	#[test]
+	fn estimate_scales_with_options() {
+		let timing_model = TimingModel {
+			size: (512, 512),
+			unet_step: Duration::from_millis(100),
+			text_encode: Duration::from_millis(50),
+			vae_decode: Duration::from_millis(200)
+		};
+		let options = || StableDiffusionTxt2ImgOptions::default().with_prompt("a").with_steps(10);
+
+		assert_eq!(estimate_ms(&timing_model, options()), 1250);
+		// 4x the pixels
+		assert_eq!(estimate_ms(&timing_model, options().with_size(1024, 1024)), 4850);
+		// batch of 2
+		assert_eq!(estimate_ms(&timing_model, options().with_prompt(["a", "b"])), 2500);
+		// no classifier-free guidance
+		assert_eq!(estimate_ms(&timing_model, options().with_guidance_scale(1.0)), 750);
+	}
+}
@@ -0,0 +1,315 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+	fs::{self, File},
+	io::{BufWriter, Write},
+	path::{Path, PathBuf}
+};
+
+use image::DynamicImage;
+use ndarray_rand::rand;
+
+use super::metadata::EncodedPrompts;
+use crate::{pipelines::broadcast_prompt_batch, DiffusionScheduler, MetadataMode, Prompt, ReproRecord, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions};
+
+/// The maximum length, in characters, of the `{prompt_slug}` placeholder of a filename template.
+const MAX_SLUG_LEN: usize = 48;
+
+/// The file format images are written in by [`StableDiffusionPipeline::txt2img_to_files`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFileFormat {
+	/// 8-bit RGB PNG, written with the `.png` extension. Requires the `image-png` feature (enabled by default). **This
+	/// is the default.**
+	#[default]
+	Png,
+	/// 8-bit RGB JPEG with the given quality (1-100), written with the `.jpg` extension. Requires the `image-jpeg`
+	/// feature.
+	Jpeg {
+		/// The JPEG quality, from 1 (worst) to 100 (best).
+		quality: u8
+	}
+}
+
+impl ImageFileFormat {
+	/// Returns the file extension (without the leading `.`) images of this format are written with.
+	pub fn extension(&self) -> &'static str {
+		match self {
+			ImageFileFormat::Png => "png",
+			ImageFileFormat::Jpeg { .. } => "jpg"
+		}
+	}
+
+	pub(crate) fn write<W: Write>(&self, image: &DynamicImage, writer: &mut W) -> anyhow::Result<()> {
+		let rgb = image.to_rgb8();
+		match *self {
+			ImageFileFormat::Png => {
+				#[cfg(feature = "image-png")]
+				{
+					use image::{codecs::png::PngEncoder, ImageEncoder};
+					PngEncoder::new(writer).write_image(&rgb, rgb.width(), rgb.height(), image::ColorType::Rgb8)?;
+					Ok(())
+				}
+				#[cfg(not(feature = "image-png"))]
+				{
+					let _ = (rgb, writer);
+					anyhow::bail!("writing PNG images requires the `image-png` feature")
+				}
+			}
+			ImageFileFormat::Jpeg { quality } => {
+				#[cfg(feature = "image-jpeg")]
+				{
+					use image::{codecs::jpeg::JpegEncoder, ImageEncoder};
+					JpegEncoder::new_with_quality(writer, quality.clamp(1, 100)).write_image(&rgb, rgb.width(), rgb.height(), image::ColorType::Rgb8)?;
+					Ok(())
+				}
+				#[cfg(not(feature = "image-jpeg"))]
+				{
+					let _ = (rgb, writer, quality);
+					anyhow::bail!("writing JPEG images requires the `image-jpeg` feature")
+				}
+			}
+		}
+	}
+}
+
+/// A generated image, either held in memory or written to disk by the low-memory decode mode enabled with
+/// [`StableDiffusionTxt2ImgOptions::with_decode_to_disk`].
+#[derive(Debug, Clone)]
+pub enum ImageRef {
+	/// An image held in memory, using a float32 buffer.
+	InMemory(DynamicImage),
+	/// The path of a PNG image written to disk.
+	OnDisk(PathBuf)
+}
+
+impl ImageRef {
+	/// Returns the width & height of the image. For on-disk images, only the image's header is read.
+	pub fn dimensions(&self) -> anyhow::Result<(u32, u32)> {
+		match self {
+			ImageRef::InMemory(image) => Ok((image.width(), image.height())),
+			ImageRef::OnDisk(path) => Ok(image::image_dimensions(path)?)
+		}
+	}
+
+	/// Returns the path of the image if it is on disk.
+	pub fn path(&self) -> Option<&Path> {
+		match self {
+			ImageRef::InMemory(_) => None,
+			ImageRef::OnDisk(path) => Some(path)
+		}
+	}
+
+	/// Returns the image, loading it into memory if it is on disk.
+	pub fn into_image(self) -> anyhow::Result<DynamicImage> {
+		match self {
+			ImageRef::InMemory(image) => Ok(image),
+			ImageRef::OnDisk(path) => Ok(image::open(path)?)
+		}
+	}
+
+	/// Writes the image to `path` in the given format. On-disk images are copied as-is when saving as PNG, and are
+	/// otherwise loaded (one at a time) & re-encoded.
+	pub fn save(&self, path: impl AsRef<Path>, format: ImageFileFormat) -> anyhow::Result<()> {
+		let path = path.as_ref();
+		match (self, format) {
+			(ImageRef::OnDisk(src), ImageFileFormat::Png) => {
+				if src != path {
+					fs::copy(src, path)?;
+				}
+				Ok(())
+			}
+			(ImageRef::OnDisk(src), _) => write_image(&image::open(src)?, path, format),
+			(ImageRef::InMemory(image), _) => write_image(image, path, format)
+		}
+	}
+}
+
+pub(crate) fn write_image(image: &DynamicImage, path: &Path, format: ImageFileFormat) -> anyhow::Result<()> {
+	let mut writer = BufWriter::new(File::create(path)?);
+	format.write(image, &mut writer)?;
+	writer.flush()?;
+	Ok(())
+}
+
+/// Converts a prompt to a filename-safe slug: lowercase ASCII letters & digits, with every run of other characters
+/// replaced by a single `-`, trimmed to at most [`MAX_SLUG_LEN`] characters.
+pub(crate) fn prompt_slug(prompt: &str) -> String {
+	let mut slug = String::with_capacity(prompt.len().min(MAX_SLUG_LEN));
+	for c in prompt.chars() {
+		if slug.len() >= MAX_SLUG_LEN {
+			break;
+		}
+		if c.is_ascii_alphanumeric() {
+			slug.push(c.to_ascii_lowercase());
+		} else if !slug.is_empty() && !slug.ends_with('-') {
+			slug.push('-');
+		}
+	}
+	slug.trim_end_matches('-').to_owned()
+}
+
+/// Substitutes the `{index}`, `{seed}`, & `{prompt_slug}` placeholders of a filename template.
+pub(crate) fn render_filename(name_template: &str, index: usize, seed: u64, prompt: &str) -> String {
+	name_template
+		.replace("{index}", &index.to_string())
+		.replace("{seed}", &seed.to_string())
+		.replace("{prompt_slug}", &prompt_slug(prompt))
+}
+
+impl StableDiffusionPipeline {
+	/// Generates images from the given prompt(s) like [`StableDiffusionTxt2ImgOptions::run`], and writes each image to
+	/// `out_dir` in the options' [`file_format`](StableDiffusionTxt2ImgOptions::file_format). Returns the paths of the
+	/// written images, in batch order.
+	///
+	/// `prompt` replaces the options' [`positive_prompt`](StableDiffusionTxt2ImgOptions::positive_prompt) (and
+	/// [`prompt_token_ids`](StableDiffusionTxt2ImgOptions::prompt_token_ids), if set). If the
+	/// options have no seed, a random seed is chosen up front so it can be used in filenames. `out_dir` is created if it
+	/// doesn't exist.
+	///
+	/// Each image's filename is `name_template` with the following placeholders substituted, followed by the format's
+	/// [extension](ImageFileFormat::extension):
+	/// - `{index}`: the image's index in the batch, starting from 0 (with multiple
+	///   [images per prompt](StableDiffusionTxt2ImgOptions::with_num_images_per_prompt), images are grouped by prompt);
+	/// - `{seed}`: the seed the images were generated with, which is shared by all images in the batch. If generation
+	///   was [retried after NaNs](StableDiffusionTxt2ImgOptions::with_retry_on_nan), this is the seed of the final
+	///   attempt, like [`StableDiffusionOutput::seed`](crate::StableDiffusionOutput::seed);
+	/// - `{prompt_slug}`: the image's prompt, lowercased, with every run of characters other than ASCII letters & digits
+	///   replaced by a single `-`, and truncated to 48 characters.
+	///
+	/// Other text, including unknown placeholders, is kept as-is. An error is returned before anything is generated if
+	/// two images of the batch would be written to the same path, e.g. when generating a batch with a template lacking
+	/// `{index}`. Existing files are overwritten.
+	///
+	/// Returns the paths of the images that were written. Only the elements of a
+	/// [batch subset](StableDiffusionTxt2ImgOptions::with_batch_subset) are written, under their index in the full
+	/// batch, and nothing is written if generation stopped early without
+	/// [decoding](StableDiffusionTxt2ImgOptions::with_decode_on_early_stop).
+	///
+	/// With a [`metadata_mode`](StableDiffusionTxt2ImgOptions::with_metadata_mode), the parameters of each image are
+	/// also recorded, so the image can be reproduced later.
+	///
+	/// This also works with the low-memory decode mode ([`StableDiffusionTxt2ImgOptions::with_decode_to_disk`]), in
+	/// which case the spilled images are copied (for PNG) or re-encoded one at a time.
+	///
+	/// ```no_run
+	/// # fn main() -> anyhow::Result<()> {
+	/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+	/// # let environment = OrtEnvironment::default().into_arc();
+	/// # let mut scheduler = EulerDiscreteScheduler::default();
+	/// let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+	/// let paths = pipeline.txt2img_to_files(
+	/// 	["photo of a red fox", "photo of a grey wolf"],
+	/// 	&mut scheduler,
+	/// 	StableDiffusionTxt2ImgOptions::default().with_seed(42),
+	/// 	"out",
+	/// 	"{seed}-{index}-{prompt_slug}"
+	/// )?;
+	/// // out/42-0-photo-of-a-red-fox.png, out/42-1-photo-of-a-grey-wolf.png
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn txt2img_to_files<P, S, D>(
+		&self,
+		prompt: P,
+		scheduler: &mut S,
+		options: StableDiffusionTxt2ImgOptions,
+		out_dir: D,
+		name_template: &str
+	) -> anyhow::Result<Vec<PathBuf>>
+	where
+		P: Into<Prompt>,
+		S: DiffusionScheduler,
+		D: AsRef<Path>
+	{
+		let prompt = prompt.into();
+		let seed = options.seed.unwrap_or_else(rand::random);
+		let mut options = options.with_prompt(prompt.clone()).with_seed(seed);
+		options.prompt_token_ids = None;
+		// filenames & records use the prompts as they will be encoded, so text scrubbed by the prompt filter isn't leaked
+		let encoded = EncodedPrompts::filter(self, &options)?;
+		let prompt = &encoded.prompt;
+		if options.metadata_mode != MetadataMode::None {
+			ReproRecord::ensure_recordable(&options)?;
+		}
+
+		let out_dir = out_dir.as_ref();
+		let extension = options.file_format.extension();
+		let num_images_per_prompt = options.num_images_per_prompt;
+		// a single prompt is broadcast against multiple negative prompts
+		let prompt_batch_size = match encoded.negative_prompt.as_ref() {
+			Some(negative_prompt) => broadcast_prompt_batch(prompt.len(), negative_prompt.len())?,
+			None => prompt.len()
+		};
+		let prompts = (0..prompt_batch_size)
+			.map(|i| &prompt[if prompt.len() == 1 { 0 } else { i }])
+			.flat_map(|prompt| std::iter::repeat(prompt).take(num_images_per_prompt))
+			.collect::<Vec<_>>();
+		let render_paths = |seed: u64| {
+			prompts
+				.iter()
+				.enumerate()
+				.map(|(index, prompt)| out_dir.join(format!("{}.{extension}", render_filename(name_template, index, seed, prompt))))
+				.collect::<Vec<_>>()
+		};
+		// every image shares the seed, so whether paths collide doesn't depend on its value
+		let paths = render_paths(seed);
+		for (i, path) in paths.iter().enumerate() {
+			if paths[..i].contains(path) {
+				anyhow::bail!("multiple images would be written to `{}`; include `{{index}}` in the filename template", path.display());
+			}
+		}
+
+		let output = options.run_with_output(self, scheduler)?;
+		// the effective seed differs from the requested one if generation was retried after NaNs
+		let seed = output.seed;
+		let paths = render_paths(seed);
+		fs::create_dir_all(out_dir)?;
+		let mut written = Vec::with_capacity(output.images.len());
+		// images of a batch subset are named & recorded by their index in the full batch, and elements that weren't
+		// generated or decoded (after an early stop) are skipped
+		for (index, (image, path)) in output.aligned_images().into_iter().zip(paths).enumerate() {
+			let image = match image {
+				Some(image) => image,
+				None => continue
+			};
+			if let Some(parent) = path.parent() {
+				fs::create_dir_all(parent)?;
+			}
+			let record = ReproRecord::new(&options, &encoded, seed, index);
+			image.save_with_metadata(&path, options.file_format, options.metadata_mode, &record)?;
+			written.push(path);
+		}
+		Ok(written)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{prompt_slug, render_filename};
+
+	#[test]
+	fn slugs() {
+		assert_eq!(prompt_slug("Photo of a red fox"), "photo-of-a-red-fox");
+		assert_eq!(prompt_slug("  (masterpiece:1.2), fox!! "), "masterpiece-1-2-fox");
+		assert_eq!(prompt_slug("日本の狐"), "");
+		assert_eq!(prompt_slug(&"a ".repeat(100)).len(), 47);
+	}
+
+	#[test]
+	fn filename_placeholders() {
+		assert_eq!(render_filename("{seed}_{index}_{prompt_slug}", 3, 42, "a red fox"), "42_3_a-red-fox");
+		assert_eq!(render_filename("img-{index}-{unknown}", 0, 42, "fox"), "img-0-{unknown}");
+	}
+}
@@ -16,15 +16,21 @@ use ndarray::{s, Array1, Array4, ArrayView4};
 use ndarray_rand::{rand::Rng, rand_distr::StandardNormal, RandomExt};
 
 use crate::{
-	schedulers::{betas_for_alpha_bar, BetaSchedule, DiffusionScheduler, SchedulerStepOutput},
+	schedulers::{
+		schedule::{to_f32, ScheduleCache, TrainingSchedule},
+		alpha_bar_sigma, BetaSchedule, DiffusionScheduler, SchedulerStepOutput
+	},
 	SchedulerOptimizedDefaults, SchedulerPredictionType
 };
 
 /// Additional configuration for the [`DDIMScheduler`].
 #[derive(Debug, Clone)]
 pub struct DDIMSchedulerConfig {
-	/// Option to predicted sample between -1 and 1 for numerical stability.
+	/// Option to clip the predicted original sample to `[-clip_sample_range, clip_sample_range]` for numerical
+	/// stability.
 	pub clip_sample: bool,
+	/// The range the predicted original sample is clipped to if `clip_sample` is enabled. Defaults to `1.0`.
+	pub clip_sample_range: f32,
 	/// Each diffusion step uses the value of alphas product at that step and at the previous one. For the final step,
 	/// there is no previous alpha. When this option is true, the previous alpha product is fixed to `1`, otherwise it
 	/// uses the value of alpha at step 0.
@@ -44,6 +50,7 @@ pub struct DDIMScheduler {
 	final_alpha_cumprod: f32,
 	init_noise_sigma: f32,
 	timesteps: Array1<usize>,
+	schedule_cache: ScheduleCache<Array1<usize>>,
 	num_train_timesteps: usize,
 	num_inference_steps: Option<usize>,
 	config: DDIMSchedulerConfig,
@@ -60,6 +67,7 @@ impl Default for DDIMSchedulerConfig {
 	fn default() -> Self {
 		Self {
 			clip_sample: true,
+			clip_sample_range: 1.0,
 			set_alpha_to_one: true,
 			steps_offset: 0
 		}
@@ -102,28 +110,9 @@ impl DDIMScheduler {
 
 		let config = config.unwrap_or_default();
 
-		let betas = match beta_schedule {
-			BetaSchedule::TrainedBetas(betas) => betas.clone(),
-			BetaSchedule::Linear => Array1::linspace(beta_start, beta_end, num_train_timesteps),
-			BetaSchedule::ScaledLinear => {
-				let mut betas = Array1::linspace(beta_start.sqrt(), beta_end.sqrt(), num_train_timesteps);
-				betas.par_map_inplace(|f| *f = f.powi(2));
-				betas
-			}
-			BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(num_train_timesteps, 0.999),
-			_ => anyhow::bail!("{beta_schedule:?} not implemented for DDIMScheduler")
-		};
-
-		let alphas = 1.0 - betas;
-
-		let alphas_cumprod = alphas
-			.view()
-			.into_iter()
-			.scan(1.0, |prod, alpha| {
-				*prod *= *alpha;
-				Some(*prod)
-			})
-			.collect::<Array1<_>>();
+		let schedule = TrainingSchedule::new(num_train_timesteps, beta_start, beta_end, beta_schedule)
+			.ok_or_else(|| anyhow::anyhow!("{beta_schedule:?} not implemented for DDIMScheduler"))?;
+		let alphas_cumprod = to_f32(&schedule.alphas_cumprod);
 
 		// At every step in DDIM, we are looking into the previous alphas_cumprod
 		// For the final step, there is no previous alphas_cumprod because we are already at 0
@@ -144,6 +133,7 @@ impl DDIMScheduler {
 			final_alpha_cumprod,
 			init_noise_sigma,
 			timesteps,
+			schedule_cache: ScheduleCache::default(),
 			num_inference_steps: None,
 			num_train_timesteps,
 			prediction_type: *prediction_type,
@@ -177,16 +167,18 @@ impl DiffusionScheduler for DDIMScheduler {
 	}
 
 	fn set_timesteps(&mut self, num_inference_steps: usize) {
+		let num_inference_steps = num_inference_steps.min(self.num_train_timesteps);
 		self.num_inference_steps = Some(num_inference_steps);
 
-		let step_ratio = self.num_train_timesteps / num_inference_steps;
-
-		let timesteps = Array1::range(0.0, (num_inference_steps - 1) as f32, 1.0)
-			.slice(s![..;-1])
-			.map(|f| (f * step_ratio as f32).round() as isize)
-			.to_owned();
-
-		self.timesteps = (timesteps + self.config.steps_offset).map(|f| *f as usize);
+		let (num_train_timesteps, steps_offset) = (self.num_train_timesteps, self.config.steps_offset);
+		self.timesteps = self.schedule_cache.get_or_insert_with(num_inference_steps, || {
+			let step_ratio = num_train_timesteps / num_inference_steps;
+			let timesteps = Array1::range(0.0, num_inference_steps as f32, 1.0)
+				.slice(s![..;-1])
+				.map(|f| (f * step_ratio as f32).round() as isize)
+				.to_owned();
+			(timesteps + steps_offset).map(|f| *f as usize)
+		});
 	}
 
 	fn step<R: Rng + ?Sized>(&mut self, model_output: ArrayView4<'_, f32>, timestep: usize, sample: ArrayView4<'_, f32>, rng: &mut R) -> SchedulerStepOutput {
@@ -218,7 +210,8 @@ impl DiffusionScheduler for DDIMScheduler {
 
 		// 4. clip predicted x_0
 		if self.config.clip_sample {
-			pred_original_sample = pred_original_sample.map(|f| f.clamp(-1.0, 1.0));
+			let range = self.config.clip_sample_range;
+			pred_original_sample = pred_original_sample.map(|f| f.clamp(-range, range));
 		}
 
 		// 5. compute variance: "sigma_t(η)" -> see formula (16)
@@ -258,10 +251,18 @@ impl DiffusionScheduler for DDIMScheduler {
 		self.timesteps.view()
 	}
 
+	fn sigma(&self, index: usize) -> Option<f32> {
+		self.timesteps.get(index).and_then(|&t| alpha_bar_sigma(&self.alphas_cumprod, t))
+	}
+
 	fn init_noise_sigma(&self) -> f32 {
 		self.init_noise_sigma
 	}
 
+	fn max_inference_steps(&self) -> Option<usize> {
+		Some(self.num_train_timesteps)
+	}
+
 	fn len(&self) -> usize {
 		self.num_train_timesteps
 	}
@@ -281,7 +282,8 @@ impl SchedulerOptimizedDefaults for DDIMScheduler {
 			Some(DDIMSchedulerConfig {
 				clip_sample: false,
 				set_alpha_to_one: false,
-				steps_offset: 1
+				steps_offset: 1,
+				..Default::default()
 			})
 		)
 	}
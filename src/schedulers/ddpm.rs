@@ -16,7 +16,10 @@ use anyhow::Context;
 use ndarray::{s, Array1, Array4, ArrayView4};
 use ndarray_rand::{rand::Rng, rand_distr::StandardNormal, RandomExt};
 
-use super::{betas_for_alpha_bar, BetaSchedule, DiffusionScheduler, SchedulerStepOutput};
+use super::{
+	schedule::{to_f32, TrainingSchedule},
+	alpha_bar_sigma, BetaSchedule, DiffusionScheduler, SchedulerStepOutput
+};
 use crate::{SchedulerOptimizedDefaults, SchedulerPredictionType};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -37,10 +40,13 @@ impl Default for DDPMVarianceType {
 }
 
 /// Additional configuration for the [`DDPMScheduler`].
-#[derive(Default, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct DDPMSchedulerConfig {
-	/// Option to predicted sample between -1 and 1 for numerical stability.
+	/// Option to clip the predicted original sample to `[-clip_sample_range, clip_sample_range]` for numerical
+	/// stability. Disabled by default.
 	pub clip_sample: bool,
+	/// The range the predicted original sample is clipped to if `clip_sample` is enabled. Defaults to `1.0`.
+	pub clip_sample_range: f32,
 	/// Option to clip the variance used when adding noise to the denoised sample.
 	pub variance_type: DDPMVarianceType
 }
@@ -68,6 +74,16 @@ impl Default for DDPMScheduler {
 	}
 }
 
+impl Default for DDPMSchedulerConfig {
+	fn default() -> Self {
+		Self {
+			clip_sample: false,
+			clip_sample_range: 1.0,
+			variance_type: DDPMVarianceType::default()
+		}
+	}
+}
+
 impl DDPMScheduler {
 	/// Creates a new instance of the scheduler.
 	///
@@ -104,28 +120,11 @@ impl DDPMScheduler {
 
 		let config = config.unwrap_or_default();
 
-		let betas = match beta_schedule {
-			BetaSchedule::TrainedBetas(betas) => betas.clone(),
-			BetaSchedule::Linear => Array1::linspace(beta_start, beta_end, num_train_timesteps),
-			BetaSchedule::ScaledLinear => {
-				let mut betas = Array1::linspace(beta_start.sqrt(), beta_end.sqrt(), num_train_timesteps);
-				betas.par_map_inplace(|f| *f = f.powi(2));
-				betas
-			}
-			BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(num_train_timesteps, 0.999),
-			BetaSchedule::Sigmoid => unimplemented!()
-		};
-
-		let alphas = 1.0 - &betas;
-
-		let alphas_cumprod = alphas
-			.view()
-			.into_iter()
-			.scan(1.0, |prod, alpha| {
-				*prod *= *alpha;
-				Some(*prod)
-			})
-			.collect::<Array1<_>>();
+		let schedule = TrainingSchedule::new(num_train_timesteps, beta_start, beta_end, beta_schedule)
+			.ok_or_else(|| anyhow::anyhow!("{beta_schedule:?} not implemented for DDPMScheduler"))?;
+		let betas = to_f32(&schedule.betas);
+		let alphas = schedule.betas.mapv(|f| (1.0 - f) as f32);
+		let alphas_cumprod = to_f32(&schedule.alphas_cumprod);
 
 		let timesteps = Array1::linspace(0.0, num_train_timesteps as f32 - 1.0, num_train_timesteps)
 			.slice(s![..;-1])
@@ -208,7 +207,8 @@ impl DiffusionScheduler for DDPMScheduler {
 
 		// 3. clip predicted x_0
 		if self.config.clip_sample {
-			pred_original_sample = pred_original_sample.map(|f| f.clamp(-1.0, 1.0));
+			let range = self.config.clip_sample_range;
+			pred_original_sample = pred_original_sample.map(|f| f.clamp(-range, range));
 		}
 
 		// 4. compute coefficients for pred_original_sample x_0 and current sample x_t (formula 7)
@@ -252,10 +252,18 @@ impl DiffusionScheduler for DDPMScheduler {
 		self.timesteps.view()
 	}
 
+	fn sigma(&self, index: usize) -> Option<f32> {
+		self.timesteps.get(index).and_then(|&t| alpha_bar_sigma(&self.alphas_cumprod, t as usize))
+	}
+
 	fn init_noise_sigma(&self) -> f32 {
 		self.init_noise_sigma
 	}
 
+	fn max_inference_steps(&self) -> Option<usize> {
+		Some(self.num_train_timesteps)
+	}
+
 	fn len(&self) -> usize {
 		self.num_train_timesteps
 	}
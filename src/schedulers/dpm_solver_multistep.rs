@@ -19,7 +19,10 @@ use ndarray::{Array1, Array4, ArrayView4};
 use ndarray_rand::rand::Rng;
 
 use crate::{
-	schedulers::{betas_for_alpha_bar, BetaSchedule, DiffusionScheduler, SchedulerStepOutput},
+	schedulers::{
+		schedule::{karras_schedule, to_f32, ScheduleCache, TrainingSchedule},
+		alpha_bar_sigma, BetaSchedule, DiffusionScheduler, SchedulerState, SchedulerStepOutput
+	},
 	SchedulerOptimizedDefaults, SchedulerPredictionType
 };
 
@@ -66,6 +69,12 @@ pub struct DPMSolverMultistepSchedulerConfig {
 	/// The threshold value for dynamic thresholding. Valid only when `thresholding: true` and
 	/// `algorithm_type: DPMSolverAlgorithmType::DPMSolverPlusPlus`.
 	pub sample_max_value: f32,
+	/// Option to clip the predicted original sample to `[-clip_sample_range, clip_sample_range]` each step for
+	/// stability at high guidance scales. Only applies to `DPMSolverAlgorithmType::DPMSolverPlusPlus`, which predicts
+	/// the original sample. Disabled by default.
+	pub clip_sample: bool,
+	/// The range the predicted original sample is clipped to if `clip_sample` is enabled. Defaults to `1.0`.
+	pub clip_sample_range: f32,
 	/// The algorithm type for the solver, see [`DPMSolverAlgorithmType`]. We recommend to use `DPMSolverPlusPlus` with
 	/// `solver_order=2` for guided sampling (e.g. Stable Diffusion).
 	pub algorithm_type: DPMSolverAlgorithmType,
@@ -84,6 +93,8 @@ impl Default for DPMSolverMultistepSchedulerConfig {
 			thresholding: false,
 			dynamic_thresholding_ratio: 0.995,
 			sample_max_value: 1.0,
+			clip_sample: false,
+			clip_sample_range: 1.0,
 			algorithm_type: DPMSolverAlgorithmType::DPMSolverPlusPlus,
 			solver_type: DPMSolverType::Midpoint,
 			lower_order_final: true
@@ -100,6 +111,11 @@ impl Default for DPMSolverMultistepSchedulerConfig {
 /// Currently, we support the multistep DPM-Solver for both noise prediction models and data prediction models. We
 /// recommend to use `solver_order: 2` for guided sampling, and `solver_order: 3` for unconditional sampling.
 ///
+/// With the default `DPMSolverPlusPlus` algorithm & `solver_order: 2`, this is the DPM++ 2M sampler. Multistep solvers
+/// reach their order by reusing the model outputs of previous steps rather than evaluating the model several times per
+/// step, so [`DiffusionScheduler::order`] is `1`; the first step (and, with `lower_order_final`, the last step) falls
+/// back to a first-order update since there is no previous model output yet.
+///
 /// [dpm]: https://arxiv.org/abs/2206.00927
 /// [dpm++]: https://arxiv.org/abs/2211.01095
 #[derive(Clone)]
@@ -110,12 +126,14 @@ pub struct DPMSolverMultistepScheduler {
 	lambda_t: Array1<f32>,
 	init_noise_sigma: f32,
 	timesteps: Array1<usize>,
+	schedule_cache: ScheduleCache<Array1<usize>>,
 	num_train_timesteps: usize,
 	num_inference_steps: Option<usize>,
 	config: DPMSolverMultistepSchedulerConfig,
 	prediction_type: SchedulerPredictionType,
 	model_outputs: VecDeque<Array4<f32>>,
-	lower_order_nums: usize
+	lower_order_nums: usize,
+	karras_sigmas: bool
 }
 
 impl Default for DPMSolverMultistepScheduler {
@@ -160,32 +178,14 @@ impl DPMSolverMultistepScheduler {
 
 		let config = config.unwrap_or_default();
 
-		let betas = match beta_schedule {
-			BetaSchedule::TrainedBetas(betas) => betas.clone(),
-			BetaSchedule::Linear => Array1::linspace(beta_start, beta_end, num_train_timesteps),
-			BetaSchedule::ScaledLinear => {
-				let mut betas = Array1::linspace(beta_start.sqrt(), beta_end.sqrt(), num_train_timesteps);
-				betas.par_map_inplace(|f| *f = f.powi(2));
-				betas
-			}
-			BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(num_train_timesteps, 0.999),
-			_ => anyhow::bail!("{beta_schedule:?} not implemented for DDIMScheduler")
-		};
-
-		let alphas = 1.0 - betas;
-
-		let alphas_cumprod = alphas
-			.view()
-			.into_iter()
-			.scan(1.0, |prod, alpha| {
-				*prod *= *alpha;
-				Some(*prod)
-			})
-			.collect::<Array1<_>>();
+		let schedule = TrainingSchedule::new(num_train_timesteps, beta_start, beta_end, beta_schedule)
+			.ok_or_else(|| anyhow::anyhow!("{beta_schedule:?} not implemented for DPMSolverMultistepScheduler"))?;
+		let alphas_cumprod = to_f32(&schedule.alphas_cumprod);
 
-		let alpha_t = alphas_cumprod.map(|f| f.sqrt());
-		let sigma_t = alphas_cumprod.map(|f| (1.0 - f).sqrt());
-		let lambda_t = alpha_t.map(|f| f.ln()) - sigma_t.map(|f| f.ln());
+		let alpha_t = schedule.alphas_cumprod.mapv(f64::sqrt);
+		let sigma_t = schedule.alphas_cumprod.mapv(|f| (1.0 - f).sqrt());
+		let lambda_t = to_f32(&(alpha_t.mapv(f64::ln) - sigma_t.mapv(f64::ln)));
+		let (alpha_t, sigma_t) = (to_f32(&alpha_t), to_f32(&sigma_t));
 
 		let timesteps = Array1::linspace(num_train_timesteps as f32 - 1.0, 0.0, num_train_timesteps).map(|f| *f as usize);
 
@@ -199,19 +199,35 @@ impl DPMSolverMultistepScheduler {
 			lambda_t,
 			init_noise_sigma,
 			timesteps,
+			schedule_cache: ScheduleCache::default(),
 			num_inference_steps: None,
 			num_train_timesteps,
 			prediction_type: *prediction_type,
 			config: config.clone(),
 			lower_order_nums: 0,
-			model_outputs: VecDeque::with_capacity(config.solver_order)
+			model_outputs: VecDeque::with_capacity(config.solver_order),
+			karras_sigmas: false
 		})
 	}
 
+	/// Sets whether [`set_timesteps`](DiffusionScheduler::set_timesteps) spaces the timesteps by the noise schedule of
+	/// [Karras et al. (2022)](https://arxiv.org/abs/2206.00364) (with `rho = 7`) instead of evenly, i.e. the DPM++ 2M
+	/// Karras sampler with the default algorithm & order. Disabled by default.
+	///
+	/// Each Karras sigma is mapped back to the nearest integer training timestep. With many steps, timesteps which
+	/// round to the same value are only taken once, so [`timesteps`](DiffusionScheduler::timesteps) may hold fewer
+	/// timesteps than steps were requested; the pipelines then take as many steps as there are timesteps, following
+	/// the options' `StepLimitPolicy`.
+	pub fn with_karras_sigmas(mut self, karras_sigmas: bool) -> Self {
+		self.karras_sigmas = karras_sigmas;
+		self.schedule_cache = ScheduleCache::default();
+		self
+	}
+
 	fn convert_model_output(&self, model_output: ArrayView4<'_, f32>, timestep: usize, sample: ArrayView4<f32>) -> Array4<f32> {
 		match self.config.algorithm_type {
 			DPMSolverAlgorithmType::DPMSolverPlusPlus => {
-				let x0_pred = match self.prediction_type {
+				let mut x0_pred = match self.prediction_type {
 					SchedulerPredictionType::Epsilon => {
 						let alpha_t = self.alpha_t[timestep];
 						let sigma_t = self.sigma_t[timestep];
@@ -227,6 +243,10 @@ impl DPMSolverMultistepScheduler {
 				if self.config.thresholding {
 					todo!("thresholding not yet implemented for DPMSolverMultistepScheduler, please open an issue");
 				}
+				if self.config.clip_sample {
+					let range = self.config.clip_sample_range;
+					x0_pred.mapv_inplace(|f| f.clamp(-range, range));
+				}
 				x0_pred
 			}
 			DPMSolverAlgorithmType::DPMSolver => match self.prediction_type {
@@ -263,7 +283,7 @@ impl DPMSolverMultistepScheduler {
 		prev_timestep: usize,
 		sample: ArrayView4<f32>
 	) -> Array4<f32> {
-		assert_eq!(timestep_list.len(), model_output_list.len());
+		assert!(model_output_list.len() >= timestep_list.len());
 
 		let (t, s0, s1) = (prev_timestep, timestep_list[timestep_list.len() - 1], timestep_list[timestep_list.len() - 2]);
 		let (m0, m1) = (&model_output_list[model_output_list.len() - 1], &model_output_list[model_output_list.len() - 2]);
@@ -292,7 +312,7 @@ impl DPMSolverMultistepScheduler {
 		prev_timestep: usize,
 		sample: ArrayView4<f32>
 	) -> Array4<f32> {
-		assert_eq!(timestep_list.len(), model_output_list.len());
+		assert!(model_output_list.len() >= timestep_list.len());
 
 		let (t, s0, s1, s2) =
 			(prev_timestep, timestep_list[timestep_list.len() - 1], timestep_list[timestep_list.len() - 2], timestep_list[timestep_list.len() - 3]);
@@ -329,6 +349,7 @@ impl DPMSolverMultistepScheduler {
 impl DiffusionScheduler for DPMSolverMultistepScheduler {
 	type TimestepType = usize;
 
+	// a single timestep per step regardless of `solver_order`, since higher orders reuse the previous model outputs
 	fn order() -> usize {
 		1
 	}
@@ -340,9 +361,19 @@ impl DiffusionScheduler for DPMSolverMultistepScheduler {
 	fn set_timesteps(&mut self, num_inference_steps: usize) {
 		self.num_inference_steps = Some(num_inference_steps);
 
-		let timesteps = Array1::linspace(self.num_train_timesteps as f32 - 1.0, 0.0, num_inference_steps).map(|f| *f as usize);
-
-		self.timesteps = timesteps;
+		let (alphas_cumprod, num_train_timesteps, karras_sigmas) = (&self.alphas_cumprod, self.num_train_timesteps, self.karras_sigmas);
+		self.timesteps = self.schedule_cache.get_or_insert_with(num_inference_steps, || {
+			if karras_sigmas {
+				let train_sigmas = alphas_cumprod.mapv(|f| ((1.0 - f) / f).sqrt());
+				let (timesteps, _) = karras_schedule(&train_sigmas, num_inference_steps);
+				let mut timesteps = timesteps.iter().map(|t| t.round() as usize).collect::<Vec<_>>();
+				// the timesteps are decreasing, so any that round to the same value are adjacent
+				timesteps.dedup();
+				Array1::from_vec(timesteps)
+			} else {
+				Array1::linspace(num_train_timesteps as f32 - 1.0, 0.0, num_inference_steps).map(|f| *f as usize)
+			}
+		});
 		self.model_outputs = VecDeque::with_capacity(self.config.solver_order);
 		self.lower_order_nums = 0;
 	}
@@ -360,8 +391,8 @@ impl DiffusionScheduler for DPMSolverMultistepScheduler {
 		let lower_order_second = (step_index == self.timesteps.len() - 2) && self.config.lower_order_final && self.timesteps.len() < 15;
 
 		let model_output = self.convert_model_output(model_output, timestep, sample);
-		let n_outputs = self.model_outputs.len();
-		for _ in n_outputs..self.config.solver_order - 1 {
+		// only the last `solver_order` model outputs are needed
+		if self.model_outputs.len() >= self.config.solver_order {
 			self.model_outputs.pop_front();
 		}
 		self.model_outputs.push_back(model_output.clone());
@@ -375,6 +406,9 @@ impl DiffusionScheduler for DPMSolverMultistepScheduler {
 			let timestep_list = [self.timesteps[step_index - 2], self.timesteps[step_index - 1], timestep];
 			self.multistep_dpm_solver_third_order_update(&self.model_outputs, timestep_list, prev_timestep, sample)
 		};
+		if self.lower_order_nums < self.config.solver_order {
+			self.lower_order_nums += 1;
+		}
 
 		SchedulerStepOutput { prev_sample, ..Default::default() }
 	}
@@ -387,6 +421,10 @@ impl DiffusionScheduler for DPMSolverMultistepScheduler {
 		self.timesteps.view()
 	}
 
+	fn sigma(&self, index: usize) -> Option<f32> {
+		self.timesteps.get(index).and_then(|&t| alpha_bar_sigma(&self.alphas_cumprod, t))
+	}
+
 	fn init_noise_sigma(&self) -> f32 {
 		self.init_noise_sigma
 	}
@@ -394,6 +432,18 @@ impl DiffusionScheduler for DPMSolverMultistepScheduler {
 	fn len(&self) -> usize {
 		self.num_train_timesteps
 	}
+
+	fn state(&self) -> SchedulerState {
+		SchedulerState {
+			model_outputs: self.model_outputs.iter().cloned().collect(),
+			lower_order_nums: self.lower_order_nums
+		}
+	}
+
+	fn restore_state(&mut self, state: SchedulerState) {
+		self.model_outputs = state.model_outputs.into();
+		self.lower_order_nums = state.lower_order_nums;
+	}
 }
 
 impl SchedulerOptimizedDefaults for DPMSolverMultistepScheduler {
@@ -412,6 +462,8 @@ impl SchedulerOptimizedDefaults for DPMSolverMultistepScheduler {
 				thresholding: false,
 				dynamic_thresholding_ratio: 0.995,
 				sample_max_value: 1.0,
+				clip_sample: false,
+				clip_sample_range: 1.0,
 				algorithm_type: DPMSolverAlgorithmType::DPMSolverPlusPlus,
 				solver_type: DPMSolverType::Midpoint,
 				lower_order_final: true
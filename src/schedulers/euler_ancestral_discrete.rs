@@ -17,7 +17,10 @@ use ndarray::{concatenate, s, Array1, Array4, ArrayView4, Axis, Zip};
 use ndarray_rand::{rand::Rng, rand_distr::StandardNormal, RandomExt};
 
 use crate::{
-	schedulers::{BetaSchedule, DiffusionScheduler, SchedulerStepOutput},
+	schedulers::{
+		schedule::{custom_sigma_schedule, karras_schedule, to_f32, ScheduleCache, TrainingSchedule},
+		BetaSchedule, DiffusionScheduler, SchedulerStepOutput
+	},
 	util::interpolation::LinearInterpolatorAccelerated,
 	SchedulerOptimizedDefaults
 };
@@ -29,13 +32,15 @@ use crate::{
 /// [kd]: https://github.com/crowsonkb/k-diffusion/blob/481677d114f6ea445aa009cf5bd7a9cdee909e47/k_diffusion/sampling.py#L72
 #[derive(Clone)]
 pub struct EulerAncestralDiscreteScheduler {
-	alphas_cumprod: Array1<f32>,
+	train_sigmas: Array1<f32>,
 	sigmas: Array1<f32>,
 	init_noise_sigma: f32,
 	timesteps: Array1<f32>,
 	num_train_timesteps: usize,
 	num_inference_steps: Option<usize>,
-	has_scale_input_been_called: bool
+	schedule_cache: ScheduleCache<(Array1<f32>, Array1<f32>)>,
+	has_scale_input_been_called: bool,
+	karras_sigmas: bool
 }
 
 impl Default for EulerAncestralDiscreteScheduler {
@@ -71,33 +76,14 @@ impl EulerAncestralDiscreteScheduler {
 			anyhow::bail!("beta_start must be < beta_end");
 		}
 
-		let betas = match beta_schedule {
-			BetaSchedule::TrainedBetas(betas) => betas.clone(),
-			BetaSchedule::Linear => Array1::linspace(beta_start, beta_end, num_train_timesteps),
-			BetaSchedule::ScaledLinear => {
-				let mut betas = Array1::linspace(beta_start.sqrt(), beta_end.sqrt(), num_train_timesteps);
-				betas.par_map_inplace(|f| *f = f.powi(2));
-				betas
-			}
-			_ => anyhow::bail!("{beta_schedule:?} not implemented for EulerAncestralDiscreteScheduler")
-		};
-
-		let alphas = 1.0 - betas;
-
-		let alphas_cumprod = alphas
-			.view()
-			.into_iter()
-			.scan(1.0, |prod, alpha| {
-				*prod *= *alpha;
-				Some(*prod)
-			})
-			.collect::<Array1<_>>();
-
-		let mut sigmas = alphas_cumprod.clone();
-		sigmas.par_map_inplace(|f| {
-			*f = ((1.0 - *f) / *f).sqrt();
-		});
-		sigmas = concatenate![Axis(0), sigmas.slice(s![..;-1]), Array1::zeros(1,)];
+		if matches!(beta_schedule, BetaSchedule::SquaredcosCapV2) {
+			anyhow::bail!("{beta_schedule:?} not implemented for EulerAncestralDiscreteScheduler");
+		}
+		let schedule = TrainingSchedule::new(num_train_timesteps, beta_start, beta_end, beta_schedule)
+			.ok_or_else(|| anyhow!("{beta_schedule:?} not implemented for EulerAncestralDiscreteScheduler"))?;
+
+		let train_sigmas = to_f32(&schedule.sigmas());
+		let sigmas = concatenate![Axis(0), train_sigmas.slice(s![..;-1]), Array1::zeros(1,)];
 
 		let timesteps = Array1::linspace(num_train_timesteps as f32 - 1.0, 0.0, num_train_timesteps);
 
@@ -108,15 +94,30 @@ impl EulerAncestralDiscreteScheduler {
 			.ok_or_else(|| anyhow!("init_noise_sigma could not be reduced from sigmas - this should never happen"))?;
 
 		Ok(Self {
-			alphas_cumprod,
+			train_sigmas,
 			sigmas,
 			init_noise_sigma,
 			timesteps,
 			num_inference_steps: None,
 			num_train_timesteps,
-			has_scale_input_been_called: false
+			schedule_cache: ScheduleCache::default(),
+			has_scale_input_been_called: false,
+			karras_sigmas: false
 		})
 	}
+
+	/// Sets whether [`set_timesteps`](DiffusionScheduler::set_timesteps) uses the noise schedule of
+	/// [Karras et al. (2022)](https://arxiv.org/abs/2206.00364) (with `rho = 7`) between the smallest & largest
+	/// training sigmas, which spends more steps at low noise levels than the default schedule. Disabled by default.
+	///
+	/// The timesteps are then interpolated back from the Karras sigmas, like with
+	/// [`set_sigmas`](DiffusionScheduler::set_sigmas), rather than being evenly spaced; the initial noise sigma is still
+	/// the largest training sigma.
+	pub fn with_karras_sigmas(mut self, karras_sigmas: bool) -> Self {
+		self.karras_sigmas = karras_sigmas;
+		self.schedule_cache = ScheduleCache::default();
+		self
+	}
 }
 
 impl DiffusionScheduler for EulerAncestralDiscreteScheduler {
@@ -150,25 +151,40 @@ impl DiffusionScheduler for EulerAncestralDiscreteScheduler {
 
 	fn set_timesteps(&mut self, num_inference_steps: usize) {
 		self.num_inference_steps = Some(num_inference_steps);
+		// reset the initial sigma, which may have been changed by `set_sigmas`
+		self.init_noise_sigma = self.train_sigmas.fold(0.0, |a, &b| a.max(b));
+
+		let (num_train_timesteps, train_sigmas, karras_sigmas) = (self.num_train_timesteps, &self.train_sigmas, self.karras_sigmas);
+		let (timesteps, sigmas) = self.schedule_cache.get_or_insert_with(num_inference_steps, || {
+			if karras_sigmas {
+				return karras_schedule(train_sigmas, num_inference_steps);
+			}
+
+			let timesteps = Array1::linspace(num_train_timesteps as f32 - 1.0, 0.0, num_inference_steps);
 
-		let timesteps = Array1::linspace(self.num_train_timesteps as f32 - 1.0, 0.0, num_inference_steps);
+			let sigmas_xa = Array1::range(0.0, train_sigmas.len() as f32, 1.0);
+			let mut interpolator = LinearInterpolatorAccelerated::new(sigmas_xa.view(), train_sigmas.view());
+			let n_timesteps = timesteps.len();
+			let mut sigmas_int = Array1::zeros((n_timesteps + 1,));
+			for (i, x) in timesteps.iter().enumerate() {
+				sigmas_int[i] = interpolator.eval(*x);
+			}
+			sigmas_int[n_timesteps] = 0.0;
 
-		let mut sigmas = self.alphas_cumprod.clone();
-		sigmas.par_map_inplace(|f| {
-			*f = ((1.0 - *f) / *f).sqrt();
+			(timesteps, sigmas_int)
 		});
 
-		let sigmas_xa = Array1::range(0.0, sigmas.len() as f32, 1.0);
-		let mut interpolator = LinearInterpolatorAccelerated::new(sigmas_xa.view(), sigmas.view());
-		let n_timesteps = timesteps.len();
-		let mut sigmas_int = Array1::zeros((n_timesteps + 1,));
-		for (i, x) in timesteps.iter().enumerate() {
-			sigmas_int[i] = interpolator.eval(*x);
-		}
-		sigmas_int[n_timesteps] = 0.0;
+		self.sigmas = sigmas;
+		self.timesteps = timesteps;
+	}
 
-		self.sigmas = sigmas_int;
+	fn set_sigmas(&mut self, sigmas: &[f32]) -> anyhow::Result<()> {
+		let (timesteps, sigmas) = custom_sigma_schedule(&self.train_sigmas, sigmas)?;
+		self.num_inference_steps = Some(timesteps.len());
+		self.init_noise_sigma = sigmas[0];
+		self.sigmas = sigmas;
 		self.timesteps = timesteps;
+		Ok(())
 	}
 
 	fn step<R: Rng + ?Sized>(&mut self, model_output: ArrayView4<'_, f32>, timestep: f32, sample: ArrayView4<'_, f32>, rng: &mut R) -> SchedulerStepOutput {
@@ -234,6 +250,10 @@ impl DiffusionScheduler for EulerAncestralDiscreteScheduler {
 		self.timesteps.view()
 	}
 
+	fn sigma(&self, index: usize) -> Option<f32> {
+		if index < self.timesteps.len() { self.sigmas.get(index).copied() } else { None }
+	}
+
 	fn init_noise_sigma(&self) -> f32 {
 		self.init_noise_sigma
 	}
@@ -17,7 +17,10 @@ use ndarray::{concatenate, s, Array1, Array4, ArrayView4, Axis, Zip};
 use ndarray_rand::{rand::Rng, rand_distr::StandardNormal, RandomExt};
 
 use crate::{
-	schedulers::{betas_for_alpha_bar, BetaSchedule, DiffusionScheduler, SchedulerStepOutput},
+	schedulers::{
+		schedule::{custom_sigma_schedule, diffusers_linspace_schedule, diffusers_train_sigmas, karras_schedule, to_f32, ScheduleCache, TrainingSchedule},
+		BetaSchedule, DiffusersParity, DiffusionScheduler, SchedulerStepOutput
+	},
 	util::interpolation::LinearInterpolatorAccelerated,
 	SchedulerOptimizedDefaults
 };
@@ -29,13 +32,19 @@ use crate::{
 /// [kd]: https://github.com/crowsonkb/k-diffusion/blob/481677d114f6ea445aa009cf5bd7a9cdee909e47/k_diffusion/sampling.py#L51
 #[derive(Clone)]
 pub struct EulerDiscreteScheduler {
-	alphas_cumprod: Array1<f32>,
+	train_sigmas: Array1<f32>,
 	sigmas: Array1<f32>,
 	init_noise_sigma: f32,
 	timesteps: Array1<f32>,
 	num_train_timesteps: usize,
 	num_inference_steps: Option<usize>,
-	has_scale_input_been_called: bool
+	schedule_cache: ScheduleCache<(Array1<f32>, Array1<f32>)>,
+	has_scale_input_been_called: bool,
+	karras_sigmas: bool,
+	parity: DiffusersParity,
+	beta_start: f32,
+	beta_end: f32,
+	beta_schedule: BetaSchedule
 }
 
 impl Default for EulerDiscreteScheduler {
@@ -71,34 +80,11 @@ impl EulerDiscreteScheduler {
 			anyhow::bail!("beta_start must be < beta_end");
 		}
 
-		let betas = match beta_schedule {
-			BetaSchedule::TrainedBetas(betas) => betas.clone(),
-			BetaSchedule::Linear => Array1::linspace(beta_start, beta_end, num_train_timesteps),
-			BetaSchedule::ScaledLinear => {
-				let mut betas = Array1::linspace(beta_start.sqrt(), beta_end.sqrt(), num_train_timesteps);
-				betas.par_map_inplace(|f| *f = f.powi(2));
-				betas
-			}
-			BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(num_train_timesteps, 0.999),
-			_ => anyhow::bail!("{beta_schedule:?} not implemented for EulerDiscreteScheduler")
-		};
-
-		let alphas = 1.0 - betas;
+		let schedule = TrainingSchedule::new(num_train_timesteps, beta_start, beta_end, beta_schedule)
+			.ok_or_else(|| anyhow!("{beta_schedule:?} not implemented for EulerDiscreteScheduler"))?;
 
-		let alphas_cumprod = alphas
-			.view()
-			.into_iter()
-			.scan(1.0, |prod, alpha| {
-				*prod *= *alpha;
-				Some(*prod)
-			})
-			.collect::<Array1<_>>();
-
-		let mut sigmas = alphas_cumprod.clone();
-		sigmas.par_map_inplace(|f| {
-			*f = ((1.0 - *f) / *f).sqrt();
-		});
-		sigmas = concatenate![Axis(0), sigmas.slice(s![..;-1]), Array1::zeros(1,)];
+		let train_sigmas = to_f32(&schedule.sigmas());
+		let sigmas = concatenate![Axis(0), train_sigmas.slice(s![..;-1]), Array1::zeros(1,)];
 
 		let timesteps = Array1::linspace(num_train_timesteps as f32 - 1.0, 0.0, num_train_timesteps);
 
@@ -109,15 +95,66 @@ impl EulerDiscreteScheduler {
 			.ok_or_else(|| anyhow!("init_noise_sigma could not be reduced from sigmas - this should never happen"))?;
 
 		Ok(Self {
-			alphas_cumprod,
+			train_sigmas,
 			sigmas,
 			init_noise_sigma,
 			timesteps,
 			num_inference_steps: None,
 			num_train_timesteps,
-			has_scale_input_been_called: false
+			schedule_cache: ScheduleCache::default(),
+			has_scale_input_been_called: false,
+			karras_sigmas: false,
+			parity: DiffusersParity::Native,
+			beta_start,
+			beta_end,
+			beta_schedule: beta_schedule.clone()
 		})
 	}
+
+	/// Sets how exactly the scheduler reproduces the arithmetic of Python diffusers' `EulerDiscreteScheduler` (with its
+	/// default `linspace` timestep spacing & linear sigma interpolation). Defaults to [`DiffusersParity::Native`].
+	///
+	/// Both compute timesteps & sigmas from the training schedule the same way; they only differ in precision:
+	/// - diffusers computes the training sigmas from `float32` tensors, while [`DiffusersParity::Native`] computes them in
+	///   `f64` & rounds them to `f32`, so sigmas near `t = 0` differ by up to ~1e-5.
+	/// - diffusers computes timesteps in `float64` from `0` upwards & interpolates sigmas in `float64` with `np.interp`,
+	///   while [`DiffusersParity::Native`] computes both in `f32` from `num_train_timesteps - 1` downwards, which differs
+	///   for step counts that don't divide `num_train_timesteps - 1` evenly. With a single step, diffusers' only
+	///   timestep is `0`, where [`DiffusersParity::Native`] takes `num_train_timesteps - 1`.
+	///
+	/// Everything else already matches exactly: both append a final sigma of `0.0`, take the initial noise sigma from
+	/// the largest sigma, never interpolate outside of the training timesteps (`np.interp` would clamp to the first &
+	/// last sigma), and compute `sample / (sigma**2 + 1) ** 0.5` & the Euler step in `float32`. With
+	/// [`DiffusersParity::Exact`], timesteps & sigmas are computed like diffusers does.
+	///
+	/// [`set_sigmas`](DiffusionScheduler::set_sigmas) takes the given sigmas as-is either way.
+	pub fn with_parity(mut self, parity: DiffusersParity) -> Self {
+		let (num_train_timesteps, beta_start, beta_end, beta_schedule) = (self.num_train_timesteps, self.beta_start, self.beta_end, &self.beta_schedule);
+		self.train_sigmas = match parity {
+			DiffusersParity::Native => TrainingSchedule::new(num_train_timesteps, beta_start, beta_end, beta_schedule).map(|s| to_f32(&s.sigmas())),
+			DiffusersParity::Exact => diffusers_train_sigmas(num_train_timesteps, beta_start, beta_end, beta_schedule)
+		}
+		.expect("the beta schedule was validated by `new`");
+		self.init_noise_sigma = self.train_sigmas.fold(0.0, |a, &b| a.max(b));
+		self.sigmas = concatenate![Axis(0), self.train_sigmas.slice(s![..;-1]), Array1::zeros(1,)];
+		self.schedule_cache = ScheduleCache::default();
+		self.parity = parity;
+		self
+	}
+
+	/// Sets whether [`set_timesteps`](DiffusionScheduler::set_timesteps) uses the noise schedule of
+	/// [Karras et al. (2022)](https://arxiv.org/abs/2206.00364) (with `rho = 7`) between the smallest & largest
+	/// training sigmas, which spends more steps at low noise levels than the default schedule, and which many
+	/// community models are tuned for. Disabled by default.
+	///
+	/// The timesteps are then interpolated back from the Karras sigmas, like with
+	/// [`set_sigmas`](DiffusionScheduler::set_sigmas), rather than being evenly spaced; the initial noise sigma is still
+	/// the largest training sigma. This takes precedence over [`DiffusersParity::Exact`]'s timestep spacing.
+	pub fn with_karras_sigmas(mut self, karras_sigmas: bool) -> Self {
+		self.karras_sigmas = karras_sigmas;
+		self.schedule_cache = ScheduleCache::default();
+		self
+	}
 }
 
 impl DiffusionScheduler for EulerDiscreteScheduler {
@@ -151,25 +188,47 @@ impl DiffusionScheduler for EulerDiscreteScheduler {
 
 	fn set_timesteps(&mut self, num_inference_steps: usize) {
 		self.num_inference_steps = Some(num_inference_steps);
+		// reset the initial sigma, which may have been changed by `set_sigmas`
+		self.init_noise_sigma = self.train_sigmas.fold(0.0, |a, &b| a.max(b));
+
+		let (num_train_timesteps, train_sigmas, parity, karras_sigmas) = (self.num_train_timesteps, &self.train_sigmas, self.parity, self.karras_sigmas);
+		let (timesteps, sigmas) = self.schedule_cache.get_or_insert_with(num_inference_steps, || {
+			if karras_sigmas {
+				return karras_schedule(train_sigmas, num_inference_steps);
+			}
+			if parity == DiffusersParity::Exact {
+				return diffusers_linspace_schedule(train_sigmas, num_inference_steps);
+			}
 
-		let timesteps = Array1::linspace(self.num_train_timesteps as f32 - 1.0, 0.0, num_inference_steps);
+			let timesteps = Array1::linspace(num_train_timesteps as f32 - 1.0, 0.0, num_inference_steps);
 
-		let mut sigmas = self.alphas_cumprod.clone();
-		sigmas.par_map_inplace(|f| {
-			*f = ((1.0 - *f) / *f).sqrt();
+			let sigmas_xa = Array1::range(0.0, train_sigmas.len() as f32, 1.0);
+			let mut interpolator = LinearInterpolatorAccelerated::new(sigmas_xa.view(), train_sigmas.view());
+			let n_timesteps = timesteps.len();
+			let mut sigmas_int = Array1::zeros((n_timesteps + 1,));
+			for (i, x) in timesteps.iter().enumerate() {
+				sigmas_int[i] = interpolator.eval(*x);
+			}
+			sigmas_int[n_timesteps] = 0.0;
+
+			(timesteps, sigmas_int)
 		});
 
-		let sigmas_xa = Array1::range(0.0, sigmas.len() as f32, 1.0);
-		let mut interpolator = LinearInterpolatorAccelerated::new(sigmas_xa.view(), sigmas.view());
-		let n_timesteps = timesteps.len();
-		let mut sigmas_int = Array1::zeros((n_timesteps + 1,));
-		for (i, x) in timesteps.iter().enumerate() {
-			sigmas_int[i] = interpolator.eval(*x);
+		if self.parity == DiffusersParity::Exact && !self.karras_sigmas {
+			// diffusers takes the initial noise sigma from the inference sigmas, which only differs with a single step
+			self.init_noise_sigma = sigmas.fold(0.0, |a, &b| a.max(b));
 		}
-		sigmas_int[n_timesteps] = 0.0;
+		self.sigmas = sigmas;
+		self.timesteps = timesteps;
+	}
 
-		self.sigmas = sigmas_int;
+	fn set_sigmas(&mut self, sigmas: &[f32]) -> anyhow::Result<()> {
+		let (timesteps, sigmas) = custom_sigma_schedule(&self.train_sigmas, sigmas)?;
+		self.num_inference_steps = Some(timesteps.len());
+		self.init_noise_sigma = sigmas[0];
+		self.sigmas = sigmas;
 		self.timesteps = timesteps;
+		Ok(())
 	}
 
 	fn step<R: Rng + ?Sized>(&mut self, model_output: ArrayView4<'_, f32>, timestep: f32, sample: ArrayView4<'_, f32>, rng: &mut R) -> SchedulerStepOutput {
@@ -244,6 +303,10 @@ impl DiffusionScheduler for EulerDiscreteScheduler {
 		self.timesteps.view()
 	}
 
+	fn sigma(&self, index: usize) -> Option<f32> {
+		if index < self.timesteps.len() { self.sigmas.get(index).copied() } else { None }
+	}
+
 	fn init_noise_sigma(&self) -> f32 {
 		self.init_noise_sigma
 	}
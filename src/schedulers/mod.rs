@@ -31,6 +31,10 @@ use ndarray::{Array1, Array4, ArrayBase, ArrayView1, ArrayView4};
 use ndarray_rand::rand::Rng;
 use num_traits::ToPrimitive;
 
+mod schedule;
+
+pub(crate) use self::schedule::validate_custom_sigmas;
+
 cfg_if::cfg_if! {
 	if #[cfg(feature = "scheduler-euler")] {
 		mod euler_discrete;
@@ -78,6 +82,12 @@ pub(crate) fn betas_for_alpha_bar(num_diffusion_timesteps: usize, max_beta: f32)
 	Array1::from_vec(betas)
 }
 
+/// Converts the cumulative product of alphas at `timestep` to the noise level in the variance-exploding
+/// parameterization used by the Euler schedulers, `sqrt((1 - alpha_bar) / alpha_bar)`.
+pub(crate) fn alpha_bar_sigma(alphas_cumprod: &Array1<f32>, timestep: usize) -> Option<f32> {
+	alphas_cumprod.get(timestep).map(|alpha_bar| ((1.0 - alpha_bar) / alpha_bar).sqrt())
+}
+
 /// A mapping from a beta range to a sequence of betas for stepping the model.
 #[derive(Debug, Clone, PartialEq)]
 pub enum BetaSchedule {
@@ -149,13 +159,42 @@ impl SchedulerStepOutput {
 	}
 }
 
+/// How exactly a scheduler reproduces the arithmetic of the Python
+/// [diffusers](https://github.com/huggingface/diffusers) library; see
+/// [`EulerDiscreteScheduler::with_parity`](crate::EulerDiscreteScheduler::with_parity).
+///
+/// Both compute the same schedule, but in a different precision: by default, training tables are computed in `f64`
+/// & timesteps interpolated in `f32`, which is more accurate but not bit-identical to diffusers. Sigmas differ by up
+/// to ~1e-5, which compounds over the steps of a generation into visible differences for the same initial noise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiffusersParity {
+	/// This crate's own arithmetic. Preserves the outputs of earlier versions.
+	#[default]
+	Native,
+	/// Reproduces diffusers' arithmetic as closely as possible, for outputs matching those of Python for the same
+	/// initial noise (see [`CompatibilityVersion`](crate::CompatibilityVersion) to also draw the same noise).
+	Exact
+}
+
+/// State accumulated by a scheduler's `step` function, beyond what
+/// [`set_timesteps`](DiffusionScheduler::set_timesteps) computes. See [`DiffusionScheduler::state`].
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct SchedulerState {
+	/// The history of (converted) model outputs kept by multistep schedulers, oldest first.
+	pub model_outputs: Vec<Array4<f32>>,
+	/// The number of lower-order warmup updates taken by multistep schedulers.
+	pub lower_order_nums: usize
+}
+
 /// A scheduler to be used in diffusion pipelines.
 #[allow(clippy::len_without_is_empty)]
 pub trait DiffusionScheduler: Default + Clone {
 	/// Scheduler timestep type.
 	type TimestepType: Copy + Clone + ToPrimitive;
 
-	/// Returns the scheduler order.
+	/// Returns the scheduler order, like diffusers' `scheduler.order`: the number of timesteps (& UNet evaluations) each
+	/// denoising step takes, e.g. `2` for schedulers which list every timestep twice. This is not the order of the
+	/// solver; multistep solvers like DPM-Solver++(2M) reuse previous model outputs & take one timestep per step.
 	fn order() -> usize;
 
 	/// Ensures interchangeability with schedulers that need to scale the denoising model input depending on the
@@ -181,14 +220,72 @@ pub trait DiffusionScheduler: Default + Clone {
 	// in both the Stable Diffusion img2img and inpaint pipelines, so this was simplified to a single float
 	fn add_noise(&mut self, original_samples: ArrayView4<'_, f32>, noise: ArrayView4<'_, f32>, timestep: Self::TimestepType) -> Array4<f32>;
 
+	/// Sets a custom noise schedule, e.g. one exported from another tool, instead of computing one with
+	/// [`set_timesteps`](DiffusionScheduler::set_timesteps). `sigmas` holds the sigma of each step, and must be strictly
+	/// decreasing; a final `0.0` may be included, and is otherwise appended. This replaces the schedule computed by
+	/// `set_timesteps` until it is called again, and also sets [`init_noise_sigma`](DiffusionScheduler::init_noise_sigma)
+	/// to the first sigma.
+	///
+	/// Only sigma-based schedulers (the Euler schedulers) support custom sigmas; the default implementation returns an
+	/// error.
+	fn set_sigmas(&mut self, sigmas: &[f32]) -> anyhow::Result<()> {
+		let _ = sigmas;
+		anyhow::bail!("this scheduler does not support custom sigmas")
+	}
+
 	/// Returns the computed scheduler timesteps.
 	fn timesteps(&self) -> ArrayView1<'_, Self::TimestepType>;
 
 	/// Returns the initial sigma noise value.
 	fn init_noise_sigma(&self) -> f32;
 
+	/// Returns the number of warmup steps for the timesteps computed by
+	/// [`set_timesteps`](DiffusionScheduler::set_timesteps) with `num_inference_steps`. Pipelines suppress callbacks
+	/// during warmup steps, and afterwards only invoke them every [`order`](DiffusionScheduler::order) steps.
+	///
+	/// The default implementation assumes the scheduler produces `num_inference_steps * order` timesteps, and treats
+	/// any additional timesteps as warmup steps. Schedulers returning a different number of timesteps, e.g.
+	/// `num_inference_steps + 1`, should override this.
+	fn num_warmup_steps(&self, num_inference_steps: usize) -> usize {
+		self.timesteps().len().saturating_sub(num_inference_steps * Self::order())
+	}
+
+	/// Returns the maximum number of inference steps [`set_timesteps`](DiffusionScheduler::set_timesteps) supports,
+	/// if any. Schedulers stepping through the training timesteps, e.g. DDPM, can't take more steps than they have
+	/// train timesteps, and clamp larger step counts to this.
+	fn max_inference_steps(&self) -> Option<usize> {
+		None
+	}
+
+	/// Returns the noise level (sigma) of the timestep at `index` in [`timesteps`](DiffusionScheduler::timesteps), or
+	/// `None` if the index is out of range or the scheduler doesn't expose its noise levels. Sigmas are given in the
+	/// variance-exploding parameterization of the Euler schedulers, `sqrt((1 - alpha_bar) / alpha_bar)`, so that they
+	/// can be compared between schedulers.
+	fn sigma(&self, index: usize) -> Option<f32> {
+		let _ = index;
+		None
+	}
+
 	/// Returns the number of train timesteps.
 	fn len(&self) -> usize;
+
+	/// Returns the state accumulated by `step` since the last call to
+	/// [`set_timesteps`](DiffusionScheduler::set_timesteps), e.g. to checkpoint a run and resume it later.
+	///
+	/// The position in the schedule is not part of the state, since schedulers locate the current step from the
+	/// timestep passed to `step`. The default implementation returns an empty state, which is correct for schedulers
+	/// whose `step` only depends on its arguments & the computed timesteps; multistep schedulers must override this to
+	/// include their history of model outputs.
+	fn state(&self) -> SchedulerState {
+		SchedulerState::default()
+	}
+
+	/// Restores state previously returned by [`state`](DiffusionScheduler::state). This must be called after
+	/// [`set_timesteps`](DiffusionScheduler::set_timesteps) with the same number of inference steps the state was
+	/// captured with, since `set_timesteps` resets the state.
+	fn restore_state(&mut self, state: SchedulerState) {
+		let _ = state;
+	}
 }
 
 /// Implements functions returning an instance of this scheduler with parameters optimized for certain models.
@@ -198,3 +295,269 @@ pub trait SchedulerOptimizedDefaults: DiffusionScheduler {
 	where
 		Self: Sized;
 }
+
+#[cfg(test)]
+mod tests {
+	use ndarray::{Array1, Array4, ArrayView1, ArrayView4};
+	use ndarray_rand::rand::Rng;
+
+	use super::{DiffusionScheduler, SchedulerStepOutput};
+
+	/// A scheduler producing `steps + extra` timesteps with the given order.
+	#[derive(Default, Clone)]
+	struct ExtraTimesteps<const ORDER: usize> {
+		extra: usize,
+		timesteps: Array1<f32>
+	}
+
+	impl<const ORDER: usize> DiffusionScheduler for ExtraTimesteps<ORDER> {
+		type TimestepType = f32;
+
+		fn order() -> usize {
+			ORDER
+		}
+
+		fn scale_model_input(&mut self, sample: ArrayView4<'_, f32>, _: f32) -> Array4<f32> {
+			sample.to_owned()
+		}
+
+		fn set_timesteps(&mut self, num_inference_steps: usize) {
+			self.timesteps = Array1::linspace(999.0, 0.0, num_inference_steps + self.extra);
+		}
+
+		fn step<R: Rng + ?Sized>(&mut self, _: ArrayView4<'_, f32>, _: f32, sample: ArrayView4<'_, f32>, _: &mut R) -> SchedulerStepOutput {
+			SchedulerStepOutput {
+				prev_sample: sample.to_owned(),
+				..Default::default()
+			}
+		}
+
+		fn add_noise(&mut self, original_samples: ArrayView4<'_, f32>, _: ArrayView4<'_, f32>, _: f32) -> Array4<f32> {
+			original_samples.to_owned()
+		}
+
+		fn timesteps(&self) -> ArrayView1<'_, f32> {
+			self.timesteps.view()
+		}
+
+		fn init_noise_sigma(&self) -> f32 {
+			1.0
+		}
+
+		fn len(&self) -> usize {
+			1000
+		}
+	}
+
+	#[test]
+	fn default_num_warmup_steps() {
+		let mut scheduler = ExtraTimesteps::<1> { extra: 0, ..Default::default() };
+		scheduler.set_timesteps(20);
+		assert_eq!(scheduler.num_warmup_steps(20), 0);
+
+		let mut scheduler = ExtraTimesteps::<1> { extra: 1, ..Default::default() };
+		scheduler.set_timesteps(20);
+		assert_eq!(scheduler.num_warmup_steps(20), 1);
+
+		// fewer timesteps than `steps * order` must not underflow
+		let mut scheduler = ExtraTimesteps::<2> { extra: 1, ..Default::default() };
+		scheduler.set_timesteps(20);
+		assert_eq!(scheduler.num_warmup_steps(20), 0);
+	}
+
+	#[test]
+	#[cfg(feature = "scheduler-ddim")]
+	fn ddim_clip_sample_range() {
+		use ndarray_rand::rand::{rngs::StdRng, SeedableRng};
+
+		use crate::{BetaSchedule, DDIMScheduler, DDIMSchedulerConfig, SchedulerPredictionType};
+
+		let pred_original_sample = |config: DDIMSchedulerConfig| {
+			let mut scheduler = DDIMScheduler::new(1000, 0.00085, 0.012, &BetaSchedule::ScaledLinear, &SchedulerPredictionType::Sample, Some(config)).unwrap();
+			scheduler.set_timesteps(10);
+			let model_output = Array4::from_shape_fn((1, 4, 2, 2), |(_, c, _, _)| c as f32 - 1.5);
+			let timestep = scheduler.timesteps()[0];
+			let output = scheduler.step(model_output.view(), timestep, Array4::zeros((1, 4, 2, 2)).view(), &mut StdRng::seed_from_u64(0));
+			output.pred_original_sample.unwrap().iter().copied().collect::<Vec<_>>()
+		};
+
+		let unclipped = pred_original_sample(DDIMSchedulerConfig { clip_sample: false, ..Default::default() });
+		assert_eq!(unclipped.iter().copied().fold(f32::MIN, f32::max), 1.5);
+		assert!(pred_original_sample(DDIMSchedulerConfig::default()).iter().all(|x| (-1.0..=1.0).contains(x)));
+		let clipped = pred_original_sample(DDIMSchedulerConfig { clip_sample_range: 0.75, ..Default::default() });
+		assert!(clipped.iter().all(|x| (-0.75..=0.75).contains(x)));
+		assert!(clipped.contains(&0.5) && clipped.contains(&-0.75));
+	}
+
+	#[test]
+	#[cfg(feature = "scheduler-dpm-solver")]
+	fn dpm_solver_multistep_history() {
+		use ndarray_rand::rand::{rngs::StdRng, SeedableRng};
+
+		use crate::{BetaSchedule, DPMSolverMultistepScheduler, DPMSolverMultistepSchedulerConfig, SchedulerOptimizedDefaults, SchedulerPredictionType};
+
+		let first_order = DPMSolverMultistepSchedulerConfig { solver_order: 1, ..Default::default() };
+		let mut first_order =
+			DPMSolverMultistepScheduler::new(1000, 0.00085, 0.012, &BetaSchedule::ScaledLinear, &SchedulerPredictionType::Epsilon, Some(first_order)).unwrap();
+		let mut second_order = DPMSolverMultistepScheduler::stable_diffusion_v1_optimized_default().unwrap();
+		first_order.set_timesteps(20);
+		second_order.set_timesteps(20);
+
+		// the samples after the first 3 steps from a sample of ones, with an epsilon prediction of `0.1 * step`, computed
+		// in float64 with DPM-Solver++(2M)'s midpoint update (`x_t = sigma_t / sigma_s0 * x - alpha_t * (e^-h - 1) *
+		// (D0 + D1 / 2)`, Lu et al. 2022, as implemented by diffusers) on timesteps 999, 946, 893 & 841
+		const DPM_2M_SAMPLES: [f32; 3] = [1.3604159, 1.7584273, 2.1969143];
+
+		let mut rng = StdRng::seed_from_u64(0);
+		let (mut first_sample, mut second_sample) = (Array4::<f32>::ones((1, 4, 2, 2)), Array4::<f32>::ones((1, 4, 2, 2)));
+		for (i, &t) in second_order.timesteps().to_owned().iter().enumerate() {
+			let model_output = Array4::from_elem((1, 4, 2, 2), i as f32 * 0.1);
+			first_sample = first_order.step(model_output.view(), t, first_sample.view(), &mut rng).prev_sample;
+			second_sample = second_order.step(model_output.view(), t, second_sample.view(), &mut rng).prev_sample;
+			if i == 0 {
+				// without a previous model output, the first step is a first-order update
+				assert_eq!(first_sample, second_sample);
+			} else if i == 1 {
+				assert_ne!(first_sample, second_sample);
+			}
+			if let Some(&expected) = DPM_2M_SAMPLES.get(i) {
+				assert!(second_sample.iter().all(|x| (x - expected).abs() <= 1e-4 * expected), "step {i}: {second_sample:?} != {expected}");
+			}
+			let state = second_order.state();
+			assert_eq!(state.model_outputs.len(), (i + 1).min(2));
+			assert_eq!(state.lower_order_nums, (i + 1).min(2));
+		}
+		assert!(second_sample.iter().all(|x| x.is_finite()));
+	}
+
+	#[test]
+	#[cfg(feature = "scheduler-euler")]
+	fn custom_sigmas_reproduce_schedule() {
+		use super::schedule::{to_f32, TrainingSchedule};
+		use crate::{BetaSchedule, EulerDiscreteScheduler, SchedulerOptimizedDefaults};
+
+		let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default().unwrap();
+		scheduler.set_timesteps(4);
+		let (timesteps, init_noise_sigma) = (scheduler.timesteps().to_owned(), scheduler.init_noise_sigma());
+		// the 4 step schedule's timesteps are 999, 666, 333, & 0, so its sigmas are exactly the training sigmas there
+		let train_sigmas = to_f32(&TrainingSchedule::new(1000, 0.00085, 0.012, &BetaSchedule::ScaledLinear).unwrap().sigmas());
+		let sigmas = [999, 666, 333, 0].map(|t| train_sigmas[t]);
+
+		scheduler.set_sigmas(&sigmas).unwrap();
+		assert_eq!(scheduler.timesteps().len(), 4);
+		for (custom, computed) in scheduler.timesteps().iter().zip(timesteps.iter()) {
+			assert!((custom - computed).abs() < 1e-2, "{custom} != {computed}");
+		}
+		assert_eq!(scheduler.init_noise_sigma(), sigmas[0]);
+
+		scheduler.set_sigmas(&[train_sigmas[800], train_sigmas[400], 0.0]).unwrap();
+		assert_eq!(scheduler.timesteps().len(), 2);
+		assert_eq!(scheduler.init_noise_sigma(), train_sigmas[800]);
+		// `set_timesteps` restores the initial sigma
+		scheduler.set_timesteps(4);
+		assert_eq!(scheduler.init_noise_sigma(), init_noise_sigma);
+
+		assert!(scheduler.set_sigmas(&[1.0, 2.0]).is_err());
+		// outside of the training schedule's range
+		assert!(scheduler.set_sigmas(&[100.0, 1.0]).is_err());
+	}
+
+	#[test]
+	#[cfg(feature = "scheduler-euler")]
+	fn karras_sigmas_remap_schedule() {
+		use super::schedule::{karras_sigmas, to_f32, TrainingSchedule};
+		use crate::{BetaSchedule, EulerDiscreteScheduler, SchedulerOptimizedDefaults};
+
+		let train_sigmas = to_f32(&TrainingSchedule::new(1000, 0.00085, 0.012, &BetaSchedule::ScaledLinear).unwrap().sigmas());
+		let mut linear = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default().unwrap();
+		// a cached linear schedule must not be reused once Karras sigmas are enabled
+		linear.set_timesteps(10);
+		let mut scheduler = linear.clone().with_karras_sigmas(true);
+		scheduler.set_timesteps(10);
+
+		let karras = karras_sigmas(train_sigmas[0], train_sigmas[999], 10);
+		for (i, &sigma) in karras.iter().enumerate() {
+			assert_eq!(scheduler.sigma(i), Some(sigma));
+		}
+		assert_eq!(scheduler.init_noise_sigma(), train_sigmas[999]);
+		assert_eq!(scheduler.init_noise_sigma(), linear.init_noise_sigma());
+
+		// timesteps follow the sigmas, so they aren't evenly spaced
+		let timesteps = scheduler.timesteps().to_owned();
+		assert!((timesteps[0] - 999.0).abs() < 1e-2 && timesteps[9].abs() < 1e-2);
+		assert!(timesteps[5] < linear.timesteps()[5] - 50.0, "{} vs. {}", timesteps[5], linear.timesteps()[5]);
+		// ...and map back to the Karras sigmas like custom sigmas do
+		let mut custom = linear.clone();
+		custom.set_sigmas(&karras).unwrap();
+		for (karras, custom) in timesteps.iter().zip(custom.timesteps().iter()) {
+			assert!((karras - custom).abs() < 1e-3, "{karras} != {custom}");
+		}
+	}
+
+	#[test]
+	#[cfg(feature = "scheduler-dpm-solver")]
+	fn dpm_solver_multistep_karras_timesteps() {
+		use crate::{DPMSolverMultistepScheduler, SchedulerOptimizedDefaults};
+
+		let mut linear = DPMSolverMultistepScheduler::stable_diffusion_v1_optimized_default().unwrap();
+		let mut karras = linear.clone().with_karras_sigmas(true);
+		karras.set_timesteps(10);
+		linear.set_timesteps(10);
+		assert_eq!(karras.timesteps().len(), 10);
+		assert_eq!((karras.timesteps()[0], karras.timesteps()[9]), (999, 0));
+		assert!(karras.timesteps()[5] < linear.timesteps()[5]);
+		assert_eq!(karras.init_noise_sigma(), linear.init_noise_sigma());
+
+		// with more steps than distinct rounded timesteps near `t = 0`, duplicates are dropped
+		karras.set_timesteps(200);
+		let timesteps = karras.timesteps();
+		assert!(timesteps.len() < 200);
+		assert!(timesteps.iter().zip(timesteps.iter().skip(1)).all(|(a, b)| b < a));
+	}
+
+	#[test]
+	#[cfg(all(feature = "scheduler-ddim", feature = "scheduler-dpm-solver"))]
+	fn cached_timesteps_match_recomputed() {
+		use crate::{DDIMScheduler, DPMSolverMultistepScheduler, SchedulerOptimizedDefaults};
+
+		fn check<S: DiffusionScheduler<TimestepType = usize> + Clone>(fresh: S) {
+			let mut scheduler = fresh.clone();
+			for steps in [10, 25, 10, 4, 25] {
+				scheduler.set_timesteps(steps);
+				let mut recomputed = fresh.clone();
+				recomputed.set_timesteps(steps);
+				assert_eq!(scheduler.timesteps(), recomputed.timesteps(), "{steps} steps");
+			}
+		}
+
+		check(DDIMScheduler::stable_diffusion_v1_optimized_default().unwrap());
+		check(DPMSolverMultistepScheduler::stable_diffusion_v1_optimized_default().unwrap());
+		check(DPMSolverMultistepScheduler::stable_diffusion_v1_optimized_default().unwrap().with_karras_sigmas(true));
+	}
+
+	#[test]
+	#[cfg(all(feature = "scheduler-ddim", feature = "scheduler-euler"))]
+	fn sigmas_are_comparable() {
+		use super::schedule::{to_f32, TrainingSchedule};
+		use crate::{BetaSchedule, DDIMScheduler, EulerDiscreteScheduler, SchedulerOptimizedDefaults};
+
+		let train_sigmas = to_f32(&TrainingSchedule::new(1000, 0.00085, 0.012, &BetaSchedule::ScaledLinear).unwrap().sigmas());
+		let mut euler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default().unwrap();
+		euler.set_timesteps(4);
+		let close = |sigma: f32, t: usize| assert!((sigma - train_sigmas[t]).abs() <= train_sigmas[t] * 1e-4, "{sigma} != {}", train_sigmas[t]);
+		for (i, t) in [999, 666, 333, 0].into_iter().enumerate() {
+			close(euler.sigma(i).unwrap(), t);
+		}
+		// the final sigma of 0 reached by the last step has no timestep
+		assert_eq!(euler.sigma(4), None);
+
+		// alpha-based schedulers report the same noise level at the same timestep
+		let mut ddim = DDIMScheduler::stable_diffusion_v1_optimized_default().unwrap();
+		ddim.set_timesteps(10);
+		assert_eq!(ddim.timesteps().len(), 10);
+		for (i, &t) in ddim.timesteps().iter().enumerate() {
+			close(ddim.sigma(i).unwrap(), t);
+		}
+		assert_eq!(ddim.sigma(10), None);
+	}
+}
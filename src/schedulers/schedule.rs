@@ -0,0 +1,388 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Noise schedule tables shared by the schedulers.
+
+use ndarray::Array1;
+
+use super::{betas_for_alpha_bar, BetaSchedule};
+use crate::util::interpolation::LinearInterpolatorAccelerated;
+
+/// The `rho` of the [Karras et al. (2022)](https://arxiv.org/abs/2206.00364) noise schedule, as recommended by the
+/// paper & used by k-diffusion.
+const KARRAS_RHO: f32 = 7.0;
+
+/// The number of [`set_timesteps`](super::DiffusionScheduler::set_timesteps) results kept by a [`ScheduleCache`].
+pub(crate) const SCHEDULE_CACHE_CAPACITY: usize = 8;
+
+/// The training noise schedule of a scheduler, precomputed once when the scheduler is constructed.
+///
+/// Tables are computed in `f64`, since `alphas_cumprod` is a product over every training timestep and accumulates
+/// rounding error in `f32`. Schedulers convert them to `f32` with [`to_f32`] where they are used.
+#[derive(Debug, Clone)]
+pub(crate) struct TrainingSchedule {
+	// only read by the DDPM scheduler
+	#[allow(dead_code)]
+	pub(crate) betas: Array1<f64>,
+	pub(crate) alphas_cumprod: Array1<f64>
+}
+
+impl TrainingSchedule {
+	/// Computes the training schedule, or returns `None` if `beta_schedule` is not supported.
+	pub(crate) fn new(num_train_timesteps: usize, beta_start: f32, beta_end: f32, beta_schedule: &BetaSchedule) -> Option<Self> {
+		let (beta_start, beta_end) = (f64::from(beta_start), f64::from(beta_end));
+		let betas = match beta_schedule {
+			BetaSchedule::TrainedBetas(betas) => betas.mapv(f64::from),
+			BetaSchedule::Linear => Array1::linspace(beta_start, beta_end, num_train_timesteps),
+			BetaSchedule::ScaledLinear => Array1::linspace(beta_start.sqrt(), beta_end.sqrt(), num_train_timesteps).mapv(|f| f.powi(2)),
+			BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(num_train_timesteps, 0.999).mapv(f64::from),
+			BetaSchedule::Sigmoid => return None
+		};
+
+		let alphas_cumprod = betas
+			.iter()
+			.scan(1.0, |prod, beta| {
+				*prod *= 1.0 - *beta;
+				Some(*prod)
+			})
+			.collect::<Array1<_>>();
+
+		Some(Self { betas, alphas_cumprod })
+	}
+
+	/// Returns the k-diffusion sigma, `((1 - alphas_cumprod) / alphas_cumprod) ** 0.5`, of each training timestep.
+	pub(crate) fn sigmas(&self) -> Array1<f64> {
+		self.alphas_cumprod.mapv(|f| ((1.0 - f) / f).sqrt())
+	}
+}
+
+/// Computes the training sigmas the way Python diffusers does, for [`DiffusersParity::Exact`]: betas & `alphas_cumprod`
+/// are `float32` tensors, with `torch.linspace` interpolating from both ends in `float32` and `torch.cumprod`
+/// accumulating in `float64`, and the sigmas are computed from them in `float32`. Returns `None` if `beta_schedule` is
+/// not supported.
+///
+/// [`DiffusersParity::Exact`]: super::DiffusersParity::Exact
+pub(crate) fn diffusers_train_sigmas(num_train_timesteps: usize, beta_start: f32, beta_end: f32, beta_schedule: &BetaSchedule) -> Option<Array1<f32>> {
+	// Python reads the config's decimal value as a double, which isn't always the double closest to its `f32`
+	let as_python = |value: f32| value.to_string().parse::<f64>().unwrap_or(f64::from(value));
+	let betas = match beta_schedule {
+		BetaSchedule::TrainedBetas(betas) => betas.clone(),
+		BetaSchedule::Linear => torch_linspace(as_python(beta_start) as f32, as_python(beta_end) as f32, num_train_timesteps),
+		BetaSchedule::ScaledLinear => {
+			torch_linspace(as_python(beta_start).sqrt() as f32, as_python(beta_end).sqrt() as f32, num_train_timesteps).mapv(|f| f * f)
+		}
+		BetaSchedule::SquaredcosCapV2 => {
+			let alpha_bar = |t: f64| ((t + 0.008) / 1.008 * std::f64::consts::FRAC_PI_2).cos().powi(2);
+			let n = num_train_timesteps as f64;
+			Array1::from_shape_fn(num_train_timesteps, |i| (1.0 - alpha_bar((i + 1) as f64 / n) / alpha_bar(i as f64 / n)).min(0.999) as f32)
+		}
+		BetaSchedule::Sigmoid => return None
+	};
+
+	let alphas_cumprod = betas
+		.iter()
+		.scan(1.0_f64, |prod, beta| {
+			*prod *= f64::from(1.0 - *beta);
+			Some(*prod as f32)
+		})
+		.collect::<Array1<_>>();
+	Some(alphas_cumprod.mapv(|f| ((1.0 - f) / f).sqrt()))
+}
+
+/// `torch.linspace` for `float32`, which computes the first half of the values from `start` and the second half from
+/// `end`.
+fn torch_linspace(start: f32, end: f32, steps: usize) -> Array1<f32> {
+	if steps == 1 {
+		return Array1::from_elem(1, start);
+	}
+	let step = (end - start) / (steps - 1) as f32;
+	let halfway = steps / 2;
+	Array1::from_shape_fn(steps, |i| if i < halfway { start + step * i as f32 } else { end - step * (steps - i - 1) as f32 })
+}
+
+/// Computes the timesteps & sigmas (with a final `0.0` appended) of `num_inference_steps` steps the way diffusers'
+/// `linspace` timestep spacing does: timesteps are `np.linspace(0, num_train_timesteps - 1, num_inference_steps)`
+/// reversed, in `float64`, and sigmas are interpolated from `train_sigmas` with `np.interp`, also in `float64`.
+pub(crate) fn diffusers_linspace_schedule(train_sigmas: &Array1<f32>, num_inference_steps: usize) -> (Array1<f32>, Array1<f32>) {
+	let last = train_sigmas.len() - 1;
+	// `np.linspace` multiplies by the step, and sets the final value to the endpoint; with a single step, it is `[0]`
+	let step = last as f64 / (num_inference_steps.max(2) - 1) as f64;
+	let timesteps = (0..num_inference_steps)
+		.rev()
+		.map(|i| if i == num_inference_steps - 1 && i > 0 { last as f64 } else { i as f64 * step })
+		.collect::<Vec<_>>();
+
+	// `np.interp` over `xp = arange(len(train_sigmas))`, clamped to the first & last sigma outside of it
+	let interp = |x: f64| {
+		if x <= 0.0 {
+			f64::from(train_sigmas[0])
+		} else if x >= last as f64 {
+			f64::from(train_sigmas[last])
+		} else {
+			let j = x.floor() as usize;
+			let (y_l, y_h) = (f64::from(train_sigmas[j]), f64::from(train_sigmas[j + 1]));
+			(y_h - y_l) * (x - j as f64) + y_l
+		}
+	};
+	let mut sigmas = timesteps.iter().map(|&t| interp(t) as f32).collect::<Vec<_>>();
+	sigmas.push(0.0);
+	(timesteps.iter().map(|&t| t as f32).collect(), Array1::from_vec(sigmas))
+}
+
+/// Converts a precomputed `f64` table to `f32`.
+pub(crate) fn to_f32(values: &Array1<f64>) -> Array1<f32> {
+	values.mapv(|f| f as f32)
+}
+
+/// Validates a custom sigma schedule for [`set_sigmas`](super::DiffusionScheduler::set_sigmas): one sigma per step,
+/// finite, positive, & strictly decreasing, optionally followed by a final `0.0`. Returns the sigmas of the steps,
+/// without the final `0.0`.
+pub(crate) fn validate_custom_sigmas(sigmas: &[f32]) -> anyhow::Result<&[f32]> {
+	let sigmas = match sigmas.split_last() {
+		Some((&last, rest)) if last == 0.0 => rest,
+		_ => sigmas
+	};
+	if sigmas.is_empty() {
+		anyhow::bail!("custom sigmas must contain at least one positive sigma");
+	}
+	if let Some(sigma) = sigmas.iter().find(|sigma| !(sigma.is_finite() && **sigma > 0.0)) {
+		anyhow::bail!("custom sigmas must be finite & positive (except for an optional final 0.0), but got {sigma}");
+	}
+	if let Some(i) = sigmas.windows(2).position(|pair| pair[1] >= pair[0]) {
+		anyhow::bail!("custom sigmas must be strictly decreasing, but sigma #{} ({}) follows {}", i + 1, sigmas[i + 1], sigmas[i]);
+	}
+	Ok(sigmas)
+}
+
+/// Computes the timesteps & sigmas (with a final `0.0` appended) of a custom sigma schedule. Each sigma's timestep is
+/// found by inverting the linear interpolation `set_timesteps` uses to compute sigmas from timesteps, so passing the
+/// sigmas computed by `set_timesteps` reproduces its timesteps.
+pub(crate) fn custom_sigma_schedule(train_sigmas: &Array1<f32>, sigmas: &[f32]) -> anyhow::Result<(Array1<f32>, Array1<f32>)> {
+	let sigmas = validate_custom_sigmas(sigmas)?;
+	let (min_sigma, max_sigma) = (train_sigmas[0], train_sigmas[train_sigmas.len() - 1]);
+	if let Some(sigma) = sigmas.iter().find(|sigma| **sigma < min_sigma || **sigma > max_sigma) {
+		anyhow::bail!("custom sigma {sigma} is outside of the range of the training schedule's sigmas ({min_sigma} to {max_sigma})");
+	}
+
+	let timesteps = sigma_timesteps(train_sigmas, sigmas);
+	// schedulers locate the current step by its timestep, so timesteps must be distinct
+	if timesteps.iter().zip(timesteps.iter().skip(1)).any(|(a, b)| b >= a) {
+		anyhow::bail!("custom sigmas are too close together to be told apart by timestep");
+	}
+
+	let mut sigmas = sigmas.to_vec();
+	sigmas.push(0.0);
+	Ok((timesteps, Array1::from_vec(sigmas)))
+}
+
+/// Finds the timestep of each of `sigmas` by inverting the linear interpolation `set_timesteps` uses to compute sigmas
+/// from timesteps. Sigmas must be within the range of `train_sigmas`.
+fn sigma_timesteps(train_sigmas: &Array1<f32>, sigmas: &[f32]) -> Array1<f32> {
+	let train_timesteps = Array1::range(0.0, train_sigmas.len() as f32, 1.0);
+	let mut interpolator = LinearInterpolatorAccelerated::new(train_sigmas.view(), train_timesteps.view());
+	sigmas.iter().map(|sigma| interpolator.eval(*sigma)).collect()
+}
+
+/// Computes the sigmas of `num_inference_steps` steps of the [Karras et al. (2022)](https://arxiv.org/abs/2206.00364)
+/// noise schedule from `sigma_max` down to `sigma_min`, which interpolates linearly between `sigma_max ** (1 / rho)`
+/// & `sigma_min ** (1 / rho)` with `rho = 7`, so steps are concentrated at low noise levels. With a single step, the
+/// only sigma is `sigma_max`.
+pub(crate) fn karras_sigmas(sigma_min: f32, sigma_max: f32, num_inference_steps: usize) -> Vec<f32> {
+	let (min_inv_rho, max_inv_rho) = (sigma_min.powf(1.0 / KARRAS_RHO), sigma_max.powf(1.0 / KARRAS_RHO));
+	let last = num_inference_steps.max(2) - 1;
+	(0..num_inference_steps)
+		.map(|i| match i {
+			0 => sigma_max,
+			i if i == last => sigma_min,
+			// rounding must not push sigmas outside of the training schedule's range
+			i => (max_inv_rho + i as f32 / last as f32 * (min_inv_rho - max_inv_rho)).powf(KARRAS_RHO).clamp(sigma_min, sigma_max)
+		})
+		.collect()
+}
+
+/// Computes the timesteps & sigmas (with a final `0.0` appended) of `num_inference_steps` steps of the Karras noise
+/// schedule (see [`karras_sigmas`]) spanning the range of `train_sigmas`. Each sigma's timestep is found the same way
+/// [`custom_sigma_schedule`] finds it, so the timesteps follow the sigmas instead of being evenly spaced.
+pub(crate) fn karras_schedule(train_sigmas: &Array1<f32>, num_inference_steps: usize) -> (Array1<f32>, Array1<f32>) {
+	let mut sigmas = karras_sigmas(train_sigmas[0], train_sigmas[train_sigmas.len() - 1], num_inference_steps);
+	let timesteps = sigma_timesteps(train_sigmas, &sigmas);
+	sigmas.push(0.0);
+	(timesteps, Array1::from_vec(sigmas))
+}
+
+/// A small least-recently-used cache of the arrays derived by
+/// [`set_timesteps`](super::DiffusionScheduler::set_timesteps), keyed by the number of inference steps.
+///
+/// Everything else these arrays depend on (the training schedule, timestep spacing & sigma schedule) is fixed when the
+/// scheduler is constructed, so the number of inference steps fully determines them. Servers typically only generate
+/// with a handful of different step counts, making `set_timesteps` nearly free after the first call with each.
+#[derive(Debug, Clone)]
+pub(crate) struct ScheduleCache<V> {
+	/// Cached entries, least recently used first.
+	entries: Vec<(usize, V)>
+}
+
+impl<V> Default for ScheduleCache<V> {
+	fn default() -> Self {
+		Self {
+			entries: Vec::with_capacity(SCHEDULE_CACHE_CAPACITY)
+		}
+	}
+}
+
+impl<V: Clone> ScheduleCache<V> {
+	/// Returns the arrays cached for `num_inference_steps`, computing them with `compute` on a cache miss. The least
+	/// recently used entry is evicted when the cache is full.
+	pub(crate) fn get_or_insert_with(&mut self, num_inference_steps: usize, compute: impl FnOnce() -> V) -> V {
+		if let Some(index) = self.entries.iter().position(|(steps, _)| *steps == num_inference_steps) {
+			let entry = self.entries.remove(index);
+			self.entries.push(entry);
+		} else {
+			if self.entries.len() >= SCHEDULE_CACHE_CAPACITY {
+				self.entries.remove(0);
+			}
+			self.entries.push((num_inference_steps, compute()));
+		}
+		self.entries[self.entries.len() - 1].1.clone()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ndarray::Array1;
+
+	use super::{karras_schedule, karras_sigmas, to_f32, validate_custom_sigmas, ScheduleCache, TrainingSchedule, SCHEDULE_CACHE_CAPACITY};
+	use crate::schedulers::BetaSchedule;
+
+	/// The `f32` computation of `alphas_cumprod` the schedulers used before tables were precomputed in `f64`.
+	fn alphas_cumprod_f32(num_train_timesteps: usize, beta_start: f32, beta_end: f32, beta_schedule: &BetaSchedule) -> Array1<f32> {
+		let betas = match beta_schedule {
+			BetaSchedule::Linear => Array1::linspace(beta_start, beta_end, num_train_timesteps),
+			BetaSchedule::ScaledLinear => Array1::linspace(beta_start.sqrt(), beta_end.sqrt(), num_train_timesteps).mapv(|f| f.powi(2)),
+			_ => unreachable!()
+		};
+		let alphas = 1.0 - betas;
+		alphas
+			.iter()
+			.scan(1.0, |prod, alpha| {
+				*prod *= *alpha;
+				Some(*prod)
+			})
+			.collect()
+	}
+
+	fn max_abs_diff(a: &Array1<f32>, b: &Array1<f32>) -> f32 {
+		a.iter().zip(b.iter()).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max)
+	}
+
+	#[test]
+	fn f64_tables_match_f32() {
+		for (beta_start, beta_end, beta_schedule) in [(0.00085, 0.012, BetaSchedule::ScaledLinear), (0.0001, 0.02, BetaSchedule::Linear)] {
+			let schedule = TrainingSchedule::new(1000, beta_start, beta_end, &beta_schedule).unwrap();
+			let reference = alphas_cumprod_f32(1000, beta_start, beta_end, &beta_schedule);
+			let alphas_cumprod = to_f32(&schedule.alphas_cumprod);
+			let diff = max_abs_diff(&alphas_cumprod, &reference);
+			assert!(diff <= 1e-6, "{beta_schedule:?}: alphas_cumprod differs by up to {diff}");
+
+			// sigmas near t=0 suffer from cancellation in `1 - alphas_cumprod` when computed in f32, so the f32 reference
+			// is off by up to ~1e-4 there. Wherever the f64 sigmas differ from the reference by more than 1e-6, they must be
+			// closer to the exact sigmas, computed without cancellation as `expm1(-sum(ln_1p(-beta))) ** 0.5`
+			let sigmas = to_f32(&schedule.sigmas());
+			let reference_sigmas = reference.mapv(|f| ((1.0 - f) / f).sqrt());
+			let exact = schedule
+				.betas
+				.iter()
+				.scan(0.0_f64, |log_alphas_cumprod, beta| {
+					*log_alphas_cumprod += (-beta).ln_1p();
+					Some((-*log_alphas_cumprod).exp_m1().sqrt())
+				})
+				.collect::<Array1<_>>();
+			for (t, ((&sigma, &reference), &exact)) in sigmas.iter().zip(&reference_sigmas).zip(&exact).enumerate() {
+				let error = (f64::from(sigma) - exact).abs() / exact;
+				assert!(error <= 1e-6, "{beta_schedule:?}: sigma {t} is off by {error}");
+				if (sigma - reference).abs() / reference > 1e-6 {
+					let reference_error = (f64::from(reference) - exact).abs() / exact;
+					assert!(error < reference_error, "{beta_schedule:?}: sigma {t} changed from {reference} to {sigma}, away from {exact}");
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn unsupported_schedule() {
+		assert!(TrainingSchedule::new(1000, 0.0001, 0.02, &BetaSchedule::Sigmoid).is_none());
+	}
+
+	#[test]
+	fn custom_sigmas() {
+		assert_eq!(validate_custom_sigmas(&[14.6, 4.0, 1.0, 0.0]).unwrap(), [14.6, 4.0, 1.0]);
+		assert_eq!(validate_custom_sigmas(&[14.6, 4.0, 1.0]).unwrap(), [14.6, 4.0, 1.0]);
+		assert!(validate_custom_sigmas(&[]).is_err());
+		assert!(validate_custom_sigmas(&[0.0]).is_err());
+		assert!(validate_custom_sigmas(&[14.6, 4.0, 4.0]).is_err());
+		assert!(validate_custom_sigmas(&[1.0, 4.0]).is_err());
+		assert!(validate_custom_sigmas(&[f32::NAN, 1.0]).is_err());
+		assert!(validate_custom_sigmas(&[4.0, 0.0, 0.0]).is_err());
+	}
+
+	#[test]
+	fn karras_schedule_spans_training_range() {
+		let train_sigmas = to_f32(&TrainingSchedule::new(1000, 0.00085, 0.012, &BetaSchedule::ScaledLinear).unwrap().sigmas());
+		let (sigma_min, sigma_max) = (train_sigmas[0], train_sigmas[999]);
+		let sigmas = karras_sigmas(sigma_min, sigma_max, 10);
+		assert_eq!((sigmas[0], sigmas[9]), (sigma_max, sigma_min));
+		assert!(sigmas.windows(2).all(|pair| pair[1] < pair[0]));
+		// k-diffusion's `get_sigmas_karras`, i.e. `(max ** (1 / 7) + i / 9 * (min ** (1 / 7) - max ** (1 / 7))) ** 7`
+		let reference = (sigma_max.powf(1.0 / 7.0) + 4.0 / 9.0 * (sigma_min.powf(1.0 / 7.0) - sigma_max.powf(1.0 / 7.0))).powf(7.0);
+		assert!((sigmas[4] - reference).abs() < 1e-5, "{} != {reference}", sigmas[4]);
+		assert_eq!(karras_sigmas(sigma_min, sigma_max, 1), [sigma_max]);
+
+		let (timesteps, schedule_sigmas) = karras_schedule(&train_sigmas, 10);
+		assert_eq!(schedule_sigmas.len(), 11);
+		assert_eq!(schedule_sigmas[10], 0.0);
+		assert!((timesteps[0] - 999.0).abs() < 1e-2 && timesteps[9].abs() < 1e-2);
+		assert!(timesteps.iter().zip(timesteps.iter().skip(1)).all(|(a, b)| b < a));
+		// Karras steps are concentrated at low noise levels, so the middle step's timestep is well below the linear one
+		assert!(timesteps[5] < 999.0 * 4.0 / 9.0, "{}", timesteps[5]);
+	}
+
+	#[test]
+	fn cache_evicts_least_recently_used() {
+		let mut cache = ScheduleCache::default();
+		let mut misses = 0;
+		let mut get = |cache: &mut ScheduleCache<usize>, steps: usize| {
+			cache.get_or_insert_with(steps, || {
+				misses += 1;
+				steps * 2
+			})
+		};
+
+		for steps in 1..=SCHEDULE_CACHE_CAPACITY {
+			assert_eq!(get(&mut cache, steps), steps * 2);
+		}
+		// hit; 1 becomes the most recently used entry
+		assert_eq!(get(&mut cache, 1), 2);
+		// evicts 2, the least recently used entry
+		get(&mut cache, 100);
+		get(&mut cache, 1);
+		assert_eq!(misses, SCHEDULE_CACHE_CAPACITY + 1);
+
+		let mut recomputed = false;
+		cache.get_or_insert_with(2, || {
+			recomputed = true;
+			4
+		});
+		assert!(recomputed);
+	}
+}
@@ -0,0 +1,406 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+	fmt, fs,
+	ops::Deref,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex, Weak}
+};
+
+use once_cell::sync::Lazy;
+use ort::{GraphOptimizationLevel, Session};
+
+use crate::{
+	util::onnx_info::{describe_load_failure, external_data_files, ComponentCompatibility},
+	DiffusionDevice, ExecutionContext
+};
+
+static GLOBAL_TRACKER: Lazy<SessionTracker> = Lazy::new(SessionTracker::new);
+
+/// Information about a live ONNX Runtime session, see [`SessionTracker`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+	/// The pipeline component this session was loaded for, e.g. `"UNet"`.
+	pub component: &'static str,
+	/// The path the model was loaded from, or `None` if it was loaded from memory (e.g. a merged UNet).
+	pub path: Option<PathBuf>,
+	/// The approximate size of the model in bytes. This is the size of the model file & its external data files, which
+	/// is typically close to the amount of memory used by the session's weights; activations are not included.
+	pub size_bytes: u64,
+	/// The device the session runs on, including its execution provider options, formatted with `Debug`. This is the
+	/// device after [`ExecutionContext`] options were applied, so sessions loaded through the same context report the
+	/// same options, and after falling back to the CPU if the requested execution provider is not available.
+	pub execution_provider: String
+}
+
+/// Error returned when loading a model would exceed
+/// [`StableDiffusionOptions::max_resident_bytes`](crate::StableDiffusionOptions::max_resident_bytes).
+#[derive(Debug, Clone)]
+pub struct ResidentLimitExceeded {
+	/// The component that was being loaded.
+	pub component: &'static str,
+	/// The approximate size of the model that was being loaded, in bytes.
+	pub requested_bytes: u64,
+	/// The configured limit, in bytes.
+	pub limit_bytes: u64,
+	/// The sessions that were resident at the time.
+	pub resident: Vec<SessionInfo>
+}
+
+impl fmt::Display for ResidentLimitExceeded {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let resident_bytes: u64 = self.resident.iter().map(|s| s.size_bytes).sum();
+		write!(
+			f,
+			"loading the {} ({} bytes) would exceed the limit of {} resident bytes; {} bytes are resident",
+			self.component, self.requested_bytes, self.limit_bytes, resident_bytes
+		)?;
+		if !self.resident.is_empty() {
+			let resident = self.resident.iter().map(|s| format!("{} ({} bytes)", s.component, s.size_bytes)).collect::<Vec<_>>();
+			write!(f, ": {}", resident.join(", "))?;
+		}
+		Ok(())
+	}
+}
+
+impl std::error::Error for ResidentLimitExceeded {}
+
+/// The memory needed to load a model on top of the memory its weights take up, for ONNX Runtime's own allocations.
+const LOAD_OVERHEAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Estimates how much free RAM is needed to load an ONNX model of `model_bytes` bytes, as checked by
+/// [`StableDiffusionOptions::with_free_memory_check`](crate::StableDiffusionOptions::with_free_memory_check).
+///
+/// While ONNX Runtime creates a session, it holds both the serialized model & the deserialized weights, so loading
+/// peaks at about twice the model's size, plus a fixed 256 MiB for the runtime's own allocations. Once loaded, a
+/// session on the CPU keeps about the model's size resident; sessions on GPUs still go through system memory while
+/// loading.
+pub fn required_load_memory(model_bytes: u64) -> u64 {
+	model_bytes.saturating_mul(2).saturating_add(LOAD_OVERHEAD_BYTES)
+}
+
+/// Error returned when there isn't enough free memory to load a model; see
+/// [`StableDiffusionOptions::with_free_memory_check`](crate::StableDiffusionOptions::with_free_memory_check).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsufficientMemory {
+	/// The component that was being loaded.
+	pub component: &'static str,
+	/// The free memory needed to load the model & keep the configured headroom free, in bytes.
+	pub required_bytes: u64,
+	/// The free memory available, in bytes.
+	pub available_bytes: u64
+}
+
+impl fmt::Display for InsufficientMemory {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"not enough free memory to load the {}: {} bytes are needed, but only {} bytes are available",
+			self.component, self.required_bytes, self.available_bytes
+		)
+	}
+}
+
+impl std::error::Error for InsufficientMemory {}
+
+/// Checks that `available_bytes` suffice to load a model of `model_bytes` bytes & keep `headroom_bytes` free.
+#[cfg_attr(not(feature = "sysinfo"), allow(dead_code))]
+fn ensure_free_memory(component: &'static str, model_bytes: u64, headroom_bytes: u64, available_bytes: u64) -> Result<(), InsufficientMemory> {
+	let required_bytes = required_load_memory(model_bytes).saturating_add(headroom_bytes);
+	if available_bytes < required_bytes {
+		return Err(InsufficientMemory { component, required_bytes, available_bytes });
+	}
+	Ok(())
+}
+
+/// Checks that there is enough free memory to load the models at `paths` together (e.g. both UNets of a merge) & keep
+/// `headroom_bytes` free.
+#[cfg(feature = "sysinfo")]
+pub(crate) fn check_free_memory(component: &'static str, paths: &[&Path], headroom_bytes: u64) -> anyhow::Result<()> {
+	use sysinfo::{System, SystemExt};
+
+	let mut model_bytes = 0_u64;
+	for path in paths {
+		model_bytes = model_bytes.saturating_add(model_size(path)?);
+	}
+	let mut system = System::new();
+	system.refresh_memory();
+	ensure_free_memory(component, model_bytes, headroom_bytes, system.available_memory())?;
+	Ok(())
+}
+
+/// Returns the size of the ONNX model at `path` in bytes, including the external data files its weights may be stored
+/// in (see [`external_data_files`]).
+pub(crate) fn model_size(path: &Path) -> anyhow::Result<u64> {
+	let mut size = fs::metadata(path)?.len();
+	for file in external_data_files(path)? {
+		let metadata = fs::metadata(&file).map_err(|e| anyhow::anyhow!("external data file `{}` of `{}`: {e}", file.display(), path.display()))?;
+		size = size.saturating_add(metadata.len());
+	}
+	Ok(size)
+}
+
+/// A registry of every live ONNX Runtime session created by pyke Diffusers.
+///
+/// Pipeline features like the safety checker, CLIP scoring, and merged UNets each hold their own multi-GB session, so
+/// it is easy to accidentally keep many models resident at once. The tracker only holds weak references, so sessions
+/// are removed from the registry as soon as they are dropped.
+///
+/// ```
+/// # fn main() -> anyhow::Result<()> {
+/// # use pyke_diffusers::{StableDiffusionPipeline, StableDiffusionOptions, OrtEnvironment, SessionTracker};
+/// # let environment = OrtEnvironment::default().into_arc();
+/// let pipeline = StableDiffusionPipeline::new(&environment, "tests/stable-diffusion", StableDiffusionOptions::default())?;
+/// for session in SessionTracker::global().active_sessions() {
+/// 	println!("{}: {} bytes", session.component, session.size_bytes);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// To limit the total size of resident sessions, see
+/// [`StableDiffusionOptions::max_resident_bytes`](crate::StableDiffusionOptions::max_resident_bytes).
+#[derive(Debug)]
+pub struct SessionTracker {
+	sessions: Mutex<Vec<Weak<SessionInfo>>>
+}
+
+impl SessionTracker {
+	fn new() -> Self {
+		Self { sessions: Mutex::new(Vec::new()) }
+	}
+
+	/// Returns the global session tracker, which tracks all sessions created by pyke Diffusers.
+	pub fn global() -> &'static SessionTracker {
+		&GLOBAL_TRACKER
+	}
+
+	/// Returns information about every live session.
+	pub fn active_sessions(&self) -> Vec<SessionInfo> {
+		let mut sessions = self.sessions.lock().unwrap();
+		sessions.retain(|s| s.strong_count() > 0);
+		sessions.iter().filter_map(Weak::upgrade).map(|s| SessionInfo::clone(&s)).collect()
+	}
+
+	/// Returns the approximate total size of every live session, in bytes.
+	pub fn resident_bytes(&self) -> u64 {
+		self.active_sessions().iter().map(|s| s.size_bytes).sum()
+	}
+
+	/// Registers `info` if it fits within `limit_bytes`. The session `replacing` (which is about to be dropped) is not
+	/// counted as resident.
+	///
+	/// The check & the registration happen under one lock, so concurrent loads can't both pass the limit. Sessions are
+	/// registered before they're loaded, reserving their size while ONNX Runtime creates them; if loading fails, the
+	/// returned `Arc` is dropped & the reservation with it.
+	fn register_within_limit(
+		&self,
+		info: SessionInfo,
+		limit_bytes: Option<u64>,
+		replacing: Option<&Arc<SessionInfo>>
+	) -> Result<Arc<SessionInfo>, ResidentLimitExceeded> {
+		let mut sessions = self.sessions.lock().unwrap();
+		sessions.retain(|s| s.strong_count() > 0);
+		if let Some(limit_bytes) = limit_bytes {
+			let resident = sessions
+				.iter()
+				.filter_map(Weak::upgrade)
+				.filter(|s| !replacing.map_or(false, |replacing| Arc::ptr_eq(s, replacing)))
+				.map(|s| SessionInfo::clone(&s))
+				.collect::<Vec<_>>();
+			let resident_bytes: u64 = resident.iter().map(|s| s.size_bytes).sum();
+			if resident_bytes.saturating_add(info.size_bytes) > limit_bytes {
+				return Err(ResidentLimitExceeded {
+					component: info.component,
+					requested_bytes: info.size_bytes,
+					limit_bytes,
+					resident
+				});
+			}
+		}
+		let info = Arc::new(info);
+		sessions.push(Arc::downgrade(&info));
+		Ok(info)
+	}
+}
+
+/// An ONNX Runtime [`Session`] registered with the global [`SessionTracker`]. The session is unregistered when this is
+/// dropped.
+pub(crate) struct TrackedSession {
+	session: Session,
+	info: Arc<SessionInfo>
+}
+
+impl TrackedSession {
+	/// Returns the information this session is registered with.
+	pub(crate) fn info(&self) -> &SessionInfo {
+		&self.info
+	}
+}
+
+impl Deref for TrackedSession {
+	type Target = Session;
+
+	fn deref(&self) -> &Self::Target {
+		&self.session
+	}
+}
+
+/// Where to load a model from.
+#[derive(Clone, Copy)]
+pub(crate) enum ModelSource<'a> {
+	File(&'a Path),
+	Memory(&'a [u8])
+}
+
+/// How ONNX Runtime's graph optimizations are applied when loading a session.
+#[derive(Clone, Copy)]
+pub(crate) enum GraphOptimization<'a> {
+	/// Optimize the graph on load, which is ONNX Runtime's default.
+	OnLoad,
+	/// Optimize the graph on load, and write the optimized graph to the given path.
+	OnLoadAndWrite(&'a Path),
+	/// The model was already optimized (with [`GraphOptimization::OnLoadAndWrite`]), so graph optimization is skipped.
+	Preoptimized
+}
+
+/// Loads a session on the given device, registering it with the global [`SessionTracker`].
+///
+/// Fails with [`ResidentLimitExceeded`] if the model would bring the total size of resident sessions above
+/// `max_resident_bytes`. `replacing` may be the session that the new session replaces, which is not counted. If ONNX
+/// Runtime fails to load the model, the error includes the model's [`ComponentCompatibility`] report.
+pub(crate) fn load_session(
+	context: &ExecutionContext,
+	device: &DiffusionDevice,
+	component: &'static str,
+	source: ModelSource<'_>,
+	max_resident_bytes: Option<u64>,
+	replacing: Option<&TrackedSession>
+) -> anyhow::Result<TrackedSession> {
+	load_session_with_optimization(context, device, component, source, max_resident_bytes, replacing, GraphOptimization::OnLoad)
+}
+
+/// Loads a session like [`load_session`], applying graph optimizations according to `optimization`.
+pub(crate) fn load_session_with_optimization(
+	context: &ExecutionContext,
+	device: &DiffusionDevice,
+	component: &'static str,
+	source: ModelSource<'_>,
+	max_resident_bytes: Option<u64>,
+	replacing: Option<&TrackedSession>,
+	optimization: GraphOptimization<'_>
+) -> anyhow::Result<TrackedSession> {
+	let (path, size_bytes) = match source {
+		ModelSource::File(path) => (Some(path.to_path_buf()), model_size(path)?),
+		ModelSource::Memory(bytes) => (None, bytes.len() as u64)
+	};
+
+	// register before loading so that concurrent loads see each other's reservations
+	let execution_provider = format!("{:?}", context.effective_device(device).resolve());
+	let info = SessionTracker::global().register_within_limit(
+		SessionInfo { component, path, size_bytes, execution_provider },
+		max_resident_bytes,
+		replacing.map(|s| &s.info)
+	)?;
+
+	let builder = context.session_builder(device)?;
+	let builder = match optimization {
+		GraphOptimization::OnLoad => builder,
+		GraphOptimization::OnLoadAndWrite(path) => builder.with_optimized_model_path(path)?,
+		GraphOptimization::Preoptimized => builder.with_optimization_level(GraphOptimizationLevel::Disable)?
+	};
+	let session = match source {
+		ModelSource::File(path) => builder.with_model_from_file(path),
+		ModelSource::Memory(bytes) => builder.with_model_from_memory(bytes)
+	}
+	.map_err(|e| {
+		let compatibility = match source {
+			ModelSource::File(path) => ComponentCompatibility::inspect_file(component, path),
+			ModelSource::Memory(bytes) => ComponentCompatibility::inspect_bytes(component, bytes)
+		};
+		anyhow::anyhow!(describe_load_failure(&compatibility, e))
+	})?;
+	Ok(TrackedSession { session, info })
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{sync::Arc, thread};
+
+	use super::{ensure_free_memory, required_load_memory, InsufficientMemory, SessionInfo, SessionTracker};
+
+	fn info(component: &'static str, size_bytes: u64) -> SessionInfo {
+		SessionInfo { component, path: None, size_bytes, execution_provider: "CPU".to_string() }
+	}
+
+	#[test]
+	fn sessions_are_removed_when_dropped() {
+		let tracker = SessionTracker::new();
+		let unet = tracker.register_within_limit(info("UNet", 100), None, None).unwrap();
+		let vae_decoder = tracker.register_within_limit(info("VAE decoder", 20), None, None).unwrap();
+		assert_eq!(tracker.active_sessions(), vec![info("UNet", 100), info("VAE decoder", 20)]);
+		assert_eq!(tracker.resident_bytes(), 120);
+
+		drop(unet);
+		assert_eq!(tracker.active_sessions(), vec![info("VAE decoder", 20)]);
+		drop(vae_decoder);
+		assert!(tracker.active_sessions().is_empty());
+	}
+
+	#[test]
+	fn limit_is_enforced() {
+		let tracker = SessionTracker::new();
+		let unet = tracker.register_within_limit(info("UNet", 100), None, None).unwrap();
+		assert!(tracker.register_within_limit(info("VAE decoder", 1000), None, None).is_ok());
+		let vae_decoder = tracker.register_within_limit(info("VAE decoder", 20), Some(120), None).unwrap();
+
+		// the VAE decoder registered above is resident now, so even a 1 byte model exceeds the limit
+		let err = tracker.register_within_limit(info("VAE encoder", 1), Some(120), None).unwrap_err();
+		assert_eq!(err.resident, vec![info("UNet", 100), info("VAE decoder", 20)]);
+		assert!(err.to_string().contains("UNet (100 bytes)"), "{err}");
+		assert_eq!(tracker.resident_bytes(), 120);
+
+		// the session being replaced doesn't count
+		drop(vae_decoder);
+		assert!(tracker.register_within_limit(info("UNet", 120), Some(120), Some(&unet)).is_ok());
+	}
+
+	#[test]
+	fn concurrent_loads_share_the_limit() {
+		let tracker = Arc::new(SessionTracker::new());
+		let handles = (0..8)
+			.map(|_| {
+				let tracker = Arc::clone(&tracker);
+				thread::spawn(move || tracker.register_within_limit(info("UNet", 100), Some(250), None))
+			})
+			.collect::<Vec<_>>();
+		let registered = handles.into_iter().filter_map(|h| h.join().unwrap().ok()).collect::<Vec<_>>();
+		assert_eq!(registered.len(), 2);
+		assert_eq!(tracker.resident_bytes(), 200);
+	}
+
+	#[test]
+	fn free_memory_heuristic() {
+		let gib = 1024 * 1024 * 1024;
+		assert_eq!(required_load_memory(gib), 2 * gib + 256 * 1024 * 1024);
+		assert_eq!(required_load_memory(u64::MAX), u64::MAX);
+
+		assert!(ensure_free_memory("UNet", gib, gib, 4 * gib).is_ok());
+		assert_eq!(
+			ensure_free_memory("UNet", gib, gib, 3 * gib),
+			Err(InsufficientMemory { component: "UNet", required_bytes: 3 * gib + 256 * 1024 * 1024, available_bytes: 3 * gib })
+		);
+	}
+}
@@ -0,0 +1,87 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Perceptual image hashing, e.g. to deduplicate near-identical generations.
+
+use image::{
+	imageops::{self, FilterType},
+	DynamicImage
+};
+
+/// The side length of the downscaled image each hash bit is computed from.
+const HASH_SIZE: u32 = 8;
+
+/// Computes the *average hash* (aHash) of an image: a 64-bit perceptual hash which stays (nearly) the same under
+/// resizing, re-encoding & small changes to the image, so similar images have hashes with a small
+/// [`hamming_distance`].
+///
+/// The image is converted to 8-bit grayscale & downscaled to 8x8 pixels with a triangle filter, ignoring its aspect
+/// ratio. Bit `y * 8 + x` of the hash (counting from the least significant bit) is set if pixel `(x, y)` is brighter
+/// than the mean of all 64 pixels. Images of a single flat color hash to `0`.
+///
+/// The average hash is fast but coarse: it only captures the rough layout of light & dark areas, so unrelated images
+/// with a similar composition may also collide. Treat a small distance as a hint to compare the images more closely.
+///
+/// ```
+/// # use image::{DynamicImage, RgbImage};
+/// # use pyke_diffusers::image_hash::{hamming_distance, image_hash};
+/// let image = DynamicImage::ImageRgb8(RgbImage::from_fn(512, 512, |x, _| image::Rgb([(x / 2) as u8; 3])));
+/// let resized = image.resize_exact(256, 256, image::imageops::FilterType::Lanczos3);
+/// assert!(hamming_distance(image_hash(&image), image_hash(&resized)) <= 2);
+/// ```
+pub fn image_hash(image: &DynamicImage) -> u64 {
+	let small = imageops::resize(&image.to_luma8(), HASH_SIZE, HASH_SIZE, FilterType::Triangle);
+	let mean = small.pixels().map(|pixel| pixel.0[0] as u32).sum::<u32>() as f32 / (HASH_SIZE * HASH_SIZE) as f32;
+	small
+		.pixels()
+		.enumerate()
+		.filter(|(_, pixel)| pixel.0[0] as f32 > mean)
+		.fold(0, |hash, (i, _)| hash | (1 << i))
+}
+
+/// Returns the number of bits that differ between two [`image_hash`]es. `0` means the images are (nearly) identical;
+/// distances above ~10 of 64 usually indicate different images.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+	(a ^ b).count_ones()
+}
+
+#[cfg(test)]
+mod tests {
+	use image::{DynamicImage, Rgb, RgbImage};
+
+	use super::{hamming_distance, image_hash};
+
+	fn gradient(width: u32, height: u32, offset: u8) -> DynamicImage {
+		DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| Rgb([((x + y) * 255 / (width + height)) as u8 + offset; 3])))
+	}
+
+	#[test]
+	fn layout_of_bits() {
+		// left half dark, right half bright
+		let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, _| Rgb([if x < 32 { 0 } else { 255 }; 3])));
+		assert_eq!(image_hash(&image), 0xf0f0_f0f0_f0f0_f0f0);
+		assert_eq!(image_hash(&DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 64, Rgb([128; 3])))), 0);
+	}
+
+	#[test]
+	fn near_identical_images_match() {
+		let image = gradient(128, 96, 0);
+		assert_eq!(image_hash(&image), image_hash(&gradient(128, 96, 0)));
+		// brightness shifts & resizing barely change the hash
+		assert!(hamming_distance(image_hash(&image), image_hash(&gradient(128, 96, 3))) <= 2);
+		assert!(hamming_distance(image_hash(&image), image_hash(&gradient(64, 48, 0))) <= 2);
+		// while the reversed gradient is entirely different
+		assert!(hamming_distance(image_hash(&image), image_hash(&image.rotate180())) > 32);
+	}
+}
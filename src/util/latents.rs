@@ -0,0 +1,217 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Utilities for transferring latents between models & inspecting them.
+
+use image::{DynamicImage, Rgb, RgbImage};
+use ndarray::{Array4, ArrayView4, Axis};
+
+/// The number of channels of Stable Diffusion latents.
+const CHANNELS: usize = 4;
+
+/// Per-channel statistics of (VAE-scaled) latents, used to match latents to the distribution a model expects with
+/// [`normalize_latents`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatentStats {
+	/// The mean of each latent channel.
+	pub mean: [f32; CHANNELS],
+	/// The standard deviation of each latent channel.
+	pub std: [f32; CHANNELS]
+}
+
+impl LatentStats {
+	/// Measures the per-channel statistics of a batch of latents of shape `(batch_size, 4, height, width)`, over all
+	/// images of the batch. To get the statistics a model expects, measure latents encoded from a representative set
+	/// of images by the model's VAE (see
+	/// [`StableDiffusionPipeline::encode_images`](crate::StableDiffusionPipeline::encode_images)).
+	///
+	/// # Panics
+	/// Panics if the latents don't have 4 channels, or are empty.
+	pub fn from_latents(latents: ArrayView4<'_, f32>) -> Self {
+		assert_eq!(latents.shape()[1], CHANNELS, "latents must have {CHANNELS} channels");
+		let mut stats = LatentStats { mean: [0.0; CHANNELS], std: [0.0; CHANNELS] };
+		for (c, channel) in latents.axis_iter(Axis(1)).enumerate() {
+			stats.mean[c] = channel.mean().expect("latents must not be empty");
+			stats.std[c] = channel.std(0.0);
+		}
+		stats
+	}
+
+	/// Returns these statistics scaled by `factor`, e.g. a scheduler's
+	/// [`init_noise_sigma`](crate::DiffusionScheduler::init_noise_sigma) to describe initial noise latents.
+	pub fn scaled(&self, factor: f32) -> Self {
+		LatentStats {
+			mean: self.mean.map(|mean| mean * factor),
+			std: self.std.map(|std| std * factor)
+		}
+	}
+}
+
+/// Matches the per-channel mean & standard deviation of each image's latents to `target`, e.g. to transplant latents
+/// inverted with one model into another, whose latent space may be shifted or scaled differently.
+///
+/// Each channel of each image is standardized using its own statistics, then rescaled to the target's. Channels with
+/// zero variance are only shifted to the target mean.
+///
+/// This only corrects the first two moments of each channel; it cannot fix differences in the spatial structure or
+/// the correlation between channels of two latent spaces, so latents from an unrelated VAE will still decode poorly.
+/// Normalizing also removes intended deviations, e.g. the lower contrast of latents of a flat, gray image, so it is
+/// best applied to noise-like latents, such as the result of inverting an image, rather than clean image latents.
+///
+/// # Panics
+/// Panics if the latents don't have 4 channels.
+///
+/// ```
+/// # use ndarray::Array4;
+/// # use pyke_diffusers::latents::{normalize_latents, LatentStats};
+/// let latents = Array4::from_shape_fn((1, 4, 8, 8), |(_, c, y, x)| 3.0 + c as f32 * (x + y) as f32);
+/// let target = LatentStats { mean: [0.0; 4], std: [1.0; 4] };
+/// let normalized = normalize_latents(latents.view(), &target);
+/// let stats = LatentStats::from_latents(normalized.view());
+/// assert!(stats.mean[1].abs() < 1e-5 && (stats.std[1] - 1.0).abs() < 1e-5);
+/// ```
+pub fn normalize_latents(latents: ArrayView4<'_, f32>, target: &LatentStats) -> Array4<f32> {
+	assert_eq!(latents.shape()[1], CHANNELS, "latents must have {CHANNELS} channels");
+	let mut normalized = latents.to_owned();
+	for mut image in normalized.outer_iter_mut() {
+		for (c, mut channel) in image.outer_iter_mut().enumerate() {
+			let (mean, std) = (channel.mean().unwrap_or(0.0), channel.std(0.0));
+			let scale = if std > 0.0 { target.std[c] / std } else { 0.0 };
+			channel.mapv_inplace(|x| (x - mean) * scale + target.mean[c]);
+		}
+	}
+	normalized
+}
+
+/// Averages the given latents element-wise, e.g. the final latents of several seeds to decode them as one image; see
+/// [`StableDiffusionTxt2ImgOptions::with_averaged_seeds`](crate::StableDiffusionTxt2ImgOptions::with_averaged_seeds).
+///
+/// Averaging reduces detail & variance: anything the latents don't agree on is washed out, and averaging `N`
+/// independent noise latents shrinks their standard deviation by `sqrt(N)`, so averaged *initial* noise should be
+/// renormalized (see [`normalize_latents`]) before generating from it. Averaging latents is also not the same as
+/// averaging the decoded images, since the VAE decoder is not linear.
+///
+/// # Panics
+/// Panics if `latents` is empty or the latents don't all have the same shape.
+pub fn average_latents(latents: &[Array4<f32>]) -> Array4<f32> {
+	assert!(!latents.is_empty(), "no latents to average");
+	let mut sum = latents[0].clone();
+	for other in &latents[1..] {
+		assert_eq!(other.shape(), sum.shape(), "latents to average must have the same shape");
+		sum += other;
+	}
+	sum / latents.len() as f32
+}
+
+/// Visualizes latents of shape `(batch_size, 4, height, width)` directly, without a VAE, returning one
+/// `width`x`height` RGB image per batch element, i.e. at 1/8th of the decoded image's resolution.
+///
+/// Latent channels 0, 1 & 2 are mapped to red, green & blue respectively; channel 3 is not shown. Each channel of each
+/// image is min-max normalized on its own, so its smallest value is black and its largest is full intensity; constant
+/// channels are shown at half intensity, and NaN or infinite values are ignored for normalization and shown as black.
+/// Since channels are stretched independently, the colors show the structure of each channel rather than anything
+/// resembling the decoded image; for that, see
+/// [`StableDiffusionPipeline::approximate_decode_latents`](crate::StableDiffusionPipeline::approximate_decode_latents).
+///
+/// # Panics
+/// Panics if the latents don't have 4 channels.
+pub fn visualize_latents(latents: &Array4<f32>) -> Vec<DynamicImage> {
+	assert_eq!(latents.shape()[1], CHANNELS, "latents must have {CHANNELS} channels");
+	let (_, _, height, width) = latents.dim();
+	latents
+		.outer_iter()
+		.map(|channels| {
+			let ranges: [(f32, f32); 3] = std::array::from_fn(|c| {
+				let finite = channels.index_axis(Axis(0), c).iter().copied().filter(|x| x.is_finite());
+				finite.fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), x| (min.min(x), max.max(x)))
+			});
+			let image = RgbImage::from_fn(width as u32, height as u32, |x, y| {
+				Rgb(std::array::from_fn(|c| {
+					let (value, (min, max)) = (channels[[c, y as usize, x as usize]], ranges[c]);
+					if !value.is_finite() {
+						0
+					} else if max > min {
+						((value - min) / (max - min) * 255.0).round() as u8
+					} else {
+						128
+					}
+				}))
+			});
+			DynamicImage::ImageRgb8(image)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use ndarray::{s, Array4};
+
+	use super::{average_latents, normalize_latents, visualize_latents, LatentStats};
+
+	fn assert_close(a: f32, b: f32) {
+		assert!((a - b).abs() < 1e-4, "{a} != {b}");
+	}
+
+	#[test]
+	fn matches_target_stats_per_image() {
+		let target = LatentStats { mean: [0.5, -0.5, 0.0, 1.0], std: [1.0, 2.0, 0.5, 1.0] };
+		let latents = Array4::from_shape_fn((2, 4, 4, 4), |(n, c, y, x)| (n as f32 + 1.0) * 10.0 + c as f32 * x as f32 - y as f32);
+		let normalized = normalize_latents(latents.view(), &target);
+		for n in 0..2 {
+			let stats = LatentStats::from_latents(normalized.slice(s![n..n + 1, .., .., ..]));
+			for c in 0..4 {
+				assert_close(stats.mean[c], target.mean[c]);
+				assert_close(stats.std[c], target.std[c]);
+			}
+		}
+	}
+
+	#[test]
+	fn constant_channels_are_shifted() {
+		let latents = Array4::from_elem((1, 4, 2, 2), 3.0);
+		let normalized = normalize_latents(latents.view(), &LatentStats { mean: [0.0; 4], std: [1.0; 4] }.scaled(14.6));
+		assert!(normalized.iter().all(|&x| x == 0.0));
+	}
+
+	#[test]
+	fn scaled_stats() {
+		let stats = LatentStats { mean: [1.0; 4], std: [0.5; 4] }.scaled(2.0);
+		assert_eq!((stats.mean, stats.std), ([2.0; 4], [1.0; 4]));
+	}
+
+	#[test]
+	fn average() {
+		let latents = [Array4::from_elem((1, 4, 2, 2), 1.0), Array4::from_elem((1, 4, 2, 2), 2.0), Array4::from_elem((1, 4, 2, 2), 6.0)];
+		assert_eq!(average_latents(&latents), Array4::from_elem((1, 4, 2, 2), 3.0));
+		assert_eq!(average_latents(&latents[..1]), latents[0]);
+	}
+
+	#[test]
+	fn visualization() {
+		let mut latents = Array4::from_shape_fn((2, 4, 2, 3), |(n, c, y, x)| match c {
+			0 => x as f32 * (n as f32 + 1.0),
+			1 => -(y as f32),
+			_ => 7.0
+		});
+		latents[[1, 1, 0, 0]] = f32::NAN;
+		let images = visualize_latents(&latents);
+		assert_eq!(images.len(), 2);
+		let (first, second) = (images[0].to_rgb8(), images[1].to_rgb8());
+		assert_eq!(first.dimensions(), (3, 2));
+		// each channel is stretched on its own, regardless of its scale
+		assert_eq!((first.get_pixel(0, 0).0, first.get_pixel(1, 1).0, first.get_pixel(2, 1).0), ([0, 255, 128], [128, 0, 128], [255, 0, 128]));
+		assert_eq!(second.get_pixel(1, 0).0, first.get_pixel(1, 0).0);
+		assert_eq!(second.get_pixel(0, 0).0, [0, 0, 128]);
+	}
+}
@@ -0,0 +1,193 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Weighted merging of ONNX models.
+
+use std::{collections::HashMap, fs, ops::Range, path::Path};
+
+use half::f16;
+
+use super::protobuf::{fields, FieldValue};
+
+// protobuf field numbers from `onnx.proto`
+const MODEL_GRAPH: u64 = 7;
+const GRAPH_INITIALIZER: u64 = 5;
+const TENSOR_DATA_TYPE: u64 = 2;
+const TENSOR_NAME: u64 = 8;
+const TENSOR_RAW_DATA: u64 = 9;
+const TENSOR_DATA_LOCATION: u64 = 14;
+
+// `TensorProto.DataType`
+const DATA_TYPE_FLOAT: u64 = 1;
+const DATA_TYPE_FLOAT16: u64 = 10;
+
+/// Merges two UNets with identical graph structure by weighted averaging of their weights, returning the merged model
+/// as an in-memory ONNX model. The result is `(1 - alpha) * a + alpha * b`, so an `alpha` of `0.0` returns model `a`
+/// and `1.0` returns (the weights of) model `b`.
+///
+/// Only floating-point initializers (f32 & f16 weights) stored inline in the model are merged; all other tensors (e.g.
+/// integer shape constants), as well as the graph itself, are taken from model `a`. Both models must have an
+/// identical graph structure, i.e. be exported from the same architecture with the same settings: every floating
+/// point initializer must exist in both models with the same name, type, and size, otherwise an error is returned.
+///
+/// Models with weights stored as external data (typically f32 UNets > 2 GB) are not supported.
+///
+/// The merged model can be loaded with `SessionBuilder::with_model_from_memory`, or automatically when loading a
+/// pipeline via [`StableDiffusionOptions::with_merged_unet`](crate::StableDiffusionOptions::with_merged_unet).
+pub fn merge_unets(path_a: impl AsRef<Path>, path_b: impl AsRef<Path>, alpha: f32) -> anyhow::Result<Vec<u8>> {
+	let mut a = fs::read(path_a)?;
+	let b = fs::read(path_b)?;
+	merge_models(&mut a, &b, alpha)?;
+	Ok(a)
+}
+
+/// Merges the floating-point initializers of `b` into `a` in place.
+pub(crate) fn merge_models(a: &mut [u8], b: &[u8], alpha: f32) -> anyhow::Result<()> {
+	if !(0.0..=1.0).contains(&alpha) {
+		anyhow::bail!("merge alpha must be between 0 and 1, got {alpha}");
+	}
+
+	let initializers_a = float_initializers(a)?;
+	let initializers_b = float_initializers(b)?;
+	if initializers_a.len() != initializers_b.len() {
+		anyhow::bail!("models have a different number of weights ({} vs {}); they must have identical structure", initializers_a.len(), initializers_b.len());
+	}
+
+	for (name, (data_type, range_a)) in initializers_a {
+		let (data_type_b, range_b) = initializers_b
+			.get(&name)
+			.ok_or_else(|| anyhow::anyhow!("weight `{name}` does not exist in the second model; models must have identical structure"))?;
+		if data_type != *data_type_b || range_a.len() != range_b.len() {
+			anyhow::bail!("weight `{name}` has a different type or size in each model; models must have identical structure");
+		}
+
+		let (data_a, data_b) = (&mut a[range_a], &b[range_b.clone()]);
+		match data_type {
+			DATA_TYPE_FLOAT => {
+				for (x, y) in data_a.chunks_exact_mut(4).zip(data_b.chunks_exact(4)) {
+					let merged = lerp(f32::from_le_bytes([x[0], x[1], x[2], x[3]]), f32::from_le_bytes([y[0], y[1], y[2], y[3]]), alpha);
+					x.copy_from_slice(&merged.to_le_bytes());
+				}
+			}
+			DATA_TYPE_FLOAT16 => {
+				for (x, y) in data_a.chunks_exact_mut(2).zip(data_b.chunks_exact(2)) {
+					let merged = lerp(f16::from_le_bytes([x[0], x[1]]).to_f32(), f16::from_le_bytes([y[0], y[1]]).to_f32(), alpha);
+					x.copy_from_slice(&f16::from_f32(merged).to_le_bytes());
+				}
+			}
+			_ => unreachable!()
+		}
+	}
+	Ok(())
+}
+
+fn lerp(a: f32, b: f32, alpha: f32) -> f32 {
+	(1.0 - alpha) * a + alpha * b
+}
+
+/// Finds all f32/f16 initializers with inline data, returning their data type & the byte range of their raw data.
+fn float_initializers(model: &[u8]) -> anyhow::Result<HashMap<String, (u64, Range<usize>)>> {
+	let mut initializers = HashMap::new();
+	for (field, value) in fields(model, 0..model.len())? {
+		let graph = match (field, value) {
+			(MODEL_GRAPH, FieldValue::Bytes(graph)) => graph,
+			_ => continue
+		};
+		for (field, value) in fields(model, graph)? {
+			let tensor = match (field, value) {
+				(GRAPH_INITIALIZER, FieldValue::Bytes(tensor)) => tensor,
+				_ => continue
+			};
+
+			let (mut name, mut data_type, mut raw_data, mut external) = (None, 0, None, false);
+			for (field, value) in fields(model, tensor)? {
+				match (field, value) {
+					(TENSOR_NAME, FieldValue::Bytes(r)) => name = Some(String::from_utf8_lossy(&model[r]).into_owned()),
+					(TENSOR_DATA_TYPE, FieldValue::Varint(v)) => data_type = v,
+					(TENSOR_RAW_DATA, FieldValue::Bytes(r)) => raw_data = Some(r),
+					(TENSOR_DATA_LOCATION, FieldValue::Varint(v)) => external = v == 1,
+					_ => {}
+				}
+			}
+			if data_type != DATA_TYPE_FLOAT && data_type != DATA_TYPE_FLOAT16 {
+				continue;
+			}
+			let name = name.unwrap_or_default();
+			if external {
+				anyhow::bail!("weight `{name}` is stored as external data, which is not supported for merging");
+			}
+			if let Some(raw_data) = raw_data {
+				initializers.insert(name, (data_type, raw_data));
+			}
+		}
+	}
+	Ok(initializers)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::merge_models;
+
+	fn varint(mut value: usize, out: &mut Vec<u8>) {
+		while value >= 0x80 {
+			out.push((value as u8 & 0x7f) | 0x80);
+			value >>= 7;
+		}
+		out.push(value as u8);
+	}
+
+	fn bytes_field(field: u8, data: &[u8], out: &mut Vec<u8>) {
+		out.push(field << 3 | 2);
+		varint(data.len(), out);
+		out.extend_from_slice(data);
+	}
+
+	/// Builds a minimal ONNX model with a single f32 initializer & a single int64 initializer.
+	fn model(weights: &[f32], shape_constant: i64) -> Vec<u8> {
+		let mut weight = Vec::new();
+		weight.extend_from_slice(&[1 << 3, weights.len() as u8]); // dims
+		weight.extend_from_slice(&[2 << 3, 1]); // data_type = FLOAT
+		bytes_field(8, b"weight", &mut weight);
+		bytes_field(9, &weights.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>(), &mut weight);
+
+		let mut shape = Vec::new();
+		shape.extend_from_slice(&[2 << 3, 7]); // data_type = INT64
+		bytes_field(8, b"shape", &mut shape);
+		bytes_field(9, &shape_constant.to_le_bytes(), &mut shape);
+
+		let mut graph = Vec::new();
+		bytes_field(5, &weight, &mut graph);
+		bytes_field(5, &shape, &mut graph);
+
+		let mut model = vec![1 << 3, 8]; // ir_version
+		bytes_field(7, &graph, &mut model);
+		model
+	}
+
+	#[test]
+	fn merge_float_weights() {
+		let mut a = model(&[0.0, 1.0, 2.0], 3);
+		let b = model(&[4.0, 1.0, -2.0], 5);
+		merge_models(&mut a, &b, 0.25).unwrap();
+		// only the float weights change
+		assert_eq!(a, model(&[1.0, 1.0, 1.0], 3));
+	}
+
+	#[test]
+	fn mismatched_structure() {
+		let mut a = model(&[0.0, 1.0, 2.0], 3);
+		let b = model(&[0.0, 1.0], 3);
+		assert!(merge_models(&mut a, &b, 0.5).is_err());
+	}
+}
@@ -12,5 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod compositing;
+pub mod image_hash;
 pub(crate) mod interpolation;
+pub mod latents;
+pub mod merge;
+pub mod onnx_info;
+pub mod prompt_templates;
 pub mod prompting;
+pub(crate) mod protobuf;
@@ -0,0 +1,721 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ONNX IR version & opset compatibility checks.
+
+use std::{
+	fmt,
+	fs::File,
+	io::{self, BufReader, Cursor, Read, Seek, SeekFrom},
+	ops::RangeInclusive,
+	path::{Path, PathBuf}
+};
+
+use once_cell::sync::Lazy;
+
+use super::protobuf::{decode_varint, fields, FieldValue};
+
+static LINKED: Lazy<OrtSupport> = Lazy::new(|| OrtSupport::for_version(linked_ort_version()));
+
+/// The newest IR & opset versions supported by each ONNX Runtime minor release, as `(minor, ir, ai.onnx, ai.onnx.ml)`.
+/// Taken from ONNX Runtime's compatibility table, since ONNX Runtime has no API to query them.
+const ORT_RELEASES: &[(u32, u64, u64, u64)] = &[
+	(6, 7, 13, 2),
+	(7, 7, 13, 2),
+	(8, 7, 14, 2),
+	(9, 8, 15, 2),
+	(10, 8, 15, 2),
+	(11, 8, 16, 2),
+	(12, 8, 17, 3),
+	(13, 8, 17, 3),
+	(14, 8, 18, 3),
+	(15, 9, 19, 3),
+	(16, 9, 19, 3),
+	(17, 9, 20, 4),
+	(18, 10, 21, 4)
+];
+
+/// An ONNX Runtime version & the ONNX IR & opset versions it supports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrtSupport {
+	/// The ONNX Runtime version, e.g. `1.14.1`.
+	pub version: String,
+	/// The supported ONNX IR versions.
+	pub ir_versions: RangeInclusive<u64>,
+	/// The supported opset versions, per operator set domain.
+	pub opsets: Vec<(&'static str, RangeInclusive<u64>)>
+}
+
+impl OrtSupport {
+	/// Returns the versions supported by the ONNX Runtime library pyke Diffusers runs on.
+	///
+	/// The ONNX Runtime version is queried from the library at runtime. With the `ort-load-dynamic` feature, where the
+	/// library is only loaded once the first session is created, the version of the ONNX Runtime API pyke Diffusers was
+	/// built against is used instead.
+	pub fn linked() -> &'static OrtSupport {
+		&LINKED
+	}
+
+	/// Returns the versions supported by the given ONNX Runtime version. The IR & opset versions are looked up by
+	/// minor version in ONNX Runtime's compatibility table; versions newer than the table are assumed to support what
+	/// the newest known release does.
+	pub fn for_version(version: impl Into<String>) -> Self {
+		let version = version.into();
+		let minor = version.split('.').nth(1).and_then(|minor| minor.parse::<u32>().ok());
+		let &(_, ir, onnx, onnx_ml) = ORT_RELEASES
+			.iter()
+			.rev()
+			.find(|(release, ..)| minor.map_or(false, |minor| *release <= minor))
+			.unwrap_or(match minor {
+				Some(minor) if minor < ORT_RELEASES[0].0 => &ORT_RELEASES[0],
+				_ => &ORT_RELEASES[ORT_RELEASES.len() - 1]
+			});
+		Self {
+			version,
+			ir_versions: 3..=ir,
+			opsets: vec![("ai.onnx", 7..=onnx), ("ai.onnx.ml", 1..=onnx_ml), ("com.microsoft", 1..=1)]
+		}
+	}
+}
+
+fn linked_ort_version() -> String {
+	#[cfg(not(feature = "ort-load-dynamic"))]
+	{
+		// SAFETY: `OrtGetApiBase` returns a pointer to a static struct, and `GetVersionString` returns a pointer to a
+		// static, NUL-terminated string.
+		let version = unsafe {
+			ort::sys::OrtGetApiBase()
+				.as_ref()
+				.and_then(|base| base.GetVersionString)
+				.map(|get_version_string| std::ffi::CStr::from_ptr(get_version_string()).to_string_lossy().into_owned())
+		};
+		if let Some(version) = version {
+			return version;
+		}
+	}
+	// ONNX Runtime's API version is its minor version
+	format!("1.{}", ort::sys::ORT_API_VERSION)
+}
+
+// protobuf field numbers from `onnx.proto`
+const MODEL_IR_VERSION: u64 = 1;
+const MODEL_PRODUCER_NAME: u64 = 2;
+const MODEL_PRODUCER_VERSION: u64 = 3;
+const MODEL_OPSET_IMPORT: u64 = 8;
+const MODEL_GRAPH: u64 = 7;
+const OPSET_DOMAIN: u64 = 1;
+const OPSET_VERSION: u64 = 2;
+const GRAPH_INITIALIZER: u64 = 5;
+const TENSOR_EXTERNAL_DATA: u64 = 13;
+const ENTRY_KEY: u64 = 1;
+const ENTRY_VALUE: u64 = 2;
+
+/// Length-delimited header fields longer than this are considered malformed.
+const MAX_HEADER_FIELD_LEN: u64 = 1 << 16;
+
+/// An operator set imported by an ONNX model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpsetImport {
+	/// The operator set's domain. The default `ai.onnx` domain is normalized from the empty string.
+	pub domain: String,
+	/// The operator set version.
+	pub version: u64
+}
+
+/// The header of an ONNX model, i.e. the model's IR version & the operator sets it imports.
+///
+/// Only the header is parsed: the graph is skipped over without being read, so inspecting even multi-gigabyte models
+/// is fast.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnnxModelInfo {
+	/// The ONNX IR version of the model.
+	pub ir_version: u64,
+	/// The operator sets imported by the model.
+	pub opset_imports: Vec<OpsetImport>,
+	/// The name of the tool that produced the model, e.g. `pytorch`.
+	pub producer_name: String,
+	/// The version of the tool that produced the model.
+	pub producer_version: String
+}
+
+impl OnnxModelInfo {
+	/// Reads the header of the ONNX model at `path`.
+	pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+		Self::from_reader(BufReader::new(File::open(path)?))
+	}
+
+	/// Reads the header of an in-memory ONNX model.
+	pub fn from_bytes(model: &[u8]) -> anyhow::Result<Self> {
+		Self::from_reader(Cursor::new(model))
+	}
+
+	/// Reads the header of an ONNX model, seeking past all other top-level fields (most importantly the graph).
+	pub fn from_reader<R: Read + Seek>(mut reader: R) -> anyhow::Result<Self> {
+		let len = reader.seek(SeekFrom::End(0))?;
+		reader.seek(SeekFrom::Start(0))?;
+
+		let mut info = OnnxModelInfo {
+			ir_version: 0,
+			opset_imports: Vec::new(),
+			producer_name: String::new(),
+			producer_version: String::new()
+		};
+		let mut pos = 0;
+		while pos < len {
+			match next_field(&mut reader, &mut pos, len)? {
+				(MODEL_IR_VERSION, StreamedField::Varint(value)) => info.ir_version = value,
+				(field @ (MODEL_PRODUCER_NAME | MODEL_PRODUCER_VERSION | MODEL_OPSET_IMPORT), StreamedField::Bytes(field_len)) => {
+					let buf = read_header_field(&mut reader, &mut pos, field, field_len)?;
+					match field {
+						MODEL_PRODUCER_NAME => info.producer_name = String::from_utf8_lossy(&buf).into_owned(),
+						MODEL_PRODUCER_VERSION => info.producer_version = String::from_utf8_lossy(&buf).into_owned(),
+						_ => info.opset_imports.push(parse_opset_import(&buf)?)
+					}
+				}
+				(_, StreamedField::Bytes(field_len)) => skip_field(&mut reader, &mut pos, field_len)?,
+				_ => {}
+			}
+		}
+		if info.ir_version == 0 {
+			anyhow::bail!("malformed ONNX model: missing IR version");
+		}
+		Ok(info)
+	}
+
+	/// Returns the version of the given operator set domain imported by the model, if any. The default domain can be
+	/// given as either `ai.onnx` or the empty string.
+	pub fn opset_version(&self, domain: &str) -> Option<u64> {
+		let domain = normalize_domain(domain);
+		self.opset_imports.iter().find(|opset| opset.domain == domain).map(|opset| opset.version)
+	}
+
+	/// Compares the model's IR version & opset imports against the versions supported by the linked ONNX Runtime
+	/// library ([`OrtSupport::linked`]).
+	pub fn compatibility_warnings(&self) -> Vec<OnnxCompatibilityWarning> {
+		self.compatibility_warnings_for(OrtSupport::linked())
+	}
+
+	/// Compares the model's IR version & opset imports against the versions supported by `ort`.
+	pub fn compatibility_warnings_for(&self, ort: &OrtSupport) -> Vec<OnnxCompatibilityWarning> {
+		let mut warnings = Vec::new();
+		if !ort.ir_versions.contains(&self.ir_version) {
+			warnings.push(OnnxCompatibilityWarning::UnsupportedIrVersion {
+				ir_version: self.ir_version,
+				supported: ort.ir_versions.clone()
+			});
+		}
+		for opset in &self.opset_imports {
+			match ort.opsets.iter().find(|(domain, _)| *domain == opset.domain) {
+				Some((_, supported)) if supported.contains(&opset.version) => {}
+				Some((_, supported)) => warnings.push(OnnxCompatibilityWarning::UnsupportedOpset {
+					domain: opset.domain.clone(),
+					version: opset.version,
+					supported: supported.clone()
+				}),
+				None => warnings.push(OnnxCompatibilityWarning::UnknownDomain {
+					domain: opset.domain.clone(),
+					version: opset.version
+				})
+			}
+		}
+		warnings
+	}
+}
+
+impl fmt::Display for OnnxModelInfo {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "IR version {}, opsets ", self.ir_version)?;
+		if self.opset_imports.is_empty() {
+			f.write_str("(none)")?;
+		}
+		for (i, opset) in self.opset_imports.iter().enumerate() {
+			write!(f, "{}{} {}", if i > 0 { ", " } else { "" }, opset.domain, opset.version)?;
+		}
+		if !self.producer_name.is_empty() {
+			write!(f, ", produced by {} {}", self.producer_name, self.producer_version)?;
+		}
+		Ok(())
+	}
+}
+
+/// A potential incompatibility between an ONNX model & the linked ONNX Runtime version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OnnxCompatibilityWarning {
+	/// The model's header could not be read, e.g. because the file does not exist or is not an ONNX model.
+	Unreadable {
+		/// Why the header could not be read.
+		reason: String
+	},
+	/// The model's IR version is not supported by ONNX Runtime. Models with a newer IR version were usually exported
+	/// with a newer version of the `onnx` package than ONNX Runtime supports.
+	UnsupportedIrVersion {
+		/// The model's IR version.
+		ir_version: u64,
+		/// The IR versions supported by ONNX Runtime.
+		supported: RangeInclusive<u64>
+	},
+	/// The model imports a version of a known operator set that ONNX Runtime doesn't support.
+	UnsupportedOpset {
+		/// The operator set's domain.
+		domain: String,
+		/// The imported version.
+		version: u64,
+		/// The versions of the operator set supported by ONNX Runtime.
+		supported: RangeInclusive<u64>
+	},
+	/// The model imports an operator set ONNX Runtime doesn't provide, which requires custom operators to be
+	/// registered.
+	UnknownDomain {
+		/// The operator set's domain.
+		domain: String,
+		/// The imported version.
+		version: u64
+	}
+}
+
+impl fmt::Display for OnnxCompatibilityWarning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let ort_version = &OrtSupport::linked().version;
+		match self {
+			OnnxCompatibilityWarning::Unreadable { reason } => write!(f, "could not read model header: {reason}"),
+			OnnxCompatibilityWarning::UnsupportedIrVersion { ir_version, supported } => write!(
+				f,
+				"IR version {ir_version} is not supported by ONNX Runtime {ort_version} (supported: {}-{}); re-export the model with an older `onnx` package",
+				supported.start(),
+				supported.end()
+			),
+			OnnxCompatibilityWarning::UnsupportedOpset { domain, version, supported } => write!(
+				f,
+				"opset {domain} {version} is not supported by ONNX Runtime {ort_version} (supported: {}-{}); re-export the model with a supported opset",
+				supported.start(),
+				supported.end()
+			),
+			OnnxCompatibilityWarning::UnknownDomain { domain, version } => {
+				write!(f, "opset {domain} {version} is not provided by ONNX Runtime {ort_version}; the model requires custom operators")
+			}
+		}
+	}
+}
+
+/// The compatibility of a single model file with the linked ONNX Runtime version; see [`ModelCompatibilityReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentCompatibility {
+	/// The name of the pipeline component the model is used as, e.g. `UNet`.
+	pub component: &'static str,
+	/// The path of the model file, or `None` for in-memory models.
+	pub path: Option<PathBuf>,
+	/// The model's header, or `None` if it could not be read.
+	pub info: Option<OnnxModelInfo>,
+	/// Potential incompatibilities, including an [`OnnxCompatibilityWarning::Unreadable`] warning if the header could
+	/// not be read.
+	pub warnings: Vec<OnnxCompatibilityWarning>
+}
+
+impl ComponentCompatibility {
+	/// Inspects the header of the model file at `path`.
+	pub fn inspect_file(component: &'static str, path: impl Into<PathBuf>) -> Self {
+		let path = path.into();
+		Self::from_result(component, Some(path.clone()), OnnxModelInfo::from_file(path))
+	}
+
+	/// Inspects the header of an in-memory model.
+	pub fn inspect_bytes(component: &'static str, model: &[u8]) -> Self {
+		Self::from_result(component, None, OnnxModelInfo::from_bytes(model))
+	}
+
+	fn from_result(component: &'static str, path: Option<PathBuf>, info: anyhow::Result<OnnxModelInfo>) -> Self {
+		match info {
+			Ok(info) => Self {
+				component,
+				path,
+				warnings: info.compatibility_warnings(),
+				info: Some(info)
+			},
+			Err(e) => Self {
+				component,
+				path,
+				info: None,
+				warnings: vec![OnnxCompatibilityWarning::Unreadable { reason: e.to_string() }]
+			}
+		}
+	}
+}
+
+impl fmt::Display for ComponentCompatibility {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.component)?;
+		if let Some(path) = &self.path {
+			write!(f, " ({})", path.display())?;
+		}
+		if let Some(info) = &self.info {
+			write!(f, ": {info}")?;
+		}
+		if self.warnings.is_empty() {
+			f.write_str(" - OK")?;
+		}
+		for warning in &self.warnings {
+			write!(f, "\n  warning: {warning}")?;
+		}
+		Ok(())
+	}
+}
+
+/// A human-readable report of the compatibility of each of a pipeline's model files with the linked ONNX Runtime
+/// version, as returned by [`StableDiffusionPipeline::preflight`](crate::StableDiffusionPipeline::preflight).
+///
+/// The report only compares IR & opset versions, which catches the most common cause of inscrutable ONNX Runtime
+/// errors about unsupported operators: mixing model files converted with different exporter versions. A model
+/// without warnings may still fail to load, e.g. if it uses an operator which is only implemented by some execution
+/// providers.
+///
+/// The [`Display`](fmt::Display) implementation renders the report as text, which is also included in the error
+/// returned when a model fails to load.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelCompatibilityReport {
+	/// The compatibility of each model file.
+	pub components: Vec<ComponentCompatibility>
+}
+
+impl ModelCompatibilityReport {
+	/// Returns all warnings in the report, along with the name of the component each applies to.
+	pub fn warnings(&self) -> impl Iterator<Item = (&'static str, &OnnxCompatibilityWarning)> {
+		self.components
+			.iter()
+			.flat_map(|component| component.warnings.iter().map(move |warning| (component.component, warning)))
+	}
+
+	/// Returns `true` if no component has any warnings.
+	pub fn is_compatible(&self) -> bool {
+		self.warnings().next().is_none()
+	}
+}
+
+impl fmt::Display for ModelCompatibilityReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{SupportedVersions}")?;
+		for component in &self.components {
+			write!(f, "\n- {component}")?;
+		}
+		Ok(())
+	}
+}
+
+/// Formats a load failure of a model, including the model's compatibility report.
+pub(crate) fn describe_load_failure(component: &ComponentCompatibility, error: impl fmt::Display) -> String {
+	format!("failed to load {}: {error}\n\n{SupportedVersions}\n- {component}", component.component)
+}
+
+/// The heading of a compatibility report, listing the supported versions.
+struct SupportedVersions;
+
+impl fmt::Display for SupportedVersions {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let ort = OrtSupport::linked();
+		write!(f, "ONNX compatibility report (ONNX Runtime {} supports IR versions {}-{}", ort.version, ort.ir_versions.start(), ort.ir_versions.end())?;
+		for (domain, versions) in &ort.opsets {
+			write!(f, ", {domain} opsets {}-{}", versions.start(), versions.end())?;
+		}
+		f.write_str("):")
+	}
+}
+
+fn normalize_domain(domain: &str) -> &str {
+	if domain.is_empty() { "ai.onnx" } else { domain }
+}
+
+fn parse_opset_import(buf: &[u8]) -> anyhow::Result<OpsetImport> {
+	let (mut domain, mut version) = (String::new(), 0);
+	for (field, value) in fields(buf, 0..buf.len())? {
+		match (field, value) {
+			(OPSET_DOMAIN, FieldValue::Bytes(r)) => domain = String::from_utf8_lossy(&buf[r]).into_owned(),
+			(OPSET_VERSION, FieldValue::Varint(v)) => version = v,
+			_ => {}
+		}
+	}
+	Ok(OpsetImport {
+		domain: normalize_domain(&domain).to_owned(),
+		version
+	})
+}
+
+/// Returns the external data files the weights of the ONNX model at `path` are stored in (typically for f32 UNets
+/// over 2 GB, which exceed protobuf's size limit), resolved against the model's directory. Each file is listed once.
+///
+/// Only the graph's initializers are inspected, and their inline data is skipped without being read, so this is cheap
+/// even for large models.
+pub(crate) fn external_data_files(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+	let mut reader = BufReader::new(File::open(path)?);
+	let len = reader.seek(SeekFrom::End(0))?;
+	reader.seek(SeekFrom::Start(0))?;
+
+	let mut files = Vec::new();
+	let mut pos = 0;
+	while pos < len {
+		let graph_end = match next_field(&mut reader, &mut pos, len)? {
+			(MODEL_GRAPH, StreamedField::Bytes(graph_len)) => pos + graph_len,
+			(_, StreamedField::Bytes(field_len)) => {
+				skip_field(&mut reader, &mut pos, field_len)?;
+				continue;
+			}
+			_ => continue
+		};
+		while pos < graph_end {
+			let tensor_end = match next_field(&mut reader, &mut pos, graph_end)? {
+				(GRAPH_INITIALIZER, StreamedField::Bytes(tensor_len)) => pos + tensor_len,
+				(_, StreamedField::Bytes(field_len)) => {
+					skip_field(&mut reader, &mut pos, field_len)?;
+					continue;
+				}
+				_ => continue
+			};
+			while pos < tensor_end {
+				match next_field(&mut reader, &mut pos, tensor_end)? {
+					(TENSOR_EXTERNAL_DATA, StreamedField::Bytes(entry_len)) => {
+						let entry = read_header_field(&mut reader, &mut pos, TENSOR_EXTERNAL_DATA, entry_len)?;
+						if let Some(location) = external_data_location(&entry)? {
+							let file = path.parent().unwrap_or_else(|| Path::new("")).join(location);
+							if !files.contains(&file) {
+								files.push(file);
+							}
+						}
+					}
+					(_, StreamedField::Bytes(field_len)) => skip_field(&mut reader, &mut pos, field_len)?,
+					_ => {}
+				}
+			}
+		}
+	}
+	Ok(files)
+}
+
+/// Returns the `location` of a tensor's `external_data` entry, or `None` if the entry is another key.
+fn external_data_location(entry: &[u8]) -> anyhow::Result<Option<String>> {
+	let (mut key, mut value) = (None, None);
+	for (field, field_value) in fields(entry, 0..entry.len())? {
+		match (field, field_value) {
+			(ENTRY_KEY, FieldValue::Bytes(r)) => key = Some(&entry[r]),
+			(ENTRY_VALUE, FieldValue::Bytes(r)) => value = Some(String::from_utf8_lossy(&entry[r]).into_owned()),
+			_ => {}
+		}
+	}
+	Ok(if key == Some(b"location".as_slice()) { value } else { None })
+}
+
+/// A protobuf field read from a stream. Fixed-size values are skipped; length-delimited values must be read or
+/// skipped by the caller.
+enum StreamedField {
+	Varint(u64),
+	/// The length of the value, which follows in the stream.
+	Bytes(u64),
+	Fixed
+}
+
+/// Reads the key of the next field of a message ending at `end` & returns the field number & value.
+fn next_field<R: Read + Seek>(reader: &mut R, pos: &mut u64, end: u64) -> anyhow::Result<(u64, StreamedField)> {
+	let key = read_varint(reader, pos)?;
+	let value = match key & 7 {
+		0 => StreamedField::Varint(read_varint(reader, pos)?),
+		2 => {
+			let field_len = read_varint(reader, pos)?;
+			if *pos + field_len > end {
+				anyhow::bail!("malformed ONNX model: field extends past the end of its message");
+			}
+			StreamedField::Bytes(field_len)
+		}
+		wire_type @ (1 | 5) => {
+			let field_len = if wire_type == 1 { 8 } else { 4 };
+			skip_field(reader, pos, field_len)?;
+			StreamedField::Fixed
+		}
+		wire_type => anyhow::bail!("malformed ONNX model: unsupported protobuf wire type {wire_type}")
+	};
+	Ok((key >> 3, value))
+}
+
+fn skip_field<R: Seek>(reader: &mut R, pos: &mut u64, field_len: u64) -> anyhow::Result<()> {
+	reader.seek(SeekFrom::Current(field_len as i64))?;
+	*pos += field_len;
+	Ok(())
+}
+
+/// Reads a (small) length-delimited field, failing for fields longer than [`MAX_HEADER_FIELD_LEN`].
+fn read_header_field<R: Read>(reader: &mut R, pos: &mut u64, field: u64, field_len: u64) -> anyhow::Result<Vec<u8>> {
+	if field_len > MAX_HEADER_FIELD_LEN {
+		anyhow::bail!("malformed ONNX model: header field {field} is {field_len} bytes long");
+	}
+	let mut buf = vec![0; field_len as usize];
+	reader.read_exact(&mut buf)?;
+	*pos += field_len;
+	Ok(buf)
+}
+
+/// Reads a varint from a stream, advancing `pos` by the number of bytes read.
+fn read_varint<R: Read>(reader: &mut R, pos: &mut u64) -> anyhow::Result<u64> {
+	decode_varint(|| {
+		let mut byte = [0];
+		reader.read_exact(&mut byte).map_err(|e| match e.kind() {
+			io::ErrorKind::UnexpectedEof => anyhow::anyhow!("malformed ONNX model: unexpected end of data"),
+			_ => e.into()
+		})?;
+		*pos += 1;
+		Ok(byte[0])
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::{external_data_files, ComponentCompatibility, ModelCompatibilityReport, OnnxCompatibilityWarning, OnnxModelInfo, OpsetImport, OrtSupport};
+	use crate::session_tracker::model_size;
+
+	fn varint(mut value: u64, out: &mut Vec<u8>) {
+		while value >= 0x80 {
+			out.push((value as u8 & 0x7f) | 0x80);
+			value >>= 7;
+		}
+		out.push(value as u8);
+	}
+
+	fn bytes_field(field: u8, data: &[u8], out: &mut Vec<u8>) {
+		out.push(field << 3 | 2);
+		varint(data.len() as u64, out);
+		out.extend_from_slice(data);
+	}
+
+	fn opset(domain: &str, version: u64) -> Vec<u8> {
+		let mut opset = Vec::new();
+		if !domain.is_empty() {
+			bytes_field(1, domain.as_bytes(), &mut opset);
+		}
+		opset.push(2 << 3);
+		varint(version, &mut opset);
+		opset
+	}
+
+	/// Builds a model header like PyTorch exports it, with the opset imports after a (dummy) graph.
+	fn model(ir_version: u64, opsets: &[(&str, u64)]) -> Vec<u8> {
+		let mut model = vec![1 << 3];
+		varint(ir_version, &mut model);
+		bytes_field(2, b"pytorch", &mut model);
+		bytes_field(3, b"1.13.1", &mut model);
+		bytes_field(7, &vec![0xaa; 300], &mut model);
+		for (domain, version) in opsets {
+			bytes_field(8, &opset(domain, *version), &mut model);
+		}
+		model
+	}
+
+	#[test]
+	fn reads_header_after_graph() {
+		let info = OnnxModelInfo::from_bytes(&model(8, &[("", 15), ("com.microsoft", 1)])).unwrap();
+		assert_eq!(info.ir_version, 8);
+		assert_eq!(info.opset_imports, vec![
+			OpsetImport { domain: "ai.onnx".to_owned(), version: 15 },
+			OpsetImport { domain: "com.microsoft".to_owned(), version: 1 }
+		]);
+		assert_eq!(info.producer_name, "pytorch");
+		assert_eq!(info.opset_version(""), Some(15));
+		assert!(info.compatibility_warnings_for(&OrtSupport::for_version("1.14.1")).is_empty());
+	}
+
+	#[test]
+	fn warns_about_unsupported_versions() {
+		let info = OnnxModelInfo::from_bytes(&model(9, &[("", 19), ("org.example", 1)])).unwrap();
+		let warnings = info.compatibility_warnings_for(&OrtSupport::for_version("1.14.1"));
+		assert_eq!(warnings.len(), 3);
+		assert_eq!(warnings[0], OnnxCompatibilityWarning::UnsupportedIrVersion { ir_version: 9, supported: 3..=8 });
+		assert!(matches!(&warnings[1], OnnxCompatibilityWarning::UnsupportedOpset { version: 19, .. }));
+		assert!(matches!(&warnings[2], OnnxCompatibilityWarning::UnknownDomain { domain, .. } if domain == "org.example"));
+	}
+
+	#[test]
+	fn supported_versions_follow_runtime_version() {
+		let info = OnnxModelInfo::from_bytes(&model(9, &[("", 19)])).unwrap();
+		assert!(info.compatibility_warnings_for(&OrtSupport::for_version("1.16.3")).is_empty());
+
+		let ort = OrtSupport::for_version("1.17.0");
+		assert_eq!((ort.ir_versions.clone(), ort.opsets[0].clone()), (3..=9, ("ai.onnx", 7..=20)));
+		// unknown releases fall back to the nearest known one
+		assert_eq!(OrtSupport::for_version("1.99.0").ir_versions, OrtSupport::for_version("1.18.0").ir_versions);
+		assert_eq!(OrtSupport::for_version("1.2.0").ir_versions, OrtSupport::for_version("1.6.0").ir_versions);
+
+		let linked = OrtSupport::linked();
+		assert!(linked.version.starts_with("1."), "{}", linked.version);
+		assert_eq!(linked.ir_versions, OrtSupport::for_version(linked.version.clone()).ir_versions);
+	}
+
+	/// Builds an initializer with the given inline data & `external_data` entries.
+	fn initializer(raw_data: &[u8], external_data: &[(&str, &str)]) -> Vec<u8> {
+		let mut tensor = Vec::new();
+		bytes_field(8, b"weight", &mut tensor);
+		bytes_field(9, raw_data, &mut tensor);
+		for (key, value) in external_data {
+			let mut entry = Vec::new();
+			bytes_field(1, key.as_bytes(), &mut entry);
+			bytes_field(2, value.as_bytes(), &mut entry);
+			bytes_field(13, &entry, &mut tensor);
+		}
+		tensor
+	}
+
+	#[test]
+	fn finds_external_data_files() -> anyhow::Result<()> {
+		let dir = std::env::temp_dir().join("pyke-diffusers-external-data");
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir)?;
+
+		let mut graph = Vec::new();
+		bytes_field(5, &initializer(&[0; 16], &[]), &mut graph);
+		let inline_graph = graph.clone();
+		for (location, offset) in [("weights.bin", "0"), ("weights.bin", "64"), ("other.bin", "0")] {
+			bytes_field(5, &initializer(&[], &[("offset", offset), ("location", location)]), &mut graph);
+		}
+		let mut bytes = vec![1 << 3, 8];
+		bytes_field(7, &graph, &mut bytes);
+		let path = dir.join("unet.onnx");
+		fs::write(&path, &bytes)?;
+		fs::write(dir.join("weights.bin"), [0_u8; 128])?;
+		fs::write(dir.join("other.bin"), [0_u8; 32])?;
+
+		// each file is listed & counted once
+		assert_eq!(external_data_files(&path)?, [dir.join("weights.bin"), dir.join("other.bin")]);
+		assert_eq!(model_size(&path)?, bytes.len() as u64 + 128 + 32);
+
+		fs::remove_file(dir.join("other.bin"))?;
+		let error = model_size(&path).unwrap_err();
+		assert!(error.to_string().contains("other.bin"), "{error}");
+
+		// models without external data are their own size
+		let mut bytes = vec![1 << 3, 8];
+		bytes_field(7, &inline_graph, &mut bytes);
+		fs::write(&path, &bytes)?;
+		assert!(external_data_files(&path)?.is_empty());
+		assert_eq!(model_size(&path)?, bytes.len() as u64);
+		Ok(())
+	}
+
+	#[test]
+	fn truncated_model() {
+		let mut bytes = model(8, &[("", 15)]);
+		bytes.truncate(40);
+		assert!(OnnxModelInfo::from_bytes(&bytes).is_err());
+
+		let report = ModelCompatibilityReport {
+			components: vec![ComponentCompatibility::inspect_bytes("UNet", &bytes)]
+		};
+		assert!(!report.is_compatible());
+		assert!(report.to_string().contains("\n- UNet\n  warning: could not read model header"));
+	}
+}
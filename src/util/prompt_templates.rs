@@ -0,0 +1,437 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Expansion of prompt templates with variables & wildcards, for generating batches of prompts.
+//!
+//! Templates use the following syntax:
+//! - `{name}` is replaced by the value of the variable `name`, set with [`TemplateContext::with_variable`].
+//! - `__name__` is replaced by an entry of the wordlist `name`, set with [`TemplateContext::with_wordlist`] or loaded
+//!   from a file with [`TemplateContext::with_wordlist_file`]. Which entries are used depends on the context's
+//!   [`WildcardSelection`].
+//! - `\{`, `\}`, & `\_` insert a literal `{`, `}`, & `_`. Other backslashes are kept as-is, so prompt weighting
+//!   escapes like `\(` pass through untouched.
+//!
+//! Names may contain ASCII letters, digits, `_`, `-`, & `/`. Variable values & wordlist entries are templates
+//! themselves, so wildcards can refer to other wildcards & variables, up to 16 levels deep.
+//!
+//! ```
+//! # fn main() -> anyhow::Result<()> {
+//! use pyke_diffusers::prompt_templates::{expand, TemplateContext};
+//!
+//! let context = TemplateContext::default()
+//! 	.with_variable("style", "an oil painting")
+//! 	.with_wordlist("animal", ["fox", "__color__ wolf"])
+//! 	.with_wordlist("color", ["grey", "white"]);
+//! let prompts = expand("{style} of a __animal__", &context)?;
+//! assert_eq!(
+//! 	prompts.iter().map(|prompt| prompt[0].as_str()).collect::<Vec<_>>(),
+//! 	["an oil painting of a fox", "an oil painting of a grey wolf", "an oil painting of a white wolf"]
+//! );
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::HashMap, fs, path::Path};
+
+use ndarray_rand::rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::Prompt;
+
+/// The maximum number of levels variables & wildcards can be nested.
+const MAX_NESTING_DEPTH: usize = 16;
+
+/// The default maximum number of prompts a template is expanded to; see [`TemplateContext::max_prompts`].
+pub const DEFAULT_MAX_PROMPTS: usize = 1000;
+
+/// How entries are chosen from the wordlists of a template's wildcards.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WildcardSelection {
+	/// Expands to every combination of wordlist entries, varying the last wildcard fastest. Each occurrence of a
+	/// wildcard is expanded independently. **This is the default.**
+	#[default]
+	Combinatorial,
+	/// Expands to `count` prompts, each choosing a random entry for every wildcard occurrence. Entries are chosen by an
+	/// RNG seeded with `seed`, so the same seed, template, & wordlists always expand to the same prompts. Prompts may
+	/// repeat.
+	Random { seed: u64, count: usize }
+}
+
+/// The variables & wordlists a template is expanded with.
+#[derive(Debug, Clone)]
+pub struct TemplateContext {
+	/// Values of `{name}` variables.
+	pub variables: HashMap<String, String>,
+	/// Wordlists of `__name__` wildcards.
+	pub wordlists: HashMap<String, Vec<String>>,
+	/// How wordlist entries are chosen. Defaults to [`WildcardSelection::Combinatorial`].
+	pub selection: WildcardSelection,
+	/// The maximum number of prompts to expand to. Expansions beyond this are dropped with a warning. Defaults to
+	/// [`DEFAULT_MAX_PROMPTS`].
+	pub max_prompts: usize
+}
+
+impl Default for TemplateContext {
+	fn default() -> Self {
+		Self {
+			variables: HashMap::new(),
+			wordlists: HashMap::new(),
+			selection: WildcardSelection::default(),
+			max_prompts: DEFAULT_MAX_PROMPTS
+		}
+	}
+}
+
+impl TemplateContext {
+	/// Sets the value of the variable `name`, replacing `{name}` in templates.
+	pub fn with_variable(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+		self.variables.insert(name.into(), value.into());
+		self
+	}
+
+	/// Sets the wordlist of the wildcard `name`, replacing `__name__` in templates.
+	pub fn with_wordlist<I, S>(mut self, name: impl Into<String>, entries: I) -> Self
+	where
+		I: IntoIterator<Item = S>,
+		S: Into<String>
+	{
+		self.wordlists.insert(name.into(), entries.into_iter().map(Into::into).collect());
+		self
+	}
+
+	/// Loads the wordlist of the wildcard `name` from a text file with one entry per line. Leading & trailing whitespace
+	/// is trimmed, and empty lines & lines starting with `#` are skipped.
+	pub fn with_wordlist_file(self, name: impl Into<String>, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+		let path = path.as_ref();
+		let text = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("failed to read wordlist `{}`: {e}", path.display()))?;
+		Ok(self.with_wordlist(name, parse_wordlist(&text)))
+	}
+
+	/// Loads every `.txt` file in `dir` as a wordlist named after the file, e.g. `animal.txt` as `__animal__`. See
+	/// [`TemplateContext::with_wordlist_file`] for the file format.
+	pub fn with_wordlist_dir(mut self, dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+		for entry in fs::read_dir(dir)? {
+			let path = entry?.path();
+			if path.extension().and_then(|extension| extension.to_str()) != Some("txt") {
+				continue;
+			}
+			if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+				self = self.with_wordlist_file(name.to_string(), &path)?;
+			}
+		}
+		Ok(self)
+	}
+
+	/// Chooses `count` random combinations of wordlist entries, seeded with `seed`; see [`WildcardSelection::Random`].
+	pub fn with_random_selection(mut self, seed: u64, count: usize) -> Self {
+		self.selection = WildcardSelection::Random { seed, count };
+		self
+	}
+
+	/// Sets the maximum number of prompts to expand to; see [`TemplateContext::max_prompts`].
+	pub fn with_max_prompts(mut self, max_prompts: usize) -> Self {
+		self.max_prompts = max_prompts;
+		self
+	}
+
+	fn variable(&self, name: &str) -> anyhow::Result<&str> {
+		self.variables
+			.get(name)
+			.map(String::as_str)
+			.ok_or_else(|| anyhow::anyhow!("template variable `{{{name}}}` is not set"))
+	}
+
+	fn wordlist(&self, name: &str) -> anyhow::Result<&[String]> {
+		match self.wordlists.get(name) {
+			Some(entries) if entries.is_empty() => anyhow::bail!("wordlist `__{name}__` is empty"),
+			Some(entries) => Ok(entries),
+			None => anyhow::bail!("no wordlist for wildcard `__{name}__`")
+		}
+	}
+}
+
+/// Expands a prompt template into one single-prompt [`Prompt`] per expansion, in the order given by the context's
+/// [`WildcardSelection`].
+///
+/// # Errors
+/// Returns an error if the template (or a variable or wordlist entry it refers to) refers to an unset variable or
+/// wordlist, contains an unescaped `{` or `}` that isn't part of a variable, or nests more than 16 levels deep.
+pub fn expand(template: &str, context: &TemplateContext) -> anyhow::Result<Vec<Prompt>> {
+	let prompts: Vec<String> = match context.selection {
+		WildcardSelection::Combinatorial => expand_combinations(context, template, 0, context.max_prompts.saturating_add(1))?,
+		WildcardSelection::Random { seed, count } => {
+			let mut rng = StdRng::seed_from_u64(seed);
+			let count = count.min(context.max_prompts.saturating_add(1));
+			(0..count).map(|_| expand_random(context, template, 0, &mut rng)).collect::<anyhow::Result<_>>()?
+		}
+	};
+	let mut prompts = prompts.into_iter().map(Prompt::from).collect::<Vec<_>>();
+	if prompts.len() > context.max_prompts {
+		tracing::warn!("prompt template expands to more than {} prompts; the remaining prompts were dropped", context.max_prompts);
+		prompts.truncate(context.max_prompts);
+	}
+	Ok(prompts)
+}
+
+/// Expands a prompt template like [`expand`], grouping the expansions into batched [`Prompt`]s of up to `batch_size`
+/// prompts each, e.g. for [`StableDiffusionPipeline::txt2img_to_files`](crate::StableDiffusionPipeline::txt2img_to_files).
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// # use pyke_diffusers::{StableDiffusionPipeline, EulerDiscreteScheduler, StableDiffusionOptions, StableDiffusionTxt2ImgOptions, OrtEnvironment};
+/// use pyke_diffusers::prompt_templates::{expand_batched, TemplateContext};
+/// # let environment = OrtEnvironment::default().into_arc();
+/// # let mut scheduler = EulerDiscreteScheduler::default();
+/// let pipeline = StableDiffusionPipeline::new(&environment, "./stable-diffusion-v1-5/", StableDiffusionOptions::default())?;
+/// let context = TemplateContext::default().with_wordlist_dir("wordlists")?;
+/// for (i, batch) in expand_batched("a photo of __animal__ in __place__", &context, 4)?.into_iter().enumerate() {
+/// 	let options = StableDiffusionTxt2ImgOptions::default().with_seed(42);
+/// 	pipeline.txt2img_to_files(batch, &mut scheduler, options, "out", &format!("{i}-{{index}}-{{prompt_slug}}"))?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn expand_batched(template: &str, context: &TemplateContext, batch_size: usize) -> anyhow::Result<Vec<Prompt>> {
+	if batch_size == 0 {
+		anyhow::bail!("`batch_size` must be at least 1");
+	}
+	let prompts = expand(template, context)?.into_iter().flat_map(|prompt| prompt.0).collect::<Vec<_>>();
+	Ok(prompts.chunks(batch_size).map(|chunk| Prompt(chunk.to_vec())).collect())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment<'t> {
+	Text(String),
+	Variable(&'t str),
+	Wildcard(&'t str)
+}
+
+fn is_valid_name(name: &str) -> bool {
+	!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '/'))
+}
+
+fn parse(template: &str) -> anyhow::Result<Vec<Segment<'_>>> {
+	let mut segments = Vec::new();
+	let mut text = String::new();
+	let mut rest = template;
+	while let Some(c) = rest.chars().next() {
+		let position = template.len() - rest.len();
+		match c {
+			'\\' => {
+				if let Some(escaped @ ('{' | '}' | '_')) = rest[1..].chars().next() {
+					text.push(escaped);
+					rest = &rest[2..];
+					continue;
+				}
+			}
+			'{' => {
+				let name = match rest.find('}') {
+					Some(end) if is_valid_name(&rest[1..end]) => &rest[1..end],
+					_ => anyhow::bail!("invalid variable at byte {position} of template `{template}`; escape literal braces as `\\{{` & `\\}}`")
+				};
+				if !text.is_empty() {
+					segments.push(Segment::Text(std::mem::take(&mut text)));
+				}
+				segments.push(Segment::Variable(name));
+				rest = &rest[name.len() + 2..];
+				continue;
+			}
+			'}' => anyhow::bail!("unmatched `}}` at byte {position} of template `{template}`; escape literal braces as `\\}}`"),
+			'_' => {
+				if let Some(after) = rest.strip_prefix("__") {
+					if let Some(name) = after.find("__").map(|end| &after[..end]) {
+						if is_valid_name(name) && !name.starts_with('_') {
+							if !text.is_empty() {
+								segments.push(Segment::Text(std::mem::take(&mut text)));
+							}
+							segments.push(Segment::Wildcard(name));
+							rest = &after[name.len() + 2..];
+							continue;
+						}
+					}
+				}
+			}
+			_ => {}
+		}
+		text.push(c);
+		rest = &rest[c.len_utf8()..];
+	}
+	if !text.is_empty() {
+		segments.push(Segment::Text(text));
+	}
+	Ok(segments)
+}
+
+fn check_depth(depth: usize) -> anyhow::Result<()> {
+	if depth > MAX_NESTING_DEPTH {
+		anyhow::bail!("prompt template is nested more than {MAX_NESTING_DEPTH} levels deep; does a variable or wordlist refer to itself?");
+	}
+	Ok(())
+}
+
+/// Expands every combination of a template, stopping after `limit` combinations. Because the last segment varies
+/// fastest, the first `limit` combinations only depend on the first `limit` alternatives of each segment, so every
+/// intermediate list can be truncated to `limit`.
+fn expand_combinations(context: &TemplateContext, template: &str, depth: usize, limit: usize) -> anyhow::Result<Vec<String>> {
+	check_depth(depth)?;
+	let mut prompts = vec![String::new()];
+	for segment in parse(template)? {
+		let alternatives = match segment {
+			Segment::Text(text) => vec![text],
+			Segment::Variable(name) => expand_combinations(context, context.variable(name)?, depth + 1, limit)?,
+			Segment::Wildcard(name) => {
+				let mut alternatives = Vec::new();
+				for entry in context.wordlist(name)? {
+					alternatives.extend(expand_combinations(context, entry, depth + 1, limit - alternatives.len())?);
+					if alternatives.len() >= limit {
+						break;
+					}
+				}
+				alternatives
+			}
+		};
+		prompts = prompts
+			.iter()
+			.flat_map(|prompt| alternatives.iter().map(move |alternative| format!("{prompt}{alternative}")))
+			.take(limit)
+			.collect();
+	}
+	Ok(prompts)
+}
+
+fn expand_random(context: &TemplateContext, template: &str, depth: usize, rng: &mut StdRng) -> anyhow::Result<String> {
+	check_depth(depth)?;
+	let mut prompt = String::new();
+	for segment in parse(template)? {
+		match segment {
+			Segment::Text(text) => prompt.push_str(&text),
+			Segment::Variable(name) => prompt.push_str(&expand_random(context, context.variable(name)?, depth + 1, rng)?),
+			Segment::Wildcard(name) => {
+				let entries = context.wordlist(name)?;
+				let entry = &entries[rng.gen_range(0..entries.len())];
+				prompt.push_str(&expand_random(context, entry, depth + 1, rng)?);
+			}
+		}
+	}
+	Ok(prompt)
+}
+
+fn parse_wordlist(text: &str) -> Vec<String> {
+	text.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(str::to_string)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{expand, expand_batched, parse, parse_wordlist, Segment, TemplateContext};
+
+	fn expand_strings(template: &str, context: &TemplateContext) -> anyhow::Result<Vec<String>> {
+		Ok(expand(template, context)?.into_iter().map(|prompt| prompt[0].clone()).collect())
+	}
+
+	#[test]
+	fn parses_segments() -> anyhow::Result<()> {
+		assert_eq!(
+			parse("a {style} photo of __hair_color__ fox")?,
+			[
+				Segment::Text("a ".to_string()),
+				Segment::Variable("style"),
+				Segment::Text(" photo of ".to_string()),
+				Segment::Wildcard("hair_color"),
+				Segment::Text(" fox".to_string())
+			]
+		);
+		assert_eq!(parse("__a/b____c__")?, [Segment::Wildcard("a/b"), Segment::Wildcard("c")]);
+		Ok(())
+	}
+
+	#[test]
+	fn escapes() -> anyhow::Result<()> {
+		assert_eq!(parse(r"\{style\} \_\_animal__")?, [Segment::Text("{style} __animal__".to_string())]);
+		// non-template escapes are left for prompt weighting
+		assert_eq!(parse(r"\(fox\) \\")?, [Segment::Text(r"\(fox\) \\".to_string())]);
+		// underscores that don't form a wildcard are literal
+		assert_eq!(parse("snake_case __ not a wildcard__")?, [Segment::Text("snake_case __ not a wildcard__".to_string())]);
+		Ok(())
+	}
+
+	#[test]
+	fn rejects_unescaped_braces() {
+		assert!(parse("a {style photo").is_err());
+		assert!(parse("a {} photo").is_err());
+		assert!(parse("a {two words} photo").is_err());
+		assert!(parse("a style} photo").is_err());
+	}
+
+	#[test]
+	fn expands_nested_wildcards() -> anyhow::Result<()> {
+		let context = TemplateContext::default()
+			.with_variable("subject", "__size__ __animal__")
+			.with_wordlist("size", ["small", "large"])
+			.with_wordlist("animal", ["fox", "{color} wolf"])
+			.with_variable("color", "grey");
+		assert_eq!(
+			expand_strings("photo of a {subject}", &context)?,
+			["photo of a small fox", "photo of a small grey wolf", "photo of a large fox", "photo of a large grey wolf"]
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn caps_combinations() -> anyhow::Result<()> {
+		let context = TemplateContext::default().with_wordlist("digit", (0..10).map(|d| d.to_string())).with_max_prompts(15);
+		let prompts = expand_strings("__digit____digit____digit__", &context)?;
+		assert_eq!(prompts.len(), 15);
+		assert_eq!(prompts[..3], ["000", "001", "002"]);
+		assert_eq!(prompts[14], "014");
+		Ok(())
+	}
+
+	#[test]
+	fn random_selection_is_reproducible() -> anyhow::Result<()> {
+		let context = TemplateContext::default()
+			.with_wordlist("animal", ["fox", "wolf", "cat", "owl"])
+			.with_random_selection(42, 8);
+		let prompts = expand_strings("a __animal__ and a __animal__", &context)?;
+		assert_eq!(prompts.len(), 8);
+		assert_eq!(prompts, expand_strings("a __animal__ and a __animal__", &context)?);
+		assert_ne!(prompts, expand_strings("a __animal__ and a __animal__", &context.clone().with_random_selection(43, 8))?);
+		Ok(())
+	}
+
+	#[test]
+	fn rejects_unknown_and_recursive_references() {
+		assert!(expand("a __animal__", &TemplateContext::default()).is_err());
+		assert!(expand("a {style}", &TemplateContext::default()).is_err());
+		assert!(expand("a __animal__", &TemplateContext::default().with_wordlist("animal", Vec::<String>::new())).is_err());
+		assert!(expand("__loop__", &TemplateContext::default().with_wordlist("loop", ["a __loop__"])).is_err());
+	}
+
+	#[test]
+	fn batches_expansions() -> anyhow::Result<()> {
+		let context = TemplateContext::default().with_wordlist("animal", ["fox", "wolf", "cat"]);
+		let batches = expand_batched("a __animal__", &context, 2)?;
+		assert_eq!(batches.len(), 2);
+		assert_eq!(*batches[0], ["a fox", "a wolf"]);
+		assert_eq!(*batches[1], ["a cat"]);
+		Ok(())
+	}
+
+	#[test]
+	fn wordlist_files() {
+		assert_eq!(parse_wordlist("# animals\nfox\n\n  grey wolf  \r\n"), ["fox", "grey wolf"]);
+	}
+}
@@ -0,0 +1,78 @@
+// Copyright 2022-2023 pyke.io
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal reader for the protobuf wire format, used to inspect ONNX models without a full ONNX dependency.
+
+use std::ops::Range;
+
+pub(crate) enum FieldValue {
+	Varint(u64),
+	Bytes(Range<usize>),
+	Fixed
+}
+
+/// Parses the fields of the protobuf message in `buf[range]`. Length-delimited values are returned as absolute byte
+/// ranges into `buf`.
+pub(crate) fn fields(buf: &[u8], range: Range<usize>) -> anyhow::Result<Vec<(u64, FieldValue)>> {
+	let mut fields = Vec::new();
+	let mut pos = range.start;
+	while pos < range.end {
+		let key = read_varint(buf, &mut pos)?;
+		let value = match key & 7 {
+			0 => FieldValue::Varint(read_varint(buf, &mut pos)?),
+			1 => {
+				pos += 8;
+				FieldValue::Fixed
+			}
+			2 => {
+				let len = read_varint(buf, &mut pos)? as usize;
+				let start = pos;
+				pos += len;
+				FieldValue::Bytes(start..pos)
+			}
+			5 => {
+				pos += 4;
+				FieldValue::Fixed
+			}
+			wire_type => anyhow::bail!("malformed ONNX model: unsupported protobuf wire type {wire_type}")
+		};
+		if pos > range.end {
+			anyhow::bail!("malformed ONNX model: field extends past the end of its message");
+		}
+		fields.push((key >> 3, value));
+	}
+	Ok(fields)
+}
+
+pub(crate) fn read_varint(buf: &[u8], pos: &mut usize) -> anyhow::Result<u64> {
+	decode_varint(|| {
+		let byte = *buf.get(*pos).ok_or_else(|| anyhow::anyhow!("malformed ONNX model: unexpected end of data"))?;
+		*pos += 1;
+		Ok(byte)
+	})
+}
+
+/// Decodes a varint from the bytes returned by `next_byte`, so that both in-memory messages & streamed files can share
+/// the decoder.
+pub(crate) fn decode_varint(mut next_byte: impl FnMut() -> anyhow::Result<u8>) -> anyhow::Result<u64> {
+	let mut value = 0;
+	for shift in (0..64).step_by(7) {
+		let byte = next_byte()?;
+		value |= ((byte & 0x7f) as u64) << shift;
+		if byte & 0x80 == 0 {
+			return Ok(value);
+		}
+	}
+	anyhow::bail!("malformed ONNX model: varint is too long")
+}
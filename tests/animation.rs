@@ -0,0 +1,86 @@
+use pyke_diffusers::{
+	AnimationKeyframe, AnimationSpec, EulerDiscreteScheduler, GenerationLimit, GenerationLimits, LimitExceeded, SchedulerOptimizedDefaults,
+	StableDiffusionOptions, StableDiffusionTxt2ImgOptions
+};
+
+use crate::common;
+
+fn spec() -> AnimationSpec {
+	AnimationSpec::new(vec![AnimationKeyframe::new("photo of a red fox", 42), AnimationKeyframe::new("photo of a grey wolf", 1337).with_guidance_scale(5.0)])
+		.with_frames_between(vec![2])
+		.with_size(64, 64)
+		.with_steps(2)
+}
+
+#[test]
+fn animations_are_deterministic() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let spec = spec();
+	assert_eq!(spec.frame_count(), Some(4));
+	let frames = pipeline.animate(&mut scheduler, &spec)?;
+	assert_eq!(frames.len(), 4);
+	let blends = frames.iter().map(|frame| frame.info.blend).collect::<Vec<_>>();
+	assert!(blends.windows(2).all(|pair| pair[0] < pair[1]), "{blends:?}");
+	assert_eq!((blends[0], blends[3]), (0.0, 1.0));
+	assert_eq!(frames.iter().map(|frame| frame.info.seed).collect::<Vec<_>>(), [42, 42, 1337, 1337]);
+	assert_eq!((frames[0].info.guidance_scale, frames[3].info.guidance_scale), (7.5, 5.0));
+
+	let again = pipeline.animate(&mut scheduler, &spec)?;
+	for (frame, again) in frames.iter().zip(&again) {
+		assert_eq!(frame.info, again.info);
+		assert_eq!(frame.image.to_rgb8(), again.image.to_rgb8());
+	}
+	assert_ne!(frames[0].image.to_rgb8(), frames[1].image.to_rgb8());
+
+	// keyframes are rendered like plain generations
+	let keyframe = StableDiffusionTxt2ImgOptions::default()
+		.with_size(64, 64)
+		.with_steps(2)
+		.with_seed(1337)
+		.with_guidance_scale(5.0)
+		.with_prompt("photo of a grey wolf")
+		.run(&pipeline, &mut scheduler)?;
+	assert_eq!(frames[3].image.to_rgb8(), keyframe[0].to_rgb8());
+	Ok(())
+}
+
+#[test]
+fn callback_streams_frames() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let mut streamed = Vec::new();
+	let frames = pipeline.animate_with_callback(&mut scheduler, &spec(), |frame| {
+		streamed.push(frame.info.index);
+		frame.info.index < 1
+	})?;
+	assert_eq!((frames.len(), streamed), (2, vec![0, 1]));
+
+	assert!(pipeline.animate(&mut scheduler, &spec().with_frames_between(vec![1, 2])).is_err());
+	Ok(())
+}
+
+#[test]
+fn untrusted_specs_are_bounded() -> anyhow::Result<()> {
+	let pipeline = common::pipeline_with(StableDiffusionOptions::default().with_limits(GenerationLimits::unlimited().with_max_frames(4)))?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let exceeded = |spec: &AnimationSpec, scheduler: &mut EulerDiscreteScheduler| {
+		let err = pipeline.animate(scheduler, spec).unwrap_err();
+		err.downcast_ref::<LimitExceeded>().unwrap_or_else(|| panic!("not a limit error: {err}")).limit
+	};
+
+	assert_eq!(pipeline.animate(&mut scheduler, &spec())?.len(), 4);
+	assert_eq!(exceeded(&spec().with_frames_between(vec![3]), &mut scheduler), GenerationLimit::Frames);
+
+	// a deserialized spec whose frame count overflows is rejected instead of panicking or allocating every frame
+	let unlimited = common::pipeline()?;
+	let mut spec: AnimationSpec = serde_json::from_value(serde_json::to_value(spec())?)?;
+	for frames_between in [usize::MAX, usize::MAX - 1] {
+		spec.frames_between = vec![frames_between];
+		assert_eq!(spec.frame_count(), None);
+		assert!(unlimited.animate(&mut scheduler, &spec).is_err());
+	}
+	Ok(())
+}
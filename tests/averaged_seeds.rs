@@ -0,0 +1,48 @@
+use std::{cell::RefCell, rc::Rc};
+
+use ndarray::Array4;
+use pyke_diffusers::{average_latents, EulerDiscreteScheduler, SchedulerOptimizedDefaults, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions};
+
+use crate::common;
+
+const STEPS: usize = 3;
+
+/// Generates with `seed` & `averaged_seeds`, returning the final latents of each run & the decoded image.
+fn generate(pipeline: &StableDiffusionPipeline, seed: u64, averaged_seeds: &[u64]) -> anyhow::Result<(Vec<Array4<f32>>, Vec<u8>)> {
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let latents = Rc::new(RefCell::new(Vec::new()));
+	let cb_latents = Rc::clone(&latents);
+	let images = StableDiffusionTxt2ImgOptions::default()
+		.with_size(64, 64)
+		.with_prompt("photo of a red fox")
+		.with_steps(STEPS)
+		.with_seed(seed)
+		.with_averaged_seeds(averaged_seeds.iter().copied())
+		.callback_latents(1, move |step, _, step_latents| {
+			if step == STEPS - 1 {
+				cb_latents.borrow_mut().push(step_latents);
+			}
+			true
+		})
+		.run(pipeline, &mut scheduler)?;
+	assert_eq!(images.len(), 1);
+	let latents = latents.borrow().clone();
+	Ok((latents, images[0].to_rgb8().into_raw()))
+}
+
+#[test]
+fn averaged_seeds_decode_mean_latents() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let (first, first_image) = generate(&pipeline, 42, &[])?;
+	let (second, _) = generate(&pipeline, 43, &[])?;
+
+	// each seed is denoised on its own, exactly like a run with that seed
+	let (runs, averaged_image) = generate(&pipeline, 42, &[43])?;
+	assert_eq!(runs, [first[0].clone(), second[0].clone()]);
+
+	// the mean of the final latents is decoded once, which is not the same as either run's image
+	let expected = pipeline.decode_latents(average_latents(&runs).view())?;
+	assert_eq!(averaged_image, expected[0].to_rgb8().into_raw());
+	assert_ne!(averaged_image, first_image);
+	Ok(())
+}
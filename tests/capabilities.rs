@@ -0,0 +1,62 @@
+use pyke_diffusers::{
+	Capabilities, EulerDiscreteScheduler, MultiDiffusionOptions, SchedulerOptimizedDefaults, StableDiffusionOptions, StableDiffusionTxt2ImgOptions
+};
+
+use crate::common;
+
+#[test]
+fn reports_model_capabilities() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let capabilities = pipeline.capabilities();
+	assert_eq!(
+		capabilities,
+		Capabilities {
+			txt2img: true,
+			img2img: true,
+			inpaint: true,
+			// the test model's UNet has dynamic channels
+			unet_in_channels: None,
+			controlnet_residuals: 0,
+			attention_maps: false,
+			reference_attention: false,
+			guidance_embedding: false,
+			deepcache: false,
+			static_size: None,
+			preview_decoder: false,
+			safety_checker: false,
+			clip_scoring: false
+		}
+	);
+	let json = serde_json::to_value(capabilities)?;
+	assert_eq!(serde_json::from_value::<Capabilities>(json)?, capabilities);
+	Ok(())
+}
+
+#[test]
+fn reports_fixture_capabilities() -> anyhow::Result<()> {
+	// the fixture's extra 4th UNet input is the guidance embedding, not a ControlNet residual
+	let distilled = common::load_fixture("guidance-embedding", StableDiffusionOptions::default())?.capabilities();
+	assert!(distilled.guidance_embedding && distilled.img2img);
+	assert_eq!((distilled.controlnet_residuals, distilled.reference_attention), (0, false));
+	// unlike the test model's, the fixture's UNet declares its channels
+	assert_eq!((distilled.unet_in_channels, distilled.static_size), (Some(4), None));
+	Ok(())
+}
+
+#[test]
+fn static_unet_capabilities_match_runtime_checks() -> anyhow::Result<()> {
+	// the fixture's UNet takes 4-channel 8x8 latents & has an `attention_maps` output
+	let pipeline = common::load_fixture("static-size", StableDiffusionOptions::default())?;
+	let capabilities = pipeline.capabilities();
+	assert_eq!((capabilities.unet_in_channels, capabilities.static_size, capabilities.attention_maps), (Some(4), Some((64, 64)), true));
+
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let options = || StableDiffusionTxt2ImgOptions::default().with_steps(1).with_seed(42).with_prompt("photo of a red fox");
+	assert_eq!(options().with_size(64, 64).run(&pipeline, &mut scheduler)?.len(), 1);
+	let err = options().with_size(128, 64).run(&pipeline, &mut scheduler).unwrap_err();
+	assert!(err.to_string().contains("static size of 64x64"), "{err}");
+	// MultiDiffusion only runs the UNet on tiles of the static size
+	let tiled = options().with_size(128, 64).with_multidiffusion(MultiDiffusionOptions { tile_size: 64, tile_overlap: 32, ..Default::default() });
+	assert_eq!(tiled.run(&pipeline, &mut scheduler)?.len(), 1);
+	Ok(())
+}
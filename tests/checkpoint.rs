@@ -0,0 +1,69 @@
+use std::{cell::RefCell, rc::Rc};
+
+use ndarray::Array4;
+use pyke_diffusers::{
+	DPMSolverMultistepScheduler, DiffusionCheckpoint, EulerAncestralDiscreteScheduler, SchedulerOptimizedDefaults, StableDiffusionOutput,
+	StableDiffusionPipeline, StableDiffusionTxt2ImgOptions
+};
+
+use crate::common;
+
+const STEPS: usize = 5;
+
+/// Runs (or resumes) a generation, returning the final latents alongside the output.
+fn final_latents<S: SchedulerOptimizedDefaults>(
+	pipeline: &StableDiffusionPipeline,
+	options: StableDiffusionTxt2ImgOptions,
+	resume: Option<&DiffusionCheckpoint>
+) -> anyhow::Result<(Array4<f32>, StableDiffusionOutput)> {
+	let mut scheduler = S::stable_diffusion_v1_optimized_default()?;
+	let latents = Rc::new(RefCell::new(None));
+	let cb_latents = Rc::clone(&latents);
+	let options = options.callback_latents(1, move |_, _, step_latents| {
+		*cb_latents.borrow_mut() = Some(step_latents);
+		true
+	});
+	let output = match resume {
+		Some(checkpoint) => options.resume_from(pipeline, &mut scheduler, checkpoint)?,
+		None => options.run_with_output(pipeline, &mut scheduler)?
+	};
+	let latents = latents.borrow_mut().take();
+	Ok((latents.ok_or_else(|| anyhow::anyhow!("latents callback was never called"))?, output))
+}
+
+fn assert_resume_matches<S: SchedulerOptimizedDefaults>(pipeline: &StableDiffusionPipeline) -> anyhow::Result<()> {
+	let (expected, output) = final_latents::<S>(pipeline, common::options(STEPS).with_checkpoint_at(2), None)?;
+	let checkpoint = output.checkpoint.ok_or_else(|| anyhow::anyhow!("no checkpoint was captured"))?;
+	assert_eq!(checkpoint.step, 2);
+
+	let mut bytes = Vec::new();
+	checkpoint.to_writer(&mut bytes)?;
+	let checkpoint = DiffusionCheckpoint::from_reader(bytes.as_slice())?;
+
+	let (resumed, output) = final_latents::<S>(pipeline, common::options(STEPS), Some(&checkpoint))?;
+	assert_eq!(output.step_stats.first().map(|stats| stats.step), Some(2));
+	let max_diff = resumed.iter().zip(expected.iter()).map(|(a, b)| (a - b).abs()).fold(0.0_f32, f32::max);
+	assert!(max_diff <= 1e-5, "resumed latents differ by up to {max_diff}");
+	Ok(())
+}
+
+#[test]
+fn resume_matches_uninterrupted_run() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+
+	// ancestral: requires restoring the scheduler's RNG
+	assert_resume_matches::<EulerAncestralDiscreteScheduler>(&pipeline)?;
+	// multistep: requires restoring the history of model outputs
+	assert_resume_matches::<DPMSolverMultistepScheduler>(&pipeline)?;
+	Ok(())
+}
+
+#[test]
+fn resume_requires_matching_size() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+
+	let (_, output) = final_latents::<EulerAncestralDiscreteScheduler>(&pipeline, common::options(STEPS).with_checkpoint_at(1), None)?;
+	let checkpoint = output.checkpoint.unwrap();
+	assert!(final_latents::<EulerAncestralDiscreteScheduler>(&pipeline, common::options(STEPS).with_size(256, 256), Some(&checkpoint)).is_err());
+	Ok(())
+}
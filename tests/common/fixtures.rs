@@ -0,0 +1,199 @@
+//! Model fixtures, each of which is the test model with a few config keys overridden. Also used by the unit tests &
+//! benchmarks, which include this file with `#[path]`.
+
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	sync::atomic::{AtomicUsize, Ordering}
+};
+
+use toml::Value;
+
+/// The root of the test model, whose config every fixture starts from.
+const BASE_MODEL: &str = "tests/stable-diffusion";
+/// The directory holding the fixtures' own files, in a subdirectory per fixture.
+const FIXTURES: &str = "tests/fixtures";
+
+/// Returns the config keys fixture `name` overrides, as TOML, and the dotted keys it removes. Paths are relative to the
+/// fixture's directory in `tests/fixtures`, if it has one.
+fn overrides(name: &str) -> (&'static str, &'static [&'static str]) {
+	match name {
+		"clip-scorer" => (
+			r#"
+			[clip-scorer]
+			image-encoder = "image_encoder.onnx"
+			text-projection = "text_projection.bin"
+			projection-dim = 3
+
+			[hashes]
+			clip-image-encoder = "9116f78f0bcb36cc5c07fc32741a7eeb"
+			"#,
+			&[]
+		),
+		// the deep subgraph is the whole test UNet, and the shallow subgraph passes the deep noise prediction through
+		"deepcache" => (
+			r#"
+			[unet.deepcache]
+			deep = "../../stable-diffusion/unet.onnx"
+			shallow = "shallow.onnx"
+			branch = 0
+			"#,
+			&[]
+		),
+		// both halves are the unsplit UNet, so the shallow UNet lacks the cached feature inputs
+		"unmatched-deepcache" => (
+			r#"
+			[unet.deepcache]
+			deep = "../../stable-diffusion/unet.onnx"
+			shallow = "../../stable-diffusion/unet.onnx"
+			"#,
+			&[]
+		),
+		"inpaint-unet" => (
+			r#"
+			[unet]
+			inpaint = "unet-inpainting.onnx"
+			"#,
+			&[]
+		),
+		"no-tokenizer" => (
+			r#"
+			[tokenizer]
+			type = "None"
+			"#,
+			&["tokenizer.path"]
+		),
+		// BOS & EOS are read from the fixture's `special_tokens_map.json`
+		"unknown-special-tokens" => ("", &["tokenizer.bos-token", "tokenizer.eos-token"]),
+		"guidance-embedding" => (
+			r#"
+			[unet]
+			path = "unet.onnx"
+
+			[hashes]
+			unet = "f94279cf46c5f96f2ee00046a19a32b7"
+			"#,
+			&[]
+		),
+		"reference-attention" => (
+			r#"
+			[unet]
+			path = "unet.onnx"
+
+			[hashes]
+			unet = "98edb6499edcd466098c132e7ee33c69"
+			"#,
+			&[]
+		),
+		"reordered-outputs" => (
+			r#"
+			[unet]
+			path = "unet.onnx"
+
+			[hashes]
+			unet = "35645e7ca4ab80a65ca2e5adfda7280d"
+			"#,
+			&[]
+		),
+		"static-size" => (
+			r#"
+			[unet]
+			path = "unet.onnx"
+
+			[hashes]
+			unet = "f33b4b8752cce12d67715fa7968b8a8f"
+			"#,
+			&[]
+		),
+		"wrapped-outputs" => (
+			r#"
+			[unet]
+			path = "unet.onnx"
+
+			[hashes]
+			unet = "587b688b3c2b06f229934bf580a61906"
+			"#,
+			&[]
+		),
+		_ => panic!("unknown fixture `{name}`")
+	}
+}
+
+/// Writes the config of fixture `name` to a directory in the system's temp directory, and returns that directory as
+/// the model root to load. Paths in the config are made absolute, and the fixture's files are copied alongside the
+/// config, so files models read from their root (such as `special_tokens_map.json`) are found too.
+pub fn fixture(name: &str) -> anyhow::Result<PathBuf> {
+	let (overrides, removed) = overrides(name);
+	let fixture_dir = Path::new(FIXTURES).join(name);
+
+	let mut config: Value = toml::from_str(&fs::read_to_string(Path::new(BASE_MODEL).join("pyke-diffusers.toml"))?)?;
+	absolutize_paths(&mut config, Path::new(BASE_MODEL))?;
+	let mut overrides: Value = toml::from_str(overrides)?;
+	absolutize_paths(&mut overrides, &fixture_dir)?;
+	merge(&mut config, overrides);
+	for key in removed {
+		let mut table = &mut config;
+		let mut segments = key.split('.').peekable();
+		while let Some(segment) = segments.next() {
+			if segments.peek().is_none() {
+				table.as_table_mut().and_then(|table| table.remove(segment));
+				break;
+			}
+			table = table.get_mut(segment).ok_or_else(|| anyhow::anyhow!("fixture `{name}` removes `{key}`, which isn't in the config"))?;
+		}
+	}
+
+	let root = std::env::temp_dir().join("pyke-diffusers-fixtures").join(name);
+	fs::create_dir_all(&root)?;
+	// fixtures which only override config keys have no directory of their own
+	if fixture_dir.is_dir() {
+		for entry in fs::read_dir(&fixture_dir)? {
+			let entry = entry?;
+			if entry.file_type()?.is_file() {
+				write_atomically(&root.join(entry.file_name()), &fs::read(entry.path())?)?;
+			}
+		}
+	}
+	write_atomically(&root.join("pyke-diffusers.toml"), toml::to_string(&config)?.as_bytes())?;
+	Ok(root)
+}
+
+/// Replaces every string in `value` which names a file relative to `dir` with the file's absolute path.
+fn absolutize_paths(value: &mut Value, dir: &Path) -> anyhow::Result<()> {
+	match value {
+		Value::String(path) if dir.join(&*path).is_file() => *path = fs::canonicalize(dir.join(&*path))?.to_string_lossy().into_owned(),
+		Value::Table(table) => {
+			for value in table.values_mut() {
+				absolutize_paths(value, dir)?;
+			}
+		}
+		_ => {}
+	}
+	Ok(())
+}
+
+/// Merges the tables of `overrides` into `config`, replacing any other values.
+fn merge(config: &mut Value, overrides: Value) {
+	match (config, overrides) {
+		(Value::Table(config), Value::Table(overrides)) => {
+			for (key, value) in overrides {
+				match config.get_mut(&key) {
+					Some(existing) => merge(existing, value),
+					None => {
+						config.insert(key, value);
+					}
+				}
+			}
+		}
+		(config, overrides) => *config = overrides
+	}
+}
+
+/// Writes a file through a temporary file, so tests loading the same fixture concurrently never read a partial file.
+fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+	static COUNTER: AtomicUsize = AtomicUsize::new(0);
+	let tmp = path.with_extension(format!("{}-{}.tmp", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+	fs::write(&tmp, contents)?;
+	fs::rename(&tmp, path)?;
+	Ok(())
+}
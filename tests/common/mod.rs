@@ -0,0 +1,36 @@
+//! Helpers shared by the integration tests.
+
+pub mod fixtures;
+
+use std::path::PathBuf;
+
+use pyke_diffusers::{OrtEnvironment, StableDiffusionOptions, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions};
+
+/// The root of the tiny test model, which generates 64x64 images.
+pub const TEST_MODEL: &str = "tests/stable-diffusion";
+
+/// Loads the test model with the default options.
+pub fn pipeline() -> anyhow::Result<StableDiffusionPipeline> {
+	pipeline_with(StableDiffusionOptions::default())
+}
+
+/// Loads the test model with the given options.
+pub fn pipeline_with(options: StableDiffusionOptions) -> anyhow::Result<StableDiffusionPipeline> {
+	load(TEST_MODEL, options)
+}
+
+/// Loads the model at `root` in a new environment.
+pub fn load(root: impl Into<PathBuf>, options: StableDiffusionOptions) -> anyhow::Result<StableDiffusionPipeline> {
+	let environment = OrtEnvironment::default().into_arc();
+	StableDiffusionPipeline::new(&environment, root, options)
+}
+
+/// Loads the fixture `name`, the test model with the config overrides listed in [`fixtures`], in a new environment.
+pub fn load_fixture(name: &str, options: StableDiffusionOptions) -> anyhow::Result<StableDiffusionPipeline> {
+	load(fixtures::fixture(name)?, options)
+}
+
+/// Options generating one 64x64 image of a fox with the test model in `steps` steps, from a fixed seed.
+pub fn options(steps: usize) -> StableDiffusionTxt2ImgOptions {
+	StableDiffusionTxt2ImgOptions::default().with_size(64, 64).with_steps(steps).with_seed(42).with_prompt("photo of a red fox")
+}
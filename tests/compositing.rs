@@ -0,0 +1,55 @@
+use image::{DynamicImage, GrayImage, Luma, RgbImage};
+use pyke_diffusers::compositing::composite_inpaint_result;
+
+fn test_images() -> (DynamicImage, DynamicImage) {
+	let original = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| [(x * 4) as u8, (y * 4) as u8, 77].into()));
+	let generated = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| [255 - (y * 4) as u8, 12, (x * 3) as u8].into()));
+	(original, generated)
+}
+
+/// A mask covering a 16x16 square at (24, 24).
+fn square_mask() -> DynamicImage {
+	DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, y| Luma([if (24..40).contains(&x) && (24..40).contains(&y) { 255 } else { 0 }])))
+}
+
+#[test]
+fn unmasked_pixels_are_identical() {
+	let (original, generated) = test_images();
+	let mask = square_mask();
+	let original_f32 = original.to_rgb32f();
+	let generated_f32 = generated.to_rgb32f();
+
+	let result = composite_inpaint_result(&original, &generated, &mask, 0.0).unwrap().into_rgb32f();
+	for (x, y, pixel) in result.enumerate_pixels() {
+		let expected = if mask.as_luma8().unwrap().get_pixel(x, y)[0] == 0 {
+			original_f32.get_pixel(x, y)
+		} else {
+			generated_f32.get_pixel(x, y)
+		};
+		assert_eq!(pixel.0.map(f32::to_bits), expected.0.map(f32::to_bits), "pixel ({x}, {y}) differs");
+	}
+}
+
+#[test]
+fn blurred_mask_keeps_distant_pixels() {
+	let (original, generated) = test_images();
+	let original_f32 = original.to_rgb32f();
+
+	let result = composite_inpaint_result(&original, &generated, &square_mask(), 2.0).unwrap().into_rgb32f();
+	for (x, y, pixel) in result.enumerate_pixels() {
+		// well outside the blur radius
+		if !(12..52).contains(&x) || !(12..52).contains(&y) {
+			assert_eq!(pixel.0.map(f32::to_bits), original_f32.get_pixel(x, y).0.map(f32::to_bits), "pixel ({x}, {y}) differs");
+		}
+	}
+	// the center of the mask should be (almost) fully generated
+	assert_ne!(result.get_pixel(32, 32), original_f32.get_pixel(32, 32));
+}
+
+#[test]
+fn size_mismatch_errors() {
+	let (original, generated) = test_images();
+	let small = DynamicImage::ImageLuma8(GrayImage::new(32, 32));
+	assert!(composite_inpaint_result(&original, &generated, &small, 0.0).is_err());
+	assert!(composite_inpaint_result(&original, &small, &square_mask(), 0.0).is_err());
+}
@@ -0,0 +1,80 @@
+use std::{cell::RefCell, rc::Rc};
+
+use ndarray::{s, Array4, ArrayView3};
+use pyke_diffusers::{
+	EulerDiscreteScheduler, ImageRegion, MultiDiffusionOptions, SchedulerOptimizedDefaults, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions
+};
+
+use crate::common;
+
+fn final_latents(pipeline: &StableDiffusionPipeline, options: StableDiffusionTxt2ImgOptions) -> anyhow::Result<Array4<f32>> {
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let latents = Rc::new(RefCell::new(None));
+	let cb_latents = Rc::clone(&latents);
+	options
+		.with_size(64, 64)
+		.with_steps(3)
+		.with_seed(42)
+		.with_guidance_scale(7.5)
+		.with_num_images_per_prompt(2)
+		.callback_latents(1, move |_, _, step_latents| {
+			*cb_latents.borrow_mut() = Some(step_latents);
+			true
+		})
+		.run(pipeline, &mut scheduler)?;
+	Ok(latents.borrow_mut().take().unwrap())
+}
+
+fn assert_close(a: ArrayView3<f32>, b: ArrayView3<f32>) {
+	for (a, b) in a.iter().zip(b.iter()) {
+		assert!((a - b).abs() < 1e-3, "{a} != {b}");
+	}
+}
+
+#[test]
+fn full_dropout_matches_negative_prompt_only() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+
+	let options = || StableDiffusionTxt2ImgOptions::default().with_negative_prompt("blurry");
+	let dropped = final_latents(&pipeline, options().with_prompt("photo of a red fox").with_conditioning_dropout(vec![0.0, 1.0]))?;
+	let normal = final_latents(&pipeline, options().with_prompt("photo of a red fox"))?;
+	let unconditional = final_latents(&pipeline, options().with_prompt("blurry"))?;
+
+	// a factor of 0 leaves the element unchanged...
+	assert_close(dropped.slice(s![0, .., .., ..]), normal.slice(s![0, .., .., ..]));
+	// ...while a factor of 1 generates it from the negative prompt alone
+	assert_close(dropped.slice(s![1, .., .., ..]), unconditional.slice(s![1, .., .., ..]));
+	assert_ne!(normal.slice(s![1, .., .., ..]), unconditional.slice(s![1, .., .., ..]));
+	Ok(())
+}
+
+#[test]
+fn dropout_applies_to_regional_prompts() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+
+	// every tile lies within the region, so only the regional prompt is used
+	let options = |prompt: &str, region_prompt: &str| {
+		let multidiffusion = MultiDiffusionOptions::default().with_region(ImageRegion::new(0, 0, 64, 64), region_prompt);
+		StableDiffusionTxt2ImgOptions::default().with_negative_prompt("blurry").with_prompt(prompt).with_multidiffusion(multidiffusion)
+	};
+	let dropped = final_latents(&pipeline, options("photo of a red fox", "photo of a grey wolf").with_conditioning_dropout(vec![0.0, 1.0]))?;
+	let unconditional = final_latents(&pipeline, options("blurry", "blurry"))?;
+	assert_close(dropped.slice(s![1, .., .., ..]), unconditional.slice(s![1, .., .., ..]));
+	assert_ne!(dropped.slice(s![0, .., .., ..]), unconditional.slice(s![0, .., .., ..]));
+	Ok(())
+}
+
+#[test]
+fn invalid_factors_are_rejected() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+
+	let options = || StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox");
+	// one factor per image is required
+	assert!(final_latents(&pipeline, options().with_conditioning_dropout(vec![0.5])).is_err());
+	assert!(final_latents(&pipeline, options().with_conditioning_dropout(vec![0.0, 1.5])).is_err());
+	// without classifier-free guidance, there are no unconditional embeddings to move toward
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let unguided = options().with_size(64, 64).with_steps(1).with_guidance_scale(1.0).with_conditioning_dropout(vec![1.0]);
+	assert!(unguided.run(&pipeline, &mut scheduler).is_err());
+	Ok(())
+}
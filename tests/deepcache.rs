@@ -0,0 +1,93 @@
+use std::{cell::RefCell, rc::Rc};
+
+use ndarray::Array4;
+use pyke_diffusers::{
+	DeepCacheConfig, EulerDiscreteScheduler, SchedulerOptimizedDefaults, SessionTracker, StableDiffusionOptions, StableDiffusionPipeline,
+	StableDiffusionTxt2ImgOptions
+};
+
+use crate::common;
+
+/// The fixture's deep subgraph is the whole test UNet, and its shallow subgraph passes the deep noise prediction
+/// through, so cached steps reuse the noise prediction of the last deep step.
+const DEEPCACHE_MODEL: &str = "deepcache";
+
+fn final_latents(pipeline: &StableDiffusionPipeline, deepcache: Option<DeepCacheConfig>) -> anyhow::Result<Array4<f32>> {
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let latents = Rc::new(RefCell::new(None));
+	let cb_latents = Rc::clone(&latents);
+	let mut options = StableDiffusionTxt2ImgOptions::default()
+		.with_size(64, 64)
+		.with_prompt("photo of a red fox")
+		.with_steps(4)
+		.with_seed(42)
+		.callback_latents(1, move |_, _, step_latents| {
+			*cb_latents.borrow_mut() = Some(step_latents);
+			true
+		});
+	options.deepcache = deepcache;
+	options.run(pipeline, &mut scheduler)?;
+	let latents = latents.borrow_mut().take().unwrap();
+	Ok(latents)
+}
+
+#[test]
+fn single_graph_unet_is_unsupported() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::default();
+	let err = StableDiffusionTxt2ImgOptions::default()
+		.with_size(64, 64)
+		.with_steps(2)
+		.with_prompt("a")
+		.with_deepcache(DeepCacheConfig::new(3))
+		.run(&pipeline, &mut scheduler)
+		.unwrap_err();
+	assert!(err.to_string().contains("DeepCache is not supported by this model"), "{err}");
+	Ok(())
+}
+
+#[test]
+fn split_unet_is_validated_on_load() -> anyhow::Result<()> {
+	let err = common::load_fixture("unmatched-deepcache", StableDiffusionOptions::default().with_deepcache(true)).err().unwrap();
+	assert!(format!("{err:#}").contains("the DeepCache shallow UNet takes 3 inputs"), "{err:#}");
+	// the split isn't loaded unless DeepCache is enabled
+	common::load_fixture("unmatched-deepcache", StableDiffusionOptions::default())?;
+	Ok(())
+}
+
+#[test]
+fn split_unet_is_loaded_on_request() -> anyhow::Result<()> {
+	let pipeline = common::load_fixture(DEEPCACHE_MODEL, StableDiffusionOptions::default())?;
+	assert!(!pipeline.sessions().iter().any(|s| s.component.starts_with("DeepCache")));
+	assert!(!pipeline.capabilities().deepcache);
+	let err = final_latents(&pipeline, Some(DeepCacheConfig::new(2))).unwrap_err();
+	assert!(err.to_string().contains("with_deepcache(true)"), "{err}");
+
+	let pipeline = common::load_fixture(DEEPCACHE_MODEL, StableDiffusionOptions::default().with_deepcache(true))?;
+	let sessions = pipeline.sessions();
+	for component in ["DeepCache deep UNet", "DeepCache shallow UNet"] {
+		assert!(sessions.iter().any(|s| s.component == component), "{component} is not loaded: {sessions:?}");
+	}
+	assert!(SessionTracker::global().active_sessions().iter().any(|s| s.component == "DeepCache deep UNet"));
+	assert!(pipeline.capabilities().deepcache);
+	Ok(())
+}
+
+#[test]
+fn cached_features_are_reused() -> anyhow::Result<()> {
+	let pipeline = common::load_fixture(DEEPCACHE_MODEL, StableDiffusionOptions::default().with_deepcache(true))?;
+	let uncached = final_latents(&pipeline, None)?;
+
+	// running the deep subgraph on every step matches the full UNet
+	let every_step = final_latents(&pipeline, Some(DeepCacheConfig::new(1)))?;
+	let max_diff = every_step.iter().zip(uncached.iter()).map(|(a, b)| (a - b).abs()).fold(0.0_f32, f32::max);
+	assert!(max_diff < 1e-5, "interval 1 differs from the full UNet by up to {max_diff}");
+
+	// with cached steps, stale noise predictions are reused, deterministically
+	let cached = final_latents(&pipeline, Some(DeepCacheConfig::new(2)))?;
+	assert!(cached.iter().all(|x| x.is_finite()));
+	assert!(cached.iter().zip(uncached.iter()).any(|(a, b)| (a - b).abs() > 1e-4), "cached features had no effect");
+	assert_eq!(final_latents(&pipeline, Some(DeepCacheConfig::new(2)))?, cached);
+	assert!(final_latents(&pipeline, Some(DeepCacheConfig::new(2).with_branch_config(1))).is_err());
+	Ok(())
+}
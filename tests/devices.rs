@@ -0,0 +1,97 @@
+use pyke_diffusers::{ArenaExtendStrategy, CUDAExecutionProviderCuDNNConvAlgoSearch, CUDAExecutionProviderOptions, DiffusionDevice, DiffusionDeviceControl};
+
+fn round_trip(device: DiffusionDevice) {
+	let json = serde_json::to_string(&device).unwrap();
+	let deserialized: DiffusionDevice = serde_json::from_str(&json).unwrap();
+	assert_eq!(format!("{deserialized:?}"), format!("{device:?}"), "{json}");
+}
+
+#[test]
+fn device_round_trip() {
+	round_trip(DiffusionDevice::CPU);
+	round_trip(DiffusionDevice::CUDA(1, None));
+	round_trip(DiffusionDevice::CUDA(0, Some(CUDAExecutionProviderOptions::default())));
+	round_trip(DiffusionDevice::CUDA(
+		0,
+		Some(CUDAExecutionProviderOptions {
+			device_id: Some(1),
+			gpu_mem_limit: Some(3500000000),
+			arena_extend_strategy: Some(ArenaExtendStrategy::SameAsRequested),
+			cudnn_conv_algo_search: Some(CUDAExecutionProviderCuDNNConvAlgoSearch::Heuristic),
+			do_copy_in_default_stream: Some(false)
+		})
+	));
+	for arena_extend_strategy in [ArenaExtendStrategy::NextPowerOfTwo, ArenaExtendStrategy::SameAsRequested] {
+		round_trip(DiffusionDevice::CUDA(
+			0,
+			Some(CUDAExecutionProviderOptions {
+				arena_extend_strategy: Some(arena_extend_strategy),
+				..Default::default()
+			})
+		));
+	}
+	for cudnn_conv_algo_search in [
+		CUDAExecutionProviderCuDNNConvAlgoSearch::Exhaustive,
+		CUDAExecutionProviderCuDNNConvAlgoSearch::Heuristic,
+		CUDAExecutionProviderCuDNNConvAlgoSearch::Default
+	] {
+		round_trip(DiffusionDevice::CUDA(
+			0,
+			Some(CUDAExecutionProviderOptions {
+				cudnn_conv_algo_search: Some(cudnn_conv_algo_search),
+				..Default::default()
+			})
+		));
+	}
+	round_trip(DiffusionDevice::TensorRT);
+	round_trip(DiffusionDevice::DirectML(2));
+	round_trip(DiffusionDevice::ROCm(3));
+	round_trip(DiffusionDevice::OneDNN);
+	round_trip(DiffusionDevice::CoreML);
+}
+
+#[test]
+fn device_format() {
+	assert_eq!(serde_json::to_string(&DiffusionDevice::CPU).unwrap(), r#"{"provider":"cpu"}"#);
+	assert_eq!(serde_json::to_string(&DiffusionDevice::CUDA(1, None)).unwrap(), r#"{"provider":"cuda","device_id":1}"#);
+	assert_eq!(serde_json::to_string(&DiffusionDevice::TensorRT).unwrap(), r#"{"provider":"tensorrt"}"#);
+	assert_eq!(serde_json::to_string(&DiffusionDevice::DirectML(0)).unwrap(), r#"{"provider":"directml","device_id":0}"#);
+}
+
+#[test]
+fn custom_device_is_not_serializable() {
+	let device = DiffusionDevice::Custom(ort::ExecutionProvider::CPU(Default::default()));
+	assert!(serde_json::to_string(&device).is_err());
+}
+
+#[test]
+fn unknown_provider_is_an_error() {
+	let err = serde_json::from_str::<DiffusionDevice>(r#"{ "provider": "cudaa" }"#).unwrap_err().to_string();
+	assert!(err.contains("unknown variant `cudaa`"), "{err}");
+	assert!(err.contains("`cuda`"), "{err}");
+}
+
+#[test]
+fn device_control_round_trip() {
+	let devices = DiffusionDeviceControl {
+		unet: DiffusionDevice::CUDA(0, None),
+		vae_decoder: DiffusionDevice::DirectML(1),
+		..Default::default()
+	};
+	let json = serde_json::to_string(&devices).unwrap();
+	assert!(json.contains(r#""version":1"#), "{json}");
+	let deserialized: DiffusionDeviceControl = serde_json::from_str(&json).unwrap();
+	assert_eq!(format!("{deserialized:?}"), format!("{devices:?}"));
+}
+
+#[test]
+fn device_control_defaults_and_unknown_fields() {
+	let devices: DiffusionDeviceControl =
+		serde_json::from_str(r#"{ "version": 2, "unet": { "provider": "cuda", "device_id": 0, "new_option": true }, "refiner": { "provider": "cpu" } }"#).unwrap();
+	assert!(matches!(devices.unet, DiffusionDevice::CUDA(0, None)));
+	assert!(matches!(devices.vae_encoder, DiffusionDevice::CPU));
+	assert!(matches!(devices.safety_checker, DiffusionDevice::CPU));
+
+	let devices: DiffusionDeviceControl = serde_json::from_str("{}").unwrap();
+	assert_eq!(format!("{devices:?}"), format!("{:?}", DiffusionDeviceControl::default()));
+}
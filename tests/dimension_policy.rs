@@ -0,0 +1,57 @@
+use image::{imageops, RgbImage};
+use ndarray::s;
+use pyke_diffusers::{CancellationToken, DimensionPolicy, EulerDiscreteScheduler, SchedulerOptimizedDefaults, StableDiffusionTxt2ImgOptions};
+
+use crate::common;
+
+fn options(policy: DimensionPolicy) -> StableDiffusionTxt2ImgOptions {
+	StableDiffusionTxt2ImgOptions::default()
+		.with_dimension_policy(policy)
+		.with_size(500, 333)
+		.with_steps(2)
+		.with_seed(42)
+		.with_prompt("photo of a red fox")
+}
+
+/// Mean absolute difference between `image` and the region of `padded` starting at `(x, y)`.
+fn difference(padded: &RgbImage, image: &RgbImage, x: u32, y: u32) -> f64 {
+	let region = imageops::crop_imm(padded, x, y, image.width(), image.height()).to_image();
+	let total: u64 = region.as_raw().iter().zip(image.as_raw()).map(|(&a, &b)| a.abs_diff(b) as u64).sum();
+	total as f64 / image.as_raw().len() as f64
+}
+
+#[test]
+fn pad_and_crop_delivers_exact_sizes() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let options = options(DimensionPolicy::PadAndCrop);
+	assert_eq!((options.width, options.height), (512, 344));
+	let padded = options.run_with_output(&pipeline, &mut scheduler)?.images.remove(0).into_image()?.to_rgb8();
+	assert_eq!(padded.dimensions(), (500, 333));
+	let rounded = options(DimensionPolicy::RoundDown).run_with_output(&pipeline, &mut scheduler)?.images.remove(0).into_image()?.to_rgb8();
+	assert_eq!(rounded.dimensions(), (496, 328));
+
+	// the rounded-down generation's noise is surrounded by one latent of padding noise...
+	let token = CancellationToken::new();
+	token.cancel();
+	let padded_noise = options(DimensionPolicy::PadAndCrop).with_cancellation_token(token.clone()).run_with_output(&pipeline, &mut scheduler)?.latents.unwrap();
+	let rounded_noise = options(DimensionPolicy::RoundDown).with_cancellation_token(token).run_with_output(&pipeline, &mut scheduler)?.latents.unwrap();
+	assert_eq!((padded_noise.dim(), rounded_noise.dim()), ((1, 4, 43, 64), (1, 4, 41, 62)));
+	assert_eq!(padded_noise.slice(s![.., .., 1..42, 1..63]), rounded_noise);
+	// ...and the delivered crop starts at that noise, so the rounded-down image lines up with its top-left corner
+	let aligned = difference(&padded, &rounded, 0, 0);
+	assert!(aligned < difference(&padded, &rounded, 2, 1));
+	assert!(aligned < difference(&padded, &rounded, 4, 5));
+	Ok(())
+}
+
+#[test]
+fn pad_and_crop_only_pads_unaligned_dimensions() {
+	let options = StableDiffusionTxt2ImgOptions::default().with_size(500, 333).with_dimension_policy(DimensionPolicy::PadAndCrop);
+	assert_eq!((options.width, options.height), (512, 344));
+	let options = options.with_size(512, 5);
+	assert_eq!((options.width, options.height), (512, 8));
+	let options = options.with_dimension_policy(DimensionPolicy::RoundDown);
+	assert_eq!((options.width, options.height, options.requested_size), (512, 8, (512, 5)));
+}
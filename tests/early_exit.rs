@@ -0,0 +1,41 @@
+use pyke_diffusers::{
+	EarlyExit, EulerDiscreteScheduler, SchedulerOptimizedDefaults, StableDiffusionOutput, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions
+};
+
+use crate::common;
+
+fn generate(pipeline: &StableDiffusionPipeline, early_exit: Option<EarlyExit>) -> anyhow::Result<StableDiffusionOutput> {
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let mut options = StableDiffusionTxt2ImgOptions::default().with_size(64, 64).with_prompt("photo of a red fox").with_steps(8).with_seed(42);
+	if let Some(early_exit) = early_exit {
+		options = options.with_early_exit(early_exit);
+	}
+	options.run_with_output(pipeline, &mut scheduler)
+}
+
+fn pixels(output: StableDiffusionOutput) -> anyhow::Result<Vec<u8>> {
+	Ok(output.images.into_iter().next().unwrap().into_image()?.to_rgb8().into_raw())
+}
+
+#[test]
+fn early_exit_on_convergence() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let full = generate(&pipeline, None)?;
+	assert_eq!(full.steps_taken, 8);
+	let full = pixels(full)?;
+
+	// a threshold of 0 never exits early, so the output is identical
+	let output = generate(&pipeline, Some(EarlyExit::new(0, 0.0)))?;
+	assert_eq!(output.steps_taken, 8);
+	assert_eq!(pixels(output)?, full);
+
+	// every step counts as converged with a huge threshold, so the run exits 2 steps after `min_steps` & decodes the
+	// partially denoised latents
+	let output = generate(&pipeline, Some(EarlyExit::new(3, 1e9)))?;
+	assert_eq!((output.steps_taken, output.images.len()), (5, 1));
+	let exited = pixels(output)?;
+	assert_ne!(exited, full);
+	// the convergence check is deterministic
+	assert_eq!(pixels(generate(&pipeline, Some(EarlyExit::new(3, 1e9)))?)?, exited);
+	Ok(())
+}
@@ -0,0 +1,87 @@
+use ndarray::Array4;
+use ndarray_rand::rand::{rngs::StdRng, SeedableRng};
+use pyke_diffusers::{DiffusersParity, DiffusionScheduler, EulerDiscreteScheduler, SchedulerOptimizedDefaults};
+
+// Reference values of diffusers' `EulerDiscreteScheduler` with the Stable Diffusion v1 config (scaled linear betas
+// from 0.00085 to 0.012, `linspace` timestep spacing). `scripts/euler_parity_reference.py` captures them from diffusers
+// itself. The values below predate that script: they were computed by transcribing diffusers' `__init__`,
+// `set_timesteps`, `scale_model_input` & `step` with every float32 operation rounded to float32, and should be
+// replaced by the script's output.
+const TIMESTEPS: [f32; 10] = [999.0, 888.0, 777.0, 666.0, 555.0, 444.0, 333.0, 222.0, 111.0, 0.0];
+const SIGMAS: [f32; 10] = [14.614647, 7.839885, 4.609176, 2.9183085, 1.9501624, 1.3449285, 0.9323583, 0.6249773, 0.3686585, 0.029167533];
+const SEVEN_STEP_SIGMAS: [f32; 7] = [14.614647, 5.9489183, 2.9183085, 1.6155834, 0.9323583, 0.49355352, 0.029167533];
+const STEP_OUTPUTS: [[f32; 16]; 3] = [
+	[
+		-9.533575, -9.400435, -9.267295, -9.134154, -7.3073235, -7.174183, -7.0410423, -6.9079022, -5.0810714, 6.9079022, 7.0410423, 7.174183, 9.001014,
+		9.134154, 9.267295, 9.400435
+	],
+	[
+		-7.9182205, -8.592758, -9.267295, -9.941832, -8.115001, -8.789537, -9.464074, -4.4848704, -2.6580396, 8.523256, 7.8487196, 7.174183, 9.001014,
+		8.326477, 7.6519403, 6.9774036
+	],
+	[
+		-7.4955034, -8.592758, -9.690012, -10.787266, -8.960435, -10.057688, -8.195923, -3.6394367, -1.8126057, 8.945973, 7.8487196, 6.751466, 8.578297,
+		7.481043, 6.3837895, 8.245554
+	]
+];
+
+fn exact_scheduler() -> anyhow::Result<EulerDiscreteScheduler> {
+	Ok(EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?.with_parity(DiffusersParity::Exact))
+}
+
+fn assert_close(actual: &[f32], expected: &[f32], what: &str) {
+	assert_eq!(actual.len(), expected.len(), "{what}: length mismatch");
+	for (i, (a, e)) in actual.iter().zip(expected.iter()).enumerate() {
+		assert!((a - e).abs() <= 1e-5, "{what}[{i}]: {a} != {e}");
+	}
+}
+
+#[test]
+fn sigmas_match_diffusers() -> anyhow::Result<()> {
+	let mut scheduler = exact_scheduler()?;
+	scheduler.set_timesteps(10);
+	assert_eq!(scheduler.timesteps().to_vec(), TIMESTEPS);
+	assert_close(&(0..10).map(|i| scheduler.sigma(i).unwrap()).collect::<Vec<_>>(), &SIGMAS, "sigmas");
+	assert_eq!(scheduler.init_noise_sigma(), SIGMAS[0]);
+
+	// timesteps which fall between training timesteps are interpolated in float64
+	scheduler.set_timesteps(7);
+	assert_eq!(scheduler.timesteps().to_vec(), [999.0, 832.5, 666.0, 499.5, 333.0, 166.5, 0.0]);
+	assert_close(&(0..7).map(|i| scheduler.sigma(i).unwrap()).collect::<Vec<_>>(), &SEVEN_STEP_SIGMAS, "7-step sigmas");
+
+	// diffusers' single step is at timestep 0
+	scheduler.set_timesteps(1);
+	assert_eq!(scheduler.timesteps().to_vec(), [0.0]);
+	assert_eq!(scheduler.init_noise_sigma(), scheduler.sigma(0).unwrap());
+	Ok(())
+}
+
+#[test]
+fn steps_match_diffusers() -> anyhow::Result<()> {
+	let mut scheduler = exact_scheduler()?;
+	scheduler.set_timesteps(10);
+
+	let mut sample = Array4::from_shape_fn((1, 4, 2, 2), |(_, c, y, x)| (c * 4 + y * 2 + x) as f32 / 8.0 - 1.0) * scheduler.init_noise_sigma();
+	let mut rng = StdRng::seed_from_u64(0);
+	for (k, expected) in STEP_OUTPUTS.iter().enumerate() {
+		let timestep = scheduler.timesteps()[k];
+		let model_output = Array4::from_shape_fn((1, 4, 2, 2), |(_, c, y, x)| ((c * 3 + y * 2 + x + k) % 7) as f32 / 4.0 - 0.75);
+		let scaled = scheduler.scale_model_input(sample.view(), timestep);
+		let scale = (SIGMAS[k].powi(2) + 1.0).sqrt();
+		assert_close(&scaled.iter().copied().collect::<Vec<_>>(), &sample.mapv(|x| x / scale).iter().copied().collect::<Vec<_>>(), "scaled input");
+		sample = scheduler.step(model_output.view(), timestep, sample.view(), &mut rng).prev_sample;
+		assert_close(&sample.iter().copied().collect::<Vec<_>>(), expected, &format!("step {k}"));
+	}
+	Ok(())
+}
+
+#[test]
+fn native_parity_is_unchanged() -> anyhow::Result<()> {
+	let mut native = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let mut explicit = exact_scheduler()?.with_parity(DiffusersParity::Native);
+	native.set_timesteps(30);
+	explicit.set_timesteps(30);
+	assert_eq!(native.timesteps(), explicit.timesteps());
+	assert_eq!((0..30).map(|i| native.sigma(i)).collect::<Vec<_>>(), (0..30).map(|i| explicit.sigma(i)).collect::<Vec<_>>());
+	Ok(())
+}
@@ -0,0 +1,115 @@
+//! Shared code for the golden latents test & the `regenerate-goldens` example.
+
+#![allow(dead_code)]
+
+use std::{
+	cell::RefCell,
+	fs,
+	io::{self, Read, Write},
+	path::{Path, PathBuf},
+	rc::Rc
+};
+
+use ndarray::Array4;
+use pyke_diffusers::{DiffusionScheduler, SchedulerOptimizedDefaults, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions};
+
+pub const GOLDEN_SEED: u64 = 42;
+pub const GOLDEN_STEPS: usize = 5;
+pub const GOLDEN_PROMPT: &str = "photo of a red fox";
+/// Maximum allowed absolute difference between generated & golden latents.
+pub const GOLDEN_TOLERANCE: f32 = 1e-4;
+
+pub fn golden_dir() -> PathBuf {
+	Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join("data")
+}
+
+/// Runs a fully deterministic generation with the given scheduler and returns the final latents.
+pub fn generate_latents<S: DiffusionScheduler>(pipeline: &StableDiffusionPipeline, scheduler: &mut S) -> anyhow::Result<Array4<f32>> {
+	let latents = Rc::new(RefCell::new(None));
+	let cb_latents = Rc::clone(&latents);
+	StableDiffusionTxt2ImgOptions::default()
+		.with_prompt(GOLDEN_PROMPT)
+		.with_steps(GOLDEN_STEPS)
+		.with_seed(GOLDEN_SEED)
+		.callback_latents(1, move |_, _, step_latents| {
+			*cb_latents.borrow_mut() = Some(step_latents);
+			true
+		})
+		.run(pipeline, scheduler)?;
+	let latents = latents.borrow_mut().take();
+	latents.ok_or_else(|| anyhow::anyhow!("latents callback was never called"))
+}
+
+fn generate_with<S: SchedulerOptimizedDefaults>(pipeline: &StableDiffusionPipeline) -> anyhow::Result<Array4<f32>> {
+	let mut scheduler = S::stable_diffusion_v1_optimized_default()?;
+	generate_latents(pipeline, &mut scheduler)
+}
+
+/// Generates golden latents for every scheduler enabled in this build, returned as `(name, latents)` pairs.
+pub fn generate_all(pipeline: &StableDiffusionPipeline) -> anyhow::Result<Vec<(&'static str, Array4<f32>)>> {
+	#[allow(unused_mut)]
+	let mut out = Vec::new();
+	#[cfg(feature = "scheduler-euler")]
+	out.push(("euler-discrete", generate_with::<pyke_diffusers::EulerDiscreteScheduler>(pipeline)?));
+	#[cfg(feature = "scheduler-euler-ancestral")]
+	out.push(("euler-ancestral-discrete", generate_with::<pyke_diffusers::EulerAncestralDiscreteScheduler>(pipeline)?));
+	#[cfg(feature = "scheduler-dpm-solver")]
+	out.push(("dpm-solver-multistep", generate_with::<pyke_diffusers::DPMSolverMultistepScheduler>(pipeline)?));
+	#[cfg(feature = "scheduler-ddim")]
+	out.push(("ddim", generate_with::<pyke_diffusers::DDIMScheduler>(pipeline)?));
+	#[cfg(feature = "scheduler-ddpm")]
+	out.push(("ddpm", generate_with::<pyke_diffusers::DDPMScheduler>(pipeline)?));
+	Ok(out)
+}
+
+/// Writes a float32 array as a version 1.0 `.npy` file.
+pub fn write_npy(path: impl AsRef<Path>, array: &Array4<f32>) -> io::Result<()> {
+	let shape = array.shape().iter().map(|d| d.to_string()).collect::<Vec<_>>().join(", ");
+	let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': ({shape}), }}");
+	// magic (6) + version (2) + header length (2) + header must be aligned to 64 bytes, with a trailing newline
+	let unpadded = 10 + header.len() + 1;
+	header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+	header.push('\n');
+
+	let mut file = fs::File::create(path)?;
+	file.write_all(b"\x93NUMPY\x01\x00")?;
+	file.write_all(&(header.len() as u16).to_le_bytes())?;
+	file.write_all(header.as_bytes())?;
+	for value in array.iter() {
+		file.write_all(&value.to_le_bytes())?;
+	}
+	Ok(())
+}
+
+/// Reads a 4-dimensional float32 array from a version 1.0 `.npy` file, as written by [`write_npy`].
+pub fn read_npy(path: impl AsRef<Path>) -> anyhow::Result<Array4<f32>> {
+	let mut bytes = Vec::new();
+	fs::File::open(path)?.read_to_end(&mut bytes)?;
+	if bytes.len() < 10 || &bytes[..8] != b"\x93NUMPY\x01\x00" {
+		anyhow::bail!("not a version 1.0 npy file");
+	}
+	let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+	let header = std::str::from_utf8(&bytes[10..10 + header_len])?;
+	if !header.contains("'descr': '<f4'") || !header.contains("'fortran_order': False") {
+		anyhow::bail!("unsupported npy header: {header}");
+	}
+	let shape = header
+		.split("'shape': (")
+		.nth(1)
+		.and_then(|s| s.split(')').next())
+		.ok_or_else(|| anyhow::anyhow!("npy header has no shape: {header}"))?;
+	let shape = shape
+		.split(',')
+		.map(str::trim)
+		.filter(|s| !s.is_empty())
+		.map(str::parse::<usize>)
+		.collect::<Result<Vec<_>, _>>()?;
+	if shape.len() != 4 {
+		anyhow::bail!("expected a 4-dimensional array, got shape {shape:?}");
+	}
+	let data = bytes[10 + header_len..]
+		.chunks_exact(4)
+		.map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+		.collect::<Vec<_>>();
+	Ok(Array4::from_shape_vec((shape[0], shape[1], shape[2], shape[3]), data)?)
+}
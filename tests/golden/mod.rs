@@ -0,0 +1,35 @@
+//! Golden latents tests. These run a deterministic 5-step generation on the tiny test model for each enabled
+//! scheduler and compare the final latents against the committed `.npy` files in `tests/golden/data`.
+//!
+//! Every enabled scheduler must have a golden file; a missing one fails the test. If a change intentionally alters
+//! outputs (or a scheduler is added), regenerate the goldens with:
+//! ```sh
+//! cargo run --example regenerate-goldens --features all-schedulers
+//! ```
+
+mod common;
+
+use self::common::{generate_all, golden_dir, read_npy, GOLDEN_TOLERANCE};
+
+#[test]
+fn golden_latents() -> anyhow::Result<()> {
+	let pipeline = crate::common::pipeline()?;
+
+	let generated = generate_all(&pipeline)?;
+	// goldens are only meaningful if generation is deterministic in the first place
+	for ((name, latents), (_, again)) in generated.iter().zip(generate_all(&pipeline)?) {
+		assert_eq!(latents, &again, "latents for `{name}` differ between two runs with the same seed");
+	}
+
+	for (name, latents) in generated {
+		let path = golden_dir().join(format!("{name}.npy"));
+		assert!(path.exists(), "no golden latents for `{name}` at {}; run the `regenerate-goldens` example", path.display());
+
+		let golden = read_npy(&path)?;
+		assert_eq!(latents.shape(), golden.shape(), "latent shape mismatch for `{name}`");
+		let max_diff = latents.iter().zip(golden.iter()).map(|(a, b)| (a - b).abs()).fold(0.0_f32, f32::max);
+		assert!(max_diff <= GOLDEN_TOLERANCE, "latents for `{name}` differ from golden by up to {max_diff} (tolerance {GOLDEN_TOLERANCE})");
+	}
+
+	Ok(())
+}
@@ -0,0 +1,40 @@
+use std::{cell::RefCell, rc::Rc};
+
+use ndarray::Array4;
+use pyke_diffusers::{EulerDiscreteScheduler, SchedulerOptimizedDefaults, StableDiffusionOptions, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions};
+
+use crate::common;
+
+fn final_latents(pipeline: &StableDiffusionPipeline, guidance_scale: f32) -> anyhow::Result<Array4<f32>> {
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let latents = Rc::new(RefCell::new(None));
+	let cb_latents = Rc::clone(&latents);
+	let images = StableDiffusionTxt2ImgOptions::default()
+		.with_size(64, 64)
+		.with_prompt(["photo of a red fox", "photo of a grey wolf"])
+		.with_negative_prompt("blurry")
+		.with_steps(2)
+		.with_seed(42)
+		.with_guidance_scale(guidance_scale)
+		.callback_latents(1, move |_, _, step_latents| {
+			*cb_latents.borrow_mut() = Some(step_latents);
+			true
+		})
+		.run(pipeline, &mut scheduler)?;
+	assert_eq!(images.len(), 2);
+	let latents = latents.borrow_mut().take().unwrap();
+	Ok(latents)
+}
+
+#[test]
+fn distilled_unet_receives_guidance_embedding() -> anyhow::Result<()> {
+	let pipeline = common::load_fixture("guidance-embedding", StableDiffusionOptions::default())?;
+
+	// the fixture UNet predicts the mean of the guidance embedding as noise, so the guidance scale changes the result
+	// even though the doubled CFG batch is never run
+	let low = final_latents(&pipeline, 2.0)?;
+	let high = final_latents(&pipeline, 8.0)?;
+	assert_eq!(low.dim(), (2, 4, 8, 8));
+	assert!(low.iter().zip(high.iter()).any(|(a, b)| (a - b).abs() > 1e-3));
+	Ok(())
+}
@@ -1,5 +1,5 @@
-use image::io::Reader;
-use pyke_diffusers::StableDiffusionImg2ImgOptions;
+use image::{io::Reader, DynamicImage, RgbImage};
+use pyke_diffusers::{PadMode, ResizeFilter, ResizeMode, StableDiffusionImg2ImgOptions};
 
 #[test]
 fn keep_image_size() {
@@ -17,3 +17,50 @@ fn keep_image_size_x4() {
 	let view = i2i.get_dimensions();
 	assert_eq!(view, (4, 3, 256, 512));
 }
+
+#[test]
+fn resize_mode_sizes() {
+	let image = DynamicImage::ImageRgb8(RgbImage::new(100, 61));
+	for (mode, size) in [
+		(ResizeMode::Stretch, (104, 64)),
+		(ResizeMode::Pad(PadMode::Edge), (104, 64)),
+		(ResizeMode::Pad(PadMode::Reflect), (104, 64)),
+		(ResizeMode::Crop, (96, 56))
+	] {
+		let i2i = StableDiffusionImg2ImgOptions::default().with_resize_mode(mode).with_image(&image, 2);
+		assert_eq!(i2i.get_size(), size);
+		assert_eq!(i2i.get_dimensions(), (2, 3, size.1 as usize, size.0 as usize));
+		assert_eq!(i2i.original_size, Some((100, 61)));
+	}
+}
+
+#[test]
+fn pad_restores_original() {
+	let image = DynamicImage::ImageRgb8(RgbImage::from_fn(100, 61, |x, y| [x as u8, y as u8, 0].into()));
+	let i2i = StableDiffusionImg2ImgOptions::default()
+		.with_resize_mode(ResizeMode::Pad(PadMode::Reflect))
+		.with_image(&image, 1);
+	// reflected padding mirrors the pixels next to the edge
+	assert_eq!(i2i.reference_image[[0, 0, 0, 100]], i2i.reference_image[[0, 0, 0, 98]]);
+	assert_eq!(i2i.reference_image[[0, 1, 61, 0]], i2i.reference_image[[0, 1, 59, 0]]);
+
+	let restored = i2i.restore_original_size(&DynamicImage::ImageRgb8(RgbImage::new(104, 64)));
+	assert_eq!((restored.width(), restored.height()), (100, 61));
+}
+
+#[test]
+fn preprocessing_options_apply_in_any_order() {
+	let image = DynamicImage::ImageRgb8(RgbImage::from_fn(100, 61, |x, y| [if (x + y) % 3 == 0 { 255 } else { 0 }, 0, 0].into()));
+	let before = StableDiffusionImg2ImgOptions::default()
+		.with_resize_filter(ResizeFilter::Nearest)
+		.with_resize_mode(ResizeMode::Stretch)
+		.with_image(&image, 2);
+	let after = StableDiffusionImg2ImgOptions::default()
+		.with_image(&image, 2)
+		.with_resize_mode(ResizeMode::Stretch)
+		.with_resize_filter(ResizeFilter::Nearest);
+	assert_eq!(after.get_dimensions(), (2, 3, 64, 104));
+	assert_eq!(after.reference_image, before.reference_image);
+	// nearest-neighbor sampling only produces values of the image
+	assert!(after.reference_image.iter().all(|&x| x == 0.0 || x == 1.0));
+}
@@ -0,0 +1,86 @@
+use std::{cell::RefCell, rc::Rc};
+
+use ndarray::{s, Array4};
+use pyke_diffusers::{EulerDiscreteScheduler, SchedulerOptimizedDefaults, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions};
+
+use crate::common;
+
+fn final_latents(pipeline: &StableDiffusionPipeline, options: StableDiffusionTxt2ImgOptions) -> anyhow::Result<(usize, Array4<f32>)> {
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let latents = Rc::new(RefCell::new(None));
+	let cb_latents = Rc::clone(&latents);
+	let images = options
+		.with_size(64, 64)
+		.with_steps(3)
+		.with_seed(42)
+		.callback_latents(1, move |_, _, step_latents| {
+			*cb_latents.borrow_mut() = Some(step_latents);
+			true
+		})
+		.run(pipeline, &mut scheduler)?;
+	let latents = latents.borrow_mut().take().unwrap();
+	Ok((images.len(), latents))
+}
+
+#[test]
+fn three_images_per_prompt_with_cfg() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+
+	let (num_images, latents) = final_latents(
+		&pipeline,
+		StableDiffusionTxt2ImgOptions::default()
+			.with_prompt(["photo of a red fox", "photo of a grey wolf"])
+			.with_negative_prompt(["blurry", "painting"])
+			.with_guidance_scale(7.5)
+			.with_num_images_per_prompt(3)
+	)?;
+	assert_eq!(num_images, 6);
+	assert_eq!(latents.dim(), (6, 4, 8, 8));
+	// each image of a prompt starts from different noise
+	assert_ne!(latents.slice(s![0, .., .., ..]), latents.slice(s![1, .., .., ..]));
+
+	// the first image draws the same initial noise as a single-image batch, so it only matches if its unconditional &
+	// conditional embeddings both belong to the first prompt
+	let (_, single) = final_latents(
+		&pipeline,
+		StableDiffusionTxt2ImgOptions::default()
+			.with_prompt("photo of a red fox")
+			.with_negative_prompt("blurry")
+			.with_guidance_scale(7.5)
+	)?;
+	for (a, b) in latents.slice(s![0, .., .., ..]).iter().zip(single.iter()) {
+		assert!((a - b).abs() < 1e-3, "{a} != {b}");
+	}
+	Ok(())
+}
+
+#[test]
+fn batch_subset_matches_full_batch() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let options = || {
+		StableDiffusionTxt2ImgOptions::default()
+			.with_prompt(["photo of a red fox", "photo of a grey wolf"])
+			.with_negative_prompt("blurry")
+			.with_num_images_per_prompt(2)
+	};
+
+	let (_, full) = final_latents(&pipeline, options())?;
+	let (num_images, subset) = final_latents(&pipeline, options().with_batch_subset([3, 1]))?;
+	assert_eq!(num_images, 2);
+	// the deterministic scheduler generates each element exactly like the full batch does
+	for (i, index) in [1, 3].into_iter().enumerate() {
+		for (a, b) in subset.slice(s![i, .., .., ..]).iter().zip(full.slice(s![index, .., .., ..]).iter()) {
+			assert!((a - b).abs() < 1e-4, "{a} != {b}");
+		}
+	}
+
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let output = options().with_size(64, 64).with_steps(1).with_batch_subset([2]).run_with_output(&pipeline, &mut scheduler)?;
+	assert_eq!(output.generated, [false, false, true, false]);
+	assert_eq!(output.aligned_images().iter().map(Option::is_some).collect::<Vec<_>>(), [false, false, true, false]);
+
+	for subset in [vec![], vec![4], vec![1, 1]] {
+		assert!(options().with_size(64, 64).with_batch_subset(subset).run(&pipeline, &mut scheduler).is_err());
+	}
+	Ok(())
+}
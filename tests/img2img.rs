@@ -0,0 +1,57 @@
+use std::{cell::RefCell, rc::Rc};
+
+use image::{DynamicImage, Rgb, RgbImage};
+use pyke_diffusers::{EulerDiscreteScheduler, SchedulerOptimizedDefaults, StableDiffusionImg2ImgOptions};
+
+use crate::common;
+
+fn reference() -> DynamicImage {
+	DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 128])))
+}
+
+fn options(noise_strength: f32) -> StableDiffusionImg2ImgOptions {
+	StableDiffusionImg2ImgOptions::default()
+		.with_size(64, 64)
+		.with_image(&reference(), 1)
+		.with_steps(4)
+		.with_seed(42)
+		.with_prompt("photo of a red fox")
+		.with_noise_strength(noise_strength)
+}
+
+#[test]
+fn strength_skips_leading_steps() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	for (noise_strength, expected) in [(1.0, vec![0, 1, 2, 3]), (0.5, vec![2, 3]), (0.6, vec![2, 3]), (0.0, vec![])] {
+		let callbacks = Rc::new(RefCell::new(Vec::new()));
+		let cb_callbacks = Rc::clone(&callbacks);
+		let images = options(noise_strength)
+			.callback_progress(1, move |step, _| {
+				cb_callbacks.borrow_mut().push(step);
+				true
+			})
+			.run(&pipeline, &mut scheduler)?;
+		assert_eq!(images.len(), 1);
+		assert_eq!((images[0].width(), images[0].height()), (64, 64));
+		assert_eq!(*callbacks.borrow(), expected, "noise strength {noise_strength}");
+	}
+
+	// the same seed noises the reference image the same way
+	let a = options(0.5).run(&pipeline, &mut scheduler)?;
+	let b = options(0.5).run(&pipeline, &mut scheduler)?;
+	assert_eq!(a[0].to_rgb8(), b[0].to_rgb8());
+	Ok(())
+}
+
+#[test]
+fn reference_image_is_required() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::default();
+	// changing the size drops the reference image
+	assert!(options(0.5).with_size(128, 128).run(&pipeline, &mut scheduler).is_err());
+	let batch = options(0.5).with_images(&[reference(), reference(), reference()]).with_prompt(["a", "b"]);
+	assert!(batch.run(&pipeline, &mut scheduler).is_err());
+	Ok(())
+}
@@ -0,0 +1,216 @@
+use std::{cell::RefCell, rc::Rc};
+
+use image::{DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+use ndarray::{s, Array3, Array4};
+use pyke_diffusers::{
+	EulerDiscreteScheduler, InpaintOptions, ResizeFilter, SchedulerOptimizedDefaults, StableDiffusionOptions, StableDiffusionTxt2ImgOptions,
+	StrengthSchedule
+};
+
+use crate::common;
+
+#[test]
+fn encode_images_to_latents() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+
+	let latents = pipeline.encode_images(Array4::from_elem((2, 3, 64, 64), 0.5).view())?;
+	assert_eq!(latents.dim(), (2, 4, 8, 8));
+	Ok(())
+}
+
+#[test]
+fn legacy_inpaint_tracks_noised_init_latents() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let init_latents = Array4::from_shape_fn((1, 4, 64, 64), |(_, c, y, x)| ((c * 7 + y * 3 + x) % 11) as f32 / 5.0 - 1.0);
+	// regenerate the left half
+	let mask = Array3::from_shape_fn((1, 64, 64), |(_, _, x)| if x < 32 { 1.0 } else { 0.0 });
+
+	let steps = Rc::new(RefCell::new(Vec::new()));
+	let cb_steps = Rc::clone(&steps);
+	StableDiffusionTxt2ImgOptions::default()
+		.with_prompt("photo of a red fox")
+		.with_steps(5)
+		.with_seed(42)
+		.with_inpaint(InpaintOptions::new(init_latents.clone(), mask))
+		.callback_latents(1, move |_, _, step_latents| {
+			cb_steps.borrow_mut().push(step_latents);
+			true
+		})
+		.run(&pipeline, &mut scheduler)?;
+	let steps = steps.borrow();
+	assert_eq!(steps.len(), 5);
+
+	// after each intermediate step, the known region is `init_latents + sigma * noise` for the next step's sigma, so
+	// its deviation from the init latents is the same noise, scaled by a per-step factor
+	let known = s![.., .., .., 32..];
+	let first_deviation = &steps[0].slice(known) - &init_latents.slice(known);
+	for (i, latents) in steps.iter().enumerate().take(steps.len() - 1).skip(1) {
+		let deviation = &latents.slice(known) - &init_latents.slice(known);
+		let ratios = deviation
+			.iter()
+			.zip(first_deviation.iter())
+			.filter(|(_, d0)| d0.abs() > 1e-2)
+			.map(|(d, d0)| d / d0)
+			.collect::<Vec<_>>();
+		let (min, max) = ratios.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &r| (min.min(r), max.max(r)));
+		assert!(max - min <= 1e-3 * max.abs().max(1.0), "step {i}: known region is not the noised init latents ({min}..{max})");
+	}
+
+	// after the final step, the known region is exactly the init latents, while the masked region was regenerated
+	let last = &steps[steps.len() - 1];
+	let max_diff = last.slice(known).iter().zip(init_latents.slice(known).iter()).map(|(a, b)| (a - b).abs()).fold(0.0_f32, f32::max);
+	assert!(max_diff <= 1e-6, "known region differs from init latents by up to {max_diff}");
+	let masked = s![.., .., .., ..32];
+	assert!(last.slice(masked).iter().zip(init_latents.slice(masked).iter()).any(|(a, b)| (a - b).abs() > 1e-3));
+	Ok(())
+}
+
+#[test]
+fn strength_schedule_scales_reinjection() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let init_latents = Array4::from_shape_fn((1, 4, 8, 8), |(_, c, y, x)| ((c * 7 + y * 3 + x) % 11) as f32 / 5.0 - 1.0);
+	// keep the whole image
+	let keep = Array3::zeros((1, 8, 8));
+	let mut final_latents = |strength: Option<StrengthSchedule>| -> anyhow::Result<Array4<f32>> {
+		let latents = Rc::new(RefCell::new(None));
+		let cb_latents = Rc::clone(&latents);
+		let mut options = StableDiffusionTxt2ImgOptions::default().with_size(64, 64).with_prompt("photo of a red fox").with_steps(4).with_seed(42);
+		if let Some(strength) = strength {
+			options = options.with_inpaint(InpaintOptions::new(init_latents.clone(), keep.clone()).with_strength_schedule(strength));
+		}
+		options
+			.callback_latents(1, move |_, _, step_latents| {
+				*cb_latents.borrow_mut() = Some(step_latents);
+				true
+			})
+			.run(&pipeline, &mut scheduler)?;
+		let latents = latents.borrow_mut().take().unwrap();
+		Ok(latents)
+	};
+
+	// a scalar strength of 1 keeps the image exactly, and 0 doesn't re-inject it at all
+	assert_eq!(final_latents(Some(StrengthSchedule::Constant(1.0)))?, init_latents);
+	assert_eq!(final_latents(Some(StrengthSchedule::Constant(0.0)))?, final_latents(None)?);
+	// a decaying ramp follows the image early on but leaves the last step free
+	let ramped = final_latents(Some(StrengthSchedule::Linear { start: 1.0, end: 0.0 }))?;
+	assert_ne!(ramped, init_latents);
+	let distance = |latents: &Array4<f32>| (latents - &init_latents).mapv(|x| x * x).sum();
+	assert!(distance(&ramped) < distance(&final_latents(None)?));
+
+	let mismatched = InpaintOptions::new(init_latents.clone(), keep.clone()).with_strength_schedule(StrengthSchedule::PerStep(vec![1.0; 3]));
+	let result = StableDiffusionTxt2ImgOptions::default()
+		.with_size(64, 64)
+		.with_prompt("photo of a red fox")
+		.with_steps(4)
+		.with_inpaint(mismatched)
+		.run(&pipeline, &mut scheduler);
+	assert!(result.is_err());
+	Ok(())
+}
+
+#[test]
+fn inpaint_keeps_unmasked_pixels() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 128])));
+	// regenerate the top half
+	let mask = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |_, y| Luma([if y < 32 { 255 } else { 0 }])));
+	let options = StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox").with_steps(3).with_seed(42);
+	let output = pipeline.inpaint(&mut scheduler, &image, &mask, options)?;
+	assert_eq!(output.images.len(), 1);
+
+	let original = image.to_rgb32f();
+	let inpainted = output.images.into_iter().next().unwrap().into_image()?.to_rgb32f();
+	assert_eq!(inpainted.dimensions(), (64, 64));
+	for (x, y, pixel) in inpainted.enumerate_pixels() {
+		if y >= 32 {
+			assert_eq!(pixel, original.get_pixel(x, y), "unmasked pixel ({x}, {y}) changed");
+		}
+	}
+	assert!(inpainted.enumerate_pixels().any(|(x, y, pixel)| y < 32 && pixel != original.get_pixel(x, y)));
+
+	// masks of another size are rejected rather than stretched
+	let small_mask = DynamicImage::ImageLuma8(GrayImage::new(32, 32));
+	assert!(InpaintOptions::from_images(&pipeline, &image, &small_mask).is_err());
+	let options = StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox").with_steps(3);
+	assert!(pipeline.inpaint(&mut scheduler, &image, &small_mask, options).is_err());
+	Ok(())
+}
+
+#[test]
+fn mask_resize_filter_is_selectable() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let image = DynamicImage::ImageRgb8(RgbImage::new(64, 64));
+	// the edge falls within a latent pixel
+	let mask = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |_, y| Luma([if y < 28 { 255 } else { 0 }])));
+	let hard = InpaintOptions::from_images(&pipeline, &image, &mask)?;
+	assert!(hard.mask.iter().all(|&m| m == 0.0 || m == 1.0));
+	let soft = InpaintOptions::from_images_with_filter(&pipeline, &image, &mask, ResizeFilter::Triangle)?;
+	assert!(soft.mask.iter().any(|&m| m > 0.0 && m < 1.0));
+	Ok(())
+}
+
+#[test]
+fn mask_channel_inpaint_needs_inpaint_unet() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 64, Rgb([200, 40, 40])));
+	let mask = DynamicImage::ImageLuma8(GrayImage::from_pixel(64, 64, Luma([255])));
+	let options = StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox").with_steps(3);
+	let error = pipeline.inpaint_with_mask_channel(&mut scheduler, &image, &mask, options).unwrap_err();
+	assert!(error.to_string().contains("no inpainting UNet"), "{error}");
+	Ok(())
+}
+
+#[test]
+fn mask_channel_inpaint_runs_inpaint_unet() -> anyhow::Result<()> {
+	// the fixture's inpainting UNet takes 9 channels & predicts half the latents plus the mask channel
+	let pipeline = common::load_fixture("inpaint-unet", StableDiffusionOptions::default())?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 128])));
+	let mask = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |_, y| Luma([if y < 32 { 255 } else { 0 }])));
+	let options = || StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox").with_steps(3).with_seed(42);
+	let output = pipeline.inpaint_with_mask_channel(&mut scheduler, &image, &mask, options())?;
+	assert_eq!((output.images.len(), output.steps_taken), (1, 3));
+	let inpainted = output.images.into_iter().next().unwrap().into_image()?.to_rgb32f();
+	assert_eq!(inpainted.dimensions(), (64, 64));
+
+	// the pipeline's own 4-channel UNet inpaints with latent blending instead, which gives another result
+	let blended = pipeline.inpaint(&mut scheduler, &image, &mask, options())?.images.into_iter().next().unwrap().into_image()?.to_rgb32f();
+	assert!(inpainted.enumerate_pixels().any(|(x, y, pixel)| y < 32 && pixel != blended.get_pixel(x, y)));
+	Ok(())
+}
+
+#[test]
+fn mask_channel_inpaint_binarizes_mask() -> anyhow::Result<()> {
+	let pipeline = common::load_fixture("inpaint-unet", StableDiffusionOptions::default())?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 128])));
+	let mut inpaint = |mask: GrayImage| -> anyhow::Result<image::Rgb32FImage> {
+		let options = StableDiffusionTxt2ImgOptions::default().with_prompt("photo of a red fox").with_steps(3).with_seed(42);
+		let output = pipeline.inpaint_with_mask_channel(&mut scheduler, &image, &DynamicImage::ImageLuma8(mask), options)?;
+		Ok(output.images.into_iter().next().unwrap().into_image()?.to_rgb32f())
+	};
+	// a soft mask regenerates the top half & keeps the bottom half, like the hard mask it binarizes to
+	let soft = inpaint(GrayImage::from_fn(64, 64, |_, y| Luma([if y < 32 { 160 } else { 100 }])))?;
+	let hard = inpaint(GrayImage::from_fn(64, 64, |_, y| Luma([if y < 32 { 255 } else { 0 }])))?;
+	assert_eq!(soft, hard);
+
+	// unmasked pixels are exactly those of the image, rather than blended with the inpainted ones
+	let original = image.to_rgb32f();
+	for (x, y, pixel) in soft.enumerate_pixels() {
+		if y >= 32 {
+			assert_eq!(pixel, original.get_pixel(x, y), "unmasked pixel ({x}, {y}) changed");
+		}
+	}
+	assert!(soft.enumerate_pixels().any(|(x, y, pixel)| y < 32 && pixel != original.get_pixel(x, y)));
+	Ok(())
+}
@@ -0,0 +1,75 @@
+use image::{DynamicImage, Rgb, RgbImage};
+use pyke_diffusers::{
+	DimensionPolicy, EulerDiscreteScheduler, GenerationLimit, GenerationLimits, LimitExceeded, MultiDiffusionOptions, ResizeMode, SchedulerOptimizedDefaults,
+	StableDiffusionImg2ImgOptions, StableDiffusionOptions, StableDiffusionPipeline
+};
+
+use crate::common;
+
+fn pipeline() -> anyhow::Result<StableDiffusionPipeline> {
+	let limits = GenerationLimits::unlimited().with_max_size(64, 64).with_max_batch_size(2).with_max_steps(4).with_max_latent_elements(2 * 4 * 8 * 8);
+	common::pipeline_with(StableDiffusionOptions::default().with_limits(limits))
+}
+
+fn exceeded(result: anyhow::Result<impl Sized>) -> GenerationLimit {
+	match result {
+		Ok(_) => panic!("generation within the limits"),
+		Err(error) => error.downcast_ref::<LimitExceeded>().unwrap_or_else(|| panic!("not a limit error: {error}")).limit
+	}
+}
+
+#[test]
+fn requests_are_bounded() -> anyhow::Result<()> {
+	let pipeline = pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	assert_eq!(common::options(2).with_num_images_per_prompt(2).run(&pipeline, &mut scheduler)?.len(), 2);
+
+	assert_eq!(exceeded(common::options(2).with_size(72, 64).run(&pipeline, &mut scheduler)), GenerationLimit::Width);
+	assert_eq!(exceeded(common::options(2).with_size(64, 72).run(&pipeline, &mut scheduler)), GenerationLimit::Height);
+	assert_eq!(exceeded(common::options(2).with_steps(5).run(&pipeline, &mut scheduler)), GenerationLimit::Steps);
+	assert_eq!(exceeded(common::options(2).with_num_images_per_prompt(3).run(&pipeline, &mut scheduler)), GenerationLimit::BatchSize);
+	// prompts broadcast against a larger batch of negative prompts
+	let negative = common::options(2).with_negative_prompt(["blurry", "dark", "grainy"]);
+	assert_eq!(exceeded(negative.run(&pipeline, &mut scheduler)), GenerationLimit::BatchSize);
+	Ok(())
+}
+
+#[test]
+fn derived_sizes_are_bounded() -> anyhow::Result<()> {
+	let pipeline = pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	// 49px is padded to 64px, which is within the limits, but 57px to 72px
+	let padded = common::options(2).with_size(49, 49).with_dimension_policy(DimensionPolicy::PadAndCrop);
+	assert_eq!(padded.run(&pipeline, &mut scheduler)?.len(), 1);
+	let padded = common::options(2).with_size(57, 49).with_dimension_policy(DimensionPolicy::PadAndCrop);
+	assert_eq!(exceeded(padded.run(&pipeline, &mut scheduler)), GenerationLimit::Width);
+	// MultiDiffusion only runs the UNet on small tiles, but generates the whole canvas
+	let tiled = common::options(2).with_size(128, 64).with_multidiffusion(MultiDiffusionOptions { tile_size: 32, tile_overlap: 16, ..Default::default() });
+	assert_eq!(exceeded(tiled.run(&pipeline, &mut scheduler)), GenerationLimit::Width);
+	// restarts re-run steps
+	let restarted = common::options(2).with_steps(4).with_restart_schedule([(100.0, 999.0, 1)]);
+	assert_eq!(exceeded(restarted.run_with_output(&pipeline, &mut scheduler)), GenerationLimit::Steps);
+	let restarted = common::options(2).with_restart_schedule([(100.0, 999.0, usize::MAX)]);
+	assert_eq!(exceeded(restarted.run_with_output(&pipeline, &mut scheduler)), GenerationLimit::Steps);
+
+	// super-resolution checks the upscaled size
+	let image = DynamicImage::ImageRgb8(RgbImage::from_fn(48, 48, |x, y| Rgb([(x * 5) as u8, (y * 5) as u8, 128])));
+	assert_eq!(exceeded(pipeline.super_resolve(&mut scheduler, &image, 2.0, 0.5, common::options(2))), GenerationLimit::Width);
+	// image-to-image takes its size from the reference image
+	let large = DynamicImage::ImageRgb8(RgbImage::new(96, 64));
+	let img2img = StableDiffusionImg2ImgOptions::default().with_resize_mode(ResizeMode::Crop).with_image(&large, 1).with_prompt("photo of a red fox");
+	assert_eq!(exceeded(img2img.run(&pipeline, &mut scheduler)), GenerationLimit::Width);
+	Ok(())
+}
+
+#[test]
+fn latent_elements_bound_size_and_batch_together() -> anyhow::Result<()> {
+	let limits = GenerationLimits::unlimited().with_max_latent_elements(4 * 8 * 8);
+	let pipeline = common::pipeline_with(StableDiffusionOptions::default().with_limits(limits))?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	assert_eq!(common::options(2).run(&pipeline, &mut scheduler)?.len(), 1);
+	assert_eq!(exceeded(common::options(2).with_num_images_per_prompt(2).run(&pipeline, &mut scheduler)), GenerationLimit::LatentElements);
+	assert_eq!(exceeded(common::options(2).with_size(64, 128).run(&pipeline, &mut scheduler)), GenerationLimit::LatentElements);
+	Ok(())
+}
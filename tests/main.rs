@@ -1 +1,44 @@
+mod animation;
+mod averaged_seeds;
+mod capabilities;
+mod checkpoint;
+mod common;
+mod compositing;
+mod conditioning_dropout;
+mod deepcache;
+mod devices;
+mod dimension_policy;
+mod early_exit;
+mod euler_parity;
+mod golden;
+mod guidance_embedding;
 mod image_progress;
+mod images_per_prompt;
+mod img2img;
+mod inpaint;
+mod limits;
+#[cfg(feature = "mock")]
+mod mock;
+mod onnx_info;
+#[cfg(feature = "parallel-decode")]
+mod parallel_decode;
+mod prepare_latents;
+mod preview;
+mod prompt_cache;
+mod reference_attention;
+mod refiner;
+mod resize_filter;
+mod restart;
+mod sessions;
+mod snapshot;
+#[cfg(feature = "scheduler-ddpm")]
+mod step_limit;
+mod stop_reason;
+mod style_prompt;
+mod super_resolution;
+mod text_embeddings;
+mod to_files;
+mod tokenizer;
+mod trajectory;
+mod txt2img_iter;
+mod unet_outputs;
@@ -0,0 +1,182 @@
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use pyke_diffusers::{
+	CancellationToken, EarlyExit, EulerDiscreteScheduler, GenerationStage, ImageRegion, MockImageInfo, MockPipeline, SchedulerOptimizedDefaults,
+	StableDiffusionTxt2ImgOptions, StopReason, TextToImagePipeline
+};
+
+use crate::common;
+
+fn options() -> StableDiffusionTxt2ImgOptions {
+	common::options(4).with_prompt(["photo of a red fox", "photo of a grey wolf"])
+}
+
+fn infos(pipeline: &impl TextToImagePipeline, options: &StableDiffusionTxt2ImgOptions) -> anyhow::Result<Vec<MockImageInfo>> {
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let output = pipeline.txt2img(&mut scheduler, options)?;
+	output
+		.images
+		.into_iter()
+		.map(|image| MockImageInfo::decode(&image.into_image()?).ok_or_else(|| anyhow::anyhow!("image holds no mock parameters")))
+		.collect()
+}
+
+#[test]
+fn callbacks_see_every_step() -> anyhow::Result<()> {
+	let steps = Rc::new(RefCell::new(Vec::new()));
+	let cb_steps = Rc::clone(&steps);
+	let options = options().callback_staged(1, move |stage, step, timestep| {
+		cb_steps.borrow_mut().push((stage, step, timestep));
+		true
+	});
+	let infos = infos(&MockPipeline::new(), &options)?;
+
+	assert_eq!(
+		*steps.borrow(),
+		[
+			(GenerationStage::Full, 0, 999.0),
+			(GenerationStage::Full, 1, 666.0),
+			(GenerationStage::Full, 2, 333.0),
+			(GenerationStage::Full, 3, 0.0)
+		]
+	);
+	assert_eq!(infos.len(), 2);
+	assert!(infos.iter().all(|info| info.steps_taken == 4));
+	Ok(())
+}
+
+#[test]
+fn callback_cancels_generation() -> anyhow::Result<()> {
+	let calls = Rc::new(RefCell::new(0));
+	let cb_calls = Rc::clone(&calls);
+	let options = || {
+		let cb_calls = Rc::clone(&cb_calls);
+		options().callback_progress(1, move |step, _| {
+			*cb_calls.borrow_mut() += 1;
+			step < 1
+		})
+	};
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let output = MockPipeline::new().txt2img(&mut scheduler, &options())?;
+	assert_eq!(*calls.borrow(), 2);
+	assert_eq!((output.stop_reason, output.steps_taken), (StopReason::CallbackStop, 2));
+	// the partial latents are returned without decoding them
+	assert!(output.images.is_empty());
+	assert_eq!(output.latents.map(|latents| latents.dim()), Some((2, 4, 8, 8)));
+
+	let infos = infos(&MockPipeline::new(), &options().with_decode_on_early_stop(true))?;
+	assert_eq!(infos.len(), 2);
+	assert!(infos.iter().all(|info| info.steps == 4 && info.steps_taken == 2));
+	Ok(())
+}
+
+#[test]
+fn cancellation_and_timeouts_stop_generation() -> anyhow::Result<()> {
+	let pipeline = MockPipeline::new();
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let output = pipeline.txt2img(&mut scheduler, &options())?;
+	assert_eq!((output.stop_reason, output.latents.is_none(), output.images.len()), (StopReason::Completed, true, 2));
+
+	let token = CancellationToken::new();
+	let result = pipeline.txt2img(&mut scheduler, &options().with_cancellation_token(token.clone()));
+	assert_eq!(StopReason::of(&result), StopReason::Completed);
+	token.cancel();
+	let output = pipeline.txt2img(&mut scheduler, &options().with_cancellation_token(token))?;
+	assert_eq!((output.stop_reason, output.steps_taken), (StopReason::Cancelled, 0));
+	assert!(output.images.is_empty() && output.latents.is_some());
+
+	let output = pipeline.txt2img(&mut scheduler, &options().with_timeout(Duration::ZERO))?;
+	assert_eq!((output.stop_reason, output.steps_taken), (StopReason::TimedOut, 0));
+	let output = pipeline.txt2img(&mut scheduler, &options().with_timeout(Duration::from_secs(60)))?;
+	assert_eq!(output.stop_reason, StopReason::Completed);
+
+	assert_eq!(StopReason::of(&pipeline.txt2img(&mut scheduler, &options().with_size(63, 64))), StopReason::Error);
+	Ok(())
+}
+
+#[test]
+fn preview_and_decoded_callbacks() -> anyhow::Result<()> {
+	let previews = Rc::new(RefCell::new(Vec::new()));
+	let regions = Rc::new(RefCell::new(Vec::new()));
+	let (cb_previews, cb_regions) = (Rc::clone(&previews), Rc::clone(&regions));
+	let options = options()
+		.with_preview(2, move |images| cb_previews.borrow_mut().push(images.len()))
+		.callback_region_decoded(2, ImageRegion::new(8, 16, 32, 24), move |step, _, images| {
+			cb_regions.borrow_mut().extend(images.iter().map(|image| (step, image.width(), image.height())));
+			true
+		});
+	infos(&MockPipeline::new(), &options)?;
+
+	assert_eq!(*previews.borrow(), [2]);
+	assert_eq!(*regions.borrow(), [(2, 32, 24), (2, 32, 24)]);
+	Ok(())
+}
+
+#[test]
+fn output_is_deterministic() -> anyhow::Result<()> {
+	let pipeline = MockPipeline::new();
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let first = pipeline.txt2img(&mut scheduler, &options())?.images;
+	let second = pipeline.txt2img(&mut scheduler, &options())?.images;
+	for (a, b) in first.into_iter().zip(second) {
+		assert_eq!(a.into_image()?.to_rgb8(), b.into_image()?.to_rgb8());
+	}
+
+	let infos = infos(&pipeline, &options().with_guidance_scale(5.0))?;
+	assert_eq!(infos[0].seed, 42);
+	assert_eq!(infos[0].guidance_scale, 5.0);
+	assert_eq!((infos[0].index, infos[1].index), (0, 1));
+	assert_ne!(infos[0].prompt_hash, infos[1].prompt_hash);
+	// the prompt hash depends only on the prompt, not its position in the batch
+	let fox = infos(&pipeline, &options().with_prompt("photo of a red fox"))?;
+	assert_eq!(fox[0].prompt_hash, infos[0].prompt_hash);
+	Ok(())
+}
+
+#[test]
+fn custom_sigmas_set_steps() -> anyhow::Result<()> {
+	let options = options().with_custom_sigmas([14.0, 7.0, 1.0, 0.0]);
+	let infos = infos(&MockPipeline::new(), &options)?;
+	assert!(infos.iter().all(|info| info.steps == 3 && info.steps_taken == 3));
+
+	let options = options().with_custom_sigmas([1.0, 7.0]);
+	assert!(infos(&MockPipeline::new(), &options).is_err());
+	Ok(())
+}
+
+#[test]
+fn early_exit_on_convergence() -> anyhow::Result<()> {
+	let pipeline = MockPipeline::new();
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let full = pipeline.txt2img(&mut scheduler, &options().with_steps(8))?;
+	assert_eq!(full.steps_taken, 8);
+
+	// a threshold of 0 never exits early, so the output is identical
+	let output = pipeline.txt2img(&mut scheduler, &options().with_steps(8).with_early_exit(EarlyExit::new(0, 0.0)))?;
+	assert_eq!(output.steps_taken, 8);
+	for (a, b) in full.images.into_iter().zip(output.images) {
+		assert_eq!(a.into_image()?.to_rgb8(), b.into_image()?.to_rgb8());
+	}
+
+	// every step counts as converged with a huge threshold, so the run exits 2 steps after `min_steps`
+	let output = pipeline.txt2img(&mut scheduler, &options().with_steps(8).with_early_exit(EarlyExit::new(3, 1e9)))?;
+	assert_eq!(output.steps_taken, 5);
+	let info = MockImageInfo::decode(&output.images[0].clone().into_image()?).unwrap();
+	assert_eq!((info.steps, info.steps_taken), (8, 5));
+	Ok(())
+}
+
+#[test]
+fn batch_subset_keeps_indices() -> anyhow::Result<()> {
+	let full = infos(&MockPipeline::new(), &options().with_num_images_per_prompt(2))?;
+	let subset = infos(&MockPipeline::new(), &options().with_num_images_per_prompt(2).with_batch_subset([3, 0]))?;
+	assert_eq!(subset.len(), 2);
+	assert_eq!(subset[0], full[0]);
+	assert_eq!(subset[1], full[3]);
+
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let output = MockPipeline::new().txt2img(&mut scheduler, &options().with_batch_subset([1]))?;
+	assert_eq!(output.generated, [false, true]);
+	assert!(output.aligned_images()[0].is_none());
+	Ok(())
+}
@@ -0,0 +1,33 @@
+use std::fs;
+
+use pyke_diffusers::StableDiffusionPipeline;
+
+use crate::common;
+
+#[test]
+fn preflight_reports_fixture_models() -> anyhow::Result<()> {
+	let report = StableDiffusionPipeline::preflight(common::TEST_MODEL)?;
+	assert_eq!(report.components.len(), 4);
+	assert!(report.is_compatible(), "{report}");
+	for component in &report.components {
+		let info = component.info.as_ref().unwrap();
+		assert_eq!(info.ir_version, 8);
+		assert_eq!(info.opset_version("ai.onnx"), Some(15));
+	}
+	Ok(())
+}
+
+#[test]
+fn load_failure_includes_report() -> anyhow::Result<()> {
+	let mut pipeline = common::pipeline()?;
+
+	let path = std::env::temp_dir().join("pyke-diffusers-not-a-model.onnx");
+	fs::write(&path, b"definitely not an ONNX model")?;
+	let error = pipeline.replace_unet(&path).unwrap_err().to_string();
+	fs::remove_file(&path)?;
+
+	assert!(error.starts_with("failed to load UNet: "), "{error}");
+	assert!(error.contains("ONNX compatibility report"), "{error}");
+	assert!(error.contains("warning: could not read model header"), "{error}");
+	Ok(())
+}
@@ -0,0 +1,17 @@
+use ndarray::Array4;
+use pyke_diffusers::StableDiffusionOptions;
+
+use crate::common;
+
+#[test]
+fn parallel_decode_matches_sequential() -> anyhow::Result<()> {
+	let latents = Array4::from_shape_fn((3, 4, 8, 8), |(b, c, y, x)| ((b * 5 + c * 3 + y * 2 + x) % 7) as f32 / 7.0 - 0.5);
+
+	let sequential = common::pipeline()?.decode_latents(latents.view())?;
+	let parallel = common::pipeline_with(StableDiffusionOptions::default().with_parallel_decode(3))?.decode_latents(latents.view())?;
+	assert_eq!(parallel.len(), 3);
+	for (sequential, parallel) in sequential.iter().zip(parallel.iter()) {
+		assert_eq!(sequential.to_rgb32f().as_raw(), parallel.to_rgb32f().as_raw());
+	}
+	Ok(())
+}
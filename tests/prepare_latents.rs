@@ -0,0 +1,72 @@
+use pyke_diffusers::{
+	DiffusionScheduler, EulerAncestralDiscreteScheduler, EulerDiscreteScheduler, LatentStats, RngDrawOrder, SchedulerOptimizedDefaults,
+	StableDiffusionTxt2ImgOptions
+};
+
+use crate::common;
+
+const UNIT_VARIANCE: LatentStats = LatentStats { mean: [0.0; 4], std: [1.0; 4] };
+
+#[test]
+fn prepared_latents_match_seeded_generation() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let latents = pipeline.prepare_latents(1, 64, 64, 42, &scheduler);
+	assert_eq!(latents.dim(), (1, 4, 8, 8));
+	// already scaled by `init_noise_sigma`
+	assert!(latents.std(0.0) > 10.0);
+
+	let expected = common::options(3).run(&pipeline, &mut scheduler)?[0].to_rgb8();
+	let images = common::options(3).with_latents(latents.clone()).run(&pipeline, &mut scheduler)?;
+	assert_eq!(images[0].to_rgb8(), expected);
+
+	let modified = common::options(3).with_latents(latents.clone() * 0.5).run(&pipeline, &mut scheduler)?;
+	assert_ne!(modified[0].to_rgb8(), expected);
+
+	assert!(common::options(3).with_latents(pipeline.prepare_latents(2, 64, 64, 42, &scheduler)).run(&pipeline, &mut scheduler).is_err());
+	Ok(())
+}
+
+#[test]
+fn normalized_latents_ignore_scale() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let latents = pipeline.prepare_latents(1, 64, 64, 42, &scheduler);
+	let mut normalized = |latents| {
+		common::options(3)
+			.with_latents(latents)
+			.with_latents_normalized_to(UNIT_VARIANCE)
+			.run(&pipeline, &mut scheduler)
+	};
+	let expected = normalized(latents.clone())?[0].to_rgb8();
+	assert_eq!(normalized(latents * 0.5)?[0].to_rgb8(), expected);
+
+	assert!(common::options(3).with_latents_normalized_to(UNIT_VARIANCE).run(&pipeline, &mut scheduler).is_err());
+	Ok(())
+}
+
+#[test]
+fn prepared_latents_honor_options() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerAncestralDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	// unscaled with `skip_init_noise_scaling`, and generating from them still matches the seeded generation
+	let unscaled = || common::options(3).with_skip_init_noise_scaling(true);
+	let latents = unscaled().prepare_latents(1, &scheduler)?;
+	assert!(latents.std(0.0) < 2.0);
+	assert_eq!(latents * scheduler.init_noise_sigma(), pipeline.prepare_latents(1, 64, 64, 42, &scheduler));
+	let expected = unscaled().run(&pipeline, &mut scheduler)?[0].to_rgb8();
+	let latents = unscaled().prepare_latents(1, &scheduler)?;
+	assert_eq!(unscaled().with_latents(latents).run(&pipeline, &mut scheduler)?[0].to_rgb8(), expected);
+
+	// the ancestral noise continues the latents' RNG with the sequential draw order
+	let sequential = || common::options(3).with_rng_draw_order(RngDrawOrder::Sequential);
+	let expected = sequential().run(&pipeline, &mut scheduler)?[0].to_rgb8();
+	let latents = sequential().prepare_latents(1, &scheduler)?;
+	assert_eq!(sequential().with_latents(latents).run(&pipeline, &mut scheduler)?[0].to_rgb8(), expected);
+
+	assert!(StableDiffusionTxt2ImgOptions::default().prepare_latents(1, &scheduler).is_err(), "latents were prepared without a seed");
+	Ok(())
+}
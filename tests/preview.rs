@@ -0,0 +1,36 @@
+use std::{cell::RefCell, rc::Rc};
+
+use pyke_diffusers::{EulerDiscreteScheduler, SchedulerOptimizedDefaults, StableDiffusionTxt2ImgOptions};
+
+use crate::common;
+
+#[test]
+fn preview_fires_once_then_generation_continues() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let previews = Rc::new(RefCell::new(Vec::new()));
+	let steps = Rc::new(RefCell::new(0));
+	let (cb_previews, cb_steps) = (Rc::clone(&previews), Rc::clone(&steps));
+	let images = StableDiffusionTxt2ImgOptions::default()
+		.with_size(64, 64)
+		.with_prompt(["photo of a red fox", "photo of a grey wolf"])
+		.with_steps(4)
+		.with_seed(42)
+		.with_preview(2, move |images| cb_previews.borrow_mut().push((*cb_steps.borrow(), images)))
+		.callback_progress(1, move |_, _| {
+			*steps.borrow_mut() += 1;
+			true
+		})
+		.run(&pipeline, &mut scheduler)?;
+
+	let previews = previews.borrow();
+	assert_eq!(previews.len(), 1);
+	let (completed_steps, preview) = &previews[0];
+	// the preview is generated after the 2nd step, before that step's progress callback
+	assert_eq!(*completed_steps, 1);
+	assert_eq!(preview.len(), 2);
+	assert_eq!(images.len(), 2);
+	assert_eq!((images[0].width(), images[0].height()), (64, 64));
+	Ok(())
+}
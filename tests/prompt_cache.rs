@@ -0,0 +1,163 @@
+use std::{
+	fs,
+	path::{Path, PathBuf}
+};
+
+use half::f16;
+use ndarray::ArrayD;
+use pyke_diffusers::{Prompt, PromptCacheConfig, PromptCacheStats, StableDiffusionOptions, WeightNormalization};
+
+use crate::common;
+
+const PROMPT: &str = "photo of a red fox";
+
+fn fresh_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("pyke-diffusers-prompt-cache-{name}"));
+	let _ = fs::remove_dir_all(&dir);
+	dir
+}
+
+fn encode(root: &Path, options: StableDiffusionOptions) -> anyhow::Result<ArrayD<f32>> {
+	let pipeline = common::load(root, options)?;
+	pipeline.encode_prompt(PROMPT.into(), true, None)
+}
+
+fn cached(dir: &Path) -> StableDiffusionOptions {
+	StableDiffusionOptions::default().with_prompt_cache(PromptCacheConfig::new(8).with_dir(dir))
+}
+
+fn rounded(embeddings: &ArrayD<f32>) -> ArrayD<f32> {
+	embeddings.mapv(|x| f16::from_f32(x).to_f32())
+}
+
+fn entries(dir: &Path) -> Vec<PathBuf> {
+	fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect()
+}
+
+/// Copies the test model to a temporary root, so its files can be modified.
+fn model_copy(name: &str) -> PathBuf {
+	let root = fresh_dir(name);
+	fs::create_dir_all(&root).unwrap();
+	for entry in fs::read_dir(common::TEST_MODEL).unwrap() {
+		let path = entry.unwrap().path();
+		fs::copy(&path, root.join(path.file_name().unwrap())).unwrap();
+	}
+	root
+}
+
+#[test]
+fn embeddings_are_persisted() -> anyhow::Result<()> {
+	let dir = fresh_dir("persisted");
+	let exact = encode(Path::new(common::TEST_MODEL), StableDiffusionOptions::default())?;
+	assert_ne!(exact, rounded(&exact));
+
+	let pipeline = common::pipeline_with(cached(&dir))?;
+	assert_eq!(pipeline.encode_prompt(PROMPT.into(), true, None)?, exact);
+	// the prompt & the (empty) negative prompt are separate entries
+	assert_eq!(entries(&dir).len(), 2);
+	// resident entries are kept at full precision
+	assert_eq!(pipeline.encode_prompt(PROMPT.into(), true, None)?, exact);
+	// once evicted, the entry is loaded from disk, compressed to float16
+	pipeline.clear_prompt_cache();
+	assert_eq!(pipeline.encode_prompt(PROMPT.into(), true, None)?, rounded(&exact));
+
+	// other pipelines share the persisted entry
+	assert_eq!(encode(Path::new(common::TEST_MODEL), cached(&dir))?, rounded(&exact));
+	// ...and other negative prompts only add their own entry
+	assert_ne!(pipeline.encode_prompt(PROMPT.into(), true, Some(&"blurry".into()))?, rounded(&exact));
+	assert_eq!(entries(&dir).len(), 3);
+	Ok(())
+}
+
+#[test]
+fn corrupt_entries_are_skipped() -> anyhow::Result<()> {
+	let dir = fresh_dir("corrupt");
+	let exact = encode(Path::new(common::TEST_MODEL), cached(&dir))?;
+
+	for entry in entries(&dir) {
+		let mut truncated = fs::read(&entry)?;
+		truncated.truncate(truncated.len() / 2);
+		fs::write(&entry, truncated)?;
+	}
+	assert_eq!(encode(Path::new(common::TEST_MODEL), cached(&dir))?, exact);
+
+	for entry in entries(&dir) {
+		fs::write(&entry, b"not a prompt cache entry")?;
+	}
+	assert_eq!(encode(Path::new(common::TEST_MODEL), cached(&dir))?, exact);
+	// the corrupt entry was overwritten with a valid one
+	assert_eq!(encode(Path::new(common::TEST_MODEL), cached(&dir))?, rounded(&exact));
+	Ok(())
+}
+
+#[test]
+fn encoding_options_invalidate_entries() -> anyhow::Result<()> {
+	let dir = fresh_dir("options");
+	encode(Path::new(common::TEST_MODEL), cached(&dir))?;
+
+	let options = StableDiffusionOptions {
+		weight_normalization: WeightNormalization::PerChunk,
+		..Default::default()
+	};
+	let exact = encode(Path::new(common::TEST_MODEL), options.clone())?;
+	assert_eq!(encode(Path::new(common::TEST_MODEL), options.with_prompt_cache(PromptCacheConfig::new(8).with_dir(&dir)))?, exact);
+	Ok(())
+}
+
+#[test]
+fn text_encoder_hash_invalidates_entries() -> anyhow::Result<()> {
+	let dir = fresh_dir("text-encoder");
+	let exact = encode(Path::new(common::TEST_MODEL), cached(&dir))?;
+
+	let root = model_copy("text-encoder-model");
+	let config = fs::read_to_string(root.join("pyke-diffusers.toml"))?;
+	fs::write(root.join("pyke-diffusers.toml"), config.replace("text-encoder = \"ebc419d220f352228add55a2f0586702\"", "text-encoder = \"retrained\""))?;
+	assert_eq!(encode(&root, cached(&dir))?, exact);
+	Ok(())
+}
+
+#[test]
+fn tokenizer_digest_invalidates_entries() -> anyhow::Result<()> {
+	let dir = fresh_dir("tokenizer");
+	let exact = encode(Path::new(common::TEST_MODEL), cached(&dir))?;
+
+	let root = model_copy("tokenizer-model");
+	let mut tokenizer = fs::read(root.join("tokenizer.json"))?;
+	tokenizer.push(b'\n');
+	fs::write(root.join("tokenizer.json"), tokenizer)?;
+	assert_eq!(encode(&root, cached(&dir))?, exact);
+	// the unchanged model still finds its entry
+	assert_eq!(encode(Path::new(common::TEST_MODEL), cached(&dir))?, rounded(&exact));
+	Ok(())
+}
+
+#[test]
+fn halves_are_cached_separately() -> anyhow::Result<()> {
+	let options = StableDiffusionOptions::default().with_prompt_cache(PromptCacheConfig::new(8));
+	let pipeline = common::pipeline_with(options)?;
+	let stats = |hits, misses| Some(PromptCacheStats { hits, misses });
+	let negative = |text: &str| Prompt::from(text);
+
+	let blurry = pipeline.encode_prompt(PROMPT.into(), true, Some(&negative("blurry")))?;
+	assert_eq!(pipeline.prompt_cache_stats(), stats(0, 2));
+	// only the negative prompt changed, so only it is encoded
+	let dark = pipeline.encode_prompt(PROMPT.into(), true, Some(&negative("dark")))?;
+	assert_eq!(pipeline.prompt_cache_stats(), stats(1, 3));
+	assert_eq!(pipeline.encode_prompt(PROMPT.into(), true, Some(&negative("blurry")))?, blurry);
+	assert_eq!(pipeline.prompt_cache_stats(), stats(3, 3));
+	// ...and the other way around
+	pipeline.encode_prompt("photo of a grey wolf".into(), true, Some(&negative("dark")))?;
+	assert_eq!(pipeline.prompt_cache_stats(), stats(4, 4));
+	// without classifier-free guidance, only the prompt is looked up
+	pipeline.encode_prompt(PROMPT.into(), false, Some(&negative("dark")))?;
+	assert_eq!(pipeline.prompt_cache_stats(), stats(5, 4));
+
+	// the halves were assembled into the same embeddings a fresh encode produces
+	let uncached = common::pipeline()?;
+	assert_eq!(uncached.prompt_cache_stats(), None);
+	let dark_uncached = uncached.encode_prompt(PROMPT.into(), true, Some(&negative("dark")))?;
+	assert_eq!(dark.shape(), dark_uncached.shape());
+	let max_diff = dark.iter().zip(dark_uncached.iter()).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+	assert!(max_diff < 1e-4, "cached halves differ from a fresh encode by {max_diff}");
+	Ok(())
+}
@@ -0,0 +1,41 @@
+use std::{cell::RefCell, rc::Rc};
+
+use pyke_diffusers::{EulerDiscreteScheduler, GenerationStage, SchedulerOptimizedDefaults};
+
+use crate::common;
+
+#[test]
+fn refiner_continues_base_schedule() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let mut refiner_scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let stages = Rc::new(RefCell::new(Vec::new()));
+	let (base_stages, refiner_stages) = (Rc::clone(&stages), Rc::clone(&stages));
+	let output = pipeline.txt2img_with_refiner(
+		&mut scheduler,
+		common::options(4).with_denoising_end(0.5).callback_staged(1, move |stage, step, _| {
+			base_stages.borrow_mut().push((stage, step));
+			true
+		}),
+		&pipeline,
+		&mut refiner_scheduler,
+		common::options(4).callback_staged(1, move |stage, step, _| {
+			refiner_stages.borrow_mut().push((stage, step));
+			true
+		})
+	)?;
+
+	assert_eq!(
+		*stages.borrow(),
+		[(GenerationStage::Base, 0), (GenerationStage::Base, 1), (GenerationStage::Refiner, 2), (GenerationStage::Refiner, 3)]
+	);
+	assert_eq!(output.images.len(), 1);
+	assert_eq!(output.step_stats.iter().map(|stats| stats.step).collect::<Vec<_>>(), [0, 1, 2, 3]);
+
+	// with the same model & a stateless scheduler, handing off to a "refiner" follows the single-stage trajectory
+	let expected = common::options(4).run(&pipeline, &mut scheduler)?[0].to_rgb8();
+	let refined = output.images.into_iter().next().unwrap().into_image()?.to_rgb8();
+	assert!(refined.pixels().zip(expected.pixels()).all(|(a, b)| a.0.iter().zip(b.0.iter()).all(|(a, b)| a.abs_diff(*b) <= 1)));
+	Ok(())
+}
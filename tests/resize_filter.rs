@@ -0,0 +1,61 @@
+use image::{DynamicImage, GrayImage, Luma};
+use ndarray::Array4;
+use pyke_diffusers::{
+	prepare_inpaint_mask, prepare_inpaint_mask_with_filter, ControlNetConfig, EulerDiscreteScheduler, ResizeFilter, StableDiffusionTxt2ImgOptions
+};
+
+use crate::common;
+
+/// A Canny-style edge map: 1 pixel wide white lines on black.
+fn edge_map() -> DynamicImage {
+	DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, y| Luma([if x % 5 == 0 || (x + y) % 11 == 0 { 255 } else { 0 }])))
+}
+
+fn is_binary(image: &DynamicImage) -> bool {
+	image.to_luma8().pixels().all(|pixel| pixel.0[0] == 0 || pixel.0[0] == 255)
+}
+
+#[test]
+fn nearest_keeps_edge_maps_binary() {
+	assert!(is_binary(&edge_map()));
+	assert!(is_binary(&ResizeFilter::Nearest.resize(&edge_map(), 40, 40)));
+	assert!(is_binary(&ResizeFilter::Nearest.resize(&edge_map(), 96, 96)));
+
+	let smoothed = ResizeFilter::Lanczos3.resize(&edge_map(), 40, 40);
+	assert_eq!((smoothed.width(), smoothed.height()), (40, 40));
+	assert!(!is_binary(&smoothed));
+}
+
+#[test]
+fn inpaint_mask_filters() {
+	let mask = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, _| Luma([if x >= 28 { 255 } else { 0 }])));
+	let hard = prepare_inpaint_mask(&mask, 64, 64);
+	assert!(hard.iter().all(|&m| m == 0.0 || m == 1.0));
+	assert_eq!(prepare_inpaint_mask_with_filter(&mask, 64, 64, ResizeFilter::Nearest), hard);
+	let soft = prepare_inpaint_mask_with_filter(&mask, 64, 64, ResizeFilter::Triangle);
+	assert!(soft.iter().any(|&m| m > 0.0 && m < 1.0));
+}
+
+#[test]
+fn controlnet_conditioning_images() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	// the conditioning image is prepared before the ControlNet runs, so any model stands in for one
+	let controlnet = pipeline.load_controlnet("tests/stable-diffusion/unet.onnx")?;
+	let is_binary = |cond: &Array4<f32>| cond.iter().all(|&x| x == 0.0 || x == 1.0);
+
+	let config = ControlNetConfig::new(controlnet.clone(), edge_map());
+	assert!(is_binary(&config.conditioning_image(64, 64).unwrap()));
+	// of another size, conditioning images are only resized with a filter
+	assert!(config.conditioning_image(40, 40).is_none());
+	let options = StableDiffusionTxt2ImgOptions::default().with_size(40, 40).with_steps(1).with_controlnet(config);
+	let err = options.run(&pipeline, &mut EulerDiscreteScheduler::default()).unwrap_err();
+	assert!(err.to_string().contains("ControlNet #1's conditioning image is 64x64"), "{err}");
+
+	let nearest = ControlNetConfig::new(controlnet.clone(), edge_map()).with_resize_filter(ResizeFilter::Nearest);
+	let cond = nearest.conditioning_image(40, 40).unwrap();
+	assert_eq!(cond.shape(), [1, 3, 40, 40]);
+	assert!(is_binary(&cond));
+	let lanczos = ControlNetConfig::new(controlnet, edge_map()).with_resize_filter(ResizeFilter::Lanczos3);
+	assert!(!is_binary(&lanczos.conditioning_image(40, 40).unwrap()));
+	Ok(())
+}
@@ -0,0 +1,36 @@
+use std::{cell::RefCell, rc::Rc};
+
+use pyke_diffusers::{EulerDiscreteScheduler, SchedulerOptimizedDefaults, StableDiffusionTxt2ImgOptions};
+
+use crate::common;
+
+#[test]
+fn restarts_rerun_interval() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let generate = |scheduler: &mut EulerDiscreteScheduler| -> anyhow::Result<(Vec<usize>, Vec<u8>)> {
+		let steps = Rc::new(RefCell::new(Vec::new()));
+		let cb_steps = Rc::clone(&steps);
+		let images = StableDiffusionTxt2ImgOptions::default()
+			.with_size(64, 64)
+			.with_prompt("photo of a red fox")
+			.with_steps(4)
+			.with_seed(42)
+			// timesteps are 999, 666, 333, 0: once denoising reaches 0, re-noise to 666 & re-run steps 1 & 2
+			.with_restart_schedule([(100.0, 700.0, 1)])
+			.callback_progress(1, move |step, _| {
+				cb_steps.borrow_mut().push(step);
+				true
+			})
+			.run(&pipeline, scheduler)?;
+		let steps = steps.borrow().clone();
+		Ok((steps, images[0].to_rgb8().into_raw()))
+	};
+
+	let (steps, image) = generate(&mut scheduler)?;
+	assert_eq!(steps, [0, 1, 2, 1, 2, 3]);
+	// restart noise is seeded, so restarted runs are reproducible
+	assert_eq!(generate(&mut scheduler)?.1, image);
+	Ok(())
+}
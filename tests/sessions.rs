@@ -0,0 +1,103 @@
+use std::{path::PathBuf, sync::Arc};
+
+use pyke_diffusers::{
+	ArenaExtendStrategy, CUDAExecutionProviderOptions, DiffusionDevice, DiffusionDeviceControl, ExecutionContext, OrtEnvironment, PipelineLoadErrors,
+	ResidentLimitExceeded, SessionTracker, StableDiffusionOptions, StableDiffusionPipeline
+};
+
+use crate::common;
+
+#[test]
+fn pipeline_sessions_are_tracked() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+
+	let sessions = SessionTracker::global().active_sessions();
+	for component in ["text encoder", "VAE encoder", "VAE decoder", "UNet"] {
+		assert!(sessions.iter().any(|s| s.component == component && s.size_bytes > 0), "{component} is not tracked: {sessions:?}");
+	}
+	assert!(SessionTracker::global().resident_bytes() > 0);
+
+	drop(pipeline);
+	Ok(())
+}
+
+#[test]
+fn resident_limit_refuses_to_load() -> anyhow::Result<()> {
+	let options = StableDiffusionOptions {
+		max_resident_bytes: Some(1),
+		..Default::default()
+	};
+	let err = match common::pipeline_with(options) {
+		Ok(_) => panic!("pipeline loaded despite resident limit"),
+		Err(err) => err
+	};
+	let err = err.downcast::<ResidentLimitExceeded>()?;
+	assert_eq!(err.component, "text encoder");
+	assert_eq!(err.limit_bytes, 1);
+
+	let options = StableDiffusionOptions {
+		max_resident_bytes: Some(u64::MAX),
+		..Default::default()
+	};
+	common::pipeline_with(options)?;
+	Ok(())
+}
+
+#[test]
+fn from_roots_aggregates_failures() -> anyhow::Result<()> {
+	let environment = OrtEnvironment::default().into_arc();
+	let roots = [PathBuf::from(common::TEST_MODEL), PathBuf::from("tests/missing-a"), PathBuf::from("tests/missing-b")];
+	let err = match StableDiffusionPipeline::from_roots(&environment, &roots, StableDiffusionOptions::default()) {
+		Ok(_) => panic!("pipelines loaded despite missing models"),
+		Err(err) => err
+	};
+	let err = err.downcast::<PipelineLoadErrors>()?;
+	assert_eq!(err.failures.iter().map(|(root, _)| root.clone()).collect::<Vec<_>>(), &roots[1..]);
+	assert!(err.to_string().contains("tests/missing-b"));
+
+	let pipelines = StableDiffusionPipeline::from_roots(&environment, &roots[..1], StableDiffusionOptions::default())?;
+	assert_eq!(pipelines.len(), 1);
+	Ok(())
+}
+
+#[test]
+fn context_shares_provider_configuration() -> anyhow::Result<()> {
+	let environment = OrtEnvironment::default().into_arc();
+	let cuda_options = CUDAExecutionProviderOptions {
+		gpu_mem_limit: Some(1 << 30),
+		arena_extend_strategy: Some(ArenaExtendStrategy::SameAsRequested),
+		..Default::default()
+	};
+	let context = ExecutionContext::new(&environment).with_cuda_options(cuda_options.clone());
+	let options = || StableDiffusionOptions {
+		devices: DiffusionDeviceControl::all(DiffusionDevice::CUDA(0, None)),
+		..Default::default()
+	};
+	let roots = [PathBuf::from(common::TEST_MODEL), PathBuf::from(common::TEST_MODEL)];
+	let pipelines = StableDiffusionPipeline::from_roots_with_context(&context, &roots, options())?;
+	let own_context = StableDiffusionPipeline::new(&environment, common::TEST_MODEL, options())?;
+
+	let providers = |pipeline: &StableDiffusionPipeline| pipeline.sessions().into_iter().map(|s| (s.component, s.execution_provider)).collect::<Vec<_>>();
+	assert!(!providers(&pipelines[0]).is_empty());
+	assert_eq!(providers(&pipelines[0]), providers(&pipelines[1]));
+	assert!(Arc::ptr_eq(pipelines[0].execution_context().environment(), pipelines[1].execution_context().environment()));
+
+	// sessions report the provider they run on: CUDA with the context's options, or the CPU if CUDA isn't available
+	let (shared, own) = match own_context.active_execution_provider().unet {
+		DiffusionDevice::CPU => (format!("{:?}", DiffusionDevice::CPU), format!("{:?}", DiffusionDevice::CPU)),
+		_ => (format!("{:?}", DiffusionDevice::CUDA(0, Some(cuda_options.clone()))), format!("{:?}", DiffusionDevice::CUDA(0, None)))
+	};
+	for pipeline in &pipelines {
+		assert!(pipeline.sessions().iter().all(|s| s.execution_provider == shared), "{:?}", pipeline.sessions());
+	}
+	assert!(own_context.sessions().iter().all(|s| s.execution_provider == own), "{:?}", own_context.sessions());
+
+	// CUDA devices of every pipeline loaded through the context get the shared options, unlike other pipelines'
+	for pipeline in &pipelines {
+		let device = pipeline.execution_context().effective_device(&DiffusionDevice::CUDA(1, None));
+		assert_eq!(format!("{device:?}"), format!("{:?}", DiffusionDevice::CUDA(1, Some(cuda_options.clone()))));
+	}
+	let device = own_context.execution_context().effective_device(&DiffusionDevice::CUDA(1, None));
+	assert_eq!(format!("{device:?}"), format!("{:?}", DiffusionDevice::CUDA(1, None)));
+	Ok(())
+}
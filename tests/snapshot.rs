@@ -0,0 +1,46 @@
+use std::fs;
+
+use pyke_diffusers::{
+	EulerDiscreteScheduler, OrtEnvironment, SchedulerOptimizedDefaults, StableDiffusionOptions, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions
+};
+
+use crate::common;
+
+fn generate(pipeline: &StableDiffusionPipeline) -> anyhow::Result<Vec<u8>> {
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let images = StableDiffusionTxt2ImgOptions::default()
+		.with_size(64, 64)
+		.with_prompt("photo of a red fox")
+		.with_steps(3)
+		.with_seed(42)
+		.run(pipeline, &mut scheduler)?;
+	Ok(images[0].to_rgb8().into_raw())
+}
+
+#[test]
+fn snapshot_matches_normal_load() -> anyhow::Result<()> {
+	let environment = OrtEnvironment::default().into_arc();
+	let snapshot_dir = std::env::temp_dir().join("pyke-diffusers-snapshot");
+	let _ = fs::remove_dir_all(&snapshot_dir);
+
+	let expected = generate(&common::pipeline()?)?;
+
+	let created = StableDiffusionPipeline::create_snapshot(&environment, common::TEST_MODEL, StableDiffusionOptions::default(), &snapshot_dir)?;
+	assert_eq!(generate(&created)?, expected);
+	drop(created);
+	assert!(snapshot_dir.join("snapshot.json").exists());
+	assert!(snapshot_dir.join("unet.onnx").exists());
+
+	let restored = StableDiffusionPipeline::from_snapshot(&environment, &snapshot_dir, StableDiffusionOptions::default())?;
+	assert_eq!(generate(&restored)?, expected);
+	drop(restored);
+
+	// a corrupt optimized model is detected, and the snapshot is refreshed from the original models
+	fs::write(snapshot_dir.join("unet.onnx"), b"not a model")?;
+	let refreshed = StableDiffusionPipeline::from_snapshot(&environment, &snapshot_dir, StableDiffusionOptions::default())?;
+	assert_eq!(generate(&refreshed)?, expected);
+	assert_ne!(fs::read(snapshot_dir.join("unet.onnx"))?, b"not a model");
+
+	fs::remove_dir_all(&snapshot_dir)?;
+	Ok(())
+}
@@ -0,0 +1,54 @@
+use std::{cell::RefCell, rc::Rc};
+
+use pyke_diffusers::{BetaSchedule, DDPMScheduler, SchedulerPredictionType, StepLimitPolicy};
+
+use crate::common;
+
+#[test]
+fn excess_steps_are_clamped() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	// a scheduler with only 4 train timesteps supports at most 4 steps
+	let mut scheduler = DDPMScheduler::new(4, 0.00085, 0.012, &BetaSchedule::ScaledLinear, &SchedulerPredictionType::Epsilon, None)?;
+
+	let callbacks = Rc::new(RefCell::new(Vec::new()));
+	let cb_callbacks = Rc::clone(&callbacks);
+	let output = common::options(1_000_000)
+		.callback_progress(1, move |step, _| {
+			cb_callbacks.borrow_mut().push(step);
+			true
+		})
+		.run_with_output(&pipeline, &mut scheduler)?;
+	assert_eq!(output.steps_taken, 4);
+	// every step is reported; none are mistaken for warmup steps
+	assert_eq!(*callbacks.borrow(), [0, 1, 2, 3]);
+	assert_eq!(output.images.len(), 1);
+
+	let clamped = common::options(1_000_000).with_steps(4).run_with_output(&pipeline, &mut scheduler)?;
+	assert_eq!(clamped.images[0].clone().into_image()?.to_rgb8(), output.images[0].clone().into_image()?.to_rgb8());
+
+	assert!(common::options(1_000_000).with_step_limit_policy(StepLimitPolicy::Error).run(&pipeline, &mut scheduler).is_err());
+	Ok(())
+}
+
+#[test]
+#[cfg(feature = "scheduler-dpm-solver")]
+fn steps_are_clamped_to_computed_timesteps() -> anyhow::Result<()> {
+	use pyke_diffusers::{DPMSolverMultistepScheduler, DiffusionScheduler, SchedulerOptimizedDefaults};
+
+	let pipeline = common::pipeline()?;
+	// with Karras sigmas, many of 200 steps round to the same timesteps near `t = 0`, and duplicates are dropped
+	let mut scheduler = DPMSolverMultistepScheduler::stable_diffusion_v1_optimized_default()?.with_karras_sigmas(true);
+	assert_eq!(scheduler.max_inference_steps(), None);
+
+	let output = common::options(1_000_000).with_steps(200).run_with_output(&pipeline, &mut scheduler)?;
+	assert!(output.steps_taken < 200);
+	assert_eq!(output.steps_taken, scheduler.timesteps().len());
+	assert!(common::options(1_000_000).with_steps(200).with_step_limit_policy(StepLimitPolicy::Error).run(&pipeline, &mut scheduler).is_err());
+
+	// checkpoints & refiner handoffs count the steps taken, not the steps requested
+	let steps = scheduler.timesteps().len();
+	let checkpoint = common::options(1_000_000).with_steps(200).with_denoising_end(0.5).run_with_output(&pipeline, &mut scheduler)?.checkpoint.unwrap();
+	assert_eq!((checkpoint.steps, checkpoint.step), (steps, (steps as f32 * 0.5).round() as usize));
+	pipeline.refine(&mut scheduler, checkpoint.latents, 0.5, common::options(1_000_000).with_steps(200))?;
+	Ok(())
+}
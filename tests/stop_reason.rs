@@ -0,0 +1,32 @@
+use pyke_diffusers::{CancellationToken, EulerDiscreteScheduler, SchedulerOptimizedDefaults, StopReason};
+
+use crate::common;
+
+#[test]
+fn early_stops_return_partial_latents() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	let stopped = common::options(4).callback_progress(1, |step, _| step < 1).run_with_output(&pipeline, &mut scheduler)?;
+	assert_eq!((stopped.stop_reason, stopped.steps_taken), (StopReason::CallbackStop, 2));
+	assert!(stopped.images.is_empty() && stopped.nsfw_flags.is_empty());
+	let latents = stopped.latents.unwrap();
+	assert_eq!(latents.dim(), (1, 4, 8, 8));
+
+	// opting in decodes the same partial latents
+	let decoded = common::options(4).callback_progress(1, |step, _| step < 1).with_decode_on_early_stop(true).run_with_output(&pipeline, &mut scheduler)?;
+	assert_eq!(decoded.images.len(), 1);
+	assert_eq!(decoded.latents.as_ref(), Some(&latents));
+	let images = pipeline.decode_latents(latents.view())?;
+	assert_eq!(decoded.images[0].clone().into_image()?.to_rgb8(), images[0].to_rgb8());
+
+	let token = CancellationToken::new();
+	token.cancel();
+	let cancelled = common::options(4).with_cancellation_token(token).run_with_output(&pipeline, &mut scheduler)?;
+	assert_eq!((cancelled.stop_reason, cancelled.steps_taken, cancelled.images.len()), (StopReason::Cancelled, 0, 0));
+
+	let completed = common::options(4).run_with_output(&pipeline, &mut scheduler)?;
+	assert_eq!((completed.stop_reason, completed.steps_taken, completed.images.len()), (StopReason::Completed, 4, 1));
+	assert!(completed.latents.is_none());
+	Ok(())
+}
@@ -0,0 +1,66 @@
+use ndarray::{s, ArrayD, Axis};
+use pyke_diffusers::{EulerDiscreteScheduler, Prompt, StableDiffusionTxt2ImgOptions, StyleBlend, StylePrompt};
+
+use crate::common;
+
+const CONTENT: &str = "photo of a red fox";
+const STYLE: &str = "watercolor painting";
+
+fn max_diff(a: &ArrayD<f32>, b: &ArrayD<f32>) -> f32 {
+	assert_eq!(a.shape(), b.shape());
+	a.iter().zip(b.iter()).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max)
+}
+
+#[test]
+fn weighted_sum_interpolates_the_prompt() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let content = pipeline.encode_prompt(CONTENT.into(), true, None)?;
+	let style = pipeline.encode_prompt(STYLE.into(), true, None)?;
+
+	let unstyled = pipeline.encode_styled_prompt(CONTENT.into(), &StylePrompt::new(STYLE, 0.0), true, None, &[])?;
+	assert!(max_diff(&unstyled, &content) < 1e-4);
+
+	// only the positive half is replaced; the negative prompt is still the content's
+	let styled = pipeline.encode_styled_prompt(CONTENT.into(), &StylePrompt::new(STYLE, 1.0), true, None, &[])?;
+	assert!(max_diff(&styled.slice_axis(Axis(0), (1..).into()).to_owned(), &style.slice_axis(Axis(0), (1..).into()).to_owned()) < 1e-4);
+	assert!(max_diff(&styled.slice_axis(Axis(0), (..1).into()).to_owned(), &content.slice_axis(Axis(0), (..1).into()).to_owned()) < 1e-4);
+
+	// a single style prompt is broadcast to every content prompt
+	let batch = pipeline.encode_styled_prompt(Prompt::from([CONTENT, "photo of a grey wolf"]), &StylePrompt::new(STYLE, 0.5), false, None, &[])?;
+	assert_eq!(batch.shape()[0], 2);
+	let mismatched = StylePrompt::new(Prompt::from([STYLE, STYLE, STYLE]), 0.5);
+	assert!(pipeline.encode_styled_prompt(Prompt::from([CONTENT, CONTENT]), &mismatched, false, None, &[]).is_err());
+	assert!(pipeline.encode_styled_prompt(CONTENT.into(), &StylePrompt::new(STYLE, f32::NAN), false, None, &[]).is_err());
+	Ok(())
+}
+
+#[test]
+fn concatenation_appends_style_tokens() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let content = pipeline.encode_prompt(CONTENT.into(), true, None)?;
+
+	let style = StylePrompt::new(STYLE, 0.5).with_blend(StyleBlend::Concatenate);
+	let styled = pipeline.encode_styled_prompt(CONTENT.into(), &style, true, None, &[])?;
+	let tokens = content.shape()[1];
+	assert_eq!(styled.shape(), [2, tokens * 2, content.shape()[2]]);
+	assert!(max_diff(&styled.slice(s![.., ..tokens, ..]).to_owned().into_dyn(), &content) < 1e-4);
+	Ok(())
+}
+
+#[test]
+fn style_prompts_generate() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::default();
+	let options = || StableDiffusionTxt2ImgOptions::default().with_size(64, 64).with_steps(2).with_seed(42).with_prompt(CONTENT);
+	let style = StylePrompt::new(STYLE, 0.4);
+
+	let styled = options().with_style_prompt(style.clone()).run(&pipeline, &mut scheduler)?;
+	assert_eq!(styled.len(), 1);
+	// generating with the style prompt is the same as generating from the blended embeddings
+	let embeddings = pipeline.encode_styled_prompt(CONTENT.into(), &style, true, None, &[])?;
+	let blended = options().with_prompt_embeddings(embeddings.clone()).run(&pipeline, &mut scheduler)?;
+	assert_eq!(styled[0].to_rgb8(), blended[0].to_rgb8());
+
+	assert!(options().with_prompt_embeddings(embeddings).with_style_prompt(style).run(&pipeline, &mut scheduler).is_err());
+	Ok(())
+}
@@ -0,0 +1,56 @@
+use image::{DynamicImage, Rgb, RgbImage};
+use ndarray::Array4;
+use pyke_diffusers::{EulerDiscreteScheduler, MultiDiffusionOptions, SchedulerOptimizedDefaults, StableDiffusionPipeline, StableDiffusionTxt2ImgOptions};
+
+use crate::common;
+
+fn pipeline() -> anyhow::Result<StableDiffusionPipeline> {
+	common::pipeline()
+}
+
+#[test]
+fn single_tile_matches_untiled_vae() -> anyhow::Result<()> {
+	let pipeline = pipeline()?;
+	let pixels = Array4::from_shape_fn((2, 3, 64, 48), |(n, c, y, x)| ((n + c + y + x) % 16) as f32 / 15.0);
+	let latents = pipeline.encode_images(pixels.view())?;
+	assert_eq!(pipeline.encode_images_tiled(pixels.view(), 64, 16)?, latents);
+
+	let expected = pipeline.decode_latents(latents.view())?;
+	let tiled = pipeline.decode_latents_tiled(latents.view(), 64, 16)?;
+	assert_eq!(tiled.iter().map(|image| image.to_rgb8()).collect::<Vec<_>>(), expected.iter().map(|image| image.to_rgb8()).collect::<Vec<_>>());
+
+	// overlapping tiles still cover the whole image
+	let tiled = pipeline.decode_latents_tiled(latents.view(), 32, 16)?;
+	assert_eq!(tiled.len(), 2);
+	assert_eq!((tiled[0].width(), tiled[0].height()), (48, 64));
+	assert!(pipeline.decode_latents_tiled(latents.view(), 32, 32).is_err());
+	Ok(())
+}
+
+#[test]
+fn super_resolve_upscales_tile_by_tile() -> anyhow::Result<()> {
+	let pipeline = pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let image = DynamicImage::ImageRgb8(RgbImage::from_fn(40, 32, |x, y| Rgb([(x * 6) as u8, (y * 8) as u8, 128])));
+	let options = || {
+		StableDiffusionTxt2ImgOptions::default()
+			.with_prompt("photo of a red fox")
+			.with_steps(4)
+			.with_seed(42)
+			.with_multidiffusion(MultiDiffusionOptions { tile_size: 32, tile_overlap: 16, ..Default::default() })
+	};
+
+	let output = pipeline.super_resolve(&mut scheduler, &image, 2.0, 0.5, options())?;
+	assert_eq!(output.images.len(), 1);
+	assert_eq!(output.steps_taken, 2);
+	let upscaled = output.images.into_iter().next().unwrap().into_image()?;
+	assert_eq!((upscaled.width(), upscaled.height()), (80, 64));
+
+	let again = pipeline.super_resolve(&mut scheduler, &image, 2.0, 0.5, options())?;
+	assert_eq!(again.images.into_iter().next().unwrap().into_image()?.to_rgb8(), upscaled.to_rgb8());
+
+	assert!(pipeline.super_resolve(&mut scheduler, &image, 0.5, 0.5, options()).is_err());
+	assert!(pipeline.super_resolve(&mut scheduler, &image, 2.0, 0.0, options()).is_err());
+	assert!(pipeline.super_resolve(&mut scheduler, &image, 2.0, 0.1, options()).is_err());
+	Ok(())
+}
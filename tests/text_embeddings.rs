@@ -0,0 +1,48 @@
+use ndarray::{s, ArrayD, IxDyn};
+use pyke_diffusers::{EulerDiscreteScheduler, SchedulerOptimizedDefaults, StableDiffusionTxt2ImgOptions};
+
+use crate::common;
+
+#[test]
+fn embeddings_match_unet_layout() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+
+	// the test UNet's `encoder_hidden_states` is (batch, sequence, 32); negative prompts come first along axis 0
+	let embeddings = pipeline.encode_prompt("photo of a red fox".into(), true, Some(&"blurry".into()))?;
+	assert_eq!(embeddings.shape(), &[2, 77, 32]);
+	let negative = pipeline.encode_prompt("blurry".into(), false, None)?;
+	let positive = pipeline.encode_prompt("photo of a red fox".into(), false, None)?;
+	for (half, expected) in [(0, negative), (1, positive)] {
+		let actual = embeddings.slice(s![half..half + 1, .., ..]);
+		let max_diff = actual.iter().zip(expected.iter()).map(|(a, b)| (a - b).abs()).fold(0.0_f32, f32::max);
+		assert!(max_diff < 1e-5, "embeddings {half} differ by up to {max_diff}");
+	}
+	Ok(())
+}
+
+#[test]
+fn mismatched_embeddings_are_rejected() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let generate = |embeddings: ArrayD<f32>, scheduler: &mut EulerDiscreteScheduler| {
+		StableDiffusionTxt2ImgOptions::default()
+			.with_size(64, 64)
+			.with_steps(1)
+			.with_prompt_embeddings(embeddings)
+			.run(&pipeline, scheduler)
+	};
+
+	// embeddings from a text encoder with a different hidden size
+	let err = generate(ArrayD::zeros(IxDyn(&[2, 77, 16])), &mut scheduler).unwrap_err();
+	let message = err.to_string();
+	assert!(message.contains("text embeddings have shape [2, 77, 16]"), "{message}");
+	assert!(message.contains("`encoder_hidden_states` input expects (batch_size, ?, 32)"), "{message}");
+
+	// pooled embeddings, without a token axis
+	let err = generate(ArrayD::zeros(IxDyn(&[2, 32])), &mut scheduler).unwrap_err();
+	assert!(err.to_string().contains("laid out as (batch_size, tokens, hidden_size)"), "{err}");
+
+	let images = generate(ArrayD::zeros(IxDyn(&[2, 77, 32])), &mut scheduler)?;
+	assert_eq!(images.len(), 1);
+	Ok(())
+}
@@ -0,0 +1,117 @@
+use std::fs;
+
+use pyke_diffusers::{
+	CancellationToken, EulerDiscreteScheduler, ImageFileFormat, ImageRef, MetadataMode, ReproRecord, SchedulerOptimizedDefaults, StableDiffusionTxt2ImgOptions
+};
+
+use crate::common;
+
+#[test]
+fn generate_and_save() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let out_dir = std::env::temp_dir().join("pyke-diffusers-to-files");
+	let _ = fs::remove_dir_all(&out_dir);
+
+	let options = || StableDiffusionTxt2ImgOptions::default().with_size(64, 64).with_steps(1).with_seed(42);
+	let paths = pipeline.txt2img_to_files(["Photo of a red fox", "a grey wolf!"], &mut scheduler, options(), &out_dir, "{seed}-{index}-{prompt_slug}")?;
+	assert_eq!(paths, vec![out_dir.join("42-0-photo-of-a-red-fox.png"), out_dir.join("42-1-a-grey-wolf.png")]);
+	for path in &paths {
+		let image = image::open(path)?;
+		assert_eq!((image.width(), image.height()), (64, 64));
+	}
+
+	// both images would be written to the same file
+	assert!(pipeline.txt2img_to_files(["a", "b"], &mut scheduler, options(), &out_dir, "{seed}").is_err());
+
+	fs::remove_dir_all(&out_dir)?;
+	Ok(())
+}
+
+#[test]
+fn batch_subset_images_keep_their_index() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let out_dir = std::env::temp_dir().join("pyke-diffusers-to-files-subset");
+	let _ = fs::remove_dir_all(&out_dir);
+
+	let options = || {
+		StableDiffusionTxt2ImgOptions::default()
+			.with_size(64, 64)
+			.with_steps(1)
+			.with_seed(42)
+			.with_metadata_mode(MetadataMode::Embedded)
+	};
+	// subset images are named & recorded by their index in the full batch
+	let paths = pipeline.txt2img_to_files(["a", "b", "c"], &mut scheduler, options().with_batch_subset([0, 2]), &out_dir, "{index}-{prompt_slug}")?;
+	assert_eq!(paths, vec![out_dir.join("0-a.png"), out_dir.join("2-c.png")]);
+	assert!(!out_dir.join("1-b.png").exists());
+	let (_, record) = ReproRecord::load_with_image(&paths[1])?;
+	let record = record.expect("image has no record");
+	assert_eq!((record.index, record.prompt.as_str()), (2, "c"));
+
+	fs::remove_dir_all(&out_dir)?;
+	Ok(())
+}
+
+#[test]
+fn early_stop_writes_nothing() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let out_dir = std::env::temp_dir().join("pyke-diffusers-to-files-early-stop");
+	let _ = fs::remove_dir_all(&out_dir);
+
+	let token = CancellationToken::new();
+	token.cancel();
+	let options = || StableDiffusionTxt2ImgOptions::default().with_size(64, 64).with_steps(1).with_cancellation_token(token.clone());
+	// nothing is decoded after an early stop, so nothing is written...
+	let paths = pipeline.txt2img_to_files(["a", "b"], &mut scheduler, options(), &out_dir, "{index}")?;
+	assert!(paths.is_empty());
+	assert!(!out_dir.join("0.png").exists());
+	// ...unless the partial latents are decoded
+	let paths = pipeline.txt2img_to_files(["a", "b"], &mut scheduler, options().with_decode_on_early_stop(true), &out_dir, "{index}")?;
+	assert_eq!(paths, vec![out_dir.join("0.png"), out_dir.join("1.png")]);
+	assert!(paths.iter().all(|path| path.exists()));
+
+	fs::remove_dir_all(&out_dir)?;
+	Ok(())
+}
+
+#[test]
+fn decode_to_disk() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let spill_dir = std::env::temp_dir().join("pyke-diffusers-decode-to-disk");
+	let out_dir = std::env::temp_dir().join("pyke-diffusers-decode-to-disk-out");
+	let _ = fs::remove_dir_all(&spill_dir);
+	let _ = fs::remove_dir_all(&out_dir);
+
+	let options = || {
+		StableDiffusionTxt2ImgOptions::default()
+			.with_size(64, 64)
+			.with_steps(1)
+			.with_seed(42)
+			.with_decode_to_disk(&spill_dir)
+	};
+	let output = options().with_prompt(["a red fox", "a grey wolf"]).run_with_output(&pipeline, &mut scheduler)?;
+	assert_eq!(output.images.len(), 2);
+	for (index, image) in output.images.iter().enumerate() {
+		assert!(matches!(image, ImageRef::OnDisk(_)));
+		assert_eq!(image.path(), Some(spill_dir.join(format!("image-42-{index}.png")).as_path()));
+		assert_eq!(image.dimensions()?, (64, 64));
+	}
+
+	// the save helper accepts spilled images
+	let paths = pipeline.txt2img_to_files(
+		["a red fox", "a grey wolf"],
+		&mut scheduler,
+		options().with_file_format(ImageFileFormat::Png),
+		&out_dir,
+		"{index}"
+	)?;
+	assert_eq!(image::open(&paths[1])?.width(), 64);
+
+	fs::remove_dir_all(&spill_dir)?;
+	fs::remove_dir_all(&out_dir)?;
+	Ok(())
+}
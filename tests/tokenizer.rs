@@ -0,0 +1,202 @@
+use ndarray::s;
+use pyke_diffusers::{
+	clip::CLIPStandardTokenizer, EulerDiscreteScheduler, LowSignalPrompts, Prompt, PromptInput, SpecialTokenValidation, StableDiffusionOptions,
+	StableDiffusionTxt2ImgOptions, TokenizerUnavailable, TruncationStrategy
+};
+
+use crate::common;
+
+const TOKENIZER: &str = "tests/stable-diffusion/tokenizer.json";
+
+#[test]
+fn special_tokens_match_vocab() {
+	let tokenizer = CLIPStandardTokenizer::new(TOKENIZER, 77, 0, 1).unwrap();
+	assert!(tokenizer.validate_special_tokens(SpecialTokenValidation::Error).is_ok());
+}
+
+#[test]
+fn mismatched_special_token_ids() {
+	let tokenizer = CLIPStandardTokenizer::new(TOKENIZER, 77, 1, 0).unwrap();
+	assert!(tokenizer.validate_special_tokens(SpecialTokenValidation::Error).is_err());
+	assert!(tokenizer.validate_special_tokens(SpecialTokenValidation::Warn).is_ok());
+	assert!(tokenizer.validate_special_tokens(SpecialTokenValidation::Ignore).is_ok());
+}
+
+#[test]
+fn special_token_id_out_of_vocab() {
+	let tokenizer = CLIPStandardTokenizer::new(TOKENIZER, 77, 0, 123456).unwrap();
+	assert!(tokenizer.validate_special_tokens(SpecialTokenValidation::Error).is_err());
+}
+
+#[test]
+fn special_tokens_from_map() {
+	// no special tokens map in the test model; defaults to the standard CLIP tokens
+	let tokenizer = CLIPStandardTokenizer::with_special_tokens_map(TOKENIZER, "tests/stable-diffusion/special_tokens_map.json", 77).unwrap();
+	assert_eq!((tokenizer.bos(), tokenizer.eos()), (0, 1));
+
+	let tokenizer =
+		CLIPStandardTokenizer::with_special_tokens_map(TOKENIZER, "tests/fixtures/swapped-special-tokens/special_tokens_map.json", 77).unwrap();
+	assert_eq!((tokenizer.bos(), tokenizer.eos()), (1, 0));
+	assert!(tokenizer.validate_special_tokens(SpecialTokenValidation::Error).is_err());
+}
+
+#[test]
+fn unknown_special_token_follows_validation() -> anyhow::Result<()> {
+	// the fixture's special tokens map names an EOS token that isn't in the vocabulary
+	let options = |special_token_validation| StableDiffusionOptions { special_token_validation, ..Default::default() };
+
+	let err = common::load_fixture("unknown-special-tokens", options(SpecialTokenValidation::Error)).err().expect("unknown EOS token was accepted");
+	assert!(err.to_string().contains("<|notatoken|>"), "{err}");
+
+	for validation in [SpecialTokenValidation::Warn, SpecialTokenValidation::Ignore] {
+		let pipeline = common::load_fixture("unknown-special-tokens", options(validation))?;
+		let tokenizer = &pipeline.text_embeddings.tokenizer;
+		assert_eq!((tokenizer.bos(), tokenizer.eos()), (0, 1), "{validation:?} did not fall back to the standard EOS token");
+	}
+	Ok(())
+}
+
+/// 50 `a` tokens followed by 50 `b` tokens.
+fn long_prompt() -> String {
+	format!("{}{}", "a ".repeat(50), "b ".repeat(50))
+}
+
+fn token_id(tokenizer: &CLIPStandardTokenizer, token: &str) -> i32 {
+	tokenizer.inner.token_to_id(token).unwrap() as i32
+}
+
+#[test]
+fn truncate_tail() {
+	let tokenizer = CLIPStandardTokenizer::new(TOKENIZER, 77, 0, 1).unwrap();
+	assert_eq!(tokenizer.truncation_strategy(), TruncationStrategy::TruncateTail);
+	let (a, b) = (token_id(&tokenizer, "a</w>"), token_id(&tokenizer, "b</w>"));
+
+	let ids = tokenizer.encode_for_text_model(vec![long_prompt()]).unwrap();
+	assert_eq!(ids.shape(), &[1, 77]);
+	let ids = ids.row(0);
+	assert_eq!(ids[0], 0);
+	assert_eq!(ids[76], 1);
+	assert!(ids.slice(s![1..51]).iter().all(|&t| t == a));
+	assert!(ids.slice(s![51..76]).iter().all(|&t| t == b));
+}
+
+#[test]
+fn truncate_head() {
+	let tokenizer = CLIPStandardTokenizer::new(TOKENIZER, 77, 0, 1).unwrap().with_truncation_strategy(TruncationStrategy::TruncateHead);
+	let (a, b) = (token_id(&tokenizer, "a</w>"), token_id(&tokenizer, "b</w>"));
+
+	let ids = tokenizer.encode_for_text_model(vec![long_prompt()]).unwrap();
+	assert_eq!(ids.shape(), &[1, 77]);
+	let ids = ids.row(0);
+	assert_eq!(ids[0], 0);
+	assert_eq!(ids[76], 1);
+	assert!(ids.slice(s![1..26]).iter().all(|&t| t == a));
+	assert!(ids.slice(s![26..76]).iter().all(|&t| t == b));
+}
+
+#[test]
+fn truncate_error() {
+	let tokenizer = CLIPStandardTokenizer::new(TOKENIZER, 77, 0, 1).unwrap().with_truncation_strategy(TruncationStrategy::Error);
+	let err = tokenizer.encode_for_text_model(vec![long_prompt()]).unwrap_err();
+	assert!(err.to_string().contains("100 tokens"));
+
+	// prompts that fit are padded with EOS & unaffected by the strategy
+	let ids = tokenizer.encode_for_text_model(vec!["a b"]).unwrap();
+	let ids = ids.row(0);
+	assert_eq!(ids[0], 0);
+	assert_eq!((ids[1], ids[2]), (token_id(&tokenizer, "a</w>"), token_id(&tokenizer, "b</w>")));
+	assert!(ids.slice(s![3..]).iter().all(|&t| t == 1));
+}
+
+#[test]
+fn tokenizer_file_postprocessing_is_disabled() {
+	// the fixture's tokenizer.json truncates to 4 tokens & pads with EOS, and its special tokens map pads with `!`
+	let tokenizer = CLIPStandardTokenizer::with_special_tokens_map(
+		"tests/fixtures/padded-tokenizer/tokenizer.json",
+		"tests/fixtures/padded-tokenizer/special_tokens_map.json",
+		77
+	)
+	.unwrap();
+	let pad = token_id(&tokenizer, "!");
+	assert_eq!(tokenizer.pad() as i32, pad);
+
+	// long prompts are truncated to 75 tokens by the truncation strategy, not to 4 by the tokenizer file
+	let ids = tokenizer.encode_for_text_model(vec![long_prompt()]).unwrap();
+	let ids = ids.row(0);
+	assert_eq!((ids[50], ids[51], ids[75], ids[76]), (token_id(&tokenizer, "a</w>"), token_id(&tokenizer, "b</w>"), token_id(&tokenizer, "b</w>"), 1));
+
+	// short prompts end with EOS, then the pad token
+	let ids = tokenizer.encode_for_text_model(vec!["a b"]).unwrap();
+	let ids = ids.row(0);
+	assert_eq!(ids.slice(s![..4]).to_vec(), vec![0, token_id(&tokenizer, "a</w>"), token_id(&tokenizer, "b</w>"), 1]);
+	assert!(ids.slice(s![4..]).iter().all(|&t| t == pad));
+}
+
+#[test]
+fn pipeline_without_tokenizer() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let no_tokenizer = common::load_fixture("no-tokenizer", StableDiffusionOptions::default())?;
+	assert!(!no_tokenizer.text_embeddings.tokenizer.has_vocab());
+
+	// token IDs produce the same embeddings as the equivalent text prompt
+	let ids = pipeline.text_embeddings.tokenizer.encode_for_text_model(vec!["a b"])?.mapv(i64::from);
+	let expected = pipeline.encode_prompt("a b".into(), true, None)?;
+	let embeddings = no_tokenizer.encode_prompt_input(&PromptInput::TokenIds(ids.clone()), true, None)?;
+	assert_eq!(embeddings.shape(), expected.shape());
+	let max_diff = embeddings.iter().zip(expected.iter()).map(|(a, b)| (a - b).abs()).fold(0.0_f32, f32::max);
+	assert!(max_diff < 1e-4, "embeddings differ by up to {max_diff}");
+
+	let err = no_tokenizer.encode_prompt("a b".into(), true, None).unwrap_err();
+	assert!(err.downcast_ref::<TokenizerUnavailable>().is_some(), "{err}");
+	let err = no_tokenizer.encode_token_ids(ids.slice(s![.., ..76]), true, None).unwrap_err();
+	assert!(err.to_string().contains("length 76"), "{err}");
+
+	let mut scheduler = EulerDiscreteScheduler::default();
+	let images = StableDiffusionTxt2ImgOptions::default()
+		.with_size(64, 64)
+		.with_steps(2)
+		.with_seed(42)
+		.with_prompt_input(ids)
+		.run(&no_tokenizer, &mut scheduler)?;
+	assert_eq!(images.len(), 1);
+
+	let err = StableDiffusionTxt2ImgOptions::default()
+		.with_size(64, 64)
+		.with_steps(2)
+		.with_prompt("a b")
+		.run(&no_tokenizer, &mut scheduler)
+		.unwrap_err();
+	assert!(err.downcast_ref::<TokenizerUnavailable>().is_some(), "{err}");
+	Ok(())
+}
+
+#[test]
+fn symbol_only_prompts() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let expected_shape = pipeline.encode_prompt("a".into(), true, None)?.shape().to_vec();
+
+	for prompt in ["🦊🔥✨", "👩‍👩‍👧", "!!!???...", ".,;:'\"-_/\\|", "((()))", "[[:]]", "(:.)", "\\(\\)", "🙂 (:1.5)"] {
+		for negative_prompt in [None, Some(prompt)] {
+			let embeddings = pipeline.encode_prompt(prompt.into(), true, negative_prompt.map(Prompt::from).as_ref())?;
+			assert_eq!(embeddings.shape(), expected_shape, "prompt `{prompt}`");
+			assert!(embeddings.iter().all(|x| x.is_finite()), "prompt `{prompt}`");
+		}
+
+		// at least BOS & EOS
+		let ids = pipeline.text_embeddings.tokenizer.encode_for_text_model(vec![prompt])?;
+		assert_eq!((ids[[0, 0]], ids[[0, 76]]), (0, 1));
+	}
+	Ok(())
+}
+
+#[test]
+fn low_signal_prompt_handling() -> anyhow::Result<()> {
+	let options = StableDiffusionOptions { low_signal_prompts: LowSignalPrompts::Error, ..Default::default() };
+	let pipeline = common::pipeline_with(options)?;
+	assert!(pipeline.encode_prompt("?!".into(), false, None).is_err());
+	assert!(pipeline.encode_prompt("a".into(), true, Some(&"🔥🔥".into())).is_err());
+	// the empty default negative prompt is fine
+	pipeline.encode_prompt("a?!".into(), true, None)?;
+	pipeline.encode_prompt("a".into(), true, Some(&"".into()))?;
+	Ok(())
+}
@@ -0,0 +1,25 @@
+use pyke_diffusers::{EulerDiscreteScheduler, SchedulerOptimizedDefaults};
+
+use crate::common;
+
+#[test]
+fn trajectory_follows_the_schedule() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+
+	assert!(common::options(4).run_with_output(&pipeline, &mut scheduler)?.trajectory.is_empty());
+
+	let output = common::options(4).with_trajectory(true).run_with_output(&pipeline, &mut scheduler)?;
+	let trajectory = output.trajectory;
+	assert_eq!(trajectory.iter().map(|record| record.step).collect::<Vec<_>>(), [0, 1, 2, 3]);
+	assert_eq!(trajectory.iter().map(|record| record.timestep).collect::<Vec<_>>(), scheduler.timesteps().to_vec());
+	// the first step denoises the initial noise
+	assert!((trajectory[0].sigma.unwrap() - scheduler.init_noise_sigma()).abs() < 1e-3);
+	assert!(trajectory.windows(2).all(|pair| pair[1].sigma < pair[0].sigma));
+	assert!(trajectory.iter().all(|record| record.latent_norm.is_finite() && record.noise_pred_norm.is_finite()));
+	// the trajectory's noise prediction norms match the step statistics
+	for (record, stats) in trajectory.iter().zip(output.step_stats.iter()) {
+		assert_eq!((record.step, record.noise_pred_norm), (stats.step, stats.noise_pred_norm));
+	}
+	Ok(())
+}
@@ -0,0 +1,68 @@
+use std::{cell::RefCell, rc::Rc};
+
+use pyke_diffusers::{EulerAncestralDiscreteScheduler, PromptCacheConfig, SchedulerOptimizedDefaults, StableDiffusionOptions, StopReason};
+
+use crate::common;
+
+const STEPS: usize = 4;
+
+#[test]
+fn steps_follow_uninterrupted_run() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+
+	let expected = Rc::new(RefCell::new(Vec::new()));
+	let cb_expected = Rc::clone(&expected);
+	let mut scheduler = EulerAncestralDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	common::options(STEPS)
+		.callback_latents(1, move |_, _, latents| {
+			cb_expected.borrow_mut().push(latents);
+			true
+		})
+		.run_with_output(&pipeline, &mut scheduler)?;
+
+	let mut scheduler = EulerAncestralDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let mut steps = pipeline.txt2img_iter(&mut scheduler, common::options(STEPS))?.with_previews(true);
+	for (i, expected) in expected.borrow().iter().enumerate() {
+		let step = steps.next().unwrap()?;
+		assert_eq!(step.step, i);
+		assert_eq!(step.preview.map(|preview| preview.len()), Some(1));
+		let max_diff = step.latents.iter().zip(expected.iter()).map(|(a, b)| (a - b).abs()).fold(0.0_f32, f32::max);
+		assert!(max_diff <= 1e-5, "step {i} latents differ by up to {max_diff}");
+	}
+	assert_eq!(steps.stop_reason(), Some(StopReason::Completed));
+	assert!(steps.next().is_none());
+	assert!(steps.next().is_none());
+	Ok(())
+}
+
+#[test]
+fn steps_stop_with_callback() -> anyhow::Result<()> {
+	let pipeline = common::pipeline()?;
+
+	let mut scheduler = EulerAncestralDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let stopping = common::options(STEPS).callback_progress(1, |step, _| step < 1);
+	let steps = pipeline.txt2img_iter(&mut scheduler, stopping)?.collect::<anyhow::Result<Vec<_>>>()?;
+	assert_eq!(steps.len(), 2);
+
+	// dropping the iterator early leaves the pipeline usable
+	let mut scheduler = EulerAncestralDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	drop(pipeline.txt2img_iter(&mut scheduler, common::options(STEPS))?.next());
+	assert_eq!(pipeline.txt2img_iter(&mut scheduler, common::options(STEPS))?.count(), STEPS);
+
+	assert!(pipeline.txt2img_iter(&mut scheduler, common::options(STEPS).with_denoising_end(0.5)).is_err());
+	Ok(())
+}
+
+#[test]
+fn steps_encode_prompts_once() -> anyhow::Result<()> {
+	let pipeline = common::pipeline_with(StableDiffusionOptions::default().with_prompt_cache(PromptCacheConfig::new(8)))?;
+
+	let mut scheduler = EulerAncestralDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let mut steps = pipeline.txt2img_iter(&mut scheduler, common::options(STEPS))?;
+	assert_eq!(steps.by_ref().count(), STEPS);
+	assert_eq!(steps.checkpoint().map(|checkpoint| checkpoint.step), Some(STEPS));
+	// encoding the prompts again for a later step would have hit the cache
+	let stats = pipeline.prompt_cache_stats().unwrap();
+	assert!(stats.misses > 0 && stats.hits == 0, "{stats:?}");
+	Ok(())
+}
@@ -0,0 +1,39 @@
+use std::{cell::RefCell, rc::Rc};
+
+use ndarray::Array4;
+use pyke_diffusers::{EulerDiscreteScheduler, SchedulerOptimizedDefaults, StableDiffusionOptions, StableDiffusionTxt2ImgOptions};
+
+use crate::common;
+
+fn final_latents(fixture: &str) -> anyhow::Result<Array4<f32>> {
+	let pipeline = common::load_fixture(fixture, StableDiffusionOptions::default())?;
+	let mut scheduler = EulerDiscreteScheduler::stable_diffusion_v1_optimized_default()?;
+	let latents = Rc::new(RefCell::new(None));
+	let cb_latents = Rc::clone(&latents);
+	let images = StableDiffusionTxt2ImgOptions::default()
+		.with_size(64, 64)
+		.with_prompt("photo of a red fox")
+		.with_steps(2)
+		.with_seed(42)
+		.callback_latents(1, move |_, _, step_latents| {
+			*cb_latents.borrow_mut() = Some(step_latents);
+			true
+		})
+		.run(&pipeline, &mut scheduler)?;
+	assert_eq!(images.len(), 1);
+	let latents = latents.borrow_mut().take().unwrap();
+	Ok(latents)
+}
+
+#[test]
+fn noise_prediction_is_found_among_outputs() -> anyhow::Result<()> {
+	// both fixture UNets predict half their input as noise: `reordered-outputs` emits it as `out_sample` after a rank-4
+	// output predicting 3x the input, and `wrapped-outputs` emits it unnamed with an extra leading dimension of size 1
+	// after a rank-2 output
+	let reordered = final_latents("reordered-outputs")?;
+	let wrapped = final_latents("wrapped-outputs")?;
+	assert_eq!(reordered.dim(), (1, 4, 8, 8));
+	assert!(reordered.iter().all(|x| x.is_finite()));
+	assert_eq!(reordered, wrapped);
+	Ok(())
+}